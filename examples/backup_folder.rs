@@ -0,0 +1,73 @@
+//! Backs up a local folder into a new forest and exports the forest as a CAR archive, which
+//! `restore_car` turns back into files.
+//!
+//! Usage: `cargo run --example backup_folder -- <folder> <store_dir> <archive.car>`
+//!
+//! The wnfs key is derived from `WNFSUTILS_PASSPHRASE`, `example` when unset.
+
+use std::{fs::File, process::ExitCode};
+
+use sha2::{Digest, Sha256};
+use wnfs::common::CODEC_DAG_CBOR;
+use wnfsutils::{
+    blockstore::FFIFriendlyBlockStore,
+    car::{export_car, CarVersion},
+    kvstore::KVBlockStore,
+    private_forest::PrivateDirectoryHelper,
+};
+
+const USAGE: &str = "usage: backup_folder <folder> <store_dir> <archive.car>";
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    env_logger::init();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let result = match args.as_slice() {
+        [folder, store_dir, archive] => backup(folder, store_dir, archive).await,
+        _ => Err(USAGE.to_string()),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn backup(folder: &String, store_dir: &str, archive: &str) -> Result<(), String> {
+    let store =
+        KVBlockStore::try_new(store_dir.to_string(), CODEC_DAG_CBOR).map_err(|e| e.to_string())?;
+    let blockstore = &mut FFIFriendlyBlockStore::new(Box::new(store));
+    let (mut helper, _, _) =
+        PrivateDirectoryHelper::init_with_state(blockstore, wnfs_key(), "backup_folder").await?;
+
+    let mut progress = |progress: &wnfsutils::private_forest::TransferProgress| {
+        println!(
+            "{}/{} files, {} bytes",
+            progress.files_done, progress.files_total, progress.bytes_done
+        );
+    };
+    let report = helper
+        .ingest(
+            folder,
+            &["backup".to_string()],
+            Default::default(),
+            Some(&mut progress),
+        )
+        .await?;
+    let root = report.forest_cid.ok_or("nothing to back up")?;
+
+    let mut out = File::create(archive).map_err(|e| e.to_string())?;
+    let count = export_car(blockstore, &root, CarVersion::V1, &mut out).await?;
+    println!(
+        "backed up {} as {} ({} blocks in {})",
+        folder, root, count, archive
+    );
+    Ok(())
+}
+
+fn wnfs_key() -> Vec<u8> {
+    let passphrase = std::env::var("WNFSUTILS_PASSPHRASE").unwrap_or_else(|_| "example".into());
+    Sha256::digest(passphrase.as_bytes()).to_vec()
+}
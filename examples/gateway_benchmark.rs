@@ -0,0 +1,85 @@
+//! Measures how fast gateways serve a block, each on its own and combined in one
+//! `GatewayStore` that picks the fastest.
+//!
+//! Usage: `cargo run --example gateway_benchmark -- <cid> <rounds> <gateway_url>...`
+//!
+//! Nothing is cached between rounds, so every round is a fresh request.
+
+use std::{
+    process::ExitCode,
+    time::{Duration, Instant},
+};
+
+use libipld::Cid;
+use wnfsutils::{
+    blockstore::FFIStore,
+    gateway::{GatewaySelection, GatewayStore},
+};
+
+const USAGE: &str = "usage: gateway_benchmark <cid> <rounds> <gateway_url>...";
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+fn main() -> ExitCode {
+    env_logger::init();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let result = match args.as_slice() {
+        [cid, rounds, gateways @ ..] if !gateways.is_empty() => benchmark(cid, rounds, gateways),
+        _ => Err(USAGE.to_string()),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn benchmark(cid: &str, rounds: &str, gateways: &[String]) -> Result<(), String> {
+    let cid = Cid::try_from(cid).map_err(|e| e.to_string())?;
+    let rounds: usize = rounds.parse().map_err(|_| USAGE.to_string())?;
+    let urls: Vec<&str> = gateways.iter().map(String::as_str).collect();
+    for url in urls.iter() {
+        let store = GatewayStore::new(url, TIMEOUT, 0).map_err(|e| e.to_string())?;
+        report(url, &measure(&store, &cid, rounds));
+    }
+    let combined = GatewayStore::with_gateways(&urls, TIMEOUT, 0, GatewaySelection::default())
+        .map_err(|e| e.to_string())?;
+    combined.probe_gateways();
+    report("combined", &measure(&combined, &cid, rounds));
+    Ok(())
+}
+
+// Latency of each successful round, and the number of failed ones.
+fn measure(store: &GatewayStore, cid: &Cid, rounds: usize) -> (Vec<Duration>, usize) {
+    let mut latencies = Vec::with_capacity(rounds);
+    let mut failures = 0;
+    for _ in 0..rounds {
+        let started = Instant::now();
+        match store.get_block(cid.to_bytes()) {
+            Ok(_) => latencies.push(started.elapsed()),
+            Err(e) => {
+                log::debug!("round failed: {}", e);
+                failures += 1;
+            }
+        }
+    }
+    latencies.sort();
+    (latencies, failures)
+}
+
+fn report(name: &str, (latencies, failures): &(Vec<Duration>, usize)) {
+    let percentile = |p: f64| {
+        let index = ((latencies.len() as f64 - 1.0) * p).round() as usize;
+        latencies.get(index).copied().unwrap_or_default()
+    };
+    println!(
+        "{}: {} ok, {} failed, p50 {:?}, p95 {:?}, max {:?}",
+        name,
+        latencies.len(),
+        failures,
+        percentile(0.5),
+        percentile(0.95),
+        latencies.last().copied().unwrap_or_default()
+    );
+}
@@ -0,0 +1,58 @@
+//! Restores a CAR archive written by `backup_folder` into a store and exports its files.
+//!
+//! Usage: `cargo run --example restore_car -- <archive.car> <store_dir> <out_dir>`
+//!
+//! Use the same `WNFSUTILS_PASSPHRASE` as for the backup.
+
+use std::{fs::File, io::BufReader, process::ExitCode};
+
+use sha2::{Digest, Sha256};
+use wnfs::common::CODEC_DAG_CBOR;
+use wnfsutils::{
+    blockstore::FFIFriendlyBlockStore, car::import_car, kvstore::KVBlockStore,
+    private_forest::PrivateDirectoryHelper,
+};
+
+const USAGE: &str = "usage: restore_car <archive.car> <store_dir> <out_dir>";
+// A maximal block plus its CID.
+const MAX_SECTION: usize = 4 * 1024 * 1024 + 128;
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    env_logger::init();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let result = match args.as_slice() {
+        [archive, store_dir, out_dir] => restore(archive, store_dir, out_dir).await,
+        _ => Err(USAGE.to_string()),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn restore(archive: &str, store_dir: &str, out_dir: &String) -> Result<(), String> {
+    let input = File::open(archive).map_err(|e| e.to_string())?;
+    let store =
+        KVBlockStore::try_new(store_dir.to_string(), CODEC_DAG_CBOR).map_err(|e| e.to_string())?;
+    let (roots, count) = import_car(&mut BufReader::new(input), &store, MAX_SECTION)?;
+    let root = *roots.first().ok_or("archive has no root")?;
+    let blockstore = &mut FFIFriendlyBlockStore::new(Box::new(store));
+    let mut helper = PrivateDirectoryHelper::load_exclusive(blockstore, root, wnfs_key()).await?;
+    let report = helper
+        .materialize(&["backup".to_string()], out_dir, Default::default(), None)
+        .await?;
+    println!(
+        "restored {} files of {} ({} blocks) into {}",
+        report.copied, root, count, out_dir
+    );
+    Ok(())
+}
+
+fn wnfs_key() -> Vec<u8> {
+    let passphrase = std::env::var("WNFSUTILS_PASSPHRASE").unwrap_or_else(|_| "example".into());
+    Sha256::digest(passphrase.as_bytes()).to_vec()
+}
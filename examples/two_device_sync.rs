@@ -0,0 +1,126 @@
+//! Simulates two devices sharing one forest through separate stores and a pointer that
+//! accepts compare-and-swap updates only. Both devices write on the same root; the second to
+//! publish is rebased by `commit_with_retry` and neither change is lost.
+//!
+//! Usage: `cargo run --example two_device_sync`
+
+use std::{cell::RefCell, process::ExitCode};
+
+use libipld::Cid;
+use wnfs::common::{BlockStore, CODEC_DAG_CBOR};
+use wnfsutils::{
+    blockstore::{FFIFriendlyBlockStore, FFIStore},
+    car::reachable_blocks,
+    kvstore::KVBlockStore,
+    private_forest::{PrivateDirectoryHelper, RetryPolicy, RootPointer},
+};
+
+const WNFS_KEY: [u8; 32] = [7; 32];
+
+// Stands in for a pointer service.
+struct Pointer(RefCell<Cid>);
+
+impl RootPointer for Pointer {
+    fn latest(&self) -> Result<Cid, String> {
+        Ok(*self.0.borrow())
+    }
+
+    fn compare_and_publish(&self, expected: Cid, new: Cid) -> Result<bool, String> {
+        let mut current = self.0.borrow_mut();
+        if *current != expected {
+            return Ok(false);
+        }
+        *current = new;
+        Ok(true)
+    }
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    env_logger::init();
+    match simulate().await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn simulate() -> Result<(), String> {
+    let dir = tempfile::tempdir().map_err(|e| e.to_string())?;
+    let open = |name: &str| {
+        KVBlockStore::try_new(
+            dir.path().join(name).to_string_lossy().to_string(),
+            CODEC_DAG_CBOR,
+        )
+        .map_err(|e| e.to_string())
+    };
+    let (phone_store, laptop_store) = (open("phone")?, open("laptop")?);
+    let phone_blocks = &mut FFIFriendlyBlockStore::new(Box::new(phone_store.to_owned()));
+    let laptop_blocks = &mut FFIFriendlyBlockStore::new(Box::new(laptop_store.to_owned()));
+
+    let (mut phone, _, initial) =
+        PrivateDirectoryHelper::init_with_state(phone_blocks, WNFS_KEY.to_vec(), "phone").await?;
+    let pointer = Pointer(RefCell::new(initial.root_cid));
+    sync(phone_blocks, &laptop_store, initial.root_cid).await?;
+
+    let phone_file = vec!["phone.txt".to_string()];
+    let phone_root = phone
+        .commit_with_retry(&pointer, RetryPolicy::default(), |helper, _| {
+            Box::pin(helper.write_file(&phone_file, b"from the phone".to_vec(), 0))
+        })
+        .await?;
+    println!("phone published {}", phone_root);
+    sync(phone_blocks, &laptop_store, phone_root).await?;
+    // Both devices run in this process, which allows one writer per forest at a time.
+    drop(phone);
+
+    // The laptop still works on the initial root, so its first publish is rejected.
+    let mut laptop = PrivateDirectoryHelper::load_from_state(
+        laptop_blocks,
+        &initial,
+        WNFS_KEY.to_vec(),
+        "laptop",
+    )
+    .await?;
+    let laptop_file = vec!["laptop.txt".to_string()];
+    let laptop_root = laptop
+        .commit_with_retry(&pointer, RetryPolicy::default(), |helper, attempt| {
+            println!("laptop attempt {} on {}", attempt.number, attempt.base);
+            Box::pin(helper.write_file(&laptop_file, b"from the laptop".to_vec(), 0))
+        })
+        .await?;
+    println!("laptop published {}", laptop_root);
+    sync(laptop_blocks, &phone_store, laptop_root).await?;
+    drop(laptop);
+
+    let mut phone =
+        PrivateDirectoryHelper::load_exclusive(phone_blocks, laptop_root, WNFS_KEY.to_vec())
+            .await?;
+    let names: Vec<String> = phone
+        .ls_files(&[])
+        .await?
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect();
+    println!("phone sees {:?}", names);
+    if !names.contains(&"phone.txt".to_string()) || !names.contains(&"laptop.txt".to_string()) {
+        return Err("a change was lost".to_string());
+    }
+    Ok(())
+}
+
+// Copies every block reachable from `root` to the other device.
+async fn sync(
+    from: &FFIFriendlyBlockStore<'_>,
+    to: &KVBlockStore,
+    root: Cid,
+) -> Result<(), String> {
+    for cid in reachable_blocks(from, &root).await? {
+        let data = from.get_block(&cid).await.map_err(|e| e.to_string())?;
+        to.put_block(cid.to_bytes(), data.to_vec())
+            .map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
@@ -0,0 +1,15 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use once_cell::sync::Lazy;
+use wnfs::common::CODEC_RAW;
+use wnfsutils::{blockstore::FFIStore, kvstore::KVBlockStore};
+
+static STORE: Lazy<KVBlockStore> =
+    Lazy::new(|| KVBlockStore::new(String::from("./tmp/fuzz_ffi_get_block"), CODEC_RAW));
+
+// Arbitrary CID bytes as handed over FFI must produce an error, never a panic.
+fuzz_target!(|cid: &[u8]| {
+    let _ = STORE.get_block(cid.to_vec());
+    let _ = wnfsutils::blockstore::cid_from_bytes(cid);
+});
@@ -0,0 +1,24 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use wnfs::common::CODEC_RAW;
+use wnfsutils::{
+    blockstore::{cid_from_bytes, FFIFriendlyBlockStore},
+    kvstore::KVBlockStore,
+    private_forest::PrivateDirectoryHelper,
+};
+
+// The first byte is the length of the forest CID and the rest is the wnfs key, both untrusted
+// FFI input.
+fuzz_target!(|data: &[u8]| {
+    let Some((cid_len, rest)) = data.split_first() else {
+        return;
+    };
+    let (cid_bytes, wnfs_key) = rest.split_at((*cid_len as usize).min(rest.len()));
+    let Ok(forest_cid) = cid_from_bytes(cid_bytes) else {
+        return;
+    };
+    let store = KVBlockStore::new(String::from("./tmp/fuzz_load_with_wnfs_key"), CODEC_RAW);
+    let blockstore = &mut FFIFriendlyBlockStore::new(Box::new(store));
+    let _ = PrivateDirectoryHelper::synced_load_shared(blockstore, forest_cid, wnfs_key.to_vec());
+});
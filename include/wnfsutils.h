@@ -0,0 +1,138 @@
+/*
+ * C ABI of wnfsutils, built with the `ffi` feature. See src/ffi.rs for the ownership rules:
+ * buffers returned in a WnfsBytes are freed with wnfs_bytes_free, helpers with
+ * wnfs_helper_free, and calls made with one helper must not overlap.
+ */
+
+#ifndef WNFSUTILS_H
+#define WNFSUTILS_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef enum WnfsStatus {
+    WNFS_STATUS_OK = 0,
+    WNFS_STATUS_INVALID_ARGUMENT = 1,
+    WNFS_STATUS_NOT_FOUND = 2,
+    WNFS_STATUS_HELD = 3,
+    WNFS_STATUS_LIMIT_EXCEEDED = 4,
+    WNFS_STATUS_CORRUPT = 5,
+    WNFS_STATUS_STORE_UNAVAILABLE = 6,
+    WNFS_STATUS_PANIC = 7,
+    WNFS_STATUS_OTHER = 8,
+    WNFS_STATUS_INVALID_KEY = 9,
+    WNFS_STATUS_CANCELLED = 10,
+    WNFS_STATUS_CONFLICT = 11,
+    WNFS_STATUS_PERMISSION_DENIED = 12,
+    WNFS_STATUS_UNSUPPORTED = 13,
+} WnfsStatus;
+
+typedef struct WnfsHelper WnfsHelper;
+typedef struct WnfsScopedHandle WnfsScopedHandle;
+
+/* Permission bits of a scoped handle. */
+#define WNFS_PERMISSION_READ 1
+#define WNFS_PERMISSION_WRITE 2
+#define WNFS_PERMISSION_DELETE 4
+#define WNFS_PERMISSION_SHARE 8
+#define WNFS_PERMISSION_HISTORY 16
+
+/* Allocated by the library. */
+typedef struct WnfsBytes {
+    uint8_t *data;
+    size_t len;
+} WnfsBytes;
+
+/* Owned by the host. */
+typedef struct WnfsHostBytes {
+    const uint8_t *data;
+    size_t len;
+} WnfsHostBytes;
+
+/* Callbacks return 0 on success; get_block returns WNFS_GET_BLOCK_MISSING for a missing block. */
+#define WNFS_GET_BLOCK_MISSING 1
+
+typedef struct WnfsBlockStoreCallbacks {
+    void *context;
+    int32_t (*get_block)(void *context, const uint8_t *cid, size_t cid_len, WnfsHostBytes *out);
+    int32_t (*put_block)(void *context, const uint8_t *cid, size_t cid_len, const uint8_t *data,
+                         size_t data_len);
+    /* Optional. */
+    void (*release)(void *context, WnfsHostBytes bytes);
+    /* Optional. */
+    void (*free_context)(void *context);
+} WnfsBlockStoreCallbacks;
+
+/* Called on the calling thread while an observed call runs; returning non-zero cancels the
+ * call. operation lives for the callback only and bytes_total is -1 when unknown. */
+typedef struct WnfsProgressCallback {
+    void *context;
+    int32_t (*on_progress)(void *context, const char *operation, uint64_t bytes_done,
+                           int64_t bytes_total, uint64_t blocks_fetched);
+} WnfsProgressCallback;
+
+/* The library owns the context from the call on and releases it if opening fails. Opening a
+ * second helper with the same wnfs key fails with WNFS_STATUS_CONFLICT until the first is
+ * freed. */
+WnfsStatus wnfs_helper_init(const WnfsBlockStoreCallbacks *callbacks, const uint8_t *wnfs_key,
+                            size_t wnfs_key_len, WnfsHelper **out_helper, WnfsBytes *out_cid);
+WnfsStatus wnfs_helper_load(const WnfsBlockStoreCallbacks *callbacks, const char *forest_cid,
+                            const uint8_t *wnfs_key, size_t wnfs_key_len,
+                            WnfsHelper **out_helper);
+void wnfs_helper_free(WnfsHelper *helper);
+
+/* The mutating calls take an idempotency key, NULL for none: a retry with the key of a
+ * completed call returns that call's root without mutating again. */
+WnfsStatus wnfs_mkdir(WnfsHelper *helper, const char *path, const char *idempotency_key,
+                      WnfsBytes *out_cid);
+WnfsStatus wnfs_write_file(WnfsHelper *helper, const char *path, const uint8_t *data,
+                           size_t data_len, int64_t modification_time_seconds,
+                           const char *idempotency_key, WnfsBytes *out_cid);
+WnfsStatus wnfs_read_file(WnfsHelper *helper, const char *path, WnfsBytes *out_content);
+/* progress may be NULL. */
+WnfsStatus wnfs_write_file_observed(WnfsHelper *helper, const char *path, const uint8_t *data,
+                                    size_t data_len, int64_t modification_time_seconds,
+                                    const char *idempotency_key,
+                                    const WnfsProgressCallback *progress, WnfsBytes *out_cid);
+WnfsStatus wnfs_read_file_observed(WnfsHelper *helper, const char *path,
+                                   const WnfsProgressCallback *progress, WnfsBytes *out_content);
+/* A JSON array of {"name", "created", "modified", "content_type"}. */
+WnfsStatus wnfs_ls(WnfsHelper *helper, const char *path, WnfsBytes *out_json);
+WnfsStatus wnfs_rm(WnfsHelper *helper, const char *path, const char *idempotency_key,
+                   WnfsBytes *out_cid);
+WnfsStatus wnfs_mv(WnfsHelper *helper, const char *source, const char *target,
+                   const char *idempotency_key, WnfsBytes *out_cid);
+WnfsStatus wnfs_cp(WnfsHelper *helper, const char *source, const char *target,
+                   const char *idempotency_key, WnfsBytes *out_cid);
+
+/* Takes the helper over, also on failure. */
+WnfsStatus wnfs_helper_into_scoped(WnfsHelper *helper, const char *path, uint8_t permissions,
+                                   WnfsScopedHandle **out_scoped);
+WnfsStatus wnfs_scoped_narrow(WnfsScopedHandle *scoped, const char *path, uint8_t permissions,
+                              WnfsScopedHandle **out_scoped);
+uint8_t wnfs_scoped_permissions(WnfsScopedHandle *scoped);
+void wnfs_scoped_free(WnfsScopedHandle *scoped);
+WnfsStatus wnfs_scoped_read_file(WnfsScopedHandle *scoped, const char *path,
+                                 WnfsBytes *out_content);
+WnfsStatus wnfs_scoped_ls(WnfsScopedHandle *scoped, const char *path, WnfsBytes *out_json);
+WnfsStatus wnfs_scoped_write_file(WnfsScopedHandle *scoped, const char *path,
+                                  const uint8_t *data, size_t data_len,
+                                  int64_t modification_time_seconds, WnfsBytes *out_cid);
+WnfsStatus wnfs_scoped_mkdir(WnfsScopedHandle *scoped, const char *path, WnfsBytes *out_cid);
+WnfsStatus wnfs_scoped_rm(WnfsScopedHandle *scoped, const char *path, WnfsBytes *out_cid);
+WnfsStatus wnfs_scoped_mv(WnfsScopedHandle *scoped, const char *source, const char *target,
+                          WnfsBytes *out_cid);
+
+void wnfs_bytes_free(WnfsBytes bytes);
+/* NULL if no call failed on this thread. */
+const char *wnfs_last_error_message(void);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* WNFSUTILS_H */
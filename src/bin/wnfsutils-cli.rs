@@ -0,0 +1,198 @@
+//! Maintenance commands for local wnfsutils stores.
+//!
+//! Usage: `wnfsutils-cli [--json] compact <db_path>`,
+//! `wnfsutils-cli [--json] migrate <src_store> <dst_store> <root_cid>...` or
+//! `wnfsutils-cli [--json] completions <bash|zsh|fish>`
+//!
+//! Stores are `KVBlockStore` database paths, or `pack:<dir>` for a `PackStore`. An interrupted
+//! `migrate` is resumed by running it again.
+//!
+//! With `--json` every command prints a single JSON object on stdout, errors included as
+//! `{"error": ...}`, so scripts don't have to parse the text output.
+
+use std::process::ExitCode;
+
+use libipld::Cid;
+use serde_json::{json, Value};
+use wnfs::common::CODEC_DAG_CBOR;
+use wnfsutils::{
+    blockstore::FFIStore,
+    kvstore::KVBlockStore,
+    migrate::{migrate_store, MigrationProgress},
+    packstore::PackStore,
+};
+
+const USAGE: &str = "usage: wnfsutils-cli [--json] compact <db_path>
+       wnfsutils-cli [--json] migrate <src_store> <dst_store> <root_cid>...
+       wnfsutils-cli [--json] completions <bash|zsh|fish>";
+
+const SUBCOMMANDS: &str = "compact migrate completions";
+// Blocks between progress lines of `migrate`.
+const MIGRATE_PROGRESS_EVERY: usize = 1000;
+const SHELLS: &str = "bash zsh fish";
+
+// What a command reports, as text for people and as JSON for scripts.
+struct Output {
+    text: String,
+    json: Value,
+}
+
+fn main() -> ExitCode {
+    env_logger::init();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let json = args.iter().any(|arg| arg == "--json");
+    let args: Vec<&str> = args
+        .iter()
+        .map(String::as_str)
+        .filter(|arg| *arg != "--json")
+        .collect();
+    let result = match args.as_slice() {
+        ["compact", db_path] => compact(db_path),
+        ["migrate", src, dst, roots @ ..] if !roots.is_empty() => migrate(src, dst, roots, json),
+        ["completions", shell] => completions(shell),
+        _ => Err(USAGE.to_string()),
+    };
+    match (result, json) {
+        (Ok(output), false) => {
+            println!("{}", output.text);
+            ExitCode::SUCCESS
+        }
+        (Ok(output), true) => {
+            println!("{}", output.json);
+            ExitCode::SUCCESS
+        }
+        (Err(e), false) => {
+            eprintln!("{}", e);
+            ExitCode::FAILURE
+        }
+        (Err(e), true) => {
+            println!("{}", json!({ "error": e }));
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn compact(db_path: &str) -> Result<Output, String> {
+    let store =
+        KVBlockStore::try_new(db_path.to_string(), CODEC_DAG_CBOR).map_err(|e| e.to_string())?;
+    let size_before = store.size_on_disk().map_err(|e| e.to_string())?;
+    let reclaimed = store.compact().map_err(|e| e.to_string())?;
+    Ok(Output {
+        text: format!(
+            "compacted {}: {} bytes before, {} bytes reclaimed",
+            db_path, size_before, reclaimed
+        ),
+        json: json!({
+            "command": "compact",
+            "db_path": db_path,
+            "size_before": size_before,
+            "reclaimed": reclaimed,
+        }),
+    })
+}
+
+fn open_store(spec: &str) -> Result<Box<dyn FFIStore<'static>>, String> {
+    match spec.strip_prefix("pack:") {
+        Some(dir) => Ok(Box::new(
+            PackStore::try_new(dir.to_string()).map_err(|e| e.to_string())?,
+        )),
+        None => Ok(Box::new(
+            KVBlockStore::try_new(spec.to_string(), CODEC_DAG_CBOR).map_err(|e| e.to_string())?,
+        )),
+    }
+}
+
+fn migrate(src: &str, dst: &str, roots: &[&str], json: bool) -> Result<Output, String> {
+    let roots = roots
+        .iter()
+        .map(|root| Cid::try_from(*root).map_err(|e| format!("invalid root {}: {}", root, e)))
+        .collect::<Result<Vec<Cid>, String>>()?;
+    let source = open_store(src)?;
+    let target = open_store(dst)?;
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .map_err(|e| e.to_string())?;
+    // Progress goes to stderr, so it doesn't mix with the JSON on stdout.
+    let mut report = |status: &MigrationProgress| {
+        let blocks = status.blocks_copied + status.blocks_skipped;
+        if !json && blocks % MIGRATE_PROGRESS_EVERY == 0 {
+            eprintln!(
+                "{} blocks copied, {} skipped, {} bytes",
+                status.blocks_copied, status.blocks_skipped, status.bytes_copied
+            );
+        }
+    };
+    let status = runtime.block_on(migrate_store(
+        source.as_ref(),
+        target.as_ref(),
+        &roots,
+        Some(&mut report),
+    ))?;
+    Ok(Output {
+        text: format!(
+            "migrated {} to {}: {} blocks copied ({} bytes), {} already present",
+            src, dst, status.blocks_copied, status.bytes_copied, status.blocks_skipped
+        ),
+        json: json!({
+            "command": "migrate",
+            "src": src,
+            "dst": dst,
+            "blocks_copied": status.blocks_copied,
+            "blocks_skipped": status.blocks_skipped,
+            "bytes_copied": status.bytes_copied,
+        }),
+    })
+}
+
+// Completes the subcommands, `--json`, paths for `compact` and `migrate` and shells for
+// `completions`.
+fn completions(shell: &str) -> Result<Output, String> {
+    let script = match shell {
+        "bash" => format!(
+            r#"_wnfsutils_cli() {{
+    local cur prev
+    cur="${{COMP_WORDS[COMP_CWORD]}}"
+    prev="${{COMP_WORDS[COMP_CWORD-1]}}"
+    case "$prev" in
+        compact|migrate) COMPREPLY=($(compgen -f -- "$cur")) ;;
+        completions) COMPREPLY=($(compgen -W "{shells}" -- "$cur")) ;;
+        *) COMPREPLY=($(compgen -W "{subcommands} --json" -- "$cur")) ;;
+    esac
+}}
+complete -F _wnfsutils_cli wnfsutils-cli"#,
+            shells = SHELLS,
+            subcommands = SUBCOMMANDS
+        ),
+        "zsh" => format!(
+            r#"#compdef wnfsutils-cli
+_wnfsutils_cli() {{
+    case "$words[CURRENT-1]" in
+        compact|migrate) _files ;;
+        completions) compadd -- {shells} ;;
+        *) compadd -- {subcommands} --json ;;
+    esac
+}}
+compdef _wnfsutils_cli wnfsutils-cli"#,
+            shells = SHELLS,
+            subcommands = SUBCOMMANDS
+        ),
+        "fish" => format!(
+            r#"complete -c wnfsutils-cli -l json -d "Print JSON"
+complete -c wnfsutils-cli -f -n "not __fish_seen_subcommand_from {subcommands}" -a "{subcommands}"
+complete -c wnfsutils-cli -F -n "__fish_seen_subcommand_from compact migrate"
+complete -c wnfsutils-cli -f -n "__fish_seen_subcommand_from completions" -a "{shells}""#,
+            shells = SHELLS,
+            subcommands = SUBCOMMANDS
+        ),
+        _ => {
+            return Err(format!(
+                "unsupported shell {}, expected one of: {}",
+                shell, SHELLS
+            ))
+        }
+    };
+    Ok(Output {
+        json: json!({ "command": "completions", "shell": shell, "script": script }),
+        text: script,
+    })
+}
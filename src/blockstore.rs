@@ -1,13 +1,137 @@
-use anyhow::Result;
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
 use async_trait::async_trait;
 use bytes::Bytes;
 
-use libipld::Cid;
+use libipld::{
+    cid::Version,
+    multihash::{Code, MultihashDigest},
+    Cid, IpldCodec,
+};
+use log::{trace, warn};
+use web_time::Instant;
 use wnfs::common::{BlockStore, BlockStoreError};
 
+use crate::car::verify_block;
+use crate::error::{ErrorCode, WnfsUtilsError};
+use crate::metrics::{IoMetricsSnapshot, StoreMetrics, StoreMetricsSnapshot};
+use crate::request_id;
+
+#[async_trait(?Send)]
 pub trait FFIStore<'a>: FFIStoreClone<'a> {
     fn get_block(&self, cid: Vec<u8>) -> Result<Vec<u8>>;
     fn put_block(&self, cid: Vec<u8>, bytes: Vec<u8>) -> Result<()>;
+
+    /// What `FFIFriendlyBlockStore` awaits to read a block. Stores doing network I/O override
+    /// it so reads don't hold a runtime thread, and implement `get_block` with [`block_on`];
+    /// the default calls `get_block`.
+    async fn get_block_async(&self, cid: Vec<u8>) -> Result<Vec<u8>> {
+        self.get_block(cid)
+    }
+
+    /// What `FFIFriendlyBlockStore` awaits to write a block, see `get_block_async`.
+    async fn put_block_async(&self, cid: Vec<u8>, bytes: Vec<u8>) -> Result<()> {
+        self.put_block(cid, bytes)
+    }
+
+    /// Reads several blocks in one call, in the order of `cids`, failing if any is missing.
+    /// Stores behind an FFI boundary override it to cross it once per batch instead of once per
+    /// block; the default calls `get_block` for each.
+    fn get_many(&self, cids: Vec<Vec<u8>>) -> Result<Vec<Vec<u8>>> {
+        cids.into_iter().map(|cid| self.get_block(cid)).collect()
+    }
+
+    /// Writes several blocks in one call, see `get_many`. `FFIFriendlyBlockStore` writes the
+    /// blocks of a forest commit with it.
+    fn put_many(&self, blocks: Vec<(Vec<u8>, Vec<u8>)>) -> Result<()> {
+        blocks
+            .into_iter()
+            .try_for_each(|(cid, bytes)| self.put_block(cid, bytes))
+    }
+
+    /// What `FFIFriendlyBlockStore` awaits to read a batch. The default awaits
+    /// `get_block_async` for each block, so stores overriding `get_many` override this as well
+    /// to use it.
+    async fn get_many_async(&self, cids: Vec<Vec<u8>>) -> Result<Vec<Vec<u8>>> {
+        let mut blocks = Vec::with_capacity(cids.len());
+        for cid in cids {
+            blocks.push(self.get_block_async(cid).await?);
+        }
+        Ok(blocks)
+    }
+
+    /// What `FFIFriendlyBlockStore` awaits to write a batch, see `get_many_async`.
+    async fn put_many_async(&self, blocks: Vec<(Vec<u8>, Vec<u8>)>) -> Result<()> {
+        for (cid, bytes) in blocks {
+            self.put_block_async(cid, bytes).await?;
+        }
+        Ok(())
+    }
+
+    /// Waits until the writes accepted so far are stored, for stores accepting writes before
+    /// storing them such as `UploadPipeline`. `FFIFriendlyBlockStore` awaits it at the end of
+    /// every batch; stores that write before returning keep the default.
+    async fn flush_async(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Lists the CIDs of all stored blocks. Needed for garbage collection only, so stores that
+    /// don't support it keep the default.
+    fn list_blocks(&self) -> Result<Vec<Vec<u8>>> {
+        Err(anyhow!("list_blocks is not supported by this store"))
+    }
+
+    fn delete_block(&self, _cid: Vec<u8>) -> Result<()> {
+        Err(anyhow!("delete_block is not supported by this store"))
+    }
+
+    /// Time of the latest `put_block` of `cid` in milliseconds since the Unix epoch, `None` when
+    /// the store doesn't track it. Blocks of unknown age are never garbage collected.
+    fn block_written_at(&self, _cid: Vec<u8>) -> Result<Option<u64>> {
+        Ok(None)
+    }
+
+    /// Rewrites the store's files so space freed by deletions is returned to the filesystem,
+    /// returning the number of bytes reclaimed. Stores without local files have nothing to do.
+    fn compact(&self) -> Result<u64> {
+        Ok(0)
+    }
+
+    /// Largest block the `FFIFriendlyBlockStore` on top accepts, from its `DecodeLimits`, set
+    /// when it's created and whenever its limits change. Remote stores stop reading a body past
+    /// it instead of buffering it first, and wrappers pass it on to the store they wrap.
+    fn set_max_block_size(&self, _max_block_size: usize) {}
+
+    /// Records that the gateway at `provider` is likely to hold `cid` and the blocks below it,
+    /// e.g. the gateway named by a share the app accepted. Stores that never fetch blocks from
+    /// elsewhere ignore hints.
+    fn add_provider_hint(&self, _cid: Vec<u8>, _provider: String) -> Result<()> {
+        Ok(())
+    }
+
+    /// What the backend wrote to disk for the blocks it was given, `None` for stores that
+    /// don't track it, e.g. remote ones.
+    fn io_metrics(&self) -> Option<IoMetricsSnapshot> {
+        None
+    }
+
+    /// Hits and misses of a `CachedBlockStore` in the stack, reported by wrappers like
+    /// `io_metrics`; `None` without one.
+    fn cache_stats(&self) -> Option<CacheStats> {
+        None
+    }
+
+    /// Writes accepted and not yet stored remotely, for write-behind stores such as
+    /// `UploadPipeline` and `WriteAheadQueue`. Stores that write before returning have none.
+    fn pending_writeback(&self) -> PendingWriteback {
+        PendingWriteback::default()
+    }
 }
 
 pub trait FFIStoreClone<'a> {
@@ -29,9 +153,188 @@ impl<'a> Clone for Box<dyn FFIStore<'a> + 'a> {
     }
 }
 
+/// Bounds applied to data read from a store that may be controlled by someone else, e.g. a
+/// remote gateway, so malformed or hostile data fails with an error instead of exhausting memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecodeLimits {
+    /// Largest block accepted by `get_block`, in bytes.
+    pub max_block_size: usize,
+    /// Deepest path the helper resolves or walks into.
+    pub max_path_depth: usize,
+    /// Most entries accepted in a single directory listing.
+    pub max_dir_entries: usize,
+}
+
+impl Default for DecodeLimits {
+    fn default() -> Self {
+        Self {
+            max_block_size: 4 * 1024 * 1024,
+            max_path_depth: 256,
+            max_dir_entries: 100_000,
+        }
+    }
+}
+
+impl DecodeLimits {
+    pub fn check_path_depth(&self, depth: usize) -> Result<(), WnfsUtilsError> {
+        if depth > self.max_path_depth {
+            return Err(WnfsUtilsError::PathTooDeep {
+                depth,
+                limit: self.max_path_depth,
+            });
+        }
+        Ok(())
+    }
+
+    pub fn check_dir_entries(&self, count: usize) -> Result<(), WnfsUtilsError> {
+        if count > self.max_dir_entries {
+            return Err(WnfsUtilsError::TooManyEntries {
+                count,
+                limit: self.max_dir_entries,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Whether `FFIFriendlyBlockStore` checks the blocks it reads against their CIDs, so a
+/// misbehaving backend, e.g. a remote gateway, can't feed corrupt data into deserialization.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BlockVerification {
+    /// Blocks not matching their CID fail with `WnfsUtilsError::BlockIntegrity`.
+    #[default]
+    Strict,
+    /// Mismatches are logged and the block is returned anyway.
+    WarnOnly,
+    /// Blocks are returned as read, for trusted local stores where hashing costs too much.
+    Off,
+}
+
+/// How `FFIFriendlyBlockStore::put_block` names blocks, for stores shared with other IPFS
+/// tooling that expects e.g. blake3 CIDs. The default gives wnfs' own CIDs: version 1, SHA-256
+/// and the codec wnfs asks for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CidConfig {
+    pub version: Version,
+    pub hash: Code,
+    /// Codec of content blocks, which wnfs writes as raw. Node blocks keep DAG-CBOR, which
+    /// `car`, `gc` and `migrate` rely on to follow links.
+    pub leaf_codec: u64,
+}
+
+impl Default for CidConfig {
+    fn default() -> Self {
+        Self {
+            version: Version::V1,
+            hash: Code::Sha2_256,
+            leaf_codec: IpldCodec::Raw.into(),
+        }
+    }
+}
+
+impl CidConfig {
+    /// Version 0 CIDs only name DAG-PB blocks, which wnfs never writes.
+    pub fn validate(&self) -> Result<(), WnfsUtilsError> {
+        if self.version == Version::V0 {
+            return Err(WnfsUtilsError::UnsupportedCidConfig(
+                "version 0 CIDs can't name DAG-CBOR or raw blocks".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    // The CID of `data`, which wnfs named `cid`.
+    fn name(&self, cid: Cid, data: &[u8]) -> Result<Cid> {
+        if *self == Self::default() {
+            return Ok(cid);
+        }
+        let codec = match cid.codec() == u64::from(IpldCodec::Raw) {
+            true => self.leaf_codec,
+            false => cid.codec(),
+        };
+        Ok(Cid::new(self.version, codec, self.hash.digest(data))?)
+    }
+}
+
+/// See `FFIStore::pending_writeback`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PendingWriteback {
+    pub blocks: usize,
+    pub bytes: u64,
+}
+
+/// When `FFIFriendlyBlockStore::compact_if_idle` may compact, or
+/// `PrivateDirectoryHelper::maintain_if_idle` run: only after the store saw no reads or writes
+/// for `idle_after`, and at most once per `min_interval`.
+#[derive(Debug)]
+pub struct CompactionSchedule {
+    pub idle_after: Duration,
+    pub min_interval: Duration,
+    // Operation count at the last poll, and when it last changed.
+    activity: Mutex<(u64, Instant)>,
+    last_run: Mutex<Option<Instant>>,
+}
+
+impl CompactionSchedule {
+    pub fn new(idle_after: Duration, min_interval: Duration) -> Self {
+        Self {
+            idle_after,
+            min_interval,
+            activity: Mutex::new((0, Instant::now())),
+            last_run: Mutex::new(None),
+        }
+    }
+
+    fn is_due(&self, operations: u64) -> bool {
+        let now = Instant::now();
+        let mut activity = match self.activity.lock() {
+            Ok(activity) => activity,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        if activity.0 != operations {
+            *activity = (operations, now);
+            return false;
+        }
+        let mut last_run = match self.last_run.lock() {
+            Ok(last_run) => last_run,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let idle = now.duration_since(activity.1) >= self.idle_after;
+        let interval_passed =
+            last_run.map_or(true, |run| now.duration_since(run) >= self.min_interval);
+        if idle && interval_passed {
+            *last_run = Some(now);
+        }
+        idle && interval_passed
+    }
+}
+
+impl Default for CompactionSchedule {
+    fn default() -> Self {
+        Self::new(
+            Duration::from_secs(5 * 60),
+            Duration::from_secs(24 * 60 * 60),
+        )
+    }
+}
+
+// Writes held back for a single `put_many`, see `FFIFriendlyBlockStore::begin_batch`.
+#[derive(Default)]
+struct WriteBatch {
+    depth: usize,
+    blocks: Vec<(Cid, Vec<u8>)>,
+    // Position of each CID in `blocks`.
+    index: HashMap<Cid, usize>,
+}
+
 #[derive(Clone)]
 pub struct FFIFriendlyBlockStore<'a> {
     pub ffi_store: Box<dyn FFIStore<'a> + 'a>,
+    metrics: Arc<StoreMetrics>,
+    decode_limits: DecodeLimits,
+    cid_config: CidConfig,
+    verification: BlockVerification,
+    batch: Arc<Mutex<WriteBatch>>,
 }
 
 //--------------------------------------------------------------------------------------------------
@@ -41,37 +344,360 @@ pub struct FFIFriendlyBlockStore<'a> {
 impl<'a> FFIFriendlyBlockStore<'a> {
     /// Creates a new kv block store.
     pub fn new(ffi_store: Box<dyn FFIStore<'a> + 'a>) -> Self {
-        Self { ffi_store }
+        let decode_limits = DecodeLimits::default();
+        ffi_store.set_max_block_size(decode_limits.max_block_size);
+        Self {
+            ffi_store,
+            metrics: Arc::new(StoreMetrics::default()),
+            decode_limits,
+            cid_config: CidConfig::default(),
+            verification: BlockVerification::default(),
+            batch: Arc::new(Mutex::new(WriteBatch::default())),
+        }
+    }
+
+    pub fn decode_limits(&self) -> DecodeLimits {
+        self.decode_limits
+    }
+
+    /// Replaces the limits of this store. Clones made afterwards, including the one kept by a
+    /// `PrivateDirectoryHelper` created from it, inherit them. The backend is told the new
+    /// `max_block_size`, see `FFIStore::set_max_block_size`.
+    pub fn set_decode_limits(&mut self, decode_limits: DecodeLimits) {
+        self.decode_limits = decode_limits;
+        self.ffi_store
+            .set_max_block_size(decode_limits.max_block_size);
+    }
+
+    pub fn cid_config(&self) -> CidConfig {
+        self.cid_config
+    }
+
+    /// Names blocks written from now on with `cid_config`, inherited by clones like the decode
+    /// limits. Blocks already written keep their CIDs, and roots and links keep resolving.
+    pub fn set_cid_config(&mut self, cid_config: CidConfig) -> Result<(), WnfsUtilsError> {
+        cid_config.validate()?;
+        self.cid_config = cid_config;
+        Ok(())
+    }
+
+    pub fn verification(&self) -> BlockVerification {
+        self.verification
+    }
+
+    /// Replaces the verification of blocks read from now on, inherited by clones like the
+    /// decode limits.
+    pub fn set_verification(&mut self, verification: BlockVerification) {
+        self.verification = verification;
+    }
+
+    /// Counters shared by this store and all of its clones, with the cache lookups of a
+    /// `CachedBlockStore` below it, see `FFIStore::cache_stats`.
+    pub fn metrics(&self) -> StoreMetricsSnapshot {
+        let mut snapshot = self.metrics.snapshot();
+        if let Some(cache) = self.ffi_store.cache_stats() {
+            snapshot.cache_hits += cache.hits;
+            snapshot.cache_misses += cache.misses;
+        }
+        snapshot
+    }
+
+    pub fn list_blocks(&self) -> Result<Vec<Cid>> {
+        self.ffi_store
+            .list_blocks()?
+            .iter()
+            .map(|cid| Ok(cid_from_bytes(cid)?))
+            .collect()
+    }
+
+    pub fn delete_block(&self, cid: &Cid) -> Result<()> {
+        self.ffi_store.delete_block(cid.to_bytes())
+    }
+
+    pub fn block_written_at(&self, cid: &Cid) -> Result<Option<u64>> {
+        self.ffi_store.block_written_at(cid.to_bytes())
+    }
+
+    /// Compacts the underlying store, see `FFIStore::compact`. Best run after garbage collection
+    /// or pruning removed many blocks.
+    pub fn compact(&self) -> Result<u64> {
+        self.ffi_store.compact()
+    }
+
+    /// Compacts when `schedule` says the store has been idle long enough, returning the bytes
+    /// reclaimed or `None` when it wasn't due. Meant to be polled from the app's own timer,
+    /// since the store can't be moved to a background thread.
+    pub fn compact_if_idle(&self, schedule: &CompactionSchedule) -> Result<Option<u64>> {
+        if !self.is_idle(schedule) {
+            return Ok(None);
+        }
+        self.compact().map(Some)
+    }
+
+    // Whether `schedule` is due, with the reads and writes seen so far as the activity.
+    pub(crate) fn is_idle(&self, schedule: &CompactionSchedule) -> bool {
+        let metrics = self.metrics.snapshot();
+        let operations = metrics.blocks_read
+            + metrics.blocks_written
+            + metrics.read_errors
+            + metrics.write_errors;
+        schedule.is_due(operations)
+    }
+
+    /// Hints where `cid` and its subtree can be fetched, see `FFIStore::add_provider_hint`. Call
+    /// it with the forest CID and gateway learned from a share or sync peer before loading it.
+    pub fn add_provider_hint(&self, cid: &Cid, provider: &str) -> Result<()> {
+        self.ffi_store
+            .add_provider_hint(cid.to_bytes(), provider.to_string())
+    }
+
+    /// I/O of the underlying backend, see `FFIStore::io_metrics`.
+    pub fn io_metrics(&self) -> Option<IoMetricsSnapshot> {
+        self.ffi_store.io_metrics()
+    }
+
+    /// Writes the backend hasn't stored remotely yet, see `FFIStore::pending_writeback`.
+    pub fn pending_writeback(&self) -> PendingWriteback {
+        self.ffi_store.pending_writeback()
+    }
+
+    /// The live counters, for layers such as caches that record their own events.
+    pub fn metrics_handle(&self) -> Arc<StoreMetrics> {
+        Arc::clone(&self.metrics)
+    }
+
+    /// Holds back the writes of this store and its clones until the matching `flush_batch`,
+    /// which hands them to the backend in one `put_many`. Blocks held back are readable in the
+    /// meantime. Batches nest: only the outermost flush writes.
+    pub fn begin_batch(&self) {
+        self.lock_batch().depth += 1;
+    }
+
+    /// Ends a batch started with `begin_batch`, returning the number of blocks written, once
+    /// the backend stored them, see `FFIStore::flush_async`. When the backend fails, the blocks
+    /// stay held back and readable, and the next flush writes them again.
+    pub async fn flush_batch(&self) -> Result<usize> {
+        let blocks = {
+            let mut batch = self.lock_batch();
+            batch.depth = batch.depth.saturating_sub(1);
+            if batch.depth > 0 {
+                return Ok(0);
+            }
+            batch.index.clear();
+            std::mem::take(&mut batch.blocks)
+        };
+        if blocks.is_empty() {
+            return self.ffi_store.flush_async().await.map(|_| 0);
+        }
+        let count = blocks.len();
+        let sizes: Vec<usize> = blocks.iter().map(|(_, data)| data.len()).collect();
+        let written = self
+            .ffi_store
+            .put_many_async(
+                blocks
+                    .iter()
+                    .map(|(cid, data)| (cid.to_bytes(), data.to_owned()))
+                    .collect(),
+            )
+            .await;
+        // Blocks written behind, by this batch or before it, are stored before it ends.
+        let result = match written {
+            Ok(()) => self.ffi_store.flush_async().await,
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(_) => {
+                sizes
+                    .into_iter()
+                    .for_each(|size| self.metrics.record_write(size));
+                Ok(count)
+            }
+            Err(e) => {
+                trace!(
+                    "wnfsError in flush_batch of {} blocks (request {:?}): {:?}",
+                    count,
+                    request_id::current(),
+                    e.to_string()
+                );
+                self.metrics.record_write_error();
+                self.hold_back_again(blocks);
+                Err(e)
+            }
+        }
+    }
+
+    // Puts the blocks of a failed flush back in front of those held back since.
+    fn hold_back_again(&self, mut blocks: Vec<(Cid, Vec<u8>)>) {
+        let mut batch = self.lock_batch();
+        blocks.append(&mut batch.blocks);
+        batch.index.clear();
+        let mut kept = Vec::with_capacity(blocks.len());
+        for (cid, data) in blocks {
+            if !batch.index.contains_key(&cid) {
+                batch.index.insert(cid, kept.len());
+                kept.push((cid, data));
+            }
+        }
+        batch.blocks = kept;
+    }
+
+    /// Reads `cids` in one `get_many`, in order, with the checks of `get_block`.
+    pub async fn get_many(&self, cids: &[Cid]) -> Result<Vec<Bytes>> {
+        let mut found: Vec<Option<Bytes>> = cids.iter().map(|cid| self.held_back(cid)).collect();
+        let missing: Vec<Vec<u8>> = cids
+            .iter()
+            .zip(found.iter())
+            .filter(|(_, block)| block.is_none())
+            .map(|(cid, _)| cid.to_bytes())
+            .collect();
+        if !missing.is_empty() {
+            let fetched = self.ffi_store.get_many_async(missing).await.map_err(|e| {
+                trace!(
+                    "wnfsError in get_many (request {:?}): {:?}",
+                    request_id::current(),
+                    e.to_string()
+                );
+                self.metrics.record_read_error();
+                e
+            })?;
+            let mut fetched = fetched.into_iter();
+            for (cid, block) in cids.iter().zip(found.iter_mut()) {
+                if block.is_some() {
+                    continue;
+                }
+                let bytes = fetched
+                    .next()
+                    .ok_or_else(|| anyhow!("get_many returned fewer blocks than requested"))?;
+                self.check_block_size(bytes.len())?;
+                self.check_block_hash(cid, &bytes)?;
+                self.metrics.record_read(bytes.len());
+                *block = Some(Bytes::from(bytes));
+            }
+        }
+        Ok(found.into_iter().flatten().collect())
+    }
+
+    fn check_block_size(&self, size: usize) -> Result<()> {
+        if size > self.decode_limits.max_block_size {
+            self.metrics.record_read_error();
+            return Err(WnfsUtilsError::BlockTooLarge {
+                size,
+                limit: self.decode_limits.max_block_size,
+            }
+            .into());
+        }
+        Ok(())
+    }
+
+    fn check_block_hash(&self, cid: &Cid, data: &[u8]) -> Result<()> {
+        if self.verification == BlockVerification::Off {
+            return Ok(());
+        }
+        let Err(reason) = verify_block(cid, data) else {
+            return Ok(());
+        };
+        if self.verification == BlockVerification::WarnOnly {
+            warn!(
+                "wnfsutils: block {} failed verification (request {:?}): {}",
+                cid,
+                request_id::current(),
+                reason
+            );
+            return Ok(());
+        }
+        trace!(
+            "wnfsError in get_block {} (request {:?}): {}",
+            cid,
+            request_id::current(),
+            reason
+        );
+        self.metrics.record_read_error();
+        Err(WnfsUtilsError::BlockIntegrity {
+            cid: cid.to_string(),
+            reason,
+        }
+        .into())
+    }
+
+    // A block written during the current batch.
+    fn held_back(&self, cid: &Cid) -> Option<Bytes> {
+        let batch = self.lock_batch();
+        let position = *batch.index.get(cid)?;
+        Some(Bytes::copy_from_slice(&batch.blocks[position].1))
+    }
+
+    fn lock_batch(&self) -> MutexGuard<'_, WriteBatch> {
+        match self.batch.lock() {
+            Ok(batch) => batch,
+            Err(poisoned) => poisoned.into_inner(),
+        }
     }
 }
 
 #[async_trait(?Send)]
 impl<'a> BlockStore for FFIFriendlyBlockStore<'a> {
-    /// Retrieves an array of bytes from the block store with given CID.
+    /// Retrieves an array of bytes from the block store with given CID, checked against it as
+    /// the store's `BlockVerification` says.
     async fn get_block(&self, cid: &Cid) -> Result<Bytes> {
+        if let Some(bytes) = self.held_back(cid) {
+            return Ok(bytes);
+        }
         let bytes = self
             .ffi_store
-            .get_block(cid.to_bytes())
-            .map_err(|_| BlockStoreError::CIDNotFound(*cid))?;
+            .get_block_async(cid.to_bytes())
+            .await
+            .map_err(|e| {
+                trace!(
+                    "wnfsError in get_block {} (request {:?}): {:?}",
+                    cid,
+                    request_id::current(),
+                    e.to_string()
+                );
+                self.metrics.record_read_error();
+                read_error(cid, e)
+            })?;
+        self.check_block_size(bytes.len())?;
+        self.check_block_hash(cid, &bytes)?;
+        self.metrics.record_read(bytes.len());
         Ok(Bytes::copy_from_slice(&bytes))
     }
 
-    /// Stores an array of bytes in the block store.
+    /// Stores an array of bytes in the block store, under a CID following the store's
+    /// `CidConfig`.
     async fn put_block(&self, bytes: impl Into<Bytes>, codec: u64) -> Result<Cid> {
         let data: Bytes = bytes.into();
 
-        let cid_res = self.create_cid(&data, codec);
-        match cid_res.is_err() {
-            true => Err(cid_res.err().unwrap()),
-            false => {
-                let cid = cid_res.unwrap();
-                let result = self
-                    .ffi_store
-                    .put_block(cid.to_owned().to_bytes(), data.to_vec());
-                match result {
-                    Ok(_) => Ok(cid.to_owned()),
-                    Err(e) => Err(e),
+        let cid = self.create_cid(&data, codec)?;
+        let cid = self.cid_config.name(cid, &data)?;
+        {
+            let mut batch = self.lock_batch();
+            if batch.depth > 0 {
+                if !batch.index.contains_key(&cid) {
+                    let position = batch.blocks.len();
+                    batch.blocks.push((cid, data.to_vec()));
+                    batch.index.insert(cid, position);
                 }
+                return Ok(cid);
+            }
+        }
+        let result = self
+            .ffi_store
+            .put_block_async(cid.to_bytes(), data.to_vec())
+            .await;
+        match result {
+            Ok(_) => {
+                self.metrics.record_write(data.len());
+                Ok(cid)
+            }
+            Err(e) => {
+                trace!(
+                    "wnfsError in put_block {} (request {:?}): {:?}",
+                    cid,
+                    request_id::current(),
+                    e.to_string()
+                );
+                self.metrics.record_write_error();
+                Err(e)
             }
         }
     }
@@ -81,5 +707,62 @@ impl<'a> BlockStore for FFIFriendlyBlockStore<'a> {
 // Functions
 //--------------------------------------------------------------------------------------------------
 
+/// Parses CID bytes received over FFI without panicking on malformed input.
+pub fn cid_from_bytes(bytes: &[u8]) -> Result<Cid, WnfsUtilsError> {
+    Cid::try_from(bytes).map_err(|e| WnfsUtilsError::InvalidCid(e.to_string()))
+}
+
+// A missing block stays the `CIDNotFound` WNFS expects; any other failure keeps its cause, as a
+// `StoreUnavailable` unless the store gave it a code.
+fn read_error(cid: &Cid, error: anyhow::Error) -> anyhow::Error {
+    match ErrorCode::of(&error) {
+        ErrorCode::NotFound => BlockStoreError::CIDNotFound(*cid).into(),
+        ErrorCode::Other => WnfsUtilsError::StoreUnavailable(error.to_string()).into(),
+        _ => error,
+    }
+}
+
+/// Runs `future` to completion from synchronous code, on the current runtime when there is one.
+/// The sync side of stores implementing `FFIStore::get_block_async` natively. Called from async
+/// code it needs a multi-threaded runtime, and fails instead of panicking on any other.
+pub fn block_on<F: Future>(future: F) -> Result<F::Output> {
+    match tokio::runtime::Handle::try_current() {
+        #[cfg(feature = "tokio-multi-thread")]
+        Ok(handle) if handle.runtime_flavor() == tokio::runtime::RuntimeFlavor::MultiThread => {
+            Ok(tokio::task::block_in_place(|| handle.block_on(future)))
+        }
+        Ok(_) => Err(anyhow!(
+            "a blocking store call was made on a current-thread runtime"
+        )),
+        Err(_) => Ok(tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?
+            .block_on(future)),
+    }
+}
+
+mod cached;
+#[cfg(feature = "reqwest")]
+mod http_gateway;
+#[cfg(target_arch = "wasm32")]
+mod js;
+mod pipelined;
+mod prefetching;
+#[cfg(feature = "reqwest")]
+mod s3;
+mod write_ahead;
+
+pub(crate) use cached::BlockCache;
+pub use cached::{CacheStats, CachedBlockStore};
+#[cfg(feature = "reqwest")]
+pub use http_gateway::{HttpGatewayConfig, HttpGatewayStore, WriteMethod};
+#[cfg(target_arch = "wasm32")]
+pub use js::JsBlockStore;
+pub use pipelined::{UploadPipeline, UploadPipelineConfig};
+pub use prefetching::{PrefetchConfig, PrefetchStats, PrefetchingStore};
+#[cfg(feature = "reqwest")]
+pub use s3::{S3BlockStore, S3Config, S3Credentials, S3_MIN_PART_SIZE};
+pub use write_ahead::{WriteAheadConfig, WriteAheadQueue};
+
 #[cfg(test)]
 mod blockstore_tests;
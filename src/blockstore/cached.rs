@@ -0,0 +1,304 @@
+//! An in-memory LRU in front of a slower store, e.g. a gateway or a store behind the FFI.
+//!
+//! Each `CachedBlockStore` owns its cache, shared by its clones only, so helpers of different
+//! accounts or tenants never see each other's blocks. Writes go through to the inner store and
+//! are cached once it accepted them. `gateway::GatewayStore` keeps the blocks it verified in the
+//! same `BlockCache` rather than one of its own.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use anyhow::Result;
+use async_trait::async_trait;
+
+use super::{FFIStore, PendingWriteback};
+use crate::metrics::{with_hook, IoMetricsSnapshot};
+
+/// Counters of a `CachedBlockStore`, across all its clones.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub cached_blocks: usize,
+    pub cached_bytes: usize,
+}
+
+impl CacheStats {
+    /// Share of reads served from memory, `None` before the first read.
+    pub fn hit_rate(&self) -> Option<f64> {
+        let reads = self.hits + self.misses;
+        (reads > 0).then(|| self.hits as f64 / reads as f64)
+    }
+}
+
+#[derive(Default)]
+struct Lru {
+    // Block and the tick of its latest use, per CID.
+    blocks: HashMap<Vec<u8>, (Vec<u8>, u64)>,
+    // CIDs by tick of their latest use, the least recently used first.
+    order: BTreeMap<u64, Vec<u8>>,
+    tick: u64,
+    max_bytes: usize,
+    stats: CacheStats,
+}
+
+impl Lru {
+    fn get(&mut self, cid: &[u8]) -> Option<Vec<u8>> {
+        self.tick += 1;
+        let tick = self.tick;
+        let (data, used) = self.blocks.get_mut(cid)?;
+        self.order.remove(used);
+        self.order.insert(tick, cid.to_vec());
+        *used = tick;
+        Some(data.to_owned())
+    }
+
+    fn insert(&mut self, cid: Vec<u8>, data: &[u8]) {
+        self.remove(&cid);
+        if data.len() > self.max_bytes {
+            return;
+        }
+        while self.stats.cached_bytes + data.len() > self.max_bytes {
+            let Some((_, oldest)) = self.order.pop_first() else {
+                break;
+            };
+            if let Some((evicted, _)) = self.blocks.remove(&oldest) {
+                self.stats.cached_bytes -= evicted.len();
+                self.stats.evictions += 1;
+            }
+        }
+        self.tick += 1;
+        self.order.insert(self.tick, cid.to_owned());
+        self.blocks.insert(cid, (data.to_vec(), self.tick));
+        self.stats.cached_bytes += data.len();
+        self.stats.cached_blocks = self.blocks.len();
+    }
+
+    fn remove(&mut self, cid: &[u8]) {
+        if let Some((data, used)) = self.blocks.remove(cid) {
+            self.order.remove(&used);
+            self.stats.cached_bytes -= data.len();
+            self.stats.cached_blocks = self.blocks.len();
+        }
+    }
+}
+
+/// An LRU of at most `max_bytes` of blocks keyed by CID bytes. Clones share the cache.
+#[derive(Clone)]
+pub(crate) struct BlockCache(Arc<Mutex<Lru>>);
+
+impl BlockCache {
+    pub(crate) fn new(max_bytes: usize) -> Self {
+        Self(Arc::new(Mutex::new(Lru {
+            max_bytes,
+            ..Default::default()
+        })))
+    }
+
+    pub(crate) fn stats(&self) -> CacheStats {
+        self.lock().stats
+    }
+
+    pub(crate) fn clear(&self) {
+        let mut cache = self.lock();
+        cache.blocks.clear();
+        cache.order.clear();
+        cache.stats.cached_blocks = 0;
+        cache.stats.cached_bytes = 0;
+    }
+
+    /// The cached block, counted as a hit or a miss.
+    pub(crate) fn lookup(&self, cid: &[u8]) -> Option<Vec<u8>> {
+        let mut cache = self.lock();
+        let data = cache.get(cid);
+        match data {
+            Some(_) => cache.stats.hits += 1,
+            None => cache.stats.misses += 1,
+        }
+        drop(cache);
+        with_hook(|hook| hook.cache_lookup(data.is_some()));
+        data
+    }
+
+    /// Keeps `data` unless it's larger than the whole cache, evicting the least recently used
+    /// blocks to make room.
+    pub(crate) fn insert(&self, cid: Vec<u8>, data: &[u8]) {
+        self.lock().insert(cid, data)
+    }
+
+    pub(crate) fn remove(&self, cid: &[u8]) {
+        self.lock().remove(cid)
+    }
+
+    // Cached blocks of `cids`, in order, and the CIDs to fetch from the inner store.
+    fn lookup_many(&self, cids: &[Vec<u8>]) -> (Vec<Option<Vec<u8>>>, Vec<Vec<u8>>) {
+        let found: Vec<Option<Vec<u8>>> = cids.iter().map(|cid| self.lookup(cid)).collect();
+        let missing = cids
+            .iter()
+            .zip(found.iter())
+            .filter(|(_, block)| block.is_none())
+            .map(|(cid, _)| cid.to_owned())
+            .collect();
+        (found, missing)
+    }
+
+    // Puts the blocks fetched for `missing` in the gaps of `found`, caching them.
+    fn fill(&self, found: &mut [Option<Vec<u8>>], missing: Vec<Vec<u8>>, fetched: Vec<Vec<u8>>) {
+        let mut cache = self.lock();
+        let mut fetched = missing.into_iter().zip(fetched);
+        for block in found.iter_mut().filter(|block| block.is_none()) {
+            if let Some((cid, data)) = fetched.next() {
+                cache.insert(cid, &data);
+                *block = Some(data);
+            }
+        }
+    }
+
+    fn insert_all(&self, blocks: Vec<(Vec<u8>, Vec<u8>)>) {
+        let mut cache = self.lock();
+        blocks
+            .into_iter()
+            .for_each(|(cid, bytes)| cache.insert(cid, &bytes));
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Lru> {
+        match self.0.lock() {
+            Ok(cache) => cache,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+}
+
+/// Wraps `inner` with an LRU of at most `max_bytes` of blocks. Clones share the cache.
+#[derive(Clone)]
+pub struct CachedBlockStore<'a> {
+    inner: Box<dyn FFIStore<'a> + 'a>,
+    cache: BlockCache,
+}
+
+impl<'a> CachedBlockStore<'a> {
+    pub fn new(inner: Box<dyn FFIStore<'a> + 'a>, max_bytes: usize) -> Self {
+        Self {
+            inner,
+            cache: BlockCache::new(max_bytes),
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.cache.stats()
+    }
+
+    /// Drops every cached block, e.g. when the app moves to the background.
+    pub fn clear(&self) {
+        self.cache.clear()
+    }
+}
+
+#[async_trait(?Send)]
+impl<'a> FFIStore<'a> for CachedBlockStore<'a> {
+    fn get_block(&self, cid: Vec<u8>) -> Result<Vec<u8>> {
+        if let Some(data) = self.cache.lookup(&cid) {
+            return Ok(data);
+        }
+        let data = self.inner.get_block(cid.to_owned())?;
+        self.cache.insert(cid, &data);
+        Ok(data)
+    }
+
+    fn put_block(&self, cid: Vec<u8>, bytes: Vec<u8>) -> Result<()> {
+        self.inner.put_block(cid.to_owned(), bytes.to_owned())?;
+        self.cache.insert(cid, &bytes);
+        Ok(())
+    }
+
+    async fn get_block_async(&self, cid: Vec<u8>) -> Result<Vec<u8>> {
+        if let Some(data) = self.cache.lookup(&cid) {
+            return Ok(data);
+        }
+        let data = self.inner.get_block_async(cid.to_owned()).await?;
+        self.cache.insert(cid, &data);
+        Ok(data)
+    }
+
+    async fn put_block_async(&self, cid: Vec<u8>, bytes: Vec<u8>) -> Result<()> {
+        self.inner
+            .put_block_async(cid.to_owned(), bytes.to_owned())
+            .await?;
+        self.cache.insert(cid, &bytes);
+        Ok(())
+    }
+
+    fn get_many(&self, cids: Vec<Vec<u8>>) -> Result<Vec<Vec<u8>>> {
+        let (mut found, missing) = self.cache.lookup_many(&cids);
+        if !missing.is_empty() {
+            let fetched = self.inner.get_many(missing.to_owned())?;
+            self.cache.fill(&mut found, missing, fetched);
+        }
+        Ok(found.into_iter().flatten().collect())
+    }
+
+    fn put_many(&self, blocks: Vec<(Vec<u8>, Vec<u8>)>) -> Result<()> {
+        self.inner.put_many(blocks.to_owned())?;
+        self.cache.insert_all(blocks);
+        Ok(())
+    }
+
+    async fn get_many_async(&self, cids: Vec<Vec<u8>>) -> Result<Vec<Vec<u8>>> {
+        let (mut found, missing) = self.cache.lookup_many(&cids);
+        if !missing.is_empty() {
+            let fetched = self.inner.get_many_async(missing.to_owned()).await?;
+            self.cache.fill(&mut found, missing, fetched);
+        }
+        Ok(found.into_iter().flatten().collect())
+    }
+
+    async fn put_many_async(&self, blocks: Vec<(Vec<u8>, Vec<u8>)>) -> Result<()> {
+        self.inner.put_many_async(blocks.to_owned()).await?;
+        self.cache.insert_all(blocks);
+        Ok(())
+    }
+
+    async fn flush_async(&self) -> Result<()> {
+        self.inner.flush_async().await
+    }
+
+    fn list_blocks(&self) -> Result<Vec<Vec<u8>>> {
+        self.inner.list_blocks()
+    }
+
+    fn delete_block(&self, cid: Vec<u8>) -> Result<()> {
+        self.cache.remove(&cid);
+        self.inner.delete_block(cid)
+    }
+
+    fn block_written_at(&self, cid: Vec<u8>) -> Result<Option<u64>> {
+        self.inner.block_written_at(cid)
+    }
+
+    fn compact(&self) -> Result<u64> {
+        self.inner.compact()
+    }
+
+    fn set_max_block_size(&self, max_block_size: usize) {
+        self.inner.set_max_block_size(max_block_size)
+    }
+
+    fn add_provider_hint(&self, cid: Vec<u8>, provider: String) -> Result<()> {
+        self.inner.add_provider_hint(cid, provider)
+    }
+
+    fn io_metrics(&self) -> Option<IoMetricsSnapshot> {
+        self.inner.io_metrics()
+    }
+
+    fn cache_stats(&self) -> Option<CacheStats> {
+        Some(self.stats())
+    }
+
+    fn pending_writeback(&self) -> PendingWriteback {
+        self.inner.pending_writeback()
+    }
+}
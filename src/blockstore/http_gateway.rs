@@ -0,0 +1,289 @@
+//! Read-write block store over HTTP, for apps that keep their blocks behind a trustless gateway
+//! and a write endpoint such as a pinning service.
+//!
+//! Blocks are fetched from a URL template with `{cid}` standing for the CID, as
+//! `application/vnd.ipld.raw`, and checked against their CID before they are used or cached.
+//! Writes send the raw block to the write template the same way. Attempts failing with a network
+//! error, a timeout, `408`, `429` or a `5xx` are retried with exponential backoff; any other
+//! error fails at once. Unlike `gateway::GatewayStore`, which reads from the fastest of several
+//! public gateways, this store talks to one service the app controls. It reaches it the same way
+//! though, with the same DoH, proxy and compression settings, and keeps no cache of its own: wrap
+//! it in a `CachedBlockStore` to keep blocks in memory.
+
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use libipld::Cid;
+use log::trace;
+use reqwest::{
+    header::{ACCEPT, CONTENT_TYPE},
+    Client, Method, RequestBuilder, StatusCode,
+};
+
+use super::{block_on, cid_from_bytes, DecodeLimits, FFIStore};
+use crate::{
+    car::verify_block,
+    error::WnfsUtilsError,
+    gateway::{read_body, DohConfig, ProxyConfig, Transport, REQUEST_ID_HEADER},
+    request_id,
+};
+
+const RAW_BLOCK: &str = "application/vnd.ipld.raw";
+const CID_PLACEHOLDER: &str = "{cid}";
+
+// Bounds the body of a write's response, which the store discards.
+const MAX_WRITE_RESPONSE: usize = 64 * 1024;
+
+/// The HTTP method blocks are written with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriteMethod {
+    Post,
+    Put,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpGatewayConfig {
+    /// Where blocks are read from, e.g. `https://gateway.example/ipfs/{cid}`.
+    pub get_url: String,
+    /// Where blocks are written to, e.g. `https://pinning.example/blocks/{cid}`. `None` makes the
+    /// store read-only.
+    pub put_url: Option<String>,
+    pub write_method: WriteMethod,
+    /// Bound on each attempt, connecting included.
+    pub timeout: Duration,
+    /// Attempts made after the first one failed.
+    pub max_retries: u32,
+    /// Delay before the first retry, doubled for every further one up to `max_backoff`.
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// Idle connections kept open to the service.
+    pub max_idle_connections: usize,
+}
+
+impl HttpGatewayConfig {
+    /// Reads from `get_url`, with no write endpoint and the default retries and limits.
+    pub fn read_only(get_url: &str) -> Self {
+        Self {
+            get_url: get_url.to_string(),
+            put_url: None,
+            write_method: WriteMethod::Put,
+            timeout: Duration::from_secs(30),
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(200),
+            max_backoff: Duration::from_secs(5),
+            max_idle_connections: 8,
+        }
+    }
+
+    /// Like `read_only`, writing to `put_url` with `write_method`.
+    pub fn read_write(get_url: &str, put_url: &str, write_method: WriteMethod) -> Self {
+        Self {
+            put_url: Some(put_url.to_string()),
+            write_method,
+            ..Self::read_only(get_url)
+        }
+    }
+
+    // Delay before retry number `retry`, counting from 0.
+    pub(super) fn backoff(&self, retry: u32) -> Duration {
+        self.initial_backoff
+            .saturating_mul(2u32.saturating_pow(retry))
+            .min(self.max_backoff)
+    }
+}
+
+/// Block store over one HTTP service, see [`HttpGatewayConfig`]. Clones share the connection
+/// pool.
+#[derive(Clone)]
+pub struct HttpGatewayStore {
+    config: Arc<HttpGatewayConfig>,
+    client: Client,
+    transport: Transport,
+    // Largest block read, from the `DecodeLimits` of the `FFIFriendlyBlockStore` on top.
+    max_block_size: Arc<AtomicUsize>,
+}
+
+impl HttpGatewayStore {
+    /// Validates the URL templates of `config` and builds the connection pool.
+    pub fn new(config: HttpGatewayConfig) -> Result<Self> {
+        check_template(&config.get_url)?;
+        if let Some(put_url) = &config.put_url {
+            check_template(put_url)?;
+        }
+        let transport = Transport::default();
+        Ok(Self {
+            client: Self::client(&config, &transport)?,
+            config: Arc::new(config),
+            transport,
+            max_block_size: Arc::new(AtomicUsize::new(DecodeLimits::default().max_block_size)),
+        })
+    }
+
+    /// Resolves the service's hostname over DNS-over-HTTPS, see
+    /// `GatewayStore::with_dns_resolver`.
+    pub fn with_dns_resolver(mut self, config: &DohConfig) -> Result<Self> {
+        self.transport
+            .set_dns_resolver(config, self.config.timeout)?;
+        self.rebuild_client()
+    }
+
+    /// Sends every request through a proxy, see `GatewayStore::with_proxy`.
+    pub fn with_proxy(mut self, config: &ProxyConfig) -> Result<Self> {
+        self.transport.set_proxy(config, self.config.timeout)?;
+        self.rebuild_client()
+    }
+
+    /// Accepts compressed responses, see `GatewayStore::with_compression`.
+    pub fn with_compression(mut self, enabled: bool) -> Result<Self> {
+        self.transport.compression = enabled;
+        self.rebuild_client()
+    }
+
+    fn rebuild_client(mut self) -> Result<Self> {
+        self.client = Self::client(&self.config, &self.transport)?;
+        Ok(self)
+    }
+
+    fn client(config: &HttpGatewayConfig, transport: &Transport) -> Result<Client> {
+        Ok(transport
+            .builder(config.timeout)?
+            .pool_max_idle_per_host(config.max_idle_connections)
+            .build()?)
+    }
+
+    pub fn config(&self) -> &HttpGatewayConfig {
+        &self.config
+    }
+
+    // Sends the request built by `request` until an attempt succeeds or fails for good,
+    // returning the response body, which fails past `limit` bytes.
+    async fn fetch(&self, request: impl Fn() -> RequestBuilder, limit: usize) -> Result<Vec<u8>> {
+        let mut retry = 0;
+        loop {
+            let mut builder = request();
+            if let Some(id) = request_id::current() {
+                builder = builder.header(REQUEST_ID_HEADER, id);
+            }
+            let error = match builder.send().await {
+                Ok(response) if response.status().is_success() => {
+                    match read_body(response, limit).await {
+                        Ok(body) => return Ok(body),
+                        Err(e) if e.downcast_ref::<WnfsUtilsError>().is_some() => return Err(e),
+                        Err(e) => e,
+                    }
+                }
+                Ok(response) if response.status() == StatusCode::NOT_FOUND => {
+                    return Err(WnfsUtilsError::NotFound(response.url().to_string()).into())
+                }
+                Ok(response) => {
+                    let error = anyhow!("{} returned {}", response.url(), response.status());
+                    if !is_transient(response.status()) {
+                        return Err(error);
+                    }
+                    error
+                }
+                Err(e) if e.is_builder() => return Err(e.into()),
+                Err(e) => anyhow!(e),
+            };
+            if retry >= self.config.max_retries {
+                return Err(WnfsUtilsError::StoreUnavailable(error.to_string()).into());
+            }
+            let delay = self.config.backoff(retry);
+            trace!("http gateway: retrying in {:?} after {}", delay, error);
+            crate::timer::sleep(delay).await;
+            retry += 1;
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl<'a> FFIStore<'a> for HttpGatewayStore {
+    fn get_block(&self, cid: Vec<u8>) -> Result<Vec<u8>> {
+        block_on(self.get_block_async(cid))?
+    }
+
+    fn put_block(&self, cid: Vec<u8>, bytes: Vec<u8>) -> Result<()> {
+        block_on(self.put_block_async(cid, bytes))?
+    }
+
+    async fn get_block_async(&self, cid: Vec<u8>) -> Result<Vec<u8>> {
+        let cid = cid_from_bytes(&cid)?;
+        let url = block_url(&self.config.get_url, &cid);
+        let limit = self.max_block_size.load(Ordering::Relaxed);
+        let data = self
+            .fetch(|| self.client.get(&url).header(ACCEPT, RAW_BLOCK), limit)
+            .await
+            .map_err(|e| {
+                trace!("wnfsError in http gateway get_block {}: {}", cid, e);
+                e
+            })?;
+        verify_block(&cid, &data).map_err(|e| anyhow!(e))?;
+        Ok(data)
+    }
+
+    async fn put_block_async(&self, cid: Vec<u8>, bytes: Vec<u8>) -> Result<()> {
+        let put_url = self
+            .config
+            .put_url
+            .as_ref()
+            .ok_or_else(|| anyhow!("the HTTP gateway store has no write endpoint"))?;
+        let cid = cid_from_bytes(&cid)?;
+        let url = block_url(put_url, &cid);
+        let method = match self.config.write_method {
+            WriteMethod::Post => Method::POST,
+            WriteMethod::Put => Method::PUT,
+        };
+        self.fetch(
+            || {
+                self.client
+                    .request(method.to_owned(), &url)
+                    .header(CONTENT_TYPE, RAW_BLOCK)
+                    .body(bytes.to_owned())
+            },
+            MAX_WRITE_RESPONSE,
+        )
+        .await
+        .map_err(|e| {
+            trace!("wnfsError in http gateway put_block {}: {}", cid, e);
+            e
+        })?;
+        Ok(())
+    }
+
+    fn set_max_block_size(&self, max_block_size: usize) {
+        self.max_block_size.store(max_block_size, Ordering::Relaxed);
+    }
+}
+
+fn is_transient(status: StatusCode) -> bool {
+    status.is_server_error()
+        || status == StatusCode::TOO_MANY_REQUESTS
+        || status == StatusCode::REQUEST_TIMEOUT
+}
+
+fn check_template(template: &str) -> Result<()> {
+    if !template.contains(CID_PLACEHOLDER) {
+        return Err(anyhow!(
+            "{} has no {} placeholder",
+            template,
+            CID_PLACEHOLDER
+        ));
+    }
+    let url = reqwest::Url::parse(&template.replace(CID_PLACEHOLDER, "cid"))
+        .map_err(|e| anyhow!("invalid block URL {}: {}", template, e))?;
+    match url.scheme() {
+        "http" | "https" => Ok(()),
+        scheme => Err(anyhow!("unsupported scheme {} in {}", scheme, template)),
+    }
+}
+
+fn block_url(template: &str, cid: &Cid) -> String {
+    template.replace(CID_PLACEHOLDER, &cid.to_string())
+}
@@ -0,0 +1,116 @@
+//! Block store over JavaScript callbacks, for browser apps running the same helper as mobile with
+//! their blocks in IndexedDB, behind `fetch` or anywhere else JS reaches.
+//!
+//! `getBlock(cid)` and `putBlock(cid, bytes)` get the CID and the block as `Uint8Array`s and may
+//! return a `Promise`. `getBlock` gives the block, or `null` or `undefined` if it is missing; a
+//! thrown error or a rejected promise fails the call. Blocks are checked against their CID before
+//! they are used. The helper's async methods await the promises: the `synced_*` calls can't block
+//! a browser thread, and fail for callbacks returning one.
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use js_sys::{Function, Promise, Uint8Array};
+use log::trace;
+use wasm_bindgen::{prelude::wasm_bindgen, JsCast, JsValue};
+use wasm_bindgen_futures::JsFuture;
+
+use super::{cid_from_bytes, FFIStore};
+use crate::{car::verify_block, error::WnfsUtilsError};
+
+#[wasm_bindgen]
+#[derive(Clone)]
+pub struct JsBlockStore {
+    get_block: Function,
+    put_block: Function,
+}
+
+#[wasm_bindgen]
+impl JsBlockStore {
+    #[wasm_bindgen(constructor)]
+    pub fn new(get_block: Function, put_block: Function) -> Self {
+        Self {
+            get_block,
+            put_block,
+        }
+    }
+}
+
+impl JsBlockStore {
+    fn call_get(&self, cid: &[u8]) -> Result<JsValue> {
+        self.get_block
+            .call1(&JsValue::NULL, &Uint8Array::from(cid))
+            .map_err(js_error)
+    }
+
+    fn call_put(&self, cid: &[u8], bytes: &[u8]) -> Result<JsValue> {
+        self.put_block
+            .call2(
+                &JsValue::NULL,
+                &Uint8Array::from(cid),
+                &Uint8Array::from(bytes),
+            )
+            .map_err(js_error)
+    }
+}
+
+#[async_trait(?Send)]
+impl<'a> FFIStore<'a> for JsBlockStore {
+    fn get_block(&self, cid: Vec<u8>) -> Result<Vec<u8>> {
+        let value = not_a_promise(self.call_get(&cid)?)?;
+        block_from(&cid, value)
+    }
+
+    fn put_block(&self, cid: Vec<u8>, bytes: Vec<u8>) -> Result<()> {
+        not_a_promise(self.call_put(&cid, &bytes)?)?;
+        Ok(())
+    }
+
+    async fn get_block_async(&self, cid: Vec<u8>) -> Result<Vec<u8>> {
+        let value = settle(self.call_get(&cid)?).await?;
+        block_from(&cid, value)
+    }
+
+    async fn put_block_async(&self, cid: Vec<u8>, bytes: Vec<u8>) -> Result<()> {
+        settle(self.call_put(&cid, &bytes)?).await?;
+        Ok(())
+    }
+}
+
+fn block_from(cid: &[u8], value: JsValue) -> Result<Vec<u8>> {
+    let cid = cid_from_bytes(cid)?;
+    if value.is_null() || value.is_undefined() {
+        trace!("wnfsError in js get_block: {} not found", cid);
+        return Err(WnfsUtilsError::NotFound(format!("block {}", cid)).into());
+    }
+    let block = value
+        .dyn_into::<Uint8Array>()
+        .map_err(|_| anyhow!("getBlock returned something other than a Uint8Array"))?
+        .to_vec();
+    verify_block(&cid, &block).map_err(|e| anyhow!(e))?;
+    Ok(block)
+}
+
+// Awaits `value` if it is a promise.
+async fn settle(value: JsValue) -> Result<JsValue> {
+    JsFuture::from(Promise::resolve(&value))
+        .await
+        .map_err(js_error)
+}
+
+fn not_a_promise(value: JsValue) -> Result<JsValue> {
+    match value.is_instance_of::<Promise>() {
+        true => Err(anyhow!(
+            "the JS block store is asynchronous, use the helper's async methods"
+        )),
+        false => Ok(value),
+    }
+}
+
+fn js_error(value: JsValue) -> anyhow::Error {
+    let message = match value.dyn_ref::<js_sys::Error>() {
+        Some(error) => String::from(error.message()),
+        None => value.as_string().unwrap_or_else(|| format!("{:?}", value)),
+    };
+    trace!("wnfsError in js block store: {}", message);
+    WnfsUtilsError::StoreUnavailable(format!("JS callback failed: {}", message)).into()
+}
@@ -0,0 +1,283 @@
+//! Bounded write-behind for slow remote stores.
+//!
+//! A remote store that uploads each block before returning makes every write wait a round trip,
+//! while buffering writes instead grows without bound when the link is slower than the app.
+//! [`UploadPipeline`] sits in between: it accepts a block, starts its upload and returns, with
+//! up to `max_in_flight_blocks` uploads running at once. Once the accepted blocks not yet
+//! uploaded add up to `max_in_flight_bytes`, the next write waits for uploads to complete, so a
+//! fast writer is slowed down to the speed of the link instead of filling memory.
+//!
+//! Uploads make progress while a write or flush is awaited, which the helper's write path does
+//! for every block. Blocks in flight are served to reads from memory. `FFIFriendlyBlockStore`
+//! waits for all uploads at the end of every batch, see `FFIStore::flush_async`, so a committed
+//! root is stored in full. A failed upload is retried by the next flush and reported by it if
+//! it fails again. The sync `put_block` writes through.
+
+use std::{
+    cell::RefCell,
+    collections::{HashMap, VecDeque},
+    rc::Rc,
+};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use futures::{
+    future::{poll_fn, LocalBoxFuture},
+    stream::FuturesUnordered,
+    FutureExt, StreamExt,
+};
+use libipld::Cid;
+use log::trace;
+
+use super::{cid_from_bytes, CacheStats, FFIStore, PendingWriteback};
+use crate::metrics::IoMetricsSnapshot;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UploadPipelineConfig {
+    /// Bytes of accepted blocks not yet uploaded before writes wait.
+    pub max_in_flight_bytes: usize,
+    /// Uploads running at once.
+    pub max_in_flight_blocks: usize,
+}
+
+impl Default for UploadPipelineConfig {
+    fn default() -> Self {
+        Self {
+            max_in_flight_bytes: 8 * 1024 * 1024,
+            max_in_flight_blocks: 4,
+        }
+    }
+}
+
+type Upload<'a> = LocalBoxFuture<'a, (Cid, Result<()>)>;
+
+#[derive(Default)]
+struct PipelineState<'a> {
+    running: FuturesUnordered<Upload<'a>>,
+    // Blocks accepted and not yet uploaded: running, queued or failed.
+    pending: HashMap<Cid, Vec<u8>>,
+    pending_bytes: usize,
+    queued: VecDeque<Cid>,
+    failed: Vec<Cid>,
+    last_error: Option<String>,
+}
+
+/// Write-behind in front of `inner`, see [`UploadPipelineConfig`]. Clones share the uploads in
+/// flight.
+#[derive(Clone)]
+pub struct UploadPipeline<'a> {
+    inner: Box<dyn FFIStore<'a> + 'a>,
+    config: UploadPipelineConfig,
+    state: Rc<RefCell<PipelineState<'a>>>,
+}
+
+impl<'a> UploadPipeline<'a> {
+    pub fn new(inner: Box<dyn FFIStore<'a> + 'a>, config: UploadPipelineConfig) -> Self {
+        Self {
+            inner,
+            config,
+            state: Rc::new(RefCell::new(PipelineState::default())),
+        }
+    }
+
+    /// Bytes of accepted blocks not yet uploaded.
+    pub fn pending_bytes(&self) -> usize {
+        self.state.borrow().pending_bytes
+    }
+
+    /// Blocks accepted and not yet uploaded, e.g. for `DaemonStatus::set_pending_writeback`.
+    pub fn pending_blocks(&self) -> usize {
+        self.state.borrow().pending.len()
+    }
+
+    // Starts queued uploads while fewer than `max_in_flight_blocks` are running.
+    fn start_queued(&self) {
+        let mut state = self.state.borrow_mut();
+        while state.running.len() < self.config.max_in_flight_blocks.max(1) {
+            let Some(cid) = state.queued.pop_front() else {
+                break;
+            };
+            let Some(data) = state.pending.get(&cid).cloned() else {
+                continue;
+            };
+            let store = self.inner.to_owned();
+            state.running.push(
+                async move {
+                    let result = store.put_block_async(cid.to_bytes(), data).await;
+                    (cid, result)
+                }
+                .boxed_local(),
+            );
+        }
+    }
+
+    // The next upload to complete, `None` when none is running. The state is only borrowed
+    // while polling, so readers can look at it in between.
+    async fn next_completed(&self) -> Option<(Cid, Result<()>)> {
+        poll_fn(|cx| self.state.borrow_mut().running.poll_next_unpin(cx)).await
+    }
+
+    fn complete(&self, cid: Cid, result: Result<()>) {
+        let mut state = self.state.borrow_mut();
+        match result {
+            Ok(()) => {
+                if let Some(data) = state.pending.remove(&cid) {
+                    state.pending_bytes -= data.len();
+                }
+            }
+            Err(e) => {
+                trace!("wnfsError in upload pipeline for {}: {:?}", cid, e);
+                state.failed.push(cid);
+                state.last_error = Some(e.to_string());
+            }
+        }
+    }
+
+    // Records the uploads that completed without waiting for any.
+    fn reap_completed(&self) {
+        while let Some(Some((cid, result))) = self.next_completed().now_or_never() {
+            self.complete(cid, result);
+            self.start_queued();
+        }
+    }
+
+    fn lookup(&self, cid: &[u8]) -> Result<Option<Vec<u8>>> {
+        let cid = cid_from_bytes(cid)?;
+        Ok(self.state.borrow().pending.get(&cid).cloned())
+    }
+}
+
+#[async_trait(?Send)]
+impl<'a> FFIStore<'a> for UploadPipeline<'a> {
+    fn get_block(&self, cid: Vec<u8>) -> Result<Vec<u8>> {
+        match self.lookup(&cid)? {
+            Some(data) => Ok(data),
+            None => self.inner.get_block(cid),
+        }
+    }
+
+    fn put_block(&self, cid: Vec<u8>, bytes: Vec<u8>) -> Result<()> {
+        self.inner.put_block(cid, bytes)
+    }
+
+    async fn get_block_async(&self, cid: Vec<u8>) -> Result<Vec<u8>> {
+        self.reap_completed();
+        match self.lookup(&cid)? {
+            Some(data) => Ok(data),
+            None => self.inner.get_block_async(cid).await,
+        }
+    }
+
+    async fn put_block_async(&self, cid: Vec<u8>, bytes: Vec<u8>) -> Result<()> {
+        let cid = cid_from_bytes(&cid)?;
+        {
+            let mut state = self.state.borrow_mut();
+            if state.pending.contains_key(&cid) {
+                return Ok(());
+            }
+            state.pending_bytes += bytes.len();
+            state.pending.insert(cid, bytes);
+            state.queued.push_back(cid);
+        }
+        self.start_queued();
+        self.reap_completed();
+        while self.pending_bytes() > self.config.max_in_flight_bytes {
+            match self.next_completed().await {
+                Some((cid, result)) => {
+                    self.complete(cid, result);
+                    self.start_queued();
+                }
+                // Only failed blocks are left: the remote is unreachable, stop accepting more.
+                None => {
+                    let state = self.state.borrow();
+                    return Err(anyhow!(
+                        "{} blocks waiting for upload: {}",
+                        state.failed.len(),
+                        state.last_error.to_owned().unwrap_or_default()
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn put_many(&self, blocks: Vec<(Vec<u8>, Vec<u8>)>) -> Result<()> {
+        self.inner.put_many(blocks)
+    }
+
+    async fn put_many_async(&self, blocks: Vec<(Vec<u8>, Vec<u8>)>) -> Result<()> {
+        for (cid, bytes) in blocks {
+            self.put_block_async(cid, bytes).await?;
+        }
+        Ok(())
+    }
+
+    async fn flush_async(&self) -> Result<()> {
+        {
+            let mut state = self.state.borrow_mut();
+            let failed = std::mem::take(&mut state.failed);
+            state.queued.extend(failed);
+            state.last_error = None;
+        }
+        self.start_queued();
+        while let Some((cid, result)) = self.next_completed().await {
+            self.complete(cid, result);
+            self.start_queued();
+        }
+        let failed = {
+            let state = self.state.borrow();
+            (!state.failed.is_empty()).then(|| {
+                anyhow!(
+                    "{} blocks failed to upload: {}",
+                    state.failed.len(),
+                    state.last_error.to_owned().unwrap_or_default()
+                )
+            })
+        };
+        match failed {
+            Some(e) => Err(e),
+            None => self.inner.flush_async().await,
+        }
+    }
+
+    fn list_blocks(&self) -> Result<Vec<Vec<u8>>> {
+        self.inner.list_blocks()
+    }
+
+    fn delete_block(&self, cid: Vec<u8>) -> Result<()> {
+        self.inner.delete_block(cid)
+    }
+
+    fn block_written_at(&self, cid: Vec<u8>) -> Result<Option<u64>> {
+        self.inner.block_written_at(cid)
+    }
+
+    fn compact(&self) -> Result<u64> {
+        self.inner.compact()
+    }
+
+    fn set_max_block_size(&self, max_block_size: usize) {
+        self.inner.set_max_block_size(max_block_size)
+    }
+
+    fn add_provider_hint(&self, cid: Vec<u8>, provider: String) -> Result<()> {
+        self.inner.add_provider_hint(cid, provider)
+    }
+
+    fn io_metrics(&self) -> Option<IoMetricsSnapshot> {
+        self.inner.io_metrics()
+    }
+
+    fn cache_stats(&self) -> Option<CacheStats> {
+        self.inner.cache_stats()
+    }
+
+    /// The uploads not completed yet, plus what `inner` holds back itself.
+    fn pending_writeback(&self) -> PendingWriteback {
+        let inner = self.inner.pending_writeback();
+        PendingWriteback {
+            blocks: self.pending_blocks() + inner.blocks,
+            bytes: self.pending_bytes() as u64 + inner.bytes,
+        }
+    }
+}
@@ -0,0 +1,359 @@
+//! Fetching the children of a directory before they are read, for remote stores.
+//!
+//! Loading a tree walks it one node at a time: the next block is only asked for once the one
+//! linking to it was decoded, so over HTTP every level of a deep directory waits a round trip
+//! per block. [`PrefetchingStore`] looks at the HAMT nodes of the private forest it returns and
+//! starts fetching their child nodes right away, with up to `parallelism` fetches running at
+//! once, so the forest's index is in memory or on its way by the time it's searched. The
+//! private nodes stored in the HAMT's buckets are left alone: they're ciphertext of every
+//! revision of every file and directory, most of which are never read.
+//!
+//! Prefetches make progress while a read is awaited. At most `max_queued` links wait for a free
+//! fetch; links found beyond that aren't prefetched. Prefetched blocks are kept until they are
+//! read, up to `max_prefetched_bytes`, after which the oldest are dropped. A failed prefetch is
+//! forgotten, and the read of the block asks `inner` again. Writes go through to `inner`.
+
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet, VecDeque},
+    rc::Rc,
+    task::{Context, Poll},
+};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use futures::{
+    future::{poll_fn, LocalBoxFuture},
+    stream::FuturesUnordered,
+    FutureExt, StreamExt,
+};
+use libipld::{cbor::DagCborCodec, codec::Codec, Cid, Ipld, IpldCodec};
+use log::trace;
+
+use super::{cid_from_bytes, CacheStats, FFIStore, PendingWriteback};
+use crate::metrics::IoMetricsSnapshot;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PrefetchConfig {
+    /// Prefetches running at once.
+    pub parallelism: usize,
+    /// Bytes of prefetched blocks kept until they are read.
+    pub max_prefetched_bytes: usize,
+    /// Links waiting for a prefetch to start.
+    pub max_queued: usize,
+}
+
+impl Default for PrefetchConfig {
+    fn default() -> Self {
+        Self {
+            parallelism: 8,
+            max_prefetched_bytes: 16 * 1024 * 1024,
+            max_queued: 1024,
+        }
+    }
+}
+
+/// Counters of a `PrefetchingStore`, across all its clones.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PrefetchStats {
+    /// Blocks fetched before they were read.
+    pub prefetched: u64,
+    /// Reads served by a prefetch, completed or still running.
+    pub hits: u64,
+    /// Prefetched blocks dropped unread to stay within `max_prefetched_bytes`.
+    pub dropped: u64,
+    pub failed: u64,
+}
+
+type Fetch<'a> = LocalBoxFuture<'a, (Cid, Result<Vec<u8>>)>;
+
+#[derive(Default)]
+struct PrefetchState<'a> {
+    running: FuturesUnordered<Fetch<'a>>,
+    in_flight: HashSet<Cid>,
+    // Links waiting for a free fetch, in the order they were found, and the same as a set.
+    queued: VecDeque<Cid>,
+    queued_set: HashSet<Cid>,
+    // Prefetched blocks not read yet, and their CIDs in the order they arrived.
+    fetched: HashMap<Cid, Vec<u8>>,
+    arrived: VecDeque<Cid>,
+    fetched_bytes: usize,
+    stats: PrefetchStats,
+}
+
+impl<'a> PrefetchState<'a> {
+    fn is_known(&self, cid: &Cid) -> bool {
+        self.fetched.contains_key(cid)
+            || self.in_flight.contains(cid)
+            || self.queued_set.contains(cid)
+    }
+
+    fn dequeue(&mut self, cid: &Cid) {
+        if self.queued_set.remove(cid) {
+            self.queued.retain(|queued| queued != cid);
+        }
+    }
+
+    fn take(&mut self, cid: &Cid) -> Option<Vec<u8>> {
+        let data = self.fetched.remove(cid)?;
+        self.fetched_bytes -= data.len();
+        self.arrived.retain(|arrived| arrived != cid);
+        Some(data)
+    }
+}
+
+/// Prefetching in front of `inner`, see [`PrefetchConfig`]. Clones share the prefetched blocks.
+#[derive(Clone)]
+pub struct PrefetchingStore<'a> {
+    inner: Box<dyn FFIStore<'a> + 'a>,
+    config: PrefetchConfig,
+    state: Rc<RefCell<PrefetchState<'a>>>,
+}
+
+impl<'a> PrefetchingStore<'a> {
+    pub fn new(inner: Box<dyn FFIStore<'a> + 'a>, config: PrefetchConfig) -> Self {
+        Self {
+            inner,
+            config,
+            state: Rc::new(RefCell::new(PrefetchState::default())),
+        }
+    }
+
+    pub fn stats(&self) -> PrefetchStats {
+        self.state.borrow().stats
+    }
+
+    // Queues the HAMT child nodes `data` links to that aren't prefetched yet.
+    fn queue_links(&self, cid: &Cid, data: &[u8]) {
+        if cid.codec() != u64::from(IpldCodec::DagCbor) {
+            return;
+        }
+        let ipld: Ipld = match DagCborCodec.decode(data) {
+            Ok(ipld) => ipld,
+            Err(_) => return,
+        };
+        let mut state = self.state.borrow_mut();
+        for link in hamt_children(&ipld) {
+            if state.queued.len() >= self.config.max_queued {
+                break;
+            }
+            if !state.is_known(&link) {
+                state.queued.push_back(link);
+                state.queued_set.insert(link);
+            }
+        }
+    }
+
+    // Starts queued prefetches while fewer than `parallelism` are running.
+    fn start_queued(&self) {
+        let mut state = self.state.borrow_mut();
+        while state.running.len() < self.config.parallelism.max(1) {
+            let cid = match state.queued.pop_front() {
+                Some(cid) => cid,
+                None => break,
+            };
+            state.queued_set.remove(&cid);
+            let store = self.inner.to_owned();
+            state.in_flight.insert(cid);
+            state.running.push(
+                async move {
+                    let result = store.get_block_async(cid.to_bytes()).await;
+                    (cid, result)
+                }
+                .boxed_local(),
+            );
+        }
+    }
+
+    // The next prefetch to complete, `None` when none is running. The state is only borrowed
+    // while polling, so readers can look at it in between.
+    async fn next_completed(&self) -> Option<(Cid, Result<Vec<u8>>)> {
+        poll_fn(|cx| self.state.borrow_mut().running.poll_next_unpin(cx)).await
+    }
+
+    fn complete(&self, cid: Cid, result: Result<Vec<u8>>) {
+        let mut state = self.state.borrow_mut();
+        state.in_flight.remove(&cid);
+        let data = match result {
+            Ok(data) => data,
+            Err(e) => {
+                trace!("wnfsError in prefetch of {}: {:?}", cid, e);
+                state.stats.failed += 1;
+                return;
+            }
+        };
+        if data.len() > self.config.max_prefetched_bytes {
+            state.stats.dropped += 1;
+            return;
+        }
+        while state.fetched_bytes + data.len() > self.config.max_prefetched_bytes {
+            let oldest = match state.arrived.pop_front() {
+                Some(oldest) => oldest,
+                None => break,
+            };
+            if let Some(dropped) = state.fetched.remove(&oldest) {
+                state.fetched_bytes -= dropped.len();
+                state.stats.dropped += 1;
+            }
+        }
+        state.stats.prefetched += 1;
+        state.fetched_bytes += data.len();
+        state.arrived.push_back(cid);
+        state.fetched.insert(cid, data);
+    }
+
+    // Records the prefetches that completed, registering `cx` to be woken by the next one.
+    fn poll_prefetches(&self, cx: &mut Context<'_>) {
+        loop {
+            let next = self.state.borrow_mut().running.poll_next_unpin(cx);
+            match next {
+                Poll::Ready(Some((cid, result))) => {
+                    self.complete(cid, result);
+                    self.start_queued();
+                }
+                _ => break,
+            }
+        }
+    }
+
+    // Reads `cid` from a completed or running prefetch, `None` when it wasn't prefetched or the
+    // prefetch failed.
+    async fn prefetched(&self, cid: &Cid) -> Option<Vec<u8>> {
+        loop {
+            if let Some(data) = self.state.borrow_mut().take(cid) {
+                return Some(data);
+            }
+            if !self.state.borrow().in_flight.contains(cid) {
+                return None;
+            }
+            let (completed, result) = self.next_completed().await?;
+            self.complete(completed, result);
+            self.start_queued();
+        }
+    }
+
+    // Reads `cid` from `inner`, with the prefetches making progress meanwhile.
+    async fn fetch(&self, cid: &Cid) -> Result<Vec<u8>> {
+        self.state.borrow_mut().dequeue(cid);
+        let mut read = self.inner.get_block_async(cid.to_bytes());
+        poll_fn(|cx| match read.poll_unpin(cx) {
+            Poll::Ready(result) => Poll::Ready(result),
+            Poll::Pending => {
+                self.poll_prefetches(cx);
+                Poll::Pending
+            }
+        })
+        .await
+    }
+}
+
+#[async_trait(?Send)]
+impl<'a> FFIStore<'a> for PrefetchingStore<'a> {
+    fn get_block(&self, cid: Vec<u8>) -> Result<Vec<u8>> {
+        let prefetched = self.state.borrow_mut().take(&cid_from_bytes(&cid)?);
+        match prefetched {
+            Some(data) => {
+                self.state.borrow_mut().stats.hits += 1;
+                Ok(data)
+            }
+            None => self.inner.get_block(cid),
+        }
+    }
+
+    fn put_block(&self, cid: Vec<u8>, bytes: Vec<u8>) -> Result<()> {
+        self.inner.put_block(cid, bytes)
+    }
+
+    async fn get_block_async(&self, cid: Vec<u8>) -> Result<Vec<u8>> {
+        let cid = cid_from_bytes(&cid)?;
+        let data = match self.prefetched(&cid).await {
+            Some(data) => {
+                self.state.borrow_mut().stats.hits += 1;
+                data
+            }
+            None => self.fetch(&cid).await?,
+        };
+        self.queue_links(&cid, &data);
+        self.start_queued();
+        Ok(data)
+    }
+
+    async fn put_block_async(&self, cid: Vec<u8>, bytes: Vec<u8>) -> Result<()> {
+        self.inner.put_block_async(cid, bytes).await
+    }
+
+    fn put_many(&self, blocks: Vec<(Vec<u8>, Vec<u8>)>) -> Result<()> {
+        self.inner.put_many(blocks)
+    }
+
+    async fn put_many_async(&self, blocks: Vec<(Vec<u8>, Vec<u8>)>) -> Result<()> {
+        self.inner.put_many_async(blocks).await
+    }
+
+    async fn flush_async(&self) -> Result<()> {
+        self.inner.flush_async().await
+    }
+
+    fn list_blocks(&self) -> Result<Vec<Vec<u8>>> {
+        self.inner.list_blocks()
+    }
+
+    fn delete_block(&self, cid: Vec<u8>) -> Result<()> {
+        self.state.borrow_mut().take(&cid_from_bytes(&cid)?);
+        self.inner.delete_block(cid)
+    }
+
+    fn block_written_at(&self, cid: Vec<u8>) -> Result<Option<u64>> {
+        self.inner.block_written_at(cid)
+    }
+
+    fn compact(&self) -> Result<u64> {
+        self.inner.compact()
+    }
+
+    fn set_max_block_size(&self, max_block_size: usize) {
+        self.inner.set_max_block_size(max_block_size)
+    }
+
+    fn add_provider_hint(&self, cid: Vec<u8>, provider: String) -> Result<()> {
+        self.inner.add_provider_hint(cid, provider)
+    }
+
+    fn io_metrics(&self) -> Option<IoMetricsSnapshot> {
+        self.inner.io_metrics()
+    }
+
+    fn cache_stats(&self) -> Option<CacheStats> {
+        self.inner.cache_stats()
+    }
+
+    fn pending_writeback(&self) -> PendingWriteback {
+        self.inner.pending_writeback()
+    }
+}
+
+// The child nodes linked from a HAMT node, or from the root of a serialized HAMT, which holds
+// its root node inline or as a link. A node is its bitmask and its pointers: links to child
+// nodes, or buckets of key-value pairs that aren't followed.
+fn hamt_children(ipld: &Ipld) -> Vec<Cid> {
+    match ipld {
+        Ipld::Map(map) if matches!(map.get("structure"), Some(Ipld::String(s)) if s == "hamt") => {
+            match map.get("root") {
+                Some(Ipld::Link(root)) => vec![*root],
+                Some(root) => hamt_children(root),
+                None => Vec::new(),
+            }
+        }
+        Ipld::List(node) => match node.as_slice() {
+            [Ipld::Bytes(_), Ipld::List(pointers)] => pointers
+                .iter()
+                .filter_map(|pointer| match pointer {
+                    Ipld::Link(child) => Some(*child),
+                    _ => None,
+                })
+                .collect(),
+            _ => Vec::new(),
+        },
+        _ => Vec::new(),
+    }
+}
@@ -0,0 +1,689 @@
+//! Block store over S3-compatible object storage such as MinIO, Backblaze B2 or AWS S3, for
+//! self-hosters backing a forest with a bucket instead of an IPFS gateway.
+//!
+//! Each block is an object named by its CID, as a string, under a configurable prefix.
+//! Requests are signed with AWS Signature Version 4 and sent with the DoH, proxy and compression
+//! settings of the other remote stores, see `gateway::GatewayStore`. Failed attempts are retried
+//! like those of `HttpGatewayStore`. Blocks are checked against their CID before they are used,
+//! and bodies larger than the block size limit of the `FFIFriendlyBlockStore` on top are refused
+//! before they're read. Listing and deleting are supported, so the store can be garbage
+//! collected, with the objects' `Last-Modified` as the time blocks were written.
+//!
+//! Objects larger than the multipart threshold are uploaded in parts, and an upload failing
+//! midway is aborted so no parts are left behind. WNFS never writes such blocks, as its blocks
+//! hold at most 256 KiB of content; multipart uploads only happen for large objects the app
+//! writes through the same `FFIStore` itself.
+
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use libipld::Cid;
+use log::trace;
+use reqwest::{
+    header::{HeaderMap, ETAG, LAST_MODIFIED},
+    Client, Method, StatusCode, Url,
+};
+use sha2::{Digest, Sha256};
+
+use super::{block_on, cid_from_bytes, DecodeLimits, FFIStore};
+use crate::{
+    car::verify_block,
+    error::WnfsUtilsError,
+    gateway::{read_body, DohConfig, ProxyConfig, Transport, REQUEST_ID_HEADER},
+    request_id,
+};
+
+/// Smallest part S3 accepts, except for the last one of an upload.
+pub const S3_MIN_PART_SIZE: usize = 5 * 1024 * 1024;
+
+// Bounds the bodies of responses other than blocks: listings of up to 1000 keys, upload replies
+// and errors stay far below it.
+const MAX_RESPONSE_BODY: usize = 4 * 1024 * 1024;
+
+const SIGNING_ALGORITHM: &str = "AWS4-HMAC-SHA256";
+
+#[derive(Clone, PartialEq, Eq)]
+pub struct S3Credentials {
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    /// For temporary credentials, sent as `x-amz-security-token`.
+    pub session_token: Option<String>,
+}
+
+impl S3Credentials {
+    pub fn new(access_key_id: &str, secret_access_key: &str) -> Self {
+        Self {
+            access_key_id: access_key_id.to_string(),
+            secret_access_key: secret_access_key.to_string(),
+            session_token: None,
+        }
+    }
+
+    /// Reads `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY` and the optional `AWS_SESSION_TOKEN`.
+    pub fn from_env() -> Result<Self> {
+        let var = |name: &str| std::env::var(name).map_err(|_| anyhow!("{} is not set", name));
+        Ok(Self {
+            access_key_id: var("AWS_ACCESS_KEY_ID")?,
+            secret_access_key: var("AWS_SECRET_ACCESS_KEY")?,
+            session_token: std::env::var("AWS_SESSION_TOKEN").ok(),
+        })
+    }
+}
+
+// Keeps the secrets out of logs.
+impl fmt::Debug for S3Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("S3Credentials")
+            .field("access_key_id", &self.access_key_id)
+            .finish_non_exhaustive()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct S3Config {
+    /// `https://s3.us-west-004.backblazeb2.com`, `http://127.0.0.1:9000` for a local MinIO.
+    pub endpoint: String,
+    /// Signing region; services with a single region such as MinIO accept `us-east-1`.
+    pub region: String,
+    pub bucket: String,
+    /// Prepended to the CID of every object, e.g. `forests/alice/`.
+    pub prefix: String,
+    /// Addresses the bucket in the path rather than as a subdomain of the endpoint, as MinIO
+    /// requires by default.
+    pub path_style: bool,
+    pub credentials: S3Credentials,
+    /// Bound on each attempt, connecting included.
+    pub timeout: Duration,
+    /// Attempts made after the first one failed.
+    pub max_retries: u32,
+    /// Delay before the first retry, doubled for every further one up to `max_backoff`.
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// Objects larger than this are uploaded in parts. WNFS blocks never are, see the module
+    /// docs.
+    pub multipart_threshold: usize,
+    /// Size of each part but the last, at least `S3_MIN_PART_SIZE`.
+    pub part_size: usize,
+}
+
+impl S3Config {
+    /// Path-style access to `bucket`, with no prefix and the default retries and part sizes.
+    pub fn new(endpoint: &str, region: &str, bucket: &str, credentials: S3Credentials) -> Self {
+        Self {
+            endpoint: endpoint.trim_end_matches('/').to_string(),
+            region: region.to_string(),
+            bucket: bucket.to_string(),
+            prefix: String::new(),
+            path_style: true,
+            credentials,
+            timeout: Duration::from_secs(30),
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(200),
+            max_backoff: Duration::from_secs(5),
+            multipart_threshold: 16 * 1024 * 1024,
+            part_size: 8 * 1024 * 1024,
+        }
+    }
+
+    // Delay before retry number `retry`, counting from 0.
+    fn backoff(&self, retry: u32) -> Duration {
+        self.initial_backoff
+            .saturating_mul(2u32.saturating_pow(retry))
+            .min(self.max_backoff)
+    }
+}
+
+struct S3Response {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Vec<u8>,
+}
+
+/// Block store over one bucket, see [`S3Config`]. Clones share the connection pool.
+#[derive(Clone)]
+pub struct S3BlockStore {
+    config: Arc<S3Config>,
+    endpoint: Url,
+    client: Client,
+    transport: Transport,
+    // Largest block read, from the `DecodeLimits` of the `FFIFriendlyBlockStore` on top.
+    max_block_size: Arc<AtomicUsize>,
+}
+
+impl S3BlockStore {
+    /// Validates `config` and builds the connection pool.
+    pub fn new(config: S3Config) -> Result<Self> {
+        let endpoint = Url::parse(&config.endpoint)?;
+        if !matches!(endpoint.scheme(), "http" | "https") || endpoint.host_str().is_none() {
+            return Err(anyhow!("{} is not an HTTP endpoint", config.endpoint));
+        }
+        if config.bucket.is_empty() {
+            return Err(anyhow!("the S3 bucket is empty"));
+        }
+        if config.part_size < S3_MIN_PART_SIZE {
+            return Err(anyhow!(
+                "S3 parts of {} bytes are smaller than {}",
+                config.part_size,
+                S3_MIN_PART_SIZE
+            ));
+        }
+        let transport = Transport::default();
+        Ok(Self {
+            client: transport.client(config.timeout)?,
+            config: Arc::new(config),
+            endpoint,
+            transport,
+            max_block_size: Arc::new(AtomicUsize::new(DecodeLimits::default().max_block_size)),
+        })
+    }
+
+    /// Resolves the endpoint's hostname over DNS-over-HTTPS, see
+    /// `GatewayStore::with_dns_resolver`.
+    pub fn with_dns_resolver(mut self, config: &DohConfig) -> Result<Self> {
+        self.transport
+            .set_dns_resolver(config, self.config.timeout)?;
+        self.rebuild_client()
+    }
+
+    /// Sends every request through a proxy, see `GatewayStore::with_proxy`.
+    pub fn with_proxy(mut self, config: &ProxyConfig) -> Result<Self> {
+        self.transport.set_proxy(config, self.config.timeout)?;
+        self.rebuild_client()
+    }
+
+    /// Accepts compressed responses, see `GatewayStore::with_compression`.
+    pub fn with_compression(mut self, enabled: bool) -> Result<Self> {
+        self.transport.compression = enabled;
+        self.rebuild_client()
+    }
+
+    fn rebuild_client(mut self) -> Result<Self> {
+        self.client = self.transport.client(self.config.timeout)?;
+        Ok(self)
+    }
+
+    pub fn config(&self) -> &S3Config {
+        &self.config
+    }
+
+    fn object_key(&self, cid: &Cid) -> String {
+        format!("{}{}", self.config.prefix, cid)
+    }
+
+    // Host and path of `key` in the bucket, the path already URI-encoded.
+    fn location(&self, key: &str) -> (String, String) {
+        let mut host = self.endpoint.host_str().unwrap_or_default().to_string();
+        if let Some(port) = self.endpoint.port() {
+            host = format!("{}:{}", host, port);
+        }
+        let path = self.endpoint.path().trim_end_matches('/').to_string();
+        match self.config.path_style {
+            true => (
+                host,
+                format!(
+                    "{}/{}/{}",
+                    path,
+                    uri_encode(&self.config.bucket, true),
+                    uri_encode(key, false)
+                ),
+            ),
+            false => (
+                format!("{}.{}", self.config.bucket, host),
+                format!("{}/{}", path, uri_encode(key, false)),
+            ),
+        }
+    }
+
+    // Signs and sends a request until an attempt succeeds or fails for good. A 404 is returned
+    // like a success, for the caller to handle. Response bodies over `limit` bytes fail.
+    async fn send(
+        &self,
+        method: Method,
+        key: &str,
+        query: &[(&str, &str)],
+        body: Vec<u8>,
+        limit: usize,
+    ) -> Result<S3Response> {
+        let (host, path) = self.location(key);
+        let query = canonical_query(query);
+        let url = match query.is_empty() {
+            true => format!("{}://{}{}", self.endpoint.scheme(), host, path),
+            false => format!("{}://{}{}?{}", self.endpoint.scheme(), host, path, query),
+        };
+        let payload_hash = hex(&Sha256::digest(&body));
+        let mut retry = 0;
+        loop {
+            let headers =
+                self.signed_headers(&method, &host, &path, &query, &payload_hash, Utc::now());
+            let mut builder = self.client.request(method.to_owned(), &url);
+            for (name, value) in headers {
+                builder = builder.header(name, value);
+            }
+            if let Some(id) = request_id::current() {
+                builder = builder.header(REQUEST_ID_HEADER, id);
+            }
+            let error = match builder.body(body.to_owned()).send().await {
+                Ok(response) => {
+                    let status = response.status();
+                    let headers = response.headers().to_owned();
+                    match read_body(response, limit).await {
+                        Ok(body) if status.is_success() || status == StatusCode::NOT_FOUND => {
+                            return Ok(S3Response {
+                                status,
+                                headers,
+                                body,
+                            })
+                        }
+                        Ok(body) => {
+                            let error = anyhow!(
+                                "S3 {} {} returned {}: {}",
+                                method,
+                                key,
+                                status,
+                                error_code(&body)
+                            );
+                            if !is_transient(status) {
+                                return Err(error);
+                            }
+                            error
+                        }
+                        Err(e) if e.downcast_ref::<WnfsUtilsError>().is_some() => return Err(e),
+                        Err(e) => e,
+                    }
+                }
+                Err(e) if e.is_builder() => return Err(e.into()),
+                Err(e) => anyhow!(e),
+            };
+            if retry >= self.config.max_retries {
+                return Err(WnfsUtilsError::StoreUnavailable(error.to_string()).into());
+            }
+            let delay = self.config.backoff(retry);
+            trace!("s3: retrying in {:?} after {}", delay, error);
+            crate::timer::sleep(delay).await;
+            retry += 1;
+        }
+    }
+
+    // The headers of a request signed at `time`, see `sign`.
+    fn signed_headers(
+        &self,
+        method: &Method,
+        host: &str,
+        path: &str,
+        query: &str,
+        payload_hash: &str,
+        time: DateTime<Utc>,
+    ) -> Vec<(String, String)> {
+        let mut headers = vec![
+            ("host".to_string(), host.to_string()),
+            ("x-amz-content-sha256".to_string(), payload_hash.to_string()),
+            (
+                "x-amz-date".to_string(),
+                time.format("%Y%m%dT%H%M%SZ").to_string(),
+            ),
+        ];
+        if let Some(token) = &self.config.credentials.session_token {
+            headers.push(("x-amz-security-token".to_string(), token.to_owned()));
+        }
+        let request = CanonicalRequest {
+            method: method.as_str(),
+            path,
+            query,
+            headers: headers.to_owned(),
+            payload_hash,
+        };
+        let authorization = sign(
+            &self.config.credentials,
+            &self.config.region,
+            &request,
+            time,
+        );
+        headers.push(("authorization".to_string(), authorization));
+        // reqwest sets the host itself.
+        headers.retain(|(name, _)| name != "host");
+        headers
+    }
+
+    async fn put_object(&self, key: &str, bytes: Vec<u8>) -> Result<()> {
+        if bytes.len() <= self.config.multipart_threshold {
+            self.send(Method::PUT, key, &[], bytes, MAX_RESPONSE_BODY)
+                .await?;
+            return Ok(());
+        }
+        let created = self
+            .send(
+                Method::POST,
+                key,
+                &[("uploads", "")],
+                Vec::new(),
+                MAX_RESPONSE_BODY,
+            )
+            .await?;
+        let upload_id = xml_values(&created.body, "UploadId")
+            .into_iter()
+            .next()
+            .ok_or_else(|| anyhow!("S3 returned no upload ID for {}", key))?;
+        match self.upload_parts(key, &upload_id, &bytes).await {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                trace!("wnfsError in s3 multipart upload of {}: {}", key, e);
+                let aborted = self
+                    .send(
+                        Method::DELETE,
+                        key,
+                        &[("uploadId", &upload_id)],
+                        Vec::new(),
+                        MAX_RESPONSE_BODY,
+                    )
+                    .await;
+                if let Err(abort) = aborted {
+                    trace!("wnfsError in s3 aborting upload of {}: {}", key, abort);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    // Pages through `ListObjectsV2` under the prefix.
+    async fn list_cids(&self) -> Result<Vec<Vec<u8>>> {
+        let mut cids = Vec::new();
+        let mut token: Option<String> = None;
+        loop {
+            let mut query = vec![("list-type", "2"), ("prefix", self.config.prefix.as_str())];
+            if let Some(token) = &token {
+                query.push(("continuation-token", token.as_str()));
+            }
+            let response = self
+                .send(Method::GET, "", &query, Vec::new(), MAX_RESPONSE_BODY)
+                .await?;
+            for key in xml_values(&response.body, "Key") {
+                let name = key.strip_prefix(&self.config.prefix).unwrap_or(&key);
+                if let Ok(cid) = Cid::try_from(name) {
+                    cids.push(cid.to_bytes());
+                }
+            }
+            let truncated = matches!(
+                xml_values(&response.body, "IsTruncated")
+                    .first()
+                    .map(String::as_str),
+                Some("true")
+            );
+            token = xml_values(&response.body, "NextContinuationToken")
+                .into_iter()
+                .next();
+            if !truncated || token.is_none() {
+                return Ok(cids);
+            }
+        }
+    }
+
+    async fn upload_parts(&self, key: &str, upload_id: &str, bytes: &[u8]) -> Result<()> {
+        let mut completion = String::from("<CompleteMultipartUpload>");
+        for (index, part) in bytes.chunks(self.config.part_size).enumerate() {
+            let number = (index + 1).to_string();
+            let uploaded = self
+                .send(
+                    Method::PUT,
+                    key,
+                    &[("partNumber", &number), ("uploadId", upload_id)],
+                    part.to_vec(),
+                    MAX_RESPONSE_BODY,
+                )
+                .await?;
+            let etag = uploaded
+                .headers
+                .get(ETAG)
+                .and_then(|etag| etag.to_str().ok())
+                .ok_or_else(|| anyhow!("S3 returned no ETag for part {} of {}", number, key))?;
+            completion.push_str(&format!(
+                "<Part><PartNumber>{}</PartNumber><ETag>{}</ETag></Part>",
+                number,
+                xml_escape(etag)
+            ));
+        }
+        completion.push_str("</CompleteMultipartUpload>");
+        let completed = self
+            .send(
+                Method::POST,
+                key,
+                &[("uploadId", upload_id)],
+                completion.into_bytes(),
+                MAX_RESPONSE_BODY,
+            )
+            .await?;
+        // A completion can fail after the 200 has been sent, reported in the body.
+        match xml_values(&completed.body, "Code").into_iter().next() {
+            Some(code) => Err(anyhow!(
+                "S3 failed to complete the upload of {}: {}",
+                key,
+                code
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl<'a> FFIStore<'a> for S3BlockStore {
+    fn get_block(&self, cid: Vec<u8>) -> Result<Vec<u8>> {
+        block_on(self.get_block_async(cid))?
+    }
+
+    fn put_block(&self, cid: Vec<u8>, bytes: Vec<u8>) -> Result<()> {
+        block_on(self.put_block_async(cid, bytes))?
+    }
+
+    async fn get_block_async(&self, cid: Vec<u8>) -> Result<Vec<u8>> {
+        let cid = cid_from_bytes(&cid)?;
+        let key = self.object_key(&cid);
+        let limit = self.max_block_size.load(Ordering::Relaxed);
+        let response = self.send(Method::GET, &key, &[], Vec::new(), limit).await?;
+        if response.status == StatusCode::NOT_FOUND {
+            trace!("wnfsError in s3 get_block: {} not found", key);
+            return Err(WnfsUtilsError::NotFound(format!(
+                "block {} in bucket {}",
+                cid, self.config.bucket
+            ))
+            .into());
+        }
+        verify_block(&cid, &response.body).map_err(|e| anyhow!(e))?;
+        Ok(response.body)
+    }
+
+    async fn put_block_async(&self, cid: Vec<u8>, bytes: Vec<u8>) -> Result<()> {
+        let cid = cid_from_bytes(&cid)?;
+        let key = self.object_key(&cid);
+        self.put_object(&key, bytes).await.map_err(|e| {
+            trace!("wnfsError in s3 put_block {}: {}", key, e);
+            e
+        })
+    }
+
+    fn set_max_block_size(&self, max_block_size: usize) {
+        self.max_block_size.store(max_block_size, Ordering::Relaxed);
+    }
+
+    /// The objects under the prefix named by a CID; other objects are skipped.
+    fn list_blocks(&self) -> Result<Vec<Vec<u8>>> {
+        block_on(self.list_cids())?
+    }
+
+    fn delete_block(&self, cid: Vec<u8>) -> Result<()> {
+        let key = self.object_key(&cid_from_bytes(&cid)?);
+        block_on(self.send(Method::DELETE, &key, &[], Vec::new(), MAX_RESPONSE_BODY))??;
+        Ok(())
+    }
+
+    fn block_written_at(&self, cid: Vec<u8>) -> Result<Option<u64>> {
+        let key = self.object_key(&cid_from_bytes(&cid)?);
+        let response =
+            block_on(self.send(Method::HEAD, &key, &[], Vec::new(), MAX_RESPONSE_BODY))??;
+        Ok(response
+            .headers
+            .get(LAST_MODIFIED)
+            .and_then(|modified| modified.to_str().ok())
+            .and_then(|modified| DateTime::parse_from_rfc2822(modified).ok())
+            .and_then(|modified| u64::try_from(modified.timestamp_millis()).ok()))
+    }
+}
+
+/// A request as AWS Signature Version 4 signs it: `path` and `query` are already canonical and
+/// `headers` are the headers to sign, with lowercase names.
+pub(super) struct CanonicalRequest<'r> {
+    pub method: &'r str,
+    pub path: &'r str,
+    pub query: &'r str,
+    pub headers: Vec<(String, String)>,
+    pub payload_hash: &'r str,
+}
+
+/// The `Authorization` header of `request` made at `time`.
+pub(super) fn sign(
+    credentials: &S3Credentials,
+    region: &str,
+    request: &CanonicalRequest,
+    time: DateTime<Utc>,
+) -> String {
+    let mut headers = request.headers.to_owned();
+    headers.sort();
+    let canonical_headers: String = headers
+        .iter()
+        .map(|(name, value)| format!("{}:{}\n", name, value.trim()))
+        .collect();
+    let signed_headers = headers
+        .iter()
+        .map(|(name, _)| name.as_str())
+        .collect::<Vec<_>>()
+        .join(";");
+    let canonical_request = format!(
+        "{}\n{}\n{}\n{}\n{}\n{}",
+        request.method,
+        request.path,
+        request.query,
+        canonical_headers,
+        signed_headers,
+        request.payload_hash
+    );
+    let date = time.format("%Y%m%d").to_string();
+    let scope = format!("{}/{}/s3/aws4_request", date, region);
+    let string_to_sign = format!(
+        "{}\n{}\n{}\n{}",
+        SIGNING_ALGORITHM,
+        time.format("%Y%m%dT%H%M%SZ"),
+        scope,
+        hex(&Sha256::digest(canonical_request.as_bytes()))
+    );
+    let mut key = format!("AWS4{}", credentials.secret_access_key).into_bytes();
+    for part in [date.as_str(), region, "s3", "aws4_request"] {
+        key = hmac(&key, part.as_bytes());
+    }
+    format!(
+        "{} Credential={}/{}, SignedHeaders={}, Signature={}",
+        SIGNING_ALGORITHM,
+        credentials.access_key_id,
+        scope,
+        signed_headers,
+        hex(&hmac(&key, string_to_sign.as_bytes()))
+    )
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    // HMAC takes keys of any length.
+    let mut mac = match Hmac::<Sha256>::new_from_slice(key) {
+        Ok(mac) => mac,
+        Err(_) => return Vec::new(),
+    };
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// Percent-encodes all but the unreserved characters; `/` only if `encode_slash`.
+fn uri_encode(value: &str, encode_slash: bool) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (byte as char).to_string()
+            }
+            b'/' if !encode_slash => "/".to_string(),
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+fn canonical_query(query: &[(&str, &str)]) -> String {
+    let mut pairs: Vec<(String, String)> = query
+        .iter()
+        .map(|(name, value)| (uri_encode(name, true), uri_encode(value, true)))
+        .collect();
+    pairs.sort();
+    pairs
+        .iter()
+        .map(|(name, value)| format!("{}={}", name, value))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+fn is_transient(status: StatusCode) -> bool {
+    status.is_server_error()
+        || status == StatusCode::TOO_MANY_REQUESTS
+        || status == StatusCode::REQUEST_TIMEOUT
+}
+
+// The `Code` of an S3 error body, or the body itself.
+fn error_code(body: &[u8]) -> String {
+    match xml_values(body, "Code").into_iter().next() {
+        Some(code) => code,
+        None => String::from_utf8_lossy(body).chars().take(200).collect(),
+    }
+}
+
+// The text of every `<tag>` element, unescaped. S3 responses are simple enough not to need an
+// XML parser: the elements read here hold text only.
+fn xml_values(body: &[u8], tag: &str) -> Vec<String> {
+    let body = String::from_utf8_lossy(body);
+    let (open, close) = (format!("<{}>", tag), format!("</{}>", tag));
+    let mut values = Vec::new();
+    let mut rest = body.as_ref();
+    while let Some(start) = rest.find(&open) {
+        rest = &rest[start + open.len()..];
+        match rest.find(&close) {
+            Some(end) => {
+                values.push(xml_unescape(&rest[..end]));
+                rest = &rest[end + close.len()..];
+            }
+            None => break,
+        }
+    }
+    values
+}
+
+fn xml_escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+fn xml_unescape(value: &str) -> String {
+    value
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
@@ -0,0 +1,321 @@
+//! Offline-tolerant writes to remote stores.
+//!
+//! Writing straight to a network-backed store fails the commit halfway when the connection
+//! drops, leaving the remote with part of a forest. [`WriteAheadQueue`] first writes every block
+//! to a local journal store, e.g. a `KVBlockStore` of its own, and uploads it from there. The
+//! journal holds exactly the blocks not yet uploaded, so they survive a restart and are picked up
+//! again by `WriteAheadQueue::open`.
+//!
+//! Uploads are attempted once at the end of every batch, see `FFIStore::flush_async`, stopping
+//! at the first failure so commits keep working offline. `flush` retries with exponential
+//! backoff and only returns `Ok` once every block is stored remotely: await it before
+//! advertising a new root. Blocks leave the journal only after the remote flushed them.
+
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    rc::Rc,
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use libipld::Cid;
+use log::trace;
+
+use super::{cid_from_bytes, CacheStats, FFIStore, PendingWriteback};
+use crate::metrics::IoMetricsSnapshot;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WriteAheadConfig {
+    /// Attempts `flush` makes per block after the first one failed.
+    pub max_retries: u32,
+    /// Delay before the first retry, doubled for every further one up to `max_backoff`.
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for WriteAheadConfig {
+    fn default() -> Self {
+        Self {
+            max_retries: 5,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+        }
+    }
+}
+
+impl WriteAheadConfig {
+    // Delay before retry number `retry`, counting from 0.
+    fn backoff(&self, retry: u32) -> Duration {
+        self.initial_backoff
+            .saturating_mul(2u32.saturating_pow(retry))
+            .min(self.max_backoff)
+    }
+}
+
+#[derive(Default)]
+struct QueueState {
+    // Blocks in the journal, not yet uploaded, with their sizes.
+    pending: HashMap<Cid, u64>,
+    last_error: Option<String>,
+}
+
+/// Journaled writes in front of `inner`, see the module docs. Clones share the queue.
+#[derive(Clone)]
+pub struct WriteAheadQueue<'a> {
+    inner: Box<dyn FFIStore<'a> + 'a>,
+    journal: Box<dyn FFIStore<'a> + 'a>,
+    config: WriteAheadConfig,
+    state: Rc<RefCell<QueueState>>,
+}
+
+impl<'a> WriteAheadQueue<'a> {
+    /// Queues writes for `inner` in `journal`, which has to support `list_blocks` and
+    /// `delete_block`. Blocks left in the journal by an earlier run are uploaded with the next
+    /// ones.
+    pub fn open(
+        inner: Box<dyn FFIStore<'a> + 'a>,
+        journal: Box<dyn FFIStore<'a> + 'a>,
+        config: WriteAheadConfig,
+    ) -> Result<Self> {
+        let pending = journal
+            .list_blocks()?
+            .into_iter()
+            .map(|cid| {
+                let size = journal.get_block(cid.to_owned())?.len() as u64;
+                Ok((cid_from_bytes(&cid)?, size))
+            })
+            .collect::<Result<HashMap<Cid, u64>>>()?;
+        Ok(Self {
+            inner,
+            journal,
+            config,
+            state: Rc::new(RefCell::new(QueueState {
+                pending,
+                last_error: None,
+            })),
+        })
+    }
+
+    /// Blocks written and not yet stored remotely.
+    pub fn pending_count(&self) -> usize {
+        self.state.borrow().pending.len()
+    }
+
+    /// Error of the latest failed upload, cleared once an upload succeeds.
+    pub fn last_error(&self) -> Option<String> {
+        self.state.borrow().last_error.to_owned()
+    }
+
+    /// Uploads every pending block, retrying as configured, and returns once the remote stored
+    /// them all. Fails with the blocks still pending when the remote stays unreachable.
+    pub async fn flush(&self) -> Result<()> {
+        self.journal.flush_async().await?;
+        self.upload_pending(self.config.max_retries).await
+    }
+
+    fn is_pending(&self, cid: &[u8]) -> Result<bool> {
+        let cid = cid_from_bytes(cid)?;
+        Ok(self.state.borrow().pending.contains_key(&cid))
+    }
+
+    fn record_pending(&self, cid: &[u8], size: usize) -> Result<()> {
+        let cid = cid_from_bytes(cid)?;
+        self.state.borrow_mut().pending.insert(cid, size as u64);
+        Ok(())
+    }
+
+    // Uploads the pending blocks, each with up to `retries` retries, and drops the uploaded
+    // ones from the journal once the remote flushed them. Stops at the first block that fails.
+    async fn upload_pending(&self, retries: u32) -> Result<()> {
+        let pending: Vec<Cid> = self.state.borrow().pending.keys().copied().collect();
+        let mut uploaded = Vec::with_capacity(pending.len());
+        let mut failure = None;
+        for cid in pending {
+            match self.upload(&cid, retries).await {
+                Ok(()) => uploaded.push(cid),
+                Err(e) => {
+                    failure = Some(e);
+                    break;
+                }
+            }
+        }
+        if !uploaded.is_empty() {
+            self.inner.flush_async().await?;
+            for cid in uploaded {
+                self.journal.delete_block(cid.to_bytes())?;
+                self.state.borrow_mut().pending.remove(&cid);
+            }
+        }
+        match failure {
+            Some(e) => {
+                self.state.borrow_mut().last_error = Some(e.to_string());
+                Err(anyhow!(
+                    "{} blocks waiting for upload: {}",
+                    self.pending_count(),
+                    e
+                ))
+            }
+            None => {
+                self.state.borrow_mut().last_error = None;
+                Ok(())
+            }
+        }
+    }
+
+    async fn upload(&self, cid: &Cid, retries: u32) -> Result<()> {
+        let data = self.journal.get_block_async(cid.to_bytes()).await?;
+        let mut retry = 0;
+        loop {
+            let error = match self
+                .inner
+                .put_block_async(cid.to_bytes(), data.to_owned())
+                .await
+            {
+                Ok(()) => return Ok(()),
+                Err(e) => e,
+            };
+            if retry >= retries {
+                return Err(error);
+            }
+            let delay = self.config.backoff(retry);
+            trace!(
+                "write-ahead queue: retrying {} in {:?} after {}",
+                cid,
+                delay,
+                error
+            );
+            crate::timer::sleep(delay).await;
+            retry += 1;
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl<'a> FFIStore<'a> for WriteAheadQueue<'a> {
+    fn get_block(&self, cid: Vec<u8>) -> Result<Vec<u8>> {
+        match self.is_pending(&cid)? {
+            true => self.journal.get_block(cid),
+            false => self.inner.get_block(cid),
+        }
+    }
+
+    fn put_block(&self, cid: Vec<u8>, bytes: Vec<u8>) -> Result<()> {
+        let size = bytes.len();
+        self.journal.put_block(cid.to_owned(), bytes)?;
+        self.record_pending(&cid, size)
+    }
+
+    async fn get_block_async(&self, cid: Vec<u8>) -> Result<Vec<u8>> {
+        match self.is_pending(&cid)? {
+            true => self.journal.get_block_async(cid).await,
+            false => self.inner.get_block_async(cid).await,
+        }
+    }
+
+    async fn put_block_async(&self, cid: Vec<u8>, bytes: Vec<u8>) -> Result<()> {
+        if self.is_pending(&cid)? {
+            return Ok(());
+        }
+        let size = bytes.len();
+        self.journal.put_block_async(cid.to_owned(), bytes).await?;
+        self.record_pending(&cid, size)
+    }
+
+    fn put_many(&self, blocks: Vec<(Vec<u8>, Vec<u8>)>) -> Result<()> {
+        let sizes: Vec<(Vec<u8>, usize)> = blocks
+            .iter()
+            .map(|(cid, bytes)| (cid.to_owned(), bytes.len()))
+            .collect();
+        self.journal.put_many(blocks)?;
+        sizes
+            .iter()
+            .try_for_each(|(cid, size)| self.record_pending(cid, *size))
+    }
+
+    async fn put_many_async(&self, blocks: Vec<(Vec<u8>, Vec<u8>)>) -> Result<()> {
+        let sizes: Vec<(Vec<u8>, usize)> = blocks
+            .iter()
+            .map(|(cid, bytes)| (cid.to_owned(), bytes.len()))
+            .collect();
+        self.journal.put_many_async(blocks).await?;
+        sizes
+            .iter()
+            .try_for_each(|(cid, size)| self.record_pending(cid, *size))
+    }
+
+    /// Waits for the journal and makes one upload attempt, which may fail without failing the
+    /// batch: the blocks are safe in the journal.
+    async fn flush_async(&self) -> Result<()> {
+        self.journal.flush_async().await?;
+        if let Err(e) = self.upload_pending(0).await {
+            trace!("write-ahead queue: uploads deferred: {}", e);
+        }
+        Ok(())
+    }
+
+    fn list_blocks(&self) -> Result<Vec<Vec<u8>>> {
+        let mut cids = self.inner.list_blocks()?;
+        let listed: HashSet<Vec<u8>> = cids.iter().cloned().collect();
+        let pending: Vec<Vec<u8>> = self
+            .state
+            .borrow()
+            .pending
+            .keys()
+            .map(|cid| cid.to_bytes())
+            .filter(|cid| !listed.contains(cid))
+            .collect();
+        cids.extend(pending);
+        Ok(cids)
+    }
+
+    fn delete_block(&self, cid: Vec<u8>) -> Result<()> {
+        if self.is_pending(&cid)? {
+            self.journal.delete_block(cid.to_owned())?;
+            self.state
+                .borrow_mut()
+                .pending
+                .remove(&cid_from_bytes(&cid)?);
+            return Ok(());
+        }
+        self.inner.delete_block(cid)
+    }
+
+    fn block_written_at(&self, cid: Vec<u8>) -> Result<Option<u64>> {
+        match self.is_pending(&cid)? {
+            true => self.journal.block_written_at(cid),
+            false => self.inner.block_written_at(cid),
+        }
+    }
+
+    fn compact(&self) -> Result<u64> {
+        Ok(self.journal.compact()? + self.inner.compact()?)
+    }
+
+    fn set_max_block_size(&self, max_block_size: usize) {
+        self.inner.set_max_block_size(max_block_size)
+    }
+
+    fn add_provider_hint(&self, cid: Vec<u8>, provider: String) -> Result<()> {
+        self.inner.add_provider_hint(cid, provider)
+    }
+
+    fn io_metrics(&self) -> Option<IoMetricsSnapshot> {
+        self.inner.io_metrics()
+    }
+
+    fn cache_stats(&self) -> Option<CacheStats> {
+        self.inner.cache_stats()
+    }
+
+    /// The blocks in the journal, plus what `inner` holds back itself.
+    fn pending_writeback(&self) -> PendingWriteback {
+        let inner = self.inner.pending_writeback();
+        let state = self.state.borrow();
+        PendingWriteback {
+            blocks: state.pending.len() + inner.blocks,
+            bytes: state.pending.values().sum::<u64>() + inner.bytes,
+        }
+    }
+}
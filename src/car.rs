@@ -0,0 +1,450 @@
+//! Minimal CAR (content addressable archive) encoding and decoding, and collection of the
+//! blocks reachable from a root so a forest can be archived or moved between stores.
+//!
+//! `export_car` and `import_car` move a whole forest through a CAR file block by block, so
+//! backups can be made and restored with standard IPFS tooling. Both CARv1 and CARv2 are
+//! read; CARv2 is written without an index, which readers rebuild when they need one.
+//!
+//! A [`ShareProof`] lists the CID and size of every block of a shared root. It travels with the
+//! share, however the blocks do, so the recipient can check it received all of them, untampered,
+//! before accepting the root into its forest. The proof of a private share, see
+//! `PrivateDirectoryHelper::share`, only lists the blocks of the shared directory and the path to
+//! them through the forest.
+
+use std::{
+    collections::{BTreeMap, HashSet, VecDeque},
+    io::{self, Read, Seek, SeekFrom, Write},
+};
+
+use libipld::{
+    cbor::DagCborCodec,
+    codec::Codec,
+    multihash::{Code, MultihashDigest},
+    Cid, Ipld, IpldCodec,
+};
+use wnfs::common::BlockStore;
+
+use crate::{
+    blockstore::FFIStore,
+    error::{describe, WnfsUtilsError},
+};
+
+// The CARv2 pragma: a CARv1 header of `{"version": 2}` without roots.
+const CARV2_PRAGMA: [u8; 11] = [
+    0x0a, 0xa1, 0x67, 0x76, 0x65, 0x72, 0x73, 0x69, 0x6f, 0x6e, 0x02,
+];
+// Characteristics, then the offset and size of the inner CARv1 and the offset of the index.
+const CARV2_HEADER_LEN: usize = 40;
+
+/// The format written by `export_car`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CarVersion {
+    V1,
+    V2,
+}
+
+/// Lists every block reachable from `root`, `root` first. DAG-CBOR blocks are followed through
+/// their links; other codecs (e.g. the raw blocks holding WNFS ciphertext) are leaves.
+pub async fn reachable_blocks(store: &impl BlockStore, root: &Cid) -> Result<Vec<Cid>, String> {
+    let mut seen: HashSet<Cid> = HashSet::new();
+    let mut order = Vec::new();
+    let mut pending = VecDeque::from([*root]);
+    while let Some(cid) = pending.pop_front() {
+        if !seen.insert(cid) {
+            continue;
+        }
+        order.push(cid);
+        if cid.codec() != u64::from(IpldCodec::DagCbor) {
+            continue;
+        }
+        let bytes = store.get_block(&cid).await.map_err(|e| describe(&e))?;
+        let ipld: Ipld = DagCborCodec.decode(&bytes).map_err(|e| describe(&e))?;
+        let mut links = Vec::new();
+        ipld.references(&mut links);
+        pending.extend(links.into_iter().filter(|link| !seen.contains(link)));
+    }
+    Ok(order)
+}
+
+/// Writes `blocks` as a CARv1 archive with the given roots.
+pub fn write_car<W: Write>(
+    out: &mut W,
+    roots: &[Cid],
+    blocks: impl IntoIterator<Item = (Cid, Vec<u8>)>,
+) -> Result<(), String> {
+    write_header(out, roots)?;
+    for (cid, data) in blocks {
+        write_block(out, &cid, &data)?;
+    }
+    Ok(())
+}
+
+/// Reads a CARv1 or CARv2 archive into its roots and blocks. Sections larger than
+/// `max_section` bytes are rejected before they are allocated.
+#[allow(clippy::type_complexity)]
+pub fn read_car<R: Read>(
+    input: &mut R,
+    max_section: usize,
+) -> Result<(Vec<Cid>, Vec<(Cid, Vec<u8>)>), String> {
+    let mut blocks = Vec::new();
+    let roots = read_blocks(input, max_section, |cid, data| {
+        blocks.push((cid, data));
+        Ok(())
+    })?;
+    Ok((roots, blocks))
+}
+
+/// Writes every block reachable from `root` to `out` as a CAR archive with `root` as its only
+/// root, one block at a time. Returns the number of blocks written.
+pub async fn export_car<W: Write + Seek>(
+    store: &impl BlockStore,
+    root: &Cid,
+    version: CarVersion,
+    out: &mut W,
+) -> Result<usize, String> {
+    let start = out.stream_position().map_err(|e| describe(&e))?;
+    if version == CarVersion::V2 {
+        // Filled in once the size of the payload is known.
+        out.write_all(&CARV2_PRAGMA).map_err(|e| describe(&e))?;
+        out.write_all(&[0u8; CARV2_HEADER_LEN])
+            .map_err(|e| describe(&e))?;
+    }
+    let data_offset = out.stream_position().map_err(|e| describe(&e))? - start;
+    write_header(out, &[*root])?;
+    let cids = reachable_blocks(store, root).await?;
+    for cid in cids.iter() {
+        let data = store.get_block(cid).await.map_err(|e| describe(&e))?;
+        write_block(out, cid, &data)?;
+    }
+    if version == CarVersion::V2 {
+        let end = out.stream_position().map_err(|e| describe(&e))?;
+        let mut header = [0u8; CARV2_HEADER_LEN];
+        header[16..24].copy_from_slice(&data_offset.to_le_bytes());
+        header[24..32].copy_from_slice(&(end - start - data_offset).to_le_bytes());
+        // An index offset of zero means there is no index.
+        out.seek(SeekFrom::Start(start + CARV2_PRAGMA.len() as u64))
+            .map_err(|e| describe(&e))?;
+        out.write_all(&header).map_err(|e| describe(&e))?;
+        out.seek(SeekFrom::Start(end)).map_err(|e| describe(&e))?;
+    }
+    Ok(cids.len())
+}
+
+/// Writes the blocks of a CARv1 or CARv2 archive to `store` as they are read, checking each
+/// against its CID. Returns the roots of the archive and the number of blocks written.
+pub fn import_car<'a, R: Read>(
+    input: &mut R,
+    store: &dyn FFIStore<'a>,
+    max_section: usize,
+) -> Result<(Vec<Cid>, usize), String> {
+    let mut count = 0;
+    let roots = read_blocks(input, max_section, |cid, data| {
+        verify_block(&cid, &data)?;
+        store
+            .put_block(cid.to_bytes(), data)
+            .map_err(|e| describe(&e))?;
+        count += 1;
+        Ok(())
+    })?;
+    Ok((roots, count))
+}
+
+/// Checks that `data` hashes to the multihash of `cid`.
+pub fn verify_block(cid: &Cid, data: &[u8]) -> Result<(), String> {
+    let code = Code::try_from(cid.hash().code())
+        .map_err(|_| format!("unsupported hash {:#x} in {}", cid.hash().code(), cid))?;
+    if code.digest(data) != *cid.hash() {
+        return Err(format!("block {} doesn't match its CID", cid));
+    }
+    Ok(())
+}
+
+/// The blocks making up a shared root, see `share_proof`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShareProof {
+    pub root: Cid,
+    /// Every block reachable from `root` with its size in bytes, `root` first.
+    pub blocks: Vec<(Cid, u64)>,
+    /// Whether the blocks link only to each other. A private share lists part of a forest,
+    /// whose blocks also link to the rest of it, so its check doesn't report those links.
+    pub closed: bool,
+}
+
+/// Blocks of a store that don't match a `ShareProof`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ShareProofCheck {
+    pub missing: Vec<Cid>,
+    /// Present, but with another size or content than the proof lists.
+    pub mismatched: Vec<Cid>,
+    /// Linked from a listed block without being listed themselves.
+    pub unlisted: Vec<Cid>,
+}
+
+impl ShareProofCheck {
+    pub fn is_complete(&self) -> bool {
+        self.missing.is_empty() && self.mismatched.is_empty() && self.unlisted.is_empty()
+    }
+}
+
+impl ShareProof {
+    /// DAG-CBOR encoding of the proof: `{"root": link, "blocks": [[link, size], ...],
+    /// "closed": bool}`, where a missing `"closed"` is true.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, String> {
+        let blocks = self
+            .blocks
+            .iter()
+            .map(|(cid, size)| Ipld::List(vec![Ipld::Link(*cid), Ipld::Integer(*size as i128)]))
+            .collect();
+        let proof = Ipld::Map(BTreeMap::from([
+            ("root".to_string(), Ipld::Link(self.root)),
+            ("blocks".to_string(), Ipld::List(blocks)),
+            ("closed".to_string(), Ipld::Bool(self.closed)),
+        ]));
+        DagCborCodec.encode(&proof).map_err(|e| describe(&e))
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        let proof = match DagCborCodec.decode(bytes).map_err(|e| describe(&e))? {
+            Ipld::Map(proof) => proof,
+            _ => return Err("share proof isn't a map".to_string()),
+        };
+        let root = match proof.get("root") {
+            Some(Ipld::Link(root)) => *root,
+            _ => return Err("share proof has no root".to_string()),
+        };
+        let blocks = match proof.get("blocks") {
+            Some(Ipld::List(blocks)) => blocks,
+            _ => return Err("share proof has no blocks".to_string()),
+        };
+        let blocks = blocks
+            .iter()
+            .map(|block| match block {
+                Ipld::List(entry) => match entry.as_slice() {
+                    [Ipld::Link(cid), Ipld::Integer(size)] => u64::try_from(*size)
+                        .map(|size| (*cid, size))
+                        .map_err(|e| describe(&e)),
+                    _ => Err("malformed share proof entry".to_string()),
+                },
+                _ => Err("malformed share proof entry".to_string()),
+            })
+            .collect::<Result<_, _>>()?;
+        let closed = match proof.get("closed") {
+            None => true,
+            Some(Ipld::Bool(closed)) => *closed,
+            Some(_) => return Err("malformed share proof closure".to_string()),
+        };
+        Ok(Self {
+            root,
+            blocks,
+            closed,
+        })
+    }
+
+    /// Checks the blocks of `store` against the proof: every listed block is present with its
+    /// size and CID, and for a closed proof links nowhere outside it, so the subtree is
+    /// complete.
+    pub async fn check(&self, store: &impl BlockStore) -> Result<ShareProofCheck, String> {
+        let mut check = ShareProofCheck::default();
+        let listed: HashSet<Cid> = self.blocks.iter().map(|(cid, _)| *cid).collect();
+        if !listed.contains(&self.root) {
+            check.unlisted.push(self.root);
+        }
+        for (cid, size) in &self.blocks {
+            let data = match store.get_block(cid).await {
+                Ok(data) => data,
+                // Stores verifying blocks refuse the tampered ones.
+                Err(e)
+                    if matches!(
+                        e.downcast_ref::<WnfsUtilsError>(),
+                        Some(WnfsUtilsError::BlockIntegrity { .. })
+                    ) =>
+                {
+                    check.mismatched.push(*cid);
+                    continue;
+                }
+                Err(_) => {
+                    check.missing.push(*cid);
+                    continue;
+                }
+            };
+            if data.len() as u64 != *size || verify_block(cid, &data).is_err() {
+                check.mismatched.push(*cid);
+                continue;
+            }
+            if !self.closed || cid.codec() != u64::from(IpldCodec::DagCbor) {
+                continue;
+            }
+            let ipld: Ipld = DagCborCodec.decode(&data).map_err(|e| describe(&e))?;
+            let mut links = Vec::new();
+            ipld.references(&mut links);
+            for link in links {
+                if !listed.contains(&link) && !check.unlisted.contains(&link) {
+                    check.unlisted.push(link);
+                }
+            }
+        }
+        Ok(check)
+    }
+}
+
+/// Builds the proof of the blocks reachable from `root`, to send along with a share of it.
+pub async fn share_proof(store: &impl BlockStore, root: &Cid) -> Result<ShareProof, String> {
+    let mut blocks = Vec::new();
+    for cid in reachable_blocks(store, root).await? {
+        let data = store.get_block(&cid).await.map_err(|e| describe(&e))?;
+        blocks.push((cid, data.len() as u64));
+    }
+    Ok(ShareProof {
+        root: *root,
+        blocks,
+        closed: true,
+    })
+}
+
+fn write_header<W: Write>(out: &mut W, roots: &[Cid]) -> Result<(), String> {
+    let header = Ipld::Map(BTreeMap::from([
+        (
+            "roots".to_string(),
+            Ipld::List(roots.iter().map(|root| Ipld::Link(*root)).collect()),
+        ),
+        ("version".to_string(), Ipld::Integer(1)),
+    ]));
+    let header = DagCborCodec.encode(&header).map_err(|e| describe(&e))?;
+    write_varint(out, header.len() as u64)?;
+    out.write_all(&header).map_err(|e| describe(&e))
+}
+
+fn write_block<W: Write>(out: &mut W, cid: &Cid, data: &[u8]) -> Result<(), String> {
+    let cid_bytes = cid.to_bytes();
+    write_varint(out, (cid_bytes.len() + data.len()) as u64)?;
+    out.write_all(&cid_bytes).map_err(|e| describe(&e))?;
+    out.write_all(data).map_err(|e| describe(&e))
+}
+
+// Passes each block of a CARv1 or CARv2 archive to `on_block`, returning the roots.
+fn read_blocks<R: Read>(
+    input: &mut R,
+    max_section: usize,
+    mut on_block: impl FnMut(Cid, Vec<u8>) -> Result<(), String>,
+) -> Result<Vec<Cid>, String> {
+    let header = read_header(input, max_section)?;
+    match header.get("version") {
+        Ok(Ipld::Integer(1)) => {
+            let roots = roots_of(&header)?;
+            read_sections(input, max_section, &mut on_block)?;
+            Ok(roots)
+        }
+        Ok(Ipld::Integer(2)) => {
+            let mut fixed = [0u8; CARV2_HEADER_LEN];
+            input.read_exact(&mut fixed).map_err(|e| describe(&e))?;
+            let data_offset = u64_le(&fixed[16..24]);
+            let data_size = u64_le(&fixed[24..32]);
+            let consumed = (CARV2_PRAGMA.len() + CARV2_HEADER_LEN) as u64;
+            let padding = data_offset
+                .checked_sub(consumed)
+                .ok_or("wnfsError CARv2 payload overlaps its header")?;
+            io::copy(&mut input.by_ref().take(padding), &mut io::sink())
+                .map_err(|e| describe(&e))?;
+            let mut payload = input.by_ref().take(data_size);
+            let header = read_header(&mut payload, max_section)?;
+            if header.get("version").ok() != Some(&Ipld::Integer(1)) {
+                return Err("wnfsError CARv2 payload is not a CARv1 archive".to_string());
+            }
+            let roots = roots_of(&header)?;
+            read_sections(&mut payload, max_section, &mut on_block)?;
+            Ok(roots)
+        }
+        _ => Err("wnfsError unsupported CAR version".to_string()),
+    }
+}
+
+fn read_header<R: Read>(input: &mut R, max_section: usize) -> Result<Ipld, String> {
+    let header_len = read_varint(input)?.ok_or("wnfsError empty CAR archive")?;
+    let header = read_section(input, header_len, max_section)?;
+    DagCborCodec.decode(&header).map_err(|e| describe(&e))
+}
+
+fn roots_of(header: &Ipld) -> Result<Vec<Cid>, String> {
+    match header.get("roots") {
+        Ok(Ipld::List(roots)) => Ok(roots
+            .iter()
+            .filter_map(|root| match root {
+                Ipld::Link(cid) => Some(*cid),
+                _ => None,
+            })
+            .collect()),
+        _ => Err("wnfsError CAR header without roots".to_string()),
+    }
+}
+
+fn read_sections<R: Read>(
+    input: &mut R,
+    max_section: usize,
+    on_block: &mut impl FnMut(Cid, Vec<u8>) -> Result<(), String>,
+) -> Result<(), String> {
+    while let Some(section_len) = read_varint(input)? {
+        let section = read_section(input, section_len, max_section)?;
+        let mut cursor = std::io::Cursor::new(section);
+        let cid = Cid::read_bytes(&mut cursor).map_err(|e| describe(&e))?;
+        let offset = cursor.position() as usize;
+        let mut data = cursor.into_inner();
+        data.drain(..offset);
+        on_block(cid, data)?;
+    }
+    Ok(())
+}
+
+fn u64_le(bytes: &[u8]) -> u64 {
+    let mut buffer = [0u8; 8];
+    buffer.copy_from_slice(bytes);
+    u64::from_le_bytes(buffer)
+}
+
+fn read_section<R: Read>(input: &mut R, len: u64, max_section: usize) -> Result<Vec<u8>, String> {
+    if len > max_section as u64 {
+        return Err(format!(
+            "wnfsError CAR section of {} bytes exceeds the limit of {} bytes",
+            len, max_section
+        ));
+    }
+    let mut section = vec![0u8; len as usize];
+    input.read_exact(&mut section).map_err(|e| describe(&e))?;
+    Ok(section)
+}
+
+fn write_varint<W: Write>(out: &mut W, mut value: u64) -> Result<(), String> {
+    let mut buffer = Vec::with_capacity(10);
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            buffer.push(byte);
+            break;
+        }
+        buffer.push(byte | 0x80);
+    }
+    out.write_all(&buffer).map_err(|e| describe(&e))
+}
+
+// `None` at a clean end of input.
+fn read_varint<R: Read>(input: &mut R) -> Result<Option<u64>, String> {
+    let mut value: u64 = 0;
+    for shift in (0..64).step_by(7) {
+        let mut byte = [0u8; 1];
+        let read = input.read(&mut byte).map_err(|e| describe(&e))?;
+        if read == 0 {
+            return match shift {
+                0 => Ok(None),
+                _ => Err("wnfsError truncated varint".to_string()),
+            };
+        }
+        value |= u64::from(byte[0] & 0x7f) << shift;
+        if byte[0] & 0x80 == 0 {
+            return Ok(Some(value));
+        }
+    }
+    Err("wnfsError varint overflow".to_string())
+}
+
+#[cfg(test)]
+mod car_tests;
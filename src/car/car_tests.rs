@@ -0,0 +1,166 @@
+use libipld::{cbor::DagCborCodec, codec::Codec, Ipld, IpldCodec};
+use wnfs::common::{BlockStore, CODEC_DAG_CBOR};
+
+use crate::{
+    blockstore::{FFIFriendlyBlockStore, FFIStore},
+    car::{
+        export_car, import_car, reachable_blocks, read_car, share_proof, write_car, CarVersion,
+        ShareProof,
+    },
+    kvstore::KVBlockStore,
+};
+
+#[tokio::test]
+async fn collects_reachable_blocks_and_roundtrips_car() {
+    let store = KVBlockStore::new(String::from("./tmp/test_car"), CODEC_DAG_CBOR);
+    let blockstore = FFIFriendlyBlockStore::new(Box::new(store));
+    let leaf = blockstore
+        .put_block(b"leaf".to_vec(), IpldCodec::Raw.into())
+        .await
+        .unwrap();
+    let unrelated = blockstore
+        .put_block(b"unrelated".to_vec(), IpldCodec::Raw.into())
+        .await
+        .unwrap();
+    let node = DagCborCodec
+        .encode(&Ipld::List(vec![Ipld::Link(leaf), Ipld::Link(leaf)]))
+        .unwrap();
+    let root = blockstore
+        .put_block(node, IpldCodec::DagCbor.into())
+        .await
+        .unwrap();
+
+    let reachable = reachable_blocks(&blockstore, &root).await.unwrap();
+    assert_eq!(reachable, vec![root, leaf]);
+    assert!(!reachable.contains(&unrelated));
+
+    let mut blocks = Vec::new();
+    for cid in reachable.iter() {
+        blocks.push((*cid, blockstore.get_block(cid).await.unwrap().to_vec()));
+    }
+    let mut archive = Vec::new();
+    write_car(&mut archive, &[root], blocks.to_owned()).unwrap();
+    let (roots, read_blocks) = read_car(&mut archive.as_slice(), 1024).unwrap();
+    assert_eq!(roots, vec![root]);
+    assert_eq!(read_blocks, blocks);
+
+    assert!(read_car(&mut archive.as_slice(), 8).is_err());
+    assert!(read_car(&mut &archive[..archive.len() - 1], 1024).is_err());
+}
+
+#[tokio::test]
+async fn exported_forests_import_into_another_store() {
+    let dir = tempfile::tempdir().unwrap();
+    let source = KVBlockStore::new(
+        dir.path().join("source").to_string_lossy().to_string(),
+        CODEC_DAG_CBOR,
+    );
+    let blockstore = FFIFriendlyBlockStore::new(Box::new(source));
+    let leaf = blockstore
+        .put_block(b"leaf".to_vec(), IpldCodec::Raw.into())
+        .await
+        .unwrap();
+    let node = DagCborCodec
+        .encode(&Ipld::List(vec![Ipld::Link(leaf)]))
+        .unwrap();
+    let root = blockstore
+        .put_block(node, IpldCodec::DagCbor.into())
+        .await
+        .unwrap();
+
+    for version in [CarVersion::V1, CarVersion::V2] {
+        let mut archive = std::io::Cursor::new(Vec::new());
+        let exported = export_car(&blockstore, &root, version, &mut archive)
+            .await
+            .unwrap();
+        assert_eq!(exported, 2);
+        let archive = archive.into_inner();
+        assert_eq!(
+            read_car(&mut archive.as_slice(), 1024).unwrap().0,
+            vec![root]
+        );
+
+        let target = KVBlockStore::new(
+            dir.path()
+                .join(format!("target-{:?}", version))
+                .to_string_lossy()
+                .to_string(),
+            CODEC_DAG_CBOR,
+        );
+        let (roots, imported) = import_car(&mut archive.as_slice(), &target, 1024).unwrap();
+        assert_eq!((roots, imported), (vec![root], 2));
+        assert_eq!(target.get_block(leaf.to_bytes()).unwrap(), b"leaf".to_vec());
+    }
+
+    // A block that doesn't match its CID is refused.
+    let mut archive = Vec::new();
+    write_car(&mut archive, &[leaf], [(leaf, b"tampered".to_vec())]).unwrap();
+    let target = KVBlockStore::new(
+        dir.path().join("tampered").to_string_lossy().to_string(),
+        CODEC_DAG_CBOR,
+    );
+    assert!(import_car(&mut archive.as_slice(), &target, 1024).is_err());
+    assert!(target.get_block(leaf.to_bytes()).is_err());
+}
+
+#[tokio::test]
+async fn share_proofs_catch_missing_and_tampered_blocks() {
+    let dir = tempfile::tempdir().unwrap();
+    let source = KVBlockStore::new(
+        dir.path().join("source").to_string_lossy().to_string(),
+        CODEC_DAG_CBOR,
+    );
+    let blockstore = FFIFriendlyBlockStore::new(Box::new(source));
+    let first = blockstore
+        .put_block(b"first".to_vec(), IpldCodec::Raw.into())
+        .await
+        .unwrap();
+    let second = blockstore
+        .put_block(b"second".to_vec(), IpldCodec::Raw.into())
+        .await
+        .unwrap();
+    let node = DagCborCodec
+        .encode(&Ipld::List(vec![Ipld::Link(first), Ipld::Link(second)]))
+        .unwrap();
+    let root = blockstore
+        .put_block(node.to_owned(), IpldCodec::DagCbor.into())
+        .await
+        .unwrap();
+
+    let proof = share_proof(&blockstore, &root).await.unwrap();
+    assert_eq!(proof.blocks.len(), 3);
+    assert_eq!(proof.blocks[0], (root, node.len() as u64));
+    let proof = ShareProof::from_bytes(&proof.to_bytes().unwrap()).unwrap();
+    assert!(proof.check(&blockstore).await.unwrap().is_complete());
+
+    // The recipient got the root, a tampered first block and nothing else.
+    let received = KVBlockStore::new(
+        dir.path().join("received").to_string_lossy().to_string(),
+        CODEC_DAG_CBOR,
+    );
+    received.put_block(root.to_bytes(), node).unwrap();
+    received
+        .put_block(first.to_bytes(), b"tampered".to_vec())
+        .unwrap();
+    let received = FFIFriendlyBlockStore::new(Box::new(received));
+    let check = proof.check(&received).await.unwrap();
+    assert_eq!(check.missing, vec![second]);
+    assert_eq!(check.mismatched, vec![first]);
+    assert!(!check.is_complete());
+
+    // A proof leaving out a block doesn't vouch for the subtree.
+    let mut partial = ShareProof {
+        root,
+        blocks: proof.blocks[..2].to_vec(),
+        closed: true,
+    };
+    assert_eq!(
+        partial.check(&blockstore).await.unwrap().unlisted,
+        vec![second]
+    );
+    // Unless it only claims a part of the DAG.
+    partial.closed = false;
+    let partial = ShareProof::from_bytes(&partial.to_bytes().unwrap()).unwrap();
+    assert!(!partial.closed);
+    assert!(partial.check(&blockstore).await.unwrap().is_complete());
+}
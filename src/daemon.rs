@@ -0,0 +1,245 @@
+//! Minimal HTTP endpoints for running the crate as a long-lived daemon or server:
+//! `/healthz` (liveness), `/readyz` (readiness) and `/metrics` (Prometheus text), plus a
+//! `POST /root` webhook for pointer services announcing a new root, see
+//! `private_forest::RemoteWatcher`.
+//!
+//! The helper and store are not `Send`, so they never move into the server. The app updates a
+//! shared [`DaemonStatus`] from its own thread and the server only reads it.
+
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use libipld::Cid;
+use log::trace;
+use serde_json::json;
+#[cfg(not(target_arch = "wasm32"))]
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+use web_time::Instant;
+use wnfs::common::BlockStore;
+
+const MAX_REQUEST_HEAD: usize = 8 * 1024;
+
+#[derive(Debug, Clone)]
+pub struct DaemonConfig {
+    /// Readiness fails while more than this many blocks are waiting to be written back.
+    pub max_writeback_backlog: u64,
+    /// A store probe older than this no longer counts as proof of reachability.
+    pub probe_stale_after: Duration,
+}
+
+impl Default for DaemonConfig {
+    fn default() -> Self {
+        Self {
+            max_writeback_backlog: 1000,
+            probe_stale_after: Duration::from_secs(120),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct StatusInner {
+    last_probe: Option<(Instant, bool)>,
+    root_resolved: bool,
+    root_announced: bool,
+    pending_writeback: u64,
+    metrics: String,
+}
+
+/// Health state shared between the app and the HTTP server. Cheap to clone.
+#[derive(Debug, Clone, Default)]
+pub struct DaemonStatus {
+    config: DaemonConfig,
+    inner: Arc<Mutex<StatusInner>>,
+}
+
+/// Outcome of a health or readiness check.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HealthReport {
+    pub healthy: bool,
+    pub store_reachable: bool,
+    pub root_resolved: bool,
+    pub pending_writeback: u64,
+}
+
+impl DaemonStatus {
+    pub fn new(config: DaemonConfig) -> Self {
+        Self {
+            config,
+            inner: Arc::new(Mutex::new(StatusInner::default())),
+        }
+    }
+
+    /// Fetches `cid` from `store` and records whether that succeeded.
+    pub async fn probe_store(&self, store: &impl BlockStore, cid: &Cid) -> bool {
+        let reachable = store.get_block(cid).await.is_ok();
+        if !reachable {
+            trace!("daemon: store probe for {:?} failed", cid);
+        }
+        self.with_inner(|inner| inner.last_probe = Some((Instant::now(), reachable)));
+        reachable
+    }
+
+    /// Records whether the current forest root has been resolved and loaded.
+    pub fn set_root_resolved(&self, resolved: bool) {
+        self.with_inner(|inner| inner.root_resolved = resolved);
+    }
+
+    /// Records that another device published a root, as the `/root` endpoint does.
+    pub fn announce_root(&self) {
+        self.with_inner(|inner| inner.root_announced = true);
+    }
+
+    /// Whether a root was announced since the last call.
+    pub fn take_root_announcement(&self) -> bool {
+        self.with_inner(|inner| std::mem::take(&mut inner.root_announced))
+    }
+
+    /// Records the number of blocks waiting to be written back to the remote store.
+    pub fn set_pending_writeback(&self, pending: u64) {
+        self.with_inner(|inner| inner.pending_writeback = pending);
+    }
+
+    /// Replaces the text served on `/metrics`, e.g. with `PrivateDirectoryHelper::prometheus_metrics`.
+    pub fn set_metrics(&self, metrics: String) {
+        self.with_inner(|inner| inner.metrics = metrics);
+    }
+
+    /// Liveness: fails only when the latest, still fresh, store probe failed. A process that
+    /// can't reach its store would not recover without a restart of its connections.
+    pub fn health(&self) -> HealthReport {
+        let report = self.report();
+        HealthReport {
+            healthy: !matches!(self.probe_failed(), Some(true)),
+            ..report
+        }
+    }
+
+    /// Readiness: the store was reachable recently, the root is resolved and the write-back
+    /// backlog is below `DaemonConfig::max_writeback_backlog`.
+    pub fn readiness(&self) -> HealthReport {
+        let report = self.report();
+        HealthReport {
+            healthy: report.store_reachable
+                && report.root_resolved
+                && report.pending_writeback <= self.config.max_writeback_backlog,
+            ..report
+        }
+    }
+
+    fn report(&self) -> HealthReport {
+        let store_reachable = self.probe_failed() == Some(false);
+        self.with_inner(|inner| HealthReport {
+            healthy: false,
+            store_reachable,
+            root_resolved: inner.root_resolved,
+            pending_writeback: inner.pending_writeback,
+        })
+    }
+
+    // `Some(failed)` for a fresh probe, `None` when there is no fresh probe.
+    fn probe_failed(&self) -> Option<bool> {
+        let stale_after = self.config.probe_stale_after;
+        self.with_inner(|inner| match inner.last_probe {
+            Some((at, reachable)) if at.elapsed() <= stale_after => Some(!reachable),
+            _ => None,
+        })
+    }
+
+    fn with_inner<T>(&self, f: impl FnOnce(&mut StatusInner) -> T) -> T {
+        let mut guard = match self.inner.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        f(&mut guard)
+    }
+}
+
+/// Serves the daemon endpoints on `listener` until the listener fails. Not available on wasm32,
+/// where the status is still kept for `RemoteWatcher`.
+#[cfg(not(target_arch = "wasm32"))]
+pub async fn serve(listener: TcpListener, status: DaemonStatus) -> std::io::Result<()> {
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let status = status.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream, status).await {
+                trace!("daemon: connection error: {:?}", e);
+            }
+        });
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+async fn handle_connection(mut stream: TcpStream, status: DaemonStatus) -> std::io::Result<()> {
+    let mut head = Vec::new();
+    let mut buffer = [0u8; 1024];
+    while !head.windows(4).any(|window| window == b"\r\n\r\n") && head.len() < MAX_REQUEST_HEAD {
+        let read = stream.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        head.extend_from_slice(&buffer[..read]);
+    }
+    let request = String::from_utf8_lossy(&head);
+    let mut request_line = request
+        .lines()
+        .next()
+        .unwrap_or_default()
+        .split_whitespace();
+    let method = request_line.next().unwrap_or_default();
+    let path = request_line.next().unwrap_or_default();
+
+    let (code, content_type, body) = match (method, path) {
+        ("GET", "/healthz") => health_response(status.health()),
+        ("GET", "/readyz") => health_response(status.readiness()),
+        ("GET", "/metrics") => (
+            200,
+            "text/plain; version=0.0.4",
+            status.with_inner(|inner| inner.metrics.to_owned()),
+        ),
+        // The body is ignored: the watcher reads the root from the pointer itself.
+        ("POST", "/root") => {
+            status.announce_root();
+            (202, "text/plain", "accepted\n".to_string())
+        }
+        ("GET", _) | ("POST", _) => (404, "text/plain", "not found\n".to_string()),
+        _ => (405, "text/plain", "method not allowed\n".to_string()),
+    };
+    let reason = match code {
+        200 => "OK",
+        202 => "Accepted",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        _ => "Service Unavailable",
+    };
+    let response = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        code,
+        reason,
+        content_type,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn health_response(report: HealthReport) -> (u16, &'static str, String) {
+    let body = json!({
+        "status": if report.healthy { "ok" } else { "unavailable" },
+        "store_reachable": report.store_reachable,
+        "root_resolved": report.root_resolved,
+        "pending_writeback": report.pending_writeback,
+    });
+    let code = if report.healthy { 200 } else { 503 };
+    (code, "application/json", body.to_string())
+}
+
+#[cfg(test)]
+mod daemon_tests;
@@ -0,0 +1,67 @@
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+use wnfs::common::{BlockStore, CODEC_RAW};
+
+use crate::{
+    blockstore::FFIFriendlyBlockStore,
+    daemon::{serve, DaemonConfig, DaemonStatus},
+    kvstore::KVBlockStore,
+};
+
+async fn get(addr: std::net::SocketAddr, path: &str) -> String {
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream
+        .write_all(format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).as_bytes())
+        .await
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    response
+}
+
+#[tokio::test]
+async fn readiness_follows_status() {
+    let store = KVBlockStore::new(String::from("./tmp/test_daemon"), CODEC_RAW);
+    let blockstore = FFIFriendlyBlockStore::new(Box::new(store));
+    let cid = blockstore
+        .put_block(b"probe".to_vec(), CODEC_RAW)
+        .await
+        .unwrap();
+
+    let status = DaemonStatus::new(DaemonConfig::default());
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(serve(listener, status.clone()));
+
+    assert!(get(addr, "/healthz").await.starts_with("HTTP/1.1 200"));
+    assert!(get(addr, "/readyz").await.starts_with("HTTP/1.1 503"));
+
+    assert!(status.probe_store(&blockstore, &cid).await);
+    status.set_root_resolved(true);
+    let response = get(addr, "/readyz").await;
+    assert!(response.starts_with("HTTP/1.1 200"));
+    assert!(response.contains("\"root_resolved\":true"));
+
+    status.set_pending_writeback(DaemonConfig::default().max_writeback_backlog + 1);
+    assert!(get(addr, "/readyz").await.starts_with("HTTP/1.1 503"));
+
+    status.set_metrics("wnfsutils_forest_commits_total 1\n".to_string());
+    assert!(get(addr, "/metrics")
+        .await
+        .ends_with("wnfsutils_forest_commits_total 1\n"));
+    assert!(get(addr, "/nope").await.starts_with("HTTP/1.1 404"));
+
+    assert!(!status.take_root_announcement());
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream
+        .write_all(b"POST /root HTTP/1.1\r\nHost: localhost\r\nContent-Length: 0\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 202"));
+    assert!(status.take_root_announcement());
+    assert!(!status.take_root_announcement());
+}
@@ -0,0 +1,288 @@
+//! Encryption at rest for local stores, so blocks cached on the device (public blocks and the
+//! forest structure included) can't be read by other apps with access to the filesystem.
+//!
+//! [`EncryptedStore`] wraps any [`FFIStore`] and seals each block with XChaCha20-Poly1305 under a
+//! device-local key. The stored value is a 24 byte random nonce followed by the ciphertext, and
+//! the CID is authenticated as associated data so a block can't be swapped for another one.
+//! CIDs themselves stay in the clear: they are the lookup keys of the inner store.
+//!
+//! Ciphertext is as long as the block plus the tag, so the sizes stored on disk tell block
+//! kinds apart. `EncryptedStore::with_size_padding` pads every block with Padmé before sealing
+//! it: the padded length keeps only its top bits, about log2 of log2 of the length, so blocks
+//! fall into few size classes while padding adds at most 12%, and usually far less. Full
+//! 256 KiB content blocks grow by about 3% instead of doubling like a power of two would.
+//! Padded blocks are authenticated under a different associated data, so opening a store with
+//! the other setting fails with `BlockDecryption` instead of returning wrong bytes.
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    Key, XChaCha20Poly1305, XNonce,
+};
+use rand::RngCore;
+
+use crate::{
+    blockstore::{CacheStats, FFIStore, PendingWriteback},
+    error::WnfsUtilsError,
+    metrics::IoMetricsSnapshot,
+};
+
+const NONCE_LEN: usize = 24;
+const TAG_LEN: usize = 16;
+// Appended to the CID in the associated data of padded blocks.
+const PADDED_AAD: &[u8] = b"wnfsutils padded";
+// Padded plaintexts end with the block's length as a little-endian u32.
+const LEN_SUFFIX: usize = 4;
+
+/// Source of the device-local key, e.g. the platform keychain or keystore.
+pub trait KeyProvider {
+    /// 32 bytes that stay the same for the lifetime of the store.
+    fn device_key(&self) -> Result<[u8; 32]>;
+}
+
+/// A key held in memory, for tests and hosts that manage the key themselves.
+#[derive(Clone)]
+pub struct StaticKeyProvider([u8; 32]);
+
+impl StaticKeyProvider {
+    pub fn new(key: [u8; 32]) -> Self {
+        Self(key)
+    }
+}
+
+impl KeyProvider for StaticKeyProvider {
+    fn device_key(&self) -> Result<[u8; 32]> {
+        Ok(self.0)
+    }
+}
+
+#[derive(Clone)]
+pub struct EncryptedStore<'a> {
+    inner: Box<dyn FFIStore<'a> + 'a>,
+    cipher: XChaCha20Poly1305,
+    // Smallest padded size, `None` when blocks aren't padded.
+    padding: Option<usize>,
+}
+
+//--------------------------------------------------------------------------------------------------
+// Implementations
+//--------------------------------------------------------------------------------------------------
+
+impl<'a> EncryptedStore<'a> {
+    /// Encrypts the blocks of `inner` with the key of `key_provider`. Blocks already in `inner`
+    /// must have been written with the same key, so enable this on a new store.
+    pub fn new(inner: Box<dyn FFIStore<'a> + 'a>, key_provider: &impl KeyProvider) -> Result<Self> {
+        let key = key_provider.device_key()?;
+        Ok(Self {
+            inner,
+            cipher: XChaCha20Poly1305::new(Key::from_slice(&key)),
+            padding: None,
+        })
+    }
+
+    /// Pads blocks to their Padmé size class, and to at least `min_size` bytes, before sealing
+    /// them. Like the key, this has to stay the same for the lifetime of the store.
+    pub fn with_size_padding(mut self, min_size: usize) -> Self {
+        self.padding = Some(min_size);
+        self
+    }
+
+    fn associated_data(&self, cid: &[u8]) -> Vec<u8> {
+        match self.padding {
+            Some(_) => [cid, PADDED_AAD].concat(),
+            None => cid.to_vec(),
+        }
+    }
+
+    fn pad(&self, bytes: &[u8]) -> Result<Vec<u8>> {
+        let min_size = match self.padding {
+            Some(min_size) => min_size,
+            None => return Ok(bytes.to_vec()),
+        };
+        let len = u32::try_from(bytes.len()).map_err(|_| anyhow!("block too large to pad"))?;
+        let padded_len = padme(bytes.len() + LEN_SUFFIX).max(min_size);
+        let mut padded = Vec::with_capacity(padded_len);
+        padded.extend_from_slice(bytes);
+        padded.resize(padded_len - LEN_SUFFIX, 0);
+        padded.extend_from_slice(&len.to_le_bytes());
+        Ok(padded)
+    }
+
+    fn unpad(&self, mut padded: Vec<u8>) -> Result<Vec<u8>> {
+        if self.padding.is_none() {
+            return Ok(padded);
+        }
+        let suffix_at = padded
+            .len()
+            .checked_sub(LEN_SUFFIX)
+            .ok_or(WnfsUtilsError::BlockDecryption)?;
+        let mut suffix = [0u8; LEN_SUFFIX];
+        suffix.copy_from_slice(&padded[suffix_at..]);
+        let len = u32::from_le_bytes(suffix) as usize;
+        if len > suffix_at {
+            return Err(WnfsUtilsError::BlockDecryption.into());
+        }
+        padded.truncate(len);
+        Ok(padded)
+    }
+
+    fn open(&self, cid: &[u8], sealed: Vec<u8>) -> Result<Vec<u8>> {
+        if sealed.len() < NONCE_LEN {
+            return Err(WnfsUtilsError::BlockDecryption.into());
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        let aad = self.associated_data(cid);
+        let payload = Payload {
+            msg: ciphertext,
+            aad: &aad,
+        };
+        let bytes = self
+            .cipher
+            .decrypt(XNonce::from_slice(nonce), payload)
+            .map_err(|_| WnfsUtilsError::BlockDecryption)?;
+        self.unpad(bytes)
+    }
+
+    fn seal(&self, cid: &[u8], bytes: &[u8]) -> Result<Vec<u8>> {
+        let mut nonce = [0u8; NONCE_LEN];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let padded = self.pad(bytes)?;
+        let aad = self.associated_data(cid);
+        let payload = Payload {
+            msg: &padded,
+            aad: &aad,
+        };
+        let ciphertext = self
+            .cipher
+            .encrypt(XNonce::from_slice(&nonce), payload)
+            .map_err(|e| anyhow!("unable to encrypt block: {}", e))?;
+        let mut sealed = Vec::with_capacity(NONCE_LEN + ciphertext.len());
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&ciphertext);
+        Ok(sealed)
+    }
+
+    fn seal_many(&self, blocks: Vec<(Vec<u8>, Vec<u8>)>) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        blocks
+            .into_iter()
+            .map(|(cid, bytes)| {
+                let sealed = self.seal(&cid, &bytes)?;
+                Ok((cid, sealed))
+            })
+            .collect()
+    }
+}
+
+// The Padmé length of `len`: rounded up so that only its top `floor(log2(e)) + 1` bits can be
+// set, `e` being `floor(log2(len))`.
+fn padme(len: usize) -> usize {
+    if len < 2 {
+        return len;
+    }
+    let exponent = usize::BITS - 1 - len.leading_zeros();
+    let exponent_bits = u32::BITS - exponent.leading_zeros();
+    let mask = (1usize << (exponent - exponent_bits)) - 1;
+    len.saturating_add(mask) & !mask
+}
+
+#[async_trait(?Send)]
+impl<'a> FFIStore<'a> for EncryptedStore<'a> {
+    fn get_block(&self, cid: Vec<u8>) -> Result<Vec<u8>> {
+        let sealed = self.inner.get_block(cid.to_owned())?;
+        self.open(&cid, sealed)
+    }
+
+    fn put_block(&self, cid: Vec<u8>, bytes: Vec<u8>) -> Result<()> {
+        let sealed = self.seal(&cid, &bytes)?;
+        self.inner.put_block(cid, sealed)
+    }
+
+    async fn get_block_async(&self, cid: Vec<u8>) -> Result<Vec<u8>> {
+        let sealed = self.inner.get_block_async(cid.to_owned()).await?;
+        self.open(&cid, sealed)
+    }
+
+    async fn put_block_async(&self, cid: Vec<u8>, bytes: Vec<u8>) -> Result<()> {
+        let sealed = self.seal(&cid, &bytes)?;
+        self.inner.put_block_async(cid, sealed).await
+    }
+
+    fn get_many(&self, cids: Vec<Vec<u8>>) -> Result<Vec<Vec<u8>>> {
+        let sealed = self.inner.get_many(cids.to_owned())?;
+        cids.iter()
+            .zip(sealed)
+            .map(|(cid, sealed)| self.open(cid, sealed))
+            .collect()
+    }
+
+    fn put_many(&self, blocks: Vec<(Vec<u8>, Vec<u8>)>) -> Result<()> {
+        let sealed = self.seal_many(blocks)?;
+        self.inner.put_many(sealed)
+    }
+
+    async fn get_many_async(&self, cids: Vec<Vec<u8>>) -> Result<Vec<Vec<u8>>> {
+        let sealed = self.inner.get_many_async(cids.to_owned()).await?;
+        cids.iter()
+            .zip(sealed)
+            .map(|(cid, sealed)| self.open(cid, sealed))
+            .collect()
+    }
+
+    async fn put_many_async(&self, blocks: Vec<(Vec<u8>, Vec<u8>)>) -> Result<()> {
+        let sealed = self.seal_many(blocks)?;
+        self.inner.put_many_async(sealed).await
+    }
+
+    async fn flush_async(&self) -> Result<()> {
+        self.inner.flush_async().await
+    }
+
+    fn list_blocks(&self) -> Result<Vec<Vec<u8>>> {
+        self.inner.list_blocks()
+    }
+
+    fn delete_block(&self, cid: Vec<u8>) -> Result<()> {
+        self.inner.delete_block(cid)
+    }
+
+    fn block_written_at(&self, cid: Vec<u8>) -> Result<Option<u64>> {
+        self.inner.block_written_at(cid)
+    }
+
+    fn compact(&self) -> Result<u64> {
+        self.inner.compact()
+    }
+
+    /// Passes on the size of the largest block once sealed.
+    fn set_max_block_size(&self, max_block_size: usize) {
+        let padded = match self.padding {
+            Some(min_size) => padme(max_block_size.saturating_add(LEN_SUFFIX)).max(min_size),
+            None => max_block_size,
+        };
+        self.inner
+            .set_max_block_size(padded.saturating_add(NONCE_LEN + TAG_LEN));
+    }
+
+    fn add_provider_hint(&self, cid: Vec<u8>, provider: String) -> Result<()> {
+        self.inner.add_provider_hint(cid, provider)
+    }
+
+    /// The backend's figures: its payload is the sealed blocks, so encryption overhead shows
+    /// up as logical bytes.
+    fn io_metrics(&self) -> Option<IoMetricsSnapshot> {
+        self.inner.io_metrics()
+    }
+
+    fn cache_stats(&self) -> Option<CacheStats> {
+        self.inner.cache_stats()
+    }
+
+    /// Sizes are those of the sealed blocks.
+    fn pending_writeback(&self) -> PendingWriteback {
+        self.inner.pending_writeback()
+    }
+}
+
+#[cfg(test)]
+mod encrypted_store_tests;
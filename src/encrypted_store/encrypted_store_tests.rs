@@ -0,0 +1,119 @@
+use libipld::IpldCodec;
+use wnfs::common::{BlockStore, CODEC_DAG_CBOR};
+
+use crate::{
+    blockstore::{FFIFriendlyBlockStore, FFIStore},
+    encrypted_store::{EncryptedStore, StaticKeyProvider},
+    error::WnfsUtilsError,
+    kvstore::KVBlockStore,
+};
+
+#[tokio::test]
+async fn blocks_are_encrypted_on_disk() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("store").to_string_lossy().to_string();
+    let kv = KVBlockStore::new(path, CODEC_DAG_CBOR);
+    let key = StaticKeyProvider::new([3u8; 32]);
+    let encrypted = EncryptedStore::new(Box::new(kv.clone()), &key).unwrap();
+    let blockstore = FFIFriendlyBlockStore::new(Box::new(encrypted.clone()));
+
+    let plaintext = b"forest structure".to_vec();
+    let cid = blockstore
+        .put_block(plaintext.to_owned(), IpldCodec::Raw.into())
+        .await
+        .unwrap();
+    assert_eq!(
+        blockstore.get_block(&cid).await.unwrap().to_vec(),
+        plaintext
+    );
+
+    let stored = FFIStore::get_block(&kv, cid.to_bytes()).unwrap();
+    assert_ne!(stored, plaintext);
+    assert!(!stored
+        .windows(plaintext.len())
+        .any(|window| window == plaintext.as_slice()));
+
+    let other_key = StaticKeyProvider::new([4u8; 32]);
+    let wrong = EncryptedStore::new(Box::new(kv.clone()), &other_key).unwrap();
+    let err = wrong.get_block(cid.to_bytes()).unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<WnfsUtilsError>(),
+        Some(&WnfsUtilsError::BlockDecryption)
+    );
+}
+
+#[tokio::test]
+async fn swapped_blocks_are_rejected() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("store").to_string_lossy().to_string();
+    let kv = KVBlockStore::new(path, CODEC_DAG_CBOR);
+    let key = StaticKeyProvider::new([3u8; 32]);
+    let encrypted = EncryptedStore::new(Box::new(kv.clone()), &key).unwrap();
+    let blockstore = FFIFriendlyBlockStore::new(Box::new(encrypted.clone()));
+
+    let first = blockstore
+        .put_block(b"first".to_vec(), IpldCodec::Raw.into())
+        .await
+        .unwrap();
+    let second = blockstore
+        .put_block(b"second".to_vec(), IpldCodec::Raw.into())
+        .await
+        .unwrap();
+    let sealed_second = FFIStore::get_block(&kv, second.to_bytes()).unwrap();
+    FFIStore::put_block(&kv, first.to_bytes(), sealed_second).unwrap();
+
+    assert!(encrypted.get_block(first.to_bytes()).is_err());
+    assert!(encrypted.get_block(second.to_bytes()).is_ok());
+}
+
+#[tokio::test]
+async fn padding_hides_block_sizes() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("store").to_string_lossy().to_string();
+    let kv = KVBlockStore::new(path, CODEC_DAG_CBOR);
+    let key = StaticKeyProvider::new([3u8; 32]);
+    let encrypted = EncryptedStore::new(Box::new(kv.clone()), &key)
+        .unwrap()
+        .with_size_padding(256);
+    let blockstore = FFIFriendlyBlockStore::new(Box::new(encrypted.clone()));
+
+    let short = blockstore
+        .put_block(b"a".to_vec(), IpldCodec::Raw.into())
+        .await
+        .unwrap();
+    let longer = blockstore
+        .put_block(vec![7u8; 200], IpldCodec::Raw.into())
+        .await
+        .unwrap();
+    let large = blockstore
+        .put_block(vec![9u8; 300], IpldCodec::Raw.into())
+        .await
+        .unwrap();
+    let full = blockstore
+        .put_block(vec![5u8; 256 * 1024], IpldCodec::Raw.into())
+        .await
+        .unwrap();
+    assert_eq!(blockstore.get_block(&short).await.unwrap().to_vec(), b"a");
+    assert_eq!(
+        blockstore.get_block(&longer).await.unwrap().to_vec(),
+        vec![7u8; 200]
+    );
+    assert_eq!(
+        blockstore.get_block(&large).await.unwrap().to_vec(),
+        vec![9u8; 300]
+    );
+
+    let stored_len = |cid: &libipld::Cid| FFIStore::get_block(&kv, cid.to_bytes()).unwrap().len();
+    assert_eq!(stored_len(&short), stored_len(&longer));
+    assert_eq!(stored_len(&large), 24 + 304 + 16);
+    // A full content block grows by a few percent, not to the next power of two.
+    assert_eq!(stored_len(&full), 24 + 270_336 + 16);
+    assert_eq!(blockstore.get_block(&full).await.unwrap().len(), 256 * 1024);
+
+    let unpadded = EncryptedStore::new(Box::new(kv.clone()), &key).unwrap();
+    let err = unpadded.get_block(short.to_bytes()).unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<WnfsUtilsError>(),
+        Some(&WnfsUtilsError::BlockDecryption)
+    );
+}
@@ -0,0 +1,354 @@
+//! Typed errors for untrusted input: bytes handed over the FFI boundary and data read from
+//! stores the client doesn't control.
+//!
+//! Every error maps to an [`ErrorCode`], what bindings show their callers instead of the message.
+//! Store errors keep their type and are classified with `ErrorCode::of`. Helper methods return
+//! their errors as strings, which carry the code at the end of the message, e.g.
+//! `a/b not found (code not_found)`: messages of a `WnfsUtilsError` carry it anyway and
+//! other errors are converted with [`describe`]. `ErrorCode::of_message` reads it back.
+
+use std::{any::Any, fmt};
+
+use wnfs::common::BlockStoreError;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WnfsUtilsError {
+    InvalidCid(String),
+    InvalidKeyLength(usize),
+    InvalidTimestamp(i64),
+    BlockTooLarge {
+        size: usize,
+        limit: usize,
+    },
+    PathTooDeep {
+        depth: usize,
+        limit: usize,
+    },
+    PathTooLong {
+        length: usize,
+        limit: usize,
+    },
+    NameTooLong {
+        name: String,
+        length: usize,
+        limit: usize,
+    },
+    InvalidPath {
+        path: String,
+        reason: String,
+    },
+    TooManyEntries {
+        count: usize,
+        limit: usize,
+    },
+    FileTooLarge {
+        size: u64,
+        limit: u64,
+    },
+    LegalHold {
+        path: String,
+    },
+    LegalHoldRelease {
+        path: String,
+    },
+    BlockDecryption,
+    InvalidGatewayUrl {
+        url: String,
+        reason: String,
+    },
+    ForestLocked,
+    StaleRoot {
+        expected: String,
+        found: String,
+    },
+    StoreOpen(String),
+    Runtime(String),
+    Overloaded {
+        limit: usize,
+    },
+    NetworkRestricted,
+    Cancelled,
+    WrongKey,
+    InvalidMnemonic(String),
+    UnsupportedCidConfig(String),
+    PermissionDenied {
+        operation: String,
+    },
+    BlockIntegrity {
+        cid: String,
+        reason: String,
+    },
+    ContentRejected {
+        scanner: String,
+        reason: String,
+    },
+    ReservedMetadataKey(String),
+    NotFound(String),
+    StoreUnavailable(String),
+    CopyMismatch {
+        path: String,
+    },
+    DuplicateChanged {
+        path: String,
+    },
+    CorruptLayout {
+        path: String,
+        reason: String,
+    },
+}
+
+impl fmt::Display for WnfsUtilsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.describe(f)?;
+        write!(f, " (code {})", self.code().as_str())
+    }
+}
+
+impl std::error::Error for WnfsUtilsError {}
+
+impl WnfsUtilsError {
+    // The message without its code.
+    fn describe(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidCid(inner) => write!(f, "invalid CID bytes: {inner}"),
+            Self::InvalidKeyLength(inner) => {
+                write!(f, "invalid wnfs key: expected 32 bytes, got {inner}")
+            }
+            Self::InvalidTimestamp(inner) => write!(f, "invalid modification time: {inner}"),
+            Self::BlockTooLarge { size, limit } => write!(
+                f,
+                "block of {size} bytes exceeds the limit of {limit} bytes"
+            ),
+            Self::PathTooDeep { depth, limit } => {
+                write!(f, "path depth {depth} exceeds the limit of {limit}")
+            }
+            Self::PathTooLong { length, limit } => write!(
+                f,
+                "path of {length} bytes exceeds the limit of {limit} bytes"
+            ),
+            Self::NameTooLong {
+                name,
+                length,
+                limit,
+            } => write!(
+                f,
+                "name {name:?} of {length} bytes exceeds the limit of {limit} bytes"
+            ),
+            Self::InvalidPath { path, reason } => write!(f, "invalid path {path:?}: {reason}"),
+            Self::TooManyEntries { count, limit } => write!(
+                f,
+                "directory with {count} entries exceeds the limit of {limit}"
+            ),
+            Self::FileTooLarge { size, limit } => {
+                write!(f, "file of {size} bytes exceeds the limit of {limit} bytes")
+            }
+            Self::LegalHold { path } => write!(f, "{path} is under legal hold"),
+            Self::LegalHoldRelease { path } => {
+                write!(f, "no legal hold on {path} for the given custodian key")
+            }
+            Self::BlockDecryption => write!(f, "block can't be decrypted with the device key"),
+            Self::InvalidGatewayUrl { url, reason } => {
+                write!(f, "invalid gateway URL {url}: {reason}")
+            }
+            Self::ForestLocked => {
+                write!(f, "the forest is already open for writing in this process")
+            }
+            Self::StaleRoot { expected, found } => {
+                write!(f, "the published root moved from {expected} to {found}")
+            }
+            Self::StoreOpen(inner) => write!(f, "unable to open store: {inner}"),
+            Self::Runtime(inner) => write!(f, "unable to create a runtime: {inner}"),
+            Self::Overloaded { limit } => write!(f, "{limit} operations are already waiting"),
+            Self::NetworkRestricted => write!(
+                f,
+                "connections are restricted to configured hosts in this build"
+            ),
+            Self::Cancelled => write!(f, "the operation was cancelled"),
+            Self::WrongKey => write!(f, "the key doesn't match the helper's wnfs key"),
+            Self::InvalidMnemonic(inner) => write!(f, "invalid mnemonic: {inner}"),
+            Self::UnsupportedCidConfig(inner) => {
+                write!(f, "unsupported CID configuration: {inner}")
+            }
+            Self::PermissionDenied { operation } => {
+                write!(f, "{operation} isn't permitted by this handle")
+            }
+            Self::BlockIntegrity { cid, reason } => {
+                write!(f, "block {cid} failed verification: {reason}")
+            }
+            Self::ContentRejected { scanner, reason } => {
+                write!(f, "content scanner {scanner} rejected the write: {reason}")
+            }
+            Self::ReservedMetadataKey(inner) => {
+                write!(f, "metadata key {inner:?} is maintained by WNFS")
+            }
+            Self::NotFound(inner) => write!(f, "{inner} not found"),
+            Self::StoreUnavailable(inner) => write!(f, "block store unavailable: {inner}"),
+            Self::CopyMismatch { path } => write!(f, "the copy of {path} doesn't match its source"),
+            Self::DuplicateChanged { path } => {
+                write!(f, "{path} changed since its duplicates were analyzed")
+            }
+            Self::CorruptLayout { path, reason } => {
+                write!(f, "paged file {path} has a corrupt layout: {reason}")
+            }
+        }
+    }
+
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Self::InvalidCid(_)
+            | Self::InvalidTimestamp(_)
+            | Self::InvalidPath { .. }
+            | Self::InvalidGatewayUrl { .. }
+            | Self::ReservedMetadataKey(_) => ErrorCode::InvalidArgument,
+            Self::BlockTooLarge { .. }
+            | Self::PathTooDeep { .. }
+            | Self::PathTooLong { .. }
+            | Self::NameTooLong { .. }
+            | Self::TooManyEntries { .. }
+            | Self::FileTooLarge { .. }
+            | Self::Overloaded { .. } => ErrorCode::LimitExceeded,
+            Self::InvalidKeyLength(_)
+            | Self::BlockDecryption
+            | Self::WrongKey
+            | Self::InvalidMnemonic(_) => ErrorCode::InvalidKey,
+            Self::LegalHold { .. } | Self::LegalHoldRelease { .. } => ErrorCode::Held,
+            Self::ForestLocked | Self::StaleRoot { .. } | Self::DuplicateChanged { .. } => {
+                ErrorCode::Conflict
+            }
+            Self::StoreOpen(_) | Self::NetworkRestricted | Self::StoreUnavailable(_) => {
+                ErrorCode::StoreUnavailable
+            }
+            Self::PermissionDenied { .. } | Self::ContentRejected { .. } => {
+                ErrorCode::PermissionDenied
+            }
+            Self::BlockIntegrity { .. }
+            | Self::CopyMismatch { .. }
+            | Self::CorruptLayout { .. } => ErrorCode::Corrupt,
+            Self::Cancelled => ErrorCode::Cancelled,
+            Self::NotFound(_) => ErrorCode::NotFound,
+            Self::UnsupportedCidConfig(_) => ErrorCode::Unsupported,
+            Self::Runtime(_) => ErrorCode::Other,
+        }
+    }
+}
+
+/// What went wrong, coarse enough for callers to act on. The values are those of the C ABI's
+/// `WnfsStatus`, which adds `Ok` and `Panic`, and stay fixed: new codes are added at the end.
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ErrorCode {
+    /// A malformed CID, path, timestamp or URL was passed.
+    InvalidArgument = 1,
+    /// Nothing exists at the path, or the store has no block for the CID.
+    NotFound = 2,
+    /// The path is under legal hold.
+    Held = 3,
+    /// A configured limit was exceeded.
+    LimitExceeded = 4,
+    /// A block doesn't match its CID.
+    Corrupt = 5,
+    /// The block store failed or couldn't be reached; retrying later may succeed.
+    StoreUnavailable = 6,
+    Other = 8,
+    /// The key is malformed or isn't the one the forest or block was encrypted with.
+    InvalidKey = 9,
+    Cancelled = 10,
+    /// Another writer holds the forest or moved its root.
+    Conflict = 11,
+    PermissionDenied = 12,
+    Unsupported = 13,
+}
+
+impl ErrorCode {
+    const ALL: [Self; 12] = [
+        Self::InvalidArgument,
+        Self::NotFound,
+        Self::Held,
+        Self::LimitExceeded,
+        Self::Corrupt,
+        Self::StoreUnavailable,
+        Self::Other,
+        Self::InvalidKey,
+        Self::Cancelled,
+        Self::Conflict,
+        Self::PermissionDenied,
+        Self::Unsupported,
+    ];
+
+    /// The code of a store or library error, from the first typed error in its chain.
+    pub fn of(error: &anyhow::Error) -> Self {
+        for cause in error.chain() {
+            if let Some(error) = cause.downcast_ref::<WnfsUtilsError>() {
+                return error.code();
+            }
+            if let Some(BlockStoreError::CIDNotFound(_)) = cause.downcast_ref::<BlockStoreError>() {
+                return Self::NotFound;
+            }
+            if is_unreachable(cause) {
+                return Self::StoreUnavailable;
+            }
+        }
+        // An error the helper returned as a string and that was wrapped since.
+        Self::of_message(&error.to_string())
+    }
+
+    /// The code an error message of the helper carries, see the module docs, `Other` if it
+    /// carries none. Wrapped messages end with the code of the outermost error.
+    pub fn of_message(message: &str) -> Self {
+        message
+            .rmatch_indices(" (code ")
+            .find_map(|(start, marker)| {
+                let name = message[start + marker.len()..].split(')').next()?;
+                Self::ALL.into_iter().find(|code| code.as_str() == name)
+            })
+            .unwrap_or(Self::Other)
+    }
+
+    /// A stable name for logs and bindings without the numeric values.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Other => "other",
+            Self::NotFound => "not_found",
+            Self::InvalidArgument => "invalid_argument",
+            Self::InvalidKey => "invalid_key",
+            Self::StoreUnavailable => "store_unavailable",
+            Self::Corrupt => "corrupt",
+            Self::Cancelled => "cancelled",
+            Self::Held => "held",
+            Self::LimitExceeded => "limit_exceeded",
+            Self::Conflict => "conflict",
+            Self::PermissionDenied => "permission_denied",
+            Self::Unsupported => "unsupported",
+        }
+    }
+}
+
+// Whether `cause` is an HTTP request that timed out or never reached the server.
+#[cfg(feature = "reqwest")]
+fn is_unreachable(cause: &(dyn std::error::Error + 'static)) -> bool {
+    match cause.downcast_ref::<reqwest::Error>() {
+        Some(error) => error.is_timeout() || error.is_connect() || error.is_request(),
+        None => false,
+    }
+}
+
+#[cfg(not(feature = "reqwest"))]
+fn is_unreachable(_cause: &(dyn std::error::Error + 'static)) -> bool {
+    false
+}
+
+/// The message of `error` as the helper returns it, carrying its code, see the module docs.
+/// Store and wnfs errors are classified with `ErrorCode::of`; other errors keep the code their
+/// message carries, if any.
+pub fn describe<E: fmt::Display + 'static>(error: &E) -> String {
+    let message = error.to_string();
+    let code = match (error as &dyn Any).downcast_ref::<anyhow::Error>() {
+        Some(error) => ErrorCode::of(error),
+        None => ErrorCode::of_message(&message),
+    };
+    match code {
+        ErrorCode::Other => message,
+        code if ErrorCode::of_message(&message) == code => message,
+        code => format!("{} (code {})", message, code.as_str()),
+    }
+}
@@ -0,0 +1,202 @@
+//! Forwarding of crate-level failures to an app supplied [`ErrorSink`] (e.g. a Sentry client),
+//! with deduplication of repeated errors and a global rate limit so a failing store can't flood
+//! the sink.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use once_cell::sync::Lazy;
+use web_time::Instant;
+
+use crate::request_id;
+
+/// A structured error event.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ErrorEvent {
+    /// Name of the failing helper operation, e.g. `"write_file"`.
+    pub operation: &'static str,
+    pub message: String,
+    /// How many times this error occurred since it was last delivered, including this one.
+    pub occurrences: u64,
+    pub first_seen: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+    /// Request ID of the occurrence being delivered, see `request_id`.
+    pub request_id: Option<String>,
+}
+
+pub trait ErrorSink: Send + Sync {
+    fn report(&self, event: &ErrorEvent);
+}
+
+#[derive(Debug, Clone)]
+pub struct ReportingPolicy {
+    /// Identical errors (same operation and message) are delivered at most once per window;
+    /// repeats are folded into the `occurrences` of the next delivery.
+    pub dedup_window: Duration,
+    /// Maximum number of events delivered per minute across all errors.
+    pub max_events_per_minute: u32,
+    /// Maximum number of distinct errors tracked for deduplication. Errors idle for longer
+    /// than the dedup window are forgotten first, then the least recently seen.
+    pub max_tracked_errors: usize,
+}
+
+impl Default for ReportingPolicy {
+    fn default() -> Self {
+        Self {
+            dedup_window: Duration::from_secs(60),
+            max_events_per_minute: 30,
+            max_tracked_errors: 1024,
+        }
+    }
+}
+
+struct PendingError {
+    last_delivered: Option<Instant>,
+    occurrences: u64,
+    first_seen: DateTime<Utc>,
+    last_seen: Instant,
+}
+
+/// Applies a [`ReportingPolicy`] in front of a sink.
+pub struct ErrorReporter {
+    sink: Arc<dyn ErrorSink>,
+    policy: ReportingPolicy,
+    state: Mutex<ReporterState>,
+}
+
+#[derive(Default)]
+struct ReporterState {
+    errors: HashMap<(&'static str, String), PendingError>,
+    window_start: Option<Instant>,
+    delivered_in_window: u32,
+}
+
+impl ErrorReporter {
+    pub fn new(sink: Arc<dyn ErrorSink>, policy: ReportingPolicy) -> Self {
+        Self {
+            sink,
+            policy,
+            state: Mutex::new(ReporterState::default()),
+        }
+    }
+
+    /// Records an error and delivers it to the sink unless deduplication or the rate limit
+    /// holds it back. Returns whether the sink was called.
+    pub fn report(&self, operation: &'static str, message: &str) -> bool {
+        let now = Instant::now();
+        let event = {
+            let mut state = match self.state.lock() {
+                Ok(state) => state,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            let key = (operation, message.to_string());
+            if !state.errors.contains_key(&key) {
+                self.make_room(&mut state, now);
+            }
+            let pending = state.errors.entry(key).or_insert_with(|| PendingError {
+                last_delivered: None,
+                occurrences: 0,
+                first_seen: Utc::now(),
+                last_seen: now,
+            });
+            pending.occurrences += 1;
+            pending.last_seen = now;
+            if let Some(last) = pending.last_delivered {
+                if now.duration_since(last) < self.policy.dedup_window {
+                    return false;
+                }
+            }
+            let event = ErrorEvent {
+                operation,
+                message: message.to_string(),
+                occurrences: pending.occurrences,
+                first_seen: pending.first_seen,
+                last_seen: Utc::now(),
+                request_id: request_id::current(),
+            };
+
+            let window_expired = state
+                .window_start
+                .map(|start| now.duration_since(start) >= Duration::from_secs(60))
+                .unwrap_or(true);
+            if window_expired {
+                state.window_start = Some(now);
+                state.delivered_in_window = 0;
+            }
+            if state.delivered_in_window >= self.policy.max_events_per_minute {
+                return false;
+            }
+            state.delivered_in_window += 1;
+            if let Some(pending) = state.errors.get_mut(&(operation, message.to_string())) {
+                pending.last_delivered = Some(now);
+                pending.occurrences = 0;
+                pending.first_seen = Utc::now();
+            }
+            event
+        };
+        // Deliver outside the lock so a slow sink doesn't block other reporters.
+        self.sink.report(&event);
+        true
+    }
+
+    // Evicts tracked errors until a new one fits within `max_tracked_errors`.
+    fn make_room(&self, state: &mut ReporterState, now: Instant) {
+        if state.errors.len() < self.policy.max_tracked_errors {
+            return;
+        }
+        let window = self.policy.dedup_window;
+        state
+            .errors
+            .retain(|_, pending| now.duration_since(pending.last_seen) < window);
+        while !state.errors.is_empty() && state.errors.len() >= self.policy.max_tracked_errors {
+            let oldest = state
+                .errors
+                .iter()
+                .min_by_key(|(_, pending)| pending.last_seen)
+                .map(|(key, _)| key.to_owned());
+            if let Some(oldest) = oldest {
+                state.errors.remove(&oldest);
+            }
+        }
+    }
+}
+
+static GLOBAL_REPORTER: Lazy<Mutex<Option<Arc<ErrorReporter>>>> = Lazy::new(|| Mutex::new(None));
+
+/// Installs the process-wide sink used by the helper's FFI-facing operations.
+pub fn install(sink: Arc<dyn ErrorSink>, policy: ReportingPolicy) {
+    if let Ok(mut reporter) = GLOBAL_REPORTER.lock() {
+        *reporter = Some(Arc::new(ErrorReporter::new(sink, policy)));
+    }
+}
+
+/// Removes the installed sink.
+pub fn uninstall() {
+    if let Ok(mut reporter) = GLOBAL_REPORTER.lock() {
+        *reporter = None;
+    }
+}
+
+/// Reports the error of `result`, if any, to the installed sink and returns `result` unchanged.
+pub fn report_result<T, E: ToString>(
+    operation: &'static str,
+    result: Result<T, E>,
+) -> Result<T, E> {
+    if let Err(e) = &result {
+        let reporter = GLOBAL_REPORTER
+            .lock()
+            .ok()
+            .and_then(|reporter| reporter.as_ref().map(Arc::clone));
+        if let Some(reporter) = reporter {
+            reporter.report(operation, &e.to_string());
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod error_sink_tests;
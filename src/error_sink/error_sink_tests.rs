@@ -0,0 +1,101 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use crate::error_sink::{ErrorEvent, ErrorReporter, ErrorSink, ReportingPolicy};
+
+#[derive(Default)]
+struct CollectingSink {
+    events: Mutex<Vec<ErrorEvent>>,
+}
+
+impl ErrorSink for CollectingSink {
+    fn report(&self, event: &ErrorEvent) {
+        self.events.lock().unwrap().push(event.to_owned());
+    }
+}
+
+#[test]
+fn deduplicates_within_window() {
+    let sink = Arc::new(CollectingSink::default());
+    let reporter = ErrorReporter::new(
+        sink.clone(),
+        ReportingPolicy {
+            dedup_window: Duration::from_millis(50),
+            max_events_per_minute: 100,
+            ..Default::default()
+        },
+    );
+
+    assert!(reporter.report("write_file", "store unavailable"));
+    assert!(!reporter.report("write_file", "store unavailable"));
+    assert!(!reporter.report("write_file", "store unavailable"));
+    // A different operation is a different error.
+    assert!(reporter.report("mkdir", "store unavailable"));
+
+    std::thread::sleep(Duration::from_millis(60));
+    assert!(reporter.report("write_file", "store unavailable"));
+
+    let events = sink.events.lock().unwrap();
+    assert_eq!(events.len(), 3);
+    assert_eq!(events[0].occurrences, 1);
+    assert_eq!(events[1].operation, "mkdir");
+    // The two suppressed repeats are folded into the next delivery.
+    assert_eq!(events[2].occurrences, 3);
+}
+
+#[test]
+fn rate_limits_distinct_errors() {
+    let sink = Arc::new(CollectingSink::default());
+    let reporter = ErrorReporter::new(
+        sink.clone(),
+        ReportingPolicy {
+            dedup_window: Duration::from_secs(60),
+            max_events_per_minute: 2,
+            ..Default::default()
+        },
+    );
+
+    for i in 0..5 {
+        reporter.report("read_file", &format!("block {} not found", i));
+    }
+    assert_eq!(sink.events.lock().unwrap().len(), 2);
+}
+
+#[test]
+fn bounds_the_tracked_errors() {
+    let sink = Arc::new(CollectingSink::default());
+    let reporter = ErrorReporter::new(
+        sink.clone(),
+        ReportingPolicy {
+            max_events_per_minute: 100,
+            max_tracked_errors: 3,
+            ..Default::default()
+        },
+    );
+
+    for i in 0..10 {
+        reporter.report("read_file", &format!("block {} not found", i));
+    }
+    assert_eq!(reporter.state.lock().unwrap().errors.len(), 3);
+    // The most recent errors are still deduplicated, evicted ones are delivered again.
+    assert!(!reporter.report("read_file", "block 9 not found"));
+    assert!(reporter.report("read_file", "block 0 not found"));
+}
+
+#[test]
+fn events_carry_the_current_request_id() {
+    let sink = Arc::new(CollectingSink::default());
+    let reporter = ErrorReporter::new(sink.clone(), ReportingPolicy::default());
+
+    reporter.report("read_file", "no request");
+    {
+        let _scope = crate::request_id::enter("abc123".into());
+        reporter.report("read_file", "in a request");
+    }
+
+    let events = sink.events.lock().unwrap();
+    assert_eq!(events[0].request_id, None);
+    assert_eq!(events[1].request_id.as_deref(), Some("abc123"));
+}
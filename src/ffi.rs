@@ -0,0 +1,970 @@
+//! C ABI over the helper, for platforms binding the crate without writing Rust glue of their own.
+//! The declarations are in `include/wnfsutils.h`.
+//!
+//! Every function returns a [`WnfsStatus`] and never unwinds into the caller: a panic is caught
+//! and reported as `WNFS_STATUS_PANIC`. The message of the last failure on the calling thread is
+//! available from `wnfs_last_error_message`. Paths are UTF-8 C strings split at `/`, see
+//! `PrivateDirectoryHelper::parse_path`, and CIDs are passed and returned in their string form.
+//! Buffers returned by the library are freed with `wnfs_bytes_free`.
+//!
+//! Blocks are read and written through the host's [`WnfsBlockStoreCallbacks`]. A helper handle
+//! is not thread-safe: the host serializes the calls made with it, and frees it with
+//! `wnfs_helper_free`, which also releases the callbacks' context. A failed open releases the
+//! context right away. `get_block` reports a block the host doesn't have with
+//! [`GET_BLOCK_MISSING`], which fails reads with `WNFS_STATUS_NOT_FOUND`.
+//!
+//! A process has at most one helper on a forest: opening another with the same wnfs key fails
+//! with `WNFS_STATUS_CONFLICT` until the first is freed, see `ExclusiveSession`.
+//!
+//! A host handing subtrees to plugins turns its helper into a [`WnfsScopedHandle`] on the whole
+//! forest and narrows it for each plugin, see `ScopedHandle`.
+//!
+//! The `_observed` calls report their progress to a [`WnfsProgressCallback`], which can cancel
+//! them, see `crate::progress`.
+//!
+//! The tests check the header against the `#[repr(C)]` types and constants here.
+
+use std::{
+    cell::RefCell,
+    ffi::{c_char, c_void, CStr, CString},
+    panic::{catch_unwind, AssertUnwindSafe},
+    ptr,
+    rc::Rc,
+    slice,
+    sync::Arc,
+};
+
+use anyhow::Result;
+use libipld::Cid;
+use serde_json::json;
+use wnfs::common::Metadata;
+
+use crate::blockstore::{cid_from_bytes, FFIFriendlyBlockStore, FFIStore};
+use crate::error::{ErrorCode, WnfsUtilsError};
+use crate::private_forest::{FileMetadata, Permissions, PrivateDirectoryHelper, ScopedHandle};
+use crate::progress::{CancellationToken, OperationObserver, OperationProgress, ProgressCallback};
+
+/// `Ok`, `Panic` or the [`ErrorCode`] of the failure, with the same value.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WnfsStatus {
+    Ok = 0,
+    /// A null pointer, invalid UTF-8, an unparsable CID or a malformed path was passed.
+    InvalidArgument = ErrorCode::InvalidArgument as isize,
+    /// Nothing exists at the path.
+    NotFound = ErrorCode::NotFound as isize,
+    /// The path is under legal hold.
+    Held = ErrorCode::Held as isize,
+    /// The path or content break a configured limit.
+    LimitExceeded = ErrorCode::LimitExceeded as isize,
+    /// A block doesn't match its CID.
+    Corrupt = ErrorCode::Corrupt as isize,
+    /// The block store failed or couldn't be reached.
+    StoreUnavailable = ErrorCode::StoreUnavailable as isize,
+    Panic = 7,
+    Other = ErrorCode::Other as isize,
+    /// The key is malformed or doesn't open the forest.
+    InvalidKey = ErrorCode::InvalidKey as isize,
+    Cancelled = ErrorCode::Cancelled as isize,
+    /// Another writer holds the forest or moved its root.
+    Conflict = ErrorCode::Conflict as isize,
+    PermissionDenied = ErrorCode::PermissionDenied as isize,
+    Unsupported = ErrorCode::Unsupported as isize,
+}
+
+impl From<ErrorCode> for WnfsStatus {
+    fn from(code: ErrorCode) -> Self {
+        match code {
+            ErrorCode::InvalidArgument => Self::InvalidArgument,
+            ErrorCode::NotFound => Self::NotFound,
+            ErrorCode::Held => Self::Held,
+            ErrorCode::LimitExceeded => Self::LimitExceeded,
+            ErrorCode::Corrupt => Self::Corrupt,
+            ErrorCode::StoreUnavailable => Self::StoreUnavailable,
+            ErrorCode::Other => Self::Other,
+            ErrorCode::InvalidKey => Self::InvalidKey,
+            ErrorCode::Cancelled => Self::Cancelled,
+            ErrorCode::Conflict => Self::Conflict,
+            ErrorCode::PermissionDenied => Self::PermissionDenied,
+            ErrorCode::Unsupported => Self::Unsupported,
+        }
+    }
+}
+
+/// A buffer allocated by the library, freed with `wnfs_bytes_free`.
+#[repr(C)]
+pub struct WnfsBytes {
+    pub data: *mut u8,
+    pub len: usize,
+}
+
+impl WnfsBytes {
+    fn from_vec(bytes: Vec<u8>) -> Self {
+        let len = bytes.len();
+        let data = Box::into_raw(bytes.into_boxed_slice()) as *mut u8;
+        Self { data, len }
+    }
+}
+
+/// A buffer owned by the host, filled in by `get_block`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct WnfsHostBytes {
+    pub data: *const u8,
+    pub len: usize,
+}
+
+/// Returned by `get_block` for a block the host doesn't have, reported as `WNFS_STATUS_NOT_FOUND`
+/// rather than a store failure.
+pub const GET_BLOCK_MISSING: i32 = 1;
+
+/// The host's block store. Callbacks return 0 on success and any other value on failure.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct WnfsBlockStoreCallbacks {
+    /// Passed to every callback.
+    pub context: *mut c_void,
+    /// Fills `out` with the block named by the CID bytes. The buffer stays the host's and is
+    /// handed back to `release` once it was copied.
+    pub get_block: Option<
+        extern "C" fn(
+            context: *mut c_void,
+            cid: *const u8,
+            cid_len: usize,
+            out: *mut WnfsHostBytes,
+        ) -> i32,
+    >,
+    pub put_block: Option<
+        extern "C" fn(
+            context: *mut c_void,
+            cid: *const u8,
+            cid_len: usize,
+            data: *const u8,
+            data_len: usize,
+        ) -> i32,
+    >,
+    pub release: Option<extern "C" fn(context: *mut c_void, bytes: WnfsHostBytes)>,
+    /// Called once the helper using `context` was freed, or failed to open.
+    pub free_context: Option<extern "C" fn(context: *mut c_void)>,
+}
+
+// Releases the context once the last clone of the store is dropped.
+struct OwnedCallbacks(WnfsBlockStoreCallbacks);
+
+impl Drop for OwnedCallbacks {
+    fn drop(&mut self) {
+        if let Some(free_context) = self.0.free_context {
+            free_context(self.0.context);
+        }
+    }
+}
+
+#[derive(Clone)]
+struct CallbackStore {
+    callbacks: Rc<OwnedCallbacks>,
+}
+
+impl<'a> FFIStore<'a> for CallbackStore {
+    fn get_block(&self, cid: Vec<u8>) -> Result<Vec<u8>> {
+        let callbacks = &self.callbacks.0;
+        let get_block = callbacks
+            .get_block
+            .ok_or_else(|| WnfsUtilsError::StoreUnavailable("no get_block callback".to_string()))?;
+        let mut out = WnfsHostBytes {
+            data: ptr::null(),
+            len: 0,
+        };
+        let status = get_block(callbacks.context, cid.as_ptr(), cid.len(), &mut out);
+        match status {
+            0 => {}
+            GET_BLOCK_MISSING => {
+                let cid = cid_from_bytes(&cid)?;
+                return Err(WnfsUtilsError::NotFound(format!("block {}", cid)).into());
+            }
+            _ => return Err(callback_failed("get_block", status)),
+        }
+        let data = match out.data.is_null() {
+            true => Vec::new(),
+            // Safety: the host hands over `len` readable bytes, kept until `release`.
+            false => unsafe { slice::from_raw_parts(out.data, out.len) }.to_vec(),
+        };
+        if let Some(release) = callbacks.release {
+            release(callbacks.context, out);
+        }
+        Ok(data)
+    }
+
+    fn put_block(&self, cid: Vec<u8>, bytes: Vec<u8>) -> Result<()> {
+        let callbacks = &self.callbacks.0;
+        let put_block = callbacks
+            .put_block
+            .ok_or_else(|| WnfsUtilsError::StoreUnavailable("no put_block callback".to_string()))?;
+        let status = put_block(
+            callbacks.context,
+            cid.as_ptr(),
+            cid.len(),
+            bytes.as_ptr(),
+            bytes.len(),
+        );
+        match status {
+            0 => Ok(()),
+            _ => Err(callback_failed("put_block", status)),
+        }
+    }
+}
+
+fn callback_failed(callback: &str, status: i32) -> anyhow::Error {
+    WnfsUtilsError::StoreUnavailable(format!("callback {} failed with {}", callback, status)).into()
+}
+
+/// Progress of an observed call, see `OperationProgress`. `on_progress` is called on the
+/// calling thread, only while the call runs; returning non-zero cancels the call at the next
+/// block, which then fails with `WNFS_STATUS_CANCELLED`.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct WnfsProgressCallback {
+    /// Passed to `on_progress`.
+    pub context: *mut c_void,
+    /// `operation` lives for the callback only, `bytes_total` is -1 when the size isn't known.
+    pub on_progress: Option<
+        extern "C" fn(
+            context: *mut c_void,
+            operation: *const c_char,
+            bytes_done: u64,
+            bytes_total: i64,
+            blocks_fetched: u64,
+        ) -> i32,
+    >,
+}
+
+struct HostProgress {
+    callback: WnfsProgressCallback,
+    cancellation: CancellationToken,
+}
+
+// Safety: the observer lives for one call and its future runs on the calling thread, see
+// `PrivateDirectoryHelper::run_limited`.
+unsafe impl Send for HostProgress {}
+unsafe impl Sync for HostProgress {}
+
+impl ProgressCallback for HostProgress {
+    fn on_progress(&self, progress: &OperationProgress) {
+        let Some(on_progress) = self.callback.on_progress else {
+            return;
+        };
+        let operation = CString::new(progress.operation).unwrap_or_default();
+        let bytes_total = progress
+            .bytes_total
+            .and_then(|total| i64::try_from(total).ok())
+            .unwrap_or(-1);
+        let status = on_progress(
+            self.callback.context,
+            operation.as_ptr(),
+            progress.bytes_done,
+            bytes_total,
+            progress.blocks_fetched,
+        );
+        if status != 0 {
+            self.cancellation.cancel();
+        }
+    }
+}
+
+/// A helper opened with `wnfs_helper_init` or `wnfs_helper_load`.
+pub struct WnfsHelper {
+    helper: PrivateDirectoryHelper<'static>,
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = RefCell::new(None);
+}
+
+type Failure = (WnfsStatus, String);
+
+fn invalid_argument(message: &str) -> Failure {
+    (WnfsStatus::InvalidArgument, message.to_string())
+}
+
+// Maps a helper error to the status of the code it carries.
+fn failed(message: String) -> Failure {
+    (ErrorCode::of_message(&message).into(), message)
+}
+
+// Runs `call`, recording its failure or panic for `wnfs_last_error_message`.
+fn guard(call: impl FnOnce() -> Result<(), Failure>) -> WnfsStatus {
+    let (status, message) = match catch_unwind(AssertUnwindSafe(call)) {
+        Ok(Ok(())) => return WnfsStatus::Ok,
+        Ok(Err(failure)) => failure,
+        Err(_) => (WnfsStatus::Panic, "wnfsutils panicked".to_string()),
+    };
+    let message = CString::new(message.replace('\0', " ")).ok();
+    LAST_ERROR.with(|last| *last.borrow_mut() = message);
+    status
+}
+
+unsafe fn path_arg(path: *const c_char) -> Result<Vec<String>, Failure> {
+    if path.is_null() {
+        return Err(invalid_argument("path is null"));
+    }
+    let path = CStr::from_ptr(path)
+        .to_str()
+        .map_err(|_| invalid_argument("path is not UTF-8"))?;
+    Ok(PrivateDirectoryHelper::parse_path(path.to_string()))
+}
+
+unsafe fn key_arg(key: *const c_char) -> Result<Option<String>, Failure> {
+    if key.is_null() {
+        return Ok(None);
+    }
+    let key = CStr::from_ptr(key)
+        .to_str()
+        .map_err(|_| invalid_argument("idempotency key is not UTF-8"))?;
+    Ok(Some(key.to_string()))
+}
+
+unsafe fn bytes_arg<'b>(data: *const u8, len: usize) -> Result<&'b [u8], Failure> {
+    match (data.is_null(), len) {
+        (true, 0) => Ok(&[]),
+        (true, _) => Err(invalid_argument("buffer is null")),
+        (false, _) => Ok(slice::from_raw_parts(data, len)),
+    }
+}
+
+unsafe fn helper_arg<'h>(
+    helper: *mut WnfsHelper,
+) -> Result<&'h mut PrivateDirectoryHelper<'static>, Failure> {
+    helper
+        .as_mut()
+        .map(|handle| &mut handle.helper)
+        .ok_or_else(|| invalid_argument("helper is null"))
+}
+
+unsafe fn out_arg<'o, T>(out: *mut T) -> Result<&'o mut T, Failure> {
+    out.as_mut()
+        .ok_or_else(|| invalid_argument("output pointer is null"))
+}
+
+unsafe fn store_arg(
+    callbacks: *const WnfsBlockStoreCallbacks,
+) -> Result<FFIFriendlyBlockStore<'static>, Failure> {
+    let callbacks = callbacks
+        .as_ref()
+        .ok_or_else(|| invalid_argument("callbacks are null"))?;
+    let store = CallbackStore {
+        callbacks: Rc::new(OwnedCallbacks(*callbacks)),
+    };
+    Ok(FFIFriendlyBlockStore::new(Box::new(store)))
+}
+
+unsafe fn observer_arg(progress: *const WnfsProgressCallback) -> OperationObserver {
+    let Some(callback) = progress.as_ref() else {
+        return OperationObserver::default();
+    };
+    let cancellation = CancellationToken::new();
+    OperationObserver {
+        callback: Some(Arc::new(HostProgress {
+            callback: *callback,
+            cancellation: cancellation.clone(),
+        })),
+        cancellation: Some(cancellation),
+    }
+}
+
+fn cid_bytes(cid: Cid) -> WnfsBytes {
+    WnfsBytes::from_vec(cid.to_string().into_bytes())
+}
+
+/// Creates a forest in the host's store and returns a helper on it and its first root.
+///
+/// The callbacks' context belongs to the library from the call on: if the helper can't be
+/// opened, for whatever reason but null `callbacks`, it's released before the call returns.
+///
+/// # Safety
+///
+/// `callbacks` points to valid callbacks, `wnfs_key` to `wnfs_key_len` readable bytes and the
+/// output pointers to writable memory.
+#[no_mangle]
+pub unsafe extern "C" fn wnfs_helper_init(
+    callbacks: *const WnfsBlockStoreCallbacks,
+    wnfs_key: *const u8,
+    wnfs_key_len: usize,
+    out_helper: *mut *mut WnfsHelper,
+    out_cid: *mut WnfsBytes,
+) -> WnfsStatus {
+    guard(|| {
+        let mut store = store_arg(callbacks)?;
+        let out_helper = out_arg(out_helper)?;
+        let out_cid = out_arg(out_cid)?;
+        let wnfs_key = bytes_arg(wnfs_key, wnfs_key_len)?.to_vec();
+        let (session, _, cid) =
+            PrivateDirectoryHelper::synced_init_exclusive(&mut store, wnfs_key).map_err(failed)?;
+        let helper = session.into_helper();
+        *out_helper = Box::into_raw(Box::new(WnfsHelper { helper }));
+        *out_cid = cid_bytes(cid);
+        Ok(())
+    })
+}
+
+/// Opens the forest at `forest_cid` with `wnfs_key`. The context is owned as for
+/// `wnfs_helper_init`.
+///
+/// # Safety
+///
+/// As for `wnfs_helper_init`; `forest_cid` is a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn wnfs_helper_load(
+    callbacks: *const WnfsBlockStoreCallbacks,
+    forest_cid: *const c_char,
+    wnfs_key: *const u8,
+    wnfs_key_len: usize,
+    out_helper: *mut *mut WnfsHelper,
+) -> WnfsStatus {
+    guard(|| {
+        let mut store = store_arg(callbacks)?;
+        let out_helper = out_arg(out_helper)?;
+        if forest_cid.is_null() {
+            return Err(invalid_argument("forest CID is null"));
+        }
+        let forest_cid = CStr::from_ptr(forest_cid)
+            .to_str()
+            .ok()
+            .and_then(|cid| Cid::try_from(cid).ok())
+            .ok_or_else(|| invalid_argument("forest CID is invalid"))?;
+        let wnfs_key = bytes_arg(wnfs_key, wnfs_key_len)?.to_vec();
+        let helper =
+            PrivateDirectoryHelper::synced_load_exclusive(&mut store, forest_cid, wnfs_key)
+                .map_err(failed)?
+                .into_helper();
+        *out_helper = Box::into_raw(Box::new(WnfsHelper { helper }));
+        Ok(())
+    })
+}
+
+/// Frees a helper and, once nothing else uses them, its callbacks' context. Null is ignored.
+///
+/// # Safety
+///
+/// `helper` was returned by this library and isn't used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn wnfs_helper_free(helper: *mut WnfsHelper) {
+    if !helper.is_null() {
+        let _ = catch_unwind(AssertUnwindSafe(|| drop(Box::from_raw(helper))));
+    }
+}
+
+/// The mutating calls take an `idempotency_key`, null for none: a call retried with the key of
+/// one that completed returns that call's root without mutating again, see
+/// `PrivateDirectoryHelper::idempotent`.
+///
+/// # Safety
+///
+/// `helper` is a live helper, `path` and a non-null `idempotency_key` NUL-terminated strings and
+/// `out_cid` writable.
+#[no_mangle]
+pub unsafe extern "C" fn wnfs_mkdir(
+    helper: *mut WnfsHelper,
+    path: *const c_char,
+    idempotency_key: *const c_char,
+    out_cid: *mut WnfsBytes,
+) -> WnfsStatus {
+    guard(|| {
+        let helper = helper_arg(helper)?;
+        let out_cid = out_arg(out_cid)?;
+        let path = path_arg(path)?;
+        let key = key_arg(idempotency_key)?;
+        let cid = helper
+            .synced_idempotent(key.as_deref(), "mkdir", move |helper| {
+                Box::pin(async move { helper.mkdir(&path).await })
+            })
+            .map_err(failed)?;
+        *out_cid = cid_bytes(cid);
+        Ok(())
+    })
+}
+
+/// # Safety
+///
+/// As for `wnfs_mkdir`; `data` points to `data_len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn wnfs_write_file(
+    helper: *mut WnfsHelper,
+    path: *const c_char,
+    data: *const u8,
+    data_len: usize,
+    modification_time_seconds: i64,
+    idempotency_key: *const c_char,
+    out_cid: *mut WnfsBytes,
+) -> WnfsStatus {
+    guard(|| {
+        let helper = helper_arg(helper)?;
+        let out_cid = out_arg(out_cid)?;
+        let path = path_arg(path)?;
+        let content = bytes_arg(data, data_len)?.to_vec();
+        let key = key_arg(idempotency_key)?;
+        let cid = helper
+            .synced_idempotent(key.as_deref(), "write_file", move |helper| {
+                Box::pin(async move {
+                    helper
+                        .write_file(&path, content, modification_time_seconds)
+                        .await
+                })
+            })
+            .map_err(failed)?;
+        *out_cid = cid_bytes(cid);
+        Ok(())
+    })
+}
+
+/// # Safety
+///
+/// As for `wnfs_mkdir`.
+#[no_mangle]
+pub unsafe extern "C" fn wnfs_read_file(
+    helper: *mut WnfsHelper,
+    path: *const c_char,
+    out_content: *mut WnfsBytes,
+) -> WnfsStatus {
+    guard(|| {
+        let helper = helper_arg(helper)?;
+        let out_content = out_arg(out_content)?;
+        let path = path_arg(path)?;
+        *out_content = WnfsBytes::from_vec(helper.synced_read_file(&path).map_err(failed)?);
+        Ok(())
+    })
+}
+
+/// `wnfs_write_file`, reporting the bytes written to `progress`, which may be null. A cancelled
+/// write leaves the tree unchanged.
+///
+/// # Safety
+///
+/// As for `wnfs_write_file`; `progress` is null or points to a valid callback.
+#[no_mangle]
+#[allow(clippy::too_many_arguments)]
+pub unsafe extern "C" fn wnfs_write_file_observed(
+    helper: *mut WnfsHelper,
+    path: *const c_char,
+    data: *const u8,
+    data_len: usize,
+    modification_time_seconds: i64,
+    idempotency_key: *const c_char,
+    progress: *const WnfsProgressCallback,
+    out_cid: *mut WnfsBytes,
+) -> WnfsStatus {
+    guard(|| {
+        let helper = helper_arg(helper)?;
+        let out_cid = out_arg(out_cid)?;
+        let path = path_arg(path)?;
+        let content = bytes_arg(data, data_len)?.to_vec();
+        let key = key_arg(idempotency_key)?;
+        let observer = observer_arg(progress);
+        let cid = helper
+            .synced_idempotent(key.as_deref(), "write_file", move |helper| {
+                Box::pin(async move {
+                    helper
+                        .write_file_observed(&path, content, modification_time_seconds, &observer)
+                        .await
+                })
+            })
+            .map_err(failed)?;
+        *out_cid = cid_bytes(cid);
+        Ok(())
+    })
+}
+
+/// `wnfs_read_file`, reporting the bytes read and blocks fetched to `progress`, which may be
+/// null.
+///
+/// # Safety
+///
+/// As for `wnfs_read_file`; `progress` is null or points to a valid callback.
+#[no_mangle]
+pub unsafe extern "C" fn wnfs_read_file_observed(
+    helper: *mut WnfsHelper,
+    path: *const c_char,
+    progress: *const WnfsProgressCallback,
+    out_content: *mut WnfsBytes,
+) -> WnfsStatus {
+    guard(|| {
+        let helper = helper_arg(helper)?;
+        let out_content = out_arg(out_content)?;
+        let path = path_arg(path)?;
+        let observer = observer_arg(progress);
+        let content = helper
+            .synced_read_file_observed(&path, &observer)
+            .map_err(failed)?;
+        *out_content = WnfsBytes::from_vec(content);
+        Ok(())
+    })
+}
+
+/// Lists the directory at `path` as a JSON array of objects with `name`, `created`, `modified`
+/// and `content_type`, see `FileMetadata`.
+///
+/// # Safety
+///
+/// As for `wnfs_mkdir`.
+#[no_mangle]
+pub unsafe extern "C" fn wnfs_ls(
+    helper: *mut WnfsHelper,
+    path: *const c_char,
+    out_json: *mut WnfsBytes,
+) -> WnfsStatus {
+    guard(|| {
+        let helper = helper_arg(helper)?;
+        let out_json = out_arg(out_json)?;
+        let path = path_arg(path)?;
+        *out_json = listing(helper.synced_ls_files(&path).map_err(failed)?)?;
+        Ok(())
+    })
+}
+
+// The JSON array `wnfs_ls` returns.
+fn listing(entries: Vec<(String, Metadata)>) -> Result<WnfsBytes, Failure> {
+    let entries: Vec<_> = entries
+        .into_iter()
+        .map(|(name, metadata)| {
+            let metadata = FileMetadata::from_wnfs(&metadata);
+            json!({
+                "name": name,
+                "created": metadata.created,
+                "modified": metadata.modified,
+                "content_type": metadata.content_type,
+            })
+        })
+        .collect();
+    let encoded = serde_json::to_vec(&entries).map_err(|e| failed(e.to_string()))?;
+    Ok(WnfsBytes::from_vec(encoded))
+}
+
+/// # Safety
+///
+/// As for `wnfs_mkdir`.
+#[no_mangle]
+pub unsafe extern "C" fn wnfs_rm(
+    helper: *mut WnfsHelper,
+    path: *const c_char,
+    idempotency_key: *const c_char,
+    out_cid: *mut WnfsBytes,
+) -> WnfsStatus {
+    guard(|| {
+        let helper = helper_arg(helper)?;
+        let out_cid = out_arg(out_cid)?;
+        let path = path_arg(path)?;
+        let key = key_arg(idempotency_key)?;
+        let cid = helper
+            .synced_idempotent(key.as_deref(), "rm", move |helper| {
+                Box::pin(async move { helper.rm(&path).await })
+            })
+            .map_err(failed)?;
+        *out_cid = cid_bytes(cid);
+        Ok(())
+    })
+}
+
+/// # Safety
+///
+/// As for `wnfs_mkdir`, for both paths.
+#[no_mangle]
+pub unsafe extern "C" fn wnfs_mv(
+    helper: *mut WnfsHelper,
+    source: *const c_char,
+    target: *const c_char,
+    idempotency_key: *const c_char,
+    out_cid: *mut WnfsBytes,
+) -> WnfsStatus {
+    guard(|| {
+        let helper = helper_arg(helper)?;
+        let out_cid = out_arg(out_cid)?;
+        let (source, target) = (path_arg(source)?, path_arg(target)?);
+        let key = key_arg(idempotency_key)?;
+        let cid = helper
+            .synced_idempotent(key.as_deref(), "mv", move |helper| {
+                Box::pin(async move { helper.mv(&source, &target).await })
+            })
+            .map_err(failed)?;
+        *out_cid = cid_bytes(cid);
+        Ok(())
+    })
+}
+
+/// # Safety
+///
+/// As for `wnfs_mkdir`, for both paths.
+#[no_mangle]
+pub unsafe extern "C" fn wnfs_cp(
+    helper: *mut WnfsHelper,
+    source: *const c_char,
+    target: *const c_char,
+    idempotency_key: *const c_char,
+    out_cid: *mut WnfsBytes,
+) -> WnfsStatus {
+    guard(|| {
+        let helper = helper_arg(helper)?;
+        let out_cid = out_arg(out_cid)?;
+        let (source, target) = (path_arg(source)?, path_arg(target)?);
+        let key = key_arg(idempotency_key)?;
+        let cid = helper
+            .synced_idempotent(key.as_deref(), "cp", move |helper| {
+                Box::pin(async move { helper.cp(&source, &target).await })
+            })
+            .map_err(failed)?;
+        *out_cid = cid_bytes(cid);
+        Ok(())
+    })
+}
+
+/// A handle limited to a directory and the permissions granted below it, see `ScopedHandle`.
+/// Its permissions are `Permissions::bits`: 1 read, 2 write, 4 delete, 8 share and 16 history.
+pub struct WnfsScopedHandle {
+    handle: ScopedHandle<'static>,
+}
+
+unsafe fn scoped_arg<'h>(
+    scoped: *mut WnfsScopedHandle,
+) -> Result<&'h ScopedHandle<'static>, Failure> {
+    scoped
+        .as_ref()
+        .map(|scoped| &scoped.handle)
+        .ok_or_else(|| invalid_argument("scoped handle is null"))
+}
+
+fn permissions_arg(bits: u8) -> Result<Permissions, Failure> {
+    Permissions::from_bits(bits).map_err(|message| invalid_argument(&message))
+}
+
+/// Turns `helper` into a handle on the directory at `path` allowing `permissions` below it,
+/// for the host to narrow for its plugins with `wnfs_scoped_narrow`. The helper is taken over
+/// whether or not the call succeeds; its callbacks' context is released with the last handle.
+///
+/// # Safety
+///
+/// `helper` was returned by this library and isn't used afterwards, `path` is a NUL-terminated
+/// string and `out_scoped` writable.
+#[no_mangle]
+pub unsafe extern "C" fn wnfs_helper_into_scoped(
+    helper: *mut WnfsHelper,
+    path: *const c_char,
+    permissions: u8,
+    out_scoped: *mut *mut WnfsScopedHandle,
+) -> WnfsStatus {
+    guard(|| {
+        if helper.is_null() {
+            return Err(invalid_argument("helper is null"));
+        }
+        let helper = Box::from_raw(helper).helper;
+        let out_scoped = out_arg(out_scoped)?;
+        let path = path_arg(path)?;
+        let permissions = permissions_arg(permissions)?;
+        let handle = helper
+            .synced_into_scoped_handle(&path, permissions)
+            .map_err(failed)?;
+        *out_scoped = Box::into_raw(Box::new(WnfsScopedHandle { handle }));
+        Ok(())
+    })
+}
+
+/// A handle on the subdirectory `path` of `scoped` with at most its permissions.
+///
+/// # Safety
+///
+/// `scoped` is a live handle, `path` a NUL-terminated string and `out_scoped` writable.
+#[no_mangle]
+pub unsafe extern "C" fn wnfs_scoped_narrow(
+    scoped: *mut WnfsScopedHandle,
+    path: *const c_char,
+    permissions: u8,
+    out_scoped: *mut *mut WnfsScopedHandle,
+) -> WnfsStatus {
+    guard(|| {
+        let scoped = scoped_arg(scoped)?;
+        let out_scoped = out_arg(out_scoped)?;
+        let path = path_arg(path)?;
+        let handle = scoped
+            .narrow(&path, permissions_arg(permissions)?)
+            .map_err(failed)?;
+        *out_scoped = Box::into_raw(Box::new(WnfsScopedHandle { handle }));
+        Ok(())
+    })
+}
+
+/// The permission bits of `scoped`, 0 for null.
+///
+/// # Safety
+///
+/// `scoped` is a live handle or null.
+#[no_mangle]
+pub unsafe extern "C" fn wnfs_scoped_permissions(scoped: *mut WnfsScopedHandle) -> u8 {
+    scoped
+        .as_ref()
+        .map_or(0, |scoped| scoped.handle.permissions().bits())
+}
+
+/// Frees a scoped handle. Null is ignored.
+///
+/// # Safety
+///
+/// `scoped` was returned by this library and isn't used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn wnfs_scoped_free(scoped: *mut WnfsScopedHandle) {
+    if !scoped.is_null() {
+        let _ = catch_unwind(AssertUnwindSafe(|| drop(Box::from_raw(scoped))));
+    }
+}
+
+/// `wnfs_read_file` below the handle's directory.
+///
+/// # Safety
+///
+/// As for `wnfs_scoped_narrow`, with `out_content` writable.
+#[no_mangle]
+pub unsafe extern "C" fn wnfs_scoped_read_file(
+    scoped: *mut WnfsScopedHandle,
+    path: *const c_char,
+    out_content: *mut WnfsBytes,
+) -> WnfsStatus {
+    guard(|| {
+        let scoped = scoped_arg(scoped)?;
+        let out_content = out_arg(out_content)?;
+        let path = path_arg(path)?;
+        *out_content = WnfsBytes::from_vec(scoped.synced_read_file(&path).map_err(failed)?);
+        Ok(())
+    })
+}
+
+/// `wnfs_ls` below the handle's directory.
+///
+/// # Safety
+///
+/// As for `wnfs_scoped_narrow`, with `out_json` writable.
+#[no_mangle]
+pub unsafe extern "C" fn wnfs_scoped_ls(
+    scoped: *mut WnfsScopedHandle,
+    path: *const c_char,
+    out_json: *mut WnfsBytes,
+) -> WnfsStatus {
+    guard(|| {
+        let scoped = scoped_arg(scoped)?;
+        let out_json = out_arg(out_json)?;
+        let path = path_arg(path)?;
+        *out_json = listing(scoped.synced_ls_files(&path).map_err(failed)?)?;
+        Ok(())
+    })
+}
+
+/// `wnfs_write_file` below the handle's directory.
+///
+/// # Safety
+///
+/// As for `wnfs_scoped_narrow`, with `data` pointing to `data_len` readable bytes and
+/// `out_cid` writable.
+#[no_mangle]
+pub unsafe extern "C" fn wnfs_scoped_write_file(
+    scoped: *mut WnfsScopedHandle,
+    path: *const c_char,
+    data: *const u8,
+    data_len: usize,
+    modification_time_seconds: i64,
+    out_cid: *mut WnfsBytes,
+) -> WnfsStatus {
+    guard(|| {
+        let scoped = scoped_arg(scoped)?;
+        let out_cid = out_arg(out_cid)?;
+        let path = path_arg(path)?;
+        let content = bytes_arg(data, data_len)?.to_vec();
+        let cid = scoped
+            .synced_write_file(&path, content, modification_time_seconds)
+            .map_err(failed)?;
+        *out_cid = cid_bytes(cid);
+        Ok(())
+    })
+}
+
+/// `wnfs_mkdir` below the handle's directory.
+///
+/// # Safety
+///
+/// As for `wnfs_scoped_narrow`, with `out_cid` writable.
+#[no_mangle]
+pub unsafe extern "C" fn wnfs_scoped_mkdir(
+    scoped: *mut WnfsScopedHandle,
+    path: *const c_char,
+    out_cid: *mut WnfsBytes,
+) -> WnfsStatus {
+    guard(|| {
+        let scoped = scoped_arg(scoped)?;
+        let out_cid = out_arg(out_cid)?;
+        let path = path_arg(path)?;
+        *out_cid = cid_bytes(scoped.synced_mkdir(&path).map_err(failed)?);
+        Ok(())
+    })
+}
+
+/// `wnfs_rm` below the handle's directory.
+///
+/// # Safety
+///
+/// As for `wnfs_scoped_mkdir`.
+#[no_mangle]
+pub unsafe extern "C" fn wnfs_scoped_rm(
+    scoped: *mut WnfsScopedHandle,
+    path: *const c_char,
+    out_cid: *mut WnfsBytes,
+) -> WnfsStatus {
+    guard(|| {
+        let scoped = scoped_arg(scoped)?;
+        let out_cid = out_arg(out_cid)?;
+        let path = path_arg(path)?;
+        *out_cid = cid_bytes(scoped.synced_rm(&path).map_err(failed)?);
+        Ok(())
+    })
+}
+
+/// `wnfs_mv` below the handle's directory.
+///
+/// # Safety
+///
+/// As for `wnfs_scoped_mkdir`, for both paths.
+#[no_mangle]
+pub unsafe extern "C" fn wnfs_scoped_mv(
+    scoped: *mut WnfsScopedHandle,
+    source: *const c_char,
+    target: *const c_char,
+    out_cid: *mut WnfsBytes,
+) -> WnfsStatus {
+    guard(|| {
+        let scoped = scoped_arg(scoped)?;
+        let out_cid = out_arg(out_cid)?;
+        let (source, target) = (path_arg(source)?, path_arg(target)?);
+        *out_cid = cid_bytes(scoped.synced_mv(&source, &target).map_err(failed)?);
+        Ok(())
+    })
+}
+
+/// Frees a buffer returned by the library. An empty buffer with a null pointer is ignored.
+///
+/// # Safety
+///
+/// `bytes` was returned by this library and isn't used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn wnfs_bytes_free(bytes: WnfsBytes) {
+    if !bytes.data.is_null() {
+        drop(Box::from_raw(ptr::slice_from_raw_parts_mut(
+            bytes.data, bytes.len,
+        )));
+    }
+}
+
+/// The message of the last failure on the calling thread, null if none. Valid until the next
+/// failing call on the thread.
+#[no_mangle]
+pub extern "C" fn wnfs_last_error_message() -> *const c_char {
+    LAST_ERROR.with(|last| {
+        last.borrow()
+            .as_ref()
+            .map_or(ptr::null(), |message| message.as_ptr())
+    })
+}
+
+#[cfg(test)]
+mod ffi_tests;
@@ -0,0 +1,536 @@
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    ffi::{c_char, c_void, CStr, CString},
+    ptr, slice,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use super::*;
+
+type MemoryBlocks = RefCell<HashMap<Vec<u8>, Vec<u8>>>;
+
+static FREED_CONTEXTS: AtomicUsize = AtomicUsize::new(0);
+
+extern "C" fn get_block(
+    context: *mut c_void,
+    cid: *const u8,
+    cid_len: usize,
+    out: *mut WnfsHostBytes,
+) -> i32 {
+    let blocks = unsafe { &*(context as *const MemoryBlocks) };
+    let cid = unsafe { slice::from_raw_parts(cid, cid_len) };
+    match blocks.borrow().get(cid) {
+        Some(block) => {
+            unsafe {
+                *out = WnfsHostBytes {
+                    data: block.as_ptr(),
+                    len: block.len(),
+                }
+            };
+            0
+        }
+        None => 1,
+    }
+}
+
+extern "C" fn put_block(
+    context: *mut c_void,
+    cid: *const u8,
+    cid_len: usize,
+    data: *const u8,
+    data_len: usize,
+) -> i32 {
+    let blocks = unsafe { &*(context as *const MemoryBlocks) };
+    let (cid, data) = unsafe {
+        (
+            slice::from_raw_parts(cid, cid_len),
+            slice::from_raw_parts(data, data_len),
+        )
+    };
+    blocks.borrow_mut().insert(cid.to_vec(), data.to_vec());
+    0
+}
+
+extern "C" fn free_context(context: *mut c_void) {
+    drop(unsafe { Box::from_raw(context as *mut MemoryBlocks) });
+    FREED_CONTEXTS.fetch_add(1, Ordering::SeqCst);
+}
+
+fn callbacks(blocks: HashMap<Vec<u8>, Vec<u8>>) -> WnfsBlockStoreCallbacks {
+    WnfsBlockStoreCallbacks {
+        context: Box::into_raw(Box::new(RefCell::new(blocks))) as *mut c_void,
+        get_block: Some(get_block),
+        put_block: Some(put_block),
+        release: None,
+        free_context: Some(free_context),
+    }
+}
+
+unsafe fn take_bytes(bytes: WnfsBytes) -> Vec<u8> {
+    let content = slice::from_raw_parts(bytes.data, bytes.len).to_vec();
+    wnfs_bytes_free(bytes);
+    content
+}
+
+fn empty_bytes() -> WnfsBytes {
+    WnfsBytes {
+        data: ptr::null_mut(),
+        len: 0,
+    }
+}
+
+#[test]
+fn test_ffi_round_trip_with_callback_store() {
+    let key = [3u8; 32];
+    let path = |path: &str| CString::new(path).unwrap();
+    unsafe {
+        let mut helper = ptr::null_mut();
+        let mut cid = empty_bytes();
+        let store = callbacks(HashMap::new());
+        // The initial forest is written through the host's callbacks; keep them for reloading.
+        let blocks = &*(store.context as *const MemoryBlocks);
+        assert_eq!(
+            wnfs_helper_init(&store, key.as_ptr(), key.len(), &mut helper, &mut cid),
+            WnfsStatus::Ok
+        );
+        wnfs_bytes_free(cid);
+
+        let content = b"hello from C";
+        let mut out = empty_bytes();
+        assert_eq!(
+            wnfs_mkdir(helper, path("docs").as_ptr(), ptr::null(), &mut out),
+            WnfsStatus::Ok
+        );
+        wnfs_bytes_free(out);
+        assert_eq!(
+            wnfs_write_file(
+                helper,
+                path("docs/a.txt").as_ptr(),
+                content.as_ptr(),
+                content.len(),
+                0,
+                path("write-a").as_ptr(),
+                &mut out,
+            ),
+            WnfsStatus::Ok
+        );
+        let written = take_bytes(out);
+        // A retry with the same key returns the first call's root.
+        assert_eq!(
+            wnfs_write_file(
+                helper,
+                path("docs/a.txt").as_ptr(),
+                content.as_ptr(),
+                content.len(),
+                0,
+                path("write-a").as_ptr(),
+                &mut out,
+            ),
+            WnfsStatus::Ok
+        );
+        assert_eq!(take_bytes(out), written);
+        assert_eq!(
+            wnfs_cp(
+                helper,
+                path("docs/a.txt").as_ptr(),
+                path("docs/b.txt").as_ptr(),
+                ptr::null(),
+                &mut out
+            ),
+            WnfsStatus::Ok
+        );
+        wnfs_bytes_free(out);
+        assert_eq!(
+            wnfs_mv(
+                helper,
+                path("docs/b.txt").as_ptr(),
+                path("c.txt").as_ptr(),
+                ptr::null(),
+                &mut out
+            ),
+            WnfsStatus::Ok
+        );
+        wnfs_bytes_free(out);
+        assert_eq!(
+            wnfs_rm(helper, path("docs/a.txt").as_ptr(), ptr::null(), &mut out),
+            WnfsStatus::Ok
+        );
+        let root = String::from_utf8(take_bytes(out)).unwrap();
+
+        assert_eq!(wnfs_ls(helper, path("").as_ptr(), &mut out), WnfsStatus::Ok);
+        let listing: serde_json::Value = serde_json::from_slice(&take_bytes(out)).unwrap();
+        let names: Vec<&str> = listing
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|entry| entry["name"].as_str().unwrap())
+            .collect();
+        assert!(names.contains(&"docs"));
+        assert!(names.contains(&"c.txt"));
+
+        // Failures map to status codes and leave a message.
+        assert_eq!(
+            wnfs_read_file(helper, path("docs/a.txt").as_ptr(), &mut out),
+            WnfsStatus::NotFound
+        );
+        assert!(!wnfs_last_error_message().is_null());
+        assert_eq!(
+            wnfs_read_file(helper, ptr::null(), &mut out),
+            WnfsStatus::InvalidArgument
+        );
+        let message = CStr::from_ptr(wnfs_last_error_message());
+        assert_eq!(message.to_str().unwrap(), "path is null");
+
+        // A second helper over a copy of the host's blocks sees the committed tree, once the
+        // first one is freed.
+        let freed = FREED_CONTEXTS.load(Ordering::SeqCst);
+        let copy = callbacks(blocks.borrow().to_owned());
+        let root = CString::new(root).unwrap();
+        let mut reloaded = ptr::null_mut();
+        let locked = callbacks(blocks.borrow().to_owned());
+        assert_eq!(
+            wnfs_helper_load(
+                &locked,
+                root.as_ptr(),
+                key.as_ptr(),
+                key.len(),
+                &mut reloaded
+            ),
+            WnfsStatus::Conflict
+        );
+        wnfs_helper_free(helper);
+        assert_eq!(FREED_CONTEXTS.load(Ordering::SeqCst), freed + 2);
+
+        assert_eq!(
+            wnfs_helper_load(&copy, root.as_ptr(), key.as_ptr(), key.len(), &mut reloaded),
+            WnfsStatus::Ok
+        );
+        assert_eq!(
+            wnfs_read_file(reloaded, path("c.txt").as_ptr(), &mut out),
+            WnfsStatus::Ok
+        );
+        assert_eq!(take_bytes(out), content);
+        wnfs_helper_free(reloaded);
+    }
+}
+
+static FAILED_OPEN_FREES: AtomicUsize = AtomicUsize::new(0);
+
+extern "C" fn count_free(context: *mut c_void) {
+    drop(unsafe { Box::from_raw(context as *mut MemoryBlocks) });
+    FAILED_OPEN_FREES.fetch_add(1, Ordering::SeqCst);
+}
+
+#[test]
+fn test_failed_open_releases_the_context() {
+    use libipld::multihash::{Code, MultihashDigest};
+
+    let key = [4u8; 32];
+    let counted = || WnfsBlockStoreCallbacks {
+        free_context: Some(count_free),
+        ..callbacks(HashMap::new())
+    };
+    unsafe {
+        // Rejected arguments release it as well as failures of the helper.
+        let mut cid = empty_bytes();
+        let store = counted();
+        assert_eq!(
+            wnfs_helper_init(&store, key.as_ptr(), key.len(), ptr::null_mut(), &mut cid),
+            WnfsStatus::InvalidArgument
+        );
+        assert_eq!(FAILED_OPEN_FREES.load(Ordering::SeqCst), 1);
+
+        let mut helper = ptr::null_mut();
+        let store = counted();
+        assert_eq!(
+            wnfs_helper_load(&store, ptr::null(), key.as_ptr(), key.len(), &mut helper),
+            WnfsStatus::InvalidArgument
+        );
+        assert_eq!(FAILED_OPEN_FREES.load(Ordering::SeqCst), 2);
+
+        // The root block is missing from the host's store.
+        let root = Cid::new_v1(0x71, Code::Sha2_256.digest(b"never written"));
+        let missing = CString::new(root.to_string()).unwrap();
+        let store = counted();
+        assert_eq!(
+            wnfs_helper_load(
+                &store,
+                missing.as_ptr(),
+                key.as_ptr(),
+                key.len(),
+                &mut helper
+            ),
+            WnfsStatus::NotFound
+        );
+        assert_eq!(FAILED_OPEN_FREES.load(Ordering::SeqCst), 3);
+        assert!(helper.is_null());
+    }
+}
+
+#[test]
+fn test_header_matches_the_abi() {
+    use std::mem::{align_of, offset_of, size_of};
+
+    let header = include_str!("../../include/wnfsutils.h");
+    let constant = |name: &str| -> i64 {
+        let line = header
+            .lines()
+            .map(|line| line.trim_start().trim_start_matches("#define "))
+            .find(|line| line.split_whitespace().next() == Some(name))
+            .unwrap_or_else(|| panic!("{} isn't declared", name));
+        let value = line
+            .rsplit(['=', ' '])
+            .find(|word| !word.is_empty())
+            .unwrap();
+        value.trim_end_matches(',').parse().unwrap()
+    };
+    for (name, status) in [
+        ("WNFS_STATUS_OK", WnfsStatus::Ok),
+        ("WNFS_STATUS_INVALID_ARGUMENT", WnfsStatus::InvalidArgument),
+        ("WNFS_STATUS_NOT_FOUND", WnfsStatus::NotFound),
+        ("WNFS_STATUS_HELD", WnfsStatus::Held),
+        ("WNFS_STATUS_LIMIT_EXCEEDED", WnfsStatus::LimitExceeded),
+        ("WNFS_STATUS_CORRUPT", WnfsStatus::Corrupt),
+        (
+            "WNFS_STATUS_STORE_UNAVAILABLE",
+            WnfsStatus::StoreUnavailable,
+        ),
+        ("WNFS_STATUS_PANIC", WnfsStatus::Panic),
+        ("WNFS_STATUS_OTHER", WnfsStatus::Other),
+        ("WNFS_STATUS_INVALID_KEY", WnfsStatus::InvalidKey),
+        ("WNFS_STATUS_CANCELLED", WnfsStatus::Cancelled),
+        ("WNFS_STATUS_CONFLICT", WnfsStatus::Conflict),
+        (
+            "WNFS_STATUS_PERMISSION_DENIED",
+            WnfsStatus::PermissionDenied,
+        ),
+        ("WNFS_STATUS_UNSUPPORTED", WnfsStatus::Unsupported),
+    ] {
+        assert_eq!(constant(name), status as i64, "{}", name);
+    }
+    // Every status the header declares is checked above; comments may mention them too.
+    let declared = header
+        .lines()
+        .filter(|line| line.trim_start().starts_with("WNFS_STATUS_"))
+        .count();
+    assert_eq!(declared, 14);
+    assert_eq!(constant("WNFS_GET_BLOCK_MISSING"), GET_BLOCK_MISSING as i64);
+    assert_eq!(size_of::<WnfsStatus>(), size_of::<i32>());
+    for (name, permission) in [
+        ("WNFS_PERMISSION_READ", Permissions::READ),
+        ("WNFS_PERMISSION_WRITE", Permissions::WRITE),
+        ("WNFS_PERMISSION_DELETE", Permissions::DELETE),
+        ("WNFS_PERMISSION_SHARE", Permissions::SHARE),
+        ("WNFS_PERMISSION_HISTORY", Permissions::HISTORY),
+    ] {
+        assert_eq!(constant(name), permission.bits() as i64, "{}", name);
+    }
+
+    // Both buffers are a pointer followed by a length.
+    let word = size_of::<usize>();
+    assert_eq!(
+        (size_of::<WnfsBytes>(), offset_of!(WnfsBytes, len)),
+        (2 * word, word)
+    );
+    assert_eq!(
+        (size_of::<WnfsHostBytes>(), offset_of!(WnfsHostBytes, len)),
+        (2 * word, word)
+    );
+    // The context and four function pointers, in the header's order.
+    assert_eq!(size_of::<WnfsBlockStoreCallbacks>(), 5 * word);
+    assert_eq!(align_of::<WnfsBlockStoreCallbacks>(), word);
+    assert_eq!(
+        [
+            offset_of!(WnfsBlockStoreCallbacks, context),
+            offset_of!(WnfsBlockStoreCallbacks, get_block),
+            offset_of!(WnfsBlockStoreCallbacks, put_block),
+            offset_of!(WnfsBlockStoreCallbacks, release),
+            offset_of!(WnfsBlockStoreCallbacks, free_context),
+        ],
+        [0, word, 2 * word, 3 * word, 4 * word]
+    );
+    assert_eq!(
+        (
+            size_of::<WnfsProgressCallback>(),
+            offset_of!(WnfsProgressCallback, on_progress)
+        ),
+        (2 * word, word)
+    );
+}
+
+#[test]
+fn test_scoped_handles_over_ffi() {
+    let key = [5u8; 32];
+    let path = |path: &str| CString::new(path).unwrap();
+    unsafe {
+        let mut helper = ptr::null_mut();
+        let mut cid = empty_bytes();
+        let store = callbacks(HashMap::new());
+        assert_eq!(
+            wnfs_helper_init(&store, key.as_ptr(), key.len(), &mut helper, &mut cid),
+            WnfsStatus::Ok
+        );
+        wnfs_bytes_free(cid);
+        let mut out = empty_bytes();
+        assert_eq!(
+            wnfs_mkdir(helper, path("plugin").as_ptr(), ptr::null(), &mut out),
+            WnfsStatus::Ok
+        );
+        wnfs_bytes_free(out);
+
+        // The host keeps a handle on everything and hands the plugin a narrower one.
+        let mut host = ptr::null_mut();
+        assert_eq!(
+            wnfs_helper_into_scoped(helper, path("").as_ptr(), 0b1_1111, &mut host),
+            WnfsStatus::Ok
+        );
+        let mut plugin = ptr::null_mut();
+        assert_eq!(
+            wnfs_scoped_narrow(host, path("plugin").as_ptr(), 0b11, &mut plugin),
+            WnfsStatus::Ok
+        );
+        assert_eq!(wnfs_scoped_permissions(plugin), 0b11);
+        assert_eq!(
+            wnfs_scoped_narrow(host, path("plugin").as_ptr(), 0x80, &mut plugin),
+            WnfsStatus::InvalidArgument
+        );
+
+        let content = b"plugin data";
+        let mut out = empty_bytes();
+        assert_eq!(
+            wnfs_scoped_write_file(
+                plugin,
+                path("notes.txt").as_ptr(),
+                content.as_ptr(),
+                content.len(),
+                0,
+                &mut out
+            ),
+            WnfsStatus::Ok
+        );
+        wnfs_bytes_free(out);
+        assert_eq!(
+            wnfs_scoped_read_file(host, path("plugin/notes.txt").as_ptr(), &mut out),
+            WnfsStatus::Ok
+        );
+        assert_eq!(take_bytes(out), content);
+        assert_eq!(
+            wnfs_scoped_rm(plugin, path("notes.txt").as_ptr(), &mut out),
+            WnfsStatus::PermissionDenied
+        );
+        assert_eq!(
+            wnfs_scoped_read_file(plugin, path("../secret.txt").as_ptr(), &mut out),
+            WnfsStatus::InvalidArgument
+        );
+        assert_eq!(
+            wnfs_scoped_ls(plugin, path("").as_ptr(), &mut out),
+            WnfsStatus::Ok
+        );
+        let listing: serde_json::Value = serde_json::from_slice(&take_bytes(out)).unwrap();
+        assert_eq!(listing[0]["name"], "notes.txt");
+
+        wnfs_scoped_free(plugin);
+        wnfs_scoped_free(host);
+        assert_eq!(wnfs_scoped_permissions(ptr::null_mut()), 0);
+    }
+}
+
+// Progress reports and the bytes, if any, at which to cancel.
+type ProgressLog = (RefCell<Vec<(String, u64, i64)>>, Cell<Option<u64>>);
+
+extern "C" fn record_progress(
+    context: *mut c_void,
+    operation: *const c_char,
+    bytes_done: u64,
+    bytes_total: i64,
+    _blocks_fetched: u64,
+) -> i32 {
+    let (reports, cancel_at) = unsafe { &*(context as *const ProgressLog) };
+    let operation = unsafe { CStr::from_ptr(operation) }.to_str().unwrap();
+    reports
+        .borrow_mut()
+        .push((operation.to_string(), bytes_done, bytes_total));
+    match cancel_at.get() {
+        Some(cancel_at) if bytes_done >= cancel_at => 1,
+        _ => 0,
+    }
+}
+
+#[test]
+fn test_observed_calls_over_ffi() {
+    let key = [6u8; 32];
+    let path = |path: &str| CString::new(path).unwrap();
+    let content: Vec<u8> = (0..600_000u32).map(|i| (i % 251) as u8).collect();
+    unsafe {
+        let mut helper = ptr::null_mut();
+        let mut out = empty_bytes();
+        let store = callbacks(HashMap::new());
+        assert_eq!(
+            wnfs_helper_init(&store, key.as_ptr(), key.len(), &mut helper, &mut out),
+            WnfsStatus::Ok
+        );
+        wnfs_bytes_free(out);
+
+        let log: ProgressLog = (RefCell::new(Vec::new()), Cell::new(None));
+        let progress = WnfsProgressCallback {
+            context: &log as *const ProgressLog as *mut c_void,
+            on_progress: Some(record_progress),
+        };
+        assert_eq!(
+            wnfs_write_file_observed(
+                helper,
+                path("big.bin").as_ptr(),
+                content.as_ptr(),
+                content.len(),
+                0,
+                ptr::null(),
+                &progress,
+                &mut out,
+            ),
+            WnfsStatus::Ok
+        );
+        wnfs_bytes_free(out);
+        let last = log.0.borrow().last().unwrap().to_owned();
+        assert_eq!(last, ("write_file".to_string(), 600_000, 600_000));
+
+        log.0.borrow_mut().clear();
+        assert_eq!(
+            wnfs_read_file_observed(helper, path("big.bin").as_ptr(), &progress, &mut out),
+            WnfsStatus::Ok
+        );
+        assert_eq!(take_bytes(out), content);
+        assert_eq!(log.0.borrow().last().unwrap().0, "read_file");
+
+        // A null callback observes nothing.
+        assert_eq!(
+            wnfs_read_file_observed(helper, path("big.bin").as_ptr(), ptr::null(), &mut out),
+            WnfsStatus::Ok
+        );
+        wnfs_bytes_free(out);
+
+        // Returning non-zero cancels the write, which leaves the tree as it was.
+        log.1.set(Some(200_000));
+        assert_eq!(
+            wnfs_write_file_observed(
+                helper,
+                path("partial.bin").as_ptr(),
+                content.as_ptr(),
+                content.len(),
+                0,
+                ptr::null(),
+                &progress,
+                &mut out,
+            ),
+            WnfsStatus::Cancelled
+        );
+        assert_eq!(
+            wnfs_read_file(helper, path("partial.bin").as_ptr(), &mut out),
+            WnfsStatus::NotFound
+        );
+        assert_eq!(wnfs_ls(helper, path("").as_ptr(), &mut out), WnfsStatus::Ok);
+        let listing: serde_json::Value = serde_json::from_slice(&take_bytes(out)).unwrap();
+        assert_eq!(listing.as_array().unwrap().len(), 1);
+        wnfs_helper_free(helper);
+    }
+}
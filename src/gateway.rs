@@ -0,0 +1,1260 @@
+//! Read-only store over an IPFS trustless gateway, and a resolver for the mutable pointer
+//! endpoints that publish a forest's current root, with HTTP caching.
+//!
+//! Blocks are requested as `application/vnd.ipld.raw` and subtrees can be prefetched as
+//! `application/vnd.ipld.car`. The gateway isn't trusted: every block is hashed locally and
+//! checked against its CID before it is used or cached.
+//!
+//! Blocks are content addressed, so a verified block never changes: the store keeps the blocks
+//! it read in the same LRU as `CachedBlockStore`, whatever the responses' `Cache-Control` said.
+//! Pointers change, so [`PointerResolver`] caches their responses as HTTP says, revalidating
+//! them on every call by default and only downloading a body when the gateway reports a new
+//! `ETag`.
+//!
+//! A CID can carry provider hints, gateways learned with it from a share or a sync peer. Hinted
+//! gateways are asked first, before the configured ones, and the hints of a DAG-CBOR block pass
+//! on to the blocks it links to, so a whole shared subtree is read from where it was published.
+//! Apps that must not contact unconfigured hosts turn hints off, see `network`.
+//!
+//! Hostnames are looked up with the system resolver unless a [`DohResolver`] is set, see
+//! `GatewayStore::with_dns_resolver`, and requests can go through a proxy such as Tor, see
+//! `GatewayStore::with_proxy`. Responses can be compressed in transit on metered connections,
+//! see `GatewayStore::with_compression`.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    io::Cursor,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use futures::{
+    future::{select, select_ok, Either},
+    FutureExt,
+};
+use libipld::{cbor::DagCborCodec, codec::Codec, Cid, Ipld, IpldCodec};
+use log::trace;
+use reqwest::{
+    header::{ACCEPT, CACHE_CONTROL, ETAG, IF_NONE_MATCH},
+    StatusCode, Url,
+};
+use web_time::Instant;
+
+use crate::blockstore::{block_on, cid_from_bytes, BlockCache, CacheStats, DecodeLimits, FFIStore};
+use crate::car::{self, read_car};
+use crate::error::{ErrorCode, WnfsUtilsError};
+use crate::network;
+use crate::request_id;
+
+/// Sent with every gateway request made inside a request scope, see `request_id`.
+pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+const RAW_BLOCK: &str = "application/vnd.ipld.raw";
+const CAR: &str = "application/vnd.ipld.car; version=1; order=dfs; dups=n";
+// The empty identity block, answered by gateways without any network fetch.
+const PROBE_CID: &str = "bafkqaaa";
+// Largest CAR section accepted: a maximal block plus its CID.
+const MAX_CAR_SECTION: usize = 4 * 1024 * 1024 + 128;
+
+// Largest CAR response `prefetch_subtree` reads by default, see `with_prefetch_limit`.
+const DEFAULT_PREFETCH_LIMIT: usize = 64 * 1024 * 1024;
+
+// Largest pointer body accepted, far more than a CID needs.
+const MAX_POINTER_BODY: usize = 64 * 1024;
+
+// Most CIDs with their own hint list; past it, hints are no longer passed on to linked blocks.
+const MAX_HINTED_CIDS: usize = 100_000;
+
+// Longest DNS label, which bounds the CIDs a subdomain gateway can serve.
+const MAX_DNS_LABEL: usize = 63;
+
+/// A validated gateway location.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GatewayUrl {
+    /// Serves `<origin>/ipfs/<cid>`, e.g. `https://ipfs.io`.
+    Path { origin: String },
+    /// Serves `<scheme>://<cid>.ipfs.<host>`, e.g. `https://dweb.link`.
+    Subdomain { scheme: String, host: String },
+}
+
+impl GatewayUrl {
+    /// Normalizes the shapes users paste. A missing scheme means `https`; an `/ipfs/<cid>` path
+    /// or a `<cid>.ipfs.` subdomain copied from a content link is stripped, and so are query
+    /// strings, fragments and trailing slashes. Bare hosts are treated as path gateways, which
+    /// subdomain gateways answer with a redirect.
+    pub fn parse(input: &str) -> Result<Self, WnfsUtilsError> {
+        let invalid = |reason: &str| WnfsUtilsError::InvalidGatewayUrl {
+            url: input.to_string(),
+            reason: reason.to_string(),
+        };
+        let trimmed = input.trim();
+        if trimmed.is_empty() {
+            return Err(invalid("empty URL"));
+        }
+        let with_scheme = match trimmed.contains("://") {
+            true => trimmed.to_string(),
+            false => format!("https://{}", trimmed),
+        };
+        let url = Url::parse(&with_scheme).map_err(|e| invalid(&e.to_string()))?;
+        let scheme = url.scheme().to_string();
+        if scheme != "https" && scheme != "http" {
+            return Err(invalid("only http and https gateways are supported"));
+        }
+        let host = url
+            .host_str()
+            .ok_or_else(|| invalid("missing host"))?
+            .to_ascii_lowercase();
+        let authority = match url.port() {
+            Some(port) => format!("{}:{}", host, port),
+            None => host.to_owned(),
+        };
+
+        if let Some((label, parent)) = authority.split_once(".ipfs.") {
+            if Cid::try_from(label).is_err() {
+                return Err(invalid("subdomain before .ipfs. is not a CID"));
+            }
+            return Ok(GatewayUrl::Subdomain {
+                scheme,
+                host: parent.to_string(),
+            });
+        }
+        let mut segments: Vec<&str> = url
+            .path_segments()
+            .map(|segments| segments.filter(|s| !s.is_empty()).collect())
+            .unwrap_or_default();
+        // Keep a path prefix (gateways mounted below one), dropping a pasted `/ipfs/<cid>/...`.
+        if let Some(position) = segments.iter().position(|s| *s == "ipfs" || *s == "ipns") {
+            segments.truncate(position);
+        }
+        let mut origin = format!("{}://{}", scheme, authority);
+        for segment in segments {
+            origin.push('/');
+            origin.push_str(segment);
+        }
+        Ok(GatewayUrl::Path { origin })
+    }
+
+    /// Whether the gateway is a Tor onion service, reachable only through a Tor proxy.
+    pub fn is_onion(&self) -> bool {
+        let host = match self {
+            GatewayUrl::Path { origin } => Url::parse(origin)
+                .ok()
+                .and_then(|url| url.host_str().map(str::to_string)),
+            GatewayUrl::Subdomain { host, .. } => Some(host.to_owned()),
+        };
+        host.map(|host| {
+            host.split(':')
+                .next()
+                .unwrap_or_default()
+                .ends_with(".onion")
+        })
+        .unwrap_or(false)
+    }
+
+    /// URL of `cid` with `query` (without `?`), falling back to path style on a subdomain
+    /// gateway when the CID doesn't fit in a DNS label.
+    pub fn content_url(&self, cid: &Cid, query: Option<&str>) -> String {
+        let query = query.map(|query| format!("?{}", query)).unwrap_or_default();
+        match self {
+            GatewayUrl::Path { origin } => format!("{}/ipfs/{}{}", origin, cid, query),
+            GatewayUrl::Subdomain { scheme, host } => {
+                // Subdomains need a case-insensitive encoding, which CIDv1 base32 is.
+                let label = cid.into_v1().map(|cid| cid.to_string()).unwrap_or_default();
+                match !label.is_empty() && label.len() <= MAX_DNS_LABEL {
+                    true => format!("{}://{}.ipfs.{}/{}", scheme, label, host, query),
+                    false => format!("{}://{}/ipfs/{}{}", scheme, host, cid, query),
+                }
+            }
+        }
+    }
+}
+
+/// The directives of a `Cache-Control` header the cache acts on.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheDirectives {
+    pub max_age: Option<Duration>,
+    pub immutable: bool,
+    /// Stored, but revalidated before every use.
+    pub no_cache: bool,
+    pub no_store: bool,
+}
+
+impl CacheDirectives {
+    pub fn parse(header: &str) -> Self {
+        let mut directives = Self::default();
+        for directive in header.split(',') {
+            let directive = directive.trim().to_ascii_lowercase();
+            match directive.split_once('=') {
+                Some(("max-age", seconds)) => {
+                    directives.max_age = seconds
+                        .trim_matches('"')
+                        .parse()
+                        .ok()
+                        .map(Duration::from_secs)
+                }
+                Some(_) => {}
+                None => match directive.as_str() {
+                    "immutable" => directives.immutable = true,
+                    "no-cache" => directives.no_cache = true,
+                    "no-store" => directives.no_store = true,
+                    _ => {}
+                },
+            }
+        }
+        directives
+    }
+}
+
+/// How [`PointerResolver`] treats cached pointers.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Revalidation {
+    /// Send a conditional request on every resolve, whatever the cache headers say.
+    #[default]
+    Always,
+    /// Trust `max-age` and only revalidate stale entries.
+    RespectMaxAge,
+}
+
+#[derive(Debug, Clone)]
+struct CachedResponse {
+    body: Vec<u8>,
+    etag: Option<String>,
+    // `None` when the entry must be revalidated before use.
+    fresh_until: Option<Instant>,
+}
+
+/// Cached pointer responses keyed by URL.
+#[derive(Debug, Default)]
+struct HttpCache {
+    entries: HashMap<String, CachedResponse>,
+    cached_bytes: usize,
+    max_bytes: usize,
+}
+
+impl HttpCache {
+    fn new(max_bytes: usize) -> Self {
+        Self {
+            max_bytes,
+            ..Default::default()
+        }
+    }
+
+    fn insert(&mut self, url: String, entry: CachedResponse) {
+        if let Some(previous) = self.entries.remove(&url) {
+            self.cached_bytes -= previous.body.len();
+        }
+        // Responses that don't fit are served but not kept.
+        if self.cached_bytes + entry.body.len() > self.max_bytes {
+            return;
+        }
+        self.cached_bytes += entry.body.len();
+        self.entries.insert(url, entry);
+    }
+
+    fn remove(&mut self, url: &str) {
+        if let Some(previous) = self.entries.remove(url) {
+            self.cached_bytes -= previous.body.len();
+        }
+    }
+}
+
+/// Checks that `data` hashes to `cid`, so a block from an untrusted gateway can be used.
+pub fn verify_block(cid: &Cid, data: &[u8]) -> Result<()> {
+    car::verify_block(cid, data).map_err(|e| {
+        trace!("wnfsError in verify_block: {}", e);
+        anyhow!(e)
+    })
+}
+
+fn lock(cache: &Mutex<HttpCache>) -> std::sync::MutexGuard<'_, HttpCache> {
+    match cache.lock() {
+        Ok(cache) => cache,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+fn lock_latencies(latencies: &Mutex<LatencyWindow>) -> std::sync::MutexGuard<'_, LatencyWindow> {
+    match latencies.lock() {
+        Ok(latencies) => latencies,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+fn lock_selector(selector: &Mutex<GatewaySelector>) -> std::sync::MutexGuard<'_, GatewaySelector> {
+    match selector.lock() {
+        Ok(selector) => selector,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+fn fresh_until(directives: &CacheDirectives, now: Instant) -> Option<Instant> {
+    if directives.no_cache {
+        return None;
+    }
+    if directives.immutable {
+        // Far enough in the future to never expire while the process runs.
+        return now.checked_add(Duration::from_secs(100 * 365 * 24 * 3600));
+    }
+    directives
+        .max_age
+        .and_then(|max_age| now.checked_add(max_age))
+}
+
+// Reads the body of `response`, failing with `WnfsUtilsError::BlockTooLarge` as soon as it
+// grows past `limit` bytes instead of buffering the rest. Compressed bodies are counted as
+// decoded.
+pub(crate) async fn read_body(mut response: reqwest::Response, limit: usize) -> Result<Vec<u8>> {
+    let too_large = |size: usize| WnfsUtilsError::BlockTooLarge { size, limit };
+    if let Some(length) = response.content_length() {
+        if length > limit as u64 {
+            trace!(
+                "wnfsError in read_body: {} announces {} bytes",
+                response.url(),
+                length
+            );
+            return Err(too_large(usize::try_from(length).unwrap_or(usize::MAX)).into());
+        }
+    }
+    let mut body = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        let size = body.len() + chunk.len();
+        if size > limit {
+            trace!(
+                "wnfsError in read_body: {} is over {} bytes",
+                response.url(),
+                limit
+            );
+            return Err(too_large(size).into());
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok(body)
+}
+
+// Performs a GET of `url` through `cache`, where it is stored under `key`, revalidating a stale
+// entry with `If-None-Match`. Bodies over `limit` bytes fail, see `read_body`.
+async fn cached_get(
+    client: &reqwest::Client,
+    cache: &Mutex<HttpCache>,
+    key: &str,
+    url: &str,
+    accept: &str,
+    revalidate_always: bool,
+    limit: usize,
+) -> Result<Vec<u8>> {
+    let now = Instant::now();
+    let cached = { lock(cache).entries.get(key).cloned() };
+    if let Some(entry) = &cached {
+        let fresh = entry.fresh_until.map(|until| until > now).unwrap_or(false);
+        if fresh && !revalidate_always {
+            return Ok(entry.body.to_owned());
+        }
+    }
+
+    let mut request = client.get(url).header(ACCEPT, accept);
+    if let Some(etag) = cached.as_ref().and_then(|entry| entry.etag.as_ref()) {
+        request = request.header(IF_NONE_MATCH, etag);
+    }
+    if let Some(id) = request_id::current() {
+        request = request.header(REQUEST_ID_HEADER, id);
+    }
+    let response = request.send().await?;
+    let directives = directives_of_headers(response.headers());
+
+    let body = match (response.status(), cached) {
+        (StatusCode::NOT_MODIFIED, Some(entry)) => {
+            trace!("gateway: {} not modified", url);
+            entry.body
+        }
+        (status, _) if status.is_success() => {
+            let etag = response
+                .headers()
+                .get(ETAG)
+                .and_then(|etag| etag.to_str().ok())
+                .map(str::to_string);
+            let body = read_body(response, limit).await?;
+            if directives.no_store {
+                return Ok(body);
+            }
+            lock(cache).insert(
+                key.to_string(),
+                CachedResponse {
+                    body: body.to_owned(),
+                    etag,
+                    fresh_until: fresh_until(&directives, now),
+                },
+            );
+            return Ok(body);
+        }
+        (StatusCode::NOT_FOUND, _) => {
+            trace!("wnfsError in gateway GET {}: not found", url);
+            return Err(WnfsUtilsError::NotFound(url.to_string()).into());
+        }
+        (status, _) => {
+            trace!("wnfsError in gateway GET {}: {}", url, status);
+            return Err(anyhow!("gateway returned {} for {}", status, url));
+        }
+    };
+
+    // A 304 refreshes the lifetime of the cached entry.
+    if let Some(entry) = lock(cache).entries.get_mut(key) {
+        entry.fresh_until = fresh_until(&directives, now);
+    }
+    Ok(body)
+}
+
+fn directives_of_headers(headers: &reqwest::header::HeaderMap) -> CacheDirectives {
+    headers
+        .get(CACHE_CONTROL)
+        .and_then(|header| header.to_str().ok())
+        .map(CacheDirectives::parse)
+        .unwrap_or_default()
+}
+
+/// How [`GatewayStore`] picks among several gateways. Latencies are smoothed over probes, and
+/// reads only move to another gateway once it is clearly faster, so two gateways of similar
+/// speed don't take turns.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GatewaySelection {
+    /// The first read this long after the last round starts a new one in the background; reads
+    /// never wait for probes.
+    pub probe_interval: Duration,
+    /// Weight of a new sample in the smoothed latency, between 0 and 1.
+    pub smoothing: f64,
+    /// Another gateway is picked only when its latency is below the current one's by this
+    /// fraction.
+    pub switch_margin: f64,
+}
+
+impl Default for GatewaySelection {
+    fn default() -> Self {
+        Self {
+            probe_interval: Duration::from_secs(5 * 60),
+            smoothing: 0.3,
+            switch_margin: 0.2,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct GatewayHealth {
+    // Smoothed latency, `None` until the first successful probe.
+    latency: Option<Duration>,
+    available: bool,
+}
+
+#[derive(Debug)]
+struct GatewaySelector {
+    policy: GatewaySelection,
+    health: Vec<GatewayHealth>,
+    current: usize,
+    last_probe: Option<Instant>,
+}
+
+impl GatewaySelector {
+    fn new(count: usize, policy: GatewaySelection) -> Self {
+        Self {
+            policy,
+            // Untested gateways count as available, so reads work before the first probe.
+            health: vec![
+                GatewayHealth {
+                    latency: None,
+                    available: true,
+                };
+                count
+            ],
+            current: 0,
+            last_probe: None,
+        }
+    }
+
+    fn probe_due(&self, now: Instant) -> bool {
+        self.last_probe
+            .map(|last| now.duration_since(last) >= self.policy.probe_interval)
+            .unwrap_or(true)
+    }
+
+    fn record(&mut self, index: usize, sample: Option<Duration>) {
+        let smoothing = self.policy.smoothing.clamp(0.0, 1.0);
+        let health = &mut self.health[index];
+        match sample {
+            Some(sample) => {
+                health.available = true;
+                health.latency = Some(match health.latency {
+                    Some(latency) => latency.mul_f64(1.0 - smoothing) + sample.mul_f64(smoothing),
+                    None => sample,
+                });
+            }
+            None => health.available = false,
+        }
+    }
+
+    // Re-picks the current gateway after new samples.
+    fn select(&mut self) {
+        let best = self
+            .health
+            .iter()
+            .enumerate()
+            .filter(|(_, health)| health.available)
+            .min_by_key(|(_, health)| health.latency.unwrap_or(Duration::MAX))
+            .map(|(index, _)| index);
+        let best = match best {
+            Some(best) => best,
+            None => return,
+        };
+        let current = &self.health[self.current];
+        let switch = match (
+            current.available,
+            current.latency,
+            self.health[best].latency,
+        ) {
+            (false, _, _) => true,
+            (true, Some(current), Some(best)) => {
+                best < current.mul_f64(1.0 - self.policy.switch_margin.clamp(0.0, 1.0))
+            }
+            (true, None, Some(_)) => true,
+            (true, _, None) => false,
+        };
+        if switch && best != self.current {
+            trace!("gateway: switching from #{} to #{}", self.current, best);
+            self.current = best;
+        }
+    }
+
+    // Current gateway first, then the other available ones by latency, unavailable ones last.
+    fn order(&self) -> Vec<usize> {
+        let mut order: Vec<usize> = (0..self.health.len()).collect();
+        order.sort_by_key(|index| {
+            let health = &self.health[*index];
+            (
+                *index != self.current,
+                !health.available,
+                health.latency.unwrap_or(Duration::MAX),
+            )
+        });
+        order
+    }
+}
+
+/// Hedged reads for latency-sensitive stores: when the best gateway hasn't answered after the
+/// `percentile` of recent fetch times, the block is requested from the next gateway as well,
+/// the first verified response wins and the other request is dropped.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HedgingPolicy {
+    /// Percentile of recent fetch latencies to wait before hedging, between 0 and 1.
+    pub percentile: f64,
+    /// Fetches recorded before the percentile is trusted.
+    pub min_samples: usize,
+    /// Delay used until `min_samples` fetches were recorded.
+    pub initial_delay: Duration,
+}
+
+impl Default for HedgingPolicy {
+    fn default() -> Self {
+        Self {
+            percentile: 0.95,
+            min_samples: 20,
+            initial_delay: Duration::from_millis(250),
+        }
+    }
+}
+
+// Latencies of the most recent fetches.
+#[derive(Debug, Default)]
+struct LatencyWindow {
+    samples: VecDeque<Duration>,
+}
+
+impl LatencyWindow {
+    const CAPACITY: usize = 128;
+
+    fn record(&mut self, latency: Duration) {
+        if self.samples.len() == Self::CAPACITY {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(latency);
+    }
+
+    fn hedge_delay(&self, policy: &HedgingPolicy) -> Duration {
+        if self.samples.is_empty() || self.samples.len() < policy.min_samples {
+            return policy.initial_delay;
+        }
+        let mut sorted: Vec<Duration> = self.samples.iter().copied().collect();
+        sorted.sort();
+        let rank = (policy.percentile.clamp(0.0, 1.0) * (sorted.len() - 1) as f64).round();
+        sorted[rank as usize]
+    }
+}
+
+// Gateways are kept once and referenced by index, as a hint is copied to every block below
+// the hinted CID.
+#[derive(Default)]
+struct ProviderHints {
+    providers: Vec<GatewayUrl>,
+    by_cid: HashMap<Cid, Vec<usize>>,
+}
+
+impl ProviderHints {
+    fn add(&mut self, cid: Cid, provider: GatewayUrl) {
+        let index = match self.providers.iter().position(|known| *known == provider) {
+            Some(index) => index,
+            None => {
+                self.providers.push(provider);
+                self.providers.len() - 1
+            }
+        };
+        let hints = self.by_cid.entry(cid).or_default();
+        if !hints.contains(&index) {
+            hints.push(index);
+        }
+    }
+
+    fn for_cid(&self, cid: &Cid) -> Vec<GatewayUrl> {
+        self.by_cid
+            .get(cid)
+            .map(|hints| {
+                hints
+                    .iter()
+                    .filter_map(|index| self.providers.get(*index).cloned())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    // Passes the hints of `parent` on to `links`, keeping hints the links already had.
+    fn inherit(&mut self, parent: &Cid, links: Vec<Cid>) {
+        let Some(hints) = self.by_cid.get(parent).cloned() else {
+            return;
+        };
+        for link in links {
+            if self.by_cid.len() >= MAX_HINTED_CIDS && !self.by_cid.contains_key(&link) {
+                trace!("gateway: provider hint table full, not hinting {}", link);
+                continue;
+            }
+            let inherited = self.by_cid.entry(link).or_default();
+            for index in &hints {
+                if !inherited.contains(index) {
+                    inherited.push(*index);
+                }
+            }
+        }
+    }
+}
+
+// Whether `error` means the gateway itself is failing, so reads should move away from it. A
+// gateway without the block, or with one past the size limit, is healthy.
+fn gateway_failed(error: &anyhow::Error) -> bool {
+    !matches!(
+        ErrorCode::of(error),
+        ErrorCode::NotFound | ErrorCode::LimitExceeded
+    )
+}
+
+// Runs `future` without waiting for it, on the current tokio runtime or, in browsers, the
+// event loop. Without a runtime it is dropped.
+#[cfg(not(target_arch = "wasm32"))]
+fn spawn_detached(future: impl std::future::Future<Output = ()> + Send + 'static) {
+    match tokio::runtime::Handle::try_current() {
+        Ok(handle) => {
+            handle.spawn(future);
+        }
+        Err(_) => trace!("gateway: no runtime to run the probes on"),
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+fn spawn_detached(future: impl std::future::Future<Output = ()> + 'static) {
+    wasm_bindgen_futures::spawn_local(future);
+}
+
+fn lock_hints(hints: &Mutex<ProviderHints>) -> std::sync::MutexGuard<'_, ProviderHints> {
+    match hints.lock() {
+        Ok(hints) => hints,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+struct Hedging {
+    policy: HedgingPolicy,
+    // Async, so the losing request can be dropped mid-flight.
+    client: reqwest::Client,
+    latencies: Mutex<LatencyWindow>,
+}
+
+/// Fetches blocks from one or more [`GatewayUrl`]s, reading from the fastest available one and
+/// falling back to the others when it fails. Writes are rejected: gateways are only a source of
+/// blocks, e.g. to `reload` a forest published elsewhere.
+#[derive(Clone)]
+pub struct GatewayStore {
+    gateways: Vec<GatewayUrl>,
+    // Async, so `FFIFriendlyBlockStore` awaits block reads without holding a thread.
+    client: reqwest::Client,
+    cache: BlockCache,
+    selector: Arc<Mutex<GatewaySelector>>,
+    timeout: Duration,
+    hedging: Option<Arc<Hedging>>,
+    hints: Arc<Mutex<ProviderHints>>,
+    transport: Transport,
+    // Largest block read, from the `DecodeLimits` of the `FFIFriendlyBlockStore` on top.
+    max_block_size: Arc<AtomicUsize>,
+    prefetch_limit: usize,
+    // `network::configured_hosts_only` as the store was built.
+    configured_hosts_only: bool,
+}
+
+// How the HTTP clients reach the network, shared by all remote stores. With `compression`,
+// responses are decoded as they are read, and every body is read through `read_body`, whose
+// limit counts decoded bytes: a small compressed response can't expand past it.
+#[derive(Clone, Default)]
+pub(crate) struct Transport {
+    dns: Option<DohResolver>,
+    proxy: Option<ProxyConfig>,
+    pub(crate) compression: bool,
+}
+
+impl Transport {
+    pub(crate) fn client(&self, timeout: Duration) -> Result<reqwest::Client> {
+        Ok(self.builder(timeout)?.build()?)
+    }
+
+    // A client builder set up for this transport, for stores tuning it further.
+    pub(crate) fn builder(&self, timeout: Duration) -> Result<reqwest::ClientBuilder> {
+        let mut builder = network::client_builder(timeout)
+            .gzip(self.compression)
+            .brotli(self.compression)
+            .zstd(self.compression);
+        // A proxy resolving hostnames itself never lets the client look one up.
+        if let Some(dns) = self.dns.as_ref().filter(|_| !self.resolves_remotely()) {
+            builder = builder.dns_resolver(Arc::new(dns.to_owned()));
+        }
+        if let Some(proxy) = &self.proxy {
+            builder = builder.proxy(proxy.proxy()?);
+        }
+        Ok(builder)
+    }
+
+    pub(crate) fn set_dns_resolver(&mut self, config: &DohConfig, timeout: Duration) -> Result<()> {
+        self.dns = Some(match &self.proxy {
+            Some(proxy) => DohResolver::through_proxy(config, timeout, proxy)?,
+            None => DohResolver::new(config, timeout)?,
+        });
+        Ok(())
+    }
+
+    // Also routes the queries of a DoH resolver set earlier through `config`.
+    pub(crate) fn set_proxy(&mut self, config: &ProxyConfig, timeout: Duration) -> Result<()> {
+        config.proxy()?;
+        self.proxy = Some(config.to_owned());
+        if let Some(dns) = &self.dns {
+            self.dns = Some(DohResolver::through_proxy(dns.config(), timeout, config)?);
+        }
+        Ok(())
+    }
+
+    // Whether hostnames are resolved by the proxy, which is then the only way to `.onion` hosts.
+    pub(crate) fn resolves_remotely(&self) -> bool {
+        self.proxy
+            .as_ref()
+            .map(|proxy| proxy.resolves_remotely().unwrap_or(false))
+            .unwrap_or(false)
+    }
+}
+
+impl GatewayStore {
+    /// Keeps up to `max_cached_bytes` of verified blocks in memory. Block reads through an
+    /// `FFIFriendlyBlockStore` are awaited on any runtime; the sync methods, `probe_gateways`
+    /// and `prefetch_subtree` among them, block the calling thread and need a multi-threaded
+    /// runtime when called from async code. `gateway_url` is validated here, see
+    /// [`GatewayUrl::parse`].
+    pub fn new(gateway_url: &str, timeout: Duration, max_cached_bytes: usize) -> Result<Self> {
+        Self::with_gateways(
+            &[gateway_url],
+            timeout,
+            max_cached_bytes,
+            GatewaySelection::default(),
+        )
+    }
+
+    /// Like `new`, reading from the best of `gateway_urls` as picked by `selection`.
+    pub fn with_gateways(
+        gateway_urls: &[&str],
+        timeout: Duration,
+        max_cached_bytes: usize,
+        selection: GatewaySelection,
+    ) -> Result<Self> {
+        let gateways = gateway_urls
+            .iter()
+            .map(|url| GatewayUrl::parse(url))
+            .collect::<Result<Vec<_>, _>>()?;
+        if gateways.is_empty() {
+            return Err(anyhow!("at least one gateway is required"));
+        }
+        let transport = Transport::default();
+        let client = transport.client(timeout)?;
+        Ok(Self {
+            selector: Arc::new(Mutex::new(GatewaySelector::new(gateways.len(), selection))),
+            gateways,
+            client,
+            cache: BlockCache::new(max_cached_bytes),
+            timeout,
+            hedging: None,
+            hints: Arc::new(Mutex::new(ProviderHints::default())),
+            transport,
+            max_block_size: Arc::new(AtomicUsize::new(DecodeLimits::default().max_block_size)),
+            prefetch_limit: DEFAULT_PREFETCH_LIMIT,
+            configured_hosts_only: network::configured_hosts_only(),
+        })
+    }
+
+    /// Hedges reads across the two best gateways, see [`HedgingPolicy`]. Meant for stores
+    /// serving interactive reads; background sync is better served without, as hedging can
+    /// double the requests sent.
+    pub fn with_hedging(mut self, policy: HedgingPolicy) -> Result<Self> {
+        self.hedging = Some(Arc::new(Hedging {
+            policy,
+            client: self.transport.client(self.timeout)?,
+            latencies: Mutex::new(LatencyWindow::default()),
+        }));
+        Ok(self)
+    }
+
+    /// Resolves gateway hostnames over DNS-over-HTTPS as set in `config`, usually
+    /// `HelperConfig::dns_over_https`.
+    pub fn with_dns_resolver(mut self, config: &DohConfig) -> Result<Self> {
+        self.transport.set_dns_resolver(config, self.timeout)?;
+        self.rebuild_clients()
+    }
+
+    /// Sends every request through the proxy in `config`, usually `HelperConfig::proxy`.
+    /// `.onion` gateways need a proxy that resolves hostnames itself, such as Tor's `socks5h`.
+    /// Such a proxy also takes over from a DoH resolver; behind a plain `socks5` proxy the
+    /// resolver's queries go through the proxy, see `DohResolver::through_proxy`.
+    pub fn with_proxy(mut self, config: &ProxyConfig) -> Result<Self> {
+        if !config.resolves_remotely()? && self.gateways.iter().any(GatewayUrl::is_onion) {
+            return Err(anyhow!(
+                "{} resolves hostnames locally and can't reach .onion gateways",
+                config.url
+            ));
+        }
+        self.transport.set_proxy(config, self.timeout)?;
+        self.rebuild_clients()
+    }
+
+    /// Asks gateways for gzip, brotli or zstd encoded responses when `enabled`, usually
+    /// `HelperConfig::transfer_compression`, trading CPU for bandwidth. Encrypted private blocks
+    /// barely compress; CAR prefetches of public data and pointer bodies do.
+    pub fn with_compression(mut self, enabled: bool) -> Result<Self> {
+        self.transport.compression = enabled;
+        self.rebuild_clients()
+    }
+
+    /// Caps the CAR responses read by `prefetch_subtree` at `max_bytes`, 64 MiB by default.
+    /// Larger subtrees fail to prefetch and are read block by block instead.
+    pub fn with_prefetch_limit(mut self, max_bytes: usize) -> Self {
+        self.prefetch_limit = max_bytes;
+        self
+    }
+
+    fn rebuild_clients(mut self) -> Result<Self> {
+        self.client = self.transport.client(self.timeout)?;
+        match self.hedging.take() {
+            Some(hedging) => self.with_hedging(hedging.policy),
+            None => Ok(self),
+        }
+    }
+
+    /// Asks the gateway at `provider` first for `cid` and every block reachable from it. The
+    /// URL is validated like the configured gateways, see [`GatewayUrl::parse`].
+    pub fn add_provider_hint(&self, cid: &Cid, provider: &str) -> Result<()> {
+        let provider = GatewayUrl::parse(provider)?;
+        lock_hints(&self.hints).add(*cid, provider);
+        Ok(())
+    }
+
+    // Fetches a block not in the cache from the gateways at `first` and, after the hedge
+    // delay, `second`.
+    async fn hedged_get(
+        &self,
+        hedging: &Hedging,
+        cid: &Cid,
+        first: usize,
+        second: usize,
+    ) -> Result<Vec<u8>> {
+        let delay = lock_latencies(&hedging.latencies).hedge_delay(&hedging.policy);
+        let limit = self.max_block_size();
+        let fetch = |index: usize| {
+            let url = self.gateways[index].content_url(cid, None);
+            let mut request = hedging.client.get(url).header(ACCEPT, RAW_BLOCK);
+            if let Some(id) = request_id::current() {
+                request = request.header(REQUEST_ID_HEADER, id);
+            }
+            async move {
+                let response = request.send().await?;
+                if !response.status().is_success() {
+                    return Err(anyhow!(
+                        "gateway returned {} for {}",
+                        response.status(),
+                        cid
+                    ));
+                }
+                let data = read_body(response, limit).await?;
+                verify_block(cid, &data)?;
+                Ok((index, data))
+            }
+            .boxed()
+        };
+
+        let started = Instant::now();
+        let hedged = async {
+            match select(fetch(first), Box::pin(crate::timer::sleep(delay))).await {
+                Either::Left((Ok(fetched), _)) => Ok(fetched),
+                // A quick failure goes straight to the second gateway.
+                Either::Left((Err(_), _)) => fetch(second).await,
+                Either::Right((_, pending)) => {
+                    trace!("gateway: hedging {} after {:?}", cid, delay);
+                    select_ok([pending, fetch(second)])
+                        .await
+                        .map(|(fetched, _)| fetched)
+                }
+            }
+        };
+        let (winner, data) = hedged.await?;
+        lock_latencies(&hedging.latencies).record(started.elapsed());
+        trace!("gateway: {} served by #{}", cid, winner);
+        self.cache.insert(cid.to_bytes(), &data);
+        Ok(data)
+    }
+
+    /// Measures every gateway with a request for the empty identity block, which a gateway
+    /// answers without fetching anything, and re-picks the gateway reads go to.
+    pub fn probe_gateways(&self) {
+        if let Err(e) = block_on(self.probe()) {
+            trace!("gateway: probes not sent: {:?}", e.to_string());
+        }
+    }
+
+    async fn probe(&self) {
+        // A constant, so this never fails.
+        let probe = match Cid::try_from(PROBE_CID) {
+            Ok(probe) => probe,
+            Err(_) => return,
+        };
+        let mut samples: Vec<Option<Duration>> = Vec::with_capacity(self.gateways.len());
+        for gateway in &self.gateways {
+            let url = gateway.content_url(&probe, None);
+            let started = Instant::now();
+            let response = self.client.get(&url).header(ACCEPT, RAW_BLOCK).send().await;
+            samples.push(match response {
+                Ok(response) if response.status().is_success() => Some(started.elapsed()),
+                Ok(response) => {
+                    trace!("gateway: probe of {} returned {}", url, response.status());
+                    None
+                }
+                Err(e) => {
+                    trace!("gateway: probe of {} failed: {:?}", url, e.to_string());
+                    None
+                }
+            });
+        }
+        let mut selector = lock_selector(&self.selector);
+        for (index, sample) in samples.into_iter().enumerate() {
+            selector.record(index, sample);
+        }
+        selector.last_probe = Some(Instant::now());
+        selector.select();
+    }
+
+    // The configured gateways, best first. A probe round that is due is started in the
+    // background, and this read goes on with the order as it stands.
+    fn gateway_order(&self) -> Vec<usize> {
+        let mut selector = lock_selector(&self.selector);
+        let now = Instant::now();
+        if selector.probe_due(now) {
+            // Counts from the start, so a slow round isn't started again by the next reads.
+            selector.last_probe = Some(now);
+            let store = self.clone();
+            spawn_detached(async move { store.probe().await });
+        }
+        selector.order()
+    }
+
+    /// Downloads every block reachable from `root` as one CAR response and caches the
+    /// verified blocks, so walking the subtree afterwards needs no further requests. Returns
+    /// the number of blocks cached. Responses past the prefetch limit fail with
+    /// `WnfsUtilsError::BlockTooLarge`, see `with_prefetch_limit`.
+    pub fn prefetch_subtree(&self, root: &Cid) -> Result<usize> {
+        block_on(self.prefetch(root))?
+    }
+
+    async fn prefetch(&self, root: &Cid) -> Result<usize> {
+        let hinted = self.hinted_gateways(root).into_iter().next();
+        let gateway = match hinted {
+            Some(hinted) => hinted,
+            None => {
+                let best = self.gateway_order().first().copied().unwrap_or_default();
+                self.gateways[best].to_owned()
+            }
+        };
+        let url = gateway.content_url(root, Some("dag-scope=all"));
+        let mut request = self.client.get(&url).header(ACCEPT, CAR);
+        if let Some(id) = request_id::current() {
+            request = request.header(REQUEST_ID_HEADER, id);
+        }
+        let response = request.send().await?;
+        if !response.status().is_success() {
+            trace!(
+                "wnfsError in prefetch_subtree {}: {}",
+                url,
+                response.status()
+            );
+            return Err(anyhow!(
+                "gateway returned {} for {}",
+                response.status(),
+                url
+            ));
+        }
+        let body = read_body(response, self.prefetch_limit).await?;
+        let (_, blocks) =
+            read_car(&mut Cursor::new(body), MAX_CAR_SECTION).map_err(|e| anyhow!(e))?;
+        let count = blocks.len();
+        for (cid, data) in blocks {
+            verify_block(&cid, &data)?;
+            self.cache.insert(cid.to_bytes(), &data);
+        }
+        Ok(count)
+    }
+
+    // Tries the gateways hinted for `cid`, without the selector: they are extra sources, not
+    // part of the configured set.
+    async fn hinted_get(&self, cid: &Cid) -> Option<Vec<u8>> {
+        for gateway in self.hinted_gateways(cid) {
+            let url = gateway.content_url(cid, None);
+            match self.get_verified(&url, cid).await {
+                Ok(data) => return Some(data),
+                Err(e) => trace!(
+                    "wnfsError in gateway get_block via hinted {}: {:?}",
+                    url,
+                    e.to_string()
+                ),
+            }
+        }
+        None
+    }
+
+    // GETs the block at `url` and checks it against `cid`. Bodies past the block size limit
+    // fail, see `read_body`.
+    async fn get_verified(&self, url: &str, cid: &Cid) -> Result<Vec<u8>> {
+        let mut request = self.client.get(url).header(ACCEPT, RAW_BLOCK);
+        if let Some(id) = request_id::current() {
+            request = request.header(REQUEST_ID_HEADER, id);
+        }
+        let response = request.send().await?;
+        match response.status() {
+            status if status.is_success() => {}
+            StatusCode::NOT_FOUND => {
+                trace!("wnfsError in gateway GET {}: not found", url);
+                return Err(WnfsUtilsError::NotFound(url.to_string()).into());
+            }
+            status => {
+                trace!("wnfsError in gateway GET {}: {}", url, status);
+                return Err(anyhow!("gateway returned {} for {}", status, url));
+            }
+        }
+        let data = read_body(response, self.max_block_size()).await?;
+        verify_block(cid, &data)?;
+        Ok(data)
+    }
+
+    // The hints for `cid`, only those naming a configured gateway when connections were
+    // restricted to configured hosts as the store was built, see `network`.
+    fn hinted_gateways(&self, cid: &Cid) -> Vec<GatewayUrl> {
+        let mut hinted = lock_hints(&self.hints).for_cid(cid);
+        if self.configured_hosts_only {
+            hinted.retain(|gateway| self.gateways.contains(gateway));
+        }
+        hinted
+    }
+
+    fn max_block_size(&self) -> usize {
+        self.max_block_size.load(Ordering::Relaxed)
+    }
+
+    async fn fetch_block(&self, cid: &Cid) -> Result<Vec<u8>> {
+        if let Some(data) = self.cache.lookup(&cid.to_bytes()) {
+            return Ok(data);
+        }
+        if let Some(data) = self.hinted_get(cid).await {
+            self.cache.insert(cid.to_bytes(), &data);
+            return Ok(data);
+        }
+        let order = self.gateway_order();
+        if let (Some(hedging), [first, second, ..]) = (&self.hedging, order.as_slice()) {
+            return self.hedged_get(hedging, cid, *first, *second).await;
+        }
+        let mut last_error = anyhow!("no gateway configured");
+        for index in order {
+            let url = self.gateways[index].content_url(cid, None);
+            match self.get_verified(&url, cid).await {
+                Ok(data) => {
+                    self.cache.insert(cid.to_bytes(), &data);
+                    return Ok(data);
+                }
+                Err(e) => {
+                    trace!(
+                        "wnfsError in gateway get_block via {}: {:?}",
+                        url,
+                        e.to_string()
+                    );
+                    if gateway_failed(&e) {
+                        let mut selector = lock_selector(&self.selector);
+                        selector.record(index, None);
+                        selector.select();
+                    }
+                    last_error = e;
+                }
+            }
+        }
+        Err(last_error)
+    }
+
+    // Hints the blocks linked from a hinted DAG-CBOR block with the same gateways.
+    fn inherit_hints(&self, cid: &Cid, data: &[u8]) {
+        if cid.codec() != u64::from(IpldCodec::DagCbor) {
+            return;
+        }
+        let mut hints = lock_hints(&self.hints);
+        if !hints.by_cid.contains_key(cid) {
+            return;
+        }
+        let ipld: Ipld = match DagCborCodec.decode(data) {
+            Ok(ipld) => ipld,
+            Err(e) => {
+                trace!(
+                    "gateway: no links inherited from {}: {:?}",
+                    cid,
+                    e.to_string()
+                );
+                return;
+            }
+        };
+        let mut links = Vec::new();
+        ipld.references(&mut links);
+        hints.inherit(cid, links);
+    }
+}
+
+#[async_trait(?Send)]
+impl<'a> FFIStore<'a> for GatewayStore {
+    fn get_block(&self, cid: Vec<u8>) -> Result<Vec<u8>> {
+        block_on(self.get_block_async(cid))?
+    }
+
+    async fn get_block_async(&self, cid: Vec<u8>) -> Result<Vec<u8>> {
+        let cid = cid_from_bytes(&cid)?;
+        let data = self.fetch_block(&cid).await?;
+        self.inherit_hints(&cid, &data);
+        Ok(data)
+    }
+
+    fn put_block(&self, _cid: Vec<u8>, _bytes: Vec<u8>) -> Result<()> {
+        Err(anyhow!("the gateway store is read-only"))
+    }
+
+    fn set_max_block_size(&self, max_block_size: usize) {
+        self.max_block_size.store(max_block_size, Ordering::Relaxed);
+    }
+
+    fn add_provider_hint(&self, cid: Vec<u8>, provider: String) -> Result<()> {
+        GatewayStore::add_provider_hint(self, &cid_from_bytes(&cid)?, &provider)
+    }
+
+    fn cache_stats(&self) -> Option<CacheStats> {
+        Some(self.cache.stats())
+    }
+}
+
+/// Resolves mutable pointer endpoints, whose body is the CID a name currently points to.
+pub struct PointerResolver {
+    client: reqwest::Client,
+    cache: Mutex<HttpCache>,
+    revalidation: Revalidation,
+    timeout: Duration,
+    transport: Transport,
+}
+
+impl PointerResolver {
+    pub fn new(timeout: Duration, revalidation: Revalidation) -> Result<Self> {
+        let transport = Transport::default();
+        Ok(Self {
+            client: transport.client(timeout)?,
+            // Pointer bodies are a CID each; the bound only guards against odd endpoints.
+            cache: Mutex::new(HttpCache::new(1024 * 1024)),
+            revalidation,
+            timeout,
+            transport,
+        })
+    }
+
+    /// Resolves pointer hostnames over DNS-over-HTTPS, see `GatewayStore::with_dns_resolver`.
+    pub fn with_dns_resolver(mut self, config: &DohConfig) -> Result<Self> {
+        self.transport.set_dns_resolver(config, self.timeout)?;
+        self.client = self.transport.client(self.timeout)?;
+        Ok(self)
+    }
+
+    /// Sends pointer requests through a proxy, see `GatewayStore::with_proxy`. Pointers are only
+    /// known when resolved, so `.onion` ones fail in `resolve` unless the proxy resolves
+    /// hostnames itself.
+    pub fn with_proxy(mut self, config: &ProxyConfig) -> Result<Self> {
+        self.transport.set_proxy(config, self.timeout)?;
+        self.client = self.transport.client(self.timeout)?;
+        Ok(self)
+    }
+
+    /// Accepts compressed pointer responses, see `GatewayStore::with_compression`.
+    pub fn with_compression(mut self, enabled: bool) -> Result<Self> {
+        self.transport.compression = enabled;
+        self.client = self.transport.client(self.timeout)?;
+        Ok(self)
+    }
+
+    /// The CID published at `url`, e.g. to pass to `PrivateDirectoryHelper::reload`.
+    pub fn resolve(&self, url: &str) -> Result<Cid> {
+        let onion = Url::parse(url)?
+            .host_str()
+            .map(|host| host.to_ascii_lowercase().ends_with(".onion"))
+            .unwrap_or(false);
+        if onion && !self.transport.resolves_remotely() {
+            return Err(anyhow!(
+                "{} is an onion service and needs a proxy resolving hostnames, see `with_proxy`",
+                url
+            ));
+        }
+        let always = self.revalidation == Revalidation::Always;
+        let body = block_on(cached_get(
+            &self.client,
+            &self.cache,
+            url,
+            url,
+            "text/plain, */*",
+            always,
+            MAX_POINTER_BODY,
+        ))??;
+        let text = String::from_utf8(body)?;
+        Cid::try_from(text.trim()).map_err(|e| anyhow!("invalid root CID at {}: {}", url, e))
+    }
+}
+
+mod doh;
+mod proxy;
+
+pub use doh::{DohConfig, DohResolver};
+pub use proxy::ProxyConfig;
+
+#[cfg(test)]
+mod gateway_tests;
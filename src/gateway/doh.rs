@@ -0,0 +1,263 @@
+//! DNS-over-HTTPS lookups for the remote stores, for networks whose DNS is broken or censored.
+//!
+//! Gateway hostnames are resolved with the JSON API of a DoH resolver (`application/dns-json`,
+//! served by Cloudflare, Google and Quad9 among others). The resolver itself is reached at
+//! pinned bootstrap addresses, so no lookup goes through the system resolver unless
+//! `system_fallback` allows it. Answers are cached for their TTL.
+//!
+//! Behind a proxy that resolves hostnames itself the stores don't use the resolver at all; behind
+//! a plain `socks5` one its queries go through the proxy too and never fall back to the system
+//! resolver, see `DohResolver::through_proxy`.
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use futures::future::join;
+use log::trace;
+use reqwest::{
+    dns::{Addrs, Name, Resolve, Resolving},
+    header::ACCEPT,
+    Url,
+};
+use serde::{Deserialize, Serialize};
+
+use super::{read_body, ProxyConfig};
+use crate::network;
+
+const DNS_JSON: &str = "application/dns-json";
+
+// DNS record types asked for.
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+
+// Bounds how long an answer is trusted, whatever TTL the resolver reports.
+const MAX_TTL: Duration = Duration::from_secs(60 * 60);
+
+// Largest answer read, far more than a few addresses take.
+const MAX_ANSWER_BYTES: usize = 64 * 1024;
+
+// Hostnames cached at most; the stores only ever talk to a handful of gateways.
+const MAX_CACHED_NAMES: usize = 256;
+
+/// Where and how to resolve gateway hostnames, kept in `HelperConfig::dns_over_https` and
+/// applied with `GatewayStore::with_dns_resolver` or `PointerResolver::with_dns_resolver`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DohConfig {
+    /// A DoH endpoint answering JSON queries, e.g. `https://cloudflare-dns.com/dns-query`.
+    pub resolver_url: String,
+    /// Addresses of the resolver's host, e.g. `1.1.1.1`. Without them the host itself is looked
+    /// up with the system resolver.
+    #[serde(default)]
+    pub bootstrap_addresses: Vec<IpAddr>,
+    /// Resolve with the system resolver when the DoH query fails.
+    #[serde(default)]
+    pub system_fallback: bool,
+}
+
+#[derive(Deserialize)]
+struct DnsAnswer {
+    #[serde(rename = "type")]
+    record_type: u16,
+    #[serde(rename = "TTL", default)]
+    ttl: u64,
+    data: String,
+}
+
+#[derive(Deserialize)]
+struct DnsResponse {
+    #[serde(rename = "Status")]
+    status: u32,
+    #[serde(rename = "Answer", default)]
+    answer: Vec<DnsAnswer>,
+}
+
+// Addresses of a JSON DoH response, with the shortest TTL among them. CNAME records are
+// skipped: resolvers follow them and include the final records in the same answer.
+pub(super) fn addresses_of(body: &[u8]) -> Result<(Vec<IpAddr>, Duration)> {
+    let response: DnsResponse = serde_json::from_slice(body)?;
+    if response.status != 0 {
+        return Err(anyhow!("DNS query failed with status {}", response.status));
+    }
+    let mut ttl = MAX_TTL;
+    let mut addresses = Vec::new();
+    for answer in response.answer {
+        if answer.record_type != TYPE_A && answer.record_type != TYPE_AAAA {
+            continue;
+        }
+        if let Ok(address) = answer.data.parse::<IpAddr>() {
+            ttl = ttl.min(Duration::from_secs(answer.ttl));
+            addresses.push(address);
+        }
+    }
+    Ok((addresses, ttl))
+}
+
+struct Resolver {
+    config: DohConfig,
+    client: reqwest::Client,
+    proxied: bool,
+    cache: Mutex<HashMap<String, (Vec<IpAddr>, Instant)>>,
+}
+
+/// Resolves hostnames over DoH as configured by a [`DohConfig`]. Cheap to clone; clones share
+/// their cache.
+#[derive(Clone)]
+pub struct DohResolver {
+    inner: Arc<Resolver>,
+}
+
+impl DohResolver {
+    /// Validates `config` and builds the client that talks to the resolver.
+    pub fn new(config: &DohConfig, timeout: Duration) -> Result<Self> {
+        Self::build(config, timeout, None)
+    }
+
+    /// Like `new`, sending the DoH queries through `proxy`, so the resolver only sees the
+    /// proxy's address. `system_fallback` is ignored, as the system resolver isn't proxied.
+    pub fn through_proxy(
+        config: &DohConfig,
+        timeout: Duration,
+        proxy: &ProxyConfig,
+    ) -> Result<Self> {
+        Self::build(config, timeout, Some(proxy))
+    }
+
+    /// The config the resolver was built from.
+    pub fn config(&self) -> &DohConfig {
+        &self.inner.config
+    }
+
+    fn build(config: &DohConfig, timeout: Duration, proxy: Option<&ProxyConfig>) -> Result<Self> {
+        let url = Url::parse(&config.resolver_url)
+            .map_err(|e| anyhow!("invalid DoH resolver {}: {}", config.resolver_url, e))?;
+        if url.scheme() != "https" && url.scheme() != "http" {
+            return Err(anyhow!("DoH resolver {} is not HTTP", config.resolver_url));
+        }
+        let host = url
+            .host_str()
+            .ok_or_else(|| anyhow!("DoH resolver {} has no host", config.resolver_url))?;
+        let mut builder = network::client_builder(timeout);
+        if !config.bootstrap_addresses.is_empty() {
+            let pinned: Vec<SocketAddr> = config
+                .bootstrap_addresses
+                .iter()
+                .map(|address| SocketAddr::new(*address, 0))
+                .collect();
+            builder = builder.resolve_to_addrs(host, &pinned);
+        }
+        if let Some(proxy) = proxy {
+            builder = builder.proxy(proxy.proxy()?);
+        }
+        Ok(Self {
+            inner: Arc::new(Resolver {
+                config: config.to_owned(),
+                client: builder.build()?,
+                proxied: proxy.is_some(),
+                cache: Mutex::new(HashMap::new()),
+            }),
+        })
+    }
+
+    /// Addresses of `host`, from the cache while its answer is fresh.
+    pub async fn lookup(&self, host: &str) -> Result<Vec<IpAddr>> {
+        if let Ok(address) = host.parse::<IpAddr>() {
+            return Ok(vec![address]);
+        }
+        let key = host.to_ascii_lowercase();
+        if let Some(addresses) = self.cached(&key) {
+            return Ok(addresses);
+        }
+        match self.query(&key).await {
+            Ok(addresses) => Ok(addresses),
+            Err(e) if self.inner.config.system_fallback && !self.inner.proxied => {
+                trace!("wnfsutils: DoH lookup of {} failed: {}", key, e);
+                let addresses = tokio::net::lookup_host((key.as_str(), 0)).await?;
+                Ok(addresses.map(|address| address.ip()).collect())
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn query(&self, host: &str) -> Result<Vec<IpAddr>> {
+        let (v4, v6) = join(
+            self.query_type(host, TYPE_A),
+            self.query_type(host, TYPE_AAAA),
+        )
+        .await;
+        // A host with only one family is normal; fail only when neither answered.
+        let (mut addresses, mut ttl) = match (v4, v6) {
+            (Err(e), Err(_)) => return Err(e),
+            (Ok(answer), Err(_)) | (Err(_), Ok(answer)) => answer,
+            (Ok((mut v4, v4_ttl)), Ok((v6, v6_ttl))) => {
+                v4.extend(v6);
+                (v4, v4_ttl.min(v6_ttl))
+            }
+        };
+        addresses.dedup();
+        if addresses.is_empty() {
+            return Err(anyhow!("no addresses found for {}", host));
+        }
+        ttl = ttl.min(MAX_TTL);
+        let mut cache = self.lock_cache();
+        if cache.len() >= MAX_CACHED_NAMES {
+            let now = Instant::now();
+            cache.retain(|_, (_, expires)| *expires > now);
+        }
+        if cache.len() < MAX_CACHED_NAMES {
+            cache.insert(
+                host.to_string(),
+                (addresses.to_owned(), Instant::now() + ttl),
+            );
+        }
+        Ok(addresses)
+    }
+
+    async fn query_type(&self, host: &str, record_type: u16) -> Result<(Vec<IpAddr>, Duration)> {
+        let record_type = record_type.to_string();
+        let response = self
+            .inner
+            .client
+            .get(&self.inner.config.resolver_url)
+            .query(&[("name", host), ("type", record_type.as_str())])
+            .header(ACCEPT, DNS_JSON)
+            .send()
+            .await?
+            .error_for_status()?;
+        addresses_of(&read_body(response, MAX_ANSWER_BYTES).await?)
+    }
+
+    fn cached(&self, host: &str) -> Option<Vec<IpAddr>> {
+        match self.lock_cache().get(host) {
+            Some((addresses, expires)) if *expires > Instant::now() => Some(addresses.to_owned()),
+            _ => None,
+        }
+    }
+
+    fn lock_cache(&self) -> std::sync::MutexGuard<'_, HashMap<String, (Vec<IpAddr>, Instant)>> {
+        match self.inner.cache.lock() {
+            Ok(cache) => cache,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+}
+
+impl Resolve for DohResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let resolver = self.clone();
+        Box::pin(async move {
+            let addresses = resolver.lookup(name.as_str()).await?;
+            // The port is taken from the request URL.
+            let addrs: Addrs = Box::new(
+                addresses
+                    .into_iter()
+                    .map(|address| SocketAddr::new(address, 0)),
+            );
+            Ok(addrs)
+        })
+    }
+}
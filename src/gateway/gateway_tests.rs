@@ -0,0 +1,646 @@
+use std::time::{Duration, Instant};
+
+use crate::gateway::{fresh_until, CacheDirectives, CachedResponse, HttpCache};
+
+#[test]
+fn parses_cache_control_directives() {
+    let directives = CacheDirectives::parse("public, max-age=29030400, immutable");
+    assert_eq!(directives.max_age, Some(Duration::from_secs(29030400)));
+    assert!(directives.immutable);
+    assert!(!directives.no_cache);
+
+    let directives = CacheDirectives::parse("No-Cache, MAX-AGE=\"60\"");
+    assert!(directives.no_cache);
+    assert_eq!(directives.max_age, Some(Duration::from_secs(60)));
+
+    assert!(CacheDirectives::parse("no-store").no_store);
+    assert_eq!(CacheDirectives::parse("max-age=soon").max_age, None);
+}
+
+#[test]
+fn freshness_follows_the_directives() {
+    let now = Instant::now();
+    let max_age = CacheDirectives::parse("max-age=60");
+    assert_eq!(
+        fresh_until(&max_age, now),
+        Some(now + Duration::from_secs(60))
+    );
+    // `no-cache` wins: the entry is kept only for revalidation.
+    let no_cache = CacheDirectives::parse("max-age=60, no-cache");
+    assert_eq!(fresh_until(&no_cache, now), None);
+    assert!(fresh_until(&CacheDirectives::parse("immutable"), now).unwrap() > now);
+    assert_eq!(fresh_until(&CacheDirectives::default(), now), None);
+}
+
+#[test]
+fn cache_keeps_within_its_bound() {
+    let entry = |size: usize| CachedResponse {
+        body: vec![0; size],
+        etag: Some("\"v1\"".into()),
+        fresh_until: None,
+    };
+    let mut cache = HttpCache::new(10);
+    cache.insert("a".into(), entry(6));
+    cache.insert("b".into(), entry(6));
+    assert!(cache.entries.contains_key("a"));
+    assert!(!cache.entries.contains_key("b"));
+    // Replacing an entry releases its previous size.
+    cache.insert("a".into(), entry(4));
+    cache.insert("b".into(), entry(6));
+    assert_eq!(cache.cached_bytes, 10);
+}
+
+#[test]
+fn blocks_are_verified_against_their_cid() {
+    use libipld::{
+        multihash::{Code, MultihashDigest},
+        Cid,
+    };
+
+    use crate::gateway::verify_block;
+
+    let data = b"encrypted block".to_vec();
+    let cid = Cid::new_v1(0x55, Code::Sha2_256.digest(&data));
+    assert!(verify_block(&cid, &data).is_ok());
+    assert!(verify_block(&cid, b"tampered block").is_err());
+}
+
+#[test]
+fn gateway_urls_are_normalized() {
+    use crate::gateway::GatewayUrl;
+
+    let path = |origin: &str| GatewayUrl::Path {
+        origin: origin.to_string(),
+    };
+    assert_eq!(
+        GatewayUrl::parse("ipfs.io").unwrap(),
+        path("https://ipfs.io")
+    );
+    assert_eq!(
+        GatewayUrl::parse(" https://IPFS.io/ipfs/ ").unwrap(),
+        path("https://ipfs.io")
+    );
+    assert_eq!(
+        GatewayUrl::parse("https://cloud.example/gateway/ipfs/bafkqaaa/a.txt?filename=a#x")
+            .unwrap(),
+        path("https://cloud.example/gateway")
+    );
+    assert_eq!(
+        GatewayUrl::parse("http://127.0.0.1:8080").unwrap(),
+        path("http://127.0.0.1:8080")
+    );
+    assert_eq!(
+        GatewayUrl::parse("https://bafkqaaa.ipfs.dweb.link/").unwrap(),
+        GatewayUrl::Subdomain {
+            scheme: "https".into(),
+            host: "dweb.link".into(),
+        }
+    );
+
+    assert!(GatewayUrl::parse("").is_err());
+    assert!(GatewayUrl::parse("ftp://ipfs.io").is_err());
+    assert!(GatewayUrl::parse("https://not-a-cid.ipfs.dweb.link").is_err());
+}
+
+#[test]
+fn content_urls_follow_the_gateway_style() {
+    use libipld::Cid;
+
+    use crate::gateway::GatewayUrl;
+
+    let cid = Cid::try_from("bafkqaaa").unwrap();
+    let path = GatewayUrl::parse("https://ipfs.io").unwrap();
+    assert_eq!(
+        path.content_url(&cid, Some("dag-scope=all")),
+        "https://ipfs.io/ipfs/bafkqaaa?dag-scope=all"
+    );
+    let subdomain = GatewayUrl::parse("https://bafkqaaa.ipfs.dweb.link").unwrap();
+    assert_eq!(
+        subdomain.content_url(&cid, None),
+        "https://bafkqaaa.ipfs.dweb.link/"
+    );
+}
+
+#[test]
+fn selection_switches_only_past_the_margin() {
+    use crate::gateway::{GatewaySelection, GatewaySelector};
+
+    let ms = Duration::from_millis;
+    let mut selector = GatewaySelector::new(
+        3,
+        GatewaySelection {
+            smoothing: 1.0,
+            switch_margin: 0.2,
+            ..Default::default()
+        },
+    );
+    assert!(selector.probe_due(Instant::now()));
+
+    selector.record(0, Some(ms(100)));
+    selector.record(1, Some(ms(90)));
+    selector.record(2, None);
+    selector.select();
+    // 90ms isn't 20% better than 100ms, so reads stay where they are.
+    assert_eq!(selector.current, 0);
+    assert_eq!(selector.order(), vec![0, 1, 2]);
+
+    selector.record(1, Some(ms(50)));
+    selector.select();
+    assert_eq!(selector.current, 1);
+    assert_eq!(selector.order(), vec![1, 0, 2]);
+
+    // An unavailable current gateway is left right away.
+    selector.record(1, None);
+    selector.record(2, Some(ms(70)));
+    selector.select();
+    assert_eq!(selector.current, 2);
+}
+
+#[test]
+fn latency_is_smoothed() {
+    use crate::gateway::{GatewaySelection, GatewaySelector};
+
+    let mut selector = GatewaySelector::new(
+        1,
+        GatewaySelection {
+            smoothing: 0.5,
+            ..Default::default()
+        },
+    );
+    selector.record(0, Some(Duration::from_millis(100)));
+    selector.record(0, Some(Duration::from_millis(200)));
+    assert_eq!(selector.health[0].latency, Some(Duration::from_millis(150)));
+}
+
+#[test]
+fn hedge_delay_tracks_the_latency_percentile() {
+    use crate::gateway::{HedgingPolicy, LatencyWindow};
+
+    let policy = HedgingPolicy {
+        percentile: 0.9,
+        min_samples: 10,
+        initial_delay: Duration::from_millis(250),
+    };
+    let mut window = LatencyWindow::default();
+    for ms in 1..=9 {
+        window.record(Duration::from_millis(ms * 10));
+    }
+    assert_eq!(window.hedge_delay(&policy), Duration::from_millis(250));
+
+    window.record(Duration::from_millis(1000));
+    // The 90th percentile of 10..90ms plus one 1s outlier.
+    assert_eq!(window.hedge_delay(&policy), Duration::from_millis(90));
+
+    for _ in 0..200 {
+        window.record(Duration::from_millis(5));
+    }
+    assert_eq!(window.samples.len(), 128);
+    assert_eq!(window.hedge_delay(&policy), Duration::from_millis(5));
+}
+
+#[test]
+fn provider_hints_pass_on_to_linked_blocks() {
+    use libipld::{
+        cbor::DagCborCodec,
+        codec::Codec,
+        multihash::{Code, MultihashDigest},
+        Cid, Ipld,
+    };
+
+    use crate::gateway::{GatewayUrl, ProviderHints};
+
+    let cid = |data: &[u8]| Cid::new_v1(0x55, Code::Sha2_256.digest(data));
+    let (root, child, other) = (cid(b"root"), cid(b"child"), cid(b"other"));
+    let sharer = GatewayUrl::parse("https://sharer.example").unwrap();
+    let peer = GatewayUrl::parse("https://peer.example").unwrap();
+
+    let mut hints = ProviderHints::default();
+    hints.add(root, sharer.to_owned());
+    hints.add(root, sharer.to_owned());
+    hints.add(child, peer.to_owned());
+    assert_eq!(hints.for_cid(&root), vec![sharer.to_owned()]);
+    assert_eq!(hints.providers.len(), 2);
+
+    let node = DagCborCodec
+        .encode(&Ipld::List(vec![Ipld::Link(child), Ipld::Link(other)]))
+        .unwrap();
+    let mut links = Vec::new();
+    DagCborCodec
+        .decode::<Ipld>(&node)
+        .unwrap()
+        .references(&mut links);
+    hints.inherit(&root, links);
+    assert_eq!(hints.for_cid(&child), vec![peer, sharer.to_owned()]);
+    assert_eq!(hints.for_cid(&other), vec![sharer]);
+    assert!(hints.for_cid(&cid(b"unrelated")).is_empty());
+}
+
+#[test]
+fn provider_hints_are_validated() {
+    use libipld::{
+        multihash::{Code, MultihashDigest},
+        Cid,
+    };
+
+    use crate::gateway::GatewayStore;
+
+    let store = GatewayStore::new("https://ipfs.io", Duration::from_secs(5), 1024).unwrap();
+    let root = Cid::new_v1(0x55, Code::Sha2_256.digest(b"root"));
+    assert!(store
+        .add_provider_hint(&root, "https://sharer.example")
+        .is_ok());
+    assert!(store
+        .add_provider_hint(&root, "ftp://sharer.example")
+        .is_err());
+}
+
+#[test]
+fn doh_answers_are_parsed() {
+    use std::net::IpAddr;
+
+    use crate::gateway::doh::addresses_of;
+
+    let body = br#"{"Status":0,"Answer":[
+        {"name":"dweb.link","type":5,"TTL":30,"data":"gateway.example."},
+        {"name":"gateway.example","type":1,"TTL":300,"data":"203.0.113.7"},
+        {"name":"gateway.example","type":28,"TTL":120,"data":"2001:db8::7"}
+    ]}"#;
+    let (addresses, ttl) = addresses_of(body).unwrap();
+    assert_eq!(
+        addresses,
+        vec![
+            "203.0.113.7".parse::<IpAddr>().unwrap(),
+            "2001:db8::7".parse::<IpAddr>().unwrap()
+        ]
+    );
+    // The CNAME's TTL doesn't count, only the addresses'.
+    assert_eq!(ttl, Duration::from_secs(120));
+
+    // NXDOMAIN.
+    assert!(addresses_of(br#"{"Status":3}"#).is_err());
+}
+
+#[test]
+fn onion_gateways_need_a_resolving_proxy() {
+    use crate::gateway::{GatewayStore, GatewayUrl, PointerResolver, ProxyConfig, Revalidation};
+
+    let onion = "http://2gzyxa5ihm7nsggfxnu52rck2vv4rvmdlkiu3zzui5du4xyclen53wid.onion";
+    assert!(GatewayUrl::parse(onion).unwrap().is_onion());
+    assert!(!GatewayUrl::parse("https://ipfs.io").unwrap().is_onion());
+
+    let store = || GatewayStore::new(onion, Duration::from_secs(5), 1024).unwrap();
+    let local = ProxyConfig {
+        url: "socks5://127.0.0.1:9050".to_string(),
+    };
+    assert!(store().with_proxy(&local).is_err());
+    assert!(store().with_proxy(&ProxyConfig::tor()).is_ok());
+    let ftp = ProxyConfig {
+        url: "ftp://127.0.0.1:21".to_string(),
+    };
+    assert!(store().with_proxy(&ftp).is_err());
+
+    // Pointers are checked when resolved, as the resolver doesn't know them up front.
+    let pointer = format!("{}/ipns/root", onion);
+    let resolver = || PointerResolver::new(Duration::from_secs(5), Revalidation::Always).unwrap();
+    let err = resolver().resolve(&pointer).unwrap_err();
+    assert!(err.to_string().contains("onion service"));
+    let err = resolver()
+        .with_proxy(&local)
+        .unwrap()
+        .resolve(&pointer)
+        .unwrap_err();
+    assert!(err.to_string().contains("onion service"));
+}
+
+// Serves `body` for every request on a local port, counting the connections it accepts.
+fn counting_gateway(body: Vec<u8>) -> (String, std::sync::Arc<std::sync::atomic::AtomicUsize>) {
+    responding_gateway(move |_| ("200 OK", body.to_owned()))
+}
+
+// Answers each request with the status line, optionally followed by header lines, and the body
+// `respond` picks from its head, counting the connections it accepts.
+fn responding_gateway(
+    respond: impl Fn(&str) -> (&'static str, Vec<u8>) + Send + 'static,
+) -> (String, std::sync::Arc<std::sync::atomic::AtomicUsize>) {
+    use std::{
+        io::{Read, Write},
+        net::TcpListener,
+        sync::{atomic::AtomicUsize, atomic::Ordering, Arc},
+    };
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let connections = Arc::new(AtomicUsize::new(0));
+    let counter = Arc::clone(&connections);
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(mut stream) = stream else { continue };
+            counter.fetch_add(1, Ordering::SeqCst);
+            let mut head = Vec::new();
+            let mut buffer = [0u8; 1024];
+            while !head.windows(4).any(|window| window == b"\r\n\r\n") {
+                match stream.read(&mut buffer) {
+                    Ok(0) | Err(_) => break,
+                    Ok(read) => head.extend_from_slice(&buffer[..read]),
+                }
+            }
+            let (status, body) = respond(&String::from_utf8_lossy(&head));
+            let header = format!(
+                "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                status,
+                body.len()
+            );
+            let _ = stream.write_all(header.as_bytes());
+            let _ = stream.write_all(&body);
+        }
+    });
+    (url, connections)
+}
+
+#[test]
+fn configured_hosts_only_ignores_foreign_provider_hints() {
+    use std::sync::atomic::Ordering;
+
+    use libipld::{
+        multihash::{Code, MultihashDigest},
+        Cid,
+    };
+
+    use crate::blockstore::FFIStore;
+    use crate::gateway::GatewayStore;
+    use crate::network::{configured_hosts_only, scoped_configured_hosts_only};
+
+    let data = b"shared block".to_vec();
+    let cid = Cid::new_v1(0x55, Code::Sha2_256.digest(&data));
+    let (configured, configured_connections) = counting_gateway(data.to_owned());
+    let (hinted, hinted_connections) = counting_gateway(data.to_owned());
+
+    let store = scoped_configured_hosts_only(true, || {
+        assert!(configured_hosts_only());
+        GatewayStore::new(&configured, Duration::from_secs(5), 1024).unwrap()
+    });
+    store.add_provider_hint(&cid, &hinted).unwrap();
+    assert_eq!(store.get_block(cid.to_bytes()).unwrap(), data);
+    assert_eq!(hinted_connections.load(Ordering::SeqCst), 0);
+    assert!(configured_connections.load(Ordering::SeqCst) > 0);
+
+    if cfg!(feature = "no-net-telemetry") {
+        assert!(crate::network::set_configured_hosts_only(false).is_err());
+        return;
+    }
+    let store = scoped_configured_hosts_only(false, || {
+        GatewayStore::new(&configured, Duration::from_secs(5), 1024).unwrap()
+    });
+    store.add_provider_hint(&cid, &hinted).unwrap();
+    assert_eq!(store.get_block(cid.to_bytes()).unwrap(), data);
+    assert_eq!(hinted_connections.load(Ordering::SeqCst), 1);
+}
+
+// Every store and resolver, with hostnames resolved over DoH so that no connection is opened
+// outside the audited clients.
+#[test]
+fn stores_and_resolvers_contact_only_configured_hosts() {
+    use std::sync::atomic::Ordering;
+
+    use libipld::{
+        multihash::{Code, MultihashDigest},
+        Cid,
+    };
+
+    use crate::blockstore::{
+        FFIStore, HttpGatewayConfig, HttpGatewayStore, S3BlockStore, S3Config, S3Credentials,
+    };
+    use crate::gateway::{DohConfig, GatewayStore, PointerResolver, Revalidation};
+    use crate::network::{audit_connections, scoped_configured_hosts_only};
+
+    let data = b"audited block".to_vec();
+    let cid = Cid::new_v1(0x55, Code::Sha2_256.digest(&data));
+    let (doh, _) = counting_gateway(
+        br#"{"Status":0,"Answer":[{"type":1,"TTL":60,"data":"127.0.0.1"}]}"#.to_vec(),
+    );
+    let (gateway, _) = counting_gateway(data.to_owned());
+    let (hinted, hinted_connections) = counting_gateway(data.to_owned());
+    let (http, _) = counting_gateway(data.to_owned());
+    let (s3, _) = counting_gateway(data.to_owned());
+    let (pointer, _) = counting_gateway(cid.to_string().into_bytes());
+    let port = |url: &str| url.rsplit(':').next().unwrap().to_string();
+    // Only the DoH resolver knows these names.
+    let named = |name: &str, url: &str| format!("http://{}.invalid:{}", name, port(url));
+    let doh_config = DohConfig {
+        resolver_url: format!("{}/dns-query", doh),
+        bootstrap_addresses: Vec::new(),
+        system_fallback: false,
+    };
+    let timeout = Duration::from_secs(5);
+
+    let ((gateway_store, http_store, s3_store, resolver), audit) = audit_connections(|| {
+        scoped_configured_hosts_only(true, || {
+            let gateway_store = GatewayStore::new(&named("gateway", &gateway), timeout, 1024)
+                .unwrap()
+                .with_dns_resolver(&doh_config)
+                .unwrap();
+            let http_store = HttpGatewayStore::new(HttpGatewayConfig::read_only(&format!(
+                "{}/ipfs/{{cid}}",
+                named("http", &http)
+            )))
+            .unwrap()
+            .with_dns_resolver(&doh_config)
+            .unwrap();
+            let s3_store = S3BlockStore::new(S3Config::new(
+                &named("s3", &s3),
+                "us-east-1",
+                "blocks",
+                S3Credentials::new("access", "secret"),
+            ))
+            .unwrap()
+            .with_dns_resolver(&doh_config)
+            .unwrap();
+            let resolver = PointerResolver::new(timeout, Revalidation::Always)
+                .unwrap()
+                .with_dns_resolver(&doh_config)
+                .unwrap();
+            (gateway_store, http_store, s3_store, resolver)
+        })
+    });
+    gateway_store.add_provider_hint(&cid, &hinted).unwrap();
+    assert_eq!(gateway_store.get_block(cid.to_bytes()).unwrap(), data);
+    assert_eq!(http_store.get_block(cid.to_bytes()).unwrap(), data);
+    // Whatever the fake bucket's answer makes of it, the request went to the bucket.
+    let _ = s3_store.get_block(cid.to_bytes());
+    let pointer_url = format!("{}/ipns/root", named("pointer", &pointer));
+    assert_eq!(resolver.resolve(&pointer_url).unwrap(), cid);
+
+    assert_eq!(hinted_connections.load(Ordering::SeqCst), 0);
+    let mut expected = vec![
+        format!("127.0.0.1:{}", port(&doh)),
+        format!("gateway.invalid:{}", port(&gateway)),
+        format!("http.invalid:{}", port(&http)),
+        format!("pointer.invalid:{}", port(&pointer)),
+        format!("s3.invalid:{}", port(&s3)),
+    ];
+    expected.sort();
+    assert_eq!(audit.hosts(), expected);
+}
+
+#[test]
+fn bodies_past_the_block_size_limit_are_refused() {
+    use libipld::{
+        multihash::{Code, MultihashDigest},
+        Cid,
+    };
+
+    use crate::blockstore::FFIStore;
+    use crate::error::WnfsUtilsError;
+    use crate::gateway::GatewayStore;
+
+    let data = vec![7u8; 4096];
+    let cid = Cid::new_v1(0x55, Code::Sha2_256.digest(&data));
+    let (url, _) = counting_gateway(data.to_owned());
+
+    let store = GatewayStore::new(&url, Duration::from_secs(5), 0).unwrap();
+    store.set_max_block_size(1024);
+    let err = store.get_block(cid.to_bytes()).unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<WnfsUtilsError>(),
+        Some(WnfsUtilsError::BlockTooLarge { .. })
+    ));
+
+    store.set_max_block_size(data.len());
+    assert_eq!(store.get_block(cid.to_bytes()).unwrap(), data);
+}
+
+#[test]
+fn prefetches_past_the_limit_are_refused() {
+    use libipld::{
+        multihash::{Code, MultihashDigest},
+        Cid,
+    };
+
+    use crate::blockstore::FFIStore;
+    use crate::error::WnfsUtilsError;
+    use crate::gateway::GatewayStore;
+
+    let root = Cid::new_v1(0x71, Code::Sha2_256.digest(b"root"));
+    let (url, _) = counting_gateway(vec![0u8; 64 * 1024]);
+
+    let store = GatewayStore::new(&url, Duration::from_secs(5), 1024)
+        .unwrap()
+        .with_prefetch_limit(16 * 1024);
+    let err = store.prefetch_subtree(&root).unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<WnfsUtilsError>(),
+        Some(WnfsUtilsError::BlockTooLarge { limit, .. }) if *limit == 16 * 1024
+    ));
+    assert_eq!(store.cache_stats().unwrap().cached_blocks, 0);
+}
+
+#[test]
+fn decompressed_bodies_are_bounded() {
+    use libipld::{
+        multihash::{Code, MultihashDigest},
+        Cid,
+    };
+
+    use crate::blockstore::FFIStore;
+    use crate::error::WnfsUtilsError;
+    use crate::gateway::GatewayStore;
+
+    // 96 bytes of gzip holding 64 KiB of zeros.
+    let mut bomb = vec![
+        0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x03, 0xed, 0xc1, 0x01, 0x01, 0x00,
+        0x00, 0x00, 0x80, 0x90, 0xfe, 0xaf, 0xee, 0x08, 0x0a,
+    ];
+    bomb.resize(bomb.len() + 63, 0);
+    bomb.extend_from_slice(&[0x6a, 0xeb, 0x8e, 0x97, 0xd7, 0x00, 0x00, 0x01, 0x00]);
+    let cid = Cid::new_v1(0x55, Code::Sha2_256.digest(&vec![0u8; 64 * 1024]));
+    let (url, _) =
+        responding_gateway(move |_| ("200 OK\r\nContent-Encoding: gzip", bomb.to_owned()));
+
+    let store = GatewayStore::new(&url, Duration::from_secs(5), 0)
+        .unwrap()
+        .with_compression(true)
+        .unwrap();
+    store.set_max_block_size(16 * 1024);
+    let err = store.get_block(cid.to_bytes()).unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<WnfsUtilsError>(),
+        Some(WnfsUtilsError::BlockTooLarge { limit, .. }) if *limit == 16 * 1024
+    ));
+
+    // Under the limit, the decoded block is what gets verified.
+    store.set_max_block_size(64 * 1024);
+    assert_eq!(
+        store.get_block(cid.to_bytes()).unwrap(),
+        vec![0u8; 64 * 1024]
+    );
+}
+
+#[test]
+fn verified_blocks_are_served_from_the_block_cache() {
+    use std::sync::atomic::Ordering;
+
+    use libipld::{
+        multihash::{Code, MultihashDigest},
+        Cid,
+    };
+
+    use crate::blockstore::FFIStore;
+    use crate::gateway::GatewayStore;
+
+    let data = b"cached block".to_vec();
+    let cid = Cid::new_v1(0x55, Code::Sha2_256.digest(&data));
+    let (url, connections) = counting_gateway(data.to_owned());
+
+    let store = GatewayStore::new(&url, Duration::from_secs(5), 1024).unwrap();
+    assert_eq!(store.get_block(cid.to_bytes()).unwrap(), data);
+    let fetched = connections.load(Ordering::SeqCst);
+    assert_eq!(store.clone().get_block(cid.to_bytes()).unwrap(), data);
+    assert_eq!(connections.load(Ordering::SeqCst), fetched);
+    let stats = store.cache_stats().unwrap();
+    assert_eq!((stats.hits, stats.misses), (1, 1));
+    assert_eq!(stats.cached_bytes, data.len());
+
+    // A block that doesn't match its CID is neither served nor cached.
+    let other = Cid::new_v1(0x55, Code::Sha2_256.digest(b"other block"));
+    assert!(store.get_block(other.to_bytes()).is_err());
+    assert_eq!(store.cache_stats().unwrap().cached_blocks, 1);
+}
+
+#[test]
+fn missing_blocks_leave_the_gateway_available() {
+    use libipld::{
+        multihash::{Code, MultihashDigest},
+        Cid,
+    };
+
+    use crate::blockstore::FFIStore;
+    use crate::error::ErrorCode;
+    use crate::gateway::{lock_selector, GatewayStore, PROBE_CID};
+
+    let data = b"published block".to_vec();
+    let cid = Cid::new_v1(0x55, Code::Sha2_256.digest(&data));
+    // Answers probes, and has none of the blocks asked for.
+    let (missing, _) = responding_gateway(|head| match head.contains(PROBE_CID) {
+        true => ("200 OK", Vec::new()),
+        false => ("404 Not Found", Vec::new()),
+    });
+    let (serving, _) = counting_gateway(data.to_owned());
+
+    let store = GatewayStore::new(&missing, Duration::from_secs(5), 1024).unwrap();
+    let err = store.get_block(cid.to_bytes()).unwrap_err();
+    assert_eq!(ErrorCode::of(&err), ErrorCode::NotFound);
+    assert!(lock_selector(&store.selector).health[0].available);
+
+    let store = GatewayStore::with_gateways(
+        &[&missing, &serving],
+        Duration::from_secs(5),
+        1024,
+        Default::default(),
+    )
+    .unwrap();
+    assert_eq!(store.get_block(cid.to_bytes()).unwrap(), data);
+    assert!(lock_selector(&store.selector)
+        .health
+        .iter()
+        .all(|health| health.available));
+}
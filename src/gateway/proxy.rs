@@ -0,0 +1,46 @@
+//! Proxies for the remote stores, including a local Tor daemon for `.onion` gateways.
+//!
+//! With a `socks5h` or HTTP proxy hostnames are resolved by the proxy, so neither the system
+//! resolver nor a DoH resolver sees which gateways are used. A plain `socks5` proxy resolves
+//! locally and can't reach `.onion` hosts at all.
+
+use anyhow::{anyhow, Result};
+use reqwest::{Proxy, Url};
+use serde::{Deserialize, Serialize};
+
+/// The proxy all block traffic goes through, kept in `HelperConfig::proxy` and applied with
+/// `GatewayStore::with_proxy` or `PointerResolver::with_proxy`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProxyConfig {
+    /// e.g. `socks5h://127.0.0.1:9050` for Tor, or an `http://` proxy. Credentials go in the
+    /// URL; Tor keeps circuits of different SOCKS credentials apart.
+    pub url: String,
+}
+
+impl ProxyConfig {
+    /// A local Tor daemon on its default SOCKS port.
+    pub fn tor() -> Self {
+        Self {
+            url: "socks5h://127.0.0.1:9050".to_string(),
+        }
+    }
+
+    pub(super) fn proxy(&self) -> Result<Proxy> {
+        self.url()?;
+        Ok(Proxy::all(&self.url)?)
+    }
+
+    /// Whether hostnames are resolved by the proxy rather than locally.
+    pub fn resolves_remotely(&self) -> Result<bool> {
+        Ok(self.url()?.scheme() != "socks5")
+    }
+
+    fn url(&self) -> Result<Url> {
+        let url =
+            Url::parse(&self.url).map_err(|e| anyhow!("invalid proxy {}: {}", self.url, e))?;
+        match url.scheme() {
+            "http" | "https" | "socks5" | "socks5h" => Ok(url),
+            scheme => Err(anyhow!("unsupported proxy scheme {}", scheme)),
+        }
+    }
+}
@@ -0,0 +1,318 @@
+//! Mark and sweep garbage collection of a block store that writers keep committing to.
+//!
+//! A block that isn't reachable from a live root is only unused if no writer is about to link
+//! it. Two rules keep such blocks alive:
+//!
+//! - blocks written within the grace period are never collected, and
+//! - every writer registered with the [`GcCoordinator`] holds an epoch, the time since which its
+//!   work may not be published yet; blocks written after the oldest epoch are never collected.
+//!
+//! A writer advances its epoch whenever it publishes a root (for the helper:
+//! `PrivateDirectoryHelper::mark_published`), since everything it wrote before is then reachable
+//! from a root the collector treats as live. The coordinator is in-process; writers in other
+//! processes are only covered by the grace period.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use libipld::{cbor::DagCborCodec, codec::Codec, Cid, Ipld};
+use log::trace;
+use rand::{seq::SliceRandom, thread_rng};
+use web_time::{SystemTime, UNIX_EPOCH};
+use wnfs::common::BlockStore;
+
+use crate::{blockstore::FFIFriendlyBlockStore, car::reachable_blocks, error::describe};
+
+#[derive(Debug, Clone)]
+pub struct GcPolicy {
+    /// Blocks written more recently than this are kept even when unreachable.
+    pub grace_period: Duration,
+    /// Only report what would be collected.
+    pub dry_run: bool,
+}
+
+impl Default for GcPolicy {
+    fn default() -> Self {
+        Self {
+            grace_period: Duration::from_secs(10 * 60),
+            dry_run: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GcReport {
+    pub scanned: usize,
+    pub reachable: usize,
+    /// Unreachable blocks kept by the grace period, a writer epoch or an unknown write time.
+    pub protected: usize,
+    pub deleted: usize,
+    pub reclaimed_bytes: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct WriterId(u64);
+
+/// Registry of writer epochs shared by the writers and the collector of a store.
+#[derive(Debug, Default)]
+pub struct GcCoordinator {
+    next_id: AtomicU64,
+    epochs: Mutex<HashMap<WriterId, u64>>,
+}
+
+impl GcCoordinator {
+    /// Registers a writer with an epoch starting now.
+    pub fn register_writer(&self) -> WriterId {
+        let id = WriterId(self.next_id.fetch_add(1, Ordering::Relaxed));
+        self.with_epochs(|epochs| epochs.insert(id, now_millis()));
+        id
+    }
+
+    /// Moves the writer's epoch to now, once everything it wrote so far is published.
+    pub fn advance_epoch(&self, id: WriterId) {
+        self.with_epochs(|epochs| {
+            if let Some(epoch) = epochs.get_mut(&id) {
+                *epoch = now_millis();
+            }
+        });
+    }
+
+    pub fn unregister_writer(&self, id: WriterId) {
+        self.with_epochs(|epochs| epochs.remove(&id));
+    }
+
+    /// The oldest epoch of all registered writers, in milliseconds since the Unix epoch.
+    pub fn oldest_epoch(&self) -> Option<u64> {
+        self.with_epochs(|epochs| epochs.values().min().copied())
+    }
+
+    fn with_epochs<T>(&self, f: impl FnOnce(&mut HashMap<WriterId, u64>) -> T) -> T {
+        let mut guard = match self.epochs.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        f(&mut guard)
+    }
+}
+
+/// Deletes the blocks of `store` that are unreachable from `live_roots` and not protected by
+/// the grace period of `policy` or a writer epoch of `coordinator`.
+pub async fn collect_garbage(
+    store: &FFIFriendlyBlockStore<'_>,
+    live_roots: &[Cid],
+    coordinator: Option<&GcCoordinator>,
+    policy: &GcPolicy,
+) -> Result<GcReport, String> {
+    // The cutoff is fixed before marking: blocks written while the collector runs are newer.
+    let cutoff = protection_cutoff(coordinator, policy);
+    let reachable = mark(store, live_roots).await?;
+
+    let mut report = GcReport::default();
+    for cid in store.list_blocks().map_err(|e| describe(&e))? {
+        report.scanned += 1;
+        if reachable.contains(&cid) {
+            report.reachable += 1;
+            continue;
+        }
+        match store.block_written_at(&cid).map_err(|e| describe(&e))? {
+            Some(written_at) if written_at < cutoff => {}
+            _ => {
+                report.protected += 1;
+                continue;
+            }
+        }
+        let size = store
+            .get_block(&cid)
+            .await
+            .map(|bytes| bytes.len())
+            .unwrap_or(0);
+        if !policy.dry_run {
+            store.delete_block(&cid).map_err(|e| {
+                trace!("wnfsError in collect_garbage: {:?}", e.to_string());
+                describe(&e)
+            })?;
+        }
+        report.deleted += 1;
+        report.reclaimed_bytes += size as u64;
+    }
+    Ok(report)
+}
+
+/// The blocks reachable from a set of live roots, marked once by `mark_reachable` and kept,
+/// e.g. persisted with `to_bytes` after a collection, so `estimate_garbage` doesn't walk the
+/// roots again.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReachableSet {
+    blocks: HashSet<Cid>,
+    // When the roots were walked, in milliseconds since the Unix epoch.
+    marked_at: u64,
+}
+
+impl ReachableSet {
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    pub fn contains(&self, cid: &Cid) -> bool {
+        self.blocks.contains(cid)
+    }
+
+    /// When the live roots were walked, in milliseconds since the Unix epoch.
+    pub fn marked_at(&self) -> u64 {
+        self.marked_at
+    }
+
+    /// The set as DAG-CBOR, for `from_bytes`.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, String> {
+        let blocks = self.blocks.iter().copied().map(Ipld::Link).collect();
+        let ipld = Ipld::Map(BTreeMap::from([
+            ("blocks".to_string(), Ipld::List(blocks)),
+            (
+                "marked_at".to_string(),
+                Ipld::Integer(self.marked_at as i128),
+            ),
+        ]));
+        DagCborCodec.encode(&ipld).map_err(|e| describe(&e))
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        let invalid = || "wnfsError invalid reachable set".to_string();
+        let ipld: Ipld = DagCborCodec.decode(bytes).map_err(|e| {
+            trace!("wnfsError in ReachableSet::from_bytes: {:?}", e.to_string());
+            describe(&e)
+        })?;
+        let (Some(Ipld::List(blocks)), Some(Ipld::Integer(marked_at))) =
+            (ipld.get("blocks").ok(), ipld.get("marked_at").ok())
+        else {
+            return Err(invalid());
+        };
+        let blocks = blocks
+            .iter()
+            .map(|block| match block {
+                Ipld::Link(cid) => Ok(*cid),
+                _ => Err(invalid()),
+            })
+            .collect::<Result<_, String>>()?;
+        Ok(Self {
+            blocks,
+            marked_at: u64::try_from(*marked_at).map_err(|_| invalid())?,
+        })
+    }
+}
+
+/// Walks `live_roots` and returns the blocks reachable from them, see `ReachableSet`.
+pub async fn mark_reachable(
+    store: &FFIFriendlyBlockStore<'_>,
+    live_roots: &[Cid],
+) -> Result<ReachableSet, String> {
+    let marked_at = now_millis();
+    Ok(ReachableSet {
+        blocks: mark(store, live_roots).await?,
+        marked_at,
+    })
+}
+
+/// Approximate outcome of `collect_garbage`, see `estimate_garbage`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct GarbageEstimate {
+    pub scanned: usize,
+    /// Blocks outside the reachable set, counted exactly. Blocks written since it was marked
+    /// are among them.
+    pub unreachable: usize,
+    /// Unreachable blocks whose size and write time were read.
+    pub sampled: usize,
+    /// Unreachable blocks `collect_garbage` would delete, extrapolated from the sample.
+    pub estimated_deletable: usize,
+    pub estimated_reclaimable_bytes: u64,
+}
+
+/// Estimates what `collect_garbage` with the roots of `reachable` would reclaim, without
+/// walking them again: the listing is checked against the set, and the size and write time of
+/// at most `sample_size` unreachable blocks picked at random are read instead of all of them.
+/// Blocks written since the set was marked may be linked from a later root, so they count as
+/// protected. Nothing is deleted.
+pub async fn estimate_garbage(
+    store: &FFIFriendlyBlockStore<'_>,
+    reachable: &ReachableSet,
+    coordinator: Option<&GcCoordinator>,
+    policy: &GcPolicy,
+    sample_size: usize,
+) -> Result<GarbageEstimate, String> {
+    let cutoff = protection_cutoff(coordinator, policy).min(reachable.marked_at);
+    let listed = store.list_blocks().map_err(|e| describe(&e))?;
+    let unreachable: Vec<Cid> = listed
+        .iter()
+        .filter(|cid| !reachable.contains(cid))
+        .copied()
+        .collect();
+    let mut deletable = 0;
+    let mut deletable_bytes = 0;
+    let sample: Vec<&Cid> = unreachable
+        .choose_multiple(&mut thread_rng(), sample_size)
+        .collect();
+    for cid in sample.iter() {
+        match store.block_written_at(cid).map_err(|e| describe(&e))? {
+            Some(written_at) if written_at < cutoff => {}
+            _ => continue,
+        }
+        deletable += 1;
+        deletable_bytes += store
+            .get_block(cid)
+            .await
+            .map(|bytes| bytes.len() as u64)
+            .unwrap_or(0);
+    }
+
+    let mut estimate = GarbageEstimate {
+        scanned: listed.len(),
+        unreachable: unreachable.len(),
+        sampled: sample.len(),
+        ..Default::default()
+    };
+    if !sample.is_empty() {
+        let scale = unreachable.len() as f64 / sample.len() as f64;
+        estimate.estimated_deletable = (deletable as f64 * scale).round() as usize;
+        estimate.estimated_reclaimable_bytes = (deletable_bytes as f64 * scale).round() as u64;
+    }
+    Ok(estimate)
+}
+
+// Blocks written before this time, in milliseconds since the Unix epoch, may be collected.
+fn protection_cutoff(coordinator: Option<&GcCoordinator>, policy: &GcPolicy) -> u64 {
+    let cutoff = now_millis().saturating_sub(policy.grace_period.as_millis() as u64);
+    match coordinator.and_then(|coordinator| coordinator.oldest_epoch()) {
+        Some(oldest_epoch) => cutoff.min(oldest_epoch),
+        None => cutoff,
+    }
+}
+
+async fn mark(
+    store: &FFIFriendlyBlockStore<'_>,
+    live_roots: &[Cid],
+) -> Result<HashSet<Cid>, String> {
+    let mut reachable: HashSet<Cid> = HashSet::new();
+    for root in live_roots {
+        reachable.extend(reachable_blocks(store, root).await?);
+    }
+    Ok(reachable)
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod gc_tests;
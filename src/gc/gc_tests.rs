@@ -0,0 +1,181 @@
+use std::time::Duration;
+
+use libipld::{cbor::DagCborCodec, codec::Codec, Ipld, IpldCodec};
+use wnfs::common::{BlockStore, CODEC_DAG_CBOR};
+
+use crate::{
+    blockstore::FFIFriendlyBlockStore,
+    gc::{
+        collect_garbage, estimate_garbage, mark_reachable, GcCoordinator, GcPolicy, ReachableSet,
+    },
+    kvstore::KVBlockStore,
+};
+
+fn gc_store(dir: &tempfile::TempDir) -> FFIFriendlyBlockStore<'static> {
+    let path = dir.path().join("store").to_string_lossy().to_string();
+    let store = KVBlockStore::new(path, CODEC_DAG_CBOR);
+    FFIFriendlyBlockStore::new(Box::new(store))
+}
+
+fn no_grace() -> GcPolicy {
+    GcPolicy {
+        grace_period: Duration::ZERO,
+        dry_run: false,
+    }
+}
+
+#[tokio::test]
+async fn deletes_only_unreachable_blocks() {
+    let dir = tempfile::tempdir().unwrap();
+    let blockstore = gc_store(&dir);
+    let leaf = blockstore
+        .put_block(b"leaf".to_vec(), IpldCodec::Raw.into())
+        .await
+        .unwrap();
+    let garbage = blockstore
+        .put_block(b"garbage".to_vec(), IpldCodec::Raw.into())
+        .await
+        .unwrap();
+    let node = DagCborCodec
+        .encode(&Ipld::List(vec![Ipld::Link(leaf)]))
+        .unwrap();
+    let root = blockstore
+        .put_block(node, IpldCodec::DagCbor.into())
+        .await
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(5)).await;
+
+    let dry_run = GcPolicy {
+        dry_run: true,
+        ..no_grace()
+    };
+    let report = collect_garbage(&blockstore, &[root], None, &dry_run)
+        .await
+        .unwrap();
+    assert_eq!(report.deleted, 1);
+    assert!(blockstore.get_block(&garbage).await.is_ok());
+
+    let report = collect_garbage(&blockstore, &[root], None, &no_grace())
+        .await
+        .unwrap();
+    assert_eq!(report.scanned, 3);
+    assert_eq!(report.reachable, 2);
+    assert_eq!(report.deleted, 1);
+    assert_eq!(report.reclaimed_bytes, b"garbage".len() as u64);
+    assert!(blockstore.get_block(&garbage).await.is_err());
+    assert!(blockstore.get_block(&leaf).await.is_ok());
+}
+
+#[tokio::test]
+async fn keeps_blocks_within_the_grace_period() {
+    let dir = tempfile::tempdir().unwrap();
+    let blockstore = gc_store(&dir);
+    let fresh = blockstore
+        .put_block(b"fresh".to_vec(), IpldCodec::Raw.into())
+        .await
+        .unwrap();
+
+    let report = collect_garbage(&blockstore, &[], None, &GcPolicy::default())
+        .await
+        .unwrap();
+    assert_eq!(report.protected, 1);
+    assert_eq!(report.deleted, 0);
+    assert!(blockstore.get_block(&fresh).await.is_ok());
+}
+
+#[tokio::test]
+async fn keeps_blocks_written_since_the_oldest_writer_epoch() {
+    let dir = tempfile::tempdir().unwrap();
+    let blockstore = gc_store(&dir);
+    let coordinator = GcCoordinator::default();
+    let writer = coordinator.register_writer();
+    tokio::time::sleep(Duration::from_millis(5)).await;
+    let pending = blockstore
+        .put_block(b"pending".to_vec(), IpldCodec::Raw.into())
+        .await
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(5)).await;
+
+    let report = collect_garbage(&blockstore, &[], Some(&coordinator), &no_grace())
+        .await
+        .unwrap();
+    assert_eq!(report.protected, 1);
+    assert!(blockstore.get_block(&pending).await.is_ok());
+
+    // Once the writer publishes, its earlier blocks are no longer protected.
+    coordinator.advance_epoch(writer);
+    tokio::time::sleep(Duration::from_millis(5)).await;
+    let report = collect_garbage(&blockstore, &[], Some(&coordinator), &no_grace())
+        .await
+        .unwrap();
+    assert_eq!(report.deleted, 1);
+
+    coordinator.unregister_writer(writer);
+    assert_eq!(coordinator.oldest_epoch(), None);
+}
+
+#[tokio::test]
+async fn estimates_garbage_from_a_sample() {
+    let dir = tempfile::tempdir().unwrap();
+    let blockstore = gc_store(&dir);
+    let leaf = blockstore
+        .put_block(b"leaf".to_vec(), IpldCodec::Raw.into())
+        .await
+        .unwrap();
+    let root = blockstore
+        .put_block(
+            DagCborCodec
+                .encode(&Ipld::List(vec![Ipld::Link(leaf)]))
+                .unwrap(),
+            IpldCodec::DagCbor.into(),
+        )
+        .await
+        .unwrap();
+    for i in 0..20u8 {
+        blockstore
+            .put_block(vec![i; 100], IpldCodec::Raw.into())
+            .await
+            .unwrap();
+    }
+    tokio::time::sleep(Duration::from_millis(5)).await;
+
+    let reachable = mark_reachable(&blockstore, &[root]).await.unwrap();
+    assert_eq!(reachable.len(), 2);
+    let reachable = ReachableSet::from_bytes(&reachable.to_bytes().unwrap()).unwrap();
+    assert_eq!(reachable.len(), 2);
+
+    let estimate = estimate_garbage(&blockstore, &reachable, None, &no_grace(), 5)
+        .await
+        .unwrap();
+    assert_eq!(estimate.scanned, 22);
+    assert_eq!(estimate.unreachable, 20);
+    assert_eq!(estimate.sampled, 5);
+    assert_eq!(estimate.estimated_deletable, 20);
+    assert_eq!(estimate.estimated_reclaimable_bytes, 2000);
+
+    // A block written after the set was marked may be linked from a later root.
+    tokio::time::sleep(Duration::from_millis(5)).await;
+    let newer = blockstore
+        .put_block(b"newer".to_vec(), IpldCodec::Raw.into())
+        .await
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(5)).await;
+    let estimate = estimate_garbage(&blockstore, &reachable, None, &no_grace(), 50)
+        .await
+        .unwrap();
+    assert_eq!(estimate.unreachable, 21);
+    assert_eq!(estimate.sampled, 21);
+    assert_eq!(estimate.estimated_deletable, 20);
+    assert_eq!(estimate.estimated_reclaimable_bytes, 2000);
+
+    // Blocks in the grace period aren't counted, and nothing is deleted.
+    let estimate = estimate_garbage(&blockstore, &reachable, None, &GcPolicy::default(), 50)
+        .await
+        .unwrap();
+    assert_eq!(estimate.sampled, 21);
+    assert_eq!(estimate.estimated_reclaimable_bytes, 0);
+    let report = collect_garbage(&blockstore, &[root, newer], None, &no_grace())
+        .await
+        .unwrap();
+    assert_eq!(report.deleted, 20);
+}
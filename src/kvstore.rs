@@ -1,16 +1,28 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
 use kv::*;
 
 use anyhow::Result;
-use libipld::Cid;
-
 use wnfs::common::BlockStoreError;
 
-use crate::blockstore::FFIStore;
+use crate::blockstore::{cid_from_bytes, FFIStore};
+use crate::error::WnfsUtilsError;
+use crate::metrics::{IoMetrics, IoMetricsSnapshot};
+
+// Write times of the blocks in the default bucket, for garbage collection grace periods.
+const WRITTEN_AT_BUCKET: &str = "written_at";
 
 #[derive(Clone)]
 pub struct KVBlockStore {
     pub store: Store,
     pub codec: u64,
+    db_path: PathBuf,
+    io: Arc<IoMetrics>,
 }
 
 //--------------------------------------------------------------------------------------------------
@@ -19,13 +31,31 @@ pub struct KVBlockStore {
 
 impl KVBlockStore {
     /// Creates a new kv block store.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the database can't be opened. Use `try_new` where that must not abort.
     pub fn new(db_path: String, codec: u64) -> Self {
+        Self::try_new(db_path, codec).expect("Unable to open the kv store")
+    }
+
+    /// Creates a new kv block store, failing if the database can't be opened.
+    pub fn try_new(db_path: String, codec: u64) -> Result<Self, WnfsUtilsError> {
         // Configure the database
         // Open the key/value store
-        Self {
-            store: Store::new(Config::new(db_path)).unwrap(),
+        let store = Store::new(Config::new(&db_path))
+            .map_err(|e| WnfsUtilsError::StoreOpen(e.to_string()))?;
+        Ok(Self {
+            store,
             codec,
-        }
+            db_path: PathBuf::from(db_path),
+            io: Arc::new(IoMetrics::default()),
+        })
+    }
+
+    /// Bytes taken by the database files.
+    pub fn size_on_disk(&self) -> Result<u64> {
+        dir_size(&self.db_path)
     }
 }
 
@@ -35,10 +65,12 @@ impl<'a> FFIStore<'a> for KVBlockStore {
         // A Bucket provides typed access to a section of the key/value store
         let bucket = self.store.bucket::<Raw, Raw>(Some("default"))?;
 
+        // Malformed CIDs from FFI are rejected before touching the database.
+        let parsed_cid = cid_from_bytes(&cid)?;
         let bytes = bucket
-            .get(&Raw::from(cid.to_owned()))
-            .map_err(|_| BlockStoreError::CIDNotFound(Cid::try_from(cid).unwrap()))?
-            .unwrap()
+            .get(&Raw::from(cid))
+            .map_err(|_| BlockStoreError::CIDNotFound(parsed_cid))?
+            .ok_or(BlockStoreError::CIDNotFound(parsed_cid))?
             .to_vec();
         Ok(bytes)
     }
@@ -46,12 +78,92 @@ impl<'a> FFIStore<'a> for KVBlockStore {
     /// Stores an array of bytes in the block store.
     fn put_block(&self, cid: Vec<u8>, bytes: Vec<u8>) -> Result<()> {
         let key = Raw::from(cid.to_owned());
+        let logical = bytes.len();
         let value = Raw::from(bytes);
 
         // A Bucket provides typed access to a section of the key/value store
         let bucket = self.store.bucket::<Raw, Raw>(Some("default"))?;
 
         bucket.set(&key, &value)?;
+        self.store
+            .bucket::<Raw, Raw>(Some(WRITTEN_AT_BUCKET))?
+            .set(&key, &Raw::from(now_millis().to_be_bytes().to_vec()))?;
+        // What is handed to the database; its own log and page overhead isn't visible here.
+        self.io.record_write(logical, 2 * key.len() + logical + 8);
         Ok(())
     }
+
+    fn list_blocks(&self) -> Result<Vec<Vec<u8>>> {
+        let bucket = self.store.bucket::<Raw, Raw>(Some("default"))?;
+        let mut cids = Vec::new();
+        for item in bucket.iter() {
+            let key: Raw = item?.key()?;
+            cids.push(key.to_vec());
+        }
+        Ok(cids)
+    }
+
+    fn delete_block(&self, cid: Vec<u8>) -> Result<()> {
+        let key = Raw::from(cid);
+        self.store
+            .bucket::<Raw, Raw>(Some("default"))?
+            .remove(&key)?;
+        self.store
+            .bucket::<Raw, Raw>(Some(WRITTEN_AT_BUCKET))?
+            .remove(&key)?;
+        Ok(())
+    }
+
+    fn block_written_at(&self, cid: Vec<u8>) -> Result<Option<u64>> {
+        let written_at = self
+            .store
+            .bucket::<Raw, Raw>(Some(WRITTEN_AT_BUCKET))?
+            .get(&Raw::from(cid))?;
+        Ok(written_at
+            .and_then(|millis| <[u8; 8]>::try_from(millis.as_ref()).ok())
+            .map(u64::from_be_bytes))
+    }
+
+    /// The database is log structured: rewriting every live entry moves it into new, densely
+    /// packed segments, and the segments left holding only deleted data are then freed.
+    fn compact(&self) -> Result<u64> {
+        let size_before = self.size_on_disk()?;
+        for name in ["default", WRITTEN_AT_BUCKET] {
+            let bucket = self.store.bucket::<Raw, Raw>(Some(name))?;
+            for item in bucket.iter() {
+                let item = item?;
+                let key: Raw = item.key()?;
+                let value: Raw = item.value()?;
+                bucket.set(&key, &value)?;
+                self.io.record_write(0, key.len() + value.len());
+            }
+            bucket.flush()?;
+            self.io.record_fsync();
+        }
+        Ok(size_before.saturating_sub(self.size_on_disk()?))
+    }
+
+    fn io_metrics(&self) -> Option<IoMetricsSnapshot> {
+        Some(self.io.snapshot("kv"))
+    }
+}
+
+fn dir_size(path: &Path) -> Result<u64> {
+    let mut size = 0;
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        size += match metadata.is_dir() {
+            true => dir_size(&entry.path())?,
+            false => metadata.len(),
+        };
+    }
+    Ok(size)
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as u64)
+        .unwrap_or_default()
 }
@@ -1,3 +1,35 @@
+#![cfg_attr(not(test), deny(clippy::unwrap_used))]
+
 pub mod blockstore;
+pub mod car;
+pub mod daemon;
+pub mod encrypted_store;
+pub mod error;
+pub mod error_sink;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+#[cfg(feature = "reqwest")]
+pub mod gateway;
+pub mod gc;
+#[cfg(not(target_arch = "wasm32"))]
 pub mod kvstore;
+pub mod media_metadata;
+pub mod metrics;
+pub mod migrate;
+pub mod mnemonic;
+pub mod network;
+pub mod packstore;
 pub mod private_forest;
+pub mod progress;
+#[cfg(all(windows, feature = "projfs"))]
+pub mod projfs;
+pub mod public_forest;
+pub mod request_id;
+#[cfg(feature = "sftp")]
+pub mod sftp;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+mod timer;
+pub mod vfs;
+#[cfg(target_arch = "wasm32")]
+pub mod wasm;
@@ -0,0 +1,165 @@
+//! Extraction of common media metadata (EXIF for images, ID3 for audio) into entries stored in
+//! WNFS node metadata, so gallery and search views don't need to re-read file content.
+
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, Seek, SeekFrom},
+    path::Path,
+};
+
+use chrono::{NaiveDate, NaiveDateTime};
+use id3::TagLike;
+use libipld::Ipld;
+use serde::{Deserialize, Serialize};
+
+/// Capture time of an image, formatted as `YYYY-MM-DDTHH:MM:SS`.
+pub const CAPTURE_TIME_KEY: &str = "exif:DateTimeOriginal";
+/// GPS latitude in signed decimal degrees.
+pub const GPS_LATITUDE_KEY: &str = "exif:GPSLatitude";
+/// GPS longitude in signed decimal degrees.
+pub const GPS_LONGITUDE_KEY: &str = "exif:GPSLongitude";
+pub const ARTIST_KEY: &str = "id3:TPE1";
+pub const TITLE_KEY: &str = "id3:TIT2";
+pub const ALBUM_KEY: &str = "id3:TALB";
+/// Track duration in milliseconds.
+pub const DURATION_KEY: &str = "id3:TLEN";
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MediaMetadataOptions {
+    /// Also record GPS coordinates. Off by default since location is sensitive.
+    pub include_gps: bool,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MediaMetadata {
+    pub capture_time: Option<NaiveDateTime>,
+    pub gps: Option<(f64, f64)>,
+    pub duration_ms: Option<u64>,
+    pub artist: Option<String>,
+    pub title: Option<String>,
+    pub album: Option<String>,
+}
+
+impl MediaMetadata {
+    /// Parses whatever EXIF or ID3 metadata can be found in `reader`. Content that isn't a
+    /// recognized media container yields an empty result rather than an error.
+    pub fn extract<R: BufRead + Seek>(reader: &mut R, options: &MediaMetadataOptions) -> Self {
+        let mut metadata = Self::default();
+        if let Ok(exif) = exif::Reader::new().read_from_container(reader) {
+            metadata.capture_time = exif_capture_time(&exif);
+            if options.include_gps {
+                metadata.gps = exif_gps(&exif);
+            }
+        }
+        if reader.seek(SeekFrom::Start(0)).is_ok() {
+            if let Ok(tag) = id3::Tag::read_from2(&mut *reader) {
+                metadata.artist = tag.artist().map(str::to_string);
+                metadata.title = tag.title().map(str::to_string);
+                metadata.album = tag.album().map(str::to_string);
+                metadata.duration_ms = tag
+                    .get("TLEN")
+                    .and_then(|frame| frame.content().text())
+                    .and_then(|text| text.trim().parse::<u64>().ok());
+            }
+        }
+        metadata
+    }
+
+    /// Same as [`MediaMetadata::extract`] for a file on the local filesystem.
+    pub fn from_path(path: &Path, options: &MediaMetadataOptions) -> Self {
+        match File::open(path) {
+            Ok(file) => Self::extract(&mut BufReader::new(file), options),
+            Err(_) => Self::default(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Converts the parsed values into metadata entries keyed by the `*_KEY` constants.
+    pub fn to_entries(&self) -> Vec<(String, Ipld)> {
+        let mut entries = Vec::new();
+        if let Some(time) = self.capture_time {
+            entries.push((
+                CAPTURE_TIME_KEY.to_string(),
+                Ipld::String(time.format("%Y-%m-%dT%H:%M:%S").to_string()),
+            ));
+        }
+        if let Some((latitude, longitude)) = self.gps {
+            entries.push((GPS_LATITUDE_KEY.to_string(), Ipld::Float(latitude)));
+            entries.push((GPS_LONGITUDE_KEY.to_string(), Ipld::Float(longitude)));
+        }
+        if let Some(duration) = self.duration_ms {
+            entries.push((DURATION_KEY.to_string(), Ipld::Integer(duration as i128)));
+        }
+        for (key, value) in [
+            (ARTIST_KEY, &self.artist),
+            (TITLE_KEY, &self.title),
+            (ALBUM_KEY, &self.album),
+        ] {
+            if let Some(value) = value {
+                entries.push((key.to_string(), Ipld::String(value.to_owned())));
+            }
+        }
+        entries
+    }
+}
+
+fn exif_capture_time(exif: &exif::Exif) -> Option<NaiveDateTime> {
+    let field = exif.get_field(exif::Tag::DateTimeOriginal, exif::In::PRIMARY)?;
+    match field.value {
+        exif::Value::Ascii(ref values) => {
+            let time = exif::DateTime::from_ascii(values.first()?).ok()?;
+            NaiveDate::from_ymd_opt(time.year as i32, time.month as u32, time.day as u32)?
+                .and_hms_opt(time.hour as u32, time.minute as u32, time.second as u32)
+        }
+        _ => None,
+    }
+}
+
+fn exif_gps(exif: &exif::Exif) -> Option<(f64, f64)> {
+    let latitude = exif_coordinate(
+        exif,
+        exif::Tag::GPSLatitude,
+        exif::Tag::GPSLatitudeRef,
+        b'S',
+    )?;
+    let longitude = exif_coordinate(
+        exif,
+        exif::Tag::GPSLongitude,
+        exif::Tag::GPSLongitudeRef,
+        b'W',
+    )?;
+    Some((latitude, longitude))
+}
+
+// Converts a degrees/minutes/seconds rational triple into signed decimal degrees.
+fn exif_coordinate(
+    exif: &exif::Exif,
+    tag: exif::Tag,
+    reference_tag: exif::Tag,
+    negative_reference: u8,
+) -> Option<f64> {
+    let field = exif.get_field(tag, exif::In::PRIMARY)?;
+    let degrees = match field.value {
+        exif::Value::Rational(ref parts) if parts.len() == 3 => {
+            parts[0].to_f64() + parts[1].to_f64() / 60.0 + parts[2].to_f64() / 3600.0
+        }
+        _ => return None,
+    };
+    let negative = match exif.get_field(reference_tag, exif::In::PRIMARY) {
+        Some(field) => match field.value {
+            exif::Value::Ascii(ref values) => values
+                .first()
+                .map(|value| value.first() == Some(&negative_reference))
+                .unwrap_or(false),
+            _ => false,
+        },
+        None => false,
+    };
+    Some(if negative { -degrees } else { degrees })
+}
+
+#[cfg(test)]
+mod media_metadata_tests;
@@ -0,0 +1,33 @@
+use std::io::Cursor;
+
+use chrono::NaiveDate;
+use libipld::Ipld;
+
+use crate::media_metadata::{
+    MediaMetadata, MediaMetadataOptions, ARTIST_KEY, CAPTURE_TIME_KEY, GPS_LATITUDE_KEY,
+};
+
+#[test]
+fn unrecognized_content_yields_no_metadata() {
+    let mut content = Cursor::new(b"plain text, no media headers".to_vec());
+    let metadata = MediaMetadata::extract(&mut content, &MediaMetadataOptions::default());
+    assert!(metadata.is_empty());
+    assert!(metadata.to_entries().is_empty());
+}
+
+#[test]
+fn entries_use_stable_keys() {
+    let metadata = MediaMetadata {
+        capture_time: NaiveDate::from_ymd_opt(2023, 7, 14).and_then(|d| d.and_hms_opt(9, 5, 0)),
+        gps: Some((52.5, -1.25)),
+        artist: Some("Someone".to_string()),
+        ..Default::default()
+    };
+    let entries = metadata.to_entries();
+    assert!(entries.contains(&(
+        CAPTURE_TIME_KEY.to_string(),
+        Ipld::String("2023-07-14T09:05:00".to_string())
+    )));
+    assert!(entries.contains(&(GPS_LATITUDE_KEY.to_string(), Ipld::Float(52.5))));
+    assert!(entries.contains(&(ARTIST_KEY.to_string(), Ipld::String("Someone".to_string()))));
+}
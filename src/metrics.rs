@@ -0,0 +1,530 @@
+//! Store and forest counters, and their rendering in the Prometheus text exposition format.
+//!
+//! Besides the counters, each helper records the time its operations take in its
+//! [`OperationMetrics`], for the `synced_*` calls and the main async entry points alike. An
+//! operation run by another timed one, e.g. the `write_file` of an `ingest`, counts towards the
+//! outer one only. An app can install a [`MetricsHook`] to receive every block transfer, cache
+//! lookup and operation as it happens, e.g. to feed its own telemetry; without one, recording
+//! takes no lock.
+
+use std::{
+    cell::Cell,
+    collections::BTreeMap,
+    fmt::Write,
+    future::Future,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use once_cell::sync::Lazy;
+use web_time::Instant;
+
+/// Counters shared by every clone of a `FFIFriendlyBlockStore`.
+#[derive(Debug, Default)]
+pub struct StoreMetrics {
+    blocks_read: AtomicU64,
+    blocks_written: AtomicU64,
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+    read_errors: AtomicU64,
+    write_errors: AtomicU64,
+    cache_hits: AtomicU64,
+    cache_misses: AtomicU64,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StoreMetricsSnapshot {
+    pub blocks_read: u64,
+    pub blocks_written: u64,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    pub read_errors: u64,
+    pub write_errors: u64,
+    pub cache_hits: u64,
+    pub cache_misses: u64,
+}
+
+/// What a storage backend puts on disk for the payload it is given, kept by the backend itself
+/// and reported through `FFIStore::io_metrics`, to compare storage formats on real devices.
+#[derive(Debug, Default)]
+pub struct IoMetrics {
+    logical_bytes_written: AtomicU64,
+    physical_bytes_written: AtomicU64,
+    fsyncs: AtomicU64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IoMetricsSnapshot {
+    /// Name of the backend, e.g. `kv` or `pack`.
+    pub backend: String,
+    /// Block payload handed to the backend.
+    pub logical_bytes_written: u64,
+    /// Bytes the backend wrote for it, including headers, indexes and compaction copies.
+    pub physical_bytes_written: u64,
+    pub fsyncs: u64,
+}
+
+/// Commit bookkeeping of a `PrivateDirectoryHelper`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ForestMetricsSnapshot {
+    pub commits: u64,
+    pub last_commit: Option<DateTime<Utc>>,
+    /// Last time the app reported a root as published to its pointer service.
+    pub last_published: Option<DateTime<Utc>>,
+}
+
+/// Calls and time spent in one helper operation, e.g. `"write_file"`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OperationStats {
+    pub calls: u64,
+    pub errors: u64,
+    pub total_time: Duration,
+    pub max_time: Duration,
+}
+
+/// Everything a helper and its store counted, see `PrivateDirectoryHelper::stats`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct HelperStats {
+    pub store: StoreMetricsSnapshot,
+    pub forest: ForestMetricsSnapshot,
+    pub io: Option<IoMetricsSnapshot>,
+    /// By operation name, for the helper and the views sharing its metrics.
+    pub operations: BTreeMap<&'static str, OperationStats>,
+}
+
+/// Receives metrics as they are recorded. Calls are made on the thread doing the work, so they
+/// should return quickly. Every method does nothing by default.
+pub trait MetricsHook: Send + Sync {
+    fn block_read(&self, _bytes: usize) {}
+
+    fn block_written(&self, _bytes: usize) {}
+
+    fn cache_lookup(&self, _hit: bool) {}
+
+    /// A timed operation finished after `elapsed`, see [`OperationMetrics`]. Operations run
+    /// without a helper, such as `synced_init`, are only reported here.
+    fn operation(&self, _operation: &'static str, _elapsed: Duration, _succeeded: bool) {}
+}
+
+/// Calls and time spent by operation name. Clones share the figures; a helper's views share
+/// the helper's, see `PrivateDirectoryHelper::stats`.
+#[derive(Debug, Clone, Default)]
+pub struct OperationMetrics {
+    operations: Arc<Mutex<BTreeMap<&'static str, OperationStats>>>,
+}
+
+static GLOBAL_HOOK: Lazy<Mutex<Option<Arc<dyn MetricsHook>>>> = Lazy::new(|| Mutex::new(None));
+
+// Whether `GLOBAL_HOOK` holds a hook, so the hot paths skip its lock when none is installed.
+static HOOK_INSTALLED: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+    // Timed operations being polled or run on this thread, see `OperationMetrics::timed`.
+    static TIMING: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Installs the process-wide hook, replacing any installed before.
+pub fn install_hook(hook: Arc<dyn MetricsHook>) {
+    if let Ok(mut installed) = GLOBAL_HOOK.lock() {
+        *installed = Some(hook);
+        HOOK_INSTALLED.store(true, Ordering::Release);
+    }
+}
+
+/// Removes the installed hook.
+pub fn uninstall_hook() {
+    if let Ok(mut installed) = GLOBAL_HOOK.lock() {
+        *installed = None;
+        HOOK_INSTALLED.store(false, Ordering::Release);
+    }
+}
+
+// Calls the installed hook outside the lock, so a hook may install another.
+pub(crate) fn with_hook(call: impl FnOnce(&dyn MetricsHook)) {
+    if !HOOK_INSTALLED.load(Ordering::Acquire) {
+        return;
+    }
+    let hook = GLOBAL_HOOK
+        .lock()
+        .ok()
+        .and_then(|installed| installed.as_ref().map(Arc::clone));
+    if let Some(hook) = hook {
+        call(hook.as_ref());
+    }
+}
+
+// Counts a timed operation running on this thread while alive.
+struct TimingGuard;
+
+impl TimingGuard {
+    fn enter() -> Self {
+        TIMING.with(|timing| timing.set(timing.get() + 1));
+        Self
+    }
+
+    fn nested() -> bool {
+        TIMING.with(|timing| timing.get() > 0)
+    }
+}
+
+impl Drop for TimingGuard {
+    fn drop(&mut self) {
+        TIMING.with(|timing| timing.set(timing.get() - 1));
+    }
+}
+
+impl OperationMetrics {
+    /// Records a finished operation and reports it to the installed hook.
+    pub fn record(&self, operation: &'static str, elapsed: Duration, succeeded: bool) {
+        if let Ok(mut operations) = self.operations.lock() {
+            let stats = operations.entry(operation).or_default();
+            stats.calls += 1;
+            stats.errors += u64::from(!succeeded);
+            stats.total_time += elapsed;
+            stats.max_time = stats.max_time.max(elapsed);
+        }
+        with_hook(|hook| hook.operation(operation, elapsed, succeeded));
+    }
+
+    /// The operations recorded so far, by name.
+    pub fn snapshot(&self) -> BTreeMap<&'static str, OperationStats> {
+        match self.operations.lock() {
+            Ok(operations) => operations.to_owned(),
+            Err(poisoned) => poisoned.into_inner().to_owned(),
+        }
+    }
+
+    /// Runs `future`, recording it as `operation` unless it's polled by another timed operation,
+    /// which then accounts for its time.
+    pub async fn timed<T, E>(
+        &self,
+        operation: &'static str,
+        future: impl Future<Output = Result<T, E>>,
+    ) -> Result<T, E> {
+        let nested = TimingGuard::nested();
+        let started = Instant::now();
+        futures::pin_mut!(future);
+        let result = futures::future::poll_fn(|cx| {
+            let _timing = TimingGuard::enter();
+            future.as_mut().poll(cx)
+        })
+        .await;
+        if !nested {
+            self.record(operation, started.elapsed(), result.is_ok());
+        }
+        result
+    }
+
+    // Runs `operation` to completion on this thread, as the `synced_*` calls do, recording it
+    // from `started` into `metrics`, or only reporting it to the hook without them.
+    pub(crate) fn time_blocking<T, E>(
+        metrics: Option<&Self>,
+        operation: &'static str,
+        started: Instant,
+        run: impl FnOnce() -> Result<T, E>,
+    ) -> Result<T, E> {
+        let result = {
+            let _timing = TimingGuard::enter();
+            run()
+        };
+        let elapsed = started.elapsed();
+        match metrics {
+            Some(metrics) => metrics.record(operation, elapsed, result.is_ok()),
+            None => with_hook(|hook| hook.operation(operation, elapsed, result.is_ok())),
+        }
+        result
+    }
+}
+
+impl StoreMetrics {
+    pub fn record_read(&self, bytes: usize) {
+        self.blocks_read.fetch_add(1, Ordering::Relaxed);
+        self.bytes_read.fetch_add(bytes as u64, Ordering::Relaxed);
+        with_hook(|hook| hook.block_read(bytes));
+    }
+
+    pub fn record_write(&self, bytes: usize) {
+        self.blocks_written.fetch_add(1, Ordering::Relaxed);
+        self.bytes_written
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+        with_hook(|hook| hook.block_written(bytes));
+    }
+
+    pub fn record_read_error(&self) {
+        self.read_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_write_error(&self) {
+        self.write_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records a lookup in a caching layer placed in front of the store.
+    pub fn record_cache(&self, hit: bool) {
+        if hit {
+            self.cache_hits.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.cache_misses.fetch_add(1, Ordering::Relaxed);
+        }
+        with_hook(|hook| hook.cache_lookup(hit));
+    }
+
+    pub fn snapshot(&self) -> StoreMetricsSnapshot {
+        StoreMetricsSnapshot {
+            blocks_read: self.blocks_read.load(Ordering::Relaxed),
+            blocks_written: self.blocks_written.load(Ordering::Relaxed),
+            bytes_read: self.bytes_read.load(Ordering::Relaxed),
+            bytes_written: self.bytes_written.load(Ordering::Relaxed),
+            read_errors: self.read_errors.load(Ordering::Relaxed),
+            write_errors: self.write_errors.load(Ordering::Relaxed),
+            cache_hits: self.cache_hits.load(Ordering::Relaxed),
+            cache_misses: self.cache_misses.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl IoMetrics {
+    /// Records a write of `logical` payload bytes that took `physical` bytes on disk.
+    pub fn record_write(&self, logical: usize, physical: usize) {
+        self.logical_bytes_written
+            .fetch_add(logical as u64, Ordering::Relaxed);
+        self.physical_bytes_written
+            .fetch_add(physical as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_fsync(&self) {
+        self.fsyncs.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self, backend: &str) -> IoMetricsSnapshot {
+        IoMetricsSnapshot {
+            backend: backend.to_string(),
+            logical_bytes_written: self.logical_bytes_written.load(Ordering::Relaxed),
+            physical_bytes_written: self.physical_bytes_written.load(Ordering::Relaxed),
+            fsyncs: self.fsyncs.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl IoMetricsSnapshot {
+    /// Physical bytes written per byte of payload, `None` before the first write.
+    pub fn write_amplification(&self) -> Option<f64> {
+        (self.logical_bytes_written > 0)
+            .then(|| self.physical_bytes_written as f64 / self.logical_bytes_written as f64)
+    }
+}
+
+impl StoreMetricsSnapshot {
+    /// Fraction of cache lookups that hit, `None` before the first lookup.
+    pub fn cache_hit_rate(&self) -> Option<f64> {
+        let lookups = self.cache_hits + self.cache_misses;
+        (lookups > 0).then(|| self.cache_hits as f64 / lookups as f64)
+    }
+}
+
+impl OperationStats {
+    /// Mean time per call, `None` before the first one.
+    pub fn mean_time(&self) -> Option<Duration> {
+        u32::try_from(self.calls)
+            .ok()
+            .filter(|calls| *calls > 0)
+            .map(|calls| self.total_time / calls)
+    }
+}
+
+impl ForestMetricsSnapshot {
+    /// Seconds the latest commit has been waiting to be published, zero when up to date.
+    pub fn sync_lag_seconds(&self) -> Option<i64> {
+        let last_commit = self.last_commit?;
+        match self.last_published {
+            Some(published) if published >= last_commit => Some(0),
+            _ => Some((Utc::now() - last_commit).num_seconds().max(0)),
+        }
+    }
+}
+
+/// Renders the snapshots in the Prometheus text exposition format (version 0.0.4).
+pub fn render_prometheus(store: &StoreMetricsSnapshot, forest: &ForestMetricsSnapshot) -> String {
+    let mut out = String::new();
+    let counters = [
+        (
+            "wnfsutils_store_blocks_read_total",
+            "Blocks read from the store.",
+            store.blocks_read,
+        ),
+        (
+            "wnfsutils_store_blocks_written_total",
+            "Blocks written to the store.",
+            store.blocks_written,
+        ),
+        (
+            "wnfsutils_store_bytes_read_total",
+            "Bytes read from the store.",
+            store.bytes_read,
+        ),
+        (
+            "wnfsutils_store_bytes_written_total",
+            "Bytes written to the store.",
+            store.bytes_written,
+        ),
+        (
+            "wnfsutils_store_read_errors_total",
+            "Failed block reads.",
+            store.read_errors,
+        ),
+        (
+            "wnfsutils_store_write_errors_total",
+            "Failed block writes.",
+            store.write_errors,
+        ),
+        (
+            "wnfsutils_cache_hits_total",
+            "Block cache hits.",
+            store.cache_hits,
+        ),
+        (
+            "wnfsutils_cache_misses_total",
+            "Block cache misses.",
+            store.cache_misses,
+        ),
+        (
+            "wnfsutils_forest_commits_total",
+            "Forest commits.",
+            forest.commits,
+        ),
+    ];
+    for (name, help, value) in counters {
+        push_metric(&mut out, name, help, "counter", &value.to_string());
+    }
+    if let Some(rate) = store.cache_hit_rate() {
+        push_metric(
+            &mut out,
+            "wnfsutils_cache_hit_ratio",
+            "Fraction of block cache lookups that hit.",
+            "gauge",
+            &rate.to_string(),
+        );
+    }
+    if let Some(last_commit) = forest.last_commit {
+        push_metric(
+            &mut out,
+            "wnfsutils_forest_last_commit_age_seconds",
+            "Seconds since the last forest commit.",
+            "gauge",
+            &(Utc::now() - last_commit).num_seconds().max(0).to_string(),
+        );
+    }
+    if let Some(lag) = forest.sync_lag_seconds() {
+        push_metric(
+            &mut out,
+            "wnfsutils_forest_sync_lag_seconds",
+            "Seconds the latest commit has been waiting to be published.",
+            "gauge",
+            &lag.to_string(),
+        );
+    }
+    out
+}
+
+/// Renders backend I/O in the Prometheus text format, labelled with the backend name so the
+/// output of several stores can be concatenated.
+pub fn render_io_prometheus(io: &IoMetricsSnapshot) -> String {
+    let mut out = String::new();
+    let label = format!("{{backend=\"{}\"}}", io.backend.replace('"', "'"));
+    let counters = [
+        (
+            "wnfsutils_backend_logical_bytes_written_total",
+            "Block payload written to the backend.",
+            io.logical_bytes_written,
+        ),
+        (
+            "wnfsutils_backend_physical_bytes_written_total",
+            "Bytes the backend wrote to disk.",
+            io.physical_bytes_written,
+        ),
+        (
+            "wnfsutils_backend_fsyncs_total",
+            "Syncs of backend files to disk.",
+            io.fsyncs,
+        ),
+    ];
+    for (name, help, value) in counters {
+        push_labelled_metric(&mut out, name, &label, help, "counter", &value.to_string());
+    }
+    if let Some(amplification) = io.write_amplification() {
+        push_labelled_metric(
+            &mut out,
+            "wnfsutils_backend_write_amplification",
+            &label,
+            "Physical bytes written per payload byte.",
+            "gauge",
+            &amplification.to_string(),
+        );
+    }
+    out
+}
+
+/// Renders operation latencies in the Prometheus text format, labelled with the operation name.
+pub fn render_operations_prometheus(operations: &BTreeMap<&'static str, OperationStats>) -> String {
+    let mut out = String::new();
+    if operations.is_empty() {
+        return out;
+    }
+    let families: [(&str, &str, &str, fn(&OperationStats) -> String); 3] = [
+        (
+            "wnfsutils_operation_duration_seconds_sum",
+            "Seconds spent in helper operations.",
+            "counter",
+            |stats| stats.total_time.as_secs_f64().to_string(),
+        ),
+        (
+            "wnfsutils_operation_calls_total",
+            "Helper operations run.",
+            "counter",
+            |stats| stats.calls.to_string(),
+        ),
+        (
+            "wnfsutils_operation_errors_total",
+            "Helper operations that failed.",
+            "counter",
+            |stats| stats.errors.to_string(),
+        ),
+    ];
+    for (name, help, kind, value) in families {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} {}", name, kind);
+        for (operation, stats) in operations {
+            let _ = writeln!(
+                out,
+                "{}{{operation=\"{}\"}} {}",
+                name,
+                operation,
+                value(stats)
+            );
+        }
+    }
+    out
+}
+
+fn push_metric(out: &mut String, name: &str, help: &str, kind: &str, value: &str) {
+    push_labelled_metric(out, name, "", help, kind, value);
+}
+
+fn push_labelled_metric(
+    out: &mut String,
+    name: &str,
+    labels: &str,
+    help: &str,
+    kind: &str,
+    value: &str,
+) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    let _ = writeln!(out, "{}{} {}", name, labels, value);
+}
+
+#[cfg(test)]
+mod metrics_tests;
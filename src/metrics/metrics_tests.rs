@@ -0,0 +1,134 @@
+use std::sync::{Arc, Mutex};
+
+use chrono::{Duration, Utc};
+
+use crate::metrics::{
+    install_hook, render_io_prometheus, render_operations_prometheus, render_prometheus,
+    uninstall_hook, ForestMetricsSnapshot, IoMetrics, MetricsHook, OperationMetrics, StoreMetrics,
+};
+
+#[test]
+fn counters_and_hit_rate() {
+    let metrics = StoreMetrics::default();
+    metrics.record_read(10);
+    metrics.record_read(5);
+    metrics.record_write(7);
+    metrics.record_cache(true);
+    metrics.record_cache(true);
+    metrics.record_cache(false);
+    metrics.record_cache(false);
+
+    let snapshot = metrics.snapshot();
+    assert_eq!(snapshot.blocks_read, 2);
+    assert_eq!(snapshot.bytes_read, 15);
+    assert_eq!(snapshot.bytes_written, 7);
+    assert_eq!(snapshot.cache_hit_rate(), Some(0.5));
+}
+
+#[test]
+fn renders_prometheus_text() {
+    let metrics = StoreMetrics::default();
+    metrics.record_write(42);
+    let forest = ForestMetricsSnapshot {
+        commits: 3,
+        last_commit: Some(Utc::now() - Duration::seconds(30)),
+        last_published: None,
+    };
+
+    let text = render_prometheus(&metrics.snapshot(), &forest);
+    assert!(text.contains("# TYPE wnfsutils_store_bytes_written_total counter"));
+    assert!(text.contains("wnfsutils_store_bytes_written_total 42\n"));
+    assert!(text.contains("wnfsutils_forest_commits_total 3\n"));
+    assert!(text.contains("wnfsutils_forest_sync_lag_seconds"));
+    assert!(!text.contains("wnfsutils_cache_hit_ratio"));
+}
+
+#[test]
+fn renders_backend_io_with_its_label() {
+    let io = IoMetrics::default();
+    assert_eq!(io.snapshot("pack").write_amplification(), None);
+    io.record_write(100, 130);
+    io.record_write(0, 20);
+    io.record_fsync();
+
+    let snapshot = io.snapshot("pack");
+    assert_eq!(snapshot.write_amplification(), Some(1.5));
+    let text = render_io_prometheus(&snapshot);
+    assert!(text.contains("# TYPE wnfsutils_backend_fsyncs_total counter\n"));
+    assert!(text.contains("wnfsutils_backend_physical_bytes_written_total{backend=\"pack\"} 150\n"));
+    assert!(text.contains("wnfsutils_backend_write_amplification{backend=\"pack\"} 1.5\n"));
+}
+
+#[derive(Default)]
+struct RecordingHook {
+    operations: Mutex<Vec<(&'static str, bool)>>,
+}
+
+impl MetricsHook for RecordingHook {
+    fn operation(&self, operation: &'static str, _elapsed: std::time::Duration, succeeded: bool) {
+        if operation.starts_with("metrics_test_") {
+            self.operations.lock().unwrap().push((operation, succeeded));
+        }
+    }
+}
+
+#[test]
+fn records_operation_latency_and_calls_the_hook() {
+    let metrics = OperationMetrics::default();
+    let hook = Arc::new(RecordingHook::default());
+    install_hook(hook.clone());
+    metrics.record(
+        "metrics_test_ls",
+        std::time::Duration::from_millis(10),
+        true,
+    );
+    metrics.record(
+        "metrics_test_ls",
+        std::time::Duration::from_millis(30),
+        false,
+    );
+    uninstall_hook();
+    metrics.record(
+        "metrics_test_ls",
+        std::time::Duration::from_millis(20),
+        true,
+    );
+
+    let stats = metrics.snapshot()["metrics_test_ls"];
+    assert_eq!(stats.calls, 3);
+    assert_eq!(stats.errors, 1);
+    assert_eq!(stats.max_time, std::time::Duration::from_millis(30));
+    assert_eq!(
+        stats.mean_time(),
+        Some(std::time::Duration::from_millis(20))
+    );
+    assert_eq!(
+        *hook.operations.lock().unwrap(),
+        vec![("metrics_test_ls", true), ("metrics_test_ls", false)]
+    );
+
+    let text = render_operations_prometheus(&metrics.snapshot());
+    assert_eq!(
+        text.matches("# TYPE wnfsutils_operation_calls_total counter\n")
+            .count(),
+        1
+    );
+    assert!(text.contains("wnfsutils_operation_calls_total{operation=\"metrics_test_ls\"} 3\n"));
+    assert!(text.contains("wnfsutils_operation_errors_total{operation=\"metrics_test_ls\"} 1\n"));
+}
+
+#[test]
+fn timed_operations_count_once_when_nested() {
+    let metrics = OperationMetrics::default();
+    let inner = || metrics.timed("metrics_test_inner", async { Ok::<_, String>(()) });
+    futures::executor::block_on(metrics.timed("metrics_test_outer", async {
+        inner().await?;
+        inner().await
+    }))
+    .unwrap();
+    futures::executor::block_on(inner()).unwrap();
+
+    let operations = metrics.snapshot();
+    assert_eq!(operations["metrics_test_outer"].calls, 1);
+    assert_eq!(operations["metrics_test_inner"].calls, 1);
+}
@@ -0,0 +1,112 @@
+//! Moving forests from one block store backend to another, e.g. from a `KVBlockStore` to a
+//! `PackStore`, without going through the forest layer.
+//!
+//! `migrate_store` copies every block reachable from the given roots, checking each against its
+//! CID before it's written. A block is written only after all blocks it links to, so a block
+//! found in the destination, intact, stands for its whole subtree: a migration interrupted
+//! halfway is resumed by running it again, which skips what was copied before.
+
+use std::collections::HashSet;
+
+use libipld::{cbor::DagCborCodec, codec::Codec, Cid, Ipld, IpldCodec};
+use log::trace;
+
+use crate::{blockstore::FFIStore, car::verify_block, error::describe};
+
+/// Progress of a migration, reported once per block.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MigrationProgress {
+    pub blocks_copied: usize,
+    /// Blocks found in the destination already, each standing for its subtree.
+    pub blocks_skipped: usize,
+    pub bytes_copied: u64,
+}
+
+// A block to visit, and once its links are pushed, its content waiting to be written.
+enum Visit {
+    Enter(Cid),
+    Write(Cid, Vec<u8>),
+}
+
+/// Copies the blocks reachable from `roots` from `src` to `dst`, see the module docs, and
+/// returns the final counts.
+pub async fn migrate_store<'a, 'b>(
+    src: &dyn FFIStore<'a>,
+    dst: &dyn FFIStore<'b>,
+    roots: &[Cid],
+    mut progress: Option<&mut dyn FnMut(&MigrationProgress)>,
+) -> Result<MigrationProgress, String> {
+    let mut status = MigrationProgress::default();
+    let mut done: HashSet<Cid> = HashSet::new();
+    let mut pending: Vec<Visit> = roots.iter().rev().map(|root| Visit::Enter(*root)).collect();
+    while let Some(visit) = pending.pop() {
+        match visit {
+            Visit::Enter(cid) => {
+                if done.contains(&cid) {
+                    continue;
+                }
+                if has_intact_block(dst, &cid).await {
+                    done.insert(cid);
+                    status.blocks_skipped += 1;
+                } else {
+                    let data = src.get_block_async(cid.to_bytes()).await.map_err(|e| {
+                        trace!("wnfsError in migrate_store reading {}: {:?}", cid, e);
+                        describe(&e)
+                    })?;
+                    verify_block(&cid, &data)?;
+                    let links = links_of(&cid, &data)?;
+                    pending.push(Visit::Write(cid, data));
+                    pending.extend(
+                        links
+                            .into_iter()
+                            .filter(|link| !done.contains(link))
+                            .map(Visit::Enter),
+                    );
+                    continue;
+                }
+            }
+            Visit::Write(cid, data) => {
+                let size = data.len() as u64;
+                dst.put_block_async(cid.to_bytes(), data)
+                    .await
+                    .map_err(|e| {
+                        trace!("wnfsError in migrate_store writing {}: {:?}", cid, e);
+                        describe(&e)
+                    })?;
+                done.insert(cid);
+                status.blocks_copied += 1;
+                status.bytes_copied += size;
+            }
+        }
+        if let Some(callback) = progress.as_mut() {
+            callback(&status);
+        }
+    }
+    dst.flush_async().await.map_err(|e| {
+        trace!("wnfsError in migrate_store on flush: {:?}", e);
+        describe(&e)
+    })?;
+    Ok(status)
+}
+
+// Whether `store` holds a block matching `cid`. A corrupt copy is written again.
+async fn has_intact_block(store: &dyn FFIStore<'_>, cid: &Cid) -> bool {
+    match store.get_block_async(cid.to_bytes()).await {
+        Ok(data) => verify_block(cid, &data).is_ok(),
+        Err(_) => false,
+    }
+}
+
+// DAG-CBOR blocks link to others; other codecs, e.g. raw ciphertext, are leaves.
+fn links_of(cid: &Cid, data: &[u8]) -> Result<Vec<Cid>, String> {
+    if cid.codec() != u64::from(IpldCodec::DagCbor) {
+        return Ok(Vec::new());
+    }
+    let ipld: Ipld = DagCborCodec.decode(data).map_err(|e| describe(&e))?;
+    let mut links = Vec::new();
+    ipld.references(&mut links);
+    Ok(links)
+}
+
+#[cfg(test)]
+mod migrate_tests;
@@ -0,0 +1,78 @@
+use libipld::{cbor::DagCborCodec, codec::Codec, Ipld, IpldCodec};
+use wnfs::common::{BlockStore, CODEC_DAG_CBOR};
+
+use crate::{
+    blockstore::{FFIFriendlyBlockStore, FFIStore},
+    car::reachable_blocks,
+    kvstore::KVBlockStore,
+    migrate::{migrate_store, MigrationProgress},
+    packstore::PackStore,
+};
+
+#[tokio::test]
+async fn migrates_reachable_blocks_and_resumes() {
+    let dir = tempfile::tempdir().unwrap();
+    let source = KVBlockStore::new(
+        dir.path().join("source").to_string_lossy().to_string(),
+        CODEC_DAG_CBOR,
+    );
+    let blockstore = FFIFriendlyBlockStore::new(Box::new(source.to_owned()));
+    let leaf = blockstore
+        .put_block(b"leaf".to_vec(), IpldCodec::Raw.into())
+        .await
+        .unwrap();
+    let other = blockstore
+        .put_block(b"other leaf".to_vec(), IpldCodec::Raw.into())
+        .await
+        .unwrap();
+    let unrelated = blockstore
+        .put_block(b"unrelated".to_vec(), IpldCodec::Raw.into())
+        .await
+        .unwrap();
+    let inner = blockstore
+        .put_block(
+            DagCborCodec
+                .encode(&Ipld::List(vec![Ipld::Link(leaf)]))
+                .unwrap(),
+            IpldCodec::DagCbor.into(),
+        )
+        .await
+        .unwrap();
+    let root = blockstore
+        .put_block(
+            DagCborCodec
+                .encode(&Ipld::List(vec![Ipld::Link(inner), Ipld::Link(other)]))
+                .unwrap(),
+            IpldCodec::DagCbor.into(),
+        )
+        .await
+        .unwrap();
+
+    // An earlier run stopped after copying the inner node and its leaf.
+    let target =
+        PackStore::try_new(dir.path().join("target").to_string_lossy().to_string()).unwrap();
+    for cid in [leaf, inner] {
+        let data = source.get_block(cid.to_bytes()).unwrap();
+        target.put_block(cid.to_bytes(), data).unwrap();
+    }
+
+    let mut reported = 0;
+    let mut count = |_: &MigrationProgress| reported += 1;
+    let status = migrate_store(&source, &target, &[root], Some(&mut count))
+        .await
+        .unwrap();
+    assert_eq!((status.blocks_copied, status.blocks_skipped), (2, 1));
+    assert_eq!(reported, 3);
+    let migrated = FFIFriendlyBlockStore::new(Box::new(target.to_owned()));
+    assert_eq!(
+        reachable_blocks(&migrated, &root).await.unwrap().len(),
+        reachable_blocks(&blockstore, &root).await.unwrap().len()
+    );
+    assert!(target.get_block(unrelated.to_bytes()).is_err());
+
+    // Everything is there now, so the root stands for the whole forest.
+    let status = migrate_store(&source, &target, &[root], None)
+        .await
+        .unwrap();
+    assert_eq!((status.blocks_copied, status.blocks_skipped), (0, 1));
+}
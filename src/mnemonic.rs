@@ -0,0 +1,74 @@
+//! Recovery phrases for wnfs keys, so a user can write down their key instead of storing it.
+//!
+//! Phrases are BIP39 English mnemonics of 12 or 24 words. `wnfs_key_from_mnemonic` stretches the
+//! phrase and an optional passphrase into the 64 byte BIP39 seed, PBKDF2-HMAC-SHA512 with 2048
+//! rounds, and derives the 32 byte wnfs key from that seed with HKDF-SHA256. The same phrase and
+//! passphrase always give the same key; a different passphrase gives an unrelated one, without
+//! any way to tell which passphrase was right other than loading the forest.
+
+use bip39::{Language, Mnemonic};
+use hkdf::Hkdf;
+use sha2::Sha256;
+
+use crate::error::WnfsUtilsError;
+
+/// Word counts accepted for phrases: 128 and 256 bits of entropy.
+pub const MNEMONIC_WORD_COUNTS: [usize; 2] = [12, 24];
+
+// HKDF info separating the wnfs key from anything else derived from the same seed.
+const WNFS_KEY_INFO: &[u8] = b"wnfsutils wnfs key v1";
+
+/// A new random phrase of `word_count` words, 12 or 24.
+pub fn generate_mnemonic(word_count: usize) -> Result<String, String> {
+    check_word_count(word_count)?;
+    Mnemonic::generate_in(Language::English, word_count)
+        .map(|mnemonic| mnemonic.to_string())
+        .map_err(|e| WnfsUtilsError::InvalidMnemonic(e.to_string()).to_string())
+}
+
+/// Checks the words and the checksum of `phrase`. Case and extra whitespace are ignored.
+pub fn validate_mnemonic(phrase: &str) -> Result<(), String> {
+    parse(phrase).map(|_| ())
+}
+
+/// The 64 byte BIP39 seed of `phrase` and `passphrase`, see the module docs.
+pub fn mnemonic_seed(phrase: &str, passphrase: &str) -> Result<[u8; 64], String> {
+    Ok(parse(phrase)?.to_seed(passphrase))
+}
+
+/// The 32 byte wnfs key of `phrase` and `passphrase`, ready for `PrivateDirectoryHelper::init`
+/// and `load_with_wnfs_key`. An empty passphrase is the same as none.
+pub fn wnfs_key_from_mnemonic(phrase: &str, passphrase: &str) -> Result<Vec<u8>, String> {
+    let seed = mnemonic_seed(phrase, passphrase)?;
+    let mut key = vec![0u8; 32];
+    Hkdf::<Sha256>::new(None, &seed)
+        .expand(WNFS_KEY_INFO, &mut key)
+        .map_err(|e| format!("wnfsError unable to derive wnfs key: {}", e))?;
+    Ok(key)
+}
+
+fn parse(phrase: &str) -> Result<Mnemonic, String> {
+    let normalized = phrase
+        .split_whitespace()
+        .map(str::to_lowercase)
+        .collect::<Vec<String>>()
+        .join(" ");
+    let mnemonic = Mnemonic::parse_in(Language::English, normalized)
+        .map_err(|e| WnfsUtilsError::InvalidMnemonic(e.to_string()).to_string())?;
+    check_word_count(mnemonic.word_count())?;
+    Ok(mnemonic)
+}
+
+fn check_word_count(word_count: usize) -> Result<(), String> {
+    match MNEMONIC_WORD_COUNTS.contains(&word_count) {
+        true => Ok(()),
+        false => Err(WnfsUtilsError::InvalidMnemonic(format!(
+            "expected 12 or 24 words, got {}",
+            word_count
+        ))
+        .to_string()),
+    }
+}
+
+#[cfg(test)]
+mod mnemonic_tests;
@@ -0,0 +1,47 @@
+use crate::mnemonic::{
+    generate_mnemonic, mnemonic_seed, validate_mnemonic, wnfs_key_from_mnemonic,
+};
+
+const PHRASE: &str =
+    "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about";
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[test]
+fn derives_the_bip39_seed() {
+    // Test vector of the BIP39 reference implementation.
+    let seed = mnemonic_seed(PHRASE, "TREZOR").unwrap();
+    assert_eq!(
+        to_hex(&seed),
+        "c55257c360c07c72029aebc1b53c05ed0362ada38ead3e3e9efa3708e53495531f09a6987599d18264c1e1c92f2cf141630c7a3c4ab7c81b2f001698e7463b04"
+    );
+}
+
+#[test]
+fn derives_stable_wnfs_keys() {
+    let key = wnfs_key_from_mnemonic(PHRASE, "").unwrap();
+    assert_eq!(key.len(), 32);
+    assert_eq!(
+        wnfs_key_from_mnemonic(&format!("  {}\n", PHRASE.to_uppercase()), "").unwrap(),
+        key
+    );
+    assert_ne!(wnfs_key_from_mnemonic(PHRASE, "TREZOR").unwrap(), key);
+}
+
+#[test]
+fn generates_and_validates_mnemonics() {
+    for word_count in [12, 24] {
+        let phrase = generate_mnemonic(word_count).unwrap();
+        assert_eq!(phrase.split(' ').count(), word_count);
+        validate_mnemonic(&phrase).unwrap();
+    }
+    assert!(generate_mnemonic(15).is_err());
+
+    // Wrong checksum, unknown word and a well formed phrase of 15 words.
+    assert!(validate_mnemonic(&PHRASE.replace("about", "abandon")).is_err());
+    assert!(validate_mnemonic(&PHRASE.replace("about", "wnfs")).is_err());
+    let fifteen_words = bip39::Mnemonic::from_entropy(&[0u8; 20]).unwrap();
+    assert!(validate_mnemonic(&fifteen_words.to_string()).is_err());
+}
@@ -0,0 +1,139 @@
+//! What the crate connects to.
+//!
+//! wnfsutils sends no telemetry, crash reports or update checks. Its only outbound connections
+//! go to what the app configured: gateways, pointer endpoints, DoH resolvers and proxies. The
+//! one exception are provider hints, gateways named by a share or a sync peer, which
+//! `gateway::GatewayStore` asks first for the blocks they were given with.
+//!
+//! With [`set_configured_hosts_only`] enabled, hints naming a gateway the store wasn't
+//! configured with are ignored, so the hosts contacted are exactly the configured ones. Stores
+//! read the setting when they are built. Building with the `no-net-telemetry` feature enables it
+//! for good, for apps that have to state this without relying on a runtime call being made early
+//! enough.
+//!
+//! [`audit_connections`] records the hosts the stores and resolvers built within it send
+//! requests to, for tests proving what an app contacts.
+
+#[cfg(test)]
+use std::cell::Cell;
+use std::sync::atomic::{AtomicBool, Ordering};
+#[cfg(feature = "reqwest")]
+use std::{
+    cell::RefCell,
+    collections::BTreeSet,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use log::trace;
+
+use crate::error::WnfsUtilsError;
+
+static CONFIGURED_HOSTS_ONLY: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+    // The audit of the `audit_connections` call running on this thread.
+    #[cfg(feature = "reqwest")]
+    static AUDIT: RefCell<Option<ConnectionAudit>> = const { RefCell::new(None) };
+    // Set by `scoped_configured_hosts_only`, overriding the process-wide setting.
+    #[cfg(test)]
+    static SCOPED_CONFIGURED_HOSTS_ONLY: Cell<Option<bool>> = const { Cell::new(None) };
+}
+
+/// Restricts connections to the configured hosts for the stores built from now on, see the
+/// module docs. Lifting the restriction fails in `no-net-telemetry` builds.
+pub fn set_configured_hosts_only(enabled: bool) -> Result<(), WnfsUtilsError> {
+    if !enabled && cfg!(feature = "no-net-telemetry") {
+        trace!("network: configured-hosts-only can't be lifted in this build");
+        return Err(WnfsUtilsError::NetworkRestricted);
+    }
+    CONFIGURED_HOSTS_ONLY.store(enabled, Ordering::SeqCst);
+    Ok(())
+}
+
+/// Whether stores built now contact only configured hosts.
+pub fn configured_hosts_only() -> bool {
+    #[cfg(test)]
+    if let Some(enabled) = SCOPED_CONFIGURED_HOSTS_ONLY.with(Cell::get) {
+        return cfg!(feature = "no-net-telemetry") || enabled;
+    }
+    cfg!(feature = "no-net-telemetry") || CONFIGURED_HOSTS_ONLY.load(Ordering::SeqCst)
+}
+
+// `set_configured_hosts_only` for the stores `build` builds on this thread alone, so tests
+// running in parallel don't see each other's setting.
+#[cfg(test)]
+pub(crate) fn scoped_configured_hosts_only<T>(enabled: bool, build: impl FnOnce() -> T) -> T {
+    let previous = SCOPED_CONFIGURED_HOSTS_ONLY.with(|scoped| scoped.replace(Some(enabled)));
+    let built = build();
+    SCOPED_CONFIGURED_HOSTS_ONLY.with(|scoped| scoped.set(previous));
+    built
+}
+
+/// The hosts requests were sent to by the HTTP clients built within an `audit_connections`
+/// call. Clones share what they record.
+#[cfg(feature = "reqwest")]
+#[derive(Debug, Clone, Default)]
+pub struct ConnectionAudit {
+    hosts: Arc<Mutex<BTreeSet<String>>>,
+}
+
+#[cfg(feature = "reqwest")]
+impl ConnectionAudit {
+    /// Every `host:port` contacted so far, sorted. Requests sent through a proxy are listed by
+    /// the host they were for.
+    pub fn hosts(&self) -> Vec<String> {
+        self.lock().iter().cloned().collect()
+    }
+
+    fn record(&self, url: &reqwest::Url) {
+        let host = format!(
+            "{}:{}",
+            url.host_str().unwrap_or_default(),
+            url.port_or_known_default().unwrap_or_default()
+        );
+        self.lock().insert(host);
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, BTreeSet<String>> {
+        match self.hosts.lock() {
+            Ok(hosts) => hosts,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+}
+
+/// Runs `build` and records the hosts every HTTP client built within it sends requests to:
+/// those of `GatewayStore`, `HttpGatewayStore`, `S3BlockStore`, `PointerResolver` and
+/// `DohResolver`, including the clients their `with_*` methods rebuild. The clients keep
+/// recording for as long as they are used. With a `DohConfig` set, hostname lookups are
+/// requests to its resolver too, so every connection the stores open is recorded. Audited
+/// clients ignore the system proxy settings.
+#[cfg(feature = "reqwest")]
+pub fn audit_connections<T>(build: impl FnOnce() -> T) -> (T, ConnectionAudit) {
+    let audit = ConnectionAudit::default();
+    let previous = AUDIT.with(|current| current.replace(Some(audit.clone())));
+    let built = build();
+    AUDIT.with(|current| current.replace(previous));
+    (built, audit)
+}
+
+// The builder every HTTP client of the crate starts from, reporting to the running
+// `audit_connections`, if any. Responses stay undecoded unless the client asks for compression,
+// see `Transport`.
+#[cfg(feature = "reqwest")]
+pub(crate) fn client_builder(timeout: Duration) -> reqwest::ClientBuilder {
+    let builder = reqwest::Client::builder()
+        .timeout(timeout)
+        .no_gzip()
+        .no_brotli()
+        .no_zstd();
+    match AUDIT.with(|current| current.borrow().clone()) {
+        // Sees every request on its way out and never proxies it.
+        Some(audit) => builder.proxy(reqwest::Proxy::custom(move |url| {
+            audit.record(url);
+            None::<reqwest::Url>
+        })),
+        None => builder,
+    }
+}
@@ -0,0 +1,512 @@
+//! Block store for a local directory that appends blocks to pack files, as git does, instead of
+//! keeping a file or database entry per block. A forest of small files turns into a handful of
+//! large files, which saves inodes and turns every write into one append.
+//!
+//! A pack is a sequence of records: a block, a deletion or a touch that renews a block's write
+//! time. Every record carries a CRC32, so a record torn by a crash is detected and cut off when
+//! the store is opened again. Once a pack exceeds the pack size it is sealed: synced, and its
+//! records listed in an index file next to it, so opening the store only reads the indexes and
+//! the pack still being written. `compact` copies the live blocks into new packs and removes
+//! the old ones.
+
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use web_time::{SystemTime, UNIX_EPOCH};
+use wnfs::common::BlockStoreError;
+
+use crate::blockstore::{cid_from_bytes, FFIStore};
+use crate::error::WnfsUtilsError;
+use crate::metrics::{IoMetrics, IoMetricsSnapshot};
+
+/// Size past which a pack is sealed and a new one started.
+pub const DEFAULT_PACK_BYTES: u64 = 64 * 1024 * 1024;
+
+const PACK_EXTENSION: &str = "pack";
+const INDEX_EXTENSION: &str = "idx";
+
+const KIND_BLOCK: u8 = 0;
+const KIND_DELETE: u8 = 1;
+const KIND_TOUCH: u8 = 2;
+
+// CID length (u16), kind, write time in milliseconds (u64) and data length (u32), big endian,
+// followed by the CID, the data and a CRC32 of everything before it.
+const RECORD_HEADER: usize = 2 + 1 + 8 + 4;
+// CID length (u16), kind, write time (u64), record offset (u64) and record length (u32),
+// followed by the CID. The index file ends with a CRC32 of all its entries.
+const INDEX_HEADER: usize = 2 + 1 + 8 + 8 + 4;
+const CHECKSUM: usize = 4;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Entry {
+    cid: Vec<u8>,
+    kind: u8,
+    written_at: u64,
+    // Start and length of the whole record in its pack.
+    offset: u64,
+    len: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Location {
+    pack: u32,
+    offset: u64,
+    len: u32,
+    written_at: u64,
+}
+
+fn encode_record(cid: &[u8], kind: u8, written_at: u64, data: &[u8]) -> Result<Vec<u8>> {
+    let cid_len = u16::try_from(cid.len()).map_err(|_| anyhow!("CID too long for a pack"))?;
+    let data_len = u32::try_from(data.len()).map_err(|_| anyhow!("block too large for a pack"))?;
+    let mut record = Vec::with_capacity(RECORD_HEADER + cid.len() + data.len() + CHECKSUM);
+    record.extend_from_slice(&cid_len.to_be_bytes());
+    record.push(kind);
+    record.extend_from_slice(&written_at.to_be_bytes());
+    record.extend_from_slice(&data_len.to_be_bytes());
+    record.extend_from_slice(cid);
+    record.extend_from_slice(data);
+    let checksum = crc32fast::hash(&record);
+    record.extend_from_slice(&checksum.to_be_bytes());
+    Ok(record)
+}
+
+// The record at the start of `bytes` with its data, `None` when it is truncated or corrupt.
+fn decode_record(bytes: &[u8]) -> Option<(Entry, &[u8])> {
+    let header = bytes.get(..RECORD_HEADER)?;
+    let cid_len = u16::from_be_bytes(header[0..2].try_into().ok()?) as usize;
+    let kind = header[2];
+    let written_at = u64::from_be_bytes(header[3..11].try_into().ok()?);
+    let data_len = u32::from_be_bytes(header[11..15].try_into().ok()?) as usize;
+    let body_end = RECORD_HEADER + cid_len + data_len;
+    let checksum = bytes.get(body_end..body_end + CHECKSUM)?;
+    if crc32fast::hash(&bytes[..body_end]).to_be_bytes() != checksum {
+        return None;
+    }
+    let entry = Entry {
+        cid: bytes[RECORD_HEADER..RECORD_HEADER + cid_len].to_vec(),
+        kind,
+        written_at,
+        offset: 0,
+        len: (body_end + CHECKSUM) as u32,
+    };
+    Some((entry, &bytes[RECORD_HEADER + cid_len..body_end]))
+}
+
+// The records of a pack up to the first torn or corrupt one, and the length they span.
+fn scan_pack(bytes: &[u8]) -> (Vec<Entry>, u64) {
+    let mut entries = Vec::new();
+    let mut offset = 0;
+    while let Some((mut entry, _)) = bytes.get(offset..).and_then(decode_record) {
+        entry.offset = offset as u64;
+        offset += entry.len as usize;
+        entries.push(entry);
+    }
+    (entries, offset as u64)
+}
+
+fn encode_index(entries: &[Entry]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    for entry in entries {
+        bytes.extend_from_slice(&(entry.cid.len() as u16).to_be_bytes());
+        bytes.push(entry.kind);
+        bytes.extend_from_slice(&entry.written_at.to_be_bytes());
+        bytes.extend_from_slice(&entry.offset.to_be_bytes());
+        bytes.extend_from_slice(&entry.len.to_be_bytes());
+        bytes.extend_from_slice(&entry.cid);
+    }
+    let checksum = crc32fast::hash(&bytes);
+    bytes.extend_from_slice(&checksum.to_be_bytes());
+    bytes
+}
+
+fn decode_index(bytes: &[u8]) -> Option<Vec<Entry>> {
+    let (body, checksum) = bytes.split_at(bytes.len().checked_sub(CHECKSUM)?);
+    if crc32fast::hash(body).to_be_bytes() != checksum {
+        return None;
+    }
+    let mut entries = Vec::new();
+    let mut rest = body;
+    while !rest.is_empty() {
+        let header = rest.get(..INDEX_HEADER)?;
+        let cid_len = u16::from_be_bytes(header[0..2].try_into().ok()?) as usize;
+        entries.push(Entry {
+            kind: header[2],
+            written_at: u64::from_be_bytes(header[3..11].try_into().ok()?),
+            offset: u64::from_be_bytes(header[11..19].try_into().ok()?),
+            len: u32::from_be_bytes(header[19..23].try_into().ok()?),
+            cid: rest.get(INDEX_HEADER..INDEX_HEADER + cid_len)?.to_vec(),
+        });
+        rest = &rest[INDEX_HEADER + cid_len..];
+    }
+    Some(entries)
+}
+
+fn apply(index: &mut HashMap<Vec<u8>, Location>, pack: u32, entry: &Entry) {
+    match entry.kind {
+        KIND_BLOCK => {
+            index.insert(
+                entry.cid.to_owned(),
+                Location {
+                    pack,
+                    offset: entry.offset,
+                    len: entry.len,
+                    written_at: entry.written_at,
+                },
+            );
+        }
+        KIND_DELETE => {
+            index.remove(&entry.cid);
+        }
+        KIND_TOUCH => {
+            if let Some(location) = index.get_mut(&entry.cid) {
+                location.written_at = entry.written_at;
+            }
+        }
+        _ => {}
+    }
+}
+
+struct Packs {
+    dir: PathBuf,
+    max_pack_bytes: u64,
+    index: HashMap<Vec<u8>, Location>,
+    current: u32,
+    writer: File,
+    // Bytes and records in the current pack, the latter written to its index on sealing.
+    written: u64,
+    records: Vec<Entry>,
+    readers: HashMap<u32, File>,
+    io: IoMetrics,
+}
+
+impl Packs {
+    fn pack_path(&self, pack: u32, extension: &str) -> PathBuf {
+        pack_path(&self.dir, pack, extension)
+    }
+
+    fn open(dir: PathBuf, max_pack_bytes: u64) -> Result<Self> {
+        fs::create_dir_all(&dir)?;
+        let io = IoMetrics::default();
+        let mut numbers = Vec::new();
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some(PACK_EXTENSION) {
+                continue;
+            }
+            let number = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.strip_prefix("pack-"))
+                .and_then(|number| number.parse::<u32>().ok());
+            if let Some(number) = number {
+                numbers.push(number);
+            }
+        }
+        numbers.sort_unstable();
+        let current = numbers.pop().unwrap_or(0);
+
+        let mut index = HashMap::new();
+        for pack in numbers {
+            let index_path = pack_path(&dir, pack, INDEX_EXTENSION);
+            let entries = match fs::read(&index_path).ok().as_deref().and_then(decode_index) {
+                Some(entries) => entries,
+                // Sealing was interrupted: rebuild the index from the pack.
+                None => {
+                    let (entries, _) = scan_pack(&fs::read(pack_path(&dir, pack, PACK_EXTENSION))?);
+                    write_atomically(&index_path, &encode_index(&entries), &io)?;
+                    entries
+                }
+            };
+            for entry in entries.iter() {
+                apply(&mut index, pack, entry);
+            }
+        }
+
+        let current_path = pack_path(&dir, current, PACK_EXTENSION);
+        let bytes = match current_path.exists() {
+            true => fs::read(&current_path)?,
+            false => Vec::new(),
+        };
+        let (records, written) = scan_pack(&bytes);
+        for entry in records.iter() {
+            apply(&mut index, current, entry);
+        }
+        let writer = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&current_path)?;
+        // Cut off a record torn by a crash, so appends continue from the last complete one.
+        if written < bytes.len() as u64 {
+            writer.set_len(written)?;
+        }
+        Ok(Self {
+            dir,
+            max_pack_bytes,
+            index,
+            current,
+            writer,
+            written,
+            records,
+            readers: HashMap::new(),
+            io,
+        })
+    }
+
+    fn append(&mut self, cid: &[u8], kind: u8, written_at: u64, data: &[u8]) -> Result<()> {
+        let record = encode_record(cid, kind, written_at, data)?;
+        self.append_record(record, data.len())
+    }
+
+    // Appends an encoded record holding `logical` bytes of payload, sealing the current pack
+    // first when it would grow too large.
+    fn append_record(&mut self, record: Vec<u8>, logical: usize) -> Result<()> {
+        if self.written > 0 && self.written + record.len() as u64 > self.max_pack_bytes {
+            self.seal()?;
+        }
+        let (mut entry, _) =
+            decode_record(&record).ok_or_else(|| anyhow!("invalid pack record"))?;
+        self.writer.write_all(&record)?;
+        self.io.record_write(logical, record.len());
+        entry.offset = self.written;
+        self.written += record.len() as u64;
+        apply(&mut self.index, self.current, &entry);
+        self.records.push(entry);
+        Ok(())
+    }
+
+    // Syncs the current pack, writes its index and starts the next one.
+    fn seal(&mut self) -> Result<()> {
+        if self.written == 0 {
+            return Ok(());
+        }
+        self.writer.sync_data()?;
+        self.io.record_fsync();
+        write_atomically(
+            &self.pack_path(self.current, INDEX_EXTENSION),
+            &encode_index(&self.records),
+            &self.io,
+        )?;
+        self.current += 1;
+        self.writer = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.pack_path(self.current, PACK_EXTENSION))?;
+        self.written = 0;
+        self.records.clear();
+        Ok(())
+    }
+
+    fn read_record(&mut self, location: &Location) -> Result<Vec<u8>> {
+        if !self.readers.contains_key(&location.pack) {
+            let reader = File::open(self.pack_path(location.pack, PACK_EXTENSION))?;
+            self.readers.insert(location.pack, reader);
+        }
+        let reader = self
+            .readers
+            .get_mut(&location.pack)
+            .ok_or_else(|| anyhow!("pack {} is not open", location.pack))?;
+        let mut record = vec![0; location.len as usize];
+        reader.seek(SeekFrom::Start(location.offset))?;
+        reader.read_exact(&mut record)?;
+        Ok(record)
+    }
+
+    fn size_on_disk(&self) -> Result<u64> {
+        self.files_size(|_| true)
+    }
+
+    // Bytes of records in all packs, live or not.
+    fn pack_bytes(&self) -> Result<u64> {
+        self.files_size(|path| path.extension().and_then(|e| e.to_str()) == Some(PACK_EXTENSION))
+    }
+
+    fn files_size(&self, include: impl Fn(&Path) -> bool) -> Result<u64> {
+        let mut size = 0;
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            if include(&entry.path()) {
+                size += entry.metadata()?.len();
+            }
+        }
+        Ok(size)
+    }
+}
+
+fn pack_path(dir: &Path, pack: u32, extension: &str) -> PathBuf {
+    dir.join(format!("pack-{:08}.{}", pack, extension))
+}
+
+fn write_atomically(path: &Path, bytes: &[u8], io: &IoMetrics) -> Result<()> {
+    let temporary = path.with_extension("tmp");
+    let mut file = File::create(&temporary)?;
+    file.write_all(bytes)?;
+    file.sync_all()?;
+    io.record_write(0, bytes.len());
+    io.record_fsync();
+    fs::rename(&temporary, path)?;
+    Ok(())
+}
+
+/// Block store over pack files in a directory, see the module documentation.
+#[derive(Clone)]
+pub struct PackStore {
+    packs: Arc<Mutex<Packs>>,
+}
+
+impl PackStore {
+    /// Opens or creates the store in `dir`, with packs of `DEFAULT_PACK_BYTES`.
+    pub fn try_new(dir: String) -> Result<Self, WnfsUtilsError> {
+        Self::with_pack_size(dir, DEFAULT_PACK_BYTES)
+    }
+
+    /// Like `try_new`, sealing packs once they exceed `max_pack_bytes`.
+    pub fn with_pack_size(dir: String, max_pack_bytes: u64) -> Result<Self, WnfsUtilsError> {
+        let packs = Packs::open(PathBuf::from(dir), max_pack_bytes.max(1))
+            .map_err(|e| WnfsUtilsError::StoreOpen(e.to_string()))?;
+        Ok(Self {
+            packs: Arc::new(Mutex::new(packs)),
+        })
+    }
+
+    /// Syncs the pack being written. Sealed packs are synced when sealed; records appended
+    /// since the last sync may be lost when the device loses power, never torn.
+    pub fn sync(&self) -> Result<()> {
+        let packs = self.lock();
+        packs.writer.sync_data()?;
+        packs.io.record_fsync();
+        Ok(())
+    }
+
+    /// Bytes taken by the pack and index files.
+    pub fn size_on_disk(&self) -> Result<u64> {
+        self.lock().size_on_disk()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Packs> {
+        match self.packs.lock() {
+            Ok(packs) => packs,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl<'a> FFIStore<'a> for PackStore {
+    fn get_block(&self, cid: Vec<u8>) -> Result<Vec<u8>> {
+        let parsed_cid = cid_from_bytes(&cid)?;
+        let mut packs = self.lock();
+        let location = *packs
+            .index
+            .get(&cid)
+            .ok_or(BlockStoreError::CIDNotFound(parsed_cid))?;
+        let record = packs.read_record(&location)?;
+        match decode_record(&record) {
+            Some((entry, data)) if entry.cid == cid => Ok(data.to_vec()),
+            _ => Err(anyhow!("corrupt pack record for {}", parsed_cid)),
+        }
+    }
+
+    fn put_block(&self, cid: Vec<u8>, bytes: Vec<u8>) -> Result<()> {
+        let mut packs = self.lock();
+        // Blocks are content addressed: a block already stored only gets a new write time.
+        match packs.index.contains_key(&cid) {
+            true => packs.append(&cid, KIND_TOUCH, now_millis(), &[]),
+            false => packs.append(&cid, KIND_BLOCK, now_millis(), &bytes),
+        }
+    }
+
+    /// Appends the whole batch under one lock, so it isn't interleaved with other writers.
+    fn put_many(&self, blocks: Vec<(Vec<u8>, Vec<u8>)>) -> Result<()> {
+        let mut packs = self.lock();
+        for (cid, bytes) in blocks {
+            match packs.index.contains_key(&cid) {
+                true => packs.append(&cid, KIND_TOUCH, now_millis(), &[])?,
+                false => packs.append(&cid, KIND_BLOCK, now_millis(), &bytes)?,
+            }
+        }
+        Ok(())
+    }
+
+    async fn put_many_async(&self, blocks: Vec<(Vec<u8>, Vec<u8>)>) -> Result<()> {
+        self.put_many(blocks)
+    }
+
+    fn list_blocks(&self) -> Result<Vec<Vec<u8>>> {
+        Ok(self.lock().index.keys().cloned().collect())
+    }
+
+    fn delete_block(&self, cid: Vec<u8>) -> Result<()> {
+        let mut packs = self.lock();
+        if !packs.index.contains_key(&cid) {
+            return Ok(());
+        }
+        packs.append(&cid, KIND_DELETE, now_millis(), &[])
+    }
+
+    fn block_written_at(&self, cid: Vec<u8>) -> Result<Option<u64>> {
+        Ok(self
+            .lock()
+            .index
+            .get(&cid)
+            .map(|location| location.written_at))
+    }
+
+    /// Copies the live blocks into new packs and removes the old packs. A crash part way only
+    /// leaves blocks stored twice, or deleted blocks back until the next garbage collection.
+    fn compact(&self) -> Result<u64> {
+        let mut packs = self.lock();
+        let size_before = packs.size_on_disk()?;
+        let live: u64 = packs
+            .index
+            .values()
+            .map(|location| location.len as u64)
+            .sum();
+        if live == packs.pack_bytes()? {
+            return Ok(0);
+        }
+        packs.seal()?;
+        let first_new = packs.current;
+        let mut live: Vec<Location> = packs.index.values().copied().collect();
+        live.sort_by_key(|location| (location.pack, location.offset));
+        for location in live {
+            let record = packs.read_record(&location)?;
+            let (entry, data) =
+                decode_record(&record).ok_or_else(|| anyhow!("corrupt pack record"))?;
+            // Keep the write time the block had, not the time of the copy.
+            let copy = encode_record(&entry.cid, KIND_BLOCK, location.written_at, data)?;
+            packs.append_record(copy, 0)?;
+        }
+        packs.seal()?;
+        packs.readers.clear();
+        for pack in 0..first_new {
+            for extension in [INDEX_EXTENSION, PACK_EXTENSION] {
+                let path = packs.pack_path(pack, extension);
+                if path.exists() {
+                    fs::remove_file(path)?;
+                }
+            }
+        }
+        Ok(size_before.saturating_sub(packs.size_on_disk()?))
+    }
+
+    fn io_metrics(&self) -> Option<IoMetricsSnapshot> {
+        Some(self.lock().io.snapshot("pack"))
+    }
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod packstore_tests;
@@ -0,0 +1,99 @@
+use std::fs::OpenOptions;
+use std::io::Write;
+
+use libipld::{
+    multihash::{Code, MultihashDigest},
+    Cid,
+};
+
+use crate::{blockstore::FFIStore, packstore::PackStore};
+
+fn block(i: usize) -> (Vec<u8>, Vec<u8>) {
+    let data = format!("block number {}", i).into_bytes();
+    let cid = Cid::new_v1(0x55, Code::Sha2_256.digest(&data));
+    (cid.to_bytes(), data)
+}
+
+#[test]
+fn blocks_survive_reopening_and_sealing() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("packs").to_string_lossy().to_string();
+    // Small packs, so the blocks spread over several sealed ones.
+    let store = PackStore::with_pack_size(path.to_owned(), 256).unwrap();
+    for i in 0..20 {
+        let (cid, data) = block(i);
+        store.put_block(cid, data).unwrap();
+    }
+    store.delete_block(block(3).0).unwrap();
+    store.sync().unwrap();
+    drop(store);
+
+    let store = PackStore::with_pack_size(path.to_owned(), 256).unwrap();
+    assert_eq!(store.list_blocks().unwrap().len(), 19);
+    for i in (0..20).filter(|i| *i != 3) {
+        let (cid, data) = block(i);
+        assert_eq!(store.get_block(cid.to_owned()).unwrap(), data);
+        assert!(store.block_written_at(cid).unwrap().is_some());
+    }
+    assert!(store.get_block(block(3).0).is_err());
+    let packs = std::fs::read_dir(dir.path().join("packs")).unwrap().count();
+    assert!(packs > 2);
+}
+
+#[test]
+fn a_torn_record_is_cut_off() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("packs").to_string_lossy().to_string();
+    let store = PackStore::try_new(path.to_owned()).unwrap();
+    let (first, first_data) = block(1);
+    store
+        .put_block(first.to_owned(), first_data.to_owned())
+        .unwrap();
+    drop(store);
+
+    // A crash in the middle of the next append.
+    let mut pack = OpenOptions::new()
+        .append(true)
+        .open(dir.path().join("packs").join("pack-00000000.pack"))
+        .unwrap();
+    pack.write_all(&[0, 36, 0, 1, 2, 3]).unwrap();
+    drop(pack);
+
+    let store = PackStore::try_new(path).unwrap();
+    assert_eq!(store.get_block(first).unwrap(), first_data);
+    let (second, second_data) = block(2);
+    store
+        .put_block(second.to_owned(), second_data.to_owned())
+        .unwrap();
+    assert_eq!(store.get_block(second).unwrap(), second_data);
+}
+
+#[test]
+fn compaction_drops_deleted_blocks() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("packs").to_string_lossy().to_string();
+    let store = PackStore::with_pack_size(path.to_owned(), 512).unwrap();
+    for i in 0..40 {
+        let (cid, data) = block(i);
+        store.put_block(cid, data).unwrap();
+    }
+    for i in 0..30 {
+        store.delete_block(block(i).0).unwrap();
+    }
+    let before = store.io_metrics().unwrap();
+    assert!(store.compact().unwrap() > 0);
+    // Nothing is left to reclaim.
+    assert_eq!(store.compact().unwrap(), 0);
+    // Compaction copies count as physical writes only.
+    let after = store.io_metrics().unwrap();
+    assert_eq!(after.logical_bytes_written, before.logical_bytes_written);
+    assert!(after.physical_bytes_written > before.physical_bytes_written);
+    assert!(after.fsyncs > before.fsyncs);
+
+    let store = PackStore::with_pack_size(path, 512).unwrap();
+    assert_eq!(store.list_blocks().unwrap().len(), 10);
+    for i in 30..40 {
+        let (cid, data) = block(i);
+        assert_eq!(store.get_block(cid).unwrap(), data);
+    }
+}
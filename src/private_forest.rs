@@ -3,17 +3,19 @@
 
 use async_trait::async_trait;
 use chrono::{prelude::*, Utc};
-use futures::StreamExt;
-use libipld::Cid;
+use futures::{AsyncReadExt, StreamExt};
+use libipld::{Cid, Ipld};
 use rand::{rngs::ThreadRng, thread_rng};
 use rand_chacha::ChaCha12Rng;
 use rand_core::SeedableRng;
 use rsa::{traits::PublicKeyParts, BigUint, Oaep, RsaPrivateKey, RsaPublicKey};
 use std::{
+    collections::{BTreeMap, HashMap, HashSet},
     fs::File,
+    future::Future,
     io::{Read, Write},
     rc::Rc,
-    sync::Mutex,
+    sync::{Arc, Mutex},
     time::SystemTime,
 };
 
@@ -28,17 +30,33 @@ use wnfs::{
     private::{
         forest::{hamt::HamtForest, traits::PrivateForest},
         share::{recipient, sharer},
-        AccessKey, ExchangeKey, PrivateDirectory, PrivateKey, PUBLIC_KEY_EXPONENT,
+        AccessKey, ExchangeKey, PrivateDirectory, PrivateKey, PrivateNode, PUBLIC_KEY_EXPONENT,
     },
     public::{PublicDirectory, PublicLink, PublicNode},
 };
 
 use anyhow::{anyhow, Result};
 use log::trace;
+use serde::{Deserialize, Serialize};
 use sha3::Sha3_256;
+use web_time::Instant;
 
 use crate::blockstore::FFIFriendlyBlockStore;
+use crate::error::{describe, WnfsUtilsError};
+use crate::error_sink::report_result;
+#[cfg(feature = "reqwest")]
+use crate::gateway::{DohConfig, ProxyConfig};
+use crate::gc::{GcCoordinator, WriterId};
+use crate::media_metadata::{MediaMetadata, MediaMetadataOptions};
+use crate::metrics::{
+    render_io_prometheus, render_operations_prometheus, render_prometheus, ForestMetricsSnapshot,
+    HelperStats, OperationMetrics,
+};
+use crate::request_id;
+use crate::vfs::VfsNodeKind;
+#[cfg(not(target_arch = "wasm32"))]
 use tokio::fs::File as TokioFile;
+#[cfg(not(target_arch = "wasm32"))]
 use tokio::io::Result as IoResult;
 
 #[derive(Clone)]
@@ -57,11 +75,97 @@ static mut STATE: Mutex<State> = Mutex::new(State {
     wnfs_key: Vec::new(),
 });
 
+/// Tunables for a `PrivateDirectoryHelper`. The defaults keep the plain write behaviour.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct HelperConfig {
+    /// When set, EXIF/ID3 metadata is parsed from content at write time and stored in the
+    /// file's node metadata.
+    #[serde(default)]
+    pub media_metadata: Option<MediaMetadataOptions>,
+    /// Per-subtree exposure of entry names, as `(subtree path, mode)`. The longest matching
+    /// prefix wins; paths without a rule use `NamePrivacy::Private`.
+    #[serde(default)]
+    pub name_privacy: Vec<(Vec<String>, NamePrivacy)>,
+    /// When set, directories growing past the threshold are split into shards, see
+    /// `DirectorySharding`.
+    #[serde(default)]
+    pub directory_sharding: Option<DirectorySharding>,
+    /// When set, mutations within a time window are coalesced into a single commit, see
+    /// `CommitBatching`.
+    #[serde(default)]
+    pub commit_batching: Option<CommitBatching>,
+    /// When set, paths are normalized to this form before they are looked up or written, see
+    /// `PathNormalization`.
+    #[serde(default)]
+    pub path_normalization: Option<PathNormalization>,
+    /// Chunk sizes picked by file size, see `AdaptiveChunking`. When set, `write_file`, the
+    /// stream writes and imports store files as paged files chunked by it. `None` keeps plain
+    /// files and uses the default policy for paged files created by size.
+    #[serde(default)]
+    pub adaptive_chunking: Option<AdaptiveChunking>,
+    /// When set, `squash_revisions` drops runs of revisions committed in quick succession, see
+    /// `RevisionSquashing`.
+    #[serde(default)]
+    pub revision_squashing: Option<RevisionSquashing>,
+    /// Longest path accepted on write, in bytes of its `/`-joined segments.
+    #[serde(default)]
+    pub max_path_bytes: Option<usize>,
+    /// Longest entry name accepted on write, in bytes, e.g. 255 for names that must fit on
+    /// common local file systems.
+    #[serde(default)]
+    pub max_name_bytes: Option<usize>,
+    /// When set, remote stores built for this helper resolve hostnames over DNS-over-HTTPS,
+    /// see `GatewayStore::with_dns_resolver`.
+    #[cfg(feature = "reqwest")]
+    #[serde(default)]
+    pub dns_over_https: Option<DohConfig>,
+    /// When set, remote stores built for this helper send all traffic through this proxy, see
+    /// `GatewayStore::with_proxy`.
+    #[cfg(feature = "reqwest")]
+    #[serde(default)]
+    pub proxy: Option<ProxyConfig>,
+    /// Whether remote stores built for this helper accept compressed responses, see
+    /// `GatewayStore::with_compression`.
+    #[cfg(feature = "reqwest")]
+    #[serde(default)]
+    pub transfer_compression: bool,
+}
+
+/// Number of forest CIDs kept in `PrivateDirectoryHelper::root_history`.
+pub const ROOT_HISTORY_LIMIT: usize = 1024;
+
 pub struct PrivateDirectoryHelper<'a> {
     pub store: FFIFriendlyBlockStore<'a>,
     forest: Rc<HamtForest>,
     root_dir: Rc<PrivateDirectory>,
     rng: ThreadRng,
+    wnfs_key: Vec<u8>,
+    config: HelperConfig,
+    name_indexes: BTreeMap<Vec<String>, Cid>,
+    // Content CID of each indexed subtree root as its name index was last built.
+    name_index_revisions: BTreeMap<Vec<String>, Cid>,
+    forest_metrics: ForestMetricsSnapshot,
+    legal_holds: Option<Vec<LegalHold>>,
+    root_history: Vec<Cid>,
+    // When the roots of `root_history` were recorded, in milliseconds since the Unix epoch.
+    root_committed_at: HashMap<Cid, i64>,
+    // Roots kept by `squash_revisions`, see `mark_checkpoint`.
+    checkpoints: HashSet<Cid>,
+    gc_writer: Option<(Arc<GcCoordinator>, WriterId)>,
+    forest_state: Option<ForestState>,
+    // When the open commit batching window was opened.
+    pending_commit: Option<Instant>,
+    // Mutations made by this helper, committed or pending.
+    mutations: u64,
+    // Whether a `Transaction` is open, which defers every commit to its own.
+    in_transaction: bool,
+    remote_root: Option<RemoteRootSeen>,
+    content_scanners: Vec<Rc<dyn ContentScanner>>,
+    limiter: OperationLimiter,
+    // Roots returned by this helper's keyed mutations, see `idempotent`.
+    idempotency_roots: HashMap<String, Cid>,
+    // Held for the helper of an `ExclusiveSession`.
+    writer_lock: Option<WriterLock>,
 }
 
 // Single root (private ref) implementation of the wnfs private directory using KVBlockStore.
@@ -72,33 +176,185 @@ impl<'a> PrivateDirectoryHelper<'a> {
         store: &mut FFIFriendlyBlockStore<'a>,
         cid: Cid,
     ) -> Result<PrivateDirectoryHelper<'a>, String> {
-        let initialized: bool;
-        let wnfs_key: Vec<u8>;
+        let state = Self::state();
+        if state.initialized {
+            PrivateDirectoryHelper::load_with_wnfs_key(store, cid, state.wnfs_key)
+                .await
+                .map_err(|e| {
+                    trace!("wnfsError in new: {:?}", e);
+                    e
+                })
+        } else {
+            Err("PrivateDirectoryHelper not initialized".into())
+        }
+    }
+
+    fn from_parts(
+        store: FFIFriendlyBlockStore<'a>,
+        forest: Rc<HamtForest>,
+        root_dir: Rc<PrivateDirectory>,
+        rng: ThreadRng,
+        wnfs_key: Vec<u8>,
+    ) -> Self {
+        Self {
+            store,
+            forest,
+            wnfs_key,
+            root_dir,
+            rng,
+            config: HelperConfig::default(),
+            name_indexes: BTreeMap::new(),
+            name_index_revisions: BTreeMap::new(),
+            forest_metrics: ForestMetricsSnapshot::default(),
+            legal_holds: None,
+            root_history: Vec::new(),
+            root_committed_at: HashMap::new(),
+            checkpoints: HashSet::new(),
+            gc_writer: None,
+            forest_state: None,
+            pending_commit: None,
+            mutations: 0,
+            in_transaction: false,
+            remote_root: None,
+            content_scanners: Vec::new(),
+            limiter: OperationLimiter::default(),
+            idempotency_roots: HashMap::new(),
+            writer_lock: None,
+        }
+    }
+
+    // The global state survives a panic of another thread holding the lock, so a poisoned
+    // mutex is read anyway instead of propagating the panic into the host app.
+    fn state() -> State {
         unsafe {
-            initialized = STATE.lock().unwrap().initialized;
-            wnfs_key = STATE.lock().unwrap().wnfs_key.to_owned();
+            match STATE.lock() {
+                Ok(state) => state.to_owned(),
+                Err(poisoned) => poisoned.into_inner().to_owned(),
+            }
         }
-        if initialized {
-            let helper_res =
-                PrivateDirectoryHelper::load_with_wnfs_key(store, cid, wnfs_key.to_owned()).await;
-            if helper_res.is_ok() {
-                Ok(helper_res.ok().unwrap())
-            } else {
-                trace!(
-                    "wnfsError in new: {:?}",
-                    helper_res.as_ref().err().unwrap().to_string()
-                );
-                Err(helper_res.err().unwrap().to_string())
+    }
+
+    fn update_state(wnfs_key: Vec<u8>) {
+        unsafe {
+            match STATE.lock() {
+                Ok(mut state) => state.update(true, wnfs_key),
+                Err(poisoned) => poisoned.into_inner().update(true, wnfs_key),
             }
-        } else {
-            Err("PrivateDirectoryHelper not initialized".into())
         }
     }
 
+    fn seed_from_key(wnfs_key: &[u8]) -> Result<[u8; 32], String> {
+        wnfs_key
+            .try_into()
+            .map_err(|_| WnfsUtilsError::InvalidKeyLength(wnfs_key.len()).to_string())
+    }
+
+    fn modification_time(modification_time_seconds: i64) -> Result<DateTime<Utc>, String> {
+        if modification_time_seconds <= 0 {
+            return Ok(Utc::now());
+        }
+        let naive_datetime = NaiveDateTime::from_timestamp_opt(modification_time_seconds, 0)
+            .ok_or_else(|| {
+                WnfsUtilsError::InvalidTimestamp(modification_time_seconds).to_string()
+            })?;
+        Ok(DateTime::from_naive_utc_and_offset(naive_datetime, Utc))
+    }
+
     fn bytes_to_hex_str(bytes: &[u8]) -> String {
         bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
     }
 
+    /// Forest CIDs this helper was loaded at or committed, oldest first. Only the latest
+    /// `ROOT_HISTORY_LIMIT` are kept, or fewer with `RevisionSquashing::max_revisions`.
+    pub fn root_history(&self) -> &[Cid] {
+        &self.root_history
+    }
+
+    fn record_root(&mut self, forest_cid: Cid) {
+        if self.root_history.last() != Some(&forest_cid) {
+            self.root_history.push(forest_cid);
+            self.root_committed_at
+                .insert(forest_cid, Utc::now().timestamp_millis());
+        }
+        self.advance_forest_state(forest_cid);
+        let limit = self.revision_limit();
+        if self.root_history.len() > limit {
+            let excess = self.root_history.len() - limit;
+            let dropped: Vec<Cid> = self.root_history.drain(..excess).collect();
+            self.forget_roots(&dropped);
+        }
+    }
+
+    pub fn config(&self) -> &HelperConfig {
+        &self.config
+    }
+
+    pub fn set_config(&mut self, config: HelperConfig) {
+        // Name privacy rules shape the indexes, so they are all rebuilt on the next commit.
+        self.name_index_revisions.clear();
+        self.config = config;
+    }
+
+    pub fn forest_metrics(&self) -> ForestMetricsSnapshot {
+        self.forest_metrics
+    }
+
+    /// The store, forest and backend counters with the latency of each operation run so far.
+    pub fn stats(&self) -> HelperStats {
+        HelperStats {
+            store: self.store.metrics(),
+            forest: self.forest_metrics,
+            io: self.store.io_metrics(),
+            operations: self.limiter.metrics().snapshot(),
+        }
+    }
+
+    /// Records that the app published the latest forest CID (e.g. to a pointer service), which
+    /// resets the reported sync lag and keeps the root as a checkpoint, see `squash_revisions`.
+    /// A remote root this helper hasn't loaded or merged stays a remote change: publishing over
+    /// it doesn't bring its changes along.
+    pub fn mark_published(&mut self) {
+        self.forest_metrics.last_published = Some(Utc::now());
+        self.mark_checkpoint();
+        if let Some((coordinator, writer)) = &self.gc_writer {
+            coordinator.advance_epoch(*writer);
+        }
+    }
+
+    /// Registers this helper as a writer with `coordinator`, so a concurrent
+    /// `gc::collect_garbage` keeps the blocks it wrote since its last `mark_published`.
+    pub fn set_gc_coordinator(&mut self, coordinator: Arc<GcCoordinator>) {
+        if let Some((previous, writer)) = self.gc_writer.take() {
+            previous.unregister_writer(writer);
+        }
+        let writer = coordinator.register_writer();
+        self.gc_writer = Some((coordinator, writer));
+    }
+
+    /// Store, forest, backend I/O and operation metrics in the Prometheus text exposition
+    /// format.
+    pub fn prometheus_metrics(&self) -> String {
+        let mut text = render_prometheus(&self.store.metrics(), &self.forest_metrics);
+        if let Some(io) = self.store.io_metrics() {
+            text.push_str(&render_io_prometheus(&io));
+        }
+        text.push_str(&render_operations_prometheus(
+            &self.limiter.metrics().snapshot(),
+        ));
+        text
+    }
+
+    // Media metadata entries to record for `content`, empty unless extraction is enabled.
+    fn media_metadata_entries<R: std::io::BufRead + std::io::Seek>(
+        &self,
+        content: &mut R,
+    ) -> Vec<(String, Ipld)> {
+        match &self.config.media_metadata {
+            Some(options) => MediaMetadata::extract(content, options).to_entries(),
+            None => Vec::new(),
+        }
+    }
+
     async fn setup_seeded_keypair_access(
         forest: &mut Rc<HamtForest>,
         access_key: AccessKey,
@@ -150,7 +406,20 @@ impl<'a> PrivateDirectoryHelper<'a> {
         Ok(seed)
     }
 
-    async fn init(
+    /// Creates a new forest shared with `wnfs_key`, committed as the helper's first root. The
+    /// helper doesn't lock the forest; outside the crate it's opened with `init_exclusive`.
+    pub(crate) async fn init(
+        store: &mut FFIFriendlyBlockStore<'a>,
+        wnfs_key: Vec<u8>,
+    ) -> Result<(PrivateDirectoryHelper<'a>, AccessKey, Cid), String> {
+        let created = Self::create_forest(store, wnfs_key.to_owned()).await?;
+        Self::update_state(wnfs_key);
+        Ok(created)
+    }
+
+    // `init` without switching the global state to `wnfs_key`, for callers that do it once
+    // they committed, see `rotate_wnfs_key`.
+    async fn create_forest(
         store: &mut FFIFriendlyBlockStore<'a>,
         wnfs_key: Vec<u8>,
     ) -> Result<(PrivateDirectoryHelper<'a>, AccessKey, Cid), String> {
@@ -161,193 +430,175 @@ impl<'a> PrivateDirectoryHelper<'a> {
             return Err(err);
         }
 
-        let forest_res = PrivateDirectoryHelper::create_private_forest(store.to_owned(), rng).await;
-
-        if forest_res.is_ok() {
-            let (forest, _) = &mut forest_res.ok().unwrap();
-            let root_dir_res = PrivateDirectory::new_and_store(
-                &forest.empty_name(),
-                Utc::now(),
-                forest,
-                store,
-                rng,
-            )
-            .await;
-
-            if root_dir_res.is_ok() {
-                // Private ref contains data and keys for fetching and decrypting the directory node in the private forest.
-                let root_dir = &mut root_dir_res.ok().unwrap();
-                let access_key = root_dir.as_node().store(forest, store, rng).await;
-                if access_key.is_ok() {
-                    let seed: [u8; 32] = wnfs_key.to_owned().try_into().expect("Length mismatch");
-                    let access_key_unwrapped = access_key.ok().unwrap();
-                    let seed_res = Self::setup_seeded_keypair_access(
-                        forest,
-                        access_key_unwrapped.to_owned(),
-                        store,
-                        seed,
-                    )
-                    .await;
-                    let forest_cid = PrivateDirectoryHelper::update_private_forest(
-                        store.to_owned(),
-                        forest.to_owned(),
-                    )
-                    .await;
-                    if forest_cid.is_ok() {
-                        unsafe {
-                            STATE.lock().unwrap().update(true, wnfs_key.to_owned());
-                        }
-                        Ok((
-                            Self {
-                                store: store.to_owned(),
-                                forest: forest.to_owned(),
-                                root_dir: root_dir.to_owned(),
-                                rng: rng.to_owned(),
-                            },
-                            access_key_unwrapped,
-                            forest_cid.unwrap(),
-                        ))
-                    } else {
-                        trace!(
-                            "wnfsError in init:setup_seeded_keypair_access : {:?}",
-                            seed_res.as_ref().err().unwrap().to_string()
-                        );
-                        Err(seed_res.err().unwrap().to_string())
-                    }
-                } else {
-                    trace!(
-                        "wnfsError in init: {:?}",
-                        access_key.as_ref().err().unwrap().to_string()
-                    );
-                    Err(access_key.err().unwrap().to_string())
-                }
-            } else {
+        let seed = Self::seed_from_key(&wnfs_key).map_err(|e| {
+            trace!("wnfsError occured in init: {:?}", e);
+            e
+        })?;
+
+        let (forest, _) = &mut PrivateDirectoryHelper::create_private_forest(store.to_owned(), rng)
+            .await
+            .map_err(|e| {
+                trace!("wnfsError occured in init: {:?}", e);
+                e
+            })?;
+        let root_dir = &mut PrivateDirectory::new_and_store(
+            &forest.empty_name(),
+            Utc::now(),
+            forest,
+            store,
+            rng,
+        )
+        .await
+        .map_err(|e| {
+            trace!("wnfsError occured in init: {:?}", e.to_string());
+            describe(&e)
+        })?;
+
+        // Private ref contains data and keys for fetching and decrypting the directory node in the private forest.
+        let access_key = root_dir
+            .as_node()
+            .store(forest, store, rng)
+            .await
+            .map_err(|e| {
+                trace!("wnfsError in init: {:?}", e.to_string());
+                describe(&e)
+            })?;
+        Self::setup_seeded_keypair_access(forest, access_key.to_owned(), store, seed)
+            .await
+            .map_err(|e| {
                 trace!(
-                    "wnfsError occured in init: {:?}",
-                    root_dir_res.as_ref().to_owned().err().unwrap().to_string()
+                    "wnfsError in init:setup_seeded_keypair_access : {:?}",
+                    e.to_string()
                 );
-                Err(root_dir_res.as_ref().to_owned().err().unwrap().to_string())
-            }
-        } else {
-            let err = forest_res.as_ref().to_owned().err().unwrap().to_string();
-            trace!("wnfsError occured in init: {:?}", err);
-            Err(err)
-        }
+                describe(&e)
+            })?;
+        let forest_cid =
+            PrivateDirectoryHelper::update_private_forest(store.to_owned(), forest.to_owned())
+                .await?;
+        let mut helper = Self::from_parts(
+            store.to_owned(),
+            forest.to_owned(),
+            root_dir.to_owned(),
+            rng.to_owned(),
+            wnfs_key,
+        );
+        helper.record_root(forest_cid);
+        Ok((helper, access_key, forest_cid))
     }
 
-    pub async fn load_with_wnfs_key(
+    // Doesn't lock the forest; outside the crate it's opened with `load_exclusive` or
+    // `load_shared`.
+    pub(crate) async fn load_with_wnfs_key(
         store: &mut FFIFriendlyBlockStore<'a>,
         forest_cid: Cid,
         wnfs_key: Vec<u8>,
     ) -> Result<PrivateDirectoryHelper<'a>, String> {
         trace!("wnfsutils: load_with_wnfs_key started");
         let rng = &mut thread_rng();
-        let root_did: String;
-        let seed: [u8; 32];
         if wnfs_key.is_empty() {
             let err = "wnfskey is empty".to_string();
             trace!("wnfsError occured in load_with_wnfs_key: {:?}", err);
             return Err(err);
-        } else {
-            root_did = Self::bytes_to_hex_str(&wnfs_key);
-            seed = wnfs_key.to_owned().try_into().expect("Length mismatch");
         }
-        let exchange_keypair_res = SeededExchangeKey::from_seed(seed);
-        if exchange_keypair_res.is_ok() {
-            let exchange_keypair = exchange_keypair_res.ok().unwrap();
+        let exchange_keypair = Self::exchange_keypair(&wnfs_key)?;
+        let (forest, latest_root_dir) =
+            Self::load_root_dir(store, forest_cid, &wnfs_key, &exchange_keypair).await?;
+        Self::update_state(wnfs_key.to_owned());
+        let mut helper = Self::from_parts(
+            store.to_owned(),
+            forest,
+            latest_root_dir,
+            rng.to_owned(),
+            wnfs_key,
+        );
+        helper.restore_revisions().await?;
+        helper.record_root(forest_cid);
+        Ok(helper)
+    }
+
+    // Deriving the exchange key pair is slow, so callers loading several roots derive it once.
+    fn exchange_keypair(wnfs_key: &[u8]) -> Result<SeededExchangeKey, String> {
+        let seed = Self::seed_from_key(wnfs_key).map_err(|e| {
+            trace!("wnfsError occured in load_with_wnfs_key: {:?}", e);
+            e
+        })?;
+        SeededExchangeKey::from_seed(seed).map_err(|e| {
             trace!(
-                "wnfsutils: load_with_wnfs_key with forest_cid: {:?}",
-                forest_cid
+                "wnfsError occured in load_with_wnfs_key exchange_keypair_res: {:?}",
+                e.to_string()
             );
-            let forest_res =
-                PrivateDirectoryHelper::load_private_forest(store.to_owned(), forest_cid).await;
-            if forest_res.is_ok() {
-                let forest = &mut forest_res.ok().unwrap();
-                // Re-load private node from forest
-                let counter_res = recipient::find_latest_share_counter(
-                    0,
-                    1000,
-                    &exchange_keypair.encode_public_key(),
-                    &root_did,
-                    forest,
-                    store,
-                )
-                .await;
-                if counter_res.is_ok() {
-                    let counter = counter_res.ok().unwrap().map(|x| x).unwrap_or_default();
-                    trace!("wnfsutils: load_with_wnfs_key with counter: {:?}", counter);
-                    let name = sharer::create_share_name(
-                        counter,
-                        &root_did,
-                        &exchange_keypair.encode_public_key(),
-                        forest,
-                    );
-                    let node_res =
-                        recipient::receive_share(&name, &exchange_keypair, forest, store).await;
-                    if node_res.is_ok() {
-                        let node = node_res.ok().unwrap();
-                        let latest_node = node.search_latest(forest, store).await;
-
-                        if latest_node.is_ok() {
-                            let latest_root_dir = latest_node.ok().unwrap().as_dir();
-                            if latest_root_dir.is_ok() {
-                                unsafe {
-                                    STATE.lock().unwrap().update(true, wnfs_key.to_owned());
-                                }
-                                Ok(Self {
-                                    store: store.to_owned(),
-                                    forest: forest.to_owned(),
-                                    root_dir: latest_root_dir.ok().unwrap(),
-                                    rng: rng.to_owned(),
-                                })
-                            } else {
-                                trace!(
-                                    "wnfsError in load_with_wnfs_key: {:?}",
-                                    latest_root_dir.as_ref().err().unwrap().to_string()
-                                );
-                                Err(latest_root_dir.err().unwrap().to_string())
-                            }
-                        } else {
-                            trace!(
-                                "wnfsError occured in load_with_wnfs_key: {:?}",
-                                latest_node.as_ref().to_owned().err().unwrap().to_string()
-                            );
-                            Err(latest_node.as_ref().to_owned().err().unwrap().to_string())
-                        }
-                    } else {
-                        let err = node_res.as_ref().to_owned().err().unwrap().to_string();
-                        trace!(
-                            "wnfsError occured in load_with_wnfs_key node_res: {:?}",
-                            err
-                        );
-                        Err(err)
-                    }
-                } else {
-                    let err = counter_res.as_ref().to_owned().err().unwrap().to_string();
-                    trace!(
-                        "wnfsError occured in load_with_wnfs_key counter_res: {:?}",
-                        err
-                    );
-                    Err(err)
-                }
-            } else {
-                let err = forest_res.as_ref().to_owned().err().unwrap().to_string();
-                trace!("wnfsError occured in load_with_wnfs_key: {:?}", err);
-                Err(err)
-            }
-        } else {
-            let err = exchange_keypair_res
-                .as_ref()
-                .to_owned()
-                .err()
-                .unwrap()
-                .to_string();
+            describe(&e)
+        })
+    }
+
+    // The forest at `forest_cid` and the latest root directory shared in it with `wnfs_key`.
+    async fn load_root_dir(
+        store: &mut FFIFriendlyBlockStore<'a>,
+        forest_cid: Cid,
+        wnfs_key: &[u8],
+        exchange_keypair: &SeededExchangeKey,
+    ) -> Result<(Rc<HamtForest>, Rc<PrivateDirectory>), String> {
+        let root_did = Self::bytes_to_hex_str(wnfs_key);
+        trace!(
+            "wnfsutils: load_with_wnfs_key with forest_cid: {:?}",
+            forest_cid
+        );
+        let forest = &mut PrivateDirectoryHelper::load_private_forest(store.to_owned(), forest_cid)
+            .await
+            .map_err(|e| {
+                trace!("wnfsError occured in load_with_wnfs_key: {:?}", e);
+                e
+            })?;
+        // Re-load private node from forest
+        let counter = recipient::find_latest_share_counter(
+            0,
+            1000,
+            &exchange_keypair.encode_public_key(),
+            &root_did,
+            forest,
+            store,
+        )
+        .await
+        .map_err(|e| {
             trace!(
-                "wnfsError occured in load_with_wnfs_key exchange_keypair_res: {:?}",
-                err
+                "wnfsError occured in load_with_wnfs_key counter_res: {:?}",
+                describe(&e)
             );
-            Err(err)
-        }
+            describe(&e)
+        })?
+        .unwrap_or_default();
+        trace!("wnfsutils: load_with_wnfs_key with counter: {:?}", counter);
+        let name = sharer::create_share_name(
+            counter,
+            &root_did,
+            &exchange_keypair.encode_public_key(),
+            forest,
+        );
+        let node = recipient::receive_share(&name, exchange_keypair, forest, store)
+            .await
+            .map_err(|e| {
+                trace!(
+                    "wnfsError occured in load_with_wnfs_key node_res: {:?}",
+                    describe(&e)
+                );
+                describe(&e)
+            })?;
+        let latest_root_dir = node
+            .search_latest(forest, store)
+            .await
+            .map_err(|e| {
+                trace!(
+                    "wnfsError occured in load_with_wnfs_key: {:?}",
+                    describe(&e)
+                );
+                describe(&e)
+            })?
+            .as_dir()
+            .map_err(|e| {
+                trace!("wnfsError in load_with_wnfs_key: {:?}", e.to_string());
+                describe(&e)
+            })?;
+        Ok((forest.to_owned(), latest_root_dir))
     }
 
     async fn create_private_forest(
@@ -361,16 +612,12 @@ impl<'a> PrivateDirectoryHelper<'a> {
         let forest = &mut HamtForest::new_rc(setup);
 
         // Doing this will give us a single root CID
-        let private_root_cid = store.put_async_serializable(forest).await;
-        if private_root_cid.is_ok() {
-            let ret_forest = Rc::clone(forest);
-            Ok((ret_forest, private_root_cid.ok().unwrap()))
-        } else {
-            trace!(
-                "wnfsError occured in create_private_forest: {:?}",
-                private_root_cid.as_ref().err().unwrap()
-            );
-            Err(private_root_cid.err().unwrap().to_string())
+        match store.put_async_serializable(forest).await {
+            Ok(private_root_cid) => Ok((Rc::clone(forest), private_root_cid)),
+            Err(e) => {
+                trace!("wnfsError occured in create_private_forest: {:?}", e);
+                Err(describe(&e))
+            }
         }
     }
 
@@ -380,15 +627,12 @@ impl<'a> PrivateDirectoryHelper<'a> {
     ) -> Result<Rc<HamtForest>, String> {
         trace!("load_private_forest called with {:?}", forest_cid);
         // Deserialize private forest from the blockstore.
-        let forest = store.get_deserializable::<HamtForest>(&forest_cid).await;
-        if forest.is_ok() {
-            Ok(Rc::new(forest.ok().unwrap()))
-        } else {
-            trace!(
-                "wnfsError occured in load__private_forest: {:?}",
-                forest.as_ref().err().unwrap()
-            );
-            Err(forest.err().unwrap().to_string())
+        match store.get_deserializable::<HamtForest>(&forest_cid).await {
+            Ok(forest) => Ok(Rc::new(forest)),
+            Err(e) => {
+                trace!("wnfsError occured in load__private_forest: {:?}", e);
+                Err(describe(&e))
+            }
         }
     }
 
@@ -398,47 +642,238 @@ impl<'a> PrivateDirectoryHelper<'a> {
     ) -> Result<Cid, String> {
         // Serialize the private forest to DAG CBOR.
         // Doing this will give us a single root CID
-        let private_root_cid = store.put_async_serializable(&forest).await;
-        if private_root_cid.is_ok() {
-            Ok(private_root_cid.ok().unwrap())
-        } else {
+        store.put_async_serializable(&forest).await.map_err(|e| {
+            trace!("wnfsError occured in create_private_forest: {:?}", e);
+            describe(&e)
+        })
+    }
+
+    // Stores the current root directory and serializes the forest, without touching the node tree.
+    // Used by operations that batch several mutations before producing a single new forest CID.
+    // Within an open commit batching window the commit is deferred, see `CommitBatching`, and
+    // within a `Transaction` until it commits.
+    async fn commit(&mut self) -> Result<Cid, String> {
+        self.mutations += 1;
+        if self.in_transaction {
+            if let Some(root) = self.root_history.last() {
+                return Ok(*root);
+            }
+        }
+        if let Some(root) = self.defer_commit() {
+            return Ok(root);
+        }
+        self.commit_now().await
+    }
+
+    // `commit` regardless of batching, for operations handing out the root they committed.
+    // The blocks of the commit reach the backend in one `put_many`.
+    async fn commit_now(&mut self) -> Result<Cid, String> {
+        self.store.begin_batch();
+        let stored = self.store_forest().await;
+        let flushed = self.store.flush_batch().await.map_err(|e| {
+            trace!("wnfsError in commit: {:?}", e.to_string());
+            describe(&e)
+        });
+        let forest_cid = stored?;
+        flushed?;
+        self.pending_commit = None;
+        self.forest_metrics.commits += 1;
+        self.forest_metrics.last_commit = Some(Utc::now());
+        self.record_root(forest_cid);
+        self.refresh_name_indexes().await?;
+        Ok(forest_cid)
+    }
+
+    async fn store_forest(&mut self) -> Result<Cid, String> {
+        self.root_dir
+            .as_node()
+            .store(&mut self.forest, &mut self.store, &mut self.rng)
+            .await
+            .map_err(|e| {
+                trace!("wnfsError in commit: {:?}", e.to_string());
+                describe(&e)
+            })?;
+        PrivateDirectoryHelper::update_private_forest(self.store.to_owned(), self.forest.to_owned())
+            .await
+    }
+
+    // Looks up the node at the given path, returning `None` if nothing exists there.
+    async fn node_at(&mut self, path_segments: &[String]) -> Result<Option<PrivateNode>, String> {
+        self.check_path_depth(path_segments)?;
+        let path_segments = self.resolve_path(path_segments).await?;
+        self.raw_node_at(&path_segments).await
+    }
+
+    // `node_at` for a path as stored in the forest, see `resolve_path`.
+    async fn raw_node_at(
+        &mut self,
+        path_segments: &[String],
+    ) -> Result<Option<PrivateNode>, String> {
+        if path_segments.is_empty() {
+            return Ok(Some(PrivateNode::Dir(self.root_dir.to_owned())));
+        }
+        let forest = &mut self.forest;
+        let root_dir = &mut self.root_dir;
+        root_dir
+            .get_node(path_segments, true, forest, &mut self.store)
+            .await
+            .map_err(|e| {
+                trace!("wnfsError in node_at: {:?}", e.to_string());
+                describe(&e)
+            })
+    }
+
+    // The message of a WNFS operation on `path_segments`, as stored in the forest, that failed
+    // with `error`. It carries `NotFound` if nothing is stored there, see `error`.
+    async fn wnfs_failure(&mut self, path_segments: &[String], error: anyhow::Error) -> String {
+        match self.raw_node_at(path_segments).await {
+            Ok(None) => WnfsUtilsError::NotFound(path_segments.join("/")).to_string(),
+            _ => describe(&error),
+        }
+    }
+
+    fn check_path_depth(&self, path_segments: &[String]) -> Result<(), String> {
+        self.store
+            .decode_limits()
+            .check_path_depth(path_segments.len())
+            .map_err(|e| {
+                trace!("wnfsError in check_path_depth: {:?}", e.to_string());
+                describe(&e)
+            })
+    }
+
+    pub(super) fn check_dir_entries(&self, count: usize) -> Result<(), String> {
+        self.store
+            .decode_limits()
+            .check_dir_entries(count)
+            .map_err(|e| {
+                trace!("wnfsError in check_dir_entries: {:?}", e.to_string());
+                describe(&e)
+            })
+    }
+
+    // Checks a path about to be written to: within the depth limit and without empty names,
+    // which the forest would store but no parsed path could reach. Only directories can be
+    // created at the root itself.
+    fn check_write_path(&self, path_segments: &[String], is_file: bool) -> Result<(), String> {
+        self.check_path_depth(path_segments)?;
+        self.check_path_length(path_segments)?;
+        let reason = match path_segments {
+            [] if is_file => "a file can't be written at the root",
+            _ if path_segments.iter().any(|segment| segment.is_empty()) => {
+                "path segments can't be empty"
+            }
+            // Written by the helper alone, as they change what their directory is.
+            _ if path_segments
+                .iter()
+                .any(|segment| Self::is_marker_name(segment)) =>
+            {
+                "names of paged file and shard markers are reserved"
+            }
+            _ => return Ok(()),
+        };
+        trace!(
+            "wnfsError in check_write_path: {:?} {}",
+            path_segments,
+            reason
+        );
+        Err(WnfsUtilsError::InvalidPath {
+            path: path_segments.join("/"),
+            reason: reason.to_string(),
+        }
+        .to_string())
+    }
+
+    // Merges `entries` into the metadata of the file at `path_segments` without committing.
+    // `time` is recorded as the file's modification time.
+    async fn put_file_metadata(
+        &mut self,
+        path_segments: &[String],
+        entries: Vec<(String, Ipld)>,
+        time: DateTime<Utc>,
+    ) -> Result<(), String> {
+        // Paged files keep their metadata on the layout marker.
+        let path_segments = match self.is_paged_file(path_segments).await? {
+            true => Self::paged_marker_path(path_segments),
+            false => path_segments.to_vec(),
+        };
+        let path_segments = &self.resolve_path(&path_segments).await?;
+        let forest = &mut self.forest;
+        let root_dir = &mut self.root_dir;
+        let file = root_dir
+            .open_file_mut(
+                path_segments,
+                true,
+                time,
+                forest,
+                &mut self.store,
+                &mut self.rng,
+            )
+            .await
+            .map_err(|e| {
+                trace!("wnfsError in put_file_metadata: {:?}", e.to_string());
+                describe(&e)
+            })?;
+        let metadata = file.get_metadata_mut();
+        for (key, value) in entries {
+            metadata.put(&key, value);
+        }
+        Ok(())
+    }
+
+    // Walks the subtree rooted at `path_segments` and returns its directories and files,
+    // both as full logical paths. Directories are listed parents first; the root itself is
+    // included when it is a directory. Paged files are listed as files, and the helper's
+    // reserved entries are left out.
+    async fn collect_subtree(
+        &mut self,
+        path_segments: &[String],
+    ) -> Result<(Vec<Vec<String>>, Vec<Vec<String>>), String> {
+        if self.node_at(path_segments).await?.is_none() {
             trace!(
-                "wnfsError occured in create_private_forest: {:?}",
-                private_root_cid.as_ref().err().unwrap()
+                "wnfsError in collect_subtree: no node at {:?}",
+                path_segments
             );
-            Err(private_root_cid.err().unwrap().to_string())
+            return Err(WnfsUtilsError::NotFound(format!("{:?}", path_segments)).to_string());
         }
+
+        let mut dirs: Vec<Vec<String>> = Vec::new();
+        let mut files: Vec<Vec<String>> = Vec::new();
+        let mut entries = self.walk(path_segments, WalkOptions::default());
+        while let Some(entry) = entries.next().await {
+            let entry = entry?;
+            match entry.stat.kind {
+                VfsNodeKind::Directory => dirs.push(entry.path),
+                VfsNodeKind::File => files.push(entry.path),
+            }
+        }
+        Ok((dirs, files))
     }
 
     fn get_file_as_byte_vec(&mut self, filename: &String) -> Result<(Vec<u8>, i64), String> {
-        let f = File::open(&filename);
-        if f.is_ok() {
-            let metadata_res = std::fs::metadata(&filename);
-            if metadata_res.is_ok() {
-                let metadata = metadata_res.ok().unwrap();
-                let modification_time = metadata.modified().unwrap_or(SystemTime::now());
-                let modification_time_seconds = modification_time
-                    .duration_since(SystemTime::UNIX_EPOCH)
-                    .unwrap_or_default()
-                    .as_secs() as i64;
-
-                let mut buffer = vec![0; metadata.len() as usize];
-                f.ok().unwrap().read(&mut buffer).expect("buffer overflow");
-                Ok((buffer, modification_time_seconds))
-            } else {
-                trace!(
-                    "wnfsError in get_file_as_byte_vec, unable to read metadata: {:?}",
-                    metadata_res.err().unwrap()
-                );
-                Err("wnfsError unable to read metadata".to_string())
-            }
-        } else {
+        let mut f = File::open(&filename).map_err(|e| {
+            trace!("wnfsError in get_file_as_byte_vec, no file found: {:?}", e);
+            "wnfsError no file found".to_string()
+        })?;
+        let metadata = std::fs::metadata(&filename).map_err(|e| {
             trace!(
-                "wnfsError in get_file_as_byte_vec, no file found: {:?}",
-                f.err().unwrap()
+                "wnfsError in get_file_as_byte_vec, unable to read metadata: {:?}",
+                e
             );
-            Err("wnfsError no file found".to_string())
-        }
+            "wnfsError unable to read metadata".to_string()
+        })?;
+        let modification_time = metadata.modified().unwrap_or(SystemTime::now());
+        let modification_time_seconds = modification_time
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs() as i64;
+
+        let mut buffer = Vec::with_capacity(metadata.len() as usize);
+        f.read_to_end(&mut buffer).map_err(|e| {
+            trace!("wnfsError in get_file_as_byte_vec, unable to read: {:?}", e);
+            describe(&e)
+        })?;
+        Ok((buffer, modification_time_seconds))
     }
 
     pub async fn write_file_from_path(
@@ -446,33 +881,26 @@ impl<'a> PrivateDirectoryHelper<'a> {
         path_segments: &[String],
         filename: &String,
     ) -> Result<Cid, String> {
-        let content: Vec<u8>;
-        let modification_time_seconds: i64;
-        let try_content = self.get_file_as_byte_vec(filename);
-        if try_content.is_ok() {
-            (content, modification_time_seconds) = try_content.ok().unwrap();
-            let writefile_res = self
-                .write_file(path_segments, content, modification_time_seconds)
-                .await;
-            if writefile_res.is_ok() {
-                Ok(writefile_res.ok().unwrap())
-            } else {
-                trace!(
-                    "wnfsError in write_file_from_path: {:?}",
-                    writefile_res.as_ref().err().unwrap()
-                );
-                Err(writefile_res.err().unwrap())
-            }
-        } else {
-            trace!(
-                "wnfsError in write_file_from_path: {:?}",
-                try_content.as_ref().err().unwrap()
-            );
-            Err(try_content.err().unwrap())
-        }
+        let metrics = self.limiter.metrics().to_owned();
+        metrics
+            .timed("write_file_from_path", async move {
+                let (content, modification_time_seconds) =
+                    self.get_file_as_byte_vec(filename).map_err(|e| {
+                        trace!("wnfsError in write_file_from_path: {:?}", e);
+                        e
+                    })?;
+                self.write_file(path_segments, content, modification_time_seconds)
+                    .await
+                    .map_err(|e| {
+                        trace!("wnfsError in write_file_from_path: {:?}", e);
+                        e
+                    })
+            })
+            .await
     }
 
     // The new get_file_as_stream method:
+    #[cfg(not(target_arch = "wasm32"))]
     pub async fn get_file_as_stream(&self, filename: &String) -> IoResult<(TokioFile, i64)> {
         let file = TokioFile::open(filename).await?;
         let metadata = tokio::fs::metadata(filename).await?;
@@ -492,38 +920,49 @@ impl<'a> PrivateDirectoryHelper<'a> {
         path_segments: &[String],
         filename: &String,
     ) -> Result<Cid, String> {
-        let filedata = async_std::fs::File::open(filename).await;
-        if let Ok(file) = filedata {
-            let metadata = file.metadata().await;
-            if metadata.is_err() {
-                return Err(format!(
-                    "Failed to get file metadata: {:?}",
-                    metadata.err().unwrap()
-                ));
-            }
-            let modification_time_seconds = metadata
-                .unwrap()
-                .modified()
-                .unwrap()
-                .duration_since(SystemTime::UNIX_EPOCH)
-                .unwrap()
-                .as_secs() as i64;
-            let mut reader = async_std::io::BufReader::new(file);
-            let writefile_res = self
-                .write_file_stream(path_segments, &mut reader, modification_time_seconds)
-                .await;
-            match writefile_res {
-                Ok(res) => Ok(res),
-                Err(e) => {
-                    trace!("wnfsError in write_file_stream_from_path: {:?}", e);
-                    Err(e.to_string())
+        let metrics = self.limiter.metrics().to_owned();
+        metrics
+            .timed("write_file_stream_from_path", async move {
+                let filedata = open_local_file(std::path::Path::new(filename)).await;
+                match filedata {
+                    Ok((mut reader, modified)) => {
+                        // Files without a usable mtime (unsupported platform, pre-epoch) get the current time.
+                        let modification_time_seconds = modified
+                            .and_then(|modified| {
+                                modified.duration_since(SystemTime::UNIX_EPOCH).ok()
+                            })
+                            .map(|duration| duration.as_secs() as i64)
+                            .unwrap_or_default();
+                        let media_entries = match &self.config.media_metadata {
+                            Some(options) => {
+                                MediaMetadata::from_path(std::path::Path::new(filename), options)
+                                    .to_entries()
+                            }
+                            None => Vec::new(),
+                        };
+                        let writefile_res = self
+                            .write_file_stream_with_metadata(
+                                path_segments,
+                                &mut reader,
+                                modification_time_seconds,
+                                media_entries,
+                            )
+                            .await;
+                        match writefile_res {
+                            Ok(res) => Ok(res),
+                            Err(e) => {
+                                trace!("wnfsError in write_file_stream_from_path: {:?}", e);
+                                Err(describe(&e))
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        trace!("wnfsError in write_file_stream_from_path: {:?}", e);
+                        Err(describe(&e))
+                    }
                 }
-            }
-        } else {
-            let e = filedata.err().unwrap();
-            trace!("wnfsError in write_file_stream_from_path: {:?}", e);
-            Err(e.to_string())
-        }
+            })
+            .await
     }
 
     fn write_byte_vec_to_file(
@@ -532,30 +971,26 @@ impl<'a> PrivateDirectoryHelper<'a> {
         file_content: Vec<u8>,
     ) -> Result<bool, String> {
         trace!("wnfs11 **********************write_byte_vec_to_file started**************filename={:?}", filename);
-        trace!("wnfs11 **********************write_byte_vec_to_file started**************file_content={:?}", file_content);
-        let file = File::create(filename);
-        if file.is_ok() {
-            let mut file_handler = file.ok().unwrap();
+        trace!(
+            "wnfs11 **********************write_byte_vec_to_file started**************bytes={}",
+            file_content.len()
+        );
+        let mut file_handler = File::create(filename).map_err(|e| {
             trace!(
-                "wnfs11 **********************write_byte_vec_to_file write created**************"
+                "wnfsError occured in write_byte_vec_to_file on file {:?}",
+                e.to_string()
             );
-            let write_res = file_handler.write_all(&file_content);
-            if write_res.is_ok() {
-                Ok(true)
-            } else {
-                trace!(
-                    "wnfsError occured in write_byte_vec_to_file on write_res {:?}",
-                    write_res.as_ref().err().unwrap().to_string()
-                );
-                Err(write_res.err().unwrap().to_string())
-            }
-        } else {
+            describe(&e)
+        })?;
+        trace!("wnfs11 **********************write_byte_vec_to_file write created**************");
+        file_handler.write_all(&file_content).map_err(|e| {
             trace!(
-                "wnfsError occured in write_byte_vec_to_file on file {:?}",
-                file.as_ref().err().unwrap().to_string()
+                "wnfsError occured in write_byte_vec_to_file on write_res {:?}",
+                e.to_string()
             );
-            Err(file.err().unwrap().to_string())
-        }
+            describe(&e)
+        })?;
+        Ok(true)
     }
 
     pub async fn write_file(
@@ -565,141 +1000,209 @@ impl<'a> PrivateDirectoryHelper<'a> {
         content: Vec<u8>,
         modification_time_seconds: i64,
     ) -> Result<Cid, String> {
-        let forest = &mut self.forest;
-        let root_dir = &mut self.root_dir;
-        let mut modification_time_utc: DateTime<Utc> = Utc::now();
-        if modification_time_seconds > 0 {
-            let naive_datetime =
-                NaiveDateTime::from_timestamp_opt(modification_time_seconds, 0).unwrap();
-            modification_time_utc = DateTime::from_naive_utc_and_offset(naive_datetime, Utc);
-        }
-        let write_res = root_dir
-            .write(
-                path_segments,
-                true,
-                modification_time_utc,
-                content,
-                forest,
-                &mut self.store,
-                &mut self.rng,
-            )
-            .await;
-        if write_res.is_ok() {
-            // Private ref contains data and keys for fetching and decrypting the directory node in the private forest.
-            let access_key = root_dir
-                .as_node()
-                .store(forest, &mut self.store, &mut self.rng)
-                .await;
-            if access_key.is_ok() {
-                let forest_cid = PrivateDirectoryHelper::update_private_forest(
-                    self.store.to_owned(),
-                    forest.to_owned(),
-                )
-                .await;
-                if forest_cid.is_ok() {
-                    Ok(forest_cid.ok().unwrap())
-                } else {
-                    trace!(
-                        "wnfsError in write_file: {:?}",
-                        forest_cid.as_ref().err().unwrap().to_string()
-                    );
-                    Err(forest_cid.err().unwrap().to_string())
+        let metrics = self.limiter.metrics().to_owned();
+        metrics
+            .timed("write_file", async move {
+                self.check_write_path(path_segments, true)?;
+                self.check_not_held(path_segments, false).await?;
+                let mut media_entries =
+                    self.media_metadata_entries(&mut std::io::Cursor::new(&content));
+                media_entries.extend(self.scan_content(path_segments, &content).await?);
+                let modification_time_utc = Self::modification_time(modification_time_seconds)?;
+                if let Some(policy) = self.config.adaptive_chunking {
+                    let len = content.len() as u64;
+                    let mut tx = self.begin();
+                    tx.write_adaptive_raw(
+                        path_segments,
+                        &policy,
+                        futures::io::Cursor::new(content),
+                        Some(len),
+                    )
+                    .await?;
+                    if !media_entries.is_empty() {
+                        tx.put_file_metadata(path_segments, media_entries, modification_time_utc)
+                            .await?;
+                    }
+                    tx.release();
+                    return self.commit().await;
                 }
-            } else {
-                trace!(
-                    "wnfsError in write_file: {:?}",
-                    access_key.as_ref().err().unwrap().to_string()
-                );
-                Err(access_key.err().unwrap().to_string())
-            }
-        } else {
-            trace!(
-                "wnfsError in write_file: {:?}",
-                write_res.as_ref().err().unwrap().to_string()
-            );
-            Err(write_res.err().unwrap().to_string())
-        }
+                let resolved = self.resolve_path(path_segments).await?;
+                let forest = &mut self.forest;
+                let root_dir = &mut self.root_dir;
+                let write_res = root_dir
+                    .write(
+                        &resolved,
+                        true,
+                        modification_time_utc,
+                        content,
+                        forest,
+                        &mut self.store,
+                        &mut self.rng,
+                    )
+                    .await;
+                match write_res {
+                    Ok(_) => {
+                        if !media_entries.is_empty() {
+                            self.put_file_metadata(
+                                path_segments,
+                                media_entries,
+                                modification_time_utc,
+                            )
+                            .await?;
+                        }
+                        self.split_parent_if_needed(path_segments).await?;
+                        // Private ref contains data and keys for fetching and decrypting the directory node in the private forest.
+                        self.commit().await
+                    }
+                    Err(e) => {
+                        trace!("wnfsError in write_file: {:?}", e.to_string());
+                        Err(describe(&e))
+                    }
+                }
+            })
+            .await
     }
 
     pub async fn write_file_stream(
         &mut self,
 
         path_segments: &[String],
-        mut content: &mut async_std::io::BufReader<async_std::fs::File>,
+        content: &mut LocalFileReader,
+        modification_time_seconds: i64,
+    ) -> Result<Cid, String> {
+        let metrics = self.limiter.metrics().to_owned();
+        metrics
+            .timed("write_file_stream", async move {
+                self.write_file_stream_with_metadata(
+                    path_segments,
+                    content,
+                    modification_time_seconds,
+                    Vec::new(),
+                )
+                .await
+            })
+            .await
+    }
+
+    async fn write_file_stream_with_metadata<R: futures::AsyncRead + Unpin>(
+        &mut self,
+        path_segments: &[String],
+        content: R,
+        modification_time_seconds: i64,
+        extra_metadata: Vec<(String, Ipld)>,
+    ) -> Result<Cid, String> {
+        match self.start_scans(path_segments) {
+            Some(scans) => {
+                self.write_file_stream_scanned(
+                    path_segments,
+                    content,
+                    modification_time_seconds,
+                    extra_metadata,
+                    scans,
+                )
+                .await
+            }
+            None => {
+                self.write_file_stream_unscanned(
+                    path_segments,
+                    content,
+                    modification_time_seconds,
+                    extra_metadata,
+                )
+                .await
+            }
+        }
+    }
+
+    async fn write_file_stream_unscanned<R: futures::AsyncRead + Unpin>(
+        &mut self,
+        path_segments: &[String],
+        mut content: R,
         modification_time_seconds: i64,
+        extra_metadata: Vec<(String, Ipld)>,
     ) -> Result<Cid, String> {
+        self.check_write_path(path_segments, true)?;
+        self.check_not_held(path_segments, false).await?;
+        let modification_time_utc = Self::modification_time(modification_time_seconds)?;
+        if let Some(policy) = self.config.adaptive_chunking {
+            let mut tx = self.begin();
+            tx.write_adaptive_raw(path_segments, &policy, content, None)
+                .await?;
+            if !extra_metadata.is_empty() {
+                tx.put_file_metadata(path_segments, extra_metadata, modification_time_utc)
+                    .await?;
+            }
+            tx.release();
+            return self.commit().await;
+        }
+        // Empty content is stored inline, like `write_file` does, instead of as a stream of no
+        // blocks.
+        let mut first = [0u8; 1];
+        let read = content.read(&mut first).await.map_err(|e| {
+            trace!("wnfsError in write_file_stream: {:?}", e.to_string());
+            describe(&e)
+        })?;
+        if read == 0 {
+            let resolved = self.resolve_path(path_segments).await?;
+            self.root_dir
+                .write(
+                    &resolved,
+                    true,
+                    modification_time_utc,
+                    Vec::new(),
+                    &mut self.forest,
+                    &mut self.store,
+                    &mut self.rng,
+                )
+                .await
+                .map_err(|e| {
+                    trace!("wnfsError in write_file_stream: {:?}", e.to_string());
+                    describe(&e)
+                })?;
+            if !extra_metadata.is_empty() {
+                self.put_file_metadata(path_segments, extra_metadata, modification_time_utc)
+                    .await?;
+            }
+            self.split_parent_if_needed(path_segments).await?;
+            return self.commit().await;
+        }
+        let mut content = futures::io::Cursor::new(first).chain(content);
+        let resolved = self.resolve_path(path_segments).await?;
         let forest = &mut self.forest;
         let root_dir = &mut self.root_dir;
-        let mut modification_time_utc: DateTime<Utc> = Utc::now();
-        if modification_time_seconds > 0 {
-            let naive_datetime =
-                NaiveDateTime::from_timestamp_opt(modification_time_seconds, 0).unwrap();
-            modification_time_utc = DateTime::from_naive_utc_and_offset(naive_datetime, Utc);
-        }
 
-        let file_open_res = root_dir
+        let file = root_dir
             .open_file_mut(
-                path_segments,
+                &resolved,
                 true,
                 modification_time_utc,
                 forest,
                 &mut self.store,
                 &mut self.rng,
             )
-            .await;
-        if file_open_res.is_ok() {
-            let file = file_open_res.unwrap();
-            let write_res = file
-                .set_content(
-                    modification_time_utc,
-                    &mut content,
-                    forest,
-                    &mut self.store,
-                    &mut self.rng,
-                )
-                .await;
-            if write_res.is_ok() {
-                // Private ref contains data and keys for fetching and decrypting the directory node in the private forest.
-                let access_key = root_dir
-                    .as_node()
-                    .store(forest, &mut self.store, &mut self.rng)
-                    .await;
-                if access_key.is_ok() {
-                    let forest_cid = PrivateDirectoryHelper::update_private_forest(
-                        self.store.to_owned(),
-                        forest.to_owned(),
-                    )
-                    .await;
-                    if forest_cid.is_ok() {
-                        Ok(forest_cid.ok().unwrap())
-                    } else {
-                        trace!(
-                            "wnfsError in write_file: {:?}",
-                            forest_cid.as_ref().err().unwrap().to_string()
-                        );
-                        Err(forest_cid.err().unwrap().to_string())
-                    }
-                } else {
-                    trace!(
-                        "wnfsError in write_file: {:?}",
-                        access_key.as_ref().err().unwrap().to_string()
-                    );
-                    Err(access_key.err().unwrap().to_string())
-                }
-            } else {
-                trace!(
-                    "wnfsError in write_file: {:?}",
-                    write_res.as_ref().err().unwrap().to_string()
-                );
-                Err(write_res.err().unwrap().to_string())
-            }
-        } else {
-            trace!(
-                "wnfsError in write_file_stream: {:?}",
-                file_open_res.as_ref().err().unwrap().to_string()
-            );
-            Err(file_open_res.err().unwrap().to_string())
+            .await
+            .map_err(|e| {
+                trace!("wnfsError in write_file_stream: {:?}", e.to_string());
+                describe(&e)
+            })?;
+        file.set_content(
+            modification_time_utc,
+            &mut content,
+            forest,
+            &mut self.store,
+            &mut self.rng,
+        )
+        .await
+        .map_err(|e| {
+            trace!("wnfsError in write_file: {:?}", e.to_string());
+            describe(&e)
+        })?;
+        let metadata = file.get_metadata_mut();
+        for (key, value) in extra_metadata {
+            metadata.put(&key, value);
         }
+        self.split_parent_if_needed(path_segments).await?;
+        self.commit().await
     }
 
     pub async fn read_filestream_to_path(
@@ -708,72 +1211,66 @@ impl<'a> PrivateDirectoryHelper<'a> {
         path_segments: &[String],
         index: usize,
     ) -> Result<bool, String> {
-        let forest = &mut self.forest;
-        let root_dir = &mut self.root_dir;
-        //let mut stream_content: Vec<u8> = vec![];
-        let local_file = File::create(local_filename);
-        if local_file.is_ok() {
-            let mut local_file_handler = local_file.ok().unwrap();
-
-            let private_node_result = root_dir
-                .get_node(path_segments, true, forest, &mut self.store)
-                .await;
-            if private_node_result.is_ok() {
-                let result = private_node_result.ok().unwrap();
-                if result.is_some() {
-                    let private_node = result.unwrap();
-                    let is_file = private_node.is_file();
-                    if is_file {
-                        let file_res = private_node.as_file();
-                        if file_res.is_ok() {
-                            let file = file_res.ok().unwrap();
-                            let mut stream = file.stream_content(index, forest, &mut self.store);
-                            while let Some(block) = stream.next().await {
-                                if block.is_ok() {
-                                    let write_result =
-                                        local_file_handler.write_all(&block.unwrap());
-                                    if write_result.is_err() {
-                                        trace!("wnfsError occured in read_filestream_to_path on write_result: {:?}", write_result.as_ref().err().unwrap().to_string());
-                                    }
-                                } else {
-                                    trace!(
-                                        "wnfsError occured in read_filestream_to_path on file_res: {:?}",
-                                        block.as_ref().err().unwrap().to_string()
-                                    );
-                                    return Err(block.err().unwrap().to_string());
-                                }
-                                //stream_content.extend_from_slice(&block.unwrap());
-                            }
-                            Ok(true)
-                        } else {
-                            trace!(
-                                "wnfsError occured in read_filestream_to_path on file_res: {:?}",
-                                file_res.as_ref().err().unwrap().to_string()
-                            );
-                            Err(file_res.err().unwrap().to_string())
-                        }
-                    } else {
-                        trace!("wnfsError occured in read_filestream_to_path on is_file");
-                        Err("wnfsError occured in read_filestream_to_path on is_file".to_string())
+        let metrics = self.limiter.metrics().to_owned();
+        metrics
+            .timed("read_filestream_to_path", async move {
+                let path_segments = &self.resolve_path(path_segments).await?;
+                let forest = &mut self.forest;
+                let root_dir = &mut self.root_dir;
+                let mut local_file_handler = File::create(local_filename).map_err(|e| {
+                    trace!(
+                        "wnfsError occured in read_filestream_to_path on local_file {:?}",
+                        e.to_string()
+                    );
+                    describe(&e)
+                })?;
+
+                let private_node = root_dir
+                    .get_node(path_segments, true, forest, &mut self.store)
+                    .await
+                    .map_err(|e| {
+                        trace!(
+                        "wnfsError occured in read_filestream_to_path on private_node_result: {:?}",
+                        e.to_string()
+                    );
+                        describe(&e)
+                    })?
+                    .ok_or_else(|| {
+                        trace!("wnfsError occured in read_filestream_to_path on result");
+                        "wnfsError occured in read_filestream_to_path on result".to_string()
+                    })?;
+                if !private_node.is_file() {
+                    trace!("wnfsError occured in read_filestream_to_path on is_file");
+                    return Err(
+                        "wnfsError occured in read_filestream_to_path on is_file".to_string()
+                    );
+                }
+                let file = private_node.as_file().map_err(|e| {
+                    trace!(
+                        "wnfsError occured in read_filestream_to_path on file_res: {:?}",
+                        e.to_string()
+                    );
+                    describe(&e)
+                })?;
+                let mut stream = file.stream_content(index, forest, &mut self.store);
+                while let Some(block) = stream.next().await {
+                    let block = block.map_err(|e| {
+                        trace!(
+                            "wnfsError occured in read_filestream_to_path on file_res: {:?}",
+                            e.to_string()
+                        );
+                        describe(&e)
+                    })?;
+                    if let Err(e) = local_file_handler.write_all(&block) {
+                        trace!(
+                            "wnfsError occured in read_filestream_to_path on write_result: {:?}",
+                            e.to_string()
+                        );
                     }
-                } else {
-                    trace!("wnfsError occured in read_filestream_to_path on result");
-                    Err("wnfsError occured in read_filestream_to_path on result".to_string())
                 }
-            } else {
-                trace!(
-                    "wnfsError occured in read_filestream_to_path on private_node_result: {:?}",
-                    private_node_result.as_ref().err().unwrap().to_string()
-                );
-                Err(private_node_result.err().unwrap().to_string())
-            }
-        } else {
-            trace!(
-                "wnfsError occured in read_filestream_to_path on local_file {:?}",
-                local_file.as_ref().err().unwrap().to_string()
-            );
-            Err(local_file.err().unwrap().to_string())
-        }
+                Ok(true)
+            })
+            .await
     }
 
     pub async fn read_file_to_path(
@@ -781,136 +1278,104 @@ impl<'a> PrivateDirectoryHelper<'a> {
         path_segments: &[String],
         filename: &String,
     ) -> Result<String, String> {
-        let file_content_res = self.read_file(path_segments).await;
-        if file_content_res.is_ok() {
-            let res = self.write_byte_vec_to_file(filename, file_content_res.ok().unwrap());
-            if res.is_ok() {
+        let metrics = self.limiter.metrics().to_owned();
+        metrics
+            .timed("read_file_to_path", async move {
+                let file_content = self.read_file(path_segments).await.map_err(|e| {
+                    trace!(
+                        "wnfsError occured in read_file_to_path on file_content_res: {:?}",
+                        e
+                    );
+                    e
+                })?;
+                self.write_byte_vec_to_file(filename, file_content)
+                    .map_err(|e| {
+                        trace!("wnfsError occured in read_file_to_path on res: {:?}", e);
+                        e
+                    })?;
                 Ok(filename.to_string())
-            } else {
-                trace!(
-                    "wnfsError occured in read_file_to_path on res: {:?}",
-                    res.as_ref().err().unwrap().to_string()
-                );
-                Err(res.err().unwrap().to_string())
-            }
-        } else {
-            trace!(
-                "wnfsError occured in read_file_to_path on file_content_res: {:?}",
-                file_content_res.as_ref().err().unwrap().to_string()
-            );
-            Err(file_content_res.err().unwrap().to_string())
-        }
+            })
+            .await
     }
 
     pub async fn read_file(&mut self, path_segments: &[String]) -> Result<Vec<u8>, String> {
-        let forest = &mut self.forest;
-        let root_dir = &mut self.root_dir;
-        let res = root_dir
-            .read(path_segments, true, forest, &mut self.store)
-            .await;
-        if res.is_ok() {
-            let result = res.ok().unwrap();
-            Ok(result)
-        } else {
-            trace!(
-                "wnfsError occured in read_file: {:?} ",
-                res.as_ref().err().unwrap()
-            );
-            Err(res.err().unwrap().to_string())
-        }
+        let metrics = self.limiter.metrics().to_owned();
+        metrics
+            .timed("read_file", async move {
+                self.check_path_depth(path_segments)?;
+                if self.is_paged_file(path_segments).await? {
+                    return self.read_at(path_segments, 0, usize::MAX).await;
+                }
+                let path_segments = &self.resolve_path(path_segments).await?;
+                let forest = &mut self.forest;
+                let root_dir = &mut self.root_dir;
+                let res = root_dir
+                    .read(path_segments, true, forest, &mut self.store)
+                    .await;
+                match res {
+                    Ok(content) => Ok(content),
+                    Err(e) => {
+                        trace!("wnfsError occured in read_file: {:?} ", e);
+                        Err(self.wnfs_failure(path_segments, e).await)
+                    }
+                }
+            })
+            .await
     }
 
     pub async fn mkdir(&mut self, path_segments: &[String]) -> Result<Cid, String> {
-        let forest = &mut self.forest;
-        let root_dir = &mut self.root_dir;
-        let res = root_dir
-            .mkdir(
-                path_segments,
-                true,
-                Utc::now(),
-                forest,
-                &mut self.store,
-                &mut self.rng,
-            )
-            .await;
-        if res.is_ok() {
-            // Private ref contains data and keys for fetching and decrypting the directory node in the private forest.
-            let access_key = root_dir
-                .as_node()
-                .store(forest, &mut self.store, &mut self.rng)
-                .await;
-            if access_key.is_ok() {
-                let forest_cid = PrivateDirectoryHelper::update_private_forest(
-                    self.store.to_owned(),
-                    forest.to_owned(),
-                )
-                .await;
-                if forest_cid.is_ok() {
-                    Ok(forest_cid.ok().unwrap())
-                } else {
-                    trace!(
-                        "wnfsError in mkdir: {:?}",
-                        forest_cid.as_ref().err().unwrap().to_string()
-                    );
-                    Err(forest_cid.err().unwrap().to_string())
+        let metrics = self.limiter.metrics().to_owned();
+        metrics
+            .timed("mkdir", async move {
+                self.check_write_path(path_segments, false)?;
+                self.check_not_held(path_segments, false).await?;
+                let resolved = self.resolve_path(path_segments).await?;
+                let forest = &mut self.forest;
+                let root_dir = &mut self.root_dir;
+                let res = root_dir
+                    .mkdir(
+                        &resolved,
+                        true,
+                        Utc::now(),
+                        forest,
+                        &mut self.store,
+                        &mut self.rng,
+                    )
+                    .await;
+                match res {
+                    Ok(_) => {
+                        self.split_parent_if_needed(path_segments).await?;
+                        self.commit().await
+                    }
+                    Err(e) => {
+                        trace!("wnfsError occured in mkdir: {:?}", e);
+                        Err(describe(&e))
+                    }
                 }
-            } else {
-                trace!(
-                    "wnfsError in mkdir: {:?}",
-                    access_key.as_ref().err().unwrap().to_string()
-                );
-                Err(access_key.err().unwrap().to_string())
-            }
-        } else {
-            trace!(
-                "wnfsError occured in mkdir: {:?}",
-                res.as_ref().err().unwrap()
-            );
-            Err(res.err().unwrap().to_string())
-        }
+            })
+            .await
     }
 
     pub async fn rm(&mut self, path_segments: &[String]) -> Result<Cid, String> {
-        let forest = &mut self.forest;
-        let root_dir = &mut self.root_dir;
-        let result = root_dir
-            .rm(path_segments, true, forest, &mut self.store)
-            .await;
-        if result.is_ok() {
-            // Private ref contains data and keys for fetching and decrypting the directory node in the private forest.
-            let access_key = root_dir
-                .as_node()
-                .store(forest, &mut self.store, &mut self.rng)
-                .await;
-            if access_key.is_ok() {
-                let forest_cid = PrivateDirectoryHelper::update_private_forest(
-                    self.store.to_owned(),
-                    forest.to_owned(),
-                )
-                .await;
-                if forest_cid.is_ok() {
-                    Ok(forest_cid.ok().unwrap())
-                } else {
-                    trace!(
-                        "wnfsError in result: {:?}",
-                        forest_cid.as_ref().err().unwrap().to_string()
-                    );
-                    Err(forest_cid.err().unwrap().to_string())
+        let metrics = self.limiter.metrics().to_owned();
+        metrics
+            .timed("rm", async move {
+                self.check_not_held(path_segments, true).await?;
+                let path_segments = &self.resolve_path(path_segments).await?;
+                let forest = &mut self.forest;
+                let root_dir = &mut self.root_dir;
+                let result = root_dir
+                    .rm(path_segments, true, forest, &mut self.store)
+                    .await;
+                match result {
+                    Ok(_) => self.commit().await,
+                    Err(e) => {
+                        trace!("wnfsError occured in rm result: {:?}", e);
+                        Err(self.wnfs_failure(path_segments, e).await)
+                    }
                 }
-            } else {
-                trace!(
-                    "wnfsError in result: {:?}",
-                    access_key.as_ref().err().unwrap().to_string()
-                );
-                Err(access_key.err().unwrap().to_string())
-            }
-        } else {
-            trace!(
-                "wnfsError occured in rm result: {:?}",
-                result.as_ref().err().unwrap()
-            );
-            Err(result.err().unwrap().to_string())
-        }
+            })
+            .await
     }
 
     pub async fn mv(
@@ -918,54 +1383,40 @@ impl<'a> PrivateDirectoryHelper<'a> {
         source_path_segments: &[String],
         target_path_segments: &[String],
     ) -> Result<Cid, String> {
-        let forest = &mut self.forest;
-        let root_dir = &mut self.root_dir;
-        let mv_result = root_dir
-            .basic_mv(
-                source_path_segments,
-                target_path_segments,
-                true,
-                Utc::now(),
-                forest,
-                &mut self.store,
-                &mut self.rng,
-            )
-            .await;
-        if mv_result.is_ok() {
-            // Private ref contains data and keys for fetching and decrypting the directory node in the private forest.
-            let access_key = root_dir
-                .as_node()
-                .store(forest, &mut self.store, &mut self.rng)
-                .await;
-            if access_key.is_ok() {
-                let forest_cid = PrivateDirectoryHelper::update_private_forest(
-                    self.store.to_owned(),
-                    forest.to_owned(),
-                )
-                .await;
-                if forest_cid.is_ok() {
-                    Ok(forest_cid.ok().unwrap())
-                } else {
-                    trace!(
-                        "wnfsError in mv_result: {:?}",
-                        forest_cid.as_ref().err().unwrap().to_string()
-                    );
-                    Err(forest_cid.err().unwrap().to_string())
+        let metrics = self.limiter.metrics().to_owned();
+        metrics
+            .timed("mv", async move {
+                self.check_path_depth(target_path_segments)?;
+                self.check_path_length(target_path_segments)?;
+                self.check_not_held(source_path_segments, true).await?;
+                self.check_not_held(target_path_segments, false).await?;
+                let source = self.resolve_path(source_path_segments).await?;
+                let target = self.resolve_path(target_path_segments).await?;
+                let forest = &mut self.forest;
+                let root_dir = &mut self.root_dir;
+                let mv_result = root_dir
+                    .basic_mv(
+                        &source,
+                        &target,
+                        true,
+                        Utc::now(),
+                        forest,
+                        &mut self.store,
+                        &mut self.rng,
+                    )
+                    .await;
+                match mv_result {
+                    Ok(_) => {
+                        self.split_parent_if_needed(target_path_segments).await?;
+                        self.commit().await
+                    }
+                    Err(e) => {
+                        trace!("wnfsError occured in mv mv_result: {:?}", e);
+                        Err(self.wnfs_failure(&source, e).await)
+                    }
                 }
-            } else {
-                trace!(
-                    "wnfsError in mv_result: {:?}",
-                    access_key.as_ref().err().unwrap().to_string()
-                );
-                Err(access_key.err().unwrap().to_string())
-            }
-        } else {
-            trace!(
-                "wnfsError occured in mv mv_result: {:?}",
-                mv_result.as_ref().err().unwrap()
-            );
-            Err(mv_result.err().unwrap().to_string())
-        }
+            })
+            .await
     }
 
     pub async fn cp(
@@ -973,105 +1424,102 @@ impl<'a> PrivateDirectoryHelper<'a> {
         source_path_segments: &[String],
         target_path_segments: &[String],
     ) -> Result<Cid, String> {
-        let forest = &mut self.forest;
-        let root_dir = &mut self.root_dir;
-        let cp_result = root_dir
-            .cp(
-                source_path_segments,
-                target_path_segments,
-                true,
-                Utc::now(),
-                forest,
-                &mut self.store,
-                &mut self.rng,
-            )
-            .await;
-        if cp_result.is_ok() {
-            // Private ref contains data and keys for fetching and decrypting the directory node in the private forest.
-            let access_key = root_dir
-                .as_node()
-                .store(forest, &mut self.store, &mut self.rng)
-                .await;
-            if access_key.is_ok() {
-                let forest_cid = PrivateDirectoryHelper::update_private_forest(
-                    self.store.to_owned(),
-                    forest.to_owned(),
-                )
-                .await;
-                if forest_cid.is_ok() {
-                    Ok(forest_cid.ok().unwrap())
-                } else {
-                    trace!(
-                        "wnfsError in cp_result: {:?}",
-                        forest_cid.as_ref().err().unwrap().to_string()
-                    );
-                    Err(forest_cid.err().unwrap().to_string())
+        let metrics = self.limiter.metrics().to_owned();
+        metrics
+            .timed("cp", async move {
+                self.check_path_depth(target_path_segments)?;
+                self.check_path_length(target_path_segments)?;
+                self.check_not_held(target_path_segments, false).await?;
+                let source = self.resolve_path(source_path_segments).await?;
+                let target = self.resolve_path(target_path_segments).await?;
+                let forest = &mut self.forest;
+                let root_dir = &mut self.root_dir;
+                let cp_result = root_dir
+                    .cp(
+                        &source,
+                        &target,
+                        true,
+                        Utc::now(),
+                        forest,
+                        &mut self.store,
+                        &mut self.rng,
+                    )
+                    .await;
+                match cp_result {
+                    Ok(_) => {
+                        self.split_parent_if_needed(target_path_segments).await?;
+                        self.commit().await
+                    }
+                    Err(e) => {
+                        trace!("wnfsError occured in cp cp_result: {:?}", e);
+                        Err(self.wnfs_failure(&source, e).await)
+                    }
                 }
-            } else {
-                trace!(
-                    "wnfsError in cp_result: {:?}",
-                    access_key.as_ref().err().unwrap().to_string()
-                );
-                Err(access_key.err().unwrap().to_string())
-            }
-        } else {
-            trace!(
-                "wnfsError occured in cp cp_result: {:?}",
-                cp_result.as_ref().err().unwrap()
-            );
-            Err(cp_result.err().unwrap().to_string())
-        }
+            })
+            .await
     }
 
     pub async fn ls_files(
         &mut self,
         path_segments: &[String],
+    ) -> Result<Vec<(String, Metadata)>, String> {
+        let metrics = self.limiter.metrics().to_owned();
+        metrics
+            .timed("ls_files", async move {
+                self.check_path_depth(path_segments)?;
+                let path_segments = self.resolve_path(path_segments).await?;
+                self.ls_resolved(&path_segments).await
+            })
+            .await
+    }
+
+    // `ls_files` of a single directory node, for a path as stored in the forest.
+    async fn ls_raw(
+        &mut self,
+        path_segments: &[String],
     ) -> Result<Vec<(String, Metadata)>, String> {
         let forest = &mut self.forest;
         let root_dir = &mut self.root_dir;
         let res = root_dir
             .ls(path_segments, true, forest, &mut self.store)
             .await;
-        if res.is_ok() {
-            let result = res.ok().unwrap();
-            Ok(result)
-        } else {
-            trace!(
-                "wnfsError occured in ls_files: {:?}",
-                res.as_ref().err().unwrap().to_string()
-            );
-            Err(res.err().unwrap().to_string())
-        }
+        let entries = match res {
+            Ok(entries) => entries,
+            Err(e) => {
+                trace!("wnfsError occured in ls_files: {:?}", e.to_string());
+                return Err(self.wnfs_failure(path_segments, e).await);
+            }
+        };
+        self.check_dir_entries(entries.len())?;
+        Ok(entries)
     }
 }
 
 // Implement synced version of the library for using in android jni.
 impl<'a> PrivateDirectoryHelper<'a> {
-    pub fn synced_init(
+    pub(crate) fn synced_init(
         store: &mut FFIFriendlyBlockStore<'a>,
         wnfs_key: Vec<u8>,
     ) -> Result<(PrivateDirectoryHelper<'a>, AccessKey, Cid), String> {
-        let runtime = tokio::runtime::Runtime::new().expect("Unable to create a runtime");
-        return runtime.block_on(PrivateDirectoryHelper::init(store, wnfs_key));
+        return Self::run_request("init", PrivateDirectoryHelper::init(store, wnfs_key));
     }
 
-    pub fn synced_load_with_wnfs_key(
+    pub(crate) fn synced_load_with_wnfs_key(
         store: &mut FFIFriendlyBlockStore<'a>,
         forest_cid: Cid,
         wnfs_key: Vec<u8>,
     ) -> Result<PrivateDirectoryHelper<'a>, String> {
-        let runtime = tokio::runtime::Runtime::new().expect("Unable to create a runtime");
-        return runtime.block_on(PrivateDirectoryHelper::load_with_wnfs_key(
-            store, forest_cid, wnfs_key,
-        ));
+        return Self::run_request(
+            "load_with_wnfs_key",
+            PrivateDirectoryHelper::load_with_wnfs_key(store, forest_cid, wnfs_key),
+        );
     }
 
-    pub fn synced_reload(
+    pub(crate) fn synced_reload(
         store: &mut FFIFriendlyBlockStore<'a>,
         forest_cid: Cid,
     ) -> Result<PrivateDirectoryHelper<'a>, String> {
-        let runtime = tokio::runtime::Runtime::new().expect("Unable to create a runtime");
-        return runtime.block_on(PrivateDirectoryHelper::reload(store, forest_cid));
+        return Self::run_request("reload", PrivateDirectoryHelper::reload(store, forest_cid));
     }
 
     pub fn synced_write_file_from_path(
@@ -1079,8 +1527,11 @@ impl<'a> PrivateDirectoryHelper<'a> {
         path_segments: &[String],
         filename: &String,
     ) -> Result<Cid, String> {
-        let runtime = tokio::runtime::Runtime::new().expect("Unable to create a runtime");
-        return runtime.block_on(self.write_file_from_path(path_segments, filename));
+        return Self::run_limited(
+            &self.operation_limiter(),
+            "write_file_from_path",
+            self.write_file_from_path(path_segments, filename),
+        );
     }
 
     pub fn synced_write_file_stream_from_path(
@@ -1088,8 +1539,11 @@ impl<'a> PrivateDirectoryHelper<'a> {
         path_segments: &[String],
         filename: &String,
     ) -> Result<Cid, String> {
-        let runtime = tokio::runtime::Runtime::new().expect("Unable to create a runtime");
-        return runtime.block_on(self.write_file_stream_from_path(path_segments, filename));
+        return Self::run_limited(
+            &self.operation_limiter(),
+            "write_file_stream_from_path",
+            self.write_file_stream_from_path(path_segments, filename),
+        );
     }
 
     pub fn synced_write_file(
@@ -1098,12 +1552,11 @@ impl<'a> PrivateDirectoryHelper<'a> {
         content: Vec<u8>,
         modification_time_seconds: i64,
     ) -> Result<Cid, String> {
-        let runtime = tokio::runtime::Runtime::new().expect("Unable to create a runtime");
-        return runtime.block_on(self.write_file(
-            path_segments,
-            content,
-            modification_time_seconds,
-        ));
+        return Self::run_limited(
+            &self.operation_limiter(),
+            "write_file",
+            self.write_file(path_segments, content, modification_time_seconds),
+        );
     }
 
     pub fn synced_read_file_to_path(
@@ -1111,13 +1564,19 @@ impl<'a> PrivateDirectoryHelper<'a> {
         path_segments: &[String],
         filename: &String,
     ) -> Result<String, String> {
-        let runtime = tokio::runtime::Runtime::new().expect("Unable to create a runtime");
-        return runtime.block_on(self.read_file_to_path(path_segments, filename));
+        return Self::run_limited(
+            &self.operation_limiter(),
+            "read_file_to_path",
+            self.read_file_to_path(path_segments, filename),
+        );
     }
 
     pub fn synced_read_file(&mut self, path_segments: &[String]) -> Result<Vec<u8>, String> {
-        let runtime = tokio::runtime::Runtime::new().expect("Unable to create a runtime");
-        return runtime.block_on(self.read_file(path_segments));
+        return Self::run_limited(
+            &self.operation_limiter(),
+            "read_file",
+            self.read_file(path_segments),
+        );
     }
 
     pub fn synced_read_filestream_to_path(
@@ -1126,17 +1585,19 @@ impl<'a> PrivateDirectoryHelper<'a> {
         path_segments: &[String],
         index: usize,
     ) -> Result<bool, String> {
-        let runtime = tokio::runtime::Runtime::new().expect("Unable to create a runtime");
-        return runtime.block_on(self.read_filestream_to_path(
-            local_filename,
-            path_segments,
-            index,
-        ));
+        return Self::run_limited(
+            &self.operation_limiter(),
+            "read_filestream_to_path",
+            self.read_filestream_to_path(local_filename, path_segments, index),
+        );
     }
 
     pub fn synced_mkdir(&mut self, path_segments: &[String]) -> Result<Cid, String> {
-        let runtime = tokio::runtime::Runtime::new().expect("Unable to create a runtime");
-        return runtime.block_on(self.mkdir(path_segments));
+        return Self::run_limited(
+            &self.operation_limiter(),
+            "mkdir",
+            self.mkdir(path_segments),
+        );
     }
 
     pub fn synced_mv(
@@ -1144,8 +1605,11 @@ impl<'a> PrivateDirectoryHelper<'a> {
         source_path_segments: &[String],
         target_path_segments: &[String],
     ) -> Result<Cid, String> {
-        let runtime = tokio::runtime::Runtime::new().expect("Unable to create a runtime");
-        return runtime.block_on(self.mv(source_path_segments, target_path_segments));
+        return Self::run_limited(
+            &self.operation_limiter(),
+            "mv",
+            self.mv(source_path_segments, target_path_segments),
+        );
     }
 
     pub fn synced_cp(
@@ -1153,27 +1617,91 @@ impl<'a> PrivateDirectoryHelper<'a> {
         source_path_segments: &[String],
         target_path_segments: &[String],
     ) -> Result<Cid, String> {
-        let runtime = tokio::runtime::Runtime::new().expect("Unable to create a runtime");
-        return runtime.block_on(self.cp(source_path_segments, target_path_segments));
+        return Self::run_limited(
+            &self.operation_limiter(),
+            "cp",
+            self.cp(source_path_segments, target_path_segments),
+        );
     }
 
     pub fn synced_rm(&mut self, path_segments: &[String]) -> Result<Cid, String> {
-        let runtime = tokio::runtime::Runtime::new().expect("Unable to create a runtime");
-        return runtime.block_on(self.rm(path_segments));
+        return Self::run_limited(&self.operation_limiter(), "rm", self.rm(path_segments));
     }
 
     pub fn synced_ls_files(
         &mut self,
         path_segments: &[String],
     ) -> Result<Vec<(String, Metadata)>, String> {
-        let runtime = tokio::runtime::Runtime::new().expect("Unable to create a runtime");
-        return runtime.block_on(self.ls_files(path_segments));
+        return Self::run_limited(
+            &self.operation_limiter(),
+            "ls_files",
+            self.ls_files(path_segments),
+        );
+    }
+
+    // Runs a top-level operation to completion inside a request scope, see `request_id`, and
+    // records its time. Errors are reported to the error sink and returned tagged with the
+    // request ID.
+    pub(crate) fn run_request<T>(
+        operation: &'static str,
+        future: impl Future<Output = Result<T, String>>,
+    ) -> Result<T, String> {
+        Self::run_request_with(None, operation, future)
+    }
+
+    // Like `run_request`, once the limiter of the helper admits the operation, see
+    // `set_operation_limiter`. The recorded time includes the wait for admission.
+    pub(crate) fn run_limited<T>(
+        limiter: &OperationLimiter,
+        operation: &'static str,
+        future: impl Future<Output = Result<T, String>>,
+    ) -> Result<T, String> {
+        Self::run_request_with(Some(limiter), operation, future)
+    }
+
+    fn run_request_with<T>(
+        limiter: Option<&OperationLimiter>,
+        operation: &'static str,
+        future: impl Future<Output = Result<T, String>>,
+    ) -> Result<T, String> {
+        let id = request_id::current().unwrap_or_else(request_id::new_request_id);
+        trace!("request {}: {}", id, operation);
+        let _scope = request_id::enter(id);
+        let started = Instant::now();
+        let admitted = match limiter {
+            Some(limiter) => limiter.admit(operation).map(Some),
+            None => Ok(None),
+        };
+        let metrics = limiter.map(OperationLimiter::metrics);
+        let result = OperationMetrics::time_blocking(metrics, operation, started, || {
+            admitted
+                .and_then(|_permit| Self::runtime().and_then(|runtime| runtime.block_on(future)))
+        });
+        report_result(operation, result).map_err(request_id::tag_error)
     }
 
+    // Creating a runtime can fail, e.g. when the host app has exhausted its threads.
+    #[cfg(feature = "tokio-multi-thread")]
+    fn runtime() -> Result<tokio::runtime::Runtime, String> {
+        tokio::runtime::Runtime::new()
+            .map_err(|e| WnfsUtilsError::Runtime(e.to_string()).to_string())
+    }
+
+    // Minimal builds run requests on the calling thread.
+    #[cfg(not(feature = "tokio-multi-thread"))]
+    fn runtime() -> Result<tokio::runtime::Runtime, String> {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .map_err(|e| WnfsUtilsError::Runtime(e.to_string()).to_string())
+    }
+
+    /// Splits `path` at `/`, skipping empty segments, so `/` and `` stand for the root and
+    /// `a//b/` for `a/b`.
     pub fn parse_path(path: String) -> Vec<String> {
         path.trim()
-            .trim_matches('/')
-            .split("/")
+            .split('/')
+            .filter(|s| !s.is_empty())
             .map(|s| s.to_string())
             .collect()
     }
@@ -1224,7 +1752,128 @@ impl ExchangeKey for PublicExchangeKey {
     }
 }
 
+impl<'a> Drop for PrivateDirectoryHelper<'a> {
+    fn drop(&mut self) {
+        if self.pending_commit.is_some() {
+            // Best effort: a window left open would otherwise lose its mutations.
+            match crate::blockstore::block_on(self.commit_now()) {
+                Ok(Ok(root)) => {
+                    trace!("wnfsutils: committed pending mutations on drop as {}", root)
+                }
+                Ok(Err(e)) => trace!("wnfsError in drop: {:?}", e),
+                Err(e) => trace!("wnfsError in drop: {:?}", e.to_string()),
+            }
+        }
+        if let Some((coordinator, writer)) = self.gc_writer.take() {
+            coordinator.unregister_writer(writer);
+        }
+    }
+}
+
+mod account;
+mod attestation;
+mod batch;
+mod batching;
+mod changes;
+mod chunking;
+mod dedup;
+mod delta;
+mod dir_handle;
+mod documents;
+mod file_handle;
+mod file_metadata;
+mod file_provider;
+mod forest_state;
+mod fork;
+mod hashing;
+mod helper_handle;
+mod history;
+mod idempotency;
+mod legal_hold;
+mod limits;
+mod local_file;
+mod manifest;
+mod materialize;
+mod media;
+mod merge;
+mod name_privacy;
+mod normalization;
+mod observed;
+mod paged;
+mod pagination;
+mod publish;
+mod rebase;
+mod rotation;
+mod scanning;
+mod scoped;
+mod search;
+mod session;
+mod sharding;
+mod sharing;
+mod snapshots;
+mod squashing;
+mod streaming;
+mod sync_status;
+mod template;
+mod transaction;
+mod transfer;
+mod vfs;
+mod walk;
+mod watcher;
+
+pub use account::AccountBundle;
+pub use attestation::{Attestation, DeviceSigningKey, SignedAttestation};
+pub use batch::{BatchErrorKind, BatchItem, BatchItemError, BatchOptions, BatchReport};
+pub use batching::CommitBatching;
+pub use changes::DirectoryChanges;
+pub use chunking::{AdaptiveChunking, FileSizeClass};
+pub use dedup::{DuplicateGroup, DuplicateReport};
+pub use delta::{FileDelta, DELTA_BLOCK_SIZE};
+pub use dir_handle::DirHandle;
+pub use documents::{ChildDocuments, DocumentRow, MIME_TYPE_DIR};
+pub use file_handle::{FileHandle, FILE_HANDLE_CACHED_BLOCKS};
+pub use file_metadata::{FileMetadata, CONTENT_TYPE_KEY};
+pub use file_provider::{
+    FileProviderChanges, FileProviderItem, FileProviderPage, ROOT_CONTAINER_IDENTIFIER,
+};
+pub use forest_state::ForestState;
+pub use hashing::HashAlgorithm;
+pub use helper_handle::{handle_channel, serve_handle, HandleBridge, HandleRequest, HelperHandle};
+pub use history::{ReadOnlyView, RevisionInfo};
+pub use idempotency::IDEMPOTENCY_KEY_LIMIT;
+pub use legal_hold::{LegalHold, RESERVED_DIR};
+pub use limits::{OperationLimiter, OperationLimits};
+pub use local_file::LocalFileReader;
+pub use manifest::{Manifest, ManifestCheck, ManifestEntry, SignedManifest};
+pub use materialize::{TransferProgress, TreeCopyOptions, TreeCopyReport};
+pub use media::{MediaIngestOptions, MediaIngestReport, CONTENT_HASH_KEY};
+pub use merge::ForestDiff;
+pub use name_privacy::{NameIndex, NamePrivacy};
+pub use normalization::{NormalizationConflict, NormalizationReport, PathNormalization};
+pub use paged::{PagedFileOptions, PAGED_MARKER};
+pub use pagination::Page;
+pub use rebase::{Attempt, RetryPolicy, RootPointer};
+pub use scanning::{ContentScan, ContentScanner, SCAN_QUEUE_CHUNKS};
+pub use scoped::{Permissions, ScopedHandle};
+pub use search::DiskUsage;
+pub use session::{ExclusiveSession, SharedSession};
+pub use sharding::{DirectorySharding, SHARD_MARKER};
+pub use sharing::{ExchangeKeyPair, SharePayload};
+pub use snapshots::Snapshot;
+pub use squashing::{MaintenanceReport, RevisionSquashing};
+pub use streaming::STREAM_CHUNK_BYTES;
+pub use sync_status::SyncStatus;
+pub use template::{ForestTemplate, TemplateDocument};
+pub use transaction::Transaction;
+pub use vfs::VFS_MAX_FILE_SIZE;
+pub use walk::{WalkEntry, WalkOptions};
+pub use watcher::{RemoteRootChange, RemoteWatcher, WatchOptions};
+
+use local_file::open_local_file;
+use session::WriterLock;
+use sync_status::RemoteRootSeen;
+
 #[cfg(test)]
 mod private_forest_tests;
-#[cfg(test)]
+#[cfg(all(test, feature = "reqwest"))]
 mod private_forest_tests2;
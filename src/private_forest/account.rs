@@ -0,0 +1,207 @@
+//! Passphrase protected account bundles: a single file holding everything needed to restore an
+//! account on another device or store, for migration and offline backup.
+//!
+//! Layout of a bundle: `MAGIC`, a 16 byte Argon2id salt, a 24 byte XChaCha20-Poly1305 nonce and
+//! the ciphertext. The plaintext is a big-endian `u32` length, that many bytes of JSON
+//! (`AccountBackup`) and, when blocks were included, a CARv1 archive of the forest.
+
+use std::collections::BTreeMap;
+
+use argon2::Argon2;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use chacha20poly1305::{
+    aead::{Aead, KeyInit},
+    Key, XChaCha20Poly1305, XNonce,
+};
+use libipld::Cid;
+use log::trace;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use wnfs::common::BlockStore;
+
+use super::{ExclusiveSession, HelperConfig, PrivateDirectoryHelper, WriterLock};
+use crate::error::describe;
+use crate::{
+    blockstore::FFIFriendlyBlockStore,
+    car::{reachable_blocks, read_car, write_car},
+};
+
+const MAGIC: &[u8; 8] = b"WNFSACC1";
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 24;
+
+/// An encrypted account bundle, see the module documentation for its layout.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AccountBundle(Vec<u8>);
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct AccountBackup {
+    version: u32,
+    /// Base64 encoded wnfs key.
+    wnfs_key: String,
+    /// Forest CIDs, oldest first. The last one is restored by `import_account`.
+    root_history: Vec<String>,
+    /// Commit times of the roots in `root_history`, in milliseconds since the Unix epoch.
+    #[serde(default)]
+    root_committed_at: BTreeMap<String, i64>,
+    settings: HelperConfig,
+}
+
+impl AccountBundle {
+    pub fn from_bytes(bytes: Vec<u8>) -> Self {
+        Self(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.0
+    }
+}
+
+impl<'a> PrivateDirectoryHelper<'a> {
+    /// Bundles the wnfs key, the root history with its commit times and the helper settings,
+    /// encrypted under
+    /// `passphrase`. With `include_blocks` every block reachable from the current root is added
+    /// as well, so the bundle restores into an empty store. Older roots of the history stay
+    /// readable only where their blocks are still stored.
+    pub async fn export_account(
+        &mut self,
+        passphrase: &str,
+        include_blocks: bool,
+    ) -> Result<AccountBundle, String> {
+        let current_root = *self
+            .root_history
+            .last()
+            .ok_or("wnfsError no committed root to export")?;
+        let backup = AccountBackup {
+            version: 1,
+            wnfs_key: BASE64.encode(&self.wnfs_key),
+            root_history: self
+                .root_history
+                .iter()
+                .map(|cid| cid.to_string())
+                .collect(),
+            root_committed_at: self
+                .root_history
+                .iter()
+                .filter_map(|cid| {
+                    let committed_at = self.root_committed_at.get(cid)?;
+                    Some((cid.to_string(), *committed_at))
+                })
+                .collect(),
+            settings: self.config.to_owned(),
+        };
+        let json = serde_json::to_vec(&backup).map_err(|e| describe(&e))?;
+        let mut plaintext = Vec::with_capacity(json.len() + 4);
+        plaintext.extend_from_slice(&(json.len() as u32).to_be_bytes());
+        plaintext.extend_from_slice(&json);
+        if include_blocks {
+            let mut blocks = Vec::new();
+            for cid in reachable_blocks(&self.store, &current_root).await? {
+                let data = self.store.get_block(&cid).await.map_err(|e| {
+                    trace!("wnfsError in export_account: {:?}", e.to_string());
+                    describe(&e)
+                })?;
+                blocks.push((cid, data.to_vec()));
+            }
+            write_car(&mut plaintext, &[current_root], blocks)?;
+        }
+
+        let mut salt = [0u8; SALT_LEN];
+        let mut nonce = [0u8; NONCE_LEN];
+        rand::thread_rng().fill_bytes(&mut salt);
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let cipher = bundle_cipher(passphrase, &salt)?;
+        let ciphertext = cipher
+            .encrypt(XNonce::from_slice(&nonce), plaintext.as_slice())
+            .map_err(|e| format!("wnfsError unable to encrypt account bundle: {}", e))?;
+
+        let mut bundle = Vec::with_capacity(MAGIC.len() + SALT_LEN + NONCE_LEN + ciphertext.len());
+        bundle.extend_from_slice(MAGIC);
+        bundle.extend_from_slice(&salt);
+        bundle.extend_from_slice(&nonce);
+        bundle.extend_from_slice(&ciphertext);
+        Ok(AccountBundle(bundle))
+    }
+
+    /// Restores an account from `bundle` into `store` and opens it in an exclusive session at
+    /// the latest root of the bundled history. Blocks contained in the bundle are written to
+    /// `store` first, so it may be empty; otherwise the store must already hold the forest.
+    /// Fails with `WnfsUtilsError::ForestLocked` while the forest is open for writing.
+    pub async fn import_account(
+        store: &mut FFIFriendlyBlockStore<'a>,
+        bundle: &AccountBundle,
+        passphrase: &str,
+    ) -> Result<ExclusiveSession<'a>, String> {
+        let bytes = bundle.as_bytes();
+        let header_len = MAGIC.len() + SALT_LEN + NONCE_LEN;
+        if bytes.len() < header_len || &bytes[..MAGIC.len()] != MAGIC {
+            return Err("wnfsError not an account bundle".to_string());
+        }
+        let salt = &bytes[MAGIC.len()..MAGIC.len() + SALT_LEN];
+        let nonce = &bytes[MAGIC.len() + SALT_LEN..header_len];
+        let cipher = bundle_cipher(passphrase, salt)?;
+        let plaintext = cipher
+            .decrypt(XNonce::from_slice(nonce), &bytes[header_len..])
+            .map_err(|_| "wnfsError wrong passphrase or corrupted account bundle".to_string())?;
+
+        let json_len = plaintext
+            .get(..4)
+            .and_then(|len| <[u8; 4]>::try_from(len).ok())
+            .map(|len| u32::from_be_bytes(len) as usize)
+            .ok_or("wnfsError truncated account bundle")?;
+        let json = plaintext
+            .get(4..4 + json_len)
+            .ok_or("wnfsError truncated account bundle")?;
+        let backup: AccountBackup = serde_json::from_slice(json).map_err(|e| describe(&e))?;
+
+        let mut car = &plaintext[4 + json_len..];
+        if !car.is_empty() {
+            let max_block_size = store.decode_limits().max_block_size;
+            let (_, blocks) = read_car(&mut car, max_block_size)?;
+            for (cid, data) in blocks {
+                let stored = store
+                    .put_block(data, cid.codec())
+                    .await
+                    .map_err(|e| describe(&e))?;
+                if stored != cid {
+                    trace!("wnfsError in import_account: block {:?} changed", cid);
+                    return Err(format!("wnfsError corrupted block {} in bundle", cid));
+                }
+            }
+        }
+
+        let root_history = backup
+            .root_history
+            .iter()
+            .map(|cid| Cid::try_from(cid.as_str()).map_err(|e| describe(&e)))
+            .collect::<Result<Vec<Cid>, String>>()?;
+        let latest = *root_history
+            .last()
+            .ok_or("wnfsError account bundle without a root")?;
+        let wnfs_key = BASE64.decode(&backup.wnfs_key).map_err(|e| describe(&e))?;
+        let lock = WriterLock::acquire(&wnfs_key)?;
+        let mut helper = Self::load_with_wnfs_key(store, latest, wnfs_key).await?;
+        for (cid, committed_at) in backup.root_committed_at {
+            let cid = Cid::try_from(cid.as_str()).map_err(|e| describe(&e))?;
+            helper.root_committed_at.insert(cid, committed_at);
+        }
+        helper
+            .root_committed_at
+            .retain(|cid, _| root_history.contains(cid));
+        helper.root_history = root_history;
+        helper.set_config(backup.settings);
+        Ok(ExclusiveSession::new(helper, lock))
+    }
+}
+
+fn bundle_cipher(passphrase: &str, salt: &[u8]) -> Result<XChaCha20Poly1305, String> {
+    let mut key = [0u8; 32];
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| format!("wnfsError unable to derive bundle key: {}", e))?;
+    Ok(XChaCha20Poly1305::new(Key::from_slice(&key)))
+}
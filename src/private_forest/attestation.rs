@@ -0,0 +1,277 @@
+//! Signed statements that a forest existed in a given state at a given time.
+//!
+//! An attestation counts the blocks reachable from a forest root and summarizes them in a
+//! Merkle root: the leaves are the SHA-256 of `0x00` and each block's CID, sorted by CID bytes,
+//! and each level hashes `0x01` and pairs of nodes, carrying an odd one up unchanged, so no inner
+//! node can pass for a leaf. The statement is signed with a [`DeviceSigningKey`] and names the
+//! signer as a `did:key`, so an auditor holding only the attestation can check which device made
+//! it, and with the blocks, that the store still holds that exact state.
+//!
+//! The signing key belongs to one device and is used for nothing else. It isn't derived from the
+//! wnfs key, which every device of the forest holds: each device generates its own and keeps its
+//! seed, so devices sharing a forest can't attest as each other.
+
+use std::fmt;
+
+use chrono::Utc;
+use libipld::{
+    cid::multibase::{self, Base},
+    Cid,
+};
+use log::trace;
+use rand::{thread_rng, RngCore};
+use rand_chacha::ChaCha12Rng;
+use rand_core::SeedableRng;
+use rsa::{
+    pkcs1::{DecodeRsaPublicKey, EncodeRsaPublicKey},
+    Pkcs1v15Sign, RsaPrivateKey, RsaPublicKey,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use wnfs::common::BlockStore;
+
+use super::{manifest::hex_to_bytes, PrivateDirectoryHelper};
+use crate::car::{reachable_blocks, verify_block};
+use crate::error::describe;
+
+// Multicodec of an RSA public key, as an unsigned varint, prefixing the key in a `did:key`.
+const RSA_PUB_MULTICODEC: [u8; 2] = [0x85, 0x24];
+
+// Prefixes of the hashed Merkle leaves and inner nodes.
+const MERKLE_LEAF: u8 = 0x00;
+const MERKLE_NODE: u8 = 0x01;
+
+// Mixed into the seed, so a seed also used for something else never gives this key.
+const SIGNING_KEY_CONTEXT: &[u8] = b"wnfsutils device signing key";
+
+/// The RSA key one device signs attestations with, derived from a 32-byte seed the device keeps
+/// to itself, see the module docs.
+pub struct DeviceSigningKey {
+    seed: [u8; 32],
+    key: RsaPrivateKey,
+}
+
+impl DeviceSigningKey {
+    /// A key from a fresh random seed.
+    pub fn generate() -> Result<Self, String> {
+        let mut seed = [0u8; 32];
+        thread_rng().fill_bytes(&mut seed);
+        Self::from_seed(seed)
+    }
+
+    pub fn from_seed(seed: [u8; 32]) -> Result<Self, String> {
+        let derived: [u8; 32] = Sha256::new()
+            .chain_update(SIGNING_KEY_CONTEXT)
+            .chain_update(seed)
+            .finalize()
+            .into();
+        let key = RsaPrivateKey::new(&mut ChaCha12Rng::from_seed(derived), 2048).map_err(|e| {
+            trace!(
+                "wnfsError in DeviceSigningKey::from_seed: {:?}",
+                e.to_string()
+            );
+            describe(&e)
+        })?;
+        Ok(Self { seed, key })
+    }
+
+    /// The secret to persist on the device, e.g. in the platform keychain.
+    pub fn seed(&self) -> [u8; 32] {
+        self.seed
+    }
+
+    /// `did:key` of the key, which attestations and manifests it signs name as their
+    /// `device_did`.
+    pub fn did(&self) -> Result<String, String> {
+        did_from_public_key(&self.key.to_public_key())
+    }
+
+    // PKCS#1 v1.5 signature over a SHA-256 `digest`.
+    pub(super) fn sign(&self, digest: &[u8]) -> Result<Vec<u8>, String> {
+        self.key
+            .sign(Pkcs1v15Sign::new::<Sha256>(), digest)
+            .map_err(|e| {
+                trace!("wnfsError in DeviceSigningKey::sign: {:?}", e.to_string());
+                describe(&e)
+            })
+    }
+}
+
+// Keeps the seed out of logs.
+impl fmt::Debug for DeviceSigningKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DeviceSigningKey").finish_non_exhaustive()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Attestation {
+    pub forest_cid: String,
+    pub total_blocks: u64,
+    pub total_bytes: u64,
+    /// Hex encoded Merkle root over the reachable blocks, see the module docs.
+    pub merkle_root: String,
+    /// RFC 3339 time of the attestation.
+    pub attested_at: String,
+    /// `did:key` of the signing key.
+    pub device_did: String,
+}
+
+/// An attestation with a PKCS#1 v1.5 signature over the SHA-256 of its JSON encoding.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SignedAttestation {
+    pub attestation: Attestation,
+    /// Hex encoded signature.
+    pub signature: String,
+}
+
+impl SignedAttestation {
+    pub fn to_json(&self) -> Result<String, String> {
+        serde_json::to_string_pretty(self).map_err(|e| describe(&e))
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, String> {
+        serde_json::from_str(json).map_err(|e| describe(&e))
+    }
+
+    /// Checks the signature against the key named by `device_did`. Callers that know the
+    /// signer should also compare `device_did` with the DID they expect.
+    pub fn verify(&self) -> Result<(), String> {
+        let public_key = public_key_from_did(&self.attestation.device_did)?;
+        let signature = hex_to_bytes(&self.signature)?;
+        public_key
+            .verify(
+                Pkcs1v15Sign::new::<Sha256>(),
+                &attestation_digest(&self.attestation)?,
+                &signature,
+            )
+            .map_err(|e| format!("wnfsError attestation signature is invalid: {}", e))
+    }
+
+    /// Checks the signature and that `store` holds the attested state: every reachable block
+    /// intact, with the same count, size and Merkle root.
+    pub async fn check_store(&self, store: &impl BlockStore) -> Result<(), String> {
+        self.verify()?;
+        let forest_cid =
+            Cid::try_from(self.attestation.forest_cid.as_str()).map_err(|e| describe(&e))?;
+        let (total_blocks, total_bytes, merkle_root) = summarize(store, &forest_cid).await?;
+        if total_blocks != self.attestation.total_blocks
+            || total_bytes != self.attestation.total_bytes
+            || merkle_root != self.attestation.merkle_root
+        {
+            return Err(format!(
+                "wnfsError store doesn't match the attested state of {}",
+                forest_cid
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl<'a> PrivateDirectoryHelper<'a> {
+    /// Attests the state of the forest at `forest_cid` in the helper's store, signed by
+    /// `signer`, see the module docs. Fails when a reachable block is missing or corrupt.
+    pub async fn attest(
+        &self,
+        forest_cid: &Cid,
+        signer: &DeviceSigningKey,
+    ) -> Result<SignedAttestation, String> {
+        let (total_blocks, total_bytes, merkle_root) =
+            summarize(&self.store, forest_cid).await.map_err(|e| {
+                trace!("wnfsError in attest: {:?}", e);
+                e
+            })?;
+        let attestation = Attestation {
+            forest_cid: forest_cid.to_string(),
+            total_blocks,
+            total_bytes,
+            merkle_root,
+            attested_at: Utc::now().to_rfc3339(),
+            device_did: signer.did()?,
+        };
+        let signature = signer.sign(&attestation_digest(&attestation)?)?;
+        Ok(SignedAttestation {
+            attestation,
+            signature: Self::bytes_to_hex_str(&signature),
+        })
+    }
+}
+
+impl<'a> PrivateDirectoryHelper<'a> {
+    pub fn synced_attest(
+        &self,
+        forest_cid: &Cid,
+        signer: &DeviceSigningKey,
+    ) -> Result<SignedAttestation, String> {
+        Self::run_limited(
+            &self.operation_limiter(),
+            "attest",
+            self.attest(forest_cid, signer),
+        )
+    }
+}
+
+// Block count, total size and hex encoded Merkle root of the blocks reachable from `root`.
+async fn summarize(store: &impl BlockStore, root: &Cid) -> Result<(u64, u64, String), String> {
+    let mut cids = reachable_blocks(store, root).await?;
+    cids.sort_by_key(|cid| cid.to_bytes());
+    let mut total_bytes = 0;
+    let mut level = Vec::with_capacity(cids.len());
+    for cid in cids.iter() {
+        let data = store.get_block(cid).await.map_err(|e| describe(&e))?;
+        verify_block(cid, &data)?;
+        total_bytes += data.len() as u64;
+        level.push(
+            Sha256::new()
+                .chain_update([MERKLE_LEAF])
+                .chain_update(cid.to_bytes())
+                .finalize()
+                .to_vec(),
+        );
+    }
+    while level.len() > 1 {
+        level = level
+            .chunks(2)
+            .map(|pair| match pair {
+                [left, right] => Sha256::new()
+                    .chain_update([MERKLE_NODE])
+                    .chain_update(left)
+                    .chain_update(right)
+                    .finalize()
+                    .to_vec(),
+                _ => pair[0].to_owned(),
+            })
+            .collect();
+    }
+    let merkle_root = level.pop().unwrap_or_default();
+    Ok((
+        cids.len() as u64,
+        total_bytes,
+        PrivateDirectoryHelper::bytes_to_hex_str(&merkle_root),
+    ))
+}
+
+fn attestation_digest(attestation: &Attestation) -> Result<Vec<u8>, String> {
+    let encoded = serde_json::to_vec(attestation).map_err(|e| describe(&e))?;
+    Ok(Sha256::digest(encoded).to_vec())
+}
+
+fn did_from_public_key(public_key: &RsaPublicKey) -> Result<String, String> {
+    let der = public_key.to_pkcs1_der().map_err(|e| describe(&e))?;
+    let key = [RSA_PUB_MULTICODEC.as_slice(), der.as_bytes()].concat();
+    Ok(format!(
+        "did:key:{}",
+        multibase::encode(Base::Base58Btc, key)
+    ))
+}
+
+pub(super) fn public_key_from_did(did: &str) -> Result<RsaPublicKey, String> {
+    let encoded = did
+        .strip_prefix("did:key:")
+        .ok_or_else(|| format!("wnfsError {} isn't a did:key", did))?;
+    let (_, key) = multibase::decode(encoded).map_err(|e| describe(&e))?;
+    let der = key
+        .strip_prefix(RSA_PUB_MULTICODEC.as_slice())
+        .ok_or_else(|| format!("wnfsError {} isn't an RSA key", did))?;
+    RsaPublicKey::from_pkcs1_der(der).map_err(|e| describe(&e))
+}
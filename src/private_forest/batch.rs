@@ -0,0 +1,225 @@
+//! Batch operations reporting the outcome of every item.
+//!
+//! `write_files` and `rm_recursive` run all items in one transaction and commit a single root
+//! for the items that succeeded. Each item runs in its own savepoint, so a failed item leaves
+//! nothing behind. By default the batch stops at the first failure; with
+//! `BatchOptions::continue_on_error` it records the failure and goes on with the next item.
+//! `ingest` takes the same option through `TreeCopyOptions`.
+
+use libipld::Cid;
+use log::trace;
+
+use super::PrivateDirectoryHelper;
+use crate::error::{ErrorCode, WnfsUtilsError};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BatchOptions {
+    /// Record failed items and go on with the next one instead of stopping.
+    pub continue_on_error: bool,
+}
+
+/// Why an item failed, derived from the error it failed with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BatchErrorKind {
+    /// The local source couldn't be read.
+    Source,
+    /// Nothing exists at the path.
+    NotFound,
+    /// The path is under legal hold.
+    Held,
+    /// The path or its metadata break a configured limit or naming rule.
+    Invalid,
+    /// The copy in the forest doesn't match its source.
+    Integrity,
+    Other,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BatchItemError {
+    pub kind: BatchErrorKind,
+    pub message: String,
+}
+
+impl BatchItemError {
+    /// An error reading the local source of an item.
+    pub(super) fn source(message: String) -> Self {
+        Self {
+            kind: BatchErrorKind::Source,
+            message,
+        }
+    }
+}
+
+impl BatchErrorKind {
+    fn of(code: ErrorCode) -> Self {
+        match code {
+            ErrorCode::Corrupt => Self::Integrity,
+            ErrorCode::Held => Self::Held,
+            ErrorCode::InvalidArgument | ErrorCode::LimitExceeded => Self::Invalid,
+            ErrorCode::NotFound => Self::NotFound,
+            _ => Self::Other,
+        }
+    }
+}
+
+impl From<WnfsUtilsError> for BatchItemError {
+    fn from(error: WnfsUtilsError) -> Self {
+        Self {
+            kind: BatchErrorKind::of(error.code()),
+            message: error.to_string(),
+        }
+    }
+}
+
+// Helper errors are strings carrying the code of the error they were made from, see `error`.
+impl From<String> for BatchItemError {
+    fn from(message: String) -> Self {
+        Self {
+            kind: BatchErrorKind::of(ErrorCode::of_message(&message)),
+            message,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BatchItem {
+    pub path: Vec<String>,
+    pub result: Result<(), BatchItemError>,
+}
+
+/// Outcome of a batch, with one item per attempted path in input order.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BatchReport {
+    pub items: Vec<BatchItem>,
+    /// Whether the batch stopped at a failure, leaving the remaining items unattempted.
+    pub stopped: bool,
+    /// The forest committed with the succeeded items, `None` if none succeeded.
+    pub forest_cid: Option<Cid>,
+}
+
+impl BatchReport {
+    pub fn succeeded(&self) -> usize {
+        self.items.iter().filter(|item| item.result.is_ok()).count()
+    }
+
+    pub fn failures(&self) -> impl Iterator<Item = &BatchItem> {
+        self.items.iter().filter(|item| item.result.is_err())
+    }
+
+    // Records an item, returning whether the batch goes on.
+    fn record(
+        &mut self,
+        path: Vec<String>,
+        result: Result<(), BatchItemError>,
+        options: BatchOptions,
+    ) -> bool {
+        if let Err(e) = &result {
+            trace!("wnfsError in batch for {:?}: {:?}", path, e.message);
+            self.stopped = !options.continue_on_error;
+        }
+        self.items.push(BatchItem { path, result });
+        !self.stopped
+    }
+}
+
+impl<'a> PrivateDirectoryHelper<'a> {
+    /// Writes `(path, content, modification_time_seconds)` entries like `write_file`, see the
+    /// module docs. Errors only if the resulting forest can't be committed.
+    pub async fn write_files(
+        &mut self,
+        files: Vec<(Vec<String>, Vec<u8>, i64)>,
+        options: BatchOptions,
+    ) -> Result<BatchReport, String> {
+        let mut report = BatchReport::default();
+        let mut tx = self.begin();
+        for (path, content, modification_time_seconds) in files {
+            let mut item = tx.begin();
+            let result = item
+                .write_file(&path, content, modification_time_seconds)
+                .await;
+            let result = match result {
+                Ok(_) => item.commit().await.map(|_| ()),
+                Err(e) => {
+                    item.rollback();
+                    Err(e)
+                }
+            };
+            if !report.record(path, result.map_err(BatchItemError::from), options) {
+                break;
+            }
+        }
+        report.forest_cid = match report.succeeded() {
+            0 => None,
+            _ => Some(tx.commit().await?),
+        };
+        Ok(report)
+    }
+
+    /// Removes each path with everything below it, see the module docs. Errors only if the
+    /// resulting forest can't be committed.
+    pub async fn rm_recursive(
+        &mut self,
+        paths: &[Vec<String>],
+        options: BatchOptions,
+    ) -> Result<BatchReport, String> {
+        let mut report = BatchReport::default();
+        let mut tx = self.begin();
+        for path in paths {
+            let mut item = tx.begin();
+            let result = match item.node_at(path).await {
+                Ok(Some(_)) => item
+                    .rm(path)
+                    .await
+                    .map(|_| ())
+                    .map_err(BatchItemError::from),
+                Ok(None) => Err(WnfsUtilsError::NotFound(path.join("/")).into()),
+                Err(e) => Err(BatchItemError::from(e)),
+            };
+            let result = match result {
+                Ok(()) => item
+                    .commit()
+                    .await
+                    .map(|_| ())
+                    .map_err(BatchItemError::from),
+                Err(e) => {
+                    item.rollback();
+                    Err(e)
+                }
+            };
+            if !report.record(path.to_owned(), result, options) {
+                break;
+            }
+        }
+        report.forest_cid = match report.succeeded() {
+            0 => None,
+            _ => Some(tx.commit().await?),
+        };
+        Ok(report)
+    }
+}
+
+impl<'a> PrivateDirectoryHelper<'a> {
+    pub fn synced_write_files(
+        &mut self,
+        files: Vec<(Vec<String>, Vec<u8>, i64)>,
+        options: BatchOptions,
+    ) -> Result<BatchReport, String> {
+        Self::run_limited(
+            &self.operation_limiter(),
+            "write_files",
+            self.write_files(files, options),
+        )
+    }
+
+    pub fn synced_rm_recursive(
+        &mut self,
+        paths: &[Vec<String>],
+        options: BatchOptions,
+    ) -> Result<BatchReport, String> {
+        Self::run_limited(
+            &self.operation_limiter(),
+            "rm_recursive",
+            self.rm_recursive(paths, options),
+        )
+    }
+}
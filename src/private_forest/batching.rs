@@ -0,0 +1,96 @@
+//! Coalescing the commits of chatty apps.
+//!
+//! Every mutation normally stores the forest and records a new root, which the app then
+//! publishes. With `HelperConfig::commit_batching` set, the first mutation opens a window and the
+//! mutations made within it only change the in-memory tree: they return the last committed
+//! root, and the first mutation after the window closed commits them all at once. Reads see the
+//! pending changes right away.
+//!
+//! The helper has no timer of its own, so a window closing without a further mutation stays
+//! open until `flush_commits` or `flush_due_commits` is called. Apps flush at their own
+//! boundaries, e.g. before going to the background. The helper flushes before it reloads another
+//! root and before handing out a root as the current one, e.g. for snapshots. It also tries to
+//! flush when it is dropped, so the changes are stored, but the root of that commit is only
+//! logged, a failure can't be reported and it can't run on a current-thread runtime.
+
+use std::time::Duration;
+
+use libipld::Cid;
+use serde::{Deserialize, Serialize};
+use web_time::Instant;
+
+use super::PrivateDirectoryHelper;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CommitBatching {
+    /// How long after the first pending mutation further ones are coalesced into its commit.
+    pub window_ms: u64,
+}
+
+impl Default for CommitBatching {
+    fn default() -> Self {
+        Self { window_ms: 500 }
+    }
+}
+
+impl<'a> PrivateDirectoryHelper<'a> {
+    // The root to return instead of committing when the mutation joins an open window.
+    pub(super) fn defer_commit(&mut self) -> Option<Cid> {
+        let window = Duration::from_millis(self.config.commit_batching?.window_ms);
+        let root = self.root_history.last().copied()?;
+        let opened = *self.pending_commit.get_or_insert_with(Instant::now);
+        (opened.elapsed() < window).then_some(root)
+    }
+
+    /// Whether mutations are waiting for their commit.
+    pub fn has_pending_commit(&self) -> bool {
+        self.pending_commit.is_some()
+    }
+
+    /// Commits the pending mutations, if any, and returns the latest root. Within a transaction
+    /// they are left for the transaction's commit.
+    pub async fn flush_commits(&mut self) -> Result<Cid, String> {
+        if self.pending_commit.is_some() && !self.in_transaction {
+            return self.commit_now().await;
+        }
+        self.root_history
+            .last()
+            .copied()
+            .ok_or_else(|| "wnfsError no root recorded".to_string())
+    }
+
+    /// Commits the pending mutations once their window has closed, e.g. from a UI timer.
+    /// Returns the new root, `None` when nothing was committed. An open `Transaction` defers
+    /// this to its own commit.
+    pub async fn flush_due_commits(&mut self) -> Result<Option<Cid>, String> {
+        let window = self
+            .config
+            .commit_batching
+            .map(|batching| Duration::from_millis(batching.window_ms))
+            .unwrap_or_default();
+        match self.pending_commit {
+            Some(opened) if opened.elapsed() >= window && !self.in_transaction => {
+                Ok(Some(self.commit_now().await?))
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+impl<'a> PrivateDirectoryHelper<'a> {
+    pub fn synced_flush_commits(&mut self) -> Result<Cid, String> {
+        Self::run_limited(
+            &self.operation_limiter(),
+            "flush_commits",
+            self.flush_commits(),
+        )
+    }
+
+    pub fn synced_flush_due_commits(&mut self) -> Result<Option<Cid>, String> {
+        Self::run_limited(
+            &self.operation_limiter(),
+            "flush_due_commits",
+            self.flush_due_commits(),
+        )
+    }
+}
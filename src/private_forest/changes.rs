@@ -0,0 +1,67 @@
+//! Incremental directory listings for UIs that refresh after a sync: entries added, removed or
+//! modified in a directory since an earlier revision of the forest.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use libipld::Cid;
+use wnfs::common::Metadata;
+
+use super::PrivateDirectoryHelper;
+
+/// Difference between a directory at two revisions.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DirectoryChanges {
+    /// Revision the changes lead up to, to pass as `since_revision` on the next refresh.
+    pub revision: Option<Cid>,
+    pub added: Vec<(String, Metadata)>,
+    pub removed: Vec<String>,
+    /// Entries present at both revisions whose metadata, e.g. the modification time, differs.
+    pub modified: Vec<(String, Metadata)>,
+}
+
+impl DirectoryChanges {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.modified.is_empty()
+    }
+}
+
+impl<'a> PrivateDirectoryHelper<'a> {
+    /// Lists how the directory at `path_segments` changed since `since_revision`, a forest CID
+    /// from an earlier commit (e.g. the `revision` of the previous call). If the directory
+    /// didn't exist at that revision, all of its entries are reported as added.
+    pub async fn ls_changes(
+        &mut self,
+        path_segments: &[String],
+        since_revision: Cid,
+    ) -> Result<DirectoryChanges, String> {
+        // The revision reported must include every change listed.
+        let revision = self.flush_commits().await?;
+        let current = self.ls_files(path_segments).await?;
+        let mut view = self.open_at(since_revision).await?;
+        let previous = match view.exists(path_segments).await? {
+            true => view.ls_files(path_segments).await?,
+            false => Vec::new(),
+        };
+
+        let mut changes = DirectoryChanges {
+            revision: Some(revision),
+            ..Default::default()
+        };
+        let previous: BTreeMap<String, Metadata> = previous.into_iter().collect();
+        for (name, metadata) in current.iter() {
+            match previous.get(name) {
+                None => changes.added.push((name.to_owned(), metadata.to_owned())),
+                Some(previous_metadata) if previous_metadata != metadata => changes
+                    .modified
+                    .push((name.to_owned(), metadata.to_owned())),
+                Some(_) => {}
+            }
+        }
+        let current: BTreeSet<&String> = current.iter().map(|(name, _)| name).collect();
+        changes.removed = previous
+            .into_keys()
+            .filter(|name| !current.contains(name))
+            .collect();
+        Ok(changes)
+    }
+}
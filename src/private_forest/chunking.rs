@@ -0,0 +1,220 @@
+//! Chunk sizes for paged files picked by file size.
+//!
+//! Small chunks keep rewrites of documents cheap and let edits re-encrypt only the pages they
+//! touch; large chunks mean fewer blocks, and fewer round trips, for videos and archives. An
+//! [`AdaptiveChunking`] policy sorts files into a [`FileSizeClass`] by length and gives each class
+//! its own `PagedFileOptions`. The options a file was written with are recorded in its
+//! `PAGED_MARKER`, so changing the policy never breaks reading existing files: files keep their
+//! chunks until `rechunk_adaptive` moves them to what the policy picks for their current length,
+//! e.g. after a database grew into a larger class.
+//!
+//! Setting `HelperConfig::adaptive_chunking` applies the policy to every write: `write_file`,
+//! the stream writes and directory imports then store each file as a paged file chunked for
+//! its length, replacing a plain file at the same path. Content of unknown length is buffered
+//! until it passes `medium_from`; a stream that ends past `large_from` is rechunked once it is
+//! complete. `read_file`, `read_file_at` and the content hashes read paged files like plain
+//! ones.
+
+use futures::{AsyncRead, AsyncReadExt};
+use libipld::Cid;
+use log::trace;
+use serde::{Deserialize, Serialize};
+
+use super::{PagedFileOptions, PrivateDirectoryHelper, TransferProgress};
+use crate::error::describe;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum FileSizeClass {
+    Small,
+    Medium,
+    Large,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AdaptiveChunking {
+    /// Files of at least this many bytes are medium.
+    pub medium_from: u64,
+    /// Files of at least this many bytes are large.
+    pub large_from: u64,
+    pub small: PagedFileOptions,
+    pub medium: PagedFileOptions,
+    pub large: PagedFileOptions,
+}
+
+impl Default for AdaptiveChunking {
+    /// 16KiB chunks below 1MiB, the default 64KiB below 64MiB and 1MiB chunks above.
+    fn default() -> Self {
+        Self {
+            medium_from: 1024 * 1024,
+            large_from: 64 * 1024 * 1024,
+            small: PagedFileOptions {
+                page_size: 4096,
+                pages_per_chunk: 4,
+            },
+            medium: PagedFileOptions::default(),
+            large: PagedFileOptions {
+                page_size: 4096,
+                pages_per_chunk: 256,
+            },
+        }
+    }
+}
+
+impl AdaptiveChunking {
+    pub fn class_of(&self, len: u64) -> FileSizeClass {
+        match len {
+            len if len >= self.large_from => FileSizeClass::Large,
+            len if len >= self.medium_from => FileSizeClass::Medium,
+            _ => FileSizeClass::Small,
+        }
+    }
+
+    pub fn options_for(&self, len: u64) -> PagedFileOptions {
+        match self.class_of(len) {
+            FileSizeClass::Small => self.small,
+            FileSizeClass::Medium => self.medium,
+            FileSizeClass::Large => self.large,
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.medium_from > self.large_from {
+            return Err("wnfsError medium files must start below large ones".to_string());
+        }
+        let classes = [self.small, self.medium, self.large];
+        if classes
+            .iter()
+            .any(|options| options.page_size == 0 || options.pages_per_chunk == 0)
+        {
+            return Err("wnfsError page size and pages per chunk must be positive".to_string());
+        }
+        Ok(())
+    }
+}
+
+impl<'a> PrivateDirectoryHelper<'a> {
+    /// The policy in `HelperConfig::adaptive_chunking`, or the default one. Only a configured
+    /// policy applies to `write_file` and the other default writes.
+    pub fn chunking_policy(&self) -> AdaptiveChunking {
+        self.config.adaptive_chunking.unwrap_or_default()
+    }
+
+    /// Creates an empty paged file with the options the chunking policy picks for a file of
+    /// `expected_len` bytes, e.g. the size of a database being restored.
+    pub async fn create_paged_file_for_size(
+        &mut self,
+        path_segments: &[String],
+        expected_len: u64,
+    ) -> Result<Cid, String> {
+        let policy = self.chunking_policy();
+        policy.validate()?;
+        self.create_paged_file(path_segments, policy.options_for(expected_len))
+            .await
+    }
+
+    /// Writes `content` as a new paged file chunked for its length, in one commit.
+    pub async fn write_paged_file(
+        &mut self,
+        path_segments: &[String],
+        content: &[u8],
+    ) -> Result<Cid, String> {
+        let mut tx = self.begin();
+        tx.create_paged_file_for_size(path_segments, content.len() as u64)
+            .await?;
+        tx.write_at(path_segments, 0, content).await?;
+        tx.commit().await
+    }
+
+    // Writes `content` as a paged file chunked by `policy`, replacing the file at
+    // `path_segments`, without scanning or committing. `len` is the length of the content when
+    // it is known up front. Returns the length written.
+    pub(super) async fn write_adaptive_raw<R: AsyncRead + Unpin>(
+        &mut self,
+        path_segments: &[String],
+        policy: &AdaptiveChunking,
+        mut content: R,
+        len: Option<u64>,
+    ) -> Result<u64, String> {
+        policy.validate()?;
+        if let Some(is_file) = self
+            .node_at(path_segments)
+            .await?
+            .map(|node| node.is_file())
+        {
+            if !is_file && !self.is_paged_file(path_segments).await? {
+                trace!(
+                    "wnfsError in write_adaptive_raw: {:?} is a directory",
+                    path_segments
+                );
+                return Err(format!(
+                    "wnfsError {} is a directory",
+                    path_segments.join("/")
+                ));
+            }
+            self.rm_raw(path_segments).await?;
+        }
+        let (options, head) = match len {
+            Some(len) => (policy.options_for(len), Vec::new()),
+            None => {
+                let mut head = Vec::new();
+                (&mut content)
+                    .take(policy.medium_from)
+                    .read_to_end(&mut head)
+                    .await
+                    .map_err(|e| {
+                        trace!("wnfsError in write_adaptive_raw: {:?}", e.to_string());
+                        describe(&e)
+                    })?;
+                (policy.options_for(head.len() as u64), head)
+            }
+        };
+        let content = futures::io::Cursor::new(head).chain(content);
+        let len = self
+            .write_paged_raw(path_segments, options, content)
+            .await?;
+        if policy.options_for(len) != options {
+            self.rechunk_with(path_segments, &|len| policy.options_for(len), None)
+                .await?;
+        }
+        Ok(len)
+    }
+
+    /// Moves every paged file below `path_segments` to the options the chunking policy picks
+    /// for its current length, like `rechunk`. Returns the number of files migrated.
+    pub async fn rechunk_adaptive(
+        &mut self,
+        path_segments: &[String],
+        progress: Option<&mut dyn FnMut(&TransferProgress)>,
+    ) -> Result<usize, String> {
+        let policy = self.chunking_policy();
+        policy.validate()?;
+        self.rechunk_with(path_segments, &|len| policy.options_for(len), progress)
+            .await
+    }
+}
+
+impl<'a> PrivateDirectoryHelper<'a> {
+    pub fn synced_write_paged_file(
+        &mut self,
+        path_segments: &[String],
+        content: &[u8],
+    ) -> Result<Cid, String> {
+        Self::run_limited(
+            &self.operation_limiter(),
+            "write_paged_file",
+            self.write_paged_file(path_segments, content),
+        )
+    }
+
+    pub fn synced_rechunk_adaptive(
+        &mut self,
+        path_segments: &[String],
+        progress: Option<&mut dyn FnMut(&TransferProgress)>,
+    ) -> Result<usize, String> {
+        Self::run_limited(
+            &self.operation_limiter(),
+            "rechunk_adaptive",
+            self.rechunk_adaptive(path_segments, progress),
+        )
+    }
+}
@@ -0,0 +1,174 @@
+//! Duplicate detection across a subtree and conversion of duplicate files into copies that share
+//! their encrypted content blocks.
+
+use std::collections::BTreeMap;
+
+use chrono::Utc;
+use libipld::{Cid, Ipld};
+use log::trace;
+
+use super::{PrivateDirectoryHelper, CONTENT_HASH_KEY};
+use crate::error::{describe, WnfsUtilsError};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DuplicateGroup {
+    /// Hex encoded SHA-256 of the shared content.
+    pub content_hash: String,
+    pub size: u64,
+    /// Paths holding this content. The first one is kept by `apply_dedup`.
+    pub paths: Vec<Vec<String>>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DuplicateReport {
+    pub groups: Vec<DuplicateGroup>,
+    /// Bytes that stop being stored separately once every group is deduplicated.
+    pub reclaimable_bytes: u64,
+}
+
+impl<'a> PrivateDirectoryHelper<'a> {
+    /// Groups the files below `path_segments` by content hash. Files with a hash recorded in
+    /// their metadata (see `CONTENT_HASH_KEY`) are grouped by it without being read; other
+    /// files are streamed and hashed. `apply_dedup` re-hashes every group before changing it.
+    pub async fn analyze_duplicates(
+        &mut self,
+        path_segments: &[String],
+    ) -> Result<DuplicateReport, String> {
+        let (dirs, _) = self.collect_subtree(path_segments).await?;
+        let mut by_hash: BTreeMap<(String, u64), Vec<Vec<String>>> = BTreeMap::new();
+
+        for dir in dirs {
+            for (name, metadata) in self.ls_files(&dir).await? {
+                let mut path = dir.to_owned();
+                path.push(name);
+                let is_file = matches!(self.node_at(&path).await?, Some(node) if node.is_file());
+                if !is_file {
+                    continue;
+                }
+                let (hash, size) = match metadata.get(CONTENT_HASH_KEY) {
+                    Some(Ipld::String(hash)) => {
+                        let mut handle = self.open_file(&path).await?;
+                        (hash.to_owned(), handle.len(self).await?)
+                    }
+                    _ => {
+                        let (size, digest) = self.hash_forest_file(&path, None).await?;
+                        (Self::bytes_to_hex_str(&digest), size)
+                    }
+                };
+                by_hash.entry((hash, size)).or_default().push(path);
+            }
+        }
+
+        let mut report = DuplicateReport::default();
+        for ((content_hash, size), paths) in by_hash {
+            if paths.len() < 2 {
+                continue;
+            }
+            report.reclaimable_bytes += size * (paths.len() as u64 - 1);
+            report.groups.push(DuplicateGroup {
+                content_hash,
+                size,
+                paths,
+            });
+        }
+        Ok(report)
+    }
+
+    /// Replaces every duplicate in `report` with a copy of the group's first path, so all of
+    /// them reference the same content blocks. Each replaced file keeps its own metadata. Runs
+    /// as a single transaction, so a duplicate that can't be replaced, e.g. under legal hold,
+    /// leaves the tree unchanged.
+    ///
+    /// Every path of every group is hashed again first, and nothing is changed unless each one
+    /// still holds its group's content; otherwise this fails with
+    /// `WnfsUtilsError::DuplicateChanged`.
+    pub async fn apply_dedup(&mut self, report: &DuplicateReport) -> Result<Cid, String> {
+        for group in report.groups.iter() {
+            for path in group.paths.iter() {
+                let (size, digest) = self.hash_forest_file(path, None).await?;
+                if size != group.size || Self::bytes_to_hex_str(&digest) != group.content_hash {
+                    let e = WnfsUtilsError::DuplicateChanged {
+                        path: path.join("/"),
+                    };
+                    trace!("wnfsError in apply_dedup: {:?}", e.to_string());
+                    return Err(e.to_string());
+                }
+            }
+        }
+
+        let mut tx = self.begin();
+        for group in report.groups.iter() {
+            let Some((canonical, duplicates)) = group.paths.split_first() else {
+                continue;
+            };
+            for duplicate in duplicates {
+                tx.replace_duplicate(canonical, duplicate).await?;
+            }
+        }
+        tx.commit().await
+    }
+
+    // Replaces the file at `duplicate` with a copy of `canonical` that keeps the metadata of
+    // the file it replaces, such as its times and content type.
+    async fn replace_duplicate(
+        &mut self,
+        canonical: &[String],
+        duplicate: &[String],
+    ) -> Result<(), String> {
+        self.check_not_held(duplicate, true).await?;
+        let metadata = match self.node_at(duplicate).await? {
+            Some(node) => node
+                .as_file()
+                .map_err(|e| describe(&e))?
+                .get_metadata()
+                .to_owned(),
+            None => return Err(WnfsUtilsError::NotFound(duplicate.join("/")).to_string()),
+        };
+        let canonical = &self.resolve_path(canonical).await?;
+        let duplicate = &self.resolve_path(duplicate).await?;
+        let forest = &mut self.forest;
+        let root_dir = &mut self.root_dir;
+        root_dir
+            .rm(duplicate, true, forest, &mut self.store)
+            .await
+            .map_err(|e| {
+                trace!("wnfsError in apply_dedup on rm: {:?}", e.to_string());
+                describe(&e)
+            })?;
+        let time = Utc::now();
+        root_dir
+            .cp(
+                canonical,
+                duplicate,
+                true,
+                time,
+                forest,
+                &mut self.store,
+                &mut self.rng,
+            )
+            .await
+            .map_err(|e| {
+                trace!("wnfsError in apply_dedup on cp: {:?}", e.to_string());
+                describe(&e)
+            })?;
+        let file = root_dir
+            .open_file_mut(
+                duplicate,
+                true,
+                time,
+                forest,
+                &mut self.store,
+                &mut self.rng,
+            )
+            .await
+            .map_err(|e| {
+                trace!(
+                    "wnfsError in apply_dedup on open_file_mut: {:?}",
+                    e.to_string()
+                );
+                describe(&e)
+            })?;
+        *file.get_metadata_mut() = metadata;
+        Ok(())
+    }
+}
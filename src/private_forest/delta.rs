@@ -0,0 +1,329 @@
+//! rsync-style deltas between a local file and the current revision of a forest file.
+//!
+//! The previous revision is split into `DELTA_BLOCK_SIZE` blocks, each with an Adler-32 style
+//! rolling checksum and a SHA-256, and the local file is scanned for those blocks at every
+//! offset. wnfs derives a fresh content key for every `set_content`, so a changed plain file is
+//! still re-encrypted as a whole; the delta makes the size of the change visible to sync tools
+//! and lets `write_file_delta` skip unchanged files, which are neither re-encrypted nor uploaded.
+//!
+//! Paged files (see `PagedFileOptions`), which is how large files are stored once
+//! `HelperConfig::adaptive_chunking` is set, are compared chunk by chunk instead, and
+//! `write_file_delta` re-encrypts and uploads only the chunks that changed.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fs::File,
+    io::{BufReader, Read},
+};
+
+use chrono::Utc;
+use futures::StreamExt;
+use libipld::Cid;
+use log::trace;
+use sha2::{Digest, Sha256};
+
+use super::PrivateDirectoryHelper;
+use crate::error::describe;
+
+pub const DELTA_BLOCK_SIZE: usize = 64 * 1024;
+
+const ADLER_MODULUS: u32 = 65521;
+
+/// Result of comparing a local file with the forest copy.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FileDelta {
+    /// Bytes of the local file found in blocks of the previous revision.
+    pub matched_bytes: u64,
+    /// Bytes of the local file that have to be sent as new data.
+    pub literal_bytes: u64,
+    /// Whether the local file is identical to the previous revision.
+    pub unchanged: bool,
+    /// New forest root, `None` when nothing was written.
+    pub written: Option<Cid>,
+}
+
+// Rolling checksum over a window, as in rsync: `a` sums the bytes, `b` sums the running `a`s.
+#[derive(Default)]
+struct RollingChecksum {
+    a: u32,
+    b: u32,
+    len: u32,
+}
+
+impl RollingChecksum {
+    fn of(data: &[u8]) -> Self {
+        let mut checksum = Self::default();
+        for byte in data {
+            checksum.push(*byte);
+        }
+        checksum
+    }
+
+    fn push(&mut self, byte: u8) {
+        self.a = (self.a + byte as u32) % ADLER_MODULUS;
+        self.b = (self.b + self.a) % ADLER_MODULUS;
+        self.len += 1;
+    }
+
+    fn pop(&mut self, byte: u8) {
+        let byte = byte as u32;
+        self.a = (self.a + ADLER_MODULUS - byte) % ADLER_MODULUS;
+        let removed = (self.len as u64 * byte as u64 % ADLER_MODULUS as u64) as u32;
+        self.b = (self.b + ADLER_MODULUS - removed) % ADLER_MODULUS;
+        self.len -= 1;
+    }
+
+    fn value(&self) -> u32 {
+        (self.b << 16) | self.a
+    }
+}
+
+struct BlockSignature {
+    index: usize,
+    len: usize,
+    strong: Vec<u8>,
+}
+
+impl<'a> PrivateDirectoryHelper<'a> {
+    /// Computes the delta of `local_filename` against the forest file at `path_segments`
+    /// without writing anything. A missing forest file makes the whole local file literal.
+    pub async fn file_delta(
+        &mut self,
+        path_segments: &[String],
+        local_filename: &String,
+    ) -> Result<FileDelta, String> {
+        if self.is_paged_file(path_segments).await? {
+            return self.paged_delta(path_segments, local_filename, false).await;
+        }
+        let signatures = self.block_signatures(path_segments).await?;
+        let file = File::open(local_filename).map_err(|e| describe(&e))?;
+        match signatures {
+            Some(signatures) => Self::scan_delta(&signatures, BufReader::new(file)),
+            None => Ok(FileDelta {
+                literal_bytes: file.metadata().map_err(|e| describe(&e))?.len(),
+                ..Default::default()
+            }),
+        }
+    }
+
+    /// Replaces the forest file at `path_segments` with `local_filename` unless both already
+    /// hold the same content, and reports the delta between them. A paged file only has the
+    /// chunks that differ rewritten.
+    pub async fn write_file_delta(
+        &mut self,
+        path_segments: &[String],
+        local_filename: &String,
+    ) -> Result<FileDelta, String> {
+        if self.is_paged_file(path_segments).await? {
+            self.check_not_held(path_segments, false).await?;
+            let mut delta = self
+                .paged_delta(path_segments, local_filename, true)
+                .await?;
+            if !delta.unchanged {
+                delta.written = Some(self.commit().await?);
+            }
+            return Ok(delta);
+        }
+        let mut delta = self.file_delta(path_segments, local_filename).await?;
+        if !delta.unchanged {
+            delta.written = Some(
+                self.write_file_stream_from_path(path_segments, local_filename)
+                    .await?,
+            );
+        }
+        Ok(delta)
+    }
+
+    // Compares `local_filename` with the paged file at `path_segments` chunk by chunk. With
+    // `write`, every chunk that differs is rewritten on its own, the content scanners see those
+    // chunks alone, and the length follows the local file. Doesn't commit.
+    async fn paged_delta(
+        &mut self,
+        path_segments: &[String],
+        local_filename: &String,
+        write: bool,
+    ) -> Result<FileDelta, String> {
+        let options = self.paged_file_options(path_segments).await?;
+        let stored_len = self.paged_file_len(path_segments).await?;
+        let chunk_size = options.page_size as u64 * options.pages_per_chunk as u64;
+        let mut reader = BufReader::new(File::open(local_filename).map_err(|e| describe(&e))?);
+        let mut delta = FileDelta::default();
+        let mut entries = Vec::new();
+        let mut len: u64 = 0;
+        loop {
+            let mut chunk = Vec::with_capacity(chunk_size as usize);
+            (&mut reader)
+                .take(chunk_size)
+                .read_to_end(&mut chunk)
+                .map_err(|e| describe(&e))?;
+            if chunk.is_empty() {
+                break;
+            }
+            let read = chunk.len() as u64;
+            let stored = self.read_at(path_segments, len, chunk.len()).await?;
+            if stored == chunk {
+                delta.matched_bytes += read;
+            } else {
+                delta.literal_bytes += read;
+                if write {
+                    entries.extend(self.scan_content(path_segments, &chunk).await?);
+                    self.write_chunk(path_segments, len / chunk_size, chunk)
+                        .await?;
+                }
+            }
+            len += read;
+            if read < chunk_size {
+                break;
+            }
+        }
+        delta.unchanged = delta.literal_bytes == 0 && len == stored_len;
+        if !write || delta.unchanged {
+            return Ok(delta);
+        }
+
+        if len != stored_len {
+            self.set_paged_len_raw(path_segments, len).await?;
+        }
+        if !entries.is_empty() {
+            self.put_file_metadata(&Self::paged_marker_path(path_segments), entries, Utc::now())
+                .await?;
+        }
+        if len > 0 {
+            let mut last_chunk = path_segments.to_vec();
+            last_chunk.push(Self::chunk_name((len - 1) / chunk_size));
+            self.split_parent_if_needed(&last_chunk).await?;
+        }
+        Ok(delta)
+    }
+
+    // Signatures of the current revision, keyed by rolling checksum.
+    async fn block_signatures(
+        &mut self,
+        path_segments: &[String],
+    ) -> Result<Option<HashMap<u32, Vec<BlockSignature>>>, String> {
+        let file = match self.node_at(path_segments).await? {
+            Some(node) if node.is_file() => node.as_file().map_err(|e| describe(&e))?,
+            _ => return Ok(None),
+        };
+        let mut signatures: HashMap<u32, Vec<BlockSignature>> = HashMap::new();
+
+        let mut add_block = |index: usize, block: &[u8]| {
+            signatures
+                .entry(RollingChecksum::of(block).value())
+                .or_default()
+                .push(BlockSignature {
+                    index,
+                    len: block.len(),
+                    strong: Sha256::digest(block).to_vec(),
+                });
+        };
+        let mut index = 0;
+        let mut pending: Vec<u8> = Vec::with_capacity(DELTA_BLOCK_SIZE);
+        let mut stream = file.stream_content(0, &self.forest, &self.store);
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk.map_err(|e| {
+                trace!("wnfsError in block_signatures: {:?}", e.to_string());
+                describe(&e)
+            })?;
+            let mut chunk = chunk.as_slice();
+            while !chunk.is_empty() {
+                let take = (DELTA_BLOCK_SIZE - pending.len()).min(chunk.len());
+                pending.extend_from_slice(&chunk[..take]);
+                chunk = &chunk[take..];
+                if pending.len() == DELTA_BLOCK_SIZE {
+                    add_block(index, &pending);
+                    index += 1;
+                    pending.clear();
+                }
+            }
+        }
+        if !pending.is_empty() {
+            add_block(index, &pending);
+        }
+        Ok(Some(signatures))
+    }
+
+    // Slides a window over `reader`, counting bytes covered by matching blocks. The content is
+    // unchanged when every block matched in order and nothing else was found.
+    fn scan_delta(
+        signatures: &HashMap<u32, Vec<BlockSignature>>,
+        reader: impl Read,
+    ) -> Result<FileDelta, String> {
+        let block_count = signatures.values().map(Vec::len).sum::<usize>();
+        let mut delta = FileDelta::default();
+        let mut in_order = true;
+        let mut next_index = 0;
+        let mut window: VecDeque<u8> = VecDeque::with_capacity(DELTA_BLOCK_SIZE);
+        let mut checksum = RollingChecksum::default();
+
+        // Repeated blocks share a signature, so the expected next index is preferred.
+        let find = |window: &mut VecDeque<u8>, checksum: &RollingChecksum, next_index: usize| {
+            let candidates = signatures.get(&checksum.value())?;
+            let strong = Sha256::digest(window.make_contiguous()).to_vec();
+            let mut matching = candidates
+                .iter()
+                .filter(|signature| signature.len == window.len() && signature.strong == strong)
+                .map(|signature| signature.index);
+            let first = matching.next()?;
+            Some(match first == next_index {
+                true => first,
+                false => matching.find(|index| *index == next_index).unwrap_or(first),
+            })
+        };
+
+        for byte in reader.bytes() {
+            let byte = byte.map_err(|e| describe(&e))?;
+            window.push_back(byte);
+            checksum.push(byte);
+            if window.len() < DELTA_BLOCK_SIZE {
+                continue;
+            }
+            match find(&mut window, &checksum, next_index) {
+                Some(index) => {
+                    in_order &= index == next_index;
+                    next_index += 1;
+                    delta.matched_bytes += window.len() as u64;
+                    window.clear();
+                    checksum = RollingChecksum::default();
+                }
+                None => {
+                    if let Some(byte) = window.pop_front() {
+                        checksum.pop(byte);
+                    }
+                    delta.literal_bytes += 1;
+                    in_order = false;
+                }
+            }
+        }
+        // The tail can only match the short last block of the previous revision.
+        if !window.is_empty() {
+            match find(&mut window, &checksum, next_index) {
+                Some(index) => {
+                    in_order &= index == next_index;
+                    next_index += 1;
+                    delta.matched_bytes += window.len() as u64;
+                }
+                None => {
+                    delta.literal_bytes += window.len() as u64;
+                    in_order = false;
+                }
+            }
+        }
+        delta.unchanged = in_order && next_index == block_count;
+        Ok(delta)
+    }
+}
+
+impl<'a> PrivateDirectoryHelper<'a> {
+    pub fn synced_write_file_delta(
+        &mut self,
+        path_segments: &[String],
+        local_filename: &String,
+    ) -> Result<FileDelta, String> {
+        Self::run_limited(
+            &self.operation_limiter(),
+            "write_file_delta",
+            self.write_file_delta(path_segments, local_filename),
+        )
+    }
+}
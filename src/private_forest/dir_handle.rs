@@ -0,0 +1,163 @@
+//! Directory handles for UIs that keep a folder on screen.
+//!
+//! A [`DirHandle`] remembers where its directory is stored in the forest, so listing it or
+//! stepping into a child doesn't resolve the whole path again, and caches the listing until
+//! the helper's root changes. Handles don't borrow the helper: they are passed it on each call
+//! and refresh themselves when the helper committed or was reloaded since.
+
+use libipld::Cid;
+use log::trace;
+use wnfs::common::Metadata;
+
+use super::PrivateDirectoryHelper;
+use crate::error::WnfsUtilsError;
+
+#[derive(Debug, Clone)]
+pub struct DirHandle {
+    path: Vec<String>,
+    resolved: Vec<String>,
+    // Root the resolution and listing were made at.
+    root: Option<Cid>,
+    // Mutations of the helper then, which change the tree without a new root while batched.
+    mutations: u64,
+    entries: Option<Vec<(String, Metadata)>>,
+}
+
+impl DirHandle {
+    /// The logical path of the directory.
+    pub fn path(&self) -> &[String] {
+        &self.path
+    }
+
+    /// Whether `helper` moved to another root or made a batched mutation since the handle was
+    /// last refreshed.
+    pub fn is_stale(&self, helper: &PrivateDirectoryHelper<'_>) -> bool {
+        helper.root_history().last() != self.root.as_ref() || helper.mutations != self.mutations
+    }
+
+    /// Resolves the directory again at the helper's current root and drops the cached listing.
+    /// Fails when the directory no longer exists.
+    pub async fn refresh(&mut self, helper: &mut PrivateDirectoryHelper<'_>) -> Result<(), String> {
+        helper.check_path_depth(&self.path)?;
+        let resolved = helper.resolve_path(&self.path).await?;
+        Self::check_dir(helper, &self.path, &resolved).await?;
+        self.resolved = resolved;
+        self.root = helper.root_history().last().copied();
+        self.mutations = helper.mutations;
+        self.entries = None;
+        Ok(())
+    }
+
+    /// Lists the directory, from the cache while the helper's root is unchanged.
+    pub async fn ls(
+        &mut self,
+        helper: &mut PrivateDirectoryHelper<'_>,
+    ) -> Result<Vec<(String, Metadata)>, String> {
+        if self.is_stale(helper) {
+            self.refresh(helper).await?;
+        }
+        if let Some(entries) = &self.entries {
+            return Ok(entries.to_owned());
+        }
+        let entries = helper.ls_resolved(&self.resolved).await?;
+        self.entries = Some(entries.to_owned());
+        Ok(entries)
+    }
+
+    /// Opens the subdirectory `name`, resolving only that last step.
+    pub async fn child(
+        &mut self,
+        helper: &mut PrivateDirectoryHelper<'_>,
+        name: &str,
+    ) -> Result<DirHandle, String> {
+        if name.is_empty() || name.contains('/') {
+            return Err(format!("wnfsError invalid directory name {}", name));
+        }
+        if self.is_stale(helper) {
+            self.refresh(helper).await?;
+        }
+        let mut path = self.path.to_owned();
+        path.push(name.to_string());
+        helper.check_path_depth(&path)?;
+        let resolved = helper.resolve_child(&self.resolved, name).await?;
+        Self::check_dir(helper, &path, &resolved).await?;
+        Ok(DirHandle {
+            path,
+            resolved,
+            root: self.root,
+            mutations: self.mutations,
+            entries: None,
+        })
+    }
+
+    async fn check_dir(
+        helper: &mut PrivateDirectoryHelper<'_>,
+        path: &[String],
+        resolved: &[String],
+    ) -> Result<(), String> {
+        match helper.raw_node_at(resolved).await? {
+            Some(node) if node.is_dir() => Ok(()),
+            _ => {
+                trace!("wnfsError in dir handle: no directory at {:?}", path);
+                Err(WnfsUtilsError::NotFound(format!("directory {}", path.join("/"))).to_string())
+            }
+        }
+    }
+}
+
+impl<'a> PrivateDirectoryHelper<'a> {
+    /// Opens a handle on the directory at `path_segments`.
+    pub async fn open_dir(&mut self, path_segments: &[String]) -> Result<DirHandle, String> {
+        let mut handle = DirHandle {
+            path: path_segments.to_vec(),
+            resolved: Vec::new(),
+            root: None,
+            mutations: 0,
+            entries: None,
+        };
+        handle.refresh(self).await?;
+        Ok(handle)
+    }
+}
+
+impl DirHandle {
+    pub fn synced_refresh(
+        &mut self,
+        helper: &mut PrivateDirectoryHelper<'_>,
+    ) -> Result<(), String> {
+        PrivateDirectoryHelper::run_limited(
+            &helper.operation_limiter(),
+            "dir_refresh",
+            self.refresh(helper),
+        )
+    }
+
+    pub fn synced_ls(
+        &mut self,
+        helper: &mut PrivateDirectoryHelper<'_>,
+    ) -> Result<Vec<(String, Metadata)>, String> {
+        PrivateDirectoryHelper::run_limited(&helper.operation_limiter(), "dir_ls", self.ls(helper))
+    }
+
+    pub fn synced_child(
+        &mut self,
+        helper: &mut PrivateDirectoryHelper<'_>,
+        name: &str,
+    ) -> Result<DirHandle, String> {
+        PrivateDirectoryHelper::run_limited(
+            &helper.operation_limiter(),
+            "dir_child",
+            self.child(helper, name),
+        )
+    }
+}
+
+impl<'a> PrivateDirectoryHelper<'a> {
+    pub fn synced_open_dir(&mut self, path_segments: &[String]) -> Result<DirHandle, String> {
+        Self::run_limited(
+            &self.operation_limiter(),
+            "open_dir",
+            self.open_dir(path_segments),
+        )
+    }
+}
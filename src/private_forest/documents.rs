@@ -0,0 +1,304 @@
+//! Support for exposing the forest through Android's Storage Access Framework: rows shaped like
+//! `DocumentsContract.Document` columns, paged child queries and streaming `openDocument`, so a
+//! `DocumentsProvider` only has to copy values into cursors.
+//!
+//! Document ids are the path of the node below the forest root, with `/` for the root itself.
+//! They stay valid until the node is moved or renamed.
+
+use log::trace;
+
+use super::PrivateDirectoryHelper;
+use crate::vfs::{VfsNodeKind, VfsStat};
+
+/// `DocumentsContract.Document.MIME_TYPE_DIR`.
+pub const MIME_TYPE_DIR: &str = "vnd.android.document/directory";
+
+// `DocumentsContract.Document` flags.
+const FLAG_SUPPORTS_WRITE: i32 = 0x2;
+const FLAG_SUPPORTS_DELETE: i32 = 0x4;
+const FLAG_DIR_SUPPORTS_CREATE: i32 = 0x8;
+const FLAG_SUPPORTS_RENAME: i32 = 0x40;
+
+/// One row of a documents cursor.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DocumentRow {
+    pub document_id: String,
+    pub display_name: String,
+    pub mime_type: String,
+    pub size: u64,
+    /// Milliseconds since the Unix epoch, `None` when unknown.
+    pub last_modified: Option<i64>,
+    pub flags: i32,
+}
+
+/// A page of `query_child_documents`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChildDocuments {
+    pub rows: Vec<DocumentRow>,
+    /// Number of children of the parent, across all pages.
+    pub total: usize,
+    pub has_more: bool,
+}
+
+impl<'a> PrivateDirectoryHelper<'a> {
+    pub fn document_id(path_segments: &[String]) -> String {
+        format!("/{}", path_segments.join("/"))
+    }
+
+    pub fn document_path(document_id: &str) -> Vec<String> {
+        document_id
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .map(str::to_string)
+            .collect()
+    }
+
+    pub async fn query_document(&mut self, document_id: &str) -> Result<DocumentRow, String> {
+        let path = Self::document_path(document_id);
+        let stat = self
+            .document_stat(&path)
+            .await?
+            .ok_or_else(|| format!("wnfsError no document {}", document_id))?;
+        Ok(Self::document_row(&path, stat))
+    }
+
+    /// Lists `limit` children of the directory `parent_id` starting at `offset`, ordered by name
+    /// so pages stay stable between queries.
+    pub async fn query_child_documents(
+        &mut self,
+        parent_id: &str,
+        offset: usize,
+        limit: usize,
+    ) -> Result<ChildDocuments, String> {
+        let parent = Self::document_path(parent_id);
+        let mut names: Vec<String> = self
+            .ls_files(&parent)
+            .await?
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+        names.sort();
+        let total = names.len();
+        let mut rows = Vec::new();
+        for name in names.into_iter().skip(offset).take(limit) {
+            let mut path = parent.to_owned();
+            path.push(name);
+            if let Some(stat) = self.document_stat(&path).await? {
+                rows.push(Self::document_row(&path, stat));
+            }
+        }
+        Ok(ChildDocuments {
+            rows,
+            total,
+            has_more: offset.saturating_add(limit) < total,
+        })
+    }
+
+    /// Streams the document's content into `local_filename`, typically the write end of a pipe
+    /// handed out as a `ParcelFileDescriptor` (`/proc/self/fd/<fd>`).
+    pub async fn open_document(
+        &mut self,
+        document_id: &str,
+        local_filename: &String,
+    ) -> Result<bool, String> {
+        let path = Self::document_path(document_id);
+        self.read_filestream_to_path(local_filename, &path, 0).await
+    }
+
+    /// Replaces the document's content with the file at `local_filename`, streaming it.
+    pub async fn write_document(
+        &mut self,
+        document_id: &str,
+        local_filename: &String,
+    ) -> Result<(), String> {
+        let path = Self::document_path(document_id);
+        self.write_file_stream_from_path(&path, local_filename)
+            .await
+            .map(|_| ())
+    }
+
+    /// Creates an empty file, or a directory for `MIME_TYPE_DIR`, and returns its id.
+    pub async fn create_document(
+        &mut self,
+        parent_id: &str,
+        mime_type: &str,
+        display_name: &str,
+    ) -> Result<String, String> {
+        let mut path = Self::document_path(parent_id);
+        path.push(display_name.to_string());
+        if self.node_at(&path).await?.is_some() {
+            trace!("wnfsError in create_document: {:?} exists", path);
+            return Err(format!(
+                "wnfsError document {} already exists",
+                Self::document_id(&path)
+            ));
+        }
+        match mime_type {
+            MIME_TYPE_DIR => self.mkdir(&path).await?,
+            _ => self.write_file(&path, Vec::new(), 0).await?,
+        };
+        Ok(Self::document_id(&path))
+    }
+
+    /// Renames the document within its directory and returns its new id.
+    pub async fn rename_document(
+        &mut self,
+        document_id: &str,
+        display_name: &str,
+    ) -> Result<String, String> {
+        let path = Self::document_path(document_id);
+        let mut target = path.to_owned();
+        match target.last_mut() {
+            Some(name) => *name = display_name.to_string(),
+            None => return Err("wnfsError the root document can't be renamed".to_string()),
+        }
+        self.mv(&path, &target).await?;
+        Ok(Self::document_id(&target))
+    }
+
+    pub async fn delete_document(&mut self, document_id: &str) -> Result<(), String> {
+        self.rm(&Self::document_path(document_id)).await.map(|_| ())
+    }
+
+    // The node's stat and, for files, the type set with `set_content_type`.
+    async fn document_stat(
+        &mut self,
+        path: &[String],
+    ) -> Result<Option<(VfsStat, Option<String>)>, String> {
+        Ok(self
+            .node_at(path)
+            .await?
+            .map(|node| (Self::vfs_stat_of(&node), Self::stored_content_type(&node))))
+    }
+
+    // Files are typed by the content type stored with them, or else by their name.
+    fn document_row(
+        path: &[String],
+        (stat, content_type): (VfsStat, Option<String>),
+    ) -> DocumentRow {
+        let display_name = path.last().cloned().unwrap_or_else(|| "/".to_string());
+        let (mime_type, flags) = match stat.kind {
+            VfsNodeKind::Directory => (
+                MIME_TYPE_DIR.to_string(),
+                FLAG_DIR_SUPPORTS_CREATE | FLAG_SUPPORTS_DELETE | FLAG_SUPPORTS_RENAME,
+            ),
+            VfsNodeKind::File => (
+                content_type.unwrap_or_else(|| Self::mime_type_of(&display_name).to_string()),
+                FLAG_SUPPORTS_WRITE | FLAG_SUPPORTS_DELETE | FLAG_SUPPORTS_RENAME,
+            ),
+        };
+        DocumentRow {
+            document_id: Self::document_id(path),
+            display_name,
+            mime_type,
+            size: stat.size,
+            last_modified: stat.modified.map(|seconds| seconds * 1000),
+            flags,
+        }
+    }
+
+    fn mime_type_of(name: &str) -> &'static str {
+        let extension = name
+            .rsplit_once('.')
+            .map(|(_, extension)| extension.to_ascii_lowercase())
+            .unwrap_or_default();
+        match extension.as_str() {
+            "txt" => "text/plain",
+            "html" | "htm" => "text/html",
+            "json" => "application/json",
+            "pdf" => "application/pdf",
+            "zip" => "application/zip",
+            "jpg" | "jpeg" => "image/jpeg",
+            "png" => "image/png",
+            "gif" => "image/gif",
+            "webp" => "image/webp",
+            "heic" => "image/heic",
+            "mp3" => "audio/mpeg",
+            "m4a" => "audio/mp4",
+            "mp4" => "video/mp4",
+            "mov" => "video/quicktime",
+            _ => "application/octet-stream",
+        }
+    }
+}
+
+// Synced versions for the JNI `DocumentsProvider` glue.
+impl<'a> PrivateDirectoryHelper<'a> {
+    pub fn synced_query_document(&mut self, document_id: &str) -> Result<DocumentRow, String> {
+        Self::run_limited(
+            &self.operation_limiter(),
+            "query_document",
+            self.query_document(document_id),
+        )
+    }
+
+    pub fn synced_query_child_documents(
+        &mut self,
+        parent_id: &str,
+        offset: usize,
+        limit: usize,
+    ) -> Result<ChildDocuments, String> {
+        Self::run_limited(
+            &self.operation_limiter(),
+            "query_child_documents",
+            self.query_child_documents(parent_id, offset, limit),
+        )
+    }
+
+    pub fn synced_open_document(
+        &mut self,
+        document_id: &str,
+        local_filename: &String,
+    ) -> Result<bool, String> {
+        Self::run_limited(
+            &self.operation_limiter(),
+            "open_document",
+            self.open_document(document_id, local_filename),
+        )
+    }
+
+    pub fn synced_write_document(
+        &mut self,
+        document_id: &str,
+        local_filename: &String,
+    ) -> Result<(), String> {
+        Self::run_limited(
+            &self.operation_limiter(),
+            "write_document",
+            self.write_document(document_id, local_filename),
+        )
+    }
+
+    pub fn synced_create_document(
+        &mut self,
+        parent_id: &str,
+        mime_type: &str,
+        display_name: &str,
+    ) -> Result<String, String> {
+        Self::run_limited(
+            &self.operation_limiter(),
+            "create_document",
+            self.create_document(parent_id, mime_type, display_name),
+        )
+    }
+
+    pub fn synced_rename_document(
+        &mut self,
+        document_id: &str,
+        display_name: &str,
+    ) -> Result<String, String> {
+        Self::run_limited(
+            &self.operation_limiter(),
+            "rename_document",
+            self.rename_document(document_id, display_name),
+        )
+    }
+
+    pub fn synced_delete_document(&mut self, document_id: &str) -> Result<(), String> {
+        Self::run_limited(
+            &self.operation_limiter(),
+            "delete_document",
+            self.delete_document(document_id),
+        )
+    }
+}
@@ -0,0 +1,322 @@
+//! Random-access file handles for consumers such as media players or archive readers that seek
+//! around a file instead of reading it start to end.
+//!
+//! A [`FileHandle`] holds the file node as it was when opened, so reads keep seeing that version
+//! even after the helper commits. WNFS stores content in encrypted blocks of a fixed size; the
+//! handle learns that size from the first blocks, starts decrypting at the block holding the
+//! requested offset and keeps the last `FILE_HANDLE_CACHED_BLOCKS` blocks, so small reads close
+//! to each other decrypt each block once.
+
+use std::{collections::VecDeque, io::SeekFrom, rc::Rc};
+
+use futures::StreamExt;
+use log::trace;
+use wnfs::private::PrivateFile;
+
+use super::PrivateDirectoryHelper;
+use crate::error::describe;
+
+/// Decrypted blocks kept by each handle.
+pub const FILE_HANDLE_CACHED_BLOCKS: usize = 4;
+
+#[derive(Debug, Clone, Copy)]
+struct BlockLayout {
+    // Content bytes in every block but the last.
+    block_size: u64,
+    len: u64,
+}
+
+pub struct FileHandle {
+    path: Vec<String>,
+    file: Rc<PrivateFile>,
+    position: u64,
+    layout: Option<BlockLayout>,
+    cache: VecDeque<(u64, Vec<u8>)>,
+}
+
+impl FileHandle {
+    /// The logical path the file was opened at.
+    pub fn path(&self) -> &[String] {
+        &self.path
+    }
+
+    /// Current position of `read`.
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// Exact length of the file's content.
+    pub async fn len(&mut self, helper: &mut PrivateDirectoryHelper<'_>) -> Result<u64, String> {
+        Ok(self.layout(helper).await?.len)
+    }
+
+    /// Moves the position of `read`, as `std::io::Seek` does, and returns the new position.
+    pub async fn seek(
+        &mut self,
+        helper: &mut PrivateDirectoryHelper<'_>,
+        to: SeekFrom,
+    ) -> Result<u64, String> {
+        let position = match to {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(delta) => self.position.checked_add_signed(delta),
+            SeekFrom::End(delta) => self.len(helper).await?.checked_add_signed(delta),
+        };
+        self.position = position.ok_or_else(|| {
+            trace!("wnfsError in seek: {:?} before the start", to);
+            "wnfsError seek before the start of the file".to_string()
+        })?;
+        Ok(self.position)
+    }
+
+    /// Reads up to `len` bytes at the current position and advances it.
+    pub async fn read(
+        &mut self,
+        helper: &mut PrivateDirectoryHelper<'_>,
+        len: usize,
+    ) -> Result<Vec<u8>, String> {
+        let data = self.read_at(helper, self.position, len).await?;
+        self.position += data.len() as u64;
+        Ok(data)
+    }
+
+    /// Reads up to `len` bytes at `offset`, fewer at the end of the file. Doesn't move the
+    /// position.
+    pub async fn read_at(
+        &mut self,
+        helper: &mut PrivateDirectoryHelper<'_>,
+        offset: u64,
+        len: usize,
+    ) -> Result<Vec<u8>, String> {
+        let layout = self.layout(helper).await?;
+        let end = offset.saturating_add(len as u64).min(layout.len);
+        let mut out = Vec::with_capacity(end.saturating_sub(offset) as usize);
+        let mut position = offset;
+        while position < end {
+            let index = position / layout.block_size;
+            let block = match self.block(helper, index).await? {
+                Some(block) => block,
+                None => break,
+            };
+            let block_start = index * layout.block_size;
+            let from = (position - block_start) as usize;
+            let to = ((end - block_start) as usize).min(block.len());
+            if from >= to {
+                break;
+            }
+            out.extend_from_slice(&block[from..to]);
+            position = block_start + to as u64;
+        }
+        Ok(out)
+    }
+
+    // Block size and length, read from the first two blocks and the last one on first use.
+    async fn layout(
+        &mut self,
+        helper: &mut PrivateDirectoryHelper<'_>,
+    ) -> Result<BlockLayout, String> {
+        if let Some(layout) = self.layout {
+            return Ok(layout);
+        }
+        let mut first = Vec::new();
+        let mut stream = self.file.stream_content(0, &helper.forest, &helper.store);
+        while let Some(block) = stream.next().await {
+            first.push(Self::block_result(block)?);
+            if first.len() == 2 {
+                break;
+            }
+        }
+        drop(stream);
+        let block_size = first.first().map(|block| block.len() as u64).unwrap_or(0);
+        let layout = match first.len() {
+            // Inline content and single-block files hold everything in block 0.
+            0 | 1 => BlockLayout {
+                block_size: block_size.max(1),
+                len: block_size,
+            },
+            _ => {
+                let upper_bound = self.file.get_content_size_upper_bound() as u64;
+                let last_index = upper_bound
+                    .div_ceil(block_size.max(1))
+                    .saturating_sub(1)
+                    .max(1);
+                let last = match last_index {
+                    1 => first.get(1).cloned(),
+                    _ => self.fetch_block(helper, last_index).await?,
+                };
+                let last = last.ok_or_else(|| {
+                    trace!("wnfsError in file handle: block {} missing", last_index);
+                    format!("wnfsError {} is truncated", self.path.join("/"))
+                })?;
+                BlockLayout {
+                    block_size,
+                    len: last_index * block_size + last.len() as u64,
+                }
+            }
+        };
+        for (index, block) in first.into_iter().enumerate() {
+            self.remember(index as u64, block);
+        }
+        self.layout = Some(layout);
+        Ok(layout)
+    }
+
+    async fn block(
+        &mut self,
+        helper: &mut PrivateDirectoryHelper<'_>,
+        index: u64,
+    ) -> Result<Option<Vec<u8>>, String> {
+        if let Some(position) = self.cache.iter().position(|(cached, _)| *cached == index) {
+            // Move the hit to the back, so the least recently read block is evicted first.
+            if let Some(entry) = self.cache.remove(position) {
+                let block = entry.1.to_owned();
+                self.cache.push_back(entry);
+                return Ok(Some(block));
+            }
+        }
+        let block = self.fetch_block(helper, index).await?;
+        if let Some(block) = &block {
+            self.remember(index, block.to_owned());
+        }
+        Ok(block)
+    }
+
+    async fn fetch_block(
+        &self,
+        helper: &mut PrivateDirectoryHelper<'_>,
+        index: u64,
+    ) -> Result<Option<Vec<u8>>, String> {
+        let mut stream = self
+            .file
+            .stream_content(index, &helper.forest, &helper.store);
+        match stream.next().await {
+            Some(block) => Ok(Some(Self::block_result(block)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn remember(&mut self, index: u64, block: Vec<u8>) {
+        self.cache.retain(|(cached, _)| *cached != index);
+        if self.cache.len() >= FILE_HANDLE_CACHED_BLOCKS {
+            self.cache.pop_front();
+        }
+        self.cache.push_back((index, block));
+    }
+
+    fn block_result(block: anyhow::Result<Vec<u8>>) -> Result<Vec<u8>, String> {
+        block.map_err(|e| {
+            trace!("wnfsError in file handle: {:?}", e.to_string());
+            describe(&e)
+        })
+    }
+}
+
+impl<'a> PrivateDirectoryHelper<'a> {
+    /// Opens the file at `path_segments` for positioned reads.
+    pub async fn open_file(&mut self, path_segments: &[String]) -> Result<FileHandle, String> {
+        let file = match self.node_at(path_segments).await? {
+            Some(node) if node.is_file() => node.as_file().map_err(|e| describe(&e))?,
+            _ => {
+                trace!("wnfsError in open_file: no file at {:?}", path_segments);
+                return Err(format!(
+                    "wnfsError no file found at {}",
+                    path_segments.join("/")
+                ));
+            }
+        };
+        Ok(FileHandle {
+            path: path_segments.to_vec(),
+            file,
+            position: 0,
+            layout: None,
+            cache: VecDeque::with_capacity(FILE_HANDLE_CACHED_BLOCKS),
+        })
+    }
+
+    /// Reads up to `len` bytes at `offset` of the file at `path_segments`, fewer at the end of
+    /// the file, decrypting only the blocks covering that range. Paged files are read chunk by
+    /// chunk the same way. For many reads of one file, `open_file` keeps the blocks cached.
+    pub async fn read_file_at(
+        &mut self,
+        path_segments: &[String],
+        offset: u64,
+        len: usize,
+    ) -> Result<Vec<u8>, String> {
+        if self.is_paged_file(path_segments).await? {
+            return self.read_at(path_segments, offset, len).await;
+        }
+        let mut handle = self.open_file(path_segments).await?;
+        handle.read_at(self, offset, len).await
+    }
+}
+
+impl FileHandle {
+    pub fn synced_len(&mut self, helper: &mut PrivateDirectoryHelper<'_>) -> Result<u64, String> {
+        PrivateDirectoryHelper::run_limited(
+            &helper.operation_limiter(),
+            "file_len",
+            self.len(helper),
+        )
+    }
+
+    pub fn synced_seek(
+        &mut self,
+        helper: &mut PrivateDirectoryHelper<'_>,
+        to: SeekFrom,
+    ) -> Result<u64, String> {
+        PrivateDirectoryHelper::run_limited(
+            &helper.operation_limiter(),
+            "file_seek",
+            self.seek(helper, to),
+        )
+    }
+
+    pub fn synced_read(
+        &mut self,
+        helper: &mut PrivateDirectoryHelper<'_>,
+        len: usize,
+    ) -> Result<Vec<u8>, String> {
+        PrivateDirectoryHelper::run_limited(
+            &helper.operation_limiter(),
+            "file_read",
+            self.read(helper, len),
+        )
+    }
+
+    pub fn synced_read_at(
+        &mut self,
+        helper: &mut PrivateDirectoryHelper<'_>,
+        offset: u64,
+        len: usize,
+    ) -> Result<Vec<u8>, String> {
+        PrivateDirectoryHelper::run_limited(
+            &helper.operation_limiter(),
+            "file_read_at",
+            self.read_at(helper, offset, len),
+        )
+    }
+}
+
+impl<'a> PrivateDirectoryHelper<'a> {
+    pub fn synced_open_file(&mut self, path_segments: &[String]) -> Result<FileHandle, String> {
+        Self::run_limited(
+            &self.operation_limiter(),
+            "open_file",
+            self.open_file(path_segments),
+        )
+    }
+
+    /// `read_file_at` with a fixed-width length for the bindings, capped to what fits in memory.
+    pub fn synced_read_file_at(
+        &mut self,
+        path_segments: &[String],
+        offset: u64,
+        len: u64,
+    ) -> Result<Vec<u8>, String> {
+        let len = usize::try_from(len).unwrap_or(usize::MAX);
+        Self::run_limited(
+            &self.operation_limiter(),
+            "read_file_at",
+            self.read_file_at(path_segments, offset, len),
+        )
+    }
+}
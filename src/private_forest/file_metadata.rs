@@ -0,0 +1,297 @@
+//! Reading and editing the metadata WNFS keeps on each node, for file managers showing dates,
+//! types and tags.
+//!
+//! WNFS stores a node's metadata as a map next to its content, holding its `created` and
+//! `modified` times. [`FileMetadata`] splits that map into the times, the content type stored
+//! under `CONTENT_TYPE_KEY` and the remaining entries, such as those of media ingestion or
+//! content scanners. Only files can be edited: directories get their metadata from WNFS.
+
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Utc};
+use libipld::{Cid, Ipld};
+use log::trace;
+use wnfs::{common::Metadata, private::PrivateNode};
+
+use super::{PrivateDirectoryHelper, CONTENT_HASH_KEY};
+use crate::error::{describe, WnfsUtilsError};
+
+/// Metadata key of the MIME type set with `set_content_type`.
+pub const CONTENT_TYPE_KEY: &str = "content_type";
+
+// Keys WNFS maintains itself.
+const TIME_KEYS: [&str; 2] = ["created", "modified"];
+
+// Keys the helper maintains, which other features trust: the times and the content hash
+// deduplication compares.
+const RESERVED_KEYS: [&str; 3] = [TIME_KEYS[0], TIME_KEYS[1], CONTENT_HASH_KEY];
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FileMetadata {
+    /// Seconds since the Unix epoch.
+    pub created: Option<i64>,
+    pub modified: Option<i64>,
+    /// As set with `set_content_type`; `None` for content typed by its name only.
+    pub content_type: Option<String>,
+    /// The other entries, by key.
+    pub attributes: BTreeMap<String, Ipld>,
+}
+
+impl FileMetadata {
+    pub fn from_wnfs(metadata: &Metadata) -> Self {
+        let mut attributes = match libipld::serde::to_ipld(metadata) {
+            Ok(Ipld::Map(map)) => map,
+            _ => BTreeMap::new(),
+        };
+        for key in TIME_KEYS {
+            attributes.remove(key);
+        }
+        let content_type = match attributes.remove(CONTENT_TYPE_KEY) {
+            Some(Ipld::String(content_type)) => Some(content_type),
+            _ => None,
+        };
+        Self {
+            created: metadata.get_created().map(|time| time.timestamp()),
+            modified: metadata.get_modified().map(|time| time.timestamp()),
+            content_type,
+            attributes,
+        }
+    }
+}
+
+impl<'a> PrivateDirectoryHelper<'a> {
+    pub async fn get_metadata(&mut self, path_segments: &[String]) -> Result<FileMetadata, String> {
+        let node = self
+            .node_at(path_segments)
+            .await?
+            .ok_or_else(|| format!("wnfsError no node found at {}", path_segments.join("/")))?;
+        let metadata = match node.as_file() {
+            Ok(file) => file.get_metadata().to_owned(),
+            Err(_) => node
+                .as_dir()
+                .map_err(|e| describe(&e))?
+                .get_metadata()
+                .to_owned(),
+        };
+        Ok(FileMetadata::from_wnfs(&metadata))
+    }
+
+    /// `ls_files` with the entries' metadata split up as in `get_metadata`.
+    pub async fn ls_with_metadata(
+        &mut self,
+        path_segments: &[String],
+    ) -> Result<Vec<(String, FileMetadata)>, String> {
+        Ok(self
+            .ls_files(path_segments)
+            .await?
+            .into_iter()
+            .map(|(name, metadata)| (name, FileMetadata::from_wnfs(&metadata)))
+            .collect())
+    }
+
+    /// Stores `value` under `key` in the metadata of the file at `path_segments`, replacing what
+    /// was stored there, and commits. The file's modification time is kept; it is set with
+    /// `set_mtime` instead. Keys the helper maintains, the times and `CONTENT_HASH_KEY`, can't
+    /// be set.
+    pub async fn set_metadata(
+        &mut self,
+        path_segments: &[String],
+        key: &str,
+        value: Ipld,
+    ) -> Result<Cid, String> {
+        Self::check_metadata_key(key)?;
+        let modified = self.check_metadata_target(path_segments).await?;
+        self.put_file_metadata(path_segments, vec![(key.to_string(), value)], modified)
+            .await?;
+        self.commit().await
+    }
+
+    pub async fn set_content_type(
+        &mut self,
+        path_segments: &[String],
+        content_type: &str,
+    ) -> Result<Cid, String> {
+        self.set_metadata(
+            path_segments,
+            CONTENT_TYPE_KEY,
+            Ipld::String(content_type.to_string()),
+        )
+        .await
+    }
+
+    /// Removes `key` from the metadata of the file at `path_segments` and commits. Fails if the
+    /// key isn't set or is maintained by the helper, as for `set_metadata`.
+    pub async fn remove_metadata(
+        &mut self,
+        path_segments: &[String],
+        key: &str,
+    ) -> Result<Cid, String> {
+        Self::check_metadata_key(key)?;
+        let modified = self.check_metadata_target(path_segments).await?;
+        let current = self.get_metadata(path_segments).await?;
+        let removed = match key {
+            CONTENT_TYPE_KEY => current.content_type.is_some(),
+            _ => current.attributes.contains_key(key),
+        };
+        if !removed {
+            trace!("wnfsError in remove_metadata: {} isn't set", key);
+            return Err(WnfsUtilsError::NotFound(format!(
+                "metadata key {} of {}",
+                key,
+                path_segments.join("/")
+            ))
+            .to_string());
+        }
+        let path = &self.resolve_path(path_segments).await?;
+        let forest = &mut self.forest;
+        let root_dir = &mut self.root_dir;
+        let file = root_dir
+            .open_file_mut(path, true, modified, forest, &mut self.store, &mut self.rng)
+            .await
+            .map_err(|e| {
+                trace!("wnfsError in remove_metadata: {:?}", e);
+                describe(&e)
+            })?;
+        let metadata = file.get_metadata_mut();
+        let mut entries = match libipld::serde::to_ipld(&*metadata) {
+            Ok(Ipld::Map(map)) => map,
+            _ => {
+                return Err(format!(
+                    "wnfsError unreadable metadata at {:?}",
+                    path_segments
+                ))
+            }
+        };
+        entries.remove(key);
+        *metadata = libipld::serde::from_ipld(Ipld::Map(entries)).map_err(|e| {
+            trace!("wnfsError in remove_metadata: {:?}", e);
+            describe(&e)
+        })?;
+        self.commit().await
+    }
+
+    /// Sets the modification time of the file at `path_segments` and commits.
+    pub async fn set_mtime(
+        &mut self,
+        path_segments: &[String],
+        modification_time_seconds: i64,
+    ) -> Result<Cid, String> {
+        let modified = Self::modification_time(modification_time_seconds)?;
+        self.check_metadata_target(path_segments).await?;
+        self.put_file_metadata(path_segments, Vec::new(), modified)
+            .await?;
+        self.commit().await
+    }
+
+    // The type set with `set_content_type` on the file `node`, for the documents and file
+    // providers. `None` for directories and untyped files.
+    pub(super) fn stored_content_type(node: &PrivateNode) -> Option<String> {
+        let file = node.as_file().ok()?;
+        FileMetadata::from_wnfs(file.get_metadata()).content_type
+    }
+
+    fn check_metadata_key(key: &str) -> Result<(), String> {
+        if !RESERVED_KEYS.contains(&key) {
+            return Ok(());
+        }
+        trace!("wnfsError in set_metadata: reserved key {}", key);
+        Err(WnfsUtilsError::ReservedMetadataKey(key.to_string()).to_string())
+    }
+
+    // Fails unless a file the helper may change is at `path_segments`, as `put_file_metadata`
+    // would create a missing one. Returns its modification time, kept by metadata edits.
+    async fn check_metadata_target(
+        &mut self,
+        path_segments: &[String],
+    ) -> Result<DateTime<Utc>, String> {
+        self.check_not_held(path_segments, false).await?;
+        match self.node_at(path_segments).await? {
+            Some(node) if node.is_file() => {
+                let file = node.as_file().map_err(|e| describe(&e))?;
+                Ok(file.get_metadata().get_modified().unwrap_or_else(Utc::now))
+            }
+            Some(_) => Err(WnfsUtilsError::InvalidPath {
+                path: path_segments.join("/"),
+                reason: "metadata can only be set on files".to_string(),
+            }
+            .to_string()),
+            None => Err(format!(
+                "wnfsError no node found at {}",
+                path_segments.join("/")
+            )),
+        }
+    }
+}
+
+impl<'a> PrivateDirectoryHelper<'a> {
+    pub fn synced_get_metadata(
+        &mut self,
+        path_segments: &[String],
+    ) -> Result<FileMetadata, String> {
+        Self::run_limited(
+            &self.operation_limiter(),
+            "get_metadata",
+            self.get_metadata(path_segments),
+        )
+    }
+
+    pub fn synced_ls_with_metadata(
+        &mut self,
+        path_segments: &[String],
+    ) -> Result<Vec<(String, FileMetadata)>, String> {
+        Self::run_limited(
+            &self.operation_limiter(),
+            "ls_with_metadata",
+            self.ls_with_metadata(path_segments),
+        )
+    }
+
+    pub fn synced_set_metadata(
+        &mut self,
+        path_segments: &[String],
+        key: &str,
+        value: Ipld,
+    ) -> Result<Cid, String> {
+        Self::run_limited(
+            &self.operation_limiter(),
+            "set_metadata",
+            self.set_metadata(path_segments, key, value),
+        )
+    }
+
+    pub fn synced_set_content_type(
+        &mut self,
+        path_segments: &[String],
+        content_type: &str,
+    ) -> Result<Cid, String> {
+        Self::run_limited(
+            &self.operation_limiter(),
+            "set_content_type",
+            self.set_content_type(path_segments, content_type),
+        )
+    }
+
+    pub fn synced_remove_metadata(
+        &mut self,
+        path_segments: &[String],
+        key: &str,
+    ) -> Result<Cid, String> {
+        Self::run_limited(
+            &self.operation_limiter(),
+            "remove_metadata",
+            self.remove_metadata(path_segments, key),
+        )
+    }
+
+    pub fn synced_set_mtime(
+        &mut self,
+        path_segments: &[String],
+        modification_time_seconds: i64,
+    ) -> Result<Cid, String> {
+        Self::run_limited(
+            &self.operation_limiter(),
+            "set_mtime",
+            self.set_mtime(path_segments, modification_time_seconds),
+        )
+    }
+}
@@ -0,0 +1,243 @@
+//! Support for an iOS File Provider extension: item identifiers, paged enumeration, change
+//! enumeration from sync anchors and partial content fetches, following `NSFileProvider*`
+//! semantics so the Swift glue only maps values.
+//!
+//! Identifiers are the path of the item below the forest root, except for the root, which uses
+//! `ROOT_CONTAINER_IDENTIFIER`. Sync anchors are forest CIDs.
+
+use std::{fs::File, io::Write, rc::Rc};
+
+use libipld::Cid;
+use log::trace;
+use wnfs::private::PrivateNode;
+
+use super::PrivateDirectoryHelper;
+use crate::error::describe;
+use crate::vfs::VfsNodeKind;
+
+// Bytes read per step of `fetch_partial_contents`.
+const PARTIAL_FETCH_CHUNK: usize = 1 << 20;
+
+/// Value of `NSFileProviderItemIdentifier.rootContainer`.
+pub const ROOT_CONTAINER_IDENTIFIER: &str = "NSFileProviderRootContainerItemIdentifier";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileProviderItem {
+    pub identifier: String,
+    pub parent_identifier: String,
+    pub filename: String,
+    /// Uniform type identifier: `public.folder` for directories, `public.data` otherwise.
+    pub content_type: String,
+    /// MIME type set with `set_content_type`, for `UTType(mimeType:)`. `None` when unset.
+    pub mime_type: Option<String>,
+    pub size: u64,
+    /// Seconds since the Unix epoch, `None` when unknown.
+    pub modified: Option<i64>,
+    /// CID of the item's current revision, which changes whenever the content or the metadata
+    /// changes, for `NSFileProviderItemVersion`.
+    pub version: String,
+}
+
+/// A page of `enumerate_items`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileProviderPage {
+    pub items: Vec<FileProviderItem>,
+    /// Token for the next page, `None` after the last one.
+    pub next_page: Option<String>,
+}
+
+/// Result of `enumerate_changes`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileProviderChanges {
+    pub updated: Vec<FileProviderItem>,
+    pub deleted: Vec<String>,
+    pub sync_anchor: String,
+}
+
+impl<'a> PrivateDirectoryHelper<'a> {
+    pub fn item_identifier(path_segments: &[String]) -> String {
+        match path_segments.is_empty() {
+            true => ROOT_CONTAINER_IDENTIFIER.to_string(),
+            false => path_segments.join("/"),
+        }
+    }
+
+    pub fn item_path(identifier: &str) -> Vec<String> {
+        if identifier == ROOT_CONTAINER_IDENTIFIER {
+            return Vec::new();
+        }
+        identifier
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .map(str::to_string)
+            .collect()
+    }
+
+    pub async fn item(&mut self, identifier: &str) -> Result<FileProviderItem, String> {
+        let path = Self::item_path(identifier);
+        let node = self
+            .node_at(&path)
+            .await?
+            .ok_or_else(|| format!("wnfsError no item {}", identifier))?;
+        self.provider_item(&path, &node).await
+    }
+
+    /// Lists up to `page_size` items of `container` after `page`, a token from a previous
+    /// page or `None` for the first one, see `Page`.
+    pub async fn enumerate_items(
+        &mut self,
+        container: &str,
+        page: Option<&str>,
+        page_size: usize,
+    ) -> Result<FileProviderPage, String> {
+        let listing = format!("items:{}", container);
+        let after = Self::continue_after(&listing, page)?;
+        let parent = Self::item_path(container);
+        let mut names: Vec<String> = self
+            .ls_files(&parent)
+            .await?
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+        names.sort();
+        let start = match &after {
+            Some(after) => names.partition_point(|name| name <= after),
+            None => 0,
+        };
+        let names = Self::page_of(
+            &listing,
+            names.into_iter().skip(start).collect(),
+            page_size,
+            String::to_owned,
+        );
+        let mut items = Vec::new();
+        for name in names.items {
+            let mut path = parent.to_owned();
+            path.push(name);
+            if let Some(node) = self.node_at(&path).await? {
+                items.push(self.provider_item(&path, &node).await?);
+            }
+        }
+        Ok(FileProviderPage {
+            items,
+            next_page: names.next_page,
+        })
+    }
+
+    /// The anchor to pass to the first `enumerate_changes`.
+    pub fn current_sync_anchor(&self) -> Result<String, String> {
+        self.root_history
+            .last()
+            .map(|cid| cid.to_string())
+            .ok_or_else(|| "wnfsError no committed root".to_string())
+    }
+
+    /// Items of `container` changed or deleted since `sync_anchor`, and the anchor to continue
+    /// from.
+    pub async fn enumerate_changes(
+        &mut self,
+        container: &str,
+        sync_anchor: &str,
+    ) -> Result<FileProviderChanges, String> {
+        let since = Cid::try_from(sync_anchor).map_err(|e| {
+            trace!("wnfsError in enumerate_changes: {:?}", e.to_string());
+            format!("wnfsError invalid sync anchor {}", sync_anchor)
+        })?;
+        let parent = Self::item_path(container);
+        let changes = self.ls_changes(&parent, since).await?;
+        let mut updated = Vec::new();
+        for (name, _) in changes.added.iter().chain(changes.modified.iter()) {
+            let mut path = parent.to_owned();
+            path.push(name.to_owned());
+            if let Some(node) = self.node_at(&path).await? {
+                updated.push(self.provider_item(&path, &node).await?);
+            }
+        }
+        let deleted = changes
+            .removed
+            .iter()
+            .map(|name| {
+                let mut path = parent.to_owned();
+                path.push(name.to_owned());
+                Self::item_identifier(&path)
+            })
+            .collect();
+        Ok(FileProviderChanges {
+            updated,
+            deleted,
+            sync_anchor: changes
+                .revision
+                .map(|cid| cid.to_string())
+                .unwrap_or_else(|| sync_anchor.to_string()),
+        })
+    }
+
+    /// Writes `length` bytes of the item's content starting at `offset` into `local_filename`,
+    /// for `NSFileProviderPartialContentFetching`. Only the blocks covering the range are
+    /// decrypted, see `read_file_at`. Returns the number of bytes written, fewer than `length`
+    /// at the end of the file.
+    pub async fn fetch_partial_contents(
+        &mut self,
+        identifier: &str,
+        offset: u64,
+        length: u64,
+        local_filename: &String,
+    ) -> Result<u64, String> {
+        let path = Self::item_path(identifier);
+        let is_file = matches!(self.node_at(&path).await?, Some(node) if node.is_file());
+        if !is_file && !self.is_paged_file(&path).await? {
+            return Err(format!("wnfsError no item {}", identifier));
+        }
+        let mut local_file = File::create(local_filename).map_err(|e| describe(&e))?;
+
+        let mut written: u64 = 0;
+        while written < length {
+            let len = (length - written).min(PARTIAL_FETCH_CHUNK as u64) as usize;
+            let chunk = self
+                .read_file_at(&path, offset.saturating_add(written), len)
+                .await?;
+            local_file.write_all(&chunk).map_err(|e| describe(&e))?;
+            written += chunk.len() as u64;
+            if chunk.len() < len {
+                break;
+            }
+        }
+        Ok(written)
+    }
+
+    async fn provider_item(
+        &mut self,
+        path: &[String],
+        node: &PrivateNode,
+    ) -> Result<FileProviderItem, String> {
+        let stat = Self::vfs_stat_of(node);
+        let parent = path
+            .split_last()
+            .map(|(_, parent)| parent)
+            .unwrap_or_default();
+        let content_type = match stat.kind {
+            VfsNodeKind::Directory => "public.folder",
+            VfsNodeKind::File => "public.data",
+        };
+        // Loaded nodes keep the CID they were stored as, so nothing new is written here; the
+        // forest is a scratch copy all the same, as this is a read.
+        let mut forest = Rc::clone(&self.forest);
+        let access_key = node
+            .store(&mut forest, &mut self.store, &mut self.rng)
+            .await
+            .map_err(|e| {
+                trace!("wnfsError in provider_item: {:?}", e.to_string());
+                describe(&e)
+            })?;
+        Ok(FileProviderItem {
+            identifier: Self::item_identifier(path),
+            parent_identifier: Self::item_identifier(parent),
+            filename: path.last().cloned().unwrap_or_default(),
+            content_type: content_type.to_string(),
+            mime_type: Self::stored_content_type(node),
+            size: stat.size,
+            modified: stat.modified,
+            version: access_key.get_content_cid().to_string(),
+        })
+    }
+}
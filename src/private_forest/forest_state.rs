@@ -0,0 +1,159 @@
+//! A typed description of a forest root, for places that used to pass a bare forest CID around:
+//! app storage, pointer service payloads and the helper's load and commit calls, see
+//! `load_from_state` and `commit_state`.
+//!
+//! `forest_version` counts the commits since the forest was created, so a pointer service can
+//! reject an update older than the state it already holds. The JSON form is stable: fields are
+//! only ever added, with defaults, and the CID is written as its string form. Only `root_cid`
+//! is required.
+
+use chrono::Utc;
+use libipld::Cid;
+use log::trace;
+use serde::{Deserialize, Serialize};
+use wnfs::private::AccessKey;
+
+use super::{ExclusiveSession, PrivateDirectoryHelper};
+use crate::blockstore::FFIFriendlyBlockStore;
+use crate::error::{describe, WnfsUtilsError};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ForestState {
+    #[serde(with = "cid_string")]
+    pub root_cid: Cid,
+    /// Commits since the forest was created.
+    #[serde(default)]
+    pub forest_version: u64,
+    /// RFC 3339 time the forest was created.
+    #[serde(default)]
+    pub created: String,
+    /// Device that produced this root, as named by the app.
+    #[serde(default)]
+    pub device: String,
+}
+
+impl ForestState {
+    pub fn to_json(&self) -> Result<String, String> {
+        serde_json::to_string(self).map_err(|e| describe(&e))
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, String> {
+        serde_json::from_str(json).map_err(|e| {
+            trace!("wnfsError in ForestState::from_json: {:?}", e.to_string());
+            describe(&e)
+        })
+    }
+
+    /// Whether `self` is newer than `other`, e.g. before replacing a published state.
+    pub fn supersedes(&self, other: &ForestState) -> bool {
+        self.forest_version > other.forest_version
+    }
+}
+
+pub(super) mod cid_string {
+    use libipld::Cid;
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(cid: &Cid, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&cid.to_string())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Cid, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        Cid::try_from(text.as_str()).map_err(D::Error::custom)
+    }
+}
+
+impl<'a> PrivateDirectoryHelper<'a> {
+    /// Like `init_exclusive`, returning the state of the new forest, produced on `device`. Later
+    /// commits of the helper advance it, see `forest_state`.
+    pub async fn init_with_state(
+        store: &mut FFIFriendlyBlockStore<'a>,
+        wnfs_key: Vec<u8>,
+        device: &str,
+    ) -> Result<(ExclusiveSession<'a>, AccessKey, ForestState), String> {
+        let (mut helper, access_key, root_cid) = Self::init_exclusive(store, wnfs_key).await?;
+        let state = ForestState {
+            root_cid,
+            forest_version: 0,
+            created: Utc::now().to_rfc3339(),
+            device: device.to_string(),
+        };
+        helper.forest_state = Some(state.to_owned());
+        Ok((helper, access_key, state))
+    }
+
+    /// Loads the forest at `state` in an exclusive session. Commits made on `device` continue
+    /// its version count.
+    pub async fn load_from_state(
+        store: &mut FFIFriendlyBlockStore<'a>,
+        state: &ForestState,
+        wnfs_key: Vec<u8>,
+        device: &str,
+    ) -> Result<ExclusiveSession<'a>, String> {
+        let mut helper = Self::load_exclusive(store, state.root_cid, wnfs_key).await?;
+        helper.forest_state = Some(ForestState {
+            device: device.to_string(),
+            ..state.to_owned()
+        });
+        Ok(helper)
+    }
+
+    /// State at the latest commit, `None` for helpers not opened from a `ForestState`.
+    pub fn forest_state(&self) -> Option<ForestState> {
+        self.forest_state.to_owned()
+    }
+
+    /// Flushes pending commits like `flush_commits` and returns the state at the new root, to
+    /// store or publish instead of its CID. Fails for helpers not opened from a `ForestState`.
+    pub async fn commit_state(&mut self) -> Result<ForestState, String> {
+        self.flush_commits().await?;
+        self.forest_state().ok_or_else(|| {
+            trace!("wnfsError in commit_state: the helper wasn't opened from a forest state");
+            WnfsUtilsError::NotFound("forest state".to_string()).to_string()
+        })
+    }
+
+    // Moves the tracked state to a newly committed root.
+    pub(super) fn advance_forest_state(&mut self, root_cid: Cid) {
+        if let Some(state) = &mut self.forest_state {
+            if state.root_cid != root_cid {
+                state.root_cid = root_cid;
+                state.forest_version += 1;
+            }
+        }
+    }
+}
+
+impl<'a> PrivateDirectoryHelper<'a> {
+    pub fn synced_init_with_state(
+        store: &mut FFIFriendlyBlockStore<'a>,
+        wnfs_key: Vec<u8>,
+        device: &str,
+    ) -> Result<(ExclusiveSession<'a>, AccessKey, ForestState), String> {
+        Self::run_request(
+            "init_with_state",
+            Self::init_with_state(store, wnfs_key, device),
+        )
+    }
+
+    pub fn synced_commit_state(&mut self) -> Result<ForestState, String> {
+        Self::run_limited(
+            &self.operation_limiter(),
+            "commit_state",
+            self.commit_state(),
+        )
+    }
+
+    pub fn synced_load_from_state(
+        store: &mut FFIFriendlyBlockStore<'a>,
+        state: &ForestState,
+        wnfs_key: Vec<u8>,
+        device: &str,
+    ) -> Result<ExclusiveSession<'a>, String> {
+        Self::run_request(
+            "load_from_state",
+            Self::load_from_state(store, state, wnfs_key, device),
+        )
+    }
+}
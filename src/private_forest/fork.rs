@@ -0,0 +1,55 @@
+//! Forks of a forest, for "duplicate workspace" features and test sandboxes.
+//!
+//! A fork is a new forest root that starts out equal to the current one and shares every block
+//! with it. Its root directory is the original's, shared with one more key the same way `init`
+//! shares it with the first one, and isn't re-encrypted: both keys open the fork, and whoever
+//! holds the original's directory keys can follow the fork's tree as it is written. A fork
+//! branches work; to hand a tree to someone who mustn't read the original, re-encrypt it with
+//! `rotate_wnfs_key` instead.
+
+use std::rc::Rc;
+
+use libipld::Cid;
+use log::trace;
+
+use super::PrivateDirectoryHelper;
+use crate::error::describe;
+
+impl<'a> PrivateDirectoryHelper<'a> {
+    /// Forks the current root, shared with `new_key` as well, and returns the forest CID to
+    /// open it at with `load_exclusive` or `load_shared`. This helper stays on the original
+    /// forest.
+    pub async fn fork(&mut self, new_key: Vec<u8>) -> Result<Cid, String> {
+        let seed = Self::seed_from_key(&new_key).map_err(|e| {
+            trace!("wnfsError in fork: {:?}", e);
+            e
+        })?;
+        let mut forest = Rc::clone(&self.forest);
+        let mut store = self.store.to_owned();
+        let access_key = self
+            .root_dir
+            .as_node()
+            .store(&mut forest, &mut store, &mut self.rng)
+            .await
+            .map_err(|e| {
+                trace!("wnfsError in fork: {:?}", e.to_string());
+                describe(&e)
+            })?;
+        Self::setup_seeded_keypair_access(&mut forest, access_key, &mut store, seed)
+            .await
+            .map_err(|e| {
+                trace!(
+                    "wnfsError in fork:setup_seeded_keypair_access: {:?}",
+                    e.to_string()
+                );
+                describe(&e)
+            })?;
+        Self::update_private_forest(store, forest).await
+    }
+}
+
+impl<'a> PrivateDirectoryHelper<'a> {
+    pub fn synced_fork(&mut self, new_key: Vec<u8>) -> Result<Cid, String> {
+        Self::run_limited(&self.operation_limiter(), "fork", self.fork(new_key))
+    }
+}
@@ -0,0 +1,92 @@
+//! Streaming content hashes, so mirror and sync tools can compare a forest file with a local
+//! copy without loading either into memory. The same hashing backs the integrity checks of
+//! `ingest` and `materialize`, see `hash_forest_file`.
+
+use std::{fs::File, io::Read, path::Path};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::PrivateDirectoryHelper;
+use crate::error::describe;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum HashAlgorithm {
+    Sha256,
+    Blake3,
+}
+
+pub(super) enum Hasher {
+    Sha256(Sha256),
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl Hasher {
+    pub(super) fn new(algo: HashAlgorithm) -> Self {
+        match algo {
+            HashAlgorithm::Sha256 => Hasher::Sha256(Sha256::new()),
+            HashAlgorithm::Blake3 => Hasher::Blake3(Box::new(blake3::Hasher::new())),
+        }
+    }
+
+    pub(super) fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Sha256(hasher) => hasher.update(data),
+            Hasher::Blake3(hasher) => {
+                hasher.update(data);
+            }
+        }
+    }
+
+    pub(super) fn finalize(self) -> Vec<u8> {
+        match self {
+            Hasher::Sha256(hasher) => hasher.finalize().to_vec(),
+            Hasher::Blake3(hasher) => hasher.finalize().as_bytes().to_vec(),
+        }
+    }
+}
+
+impl HashAlgorithm {
+    /// Hashes a file on the local filesystem in 64KiB chunks, for comparison with
+    /// `PrivateDirectoryHelper::hash_file`.
+    pub fn hash_local_file(self, path: &Path) -> Result<Vec<u8>, String> {
+        let mut file = File::open(path).map_err(|e| describe(&e))?;
+        let mut hasher = Hasher::new(self);
+        let mut buffer = vec![0u8; 64 * 1024];
+        loop {
+            let read = file.read(&mut buffer).map_err(|e| describe(&e))?;
+            if read == 0 {
+                break;
+            }
+            hasher.update(&buffer[..read]);
+        }
+        Ok(hasher.finalize())
+    }
+}
+
+impl<'a> PrivateDirectoryHelper<'a> {
+    /// Hashes the content of the file at `path_segments` with `algo`, decrypting one block at a
+    /// time, or one chunk at a time for paged files.
+    pub async fn hash_file(
+        &mut self,
+        path_segments: &[String],
+        algo: HashAlgorithm,
+    ) -> Result<Vec<u8>, String> {
+        let (_, digest) = self
+            .hash_forest_file_with(path_segments, algo, None)
+            .await?;
+        Ok(digest)
+    }
+
+    pub fn synced_hash_file(
+        &mut self,
+        path_segments: &[String],
+        algo: HashAlgorithm,
+    ) -> Result<Vec<u8>, String> {
+        Self::run_limited(
+            &self.operation_limiter(),
+            "hash_file",
+            self.hash_file(path_segments, algo),
+        )
+    }
+}
@@ -0,0 +1,374 @@
+//! A clonable handle on a helper, for apps serving reads while a write is in flight.
+//!
+//! The helper's operations take `&mut self`, so a single owner can only run one at a time. A
+//! [`HelperHandle`] owns the helper behind an async lock that serializes writes, and keeps a
+//! snapshot of the tree as of the last completed write. Reads run on their own copy of that
+//! snapshot, see `PrivateDirectoryHelper::snapshot`, so any number of them run next to each
+//! other and next to a write without waiting for its lock; they see the write once it returned.
+//!
+//! Like the helper, handles hold `Rc`s and stay on the thread that created them, e.g. in a
+//! `LocalSet`. Code on other threads reaches them through a [`HandleBridge`], which is `Send`
+//! and `Sync`: the thread owning the handle drives [`serve_handle`], which runs the bridged
+//! requests next to each other like calls on the handle itself.
+
+use std::{cell::RefCell, rc::Rc};
+
+use futures::{future::LocalBoxFuture, stream::FuturesUnordered, StreamExt};
+use libipld::Cid;
+use tokio::sync::{mpsc, oneshot, Mutex};
+use wnfs::common::Metadata;
+
+use super::{OperationLimiter, PrivateDirectoryHelper, ReadOnlyView};
+
+struct HandleState<'a> {
+    writer: Mutex<PrivateDirectoryHelper<'a>>,
+    // The tree as of the last completed write.
+    latest: RefCell<ReadOnlyView<'a>>,
+}
+
+/// See the module docs. Clones share the helper.
+#[derive(Clone)]
+pub struct HelperHandle<'a> {
+    state: Rc<HandleState<'a>>,
+}
+
+impl<'a> HelperHandle<'a> {
+    /// The forest CID reads currently run on.
+    pub fn root_cid(&self) -> Cid {
+        self.state.latest.borrow().root_cid()
+    }
+
+    /// The limiter of the helper, which its synced operations run under.
+    pub fn operation_limiter(&self) -> OperationLimiter {
+        self.state.latest.borrow().helper.operation_limiter()
+    }
+
+    /// A view of the tree as of the last completed write, for reads the handle doesn't offer.
+    pub fn snapshot(&self) -> Result<ReadOnlyView<'a>, String> {
+        let latest = self.state.latest.borrow();
+        Ok(latest.helper.share_tree(latest.root_cid()))
+    }
+
+    pub async fn read_file(&self, path_segments: &[String]) -> Result<Vec<u8>, String> {
+        self.snapshot()?.read_file(path_segments).await
+    }
+
+    pub async fn ls_files(
+        &self,
+        path_segments: &[String],
+    ) -> Result<Vec<(String, Metadata)>, String> {
+        self.snapshot()?.ls_files(path_segments).await
+    }
+
+    pub async fn exists(&self, path_segments: &[String]) -> Result<bool, String> {
+        self.snapshot()?.exists(path_segments).await
+    }
+
+    /// Runs `operation` on the helper once the writes started before it completed. Reads see
+    /// its changes after it returned successfully.
+    pub async fn write<T, F>(&self, operation: F) -> Result<T, String>
+    where
+        F: for<'h> FnOnce(
+            &'h mut PrivateDirectoryHelper<'a>,
+        ) -> LocalBoxFuture<'h, Result<T, String>>,
+    {
+        let mut helper = self.state.writer.lock().await;
+        let result = operation(&mut helper).await?;
+        let latest = helper.snapshot().await?;
+        *self.state.latest.borrow_mut() = latest;
+        Ok(result)
+    }
+
+    pub async fn write_file(
+        &self,
+        path_segments: &[String],
+        content: Vec<u8>,
+        modification_time_seconds: i64,
+    ) -> Result<Cid, String> {
+        let path_segments = path_segments.to_vec();
+        self.write(move |helper| {
+            Box::pin(async move {
+                helper
+                    .write_file(&path_segments, content, modification_time_seconds)
+                    .await
+            })
+        })
+        .await
+    }
+
+    pub async fn mkdir(&self, path_segments: &[String]) -> Result<Cid, String> {
+        let path_segments = path_segments.to_vec();
+        self.write(move |helper| Box::pin(async move { helper.mkdir(&path_segments).await }))
+            .await
+    }
+
+    pub async fn rm(&self, path_segments: &[String]) -> Result<Cid, String> {
+        let path_segments = path_segments.to_vec();
+        self.write(move |helper| Box::pin(async move { helper.rm(&path_segments).await }))
+            .await
+    }
+
+    pub async fn mv(
+        &self,
+        source_path_segments: &[String],
+        target_path_segments: &[String],
+    ) -> Result<Cid, String> {
+        let source = source_path_segments.to_vec();
+        let target = target_path_segments.to_vec();
+        self.write(move |helper| Box::pin(async move { helper.mv(&source, &target).await }))
+            .await
+    }
+
+    /// The helper back, once this is the last clone of the handle.
+    pub fn into_inner(self) -> Result<PrivateDirectoryHelper<'a>, Self> {
+        match Rc::try_unwrap(self.state) {
+            Ok(state) => Ok(state.writer.into_inner()),
+            Err(state) => Err(Self { state }),
+        }
+    }
+}
+
+impl<'a> PrivateDirectoryHelper<'a> {
+    /// Moves the helper behind a [`HelperHandle`], committing batched mutations first. Fails
+    /// when no root was recorded yet.
+    pub async fn into_handle(mut self) -> Result<HelperHandle<'a>, String> {
+        let latest = self.snapshot().await?;
+        Ok(HelperHandle {
+            state: Rc::new(HandleState {
+                writer: Mutex::new(self),
+                latest: RefCell::new(latest),
+            }),
+        })
+    }
+}
+
+impl<'a> HelperHandle<'a> {
+    pub fn synced_read_file(&self, path_segments: &[String]) -> Result<Vec<u8>, String> {
+        PrivateDirectoryHelper::run_limited(
+            &self.operation_limiter(),
+            "handle_read_file",
+            self.read_file(path_segments),
+        )
+    }
+
+    pub fn synced_ls_files(
+        &self,
+        path_segments: &[String],
+    ) -> Result<Vec<(String, Metadata)>, String> {
+        PrivateDirectoryHelper::run_limited(
+            &self.operation_limiter(),
+            "handle_ls_files",
+            self.ls_files(path_segments),
+        )
+    }
+
+    pub fn synced_write_file(
+        &self,
+        path_segments: &[String],
+        content: Vec<u8>,
+        modification_time_seconds: i64,
+    ) -> Result<Cid, String> {
+        PrivateDirectoryHelper::run_limited(
+            &self.operation_limiter(),
+            "handle_write_file",
+            self.write_file(path_segments, content, modification_time_seconds),
+        )
+    }
+
+    pub fn synced_mkdir(&self, path_segments: &[String]) -> Result<Cid, String> {
+        PrivateDirectoryHelper::run_limited(
+            &self.operation_limiter(),
+            "handle_mkdir",
+            self.mkdir(path_segments),
+        )
+    }
+
+    pub fn synced_rm(&self, path_segments: &[String]) -> Result<Cid, String> {
+        PrivateDirectoryHelper::run_limited(
+            &self.operation_limiter(),
+            "handle_rm",
+            self.rm(path_segments),
+        )
+    }
+
+    pub fn synced_mv(
+        &self,
+        source_path_segments: &[String],
+        target_path_segments: &[String],
+    ) -> Result<Cid, String> {
+        PrivateDirectoryHelper::run_limited(
+            &self.operation_limiter(),
+            "handle_mv",
+            self.mv(source_path_segments, target_path_segments),
+        )
+    }
+}
+
+/// A request to a [`HelperHandle`] served by [`serve_handle`], carrying the channel its result
+/// is sent on.
+pub enum HandleRequest {
+    RootCid(oneshot::Sender<Result<Cid, String>>),
+    ReadFile(Vec<String>, oneshot::Sender<Result<Vec<u8>, String>>),
+    LsFiles(
+        Vec<String>,
+        oneshot::Sender<Result<Vec<(String, Metadata)>, String>>,
+    ),
+    Exists(Vec<String>, oneshot::Sender<Result<bool, String>>),
+    WriteFile(
+        Vec<String>,
+        Vec<u8>,
+        i64,
+        oneshot::Sender<Result<Cid, String>>,
+    ),
+    Mkdir(Vec<String>, oneshot::Sender<Result<Cid, String>>),
+    Rm(Vec<String>, oneshot::Sender<Result<Cid, String>>),
+    Mv(
+        Vec<String>,
+        Vec<String>,
+        oneshot::Sender<Result<Cid, String>>,
+    ),
+}
+
+/// A `Send` and `Sync` handle to a [`HelperHandle`] that stays on the thread owning it, see the
+/// module docs. Cheap to clone.
+#[derive(Clone)]
+pub struct HandleBridge {
+    requests: mpsc::Sender<HandleRequest>,
+}
+
+/// Creates a bridge and the receiving end to pass to [`serve_handle`]. `buffer` bounds the
+/// number of requests waiting to be served.
+pub fn handle_channel(buffer: usize) -> (HandleBridge, mpsc::Receiver<HandleRequest>) {
+    let (requests, receiver) = mpsc::channel(buffer);
+    (HandleBridge { requests }, receiver)
+}
+
+/// Serves requests until every bridge is dropped and the requests in flight completed. Reads
+/// don't wait for writes, and writes are serialized, as on the handle.
+pub async fn serve_handle(handle: HelperHandle<'_>, mut requests: mpsc::Receiver<HandleRequest>) {
+    let mut running = FuturesUnordered::new();
+    loop {
+        tokio::select! {
+            request = requests.recv() => match request {
+                Some(request) => running.push(serve_request(handle.clone(), request)),
+                None => break,
+            },
+            Some(()) = running.next(), if !running.is_empty() => {}
+        }
+    }
+    while running.next().await.is_some() {}
+}
+
+// A requester that went away doesn't want its result, so failed replies are ignored.
+async fn serve_request(handle: HelperHandle<'_>, request: HandleRequest) {
+    match request {
+        HandleRequest::RootCid(reply) => {
+            let _ = reply.send(Ok(handle.root_cid()));
+        }
+        HandleRequest::ReadFile(path, reply) => {
+            let _ = reply.send(handle.read_file(&path).await);
+        }
+        HandleRequest::LsFiles(path, reply) => {
+            let _ = reply.send(handle.ls_files(&path).await);
+        }
+        HandleRequest::Exists(path, reply) => {
+            let _ = reply.send(handle.exists(&path).await);
+        }
+        HandleRequest::WriteFile(path, content, modification_time_seconds, reply) => {
+            let _ = reply.send(
+                handle
+                    .write_file(&path, content, modification_time_seconds)
+                    .await,
+            );
+        }
+        HandleRequest::Mkdir(path, reply) => {
+            let _ = reply.send(handle.mkdir(&path).await);
+        }
+        HandleRequest::Rm(path, reply) => {
+            let _ = reply.send(handle.rm(&path).await);
+        }
+        HandleRequest::Mv(source, target, reply) => {
+            let _ = reply.send(handle.mv(&source, &target).await);
+        }
+    }
+}
+
+impl HandleBridge {
+    pub async fn root_cid(&self) -> Result<Cid, String> {
+        self.call(HandleRequest::RootCid).await
+    }
+
+    pub async fn read_file(&self, path_segments: &[String]) -> Result<Vec<u8>, String> {
+        self.call(|reply| HandleRequest::ReadFile(path_segments.to_vec(), reply))
+            .await
+    }
+
+    pub async fn ls_files(
+        &self,
+        path_segments: &[String],
+    ) -> Result<Vec<(String, Metadata)>, String> {
+        self.call(|reply| HandleRequest::LsFiles(path_segments.to_vec(), reply))
+            .await
+    }
+
+    pub async fn exists(&self, path_segments: &[String]) -> Result<bool, String> {
+        self.call(|reply| HandleRequest::Exists(path_segments.to_vec(), reply))
+            .await
+    }
+
+    pub async fn write_file(
+        &self,
+        path_segments: &[String],
+        content: Vec<u8>,
+        modification_time_seconds: i64,
+    ) -> Result<Cid, String> {
+        self.call(|reply| {
+            HandleRequest::WriteFile(
+                path_segments.to_vec(),
+                content,
+                modification_time_seconds,
+                reply,
+            )
+        })
+        .await
+    }
+
+    pub async fn mkdir(&self, path_segments: &[String]) -> Result<Cid, String> {
+        self.call(|reply| HandleRequest::Mkdir(path_segments.to_vec(), reply))
+            .await
+    }
+
+    pub async fn rm(&self, path_segments: &[String]) -> Result<Cid, String> {
+        self.call(|reply| HandleRequest::Rm(path_segments.to_vec(), reply))
+            .await
+    }
+
+    pub async fn mv(
+        &self,
+        source_path_segments: &[String],
+        target_path_segments: &[String],
+    ) -> Result<Cid, String> {
+        self.call(|reply| {
+            HandleRequest::Mv(
+                source_path_segments.to_vec(),
+                target_path_segments.to_vec(),
+                reply,
+            )
+        })
+        .await
+    }
+
+    async fn call<T>(
+        &self,
+        request: impl FnOnce(oneshot::Sender<Result<T, String>>) -> HandleRequest,
+    ) -> Result<T, String> {
+        let (reply, result) = oneshot::channel();
+        self.requests
+            .send(request(reply))
+            .await
+            .map_err(|_| "wnfsError the handle is no longer served".to_string())?;
+        result
+            .await
+            .map_err(|_| "wnfsError the handle is no longer served".to_string())?
+    }
+}
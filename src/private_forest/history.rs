@@ -0,0 +1,332 @@
+//! Read-only access to historical forest roots, side by side with the live helper.
+//!
+//! A view is also how long reads get snapshot isolation: `snapshot` captures the latest root
+//! before a CAR export, an archive or a media stream starts, and the view keeps reading that
+//! revision while the live helper commits new ones.
+//!
+//! `history` lists the revisions of a single entry, for undo and "restore previous version". It
+//! starts from the entry in the oldest root the helper recorded and follows the entry's own
+//! revision links from there, so revisions committed between two recorded roots, e.g. by
+//! another device or squashed away, are listed too; revisions older than the oldest recorded
+//! root are out of its reach, since the keys of a revision only lead to later ones.
+
+use std::rc::Rc;
+
+use futures::stream::LocalBoxStream;
+use libipld::Cid;
+use log::trace;
+use rand::thread_rng;
+use wnfs::{
+    common::Metadata,
+    private::{PrivateNode, PrivateNodeHistory},
+};
+
+use super::{PrivateDirectoryHelper, SeededExchangeKey, WalkEntry, WalkOptions};
+use crate::error::describe;
+
+// How many revisions walking back between two recorded roots may pass.
+const HISTORY_DISCREPANCY_BUDGET: usize = 1_000_000;
+
+/// A revision of a file or directory, see `PrivateDirectoryHelper::history`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RevisionInfo {
+    /// First recorded forest root the revision was the latest in; `open_at` it to browse its
+    /// siblings as they were then. `None` for revisions committed between two recorded roots.
+    pub root_cid: Option<Cid>,
+    pub is_dir: bool,
+    /// Upper bound of the content size, 0 for directories.
+    pub size: u64,
+    pub metadata: Metadata,
+}
+
+impl RevisionInfo {
+    fn of(root_cid: Option<Cid>, node: &PrivateNode) -> Self {
+        match node {
+            PrivateNode::Dir(dir) => RevisionInfo {
+                root_cid,
+                is_dir: true,
+                size: 0,
+                metadata: dir.get_metadata().to_owned(),
+            },
+            PrivateNode::File(file) => RevisionInfo {
+                root_cid,
+                is_dir: false,
+                size: file.get_content_size_upper_bound() as u64,
+                metadata: file.get_metadata().to_owned(),
+            },
+        }
+    }
+}
+
+/// A past forest root opened for reading. It shares the store (and anything layered into it) with
+/// the helper that opened it, and offers no way to mutate the forest.
+pub struct ReadOnlyView<'a> {
+    pub(super) helper: PrivateDirectoryHelper<'a>,
+    pub(super) root_cid: Cid,
+}
+
+impl<'a> ReadOnlyView<'a> {
+    /// The forest CID this view was opened at.
+    pub fn root_cid(&self) -> Cid {
+        self.root_cid
+    }
+
+    pub async fn exists(&mut self, path_segments: &[String]) -> Result<bool, String> {
+        Ok(self.helper.node_at(path_segments).await?.is_some())
+    }
+
+    pub async fn read_file(&mut self, path_segments: &[String]) -> Result<Vec<u8>, String> {
+        self.helper.read_file(path_segments).await
+    }
+
+    pub async fn read_filestream_to_path(
+        &mut self,
+        local_filename: &String,
+        path_segments: &[String],
+        index: usize,
+    ) -> Result<bool, String> {
+        self.helper
+            .read_filestream_to_path(local_filename, path_segments, index)
+            .await
+    }
+
+    pub async fn ls_files(
+        &mut self,
+        path_segments: &[String],
+    ) -> Result<Vec<(String, Metadata)>, String> {
+        self.helper.ls_files(path_segments).await
+    }
+
+    /// See `PrivateDirectoryHelper::read_file_stream`.
+    pub fn read_file_stream<'h>(
+        &'h mut self,
+        path_segments: &[String],
+    ) -> LocalBoxStream<'h, Result<Vec<u8>, String>> {
+        self.helper.read_file_stream(path_segments)
+    }
+
+    /// See `PrivateDirectoryHelper::walk`.
+    pub fn walk<'h>(
+        &'h mut self,
+        path_segments: &[String],
+        options: WalkOptions,
+    ) -> LocalBoxStream<'h, Result<WalkEntry, String>> {
+        self.helper.walk(path_segments, options)
+    }
+}
+
+impl<'a> PrivateDirectoryHelper<'a> {
+    /// Opens the forest as it was at `root_cid`, a CID previously returned by a commit of this
+    /// forest, e.g. to show a file "as of last Tuesday" next to its current version.
+    pub async fn open_at(&self, root_cid: Cid) -> Result<ReadOnlyView<'a>, String> {
+        let exchange_keypair = Self::exchange_keypair(&self.wnfs_key)?;
+        self.open_with_keypair(root_cid, &exchange_keypair).await
+    }
+
+    pub(super) async fn open_with_keypair(
+        &self,
+        root_cid: Cid,
+        exchange_keypair: &SeededExchangeKey,
+    ) -> Result<ReadOnlyView<'a>, String> {
+        let mut store = self.store.to_owned();
+        let (forest, root_dir) =
+            Self::load_root_dir(&mut store, root_cid, &self.wnfs_key, exchange_keypair).await?;
+        let mut helper = Self::from_parts(
+            store,
+            forest,
+            root_dir,
+            thread_rng(),
+            self.wnfs_key.to_owned(),
+        );
+        // Paths resolve the same way as in the live helper, e.g. into directory shards.
+        helper.config = self.config.to_owned();
+        helper.limiter = self.operation_limiter();
+        helper.record_root(root_cid);
+        Ok(ReadOnlyView { helper, root_cid })
+    }
+
+    /// Revisions of the entry at `path_segments`, oldest first, see the module docs. Each stored
+    /// change of the entry is a revision, e.g. a write; commits that didn't touch it add none.
+    /// Roots where the entry didn't exist are skipped.
+    pub async fn history(&mut self, path_segments: &[String]) -> Result<Vec<RevisionInfo>, String> {
+        Ok(self
+            .revisions(path_segments)
+            .await?
+            .into_iter()
+            .map(|(info, _)| info)
+            .collect())
+    }
+
+    async fn revisions(
+        &mut self,
+        path_segments: &[String],
+    ) -> Result<Vec<(RevisionInfo, PrivateNode)>, String> {
+        self.check_path_depth(path_segments)?;
+        let exchange_keypair = Self::exchange_keypair(&self.wnfs_key)?;
+        let mut revisions: Vec<(RevisionInfo, PrivateNode)> = Vec::new();
+        for root_cid in self.root_history.to_owned() {
+            let mut view = self.open_with_keypair(root_cid, &exchange_keypair).await?;
+            let Some(node) = view.helper.node_at(path_segments).await? else {
+                continue;
+            };
+            if let Some((_, last)) = revisions.last() {
+                // The same stored revision, reached through a root that didn't change it.
+                if *last == node {
+                    continue;
+                }
+                for between in self.revisions_between(&node, last).await {
+                    revisions.push((RevisionInfo::of(None, &between), between));
+                }
+            }
+            revisions.push((RevisionInfo::of(Some(root_cid), &node), node));
+        }
+        Ok(revisions)
+    }
+
+    // The revisions committed after `past` and before `node`, oldest first. None when `node`
+    // doesn't descend from `past`, e.g. for a file created again at the same path, or when a
+    // revision can't be read.
+    async fn revisions_between(&self, node: &PrivateNode, past: &PrivateNode) -> Vec<PrivateNode> {
+        let forest = Rc::clone(&self.forest);
+        let mut history =
+            match PrivateNodeHistory::of(node, past, HISTORY_DISCREPANCY_BUDGET, forest) {
+                Ok(history) => history,
+                Err(e) => {
+                    trace!("wnfsutils: history can't walk back: {:?}", e.to_string());
+                    return Vec::new();
+                }
+            };
+        let mut between = Vec::new();
+        loop {
+            match history.get_previous_node(&self.store).await {
+                Ok(Some(previous)) if previous != *past => between.push(previous),
+                Ok(_) => break,
+                Err(e) => {
+                    trace!("wnfsutils: history can't walk back: {:?}", e.to_string());
+                    return Vec::new();
+                }
+            }
+        }
+        between.reverse();
+        between
+    }
+
+    /// The content of the file at `path_segments` as of revision `revision` of its `history`.
+    pub async fn read_file_at_revision(
+        &mut self,
+        path_segments: &[String],
+        revision: usize,
+    ) -> Result<Vec<u8>, String> {
+        let mut revisions = self.revisions(path_segments).await?;
+        if revision >= revisions.len() {
+            trace!(
+                "wnfsError in read_file_at_revision: {:?} has {} revisions",
+                path_segments,
+                revisions.len()
+            );
+            return Err(format!(
+                "wnfsError no revision {} of {:?}",
+                revision, path_segments
+            ));
+        }
+        let (info, node) = revisions.swap_remove(revision);
+        let file = match node {
+            PrivateNode::File(file) => file,
+            PrivateNode::Dir(_) => {
+                return Err(format!(
+                    "wnfsError revision {} of {:?} is a directory",
+                    revision, path_segments
+                ))
+            }
+        };
+        if let Some(root_cid) = info.root_cid {
+            // Through the tree, which also reads paged files.
+            return self.open_at(root_cid).await?.read_file(path_segments).await;
+        }
+        file.get_content(&self.forest, &self.store)
+            .await
+            .map_err(|e| {
+                trace!("wnfsError in read_file_at_revision: {:?}", e.to_string());
+                describe(&e)
+            })
+    }
+
+    /// Writes the content of revision `revision` of the file at `path_segments` back as its
+    /// latest version, in a new commit. Later revisions stay in the history.
+    pub async fn restore_revision(
+        &mut self,
+        path_segments: &[String],
+        revision: usize,
+    ) -> Result<Cid, String> {
+        let content = self.read_file_at_revision(path_segments, revision).await?;
+        self.write_file(path_segments, content, 0).await
+    }
+
+    /// Opens the forest at its latest commit, committing batched mutations first. Outside a
+    /// transaction the view shares the nodes already decrypted instead of loading them again,
+    /// and the helper copies them on its next write instead of changing them in place. Nothing
+    /// committed afterwards shows up in the view. Within a transaction the view is loaded from
+    /// the root the transaction started from.
+    pub async fn snapshot(&mut self) -> Result<ReadOnlyView<'a>, String> {
+        let root_cid = self.flush_commits().await?;
+        if self.in_transaction {
+            // The tree holds changes the transaction hasn't committed.
+            return self.open_at(root_cid).await;
+        }
+        Ok(self.share_tree(root_cid))
+    }
+
+    // A view sharing the in-memory tree, which must be the one committed as `root_cid`.
+    pub(super) fn share_tree(&self, root_cid: Cid) -> ReadOnlyView<'a> {
+        let mut helper = Self::from_parts(
+            self.store.to_owned(),
+            Rc::clone(&self.forest),
+            Rc::clone(&self.root_dir),
+            thread_rng(),
+            self.wnfs_key.to_owned(),
+        );
+        helper.config = self.config.to_owned();
+        helper.name_indexes = self.name_indexes.to_owned();
+        helper.name_index_revisions = self.name_index_revisions.to_owned();
+        helper.limiter = self.operation_limiter();
+        helper.root_history = vec![root_cid];
+        ReadOnlyView { helper, root_cid }
+    }
+}
+
+impl<'a> PrivateDirectoryHelper<'a> {
+    pub fn synced_history(
+        &mut self,
+        path_segments: &[String],
+    ) -> Result<Vec<RevisionInfo>, String> {
+        Self::run_limited(
+            &self.operation_limiter(),
+            "history",
+            self.history(path_segments),
+        )
+    }
+
+    pub fn synced_read_file_at_revision(
+        &mut self,
+        path_segments: &[String],
+        revision: usize,
+    ) -> Result<Vec<u8>, String> {
+        Self::run_limited(
+            &self.operation_limiter(),
+            "read_file_at_revision",
+            self.read_file_at_revision(path_segments, revision),
+        )
+    }
+
+    pub fn synced_restore_revision(
+        &mut self,
+        path_segments: &[String],
+        revision: usize,
+    ) -> Result<Cid, String> {
+        Self::run_limited(
+            &self.operation_limiter(),
+            "restore_revision",
+            self.restore_revision(path_segments, revision),
+        )
+    }
+}
@@ -0,0 +1,145 @@
+//! Idempotency keys for mutations, so an app retrying a call after its process was killed
+//! doesn't write a file twice or append the same entry again.
+//!
+//! Completed keys are kept in a reserved file inside the private forest, written in the same
+//! commit as the mutation, so the CID returned holds both or neither. A retry is recognized once
+//! the app loads that root. A root that was never persisted holds neither the mutation nor its
+//! key, and the retry runs again as it should.
+//!
+//! A duplicate returns the root the first call returned. A record can't hold the root it's
+//! committed in, so the helper remembers the roots of its own keyed calls and writes them into
+//! the older records with the next keyed mutation. Only the newest key of a loaded forest has no
+//! root recorded yet; its duplicate returns the loaded root, which is the first call's root unless
+//! unkeyed mutations were committed after it.
+
+use chrono::Utc;
+use futures::future::LocalBoxFuture;
+use libipld::Cid;
+use log::trace;
+use serde::{Deserialize, Serialize};
+
+use super::{PrivateDirectoryHelper, RESERVED_DIR};
+use crate::error::describe;
+
+const IDEMPOTENCY_FILE: &str = "idempotency.json";
+
+/// Number of completed keys remembered, oldest dropped first.
+pub const IDEMPOTENCY_KEY_LIMIT: usize = 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct IdempotencyRecord {
+    key: String,
+    operation: String,
+    /// RFC 3339 time the mutation completed.
+    completed_at: String,
+    /// The root returned for the key, once a later keyed mutation could record it.
+    #[serde(default)]
+    result: Option<String>,
+}
+
+impl<'a> PrivateDirectoryHelper<'a> {
+    /// Runs the mutation `op` once per `key`. A duplicate `key` returns the root the first
+    /// attempt returned, see the module docs, without running `op` again. Reusing a key for a
+    /// different `operation` is an error. Without a key, `op` simply runs. `op` gets
+    /// the helper back, e.g. `|helper| Box::pin(helper.write_file(&path, content, 0))`.
+    pub async fn idempotent<F>(
+        &mut self,
+        key: Option<&str>,
+        operation: &str,
+        op: F,
+    ) -> Result<Cid, String>
+    where
+        F: for<'h> FnOnce(
+            &'h mut PrivateDirectoryHelper<'a>,
+        ) -> LocalBoxFuture<'h, Result<Cid, String>>,
+    {
+        let key = match key {
+            Some(key) if !key.is_empty() => key,
+            Some(_) => return Err("wnfsError idempotency key is empty".to_string()),
+            None => return op(self).await,
+        };
+        let mut records = self.idempotency_records().await?;
+        if let Some(record) = records.iter().find(|record| record.key == key) {
+            if record.operation != operation {
+                trace!(
+                    "wnfsError in idempotent: key {:?} used for {} and {}",
+                    key,
+                    record.operation,
+                    operation
+                );
+                return Err(format!(
+                    "wnfsError idempotency key {} was used for {}",
+                    key, record.operation
+                ));
+            }
+            trace!("wnfsutils: {} with key {:?} already done", operation, key);
+            let recorded = record
+                .result
+                .as_deref()
+                .and_then(|result| Cid::try_from(result).ok())
+                .or_else(|| self.idempotency_roots.get(key).copied());
+            return match recorded {
+                Some(root) => Ok(root),
+                None => self.flush_commits().await,
+            };
+        }
+        for record in records.iter_mut().filter(|record| record.result.is_none()) {
+            if let Some(root) = self.idempotency_roots.get(&record.key) {
+                record.result = Some(root.to_string());
+            }
+        }
+        records.push(IdempotencyRecord {
+            key: key.to_string(),
+            operation: operation.to_string(),
+            completed_at: Utc::now().to_rfc3339(),
+            result: None,
+        });
+        if records.len() > IDEMPOTENCY_KEY_LIMIT {
+            let excess = records.len() - IDEMPOTENCY_KEY_LIMIT;
+            records.drain(..excess);
+        }
+        let content = serde_json::to_vec(&records).map_err(|e| describe(&e))?;
+        let mut tx = self.begin();
+        op(&mut tx).await?;
+        tx.write_raw(&Self::idempotency_path(), content).await?;
+        let root = tx.commit().await?;
+        self.idempotency_roots.insert(key.to_string(), root);
+        Ok(root)
+    }
+
+    async fn idempotency_records(&mut self) -> Result<Vec<IdempotencyRecord>, String> {
+        let path = Self::idempotency_path();
+        if self.node_at(&path).await?.is_none() {
+            return Ok(Vec::new());
+        }
+        let content = self.read_file(&path).await?;
+        serde_json::from_slice(&content).map_err(|e| {
+            trace!("wnfsError in idempotency_records: {:?}", e.to_string());
+            describe(&e)
+        })
+    }
+
+    fn idempotency_path() -> Vec<String> {
+        vec![RESERVED_DIR.to_string(), IDEMPOTENCY_FILE.to_string()]
+    }
+}
+
+impl<'a> PrivateDirectoryHelper<'a> {
+    pub fn synced_idempotent<F>(
+        &mut self,
+        key: Option<&str>,
+        operation: &str,
+        op: F,
+    ) -> Result<Cid, String>
+    where
+        F: for<'h> FnOnce(
+            &'h mut PrivateDirectoryHelper<'a>,
+        ) -> LocalBoxFuture<'h, Result<Cid, String>>,
+    {
+        Self::run_limited(
+            &self.operation_limiter(),
+            "idempotent",
+            self.idempotent(key, operation, op),
+        )
+    }
+}
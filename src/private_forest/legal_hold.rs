@@ -0,0 +1,173 @@
+//! Legal holds: subtrees that reject every mutation until the custodian who placed the hold
+//! releases it. Holds are kept in a reserved file inside the private forest, so they are
+//! encrypted and travel with the forest CID.
+
+use chrono::Utc;
+use libipld::Cid;
+use log::trace;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::PrivateDirectoryHelper;
+use crate::error::{describe, WnfsUtilsError};
+
+/// Directory reserved for the helper's own bookkeeping. It can only be changed through the
+/// helper APIs that own it, never through the regular write operations.
+pub const RESERVED_DIR: &str = ".wnfsutils";
+const LEGAL_HOLDS_FILE: &str = "legal_holds.json";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LegalHold {
+    pub path: Vec<String>,
+    pub reason: String,
+    /// RFC 3339 time the hold was placed.
+    pub placed_at: String,
+    /// Hex encoded SHA-256 of the custodian key, which must be presented to release the hold.
+    pub custodian_key_hash: String,
+}
+
+impl<'a> PrivateDirectoryHelper<'a> {
+    pub async fn legal_holds(&mut self) -> Result<Vec<LegalHold>, String> {
+        if let Some(holds) = &self.legal_holds {
+            return Ok(holds.to_owned());
+        }
+        let path = Self::legal_holds_path();
+        let holds = match self.node_at(&path).await? {
+            Some(_) => {
+                let content = self.read_file(&path).await?;
+                serde_json::from_slice(&content).map_err(|e| {
+                    trace!("wnfsError in legal_holds: {:?}", e.to_string());
+                    describe(&e)
+                })?
+            }
+            None => Vec::new(),
+        };
+        self.legal_holds = Some(holds.to_owned());
+        Ok(holds)
+    }
+
+    /// Makes the subtree at `path_segments` immutable: writes, moves, copies onto and removals
+    /// inside it, or of any of its ancestors, fail with `WnfsUtilsError::LegalHold` until the
+    /// hold is released with the same `custodian_key`.
+    pub async fn place_legal_hold(
+        &mut self,
+        path_segments: &[String],
+        reason: &str,
+        custodian_key: &[u8],
+    ) -> Result<Cid, String> {
+        if custodian_key.is_empty() {
+            return Err("wnfsError custodian key is empty".to_string());
+        }
+        if self.node_at(path_segments).await?.is_none() {
+            return Err(format!(
+                "wnfsError no node found at {}",
+                path_segments.join("/")
+            ));
+        }
+        let mut holds = self.legal_holds().await?;
+        holds.push(LegalHold {
+            path: self.normalize_path(path_segments),
+            reason: reason.to_string(),
+            placed_at: Utc::now().to_rfc3339(),
+            custodian_key_hash: Self::custodian_key_hash(custodian_key),
+        });
+        self.store_legal_holds(holds).await
+    }
+
+    /// Releases the hold on exactly `path_segments` placed with `custodian_key`. Other holds on
+    /// the same or overlapping subtrees stay in place.
+    pub async fn release_legal_hold(
+        &mut self,
+        path_segments: &[String],
+        custodian_key: &[u8],
+    ) -> Result<Cid, String> {
+        let key_hash = Self::custodian_key_hash(custodian_key);
+        let path = self.normalize_path(path_segments);
+        let mut holds = self.legal_holds().await?;
+        let position = holds
+            .iter()
+            .position(|hold| {
+                self.normalize_path(&hold.path) == path && hold.custodian_key_hash == key_hash
+            })
+            .ok_or_else(|| {
+                WnfsUtilsError::LegalHoldRelease {
+                    path: path_segments.join("/"),
+                }
+                .to_string()
+            })?;
+        holds.remove(position);
+        self.store_legal_holds(holds).await
+    }
+
+    // Fails if `path_segments` lies inside a held subtree or the reserved directory. With
+    // `include_descendants`, also fails if a held subtree lies below `path_segments`, for
+    // operations such as `rm` and `mv` that take the whole subtree with them. Paths are compared
+    // as they will be stored, so another normalization form of a held name is held too.
+    pub(super) async fn check_not_held(
+        &mut self,
+        path_segments: &[String],
+        include_descendants: bool,
+    ) -> Result<(), String> {
+        let normalized = self.normalize_path(path_segments);
+        let path_segments = normalized.as_slice();
+        let reserved = [RESERVED_DIR.to_string()];
+        if path_segments.starts_with(&reserved) || (include_descendants && path_segments.is_empty())
+        {
+            return Err(WnfsUtilsError::LegalHold {
+                path: RESERVED_DIR.to_string(),
+            }
+            .to_string());
+        }
+        let holds = self.legal_holds().await?;
+        let held = holds.iter().find(|hold| {
+            let hold_path = self.normalize_path(&hold.path);
+            path_segments.starts_with(&hold_path)
+                || (include_descendants && hold_path.starts_with(path_segments))
+        });
+        match held {
+            Some(hold) => {
+                trace!(
+                    "wnfsError in check_not_held: {:?} is under legal hold",
+                    path_segments
+                );
+                Err(WnfsUtilsError::LegalHold {
+                    path: hold.path.join("/"),
+                }
+                .to_string())
+            }
+            None => Ok(()),
+        }
+    }
+
+    async fn store_legal_holds(&mut self, holds: Vec<LegalHold>) -> Result<Cid, String> {
+        let content = serde_json::to_vec(&holds).map_err(|e| describe(&e))?;
+        let path = Self::legal_holds_path();
+        let forest = &mut self.forest;
+        let root_dir = &mut self.root_dir;
+        root_dir
+            .write(
+                &path,
+                true,
+                Utc::now(),
+                content,
+                forest,
+                &mut self.store,
+                &mut self.rng,
+            )
+            .await
+            .map_err(|e| {
+                trace!("wnfsError in store_legal_holds: {:?}", e.to_string());
+                describe(&e)
+            })?;
+        self.legal_holds = Some(holds);
+        self.commit().await
+    }
+
+    fn legal_holds_path() -> Vec<String> {
+        vec![RESERVED_DIR.to_string(), LEGAL_HOLDS_FILE.to_string()]
+    }
+
+    fn custodian_key_hash(custodian_key: &[u8]) -> String {
+        Self::bytes_to_hex_str(&Sha256::digest(custodian_key))
+    }
+}
@@ -0,0 +1,217 @@
+//! Limits on the synced operations, the entry points the bindings call.
+//!
+//! Without them a UI firing hundreds of reads at once opens as many files, runtimes and gateway
+//! connections. An [`OperationLimiter`] with [`OperationLimits`] set admits at most
+//! `max_in_flight` operations at a time and has the others wait on the calling thread, admitted
+//! in the order they arrived so a burst can't starve an older request. Each helper starts with a
+//! limiter of its own and no limits; an app shares one limiter between its helpers with
+//! `set_operation_limiter`. Async callers manage their own concurrency and aren't limited, and
+//! neither are the synced constructors, which have no helper yet.
+//!
+//! The limiter also carries the helper's `OperationMetrics`, as both go along with every synced
+//! call. A limiter set with `set_operation_limiter` only brings its limits and queue, so each
+//! helper keeps timing its own operations.
+
+use std::{
+    sync::{Arc, Condvar, Mutex, MutexGuard},
+    time::Duration,
+};
+
+use log::trace;
+use web_time::Instant;
+
+use super::PrivateDirectoryHelper;
+use crate::{error::WnfsUtilsError, metrics::OperationMetrics};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OperationLimits {
+    /// Operations running at the same time.
+    pub max_in_flight: usize,
+    /// Operations started per second, `None` for no rate limit.
+    pub max_per_second: Option<u32>,
+    /// Operations waiting for their turn. Further ones fail at once with
+    /// `WnfsUtilsError::Overloaded`; `None` lets the queue grow.
+    pub max_queued: Option<usize>,
+}
+
+impl Default for OperationLimits {
+    fn default() -> Self {
+        Self {
+            max_in_flight: 8,
+            max_per_second: None,
+            max_queued: Some(256),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Queue {
+    limits: Option<OperationLimits>,
+    in_flight: usize,
+    // Tickets are handed out on arrival and admitted in order.
+    next_ticket: u64,
+    serving: u64,
+    // Start times within the last second, oldest first, kept only under `max_per_second`.
+    recent_starts: Vec<Instant>,
+}
+
+impl Queue {
+    fn queued(&self) -> usize {
+        (self.next_ticket - self.serving) as usize
+    }
+
+    // How long the operation holding the next ticket still has to wait, `None` once it may start.
+    fn wait_for(&mut self, limits: &OperationLimits, now: Instant) -> Option<Duration> {
+        if self.in_flight >= limits.max_in_flight.max(1) {
+            return Some(Duration::MAX);
+        }
+        let Some(rate) = limits.max_per_second else {
+            self.recent_starts.clear();
+            return None;
+        };
+        self.recent_starts
+            .retain(|start| now.duration_since(*start) < Duration::from_secs(1));
+        if self.recent_starts.len() < rate.max(1) as usize {
+            return None;
+        }
+        let oldest = self.recent_starts.first()?;
+        Some(Duration::from_secs(1).saturating_sub(now.duration_since(*oldest)))
+    }
+}
+
+#[derive(Default)]
+struct Shared {
+    queue: Mutex<Queue>,
+    turn: Condvar,
+}
+
+/// Admits the synced operations of the helpers sharing it, see the module docs. Clones share
+/// the limits, the queue and the metrics.
+#[derive(Clone, Default)]
+pub struct OperationLimiter {
+    shared: Arc<Shared>,
+    metrics: OperationMetrics,
+}
+
+// Held while an admitted operation runs.
+pub(super) struct Permit {
+    limiter: Option<OperationLimiter>,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        if let Some(limiter) = self.limiter.take() {
+            let mut queue = limiter.lock();
+            queue.in_flight -= 1;
+            drop(queue);
+            limiter.shared.turn.notify_all();
+        }
+    }
+}
+
+impl OperationLimiter {
+    pub fn new(limits: Option<OperationLimits>) -> Self {
+        let limiter = Self::default();
+        limiter.set_limits(limits);
+        limiter
+    }
+
+    /// Changes the limits, `None` to lift them. Operations already waiting are checked against
+    /// the new limits.
+    pub fn set_limits(&self, limits: Option<OperationLimits>) {
+        self.lock().limits = limits;
+        self.shared.turn.notify_all();
+    }
+
+    pub fn limits(&self) -> Option<OperationLimits> {
+        self.lock().limits
+    }
+
+    pub fn metrics(&self) -> &OperationMetrics {
+        &self.metrics
+    }
+
+    /// Operations running and waiting under the limits.
+    pub fn in_flight(&self) -> (usize, usize) {
+        let queue = self.lock();
+        (queue.in_flight, queue.queued())
+    }
+
+    // Waits for the turn of the calling operation.
+    pub(super) fn admit(&self, operation: &'static str) -> Result<Permit, String> {
+        let mut queue = self.lock();
+        let Some(limits) = queue.limits else {
+            return Ok(Permit { limiter: None });
+        };
+        if let Some(max_queued) = limits.max_queued {
+            if queue.queued() >= max_queued {
+                trace!("wnfsError in {}: too many queued operations", operation);
+                return Err(WnfsUtilsError::Overloaded { limit: max_queued }.to_string());
+            }
+        }
+        let ticket = queue.next_ticket;
+        queue.next_ticket += 1;
+        loop {
+            let limits = queue.limits;
+            let wait = match limits {
+                // Tickets passed over while the limits were lifted go first.
+                Some(limits) if queue.serving >= ticket => queue.wait_for(&limits, Instant::now()),
+                Some(_) => Some(Duration::MAX),
+                // Limits lifted while waiting.
+                None => None,
+            };
+            match wait {
+                None => break,
+                Some(Duration::MAX) => {
+                    queue = match self.shared.turn.wait(queue) {
+                        Ok(queue) => queue,
+                        Err(poisoned) => poisoned.into_inner(),
+                    }
+                }
+                Some(wait) => {
+                    queue = match self.shared.turn.wait_timeout(queue, wait) {
+                        Ok((queue, _)) => queue,
+                        Err(poisoned) => poisoned.into_inner().0,
+                    }
+                }
+            }
+        }
+        queue.serving = queue.serving.max(ticket + 1);
+        queue.in_flight += 1;
+        if queue
+            .limits
+            .and_then(|limits| limits.max_per_second)
+            .is_some()
+        {
+            queue.recent_starts.push(Instant::now());
+        }
+        drop(queue);
+        // The next ticket may be admitted too.
+        self.shared.turn.notify_all();
+        Ok(Permit {
+            limiter: Some(self.to_owned()),
+        })
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Queue> {
+        match self.shared.queue.lock() {
+            Ok(queue) => queue,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+}
+
+impl<'a> PrivateDirectoryHelper<'a> {
+    /// Runs the synced operations of this helper under `limiter`, e.g. one shared by every
+    /// helper of the app. The helper keeps its own metrics.
+    pub fn set_operation_limiter(&mut self, limiter: OperationLimiter) {
+        self.limiter = OperationLimiter {
+            metrics: self.limiter.metrics.to_owned(),
+            ..limiter
+        };
+    }
+
+    pub fn operation_limiter(&self) -> OperationLimiter {
+        self.limiter.to_owned()
+    }
+}
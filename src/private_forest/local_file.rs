@@ -0,0 +1,29 @@
+//! Local files read by the path-based writes and imports. Browsers have no filesystem: on wasm32
+//! the same calls build and fail with the platform's `Unsupported` error.
+
+use std::{io, path::Path, time::SystemTime};
+
+/// A local file opened for `PrivateDirectoryHelper::write_file_stream`.
+#[cfg(not(target_arch = "wasm32"))]
+pub type LocalFileReader = async_std::io::BufReader<async_std::fs::File>;
+#[cfg(target_arch = "wasm32")]
+pub type LocalFileReader = futures::io::BufReader<futures::io::AllowStdIo<std::fs::File>>;
+
+/// Opens `path` with its modification time, `None` where the platform has none.
+pub(super) async fn open_local_file(
+    path: &Path,
+) -> io::Result<(LocalFileReader, Option<SystemTime>)> {
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        let file = async_std::fs::File::open(path).await?;
+        let modified = file.metadata().await?.modified().ok();
+        Ok((async_std::io::BufReader::new(file), modified))
+    }
+    #[cfg(target_arch = "wasm32")]
+    {
+        let file = std::fs::File::open(path)?;
+        let modified = file.metadata()?.modified().ok();
+        let reader = futures::io::BufReader::new(futures::io::AllowStdIo::new(file));
+        Ok((reader, modified))
+    }
+}
@@ -0,0 +1,165 @@
+//! Signed checksum manifests of a subtree. A manifest travels with an exported archive (see
+//! `materialize`) and lets the recipient check completeness and integrity using only RSA and
+//! SHA-256, without any WNFS key machinery.
+//!
+//! Manifests are signed with a [`DeviceSigningKey`], like attestations, and never with the
+//! exchange key derived from the wnfs key, which decrypts shares and is held by every device.
+
+use std::path::Path;
+
+use chrono::Utc;
+use rsa::Pkcs1v15Sign;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::{attestation::public_key_from_did, DeviceSigningKey, PrivateDirectoryHelper};
+use crate::error::describe;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    /// Path relative to the manifest root, joined with `/`.
+    pub path: String,
+    pub size: u64,
+    /// Hex encoded SHA-256 of the content.
+    pub sha256: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub root: Vec<String>,
+    /// RFC 3339 creation time.
+    pub created_at: String,
+    pub entries: Vec<ManifestEntry>,
+}
+
+/// A manifest with a PKCS#1 v1.5 signature over the SHA-256 of its JSON encoding.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SignedManifest {
+    pub manifest: Manifest,
+    /// `did:key` of the signing key.
+    pub device_did: String,
+    /// Hex encoded signature.
+    pub signature: String,
+}
+
+/// Differences between a manifest and a local copy of the subtree.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ManifestCheck {
+    pub missing: Vec<String>,
+    pub mismatched: Vec<String>,
+    /// Entries whose path would resolve outside of the checked directory. They aren't read.
+    pub rejected: Vec<String>,
+}
+
+impl ManifestCheck {
+    pub fn is_complete(&self) -> bool {
+        self.missing.is_empty() && self.mismatched.is_empty() && self.rejected.is_empty()
+    }
+}
+
+impl SignedManifest {
+    pub fn to_json(&self) -> Result<String, String> {
+        serde_json::to_string_pretty(self).map_err(|e| describe(&e))
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, String> {
+        serde_json::from_str(json).map_err(|e| describe(&e))
+    }
+
+    /// Checks the signature against the key named by `device_did`. Callers that know the
+    /// signer should also compare `device_did` with the DID they expect.
+    pub fn verify(&self) -> Result<(), String> {
+        let public_key = public_key_from_did(&self.device_did)?;
+        let signature = hex_to_bytes(&self.signature)?;
+        public_key
+            .verify(
+                Pkcs1v15Sign::new::<Sha256>(),
+                &manifest_digest(&self.manifest)?,
+                &signature,
+            )
+            .map_err(|e| format!("wnfsError manifest signature is invalid: {}", e))
+    }
+
+    /// Hashes the files of a local copy of the subtree rooted at `local_dir` and reports every
+    /// entry that is missing or differs. Extra local files are ignored.
+    pub fn check_dir(&self, local_dir: &Path) -> ManifestCheck {
+        let mut check = ManifestCheck::default();
+        for entry in self.manifest.entries.iter() {
+            let segments: Vec<String> = entry.path.split('/').map(String::from).collect();
+            let local_file = match PrivateDirectoryHelper::local_path(local_dir, &segments) {
+                Ok(local_file) => local_file,
+                Err(_) => {
+                    check.rejected.push(entry.path.to_owned());
+                    continue;
+                }
+            };
+            match PrivateDirectoryHelper::hash_local_file(&local_file) {
+                Ok(digest) if PrivateDirectoryHelper::bytes_to_hex_str(&digest) == entry.sha256 => {
+                }
+                Ok(_) => check.mismatched.push(entry.path.to_owned()),
+                Err(_) => check.missing.push(entry.path.to_owned()),
+            }
+        }
+        check
+    }
+}
+
+impl<'a> PrivateDirectoryHelper<'a> {
+    /// Lists every file below `path_segments` with its size and SHA-256, and signs the list
+    /// with `signer`, see the module docs.
+    pub async fn export_manifest(
+        &mut self,
+        path_segments: &[String],
+        signer: &DeviceSigningKey,
+    ) -> Result<SignedManifest, String> {
+        let (_, files) = self.collect_subtree(path_segments).await?;
+        let prefix_len = path_segments.len();
+        let mut entries = Vec::with_capacity(files.len());
+        for file_path in files.iter() {
+            // Same relative naming as `materialize`, so a materialized copy can be checked.
+            let relative = if file_path.len() == prefix_len {
+                &file_path[prefix_len.saturating_sub(1)..]
+            } else {
+                &file_path[prefix_len..]
+            };
+            let (size, digest) = self.hash_forest_file(file_path, None).await?;
+            entries.push(ManifestEntry {
+                path: relative.join("/"),
+                size,
+                sha256: Self::bytes_to_hex_str(&digest),
+            });
+        }
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+        let manifest = Manifest {
+            root: path_segments.to_vec(),
+            created_at: Utc::now().to_rfc3339(),
+            entries,
+        };
+
+        let signature = signer.sign(&manifest_digest(&manifest)?)?;
+        Ok(SignedManifest {
+            device_did: signer.did()?,
+            manifest,
+            signature: Self::bytes_to_hex_str(&signature),
+        })
+    }
+}
+
+fn manifest_digest(manifest: &Manifest) -> Result<Vec<u8>, String> {
+    let encoded = serde_json::to_vec(manifest).map_err(|e| describe(&e))?;
+    Ok(Sha256::digest(encoded).to_vec())
+}
+
+pub(super) fn hex_to_bytes(hex: &str) -> Result<Vec<u8>, String> {
+    if hex.len() % 2 != 0 {
+        return Err("wnfsError odd length hex string".to_string());
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            hex.get(i..i + 2)
+                .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                .ok_or_else(|| format!("wnfsError invalid hex at offset {}", i))
+        })
+        .collect()
+}
@@ -0,0 +1,530 @@
+//! Export of private subtrees to the OS filesystem and the inverse ingest, for interop with apps
+//! that only understand regular files. Every file is hashed on both sides of the copy and the
+//! operation fails if the two digests differ.
+
+use std::{
+    fs::{self, File},
+    io::Write,
+    path::{Component, Path, PathBuf},
+    time::SystemTime,
+};
+
+use chrono::{DateTime, Utc};
+use futures::StreamExt;
+use libipld::Cid;
+use log::trace;
+use wnfs::private::PrivateNode;
+
+use super::{
+    hashing::Hasher, local_file::open_local_file, BatchItem, BatchItemError, HashAlgorithm,
+    PrivateDirectoryHelper,
+};
+use crate::error::{describe, WnfsUtilsError};
+use crate::progress::{OperationObserver, ProgressMeter};
+
+/// Progress of a materialize or ingest run, reported once per completed file.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TransferProgress {
+    pub files_done: usize,
+    pub files_total: usize,
+    pub bytes_done: u64,
+    pub current_path: String,
+}
+
+/// How `ingest` and `materialize` treat files present on both sides.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TreeCopyOptions {
+    /// Skip files whose size and modification time, to the second, match the other side.
+    pub skip_unchanged: bool,
+    /// Import the remaining files when one fails, see `ingest`.
+    pub continue_on_error: bool,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TreeCopyReport {
+    pub copied: usize,
+    pub skipped: usize,
+    /// The forest committed by `ingest`, `None` if nothing changed or for `materialize`.
+    pub forest_cid: Option<Cid>,
+    /// Files left out by `ingest` with `TreeCopyOptions::continue_on_error`.
+    pub failed: Vec<BatchItem>,
+}
+
+impl<'a> PrivateDirectoryHelper<'a> {
+    /// Exports the subtree at `path_segments` into `local_fs_dir`, recreating directories and
+    /// modification times. Each exported file is re-read from disk and compared against the
+    /// digest of the content streamed out of the forest. With `options.skip_unchanged`, local
+    /// files whose size and modification time match the forest's aren't written again.
+    pub async fn materialize(
+        &mut self,
+        path_segments: &[String],
+        local_fs_dir: &String,
+        options: TreeCopyOptions,
+        progress: Option<&mut dyn FnMut(&TransferProgress)>,
+    ) -> Result<TreeCopyReport, String> {
+        let observer = OperationObserver::default();
+        self.materialize_with(path_segments, local_fs_dir, options, progress, &observer)
+            .await
+    }
+
+    pub(super) async fn materialize_with(
+        &mut self,
+        path_segments: &[String],
+        local_fs_dir: &String,
+        options: TreeCopyOptions,
+        mut progress: Option<&mut dyn FnMut(&TransferProgress)>,
+        observer: &OperationObserver,
+    ) -> Result<TreeCopyReport, String> {
+        let mut meter = ProgressMeter::new(self.store.metrics_handle(), "materialize", None);
+        let (dirs, files) = self.collect_subtree(path_segments).await?;
+        let base = PathBuf::from(local_fs_dir);
+        let prefix_len = path_segments.len();
+        for dir in dirs.iter() {
+            fs::create_dir_all(Self::local_path(&base, &dir[prefix_len..])?).map_err(|e| {
+                trace!("wnfsError in materialize on create_dir_all: {:?}", e);
+                describe(&e)
+            })?;
+        }
+
+        let mut report = TreeCopyReport::default();
+        let mut status = TransferProgress {
+            files_total: files.len(),
+            ..Default::default()
+        };
+        for file_path in files.iter() {
+            // A single file as the subtree root is materialized under its own name.
+            let relative = if file_path.len() == prefix_len {
+                &file_path[prefix_len.saturating_sub(1)..]
+            } else {
+                &file_path[prefix_len..]
+            };
+            let local_file = Self::local_path(&base, relative)?;
+            observer.check()?;
+            let unchanged = match options.skip_unchanged && local_file.is_file() {
+                true => {
+                    let (size, modified) = Self::local_size_and_mtime(&local_file)?;
+                    self.forest_file_matches(file_path, size, modified).await?
+                }
+                false => false,
+            };
+            if unchanged {
+                report.skipped += 1;
+            } else {
+                if let Some(parent) = local_file.parent() {
+                    fs::create_dir_all(parent).map_err(|e| describe(&e))?;
+                }
+                let (written, digest) = self.export_file(file_path, &local_file).await?;
+                if Self::hash_local_file(&local_file)? != digest {
+                    trace!(
+                        "wnfsError in materialize: digest mismatch for {:?}",
+                        local_file
+                    );
+                    return Err(WnfsUtilsError::CopyMismatch {
+                        path: local_file.to_string_lossy().into_owned(),
+                    }
+                    .to_string());
+                }
+                report.copied += 1;
+                status.bytes_done += written;
+                meter.advance(observer, written);
+            }
+            status.files_done += 1;
+            status.current_path = file_path.join("/");
+            if let Some(callback) = progress.as_mut() {
+                callback(&status);
+            }
+        }
+        Ok(report)
+    }
+
+    /// Imports the local directory `local_fs_dir` under `path_segments`, mirroring its
+    /// structure and keeping modification times. Every file is verified by reading it back from
+    /// the forest, and the import commits once, only if anything was added. With
+    /// `options.skip_unchanged`, files whose size and modification time match the forest's copy
+    /// aren't imported again. A failed file undoes the whole import, unless
+    /// `options.continue_on_error` is set: then it's left out and listed in
+    /// `TreeCopyReport::failed`.
+    pub async fn ingest(
+        &mut self,
+        local_fs_dir: &String,
+        path_segments: &[String],
+        options: TreeCopyOptions,
+        progress: Option<&mut dyn FnMut(&TransferProgress)>,
+    ) -> Result<TreeCopyReport, String> {
+        let observer = OperationObserver::default();
+        self.ingest_with(local_fs_dir, path_segments, options, progress, &observer)
+            .await
+    }
+
+    pub(super) async fn ingest_with(
+        &mut self,
+        local_fs_dir: &String,
+        path_segments: &[String],
+        options: TreeCopyOptions,
+        mut progress: Option<&mut dyn FnMut(&TransferProgress)>,
+        observer: &OperationObserver,
+    ) -> Result<TreeCopyReport, String> {
+        let (dirs, files) = Self::collect_local_tree(Path::new(local_fs_dir))?;
+        let bytes_total = files
+            .iter()
+            .map(|(_, local_file)| fs::metadata(local_file).map(|metadata| metadata.len()))
+            .sum::<std::io::Result<u64>>()
+            .ok();
+        let mut meter = ProgressMeter::new(self.store.metrics_handle(), "ingest", bytes_total);
+        let mut tx = self.begin();
+        let created = tx.mkdir_local_dirs(path_segments, &dirs).await?;
+
+        let mut report = TreeCopyReport::default();
+        let mut status = TransferProgress {
+            files_total: files.len(),
+            ..Default::default()
+        };
+        for (relative, local_file) in files.iter() {
+            let mut target = path_segments.to_vec();
+            target.extend(relative.iter().cloned());
+            observer.check()?;
+            let mut item = tx.begin();
+            match item.ingest_file(&target, local_file, options).await {
+                Ok((size, true)) => {
+                    item.commit().await?;
+                    report.copied += 1;
+                    status.bytes_done += size;
+                    meter.advance(observer, size);
+                }
+                Ok((size, false)) => {
+                    report.skipped += 1;
+                    meter.advance(observer, size);
+                }
+                Err(e) if options.continue_on_error => {
+                    item.rollback();
+                    trace!("wnfsError in ingest for {:?}: {:?}", target, e.message);
+                    report.failed.push(BatchItem {
+                        path: target,
+                        result: Err(e),
+                    });
+                }
+                Err(e) => return Err(e.message),
+            }
+            status.files_done += 1;
+            status.current_path = local_file.to_string_lossy().into_owned();
+            if let Some(callback) = progress.as_mut() {
+                callback(&status);
+            }
+        }
+        if report.copied > 0 || created > 0 {
+            report.forest_cid = Some(tx.commit().await?);
+        }
+        Ok(report)
+    }
+
+    // Imports a file of `ingest` and verifies it. Returns its size and whether it was imported
+    // rather than skipped as unchanged.
+    async fn ingest_file(
+        &mut self,
+        target: &[String],
+        local_file: &Path,
+        options: TreeCopyOptions,
+    ) -> Result<(u64, bool), BatchItemError> {
+        let (size, modified) =
+            Self::local_size_and_mtime(local_file).map_err(BatchItemError::source)?;
+        if options.skip_unchanged && self.forest_file_matches(target, size, modified).await? {
+            return Ok((size, false));
+        }
+        let local_digest = Self::hash_local_file(local_file).map_err(BatchItemError::source)?;
+        self.import_file(target, local_file).await?;
+        let (_, digest) = self.hash_forest_file(target, None).await?;
+        if digest != local_digest {
+            trace!("wnfsError in ingest: digest mismatch for {:?}", target);
+            return Err(BatchItemError::from(WnfsUtilsError::CopyMismatch {
+                path: target.join("/"),
+            }));
+        }
+        Ok((size, true))
+    }
+
+    fn local_size_and_mtime(local_file: &Path) -> Result<(u64, Option<i64>), String> {
+        let metadata = fs::metadata(local_file).map_err(|e| {
+            trace!("wnfsError in local_size_and_mtime: {:?}", e);
+            describe(&e)
+        })?;
+        let modified = metadata
+            .modified()
+            .ok()
+            .map(|time| DateTime::<Utc>::from(time).timestamp());
+        Ok((metadata.len(), modified))
+    }
+
+    // Whether the forest holds a file at `path_segments` of `size` bytes, modified at the same
+    // second. The exact size is only looked up once the modification times match. Paged files
+    // count as files of their logical length.
+    async fn forest_file_matches(
+        &mut self,
+        path_segments: &[String],
+        size: u64,
+        modified: Option<i64>,
+    ) -> Result<bool, String> {
+        let paged = self.is_paged_file(path_segments).await?;
+        let forest_modified = match self.node_at(path_segments).await? {
+            Some(PrivateNode::File(file)) => file.get_metadata().get_modified(),
+            Some(PrivateNode::Dir(dir)) if paged => dir.get_metadata().get_modified(),
+            _ => return Ok(false),
+        };
+        if modified.is_none() || forest_modified.map(|time| time.timestamp()) != modified {
+            return Ok(false);
+        }
+        if paged {
+            return Ok(self.paged_file_len(path_segments).await? == size);
+        }
+        let mut handle = self.open_file(path_segments).await?;
+        Ok(handle.len(self).await? == size)
+    }
+
+    // Creates the directories of a local tree under `path_segments` without committing,
+    // returning how many didn't exist yet.
+    async fn mkdir_local_dirs(
+        &mut self,
+        path_segments: &[String],
+        dirs: &[Vec<String>],
+    ) -> Result<usize, String> {
+        let mut created = 0;
+        for dir in dirs {
+            let mut target = path_segments.to_vec();
+            target.extend(dir.iter().cloned());
+            if target.is_empty() || self.node_at(&target).await?.is_some() {
+                continue;
+            }
+            created += 1;
+            self.check_not_held(&target, false).await?;
+            let resolved = self.resolve_path(&target).await?;
+            let forest = &mut self.forest;
+            let root_dir = &mut self.root_dir;
+            root_dir
+                .mkdir(
+                    &resolved,
+                    true,
+                    Utc::now(),
+                    forest,
+                    &mut self.store,
+                    &mut self.rng,
+                )
+                .await
+                .map_err(|e| {
+                    trace!("wnfsError in mkdir_local_dirs: {:?}", e.to_string());
+                    describe(&e)
+                })?;
+        }
+        Ok(created)
+    }
+
+    // Joins forest names onto `base`, rejecting any that could resolve outside of it: `.`, `..`,
+    // empty names, names with a path separator of any platform and drive prefixes.
+    pub(super) fn local_path(base: &Path, relative: &[String]) -> Result<PathBuf, String> {
+        let mut path = base.to_path_buf();
+        for segment in relative {
+            let mut components = Path::new(segment).components();
+            let plain = matches!(
+                (components.next(), components.next()),
+                (Some(Component::Normal(_)), None)
+            ) && !segment.contains(['/', '\\']);
+            if !plain {
+                trace!("wnfsError in local_path: unsafe name {:?}", segment);
+                return Err(WnfsUtilsError::InvalidPath {
+                    path: relative.join("/"),
+                    reason: format!("{:?} can't be used as a local file name", segment),
+                }
+                .to_string());
+            }
+            path.push(segment);
+        }
+        Ok(path)
+    }
+
+    // Streams a forest file into `local_file`, returning the byte count and its SHA-256 digest.
+    async fn export_file(
+        &mut self,
+        path_segments: &[String],
+        local_file: &Path,
+    ) -> Result<(u64, Vec<u8>), String> {
+        let mut handle = File::create(local_file).map_err(|e| {
+            trace!("wnfsError in export_file on create: {:?}", e);
+            describe(&e)
+        })?;
+        let (written, digest) = self
+            .hash_forest_file(path_segments, Some(&mut handle))
+            .await?;
+
+        let modified = self
+            .ls_files(&path_segments[..path_segments.len().saturating_sub(1)])
+            .await?
+            .into_iter()
+            .find(|(name, _)| Some(name) == path_segments.last())
+            .and_then(|(_, metadata)| metadata.get_modified());
+        if let Some(modified) = modified {
+            let _ = handle.set_modified(SystemTime::from(modified));
+        }
+        Ok((written, digest))
+    }
+
+    // Streams the content of a forest file through SHA-256, optionally teeing it into `sink`.
+    pub(super) async fn hash_forest_file(
+        &mut self,
+        path_segments: &[String],
+        sink: Option<&mut File>,
+    ) -> Result<(u64, Vec<u8>), String> {
+        self.hash_forest_file_with(path_segments, HashAlgorithm::Sha256, sink)
+            .await
+    }
+
+    // `hash_forest_file` with any of the supported algorithms.
+    pub(super) async fn hash_forest_file_with(
+        &mut self,
+        path_segments: &[String],
+        algo: HashAlgorithm,
+        mut sink: Option<&mut File>,
+    ) -> Result<(u64, Vec<u8>), String> {
+        if self.is_paged_file(path_segments).await? {
+            return self.hash_paged_file(path_segments, algo, sink).await;
+        }
+        let node = self
+            .node_at(path_segments)
+            .await?
+            .ok_or_else(|| format!("wnfsError no file found at {}", path_segments.join("/")))?;
+        let file = node.as_file().map_err(|e| describe(&e))?;
+
+        let forest = &mut self.forest;
+        let mut hasher = Hasher::new(algo);
+        let mut total: u64 = 0;
+        let mut stream = file.stream_content(0, forest, &mut self.store);
+        while let Some(block) = stream.next().await {
+            let block = block.map_err(|e| {
+                trace!("wnfsError in hash_forest_file: {:?}", e.to_string());
+                describe(&e)
+            })?;
+            hasher.update(&block);
+            total += block.len() as u64;
+            if let Some(sink) = sink.as_mut() {
+                sink.write_all(&block).map_err(|e| describe(&e))?;
+            }
+        }
+        Ok((total, hasher.finalize()))
+    }
+
+    // `hash_forest_file_with` for a paged file, read a chunk at a time.
+    async fn hash_paged_file(
+        &mut self,
+        path_segments: &[String],
+        algo: HashAlgorithm,
+        mut sink: Option<&mut File>,
+    ) -> Result<(u64, Vec<u8>), String> {
+        let options = self.paged_file_options(path_segments).await?;
+        let len = self.paged_file_len(path_segments).await?;
+        let chunk_size = options.page_size as usize * options.pages_per_chunk as usize;
+        let mut hasher = Hasher::new(algo);
+        let mut total: u64 = 0;
+        while total < len {
+            let chunk = self.read_at(path_segments, total, chunk_size).await?;
+            hasher.update(&chunk);
+            total += chunk.len() as u64;
+            if let Some(sink) = sink.as_mut() {
+                sink.write_all(&chunk).map_err(|e| describe(&e))?;
+            }
+        }
+        Ok((total, hasher.finalize()))
+    }
+
+    // Streams a local file into the forest without committing, returning its size.
+    pub(super) async fn import_file(
+        &mut self,
+        path_segments: &[String],
+        local_file: &Path,
+    ) -> Result<u64, String> {
+        self.check_not_held(path_segments, false).await?;
+        let (mut reader, modified) = open_local_file(local_file)
+            .await
+            .map_err(|e| describe(&e))?;
+        let modification_time: DateTime<Utc> =
+            modified.map(DateTime::<Utc>::from).unwrap_or_else(Utc::now);
+
+        let (size, entries) = match self.config.adaptive_chunking {
+            Some(policy) => {
+                self.write_adaptive_scanned(path_segments, &policy, &mut reader)
+                    .await?
+            }
+            None => {
+                let resolved = self.resolve_path(path_segments).await?;
+                self.set_content_scanned(path_segments, &resolved, &mut reader, modification_time)
+                    .await?
+            }
+        };
+        if !entries.is_empty() {
+            self.put_file_metadata(path_segments, entries, modification_time)
+                .await?;
+        }
+        self.split_parent_if_needed(path_segments).await?;
+        Ok(size)
+    }
+
+    pub(super) fn hash_local_file(path: &Path) -> Result<Vec<u8>, String> {
+        HashAlgorithm::Sha256.hash_local_file(path)
+    }
+
+    // Lists a local directory tree as relative directory paths (parents first) and
+    // (relative path, absolute path) pairs for files.
+    #[allow(clippy::type_complexity)]
+    fn collect_local_tree(
+        root: &Path,
+    ) -> Result<(Vec<Vec<String>>, Vec<(Vec<String>, PathBuf)>), String> {
+        let mut dirs: Vec<Vec<String>> = vec![Vec::new()];
+        let mut files: Vec<(Vec<String>, PathBuf)> = Vec::new();
+        let mut pending: Vec<(Vec<String>, PathBuf)> = vec![(Vec::new(), root.to_path_buf())];
+        while let Some((relative, dir)) = pending.pop() {
+            let entries = fs::read_dir(&dir).map_err(|e| {
+                trace!("wnfsError in collect_local_tree: {:?}", e);
+                describe(&e)
+            })?;
+            for entry in entries {
+                let entry = entry.map_err(|e| describe(&e))?;
+                let mut child = relative.to_owned();
+                child.push(entry.file_name().to_string_lossy().into_owned());
+                let file_type = entry.file_type().map_err(|e| describe(&e))?;
+                if file_type.is_dir() {
+                    dirs.push(child.to_owned());
+                    pending.push((child, entry.path()));
+                } else if file_type.is_file() {
+                    files.push((child, entry.path()));
+                }
+            }
+        }
+        Ok((dirs, files))
+    }
+}
+
+impl<'a> PrivateDirectoryHelper<'a> {
+    pub fn synced_materialize(
+        &mut self,
+        path_segments: &[String],
+        local_fs_dir: &String,
+        options: TreeCopyOptions,
+        progress: Option<&mut dyn FnMut(&TransferProgress)>,
+    ) -> Result<TreeCopyReport, String> {
+        Self::run_limited(
+            &self.operation_limiter(),
+            "materialize",
+            self.materialize(path_segments, local_fs_dir, options, progress),
+        )
+    }
+
+    pub fn synced_ingest(
+        &mut self,
+        local_fs_dir: &String,
+        path_segments: &[String],
+        options: TreeCopyOptions,
+        progress: Option<&mut dyn FnMut(&TransferProgress)>,
+    ) -> Result<TreeCopyReport, String> {
+        Self::run_limited(
+            &self.operation_limiter(),
+            "ingest",
+            self.ingest(local_fs_dir, path_segments, options, progress),
+        )
+    }
+}
@@ -0,0 +1,159 @@
+//! Batch ingestion of photos and other media into a date based hierarchy
+//! (`<base>/<YYYY>/<MM>/<DD>/<name>`), deduplicated by content hash.
+
+use std::{collections::HashSet, path::Path, time::SystemTime};
+
+use chrono::{DateTime, Datelike, Utc};
+use libipld::{Cid, Ipld};
+use log::trace;
+
+use super::PrivateDirectoryHelper;
+use crate::media_metadata::MediaMetadata;
+
+/// Metadata key holding the hex encoded SHA-256 of a file's content.
+pub const CONTENT_HASH_KEY: &str = "sha256";
+
+#[derive(Debug, Clone)]
+pub struct MediaIngestOptions {
+    /// Number of files written per commit. Larger values mean fewer forest revisions.
+    pub batch_size: usize,
+    /// Skip files whose content hash already exists under the base path.
+    pub dedupe: bool,
+}
+
+impl Default for MediaIngestOptions {
+    fn default() -> Self {
+        Self {
+            batch_size: 500,
+            dedupe: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct MediaIngestReport {
+    /// Forest paths of the files written, in input order.
+    pub written: Vec<Vec<String>>,
+    /// Local files skipped because their content was already present.
+    pub duplicates: Vec<String>,
+    /// Number of commits performed.
+    pub commits: usize,
+    /// Forest CID after the last commit, `None` if nothing was written.
+    pub forest_cid: Option<Cid>,
+}
+
+impl<'a> PrivateDirectoryHelper<'a> {
+    /// Ingests a batch of local media files under `base_path`, placing each one in a
+    /// `YYYY/MM/DD` directory derived from its EXIF capture time (falling back to the file's
+    /// modification time). The content hash and any parsed media metadata are recorded in the
+    /// file metadata; GPS coordinates only when the helper config enables them.
+    pub async fn ingest_media(
+        &mut self,
+        local_files: &[String],
+        base_path: &[String],
+        options: MediaIngestOptions,
+    ) -> Result<MediaIngestReport, String> {
+        let mut known_hashes = if options.dedupe {
+            self.content_hashes_under(base_path).await?
+        } else {
+            HashSet::new()
+        };
+        let metadata_options = self.config.media_metadata.to_owned().unwrap_or_default();
+        let batch_size = options.batch_size.max(1);
+        let mut report = MediaIngestReport::default();
+        let mut uncommitted = 0;
+
+        for local_file in local_files {
+            let local_path = Path::new(local_file);
+            let hash = Self::bytes_to_hex_str(&Self::hash_local_file(local_path)?);
+            if options.dedupe && known_hashes.contains(&hash) {
+                trace!("ingest_media: skipping duplicate {:?}", local_file);
+                report.duplicates.push(local_file.to_owned());
+                continue;
+            }
+
+            let media = MediaMetadata::from_path(local_path, &metadata_options);
+            let modified = Self::local_modified_time(local_path);
+            let date = media
+                .capture_time
+                .map(|time| time.date())
+                .unwrap_or_else(|| modified.date_naive());
+            let name = local_path
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .ok_or_else(|| format!("wnfsError invalid media file name {}", local_file))?;
+
+            let mut dir = base_path.to_vec();
+            dir.push(format!("{:04}", date.year()));
+            dir.push(format!("{:02}", date.month()));
+            dir.push(format!("{:02}", date.day()));
+            let target = self.free_file_path(&dir, &name).await?;
+
+            self.import_file(&target, local_path).await?;
+            let mut entries = vec![(CONTENT_HASH_KEY.to_string(), Ipld::String(hash.to_owned()))];
+            entries.extend(media.to_entries());
+            self.put_file_metadata(&target, entries, modified).await?;
+
+            known_hashes.insert(hash);
+            report.written.push(target);
+            uncommitted += 1;
+            if uncommitted >= batch_size {
+                report.forest_cid = Some(self.commit().await?);
+                report.commits += 1;
+                uncommitted = 0;
+            }
+        }
+
+        if uncommitted > 0 {
+            report.forest_cid = Some(self.commit().await?);
+            report.commits += 1;
+        }
+        Ok(report)
+    }
+
+    // Collects the recorded content hashes of every file below `base_path`.
+    async fn content_hashes_under(
+        &mut self,
+        base_path: &[String],
+    ) -> Result<HashSet<String>, String> {
+        let mut hashes = HashSet::new();
+        if self.node_at(base_path).await?.is_none() {
+            return Ok(hashes);
+        }
+        let (dirs, _) = self.collect_subtree(base_path).await?;
+        for dir in dirs {
+            for (_, metadata) in self.ls_files(&dir).await? {
+                if let Some(Ipld::String(hash)) = metadata.get(CONTENT_HASH_KEY) {
+                    hashes.insert(hash.to_owned());
+                }
+            }
+        }
+        Ok(hashes)
+    }
+
+    // Returns `dir/name`, or `dir/name (n).ext` if that path is already taken.
+    async fn free_file_path(&mut self, dir: &[String], name: &str) -> Result<Vec<String>, String> {
+        let (stem, extension) = match name.rfind('.') {
+            Some(index) if index > 0 => (&name[..index], &name[index..]),
+            _ => (name, ""),
+        };
+        let mut candidate = name.to_string();
+        let mut counter = 1;
+        loop {
+            let mut path = dir.to_vec();
+            path.push(candidate.to_owned());
+            if self.node_at(&path).await?.is_none() {
+                return Ok(path);
+            }
+            candidate = format!("{} ({}){}", stem, counter, extension);
+            counter += 1;
+        }
+    }
+
+    fn local_modified_time(path: &Path) -> DateTime<Utc> {
+        let modified = std::fs::metadata(path)
+            .and_then(|metadata| metadata.modified())
+            .unwrap_or_else(|_| SystemTime::now());
+        DateTime::<Utc>::from(modified)
+    }
+}
@@ -0,0 +1,309 @@
+//! Reconciling roots two devices committed concurrently to the same forest.
+//!
+//! `diff_forests` compares two roots path by path. `merge_forests` joins them into one: the
+//! HAMTs are merged with wnfs' forest merge, which keeps the blocks of both sides and turns names
+//! both wrote into multivalues, and the tree of the first root takes in the changes of the
+//! second. Entries are compared by content: the same stored revision, or files of the same size
+//! and SHA-256 digest.
+//!
+//! Given the root both sides were committed on, the merge is three-way: an entry only the second
+//! side changed, added or deleted since that base is taken from it, and one only the first side
+//! changed is kept. An entry both changed is taken from the side that modified it last, ties
+//! going to the second, and a change wins over a deletion. Without a base an entry deleted on one
+//! side can't be told from one created on the other, so nothing is removed and every difference
+//! counts as changed on both sides.
+
+use std::{collections::BTreeMap, rc::Rc};
+
+use futures::StreamExt;
+use libipld::Cid;
+use log::trace;
+use wnfs::private::PrivateNode;
+
+use super::{PrivateDirectoryHelper, WalkOptions};
+use crate::error::describe;
+use crate::vfs::{VfsNodeKind, VfsStat};
+
+/// Paths that differ between two roots, parents before their children.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ForestDiff {
+    /// Entries of the second root missing from the first.
+    pub added: Vec<Vec<String>>,
+    /// Entries of the first root missing from the second.
+    pub removed: Vec<Vec<String>>,
+    /// Files whose content differs, and entries that changed kind.
+    pub modified: Vec<Vec<String>>,
+}
+
+impl ForestDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.modified.is_empty()
+    }
+}
+
+// An entry of a tree, with the node stored for it.
+struct Entry {
+    stat: VfsStat,
+    node: PrivateNode,
+}
+
+impl<'a> PrivateDirectoryHelper<'a> {
+    /// Lists how the tree at `cid_b` differs from the one at `cid_a`, both roots of this
+    /// helper's forest.
+    pub async fn diff_forests(&self, cid_a: Cid, cid_b: Cid) -> Result<ForestDiff, String> {
+        let exchange_keypair = Self::exchange_keypair(&self.wnfs_key)?;
+        let mut a = self.open_with_keypair(cid_a, &exchange_keypair).await?;
+        let mut b = self.open_with_keypair(cid_b, &exchange_keypair).await?;
+        let ours = a.helper.entries().await?;
+        let theirs = b.helper.entries().await?;
+        let mut diff = ForestDiff::default();
+        for (path, entry) in theirs.iter() {
+            match ours.get(path) {
+                None => diff.added.push(path.to_owned()),
+                Some(previous) => {
+                    if !Self::same_entry(&mut a.helper, previous, &mut b.helper, entry, path)
+                        .await?
+                    {
+                        diff.modified.push(path.to_owned())
+                    }
+                }
+            }
+        }
+        diff.removed = ours
+            .keys()
+            .filter(|path| !theirs.contains_key(*path))
+            .cloned()
+            .collect();
+        Ok(diff)
+    }
+
+    /// Merges the roots `cid_a` and `cid_b`, see the module docs, in a single commit and moves
+    /// this helper to the merged root, which it returns. `base` is the root both were committed
+    /// on, e.g. the root last published before they diverged. Batched commits still pending are
+    /// committed first.
+    pub async fn merge_forests(
+        &mut self,
+        base: Option<Cid>,
+        cid_a: Cid,
+        cid_b: Cid,
+    ) -> Result<Cid, String> {
+        let exchange_keypair = Self::exchange_keypair(&self.wnfs_key)?;
+        let mut b = self.open_with_keypair(cid_b, &exchange_keypair).await?;
+        let mut base = match base {
+            Some(base) => Some(self.open_with_keypair(base, &exchange_keypair).await?),
+            None => None,
+        };
+        self.reload_in_place(cid_a).await?;
+        let merged = self
+            .forest
+            .merge(&b.helper.forest, &mut self.store)
+            .await
+            .map_err(|e| {
+                trace!("wnfsError in merge_forests: {:?}", e.to_string());
+                describe(&e)
+            })?;
+        self.forest = Rc::new(merged);
+
+        let ours = self.entries().await?;
+        let theirs = b.helper.entries().await?;
+        let ancestor = match base.as_mut() {
+            Some(view) => Some(view.helper.entries().await?),
+            None => None,
+        };
+
+        // Whether each side changed the entry at a path since the base, `true` without one.
+        let mut ours_changed = BTreeMap::new();
+        let mut theirs_changed = BTreeMap::new();
+        if let (Some(view), Some(ancestor)) = (base.as_mut(), ancestor.as_ref()) {
+            for (path, entry) in ours.iter() {
+                let changed = match ancestor.get(path) {
+                    Some(original) => {
+                        !Self::same_entry(self, entry, &mut view.helper, original, path).await?
+                    }
+                    None => true,
+                };
+                ours_changed.insert(path.to_owned(), changed);
+            }
+            for (path, entry) in theirs.iter() {
+                let changed = match ancestor.get(path) {
+                    Some(original) => {
+                        !Self::same_entry(&mut b.helper, entry, &mut view.helper, original, path)
+                            .await?
+                    }
+                    None => true,
+                };
+                theirs_changed.insert(path.to_owned(), changed);
+            }
+        }
+
+        let mut taken = Vec::new();
+        for (path, entry) in theirs.iter() {
+            let previous = ours.get(path);
+            if let Some(previous) = previous {
+                if Self::same_entry(self, previous, &mut b.helper, entry, path).await? {
+                    continue;
+                }
+            }
+            if !theirs_changed.get(path).copied().unwrap_or(true) {
+                // Only the first side changed or deleted it.
+                continue;
+            }
+            let both_changed = match (&ancestor, previous) {
+                (None, _) => true,
+                (Some(_), Some(_)) => ours_changed.get(path).copied().unwrap_or(true),
+                // Deleted by the first side, or never there.
+                (Some(ancestor), None) => ancestor.contains_key(path),
+            };
+            let take = match (entry.stat.kind, previous) {
+                (_, None) => true,
+                (VfsNodeKind::File, Some(previous)) if previous.stat.kind == VfsNodeKind::File => {
+                    !both_changed || entry.stat.modified >= previous.stat.modified
+                }
+                // Kept as the first root has it: a directory on one side only.
+                _ => false,
+            };
+            if take {
+                taken.push(path.to_owned());
+            }
+        }
+
+        let mut removed: Vec<Vec<String>> = Vec::new();
+        if let Some(ancestor) = ancestor.as_ref() {
+            for path in ours.keys() {
+                if theirs.contains_key(path) || !ancestor.contains_key(path) {
+                    continue;
+                }
+                if removed.iter().any(|parent| path.starts_with(parent)) {
+                    continue;
+                }
+                // Deleted by the second side: only removed when the first side changed nothing
+                // at or below it.
+                let kept = ours_changed
+                    .iter()
+                    .any(|(changed, is_changed)| *is_changed && changed.starts_with(path));
+                if !kept {
+                    removed.push(path.to_owned());
+                }
+            }
+        }
+
+        let mutations = self.mutations;
+        let mut tx = self.begin();
+        for path in taken.iter() {
+            let entry = &theirs[path];
+            match (entry.stat.kind, ours.get(path)) {
+                (VfsNodeKind::Directory, None) => {
+                    tx.mkdir(path).await?;
+                }
+                (VfsNodeKind::Directory, Some(_)) => {}
+                (VfsNodeKind::File, _) => {
+                    b.helper
+                        .copy_entry_into(path, entry.stat.modified, &mut tx)
+                        .await?
+                }
+            }
+        }
+        for path in removed.iter() {
+            tx.rm(path).await?;
+        }
+        let merged_root = match tx.mutations != mutations {
+            true => tx.commit().await?,
+            false => {
+                drop(tx);
+                // The merged forest holds names of the second root even when the tree didn't
+                // change.
+                self.commit_now().await?
+            }
+        };
+        self.resolve_remote_root(&[cid_a, cid_b]);
+        Ok(merged_root)
+    }
+
+    // Kind, size, modification time and node of every entry, bookkeeping entries left out.
+    async fn entries(&mut self) -> Result<BTreeMap<Vec<String>, Entry>, String> {
+        let walked: Vec<_> = self
+            .walk(&[], WalkOptions::default())
+            .collect::<Vec<_>>()
+            .await
+            .into_iter()
+            .collect::<Result<_, String>>()?;
+        let mut entries = BTreeMap::new();
+        for entry in walked.into_iter().filter(|entry| !entry.path.is_empty()) {
+            if let Some(node) = self.node_at(&entry.path).await? {
+                let stat = entry.stat;
+                entries.insert(entry.path, Entry { stat, node });
+            }
+        }
+        Ok(entries)
+    }
+
+    // Whether `ours` in the tree of `a` and `theirs` in the tree of `b`, both at `path`, hold
+    // the same: the same stored revision, or files of equal size and digest, e.g. one copied
+    // from the other. A directory's content is its children, which are compared on their own.
+    async fn same_entry(
+        a: &mut PrivateDirectoryHelper<'_>,
+        ours: &Entry,
+        b: &mut PrivateDirectoryHelper<'_>,
+        theirs: &Entry,
+        path: &[String],
+    ) -> Result<bool, String> {
+        if ours.stat.kind != theirs.stat.kind {
+            return Ok(false);
+        }
+        if ours.stat.kind == VfsNodeKind::Directory || ours.node == theirs.node {
+            return Ok(true);
+        }
+        if ours.stat.size != theirs.stat.size {
+            return Ok(false);
+        }
+        match (&ours.node, &theirs.node) {
+            (PrivateNode::File(_), PrivateNode::File(_)) => {
+                let (_, ours_digest) = a.hash_forest_file(path, None).await?;
+                let (_, theirs_digest) = b.hash_forest_file(path, None).await?;
+                Ok(ours_digest == theirs_digest)
+            }
+            // Paged files are only the same as the same revision.
+            _ => Ok(false),
+        }
+    }
+
+    // Writes the file at `path_segments` into the same path of `dst`, without committing it.
+    // Paged files are rewritten whole, with `modified` as reported by `walk`.
+    async fn copy_entry_into(
+        &mut self,
+        path_segments: &[String],
+        modified: Option<i64>,
+        dst: &mut PrivateDirectoryHelper<'_>,
+    ) -> Result<(), String> {
+        if !self.is_paged_file(path_segments).await? {
+            return self.copy_file_into(path_segments, dst, path_segments).await;
+        }
+        let content = self.read_file_at(path_segments, 0, usize::MAX).await?;
+        dst.write_file(path_segments, content, modified.unwrap_or_default())
+            .await?;
+        Ok(())
+    }
+}
+
+impl<'a> PrivateDirectoryHelper<'a> {
+    pub fn synced_diff_forests(&self, cid_a: Cid, cid_b: Cid) -> Result<ForestDiff, String> {
+        Self::run_limited(
+            &self.operation_limiter(),
+            "diff_forests",
+            self.diff_forests(cid_a, cid_b),
+        )
+    }
+
+    pub fn synced_merge_forests(
+        &mut self,
+        base: Option<Cid>,
+        cid_a: Cid,
+        cid_b: Cid,
+    ) -> Result<Cid, String> {
+        Self::run_limited(
+            &self.operation_limiter(),
+            "merge_forests",
+            self.merge_forests(base, cid_a, cid_b),
+        )
+    }
+}
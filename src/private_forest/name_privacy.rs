@@ -0,0 +1,176 @@
+//! Per-subtree control over whether directory entry names exist at rest only inside encrypted
+//! WNFS nodes, or are additionally published as a plaintext index for fast, keyless listing.
+
+use std::{collections::BTreeMap, rc::Rc};
+
+use libipld::Cid;
+use log::trace;
+use serde::{Deserialize, Serialize};
+use wnfs::common::BlockStore;
+
+use super::PrivateDirectoryHelper;
+use crate::blockstore::FFIFriendlyBlockStore;
+use crate::error::describe;
+
+/// How the entry names below a subtree are exposed at rest.
+///
+/// WNFS keeps entry names inside encrypted private directory nodes, so listing a directory means
+/// fetching and decrypting every node on its path. `Indexed` trades the privacy of the names for
+/// listing speed; file content stays encrypted either way.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum NamePrivacy {
+    /// Names are only stored inside encrypted directory nodes. Listing needs the key.
+    #[default]
+    Private,
+    /// After every commit changing the subtree, a plaintext [`NameIndex`] of it is written to
+    /// the store.
+    /// Listing from it needs neither the key nor any decryption, but anyone holding the index
+    /// CID and store access can read every entry name in the subtree.
+    Indexed,
+}
+
+/// Plaintext listing of a subtree, published for subtrees configured as `NamePrivacy::Indexed`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NameIndex {
+    /// Path of the indexed subtree.
+    pub root: Vec<String>,
+    /// Entry names per directory, keyed by the directory path relative to `root` joined with
+    /// `/`. The subtree root itself is keyed by the empty string.
+    pub directories: BTreeMap<String, Vec<String>>,
+}
+
+impl NameIndex {
+    /// Loads an index from the store. Requires no WNFS key.
+    pub async fn load(store: &FFIFriendlyBlockStore<'_>, cid: &Cid) -> Result<Self, String> {
+        store
+            .get_deserializable::<NameIndex>(cid)
+            .await
+            .map_err(|e| describe(&e))
+    }
+
+    /// Entry names of the directory at `relative_path` below the index root.
+    pub fn entries(&self, relative_path: &[String]) -> Option<&Vec<String>> {
+        self.directories.get(&relative_path.join("/"))
+    }
+}
+
+impl<'a> PrivateDirectoryHelper<'a> {
+    /// The name privacy mode that applies to `path_segments`.
+    pub fn name_privacy_for(&self, path_segments: &[String]) -> NamePrivacy {
+        self.config
+            .name_privacy
+            .iter()
+            .filter(|(prefix, _)| path_segments.starts_with(prefix))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, mode)| *mode)
+            .unwrap_or_default()
+    }
+
+    /// CID of the latest name index published for the indexed subtree at `path_segments`, to be
+    /// handed to keyless readers alongside the forest CID.
+    pub fn name_index(&self, path_segments: &[String]) -> Option<Cid> {
+        self.name_indexes.get(path_segments).copied()
+    }
+
+    /// Lists entry names at `path_segments`. Inside an indexed subtree this is served from the
+    /// published index without decrypting directory nodes; elsewhere it falls back to `ls_files`.
+    pub async fn ls_names(&mut self, path_segments: &[String]) -> Result<Vec<String>, String> {
+        // Mutations not committed yet aren't in any index.
+        let uncommitted = self.in_transaction || self.pending_commit.is_some();
+        if !uncommitted && self.name_privacy_for(path_segments) == NamePrivacy::Indexed {
+            let published = self
+                .name_indexes
+                .iter()
+                .filter(|(root, _)| path_segments.starts_with(root))
+                .max_by_key(|(root, _)| root.len())
+                .map(|(root, cid)| (root.to_owned(), *cid));
+            if let Some((root, cid)) = published {
+                let revision = self.subtree_revision(&root).await?;
+                if revision.is_some() && revision.as_ref() == self.name_index_revisions.get(&root) {
+                    let index = NameIndex::load(&self.store, &cid).await?;
+                    if let Some(entries) = index.entries(&path_segments[root.len()..]) {
+                        return Ok(entries.to_owned());
+                    }
+                }
+            }
+        }
+        Ok(self
+            .ls_files(path_segments)
+            .await?
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect())
+    }
+
+    // Rebuilds and stores the name index of every indexed subtree changed since its index was
+    // built. A change anywhere below a directory gives it a new content CID, so comparing the
+    // subtree root's is enough. Called from `commit`.
+    pub(super) async fn refresh_name_indexes(&mut self) -> Result<(), String> {
+        let roots: Vec<Vec<String>> = self
+            .config
+            .name_privacy
+            .iter()
+            .filter(|(_, mode)| *mode == NamePrivacy::Indexed)
+            .map(|(root, _)| root.to_owned())
+            .collect();
+        for root in roots {
+            let revision = match self.subtree_revision(&root).await? {
+                Some(revision) => revision,
+                None => {
+                    self.name_indexes.remove(&root);
+                    self.name_index_revisions.remove(&root);
+                    continue;
+                }
+            };
+            if self.name_indexes.contains_key(&root)
+                && self.name_index_revisions.get(&root) == Some(&revision)
+            {
+                continue;
+            }
+            let (dirs, _) = self.collect_subtree(&root).await?;
+            let mut index = NameIndex {
+                root: root.to_owned(),
+                ..Default::default()
+            };
+            for dir in dirs {
+                // A nested `Private` rule keeps that part of the subtree out of the index.
+                if self.name_privacy_for(&dir) != NamePrivacy::Indexed {
+                    continue;
+                }
+                let names = self
+                    .ls_files(&dir)
+                    .await?
+                    .into_iter()
+                    .map(|(name, _)| name)
+                    .filter(|name| !Self::is_reserved_name(name))
+                    .collect();
+                index.directories.insert(dir[root.len()..].join("/"), names);
+            }
+            let cid = self.store.put_serializable(&index).await.map_err(|e| {
+                trace!("wnfsError in refresh_name_indexes: {:?}", e.to_string());
+                describe(&e)
+            })?;
+            self.name_indexes.insert(root.to_owned(), cid);
+            self.name_index_revisions.insert(root, revision);
+        }
+        Ok(())
+    }
+
+    // Content CID of the node at `path_segments`, `None` if there is none. Committed nodes keep
+    // the CID they were stored as, so this writes nothing new for them.
+    async fn subtree_revision(&mut self, path_segments: &[String]) -> Result<Option<Cid>, String> {
+        let node = match self.node_at(path_segments).await? {
+            Some(node) => node,
+            None => return Ok(None),
+        };
+        let mut forest = Rc::clone(&self.forest);
+        let access_key = node
+            .store(&mut forest, &mut self.store, &mut self.rng)
+            .await
+            .map_err(|e| {
+                trace!("wnfsError in subtree_revision: {:?}", e.to_string());
+                describe(&e)
+            })?;
+        Ok(Some(*access_key.get_content_cid()))
+    }
+}
@@ -0,0 +1,235 @@
+//! Unicode normalization and length limits of entry names.
+//!
+//! macOS hands out file names in NFD while Android and most Linux apps use NFC, so the same name
+//! typed on two devices can end up as two entries, one of which the other device can't open.
+//! With `HelperConfig::path_normalization` set, every path passed to the helper is normalized
+//! before it's looked up or written. Entries written before it was set keep their stored form:
+//! `find_normalization_issues` lists them, and `normalize_names` renames those that don't clash
+//! with another entry of the same directory.
+
+use std::collections::BTreeMap;
+
+use chrono::Utc;
+use log::trace;
+use serde::{Deserialize, Serialize};
+use unicode_normalization::{is_nfc, is_nfd, UnicodeNormalization};
+
+use super::PrivateDirectoryHelper;
+use crate::error::{describe, WnfsUtilsError};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PathNormalization {
+    /// Composed form, as written by Android, Windows and most Linux apps.
+    Nfc,
+    /// Decomposed form, as handed out by macOS.
+    Nfd,
+}
+
+impl PathNormalization {
+    pub fn normalize(&self, name: &str) -> String {
+        match self {
+            PathNormalization::Nfc => name.nfc().collect(),
+            PathNormalization::Nfd => name.nfd().collect(),
+        }
+    }
+
+    pub fn is_normalized(&self, name: &str) -> bool {
+        match self {
+            PathNormalization::Nfc => is_nfc(name),
+            PathNormalization::Nfd => is_nfd(name),
+        }
+    }
+}
+
+/// Entries of one directory whose names differ only by normalization.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NormalizationConflict {
+    pub directory: Vec<String>,
+    pub names: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NormalizationReport {
+    /// Entries stored in another form than the configured one, which lookups no longer reach.
+    pub unnormalized: Vec<Vec<String>>,
+    /// Names clashing once normalized. `normalize_names` leaves them for the app to merge or
+    /// rename.
+    pub conflicts: Vec<NormalizationConflict>,
+}
+
+impl<'a> PrivateDirectoryHelper<'a> {
+    // `path_segments` in the configured normalization form.
+    pub(super) fn normalize_path(&self, path_segments: &[String]) -> Vec<String> {
+        match &self.config.path_normalization {
+            Some(normalization) => path_segments
+                .iter()
+                .map(|segment| normalization.normalize(segment))
+                .collect(),
+            None => path_segments.to_vec(),
+        }
+    }
+
+    // Checks the lengths of the path as it will be stored against the configured limits.
+    pub(super) fn check_path_length(&self, path_segments: &[String]) -> Result<(), String> {
+        let path_segments = self.normalize_path(path_segments);
+        if let Some(limit) = self.config.max_name_bytes {
+            if let Some(name) = path_segments.iter().find(|name| name.len() > limit) {
+                trace!("wnfsError in check_path_length: {:?} is too long", name);
+                return Err(WnfsUtilsError::NameTooLong {
+                    name: name.to_owned(),
+                    length: name.len(),
+                    limit,
+                }
+                .to_string());
+            }
+        }
+        if let Some(limit) = self.config.max_path_bytes {
+            let length = path_segments.join("/").len();
+            if length > limit {
+                trace!(
+                    "wnfsError in check_path_length: {:?} is too long",
+                    path_segments
+                );
+                return Err(WnfsUtilsError::PathTooLong { length, limit }.to_string());
+            }
+        }
+        Ok(())
+    }
+
+    /// Entries below `path_segments` not stored in the configured form, NFC if none is set,
+    /// and the names clashing once normalized.
+    pub async fn find_normalization_issues(
+        &mut self,
+        path_segments: &[String],
+    ) -> Result<NormalizationReport, String> {
+        let path_segments = self.normalize_path(path_segments);
+        // Stored names are walked as they are, without normalizing them on lookup.
+        let normalization = self.config.path_normalization.take();
+        let report = self
+            .scan_normalization(
+                &path_segments,
+                normalization.unwrap_or(PathNormalization::Nfc),
+            )
+            .await;
+        self.config.path_normalization = normalization;
+        report
+    }
+
+    /// Renames the entries below `path_segments` to the configured form, NFC if none is set,
+    /// in a single commit. Conflicting names are left alone and returned.
+    pub async fn normalize_names(
+        &mut self,
+        path_segments: &[String],
+    ) -> Result<Vec<NormalizationConflict>, String> {
+        let path_segments = self.normalize_path(path_segments);
+        let normalization = self.config.path_normalization.take();
+        let renamed = self
+            .rename_unnormalized(
+                &path_segments,
+                normalization.unwrap_or(PathNormalization::Nfc),
+            )
+            .await;
+        self.config.path_normalization = normalization;
+        renamed
+    }
+
+    async fn scan_normalization(
+        &mut self,
+        path_segments: &[String],
+        normalization: PathNormalization,
+    ) -> Result<NormalizationReport, String> {
+        let (dirs, _) = self.collect_subtree(path_segments).await?;
+        let mut report = NormalizationReport::default();
+        for dir in dirs {
+            let mut groups: BTreeMap<String, Vec<String>> = BTreeMap::new();
+            for (name, _) in self.ls_files(&dir).await? {
+                groups
+                    .entry(normalization.normalize(&name))
+                    .or_default()
+                    .push(name);
+            }
+            for (_, names) in groups {
+                if names.len() > 1 {
+                    report.conflicts.push(NormalizationConflict {
+                        directory: dir.to_owned(),
+                        names,
+                    });
+                } else if let Some(name) =
+                    names.into_iter().find(|n| !normalization.is_normalized(n))
+                {
+                    let mut entry = dir.to_owned();
+                    entry.push(name);
+                    report.unnormalized.push(entry);
+                }
+            }
+        }
+        Ok(report)
+    }
+
+    async fn rename_unnormalized(
+        &mut self,
+        path_segments: &[String],
+        normalization: PathNormalization,
+    ) -> Result<Vec<NormalizationConflict>, String> {
+        let mut report = self
+            .scan_normalization(path_segments, normalization)
+            .await?;
+        if report.unnormalized.is_empty() {
+            return Ok(report.conflicts);
+        }
+        // Deepest entries first, so the stored path of their parents still holds.
+        report
+            .unnormalized
+            .sort_by_key(|entry| std::cmp::Reverse(entry.len()));
+        for entry in &report.unnormalized {
+            self.check_not_held(entry, true).await?;
+            let mut target = entry.to_owned();
+            if let Some(name) = target.last_mut() {
+                *name = normalization.normalize(name);
+            }
+            let source = self.resolve_path(entry).await?;
+            let target = self.resolve_path(&target).await?;
+            self.root_dir
+                .basic_mv(
+                    &source,
+                    &target,
+                    true,
+                    Utc::now(),
+                    &mut self.forest,
+                    &mut self.store,
+                    &mut self.rng,
+                )
+                .await
+                .map_err(|e| {
+                    trace!("wnfsError in normalize_names: {:?}", e.to_string());
+                    describe(&e)
+                })?;
+        }
+        self.commit().await?;
+        Ok(report.conflicts)
+    }
+}
+
+impl<'a> PrivateDirectoryHelper<'a> {
+    pub fn synced_find_normalization_issues(
+        &mut self,
+        path_segments: &[String],
+    ) -> Result<NormalizationReport, String> {
+        Self::run_limited(
+            &self.operation_limiter(),
+            "find_normalization_issues",
+            self.find_normalization_issues(path_segments),
+        )
+    }
+
+    pub fn synced_normalize_names(
+        &mut self,
+        path_segments: &[String],
+    ) -> Result<Vec<NormalizationConflict>, String> {
+        Self::run_limited(
+            &self.operation_limiter(),
+            "normalize_names",
+            self.normalize_names(path_segments),
+        )
+    }
+}
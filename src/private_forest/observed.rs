@@ -0,0 +1,219 @@
+//! Helper operations reporting progress and honouring cancellation, see `crate::progress`.
+
+use futures::{
+    future::{self, Either},
+    StreamExt,
+};
+use libipld::Cid;
+use log::trace;
+
+use super::{ExclusiveSession, PrivateDirectoryHelper, TreeCopyOptions, TreeCopyReport};
+use crate::error::describe;
+use crate::{
+    blockstore::FFIFriendlyBlockStore,
+    error::WnfsUtilsError,
+    progress::{ObservedReader, OperationObserver, ProgressMeter},
+};
+
+impl<'a> PrivateDirectoryHelper<'a> {
+    /// `write_file`, reporting the bytes written. A cancelled or failed write leaves the tree
+    /// unchanged. The write is committed like `write_file`'s, batched or left to the
+    /// enclosing transaction.
+    pub async fn write_file_observed(
+        &mut self,
+        path_segments: &[String],
+        content: Vec<u8>,
+        modification_time_seconds: i64,
+        observer: &OperationObserver,
+    ) -> Result<Cid, String> {
+        observer.check()?;
+        let media_entries = self.media_metadata_entries(&mut std::io::Cursor::new(&content));
+        let meter = ProgressMeter::new(
+            self.store.metrics_handle(),
+            "write_file",
+            Some(content.len() as u64),
+        );
+        let reader = ObservedReader::new(futures::io::Cursor::new(content), observer, meter);
+        let mut tx = self.begin();
+        tx.write_file_stream_with_metadata(
+            path_segments,
+            reader,
+            modification_time_seconds,
+            media_entries,
+        )
+        .await?;
+        tx.release();
+        self.commit().await
+    }
+
+    /// `read_file`, reporting the bytes read and blocks fetched. `bytes_total` is the upper
+    /// bound wnfs keeps of the size. Paged files are reported once, when they were read.
+    pub async fn read_file_observed(
+        &mut self,
+        path_segments: &[String],
+        observer: &OperationObserver,
+    ) -> Result<Vec<u8>, String> {
+        observer.check()?;
+        if self.is_paged_file(path_segments).await? {
+            let content = self.read_file_at(path_segments, 0, usize::MAX).await?;
+            let mut meter = ProgressMeter::new(
+                self.store.metrics_handle(),
+                "read_file",
+                Some(content.len() as u64),
+            );
+            meter.advance(observer, content.len() as u64);
+            return Ok(content);
+        }
+        let file = match self.node_at(path_segments).await? {
+            Some(node) if node.is_file() => node.as_file().map_err(|e| {
+                trace!("wnfsError in read_file_observed: {:?}", e.to_string());
+                describe(&e)
+            })?,
+            Some(_) => {
+                return Err(format!(
+                    "wnfsError {} is a directory",
+                    path_segments.join("/")
+                ))
+            }
+            None => {
+                return Err(format!(
+                    "wnfsError nothing found at {}",
+                    path_segments.join("/")
+                ))
+            }
+        };
+        let mut meter = ProgressMeter::new(
+            self.store.metrics_handle(),
+            "read_file",
+            Some(file.get_content_size_upper_bound() as u64),
+        );
+        let mut content = Vec::new();
+        let mut stream = file.stream_content(0, &mut self.forest, &mut self.store);
+        while let Some(block) = stream.next().await {
+            observer.check()?;
+            let block = block.map_err(|e| {
+                trace!("wnfsError in read_file_observed: {:?}", e.to_string());
+                describe(&e)
+            })?;
+            meter.advance(observer, block.len() as u64);
+            content.extend_from_slice(&block);
+        }
+        Ok(content)
+    }
+
+    /// `ingest`, reporting the bytes of the files done. A cancelled import leaves the tree
+    /// unchanged.
+    pub async fn ingest_observed(
+        &mut self,
+        local_fs_dir: &String,
+        path_segments: &[String],
+        options: TreeCopyOptions,
+        observer: &OperationObserver,
+    ) -> Result<TreeCopyReport, String> {
+        self.ingest_with(local_fs_dir, path_segments, options, None, observer)
+            .await
+    }
+
+    /// `materialize`, reporting the bytes of the files done. Files exported before the
+    /// cancellation stay on disk.
+    pub async fn materialize_observed(
+        &mut self,
+        path_segments: &[String],
+        local_fs_dir: &String,
+        options: TreeCopyOptions,
+        observer: &OperationObserver,
+    ) -> Result<TreeCopyReport, String> {
+        self.materialize_with(path_segments, local_fs_dir, options, None, observer)
+            .await
+    }
+
+    /// `load_exclusive`, reporting the bytes and blocks fetched once loaded. Cancelling stops
+    /// waiting for the store right away.
+    pub async fn load_with_wnfs_key_observed(
+        store: &mut FFIFriendlyBlockStore<'a>,
+        forest_cid: Cid,
+        wnfs_key: Vec<u8>,
+        observer: &OperationObserver,
+    ) -> Result<ExclusiveSession<'a>, String> {
+        observer.check()?;
+        let metrics = store.metrics_handle();
+        let bytes_before = metrics.snapshot().bytes_read;
+        let mut meter = ProgressMeter::new(metrics.clone(), "load_forest", None);
+        let load = Self::load_exclusive(store, forest_cid, wnfs_key);
+        let helper = match future::select(Box::pin(load), Box::pin(observer.cancelled())).await {
+            Either::Left((helper, _)) => helper?,
+            Either::Right(_) => return Err(WnfsUtilsError::Cancelled.to_string()),
+        };
+        let bytes_read = metrics.snapshot().bytes_read.saturating_sub(bytes_before);
+        meter.advance(observer, bytes_read);
+        Ok(helper)
+    }
+}
+
+impl<'a> PrivateDirectoryHelper<'a> {
+    pub fn synced_write_file_observed(
+        &mut self,
+        path_segments: &[String],
+        content: Vec<u8>,
+        modification_time_seconds: i64,
+        observer: &OperationObserver,
+    ) -> Result<Cid, String> {
+        Self::run_limited(
+            &self.operation_limiter(),
+            "write_file_observed",
+            self.write_file_observed(path_segments, content, modification_time_seconds, observer),
+        )
+    }
+
+    pub fn synced_read_file_observed(
+        &mut self,
+        path_segments: &[String],
+        observer: &OperationObserver,
+    ) -> Result<Vec<u8>, String> {
+        Self::run_limited(
+            &self.operation_limiter(),
+            "read_file_observed",
+            self.read_file_observed(path_segments, observer),
+        )
+    }
+
+    pub fn synced_ingest_observed(
+        &mut self,
+        local_fs_dir: &String,
+        path_segments: &[String],
+        options: TreeCopyOptions,
+        observer: &OperationObserver,
+    ) -> Result<TreeCopyReport, String> {
+        Self::run_limited(
+            &self.operation_limiter(),
+            "ingest_observed",
+            self.ingest_observed(local_fs_dir, path_segments, options, observer),
+        )
+    }
+
+    pub fn synced_materialize_observed(
+        &mut self,
+        path_segments: &[String],
+        local_fs_dir: &String,
+        options: TreeCopyOptions,
+        observer: &OperationObserver,
+    ) -> Result<TreeCopyReport, String> {
+        Self::run_limited(
+            &self.operation_limiter(),
+            "materialize_observed",
+            self.materialize_observed(path_segments, local_fs_dir, options, observer),
+        )
+    }
+
+    pub fn synced_load_with_wnfs_key_observed(
+        store: &mut FFIFriendlyBlockStore<'a>,
+        forest_cid: Cid,
+        wnfs_key: Vec<u8>,
+        observer: &OperationObserver,
+    ) -> Result<ExclusiveSession<'a>, String> {
+        Self::run_request(
+            "load_with_wnfs_key_observed",
+            Self::load_with_wnfs_key_observed(store, forest_cid, wnfs_key, observer),
+        )
+    }
+}
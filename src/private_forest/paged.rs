@@ -0,0 +1,535 @@
+//! Page-aligned files for databases such as SQLite, which rewrite single 4KiB pages.
+//!
+//! Rewriting a page of a plain WNFS file re-encrypts the whole file. A paged file is instead a
+//! directory holding fixed-size chunk files, each a whole number of pages, plus a
+//! `PAGED_MARKER` file with the layout and logical length. `write_at` only rewrites the chunks
+//! the write touches, and chunks never written read back as zeros.
+//!
+//! `rechunk` moves existing paged files to new options, e.g. after tuning the chunk size on a
+//! forest that already holds large databases.
+
+use chrono::Utc;
+use futures::{AsyncRead, AsyncReadExt};
+use libipld::Cid;
+use log::trace;
+use serde::{Deserialize, Serialize};
+
+use super::{PrivateDirectoryHelper, TransferProgress};
+use crate::error::{describe, WnfsUtilsError};
+
+/// Holds the layout of a paged file, inside its directory.
+pub const PAGED_MARKER: &str = ".wnfsutils-paged";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PagedFileOptions {
+    pub page_size: u32,
+    /// Pages stored together in one chunk file.
+    pub pages_per_chunk: u32,
+}
+
+impl Default for PagedFileOptions {
+    fn default() -> Self {
+        Self {
+            page_size: 4096,
+            pages_per_chunk: 16,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct PagedLayout {
+    options: PagedFileOptions,
+    len: u64,
+}
+
+impl PagedLayout {
+    fn chunk_size(&self) -> u64 {
+        self.options.page_size as u64 * self.options.pages_per_chunk as u64
+    }
+}
+
+impl<'a> PrivateDirectoryHelper<'a> {
+    /// Creates an empty paged file at `path_segments`.
+    pub async fn create_paged_file(
+        &mut self,
+        path_segments: &[String],
+        options: PagedFileOptions,
+    ) -> Result<Cid, String> {
+        if options.page_size == 0 || options.pages_per_chunk == 0 {
+            return Err("wnfsError page size and pages per chunk must be positive".to_string());
+        }
+        if self.node_at(path_segments).await?.is_some() {
+            trace!("wnfsError in create_paged_file: {:?} exists", path_segments);
+            return Err(format!(
+                "wnfsError {} already exists",
+                path_segments.join("/")
+            ));
+        }
+        self.check_not_held(path_segments, false).await?;
+        self.store_paged_layout(path_segments, PagedLayout { options, len: 0 })
+            .await?;
+        self.split_parent_if_needed(path_segments).await?;
+        self.commit().await
+    }
+
+    pub async fn is_paged_file(&mut self, path_segments: &[String]) -> Result<bool, String> {
+        let marker = Self::paged_marker_path(path_segments);
+        // No paged file can be stored where its marker would be too deep.
+        if self.check_path_depth(&marker).is_err() {
+            return Ok(false);
+        }
+        Ok(self.node_at(&marker).await?.is_some())
+    }
+
+    pub async fn paged_file_len(&mut self, path_segments: &[String]) -> Result<u64, String> {
+        Ok(self.paged_layout(path_segments).await?.len)
+    }
+
+    /// The options the paged file at `path_segments` was created or last rechunked with.
+    pub async fn paged_file_options(
+        &mut self,
+        path_segments: &[String],
+    ) -> Result<PagedFileOptions, String> {
+        Ok(self.paged_layout(path_segments).await?.options)
+    }
+
+    /// Reads up to `len` bytes at `offset`, fewer at the end of the file.
+    pub async fn read_at(
+        &mut self,
+        path_segments: &[String],
+        offset: u64,
+        len: usize,
+    ) -> Result<Vec<u8>, String> {
+        let layout = self.paged_layout(path_segments).await?;
+        let end = offset.saturating_add(len as u64).min(layout.len);
+        // Grown from the chunks read: `len` may be `usize::MAX` and the layout's length is data.
+        let mut out = Vec::new();
+        let chunk_size = layout.chunk_size();
+        let mut position = offset;
+        while position < end {
+            let index = position / chunk_size;
+            let chunk_start = index * chunk_size;
+            let chunk = self.read_chunk(path_segments, index).await?;
+            let from = (position - chunk_start) as usize;
+            let to = ((end - chunk_start).min(chunk_size)) as usize;
+            let stored = chunk.get(from..to.min(chunk.len())).unwrap_or_default();
+            out.extend_from_slice(stored);
+            // Sparse chunks and short last chunks read as zeros.
+            out.resize(out.len() + (to - from - stored.len()), 0);
+            position = chunk_start + to as u64;
+        }
+        Ok(out)
+    }
+
+    /// Writes `data` at `offset`, growing the file when needed. Only the chunks overlapping the
+    /// write are re-encrypted, and the change is committed once. Like a POSIX `write`, writing
+    /// no bytes leaves the file as it is, even past its end. The content scanners see `data`
+    /// alone, and their entries are kept on the layout marker.
+    pub async fn write_at(
+        &mut self,
+        path_segments: &[String],
+        offset: u64,
+        data: &[u8],
+    ) -> Result<Cid, String> {
+        self.check_not_held(path_segments, false).await?;
+        let mut layout = self.paged_layout(path_segments).await?;
+        if data.is_empty() {
+            return self.flush_commits().await;
+        }
+        let entries = self.scan_content(path_segments, data).await?;
+        let chunk_size = layout.chunk_size();
+        let end = offset.saturating_add(data.len() as u64);
+        let mut position = offset;
+        while position < end {
+            let index = position / chunk_size;
+            let chunk_start = index * chunk_size;
+            let from = (position - chunk_start) as usize;
+            let to = ((end - chunk_start).min(chunk_size)) as usize;
+            let mut chunk = self.read_chunk(path_segments, index).await?;
+            if chunk.len() < to {
+                chunk.resize(to, 0);
+            }
+            let source = (position - offset) as usize;
+            chunk[from..to].copy_from_slice(&data[source..source + (to - from)]);
+            self.write_chunk(path_segments, index, chunk).await?;
+            position = chunk_start + to as u64;
+        }
+        if end > layout.len {
+            layout.len = end;
+            self.store_paged_layout(path_segments, layout).await?;
+        }
+        if !entries.is_empty() {
+            self.put_file_metadata(&Self::paged_marker_path(path_segments), entries, Utc::now())
+                .await?;
+        }
+        let mut last_chunk = path_segments.to_vec();
+        last_chunk.push(Self::chunk_name(end.saturating_sub(1) / chunk_size));
+        self.split_parent_if_needed(&last_chunk).await?;
+        self.commit().await
+    }
+
+    /// Truncates or extends the file to `len` bytes. Chunks past the new end are removed.
+    pub async fn set_paged_len(
+        &mut self,
+        path_segments: &[String],
+        len: u64,
+    ) -> Result<Cid, String> {
+        self.check_not_held(path_segments, false).await?;
+        self.set_paged_len_raw(path_segments, len).await?;
+        self.commit().await
+    }
+
+    // `set_paged_len` without the hold check and the commit.
+    pub(super) async fn set_paged_len_raw(
+        &mut self,
+        path_segments: &[String],
+        len: u64,
+    ) -> Result<(), String> {
+        let mut layout = self.paged_layout(path_segments).await?;
+        let chunk_size = layout.chunk_size();
+        if len < layout.len {
+            let first_removed = len.div_ceil(chunk_size);
+            let last = layout.len.div_ceil(chunk_size);
+            for index in first_removed..last {
+                let mut chunk_path = path_segments.to_vec();
+                chunk_path.push(Self::chunk_name(index));
+                if self.node_at(&chunk_path).await?.is_some() {
+                    self.rm_raw(&chunk_path).await?;
+                }
+            }
+            // Zero the tail of the last kept chunk, so extending the file again reads zeros.
+            let kept = len % chunk_size;
+            if kept > 0 {
+                let index = len / chunk_size;
+                let mut chunk = self.read_chunk(path_segments, index).await?;
+                if chunk.len() as u64 > kept {
+                    chunk.truncate(kept as usize);
+                    self.write_chunk(path_segments, index, chunk).await?;
+                }
+            }
+        }
+        layout.len = len;
+        self.store_paged_layout(path_segments, layout).await
+    }
+
+    /// Migrates every paged file in the subtree at `path_segments` (`[]` for the whole forest) to
+    /// `options`, committing once per file. Files already using `options` are skipped, so a run
+    /// that was interrupted picks up where it stopped when called again. Returns the number of
+    /// files migrated.
+    pub async fn rechunk(
+        &mut self,
+        path_segments: &[String],
+        options: PagedFileOptions,
+        progress: Option<&mut dyn FnMut(&TransferProgress)>,
+    ) -> Result<usize, String> {
+        if options.page_size == 0 || options.pages_per_chunk == 0 {
+            return Err("wnfsError page size and pages per chunk must be positive".to_string());
+        }
+        self.rechunk_with(path_segments, &|_| options, progress)
+            .await
+    }
+
+    // `rechunk`, moving each paged file to the options `choose` picks for its length.
+    pub(super) async fn rechunk_with(
+        &mut self,
+        path_segments: &[String],
+        choose: &dyn Fn(u64) -> PagedFileOptions,
+        mut progress: Option<&mut dyn FnMut(&TransferProgress)>,
+    ) -> Result<usize, String> {
+        let (_, files) = self.collect_subtree(path_segments).await?;
+        let mut paged = Vec::new();
+        for file in files {
+            if self.is_paged_file(&file).await? {
+                paged.push(file);
+            }
+        }
+        let mut report = TransferProgress {
+            files_total: paged.len(),
+            ..Default::default()
+        };
+        let mut migrated = 0;
+        for path in paged {
+            let layout = self.paged_layout(&path).await?;
+            let options = choose(layout.len);
+            if layout.options != options {
+                self.rechunk_file(&path, layout, options).await?;
+                migrated += 1;
+            }
+            report.files_done += 1;
+            report.bytes_done += layout.len;
+            report.current_path = path.join("/");
+            if let Some(callback) = progress.as_mut() {
+                callback(&report);
+            }
+        }
+        Ok(migrated)
+    }
+
+    // Copies the file into a sibling with the new layout, one new chunk at a time, then swaps
+    // the two and commits.
+    async fn rechunk_file(
+        &mut self,
+        path_segments: &[String],
+        layout: PagedLayout,
+        options: PagedFileOptions,
+    ) -> Result<Cid, String> {
+        self.check_not_held(path_segments, true).await?;
+        let mut staging = path_segments.to_vec();
+        match staging.last_mut() {
+            Some(name) => *name = format!(".{}.rechunk", name),
+            None => return Err("wnfsError the root is not a paged file".to_string()),
+        }
+        if self.node_at(&staging).await?.is_some() {
+            self.rm_raw(&staging).await?;
+        }
+        let target = PagedLayout {
+            options,
+            len: layout.len,
+        };
+        let chunk_size = target.chunk_size();
+        for index in 0..layout.len.div_ceil(chunk_size) {
+            let chunk = self
+                .read_at(path_segments, index * chunk_size, chunk_size as usize)
+                .await?;
+            // Keep sparse regions sparse.
+            if chunk.iter().any(|byte| *byte != 0) {
+                self.write_chunk(&staging, index, chunk).await?;
+            }
+        }
+        self.store_paged_layout(&staging, target).await?;
+        self.rm_raw(path_segments).await?;
+        let source = self.resolve_path(&staging).await?;
+        let destination = self.resolve_path(path_segments).await?;
+        let forest = &mut self.forest;
+        let root_dir = &mut self.root_dir;
+        root_dir
+            .basic_mv(
+                &source,
+                &destination,
+                true,
+                Utc::now(),
+                forest,
+                &mut self.store,
+                &mut self.rng,
+            )
+            .await
+            .map_err(|e| {
+                trace!("wnfsError in rechunk: {:?}", e.to_string());
+                describe(&e)
+            })?;
+        self.split_parent_if_needed(path_segments).await?;
+        self.commit().await
+    }
+
+    // Writes `content` as a new paged file with `options` at `path_segments`, which must not
+    // exist yet, without scanning or committing. Returns the length written.
+    pub(super) async fn write_paged_raw<R: AsyncRead + Unpin>(
+        &mut self,
+        path_segments: &[String],
+        options: PagedFileOptions,
+        mut content: R,
+    ) -> Result<u64, String> {
+        let mut layout = PagedLayout { options, len: 0 };
+        let chunk_size = layout.chunk_size();
+        let mut chunks = 0;
+        loop {
+            let mut chunk = Vec::with_capacity(chunk_size as usize);
+            (&mut content)
+                .take(chunk_size)
+                .read_to_end(&mut chunk)
+                .await
+                .map_err(|e| {
+                    trace!("wnfsError in write_paged_raw: {:?}", e.to_string());
+                    describe(&e)
+                })?;
+            if chunk.is_empty() {
+                break;
+            }
+            let full = chunk.len() as u64 == chunk_size;
+            layout.len += chunk.len() as u64;
+            self.write_chunk(path_segments, chunks, chunk).await?;
+            chunks += 1;
+            if !full {
+                break;
+            }
+        }
+        self.store_paged_layout(path_segments, layout).await?;
+        if chunks > 0 {
+            let mut last_chunk = path_segments.to_vec();
+            last_chunk.push(Self::chunk_name(chunks - 1));
+            self.split_parent_if_needed(&last_chunk).await?;
+        }
+        self.split_parent_if_needed(path_segments).await?;
+        Ok(layout.len)
+    }
+
+    async fn paged_layout(&mut self, path_segments: &[String]) -> Result<PagedLayout, String> {
+        let marker = Self::paged_marker_path(path_segments);
+        if self.node_at(&marker).await?.is_none() {
+            trace!(
+                "wnfsError in paged_layout: {:?} is not paged",
+                path_segments
+            );
+            return Err(format!(
+                "wnfsError {} is not a paged file",
+                path_segments.join("/")
+            ));
+        }
+        let content = self.read_file(&marker).await?;
+        let corrupt = |reason: String| {
+            trace!("wnfsError in paged_layout: {:?} {}", path_segments, reason);
+            WnfsUtilsError::CorruptLayout {
+                path: path_segments.join("/"),
+                reason,
+            }
+            .to_string()
+        };
+        let layout: PagedLayout =
+            serde_json::from_slice(&content).map_err(|e| corrupt(e.to_string()))?;
+        // The marker is read from the forest, so a forged one mustn't make reads divide by zero.
+        if layout.chunk_size() == 0 {
+            return Err(corrupt(
+                "page size and pages per chunk must be positive".to_string(),
+            ));
+        }
+        Ok(layout)
+    }
+
+    async fn store_paged_layout(
+        &mut self,
+        path_segments: &[String],
+        layout: PagedLayout,
+    ) -> Result<(), String> {
+        let content = serde_json::to_vec(&layout).map_err(|e| describe(&e))?;
+        self.write_raw(&Self::paged_marker_path(path_segments), content)
+            .await
+    }
+
+    async fn read_chunk(
+        &mut self,
+        path_segments: &[String],
+        index: u64,
+    ) -> Result<Vec<u8>, String> {
+        let mut chunk_path = path_segments.to_vec();
+        chunk_path.push(Self::chunk_name(index));
+        match self.node_at(&chunk_path).await? {
+            Some(_) => self.read_file(&chunk_path).await,
+            None => Ok(Vec::new()),
+        }
+    }
+
+    pub(super) async fn write_chunk(
+        &mut self,
+        path_segments: &[String],
+        index: u64,
+        content: Vec<u8>,
+    ) -> Result<(), String> {
+        let mut chunk_path = path_segments.to_vec();
+        chunk_path.push(Self::chunk_name(index));
+        self.write_raw(&chunk_path, content).await
+    }
+
+    // Writes below a logical path without committing.
+    pub(super) async fn write_raw(
+        &mut self,
+        path_segments: &[String],
+        content: Vec<u8>,
+    ) -> Result<(), String> {
+        let resolved = self.resolve_path(path_segments).await?;
+        let forest = &mut self.forest;
+        let root_dir = &mut self.root_dir;
+        root_dir
+            .write(
+                &resolved,
+                true,
+                Utc::now(),
+                content,
+                forest,
+                &mut self.store,
+                &mut self.rng,
+            )
+            .await
+            .map_err(|e| {
+                trace!("wnfsError in write_raw: {:?}", e.to_string());
+                describe(&e)
+            })
+    }
+
+    pub(super) async fn rm_raw(&mut self, path_segments: &[String]) -> Result<(), String> {
+        let resolved = self.resolve_path(path_segments).await?;
+        let forest = &mut self.forest;
+        let root_dir = &mut self.root_dir;
+        root_dir
+            .rm(&resolved, true, forest, &mut self.store)
+            .await
+            .map(|_| ())
+            .map_err(|e| {
+                trace!("wnfsError in rm_raw: {:?}", e.to_string());
+                describe(&e)
+            })
+    }
+
+    pub(super) fn paged_marker_path(path_segments: &[String]) -> Vec<String> {
+        let mut marker = path_segments.to_vec();
+        marker.push(PAGED_MARKER.to_string());
+        marker
+    }
+
+    pub(super) fn chunk_name(index: u64) -> String {
+        format!("{:016x}", index)
+    }
+}
+
+// Synced versions for SQLite VFS glue, which calls in from a blocking thread.
+impl<'a> PrivateDirectoryHelper<'a> {
+    pub fn synced_read_at(
+        &mut self,
+        path_segments: &[String],
+        offset: u64,
+        len: usize,
+    ) -> Result<Vec<u8>, String> {
+        Self::run_limited(
+            &self.operation_limiter(),
+            "read_at",
+            self.read_at(path_segments, offset, len),
+        )
+    }
+
+    pub fn synced_write_at(
+        &mut self,
+        path_segments: &[String],
+        offset: u64,
+        data: &[u8],
+    ) -> Result<Cid, String> {
+        Self::run_limited(
+            &self.operation_limiter(),
+            "write_at",
+            self.write_at(path_segments, offset, data),
+        )
+    }
+
+    pub fn synced_set_paged_len(
+        &mut self,
+        path_segments: &[String],
+        len: u64,
+    ) -> Result<Cid, String> {
+        Self::run_limited(
+            &self.operation_limiter(),
+            "set_paged_len",
+            self.set_paged_len(path_segments, len),
+        )
+    }
+
+    pub fn synced_rechunk(
+        &mut self,
+        path_segments: &[String],
+        options: PagedFileOptions,
+        progress: Option<&mut dyn FnMut(&TransferProgress)>,
+    ) -> Result<usize, String> {
+        Self::run_limited(
+            &self.operation_limiter(),
+            "rechunk",
+            self.rechunk(path_segments, options, progress),
+        )
+    }
+}
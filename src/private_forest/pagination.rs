@@ -0,0 +1,148 @@
+//! Paged results for list-like calls, so bindings fetch large listings in bounded pieces the
+//! same way everywhere.
+//!
+//! Every paged call takes an optional continuation token and a page size, and returns a
+//! [`Page`] whose `next_page` token continues the listing, or `None` after the last page. Tokens
+//! are opaque to callers: they remember the last item returned rather than an offset, so entries
+//! added or removed between pages don't shift the listing, and a token is rejected by any call
+//! but the one that issued it.
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD as BASE64, Engine};
+use libipld::Cid;
+use log::trace;
+use serde::{Deserialize, Serialize};
+use wnfs::common::Metadata;
+
+use super::PrivateDirectoryHelper;
+
+/// One page of a listing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    /// Token for the next page, `None` after the last one.
+    pub next_page: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Continuation {
+    // The listing the token belongs to, e.g. `ls:photos/2024`.
+    listing: String,
+    // Key of the last item returned.
+    after: String,
+}
+
+impl<'a> PrivateDirectoryHelper<'a> {
+    /// Pages through `ls_files` of `path_segments`, sorted by name.
+    pub async fn ls_files_page(
+        &mut self,
+        path_segments: &[String],
+        page: Option<&str>,
+        page_size: usize,
+    ) -> Result<Page<(String, Metadata)>, String> {
+        let listing = format!("ls:{}", path_segments.join("/"));
+        let after = Self::continue_after(&listing, page)?;
+        let mut entries = self.ls_files(path_segments).await?;
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        let start = match &after {
+            Some(after) => entries.partition_point(|(name, _)| name <= after),
+            None => 0,
+        };
+        Ok(Self::page_of(
+            &listing,
+            entries.into_iter().skip(start).collect(),
+            page_size,
+            |(name, _)| name.to_owned(),
+        ))
+    }
+
+    /// Pages through `root_history`, newest first.
+    pub fn root_history_page(
+        &self,
+        page: Option<&str>,
+        page_size: usize,
+    ) -> Result<Page<Cid>, String> {
+        let listing = "history".to_string();
+        let after = Self::continue_after(&listing, page)?;
+        let newest_first: Vec<Cid> = self.root_history.iter().rev().copied().collect();
+        let start = match after {
+            Some(after) => {
+                newest_first
+                    .iter()
+                    .position(|cid| cid.to_string() == after)
+                    .ok_or_else(|| {
+                        // The root dropped out of the bounded history since the token was issued.
+                        trace!("wnfsError in root_history_page: {} expired", after);
+                        "wnfsError page token expired".to_string()
+                    })?
+                    + 1
+            }
+            None => 0,
+        };
+        Ok(Self::page_of(
+            &listing,
+            newest_first.into_iter().skip(start).collect(),
+            page_size,
+            Cid::to_string,
+        ))
+    }
+
+    // Decodes `page` into the key of the last item already returned.
+    pub(super) fn continue_after(
+        listing: &str,
+        page: Option<&str>,
+    ) -> Result<Option<String>, String> {
+        let Some(page) = page else {
+            return Ok(None);
+        };
+        let invalid = || {
+            trace!("wnfsError in paged listing {}: invalid token", listing);
+            format!("wnfsError invalid page token {}", page)
+        };
+        let bytes = BASE64.decode(page).map_err(|_| invalid())?;
+        let continuation: Continuation = serde_json::from_slice(&bytes).map_err(|_| invalid())?;
+        if continuation.listing != listing {
+            return Err(invalid());
+        }
+        Ok(Some(continuation.after))
+    }
+
+    // Takes the first `page_size` of `remaining` and a token after the last of them.
+    pub(super) fn page_of<T>(
+        listing: &str,
+        mut remaining: Vec<T>,
+        page_size: usize,
+        key: impl Fn(&T) -> String,
+    ) -> Page<T> {
+        let page_size = page_size.max(1);
+        let more = remaining.len() > page_size;
+        remaining.truncate(page_size);
+        let next_page = match (more, remaining.last()) {
+            (true, Some(last)) => serde_json::to_vec(&Continuation {
+                listing: listing.to_string(),
+                after: key(last),
+            })
+            .ok()
+            .map(|bytes| BASE64.encode(bytes)),
+            _ => None,
+        };
+        Page {
+            items: remaining,
+            next_page,
+        }
+    }
+}
+
+impl<'a> PrivateDirectoryHelper<'a> {
+    pub fn synced_ls_files_page(
+        &mut self,
+        path_segments: &[String],
+        page: Option<&str>,
+        page_size: usize,
+    ) -> Result<Page<(String, Metadata)>, String> {
+        Self::run_limited(
+            &self.operation_limiter(),
+            "ls_files_page",
+            self.ls_files_page(path_segments, page, page_size),
+        )
+    }
+}
@@ -1,6 +1,6 @@
 use wnfs::common::CODEC_DAG_CBOR;
 
-use crate::blockstore::FFIFriendlyBlockStore;
+use crate::blockstore::{DecodeLimits, FFIFriendlyBlockStore};
 use crate::kvstore::KVBlockStore;
 use crate::private_forest::PrivateDirectoryHelper;
 use libipld::Cid;
@@ -22,6 +22,11 @@ async fn test_parse_path() {
     let out = PrivateDirectoryHelper::parse_path(path);
     assert_eq!(out[0], "root".to_string());
     assert_eq!(out[1], "test.txt".to_string());
+    assert!(PrivateDirectoryHelper::parse_path("/".to_string()).is_empty());
+    assert_eq!(
+        PrivateDirectoryHelper::parse_path("a//b/".to_string()),
+        vec!["a".to_string(), "b".to_string()]
+    );
 }
 
 #[tokio::test]
@@ -1113,3 +1118,4642 @@ async fn test_large_file_write_stream_with_reload() {
     file2.read_to_end(&mut content2).unwrap();
     assert_eq!(content1, content2);
 }
+
+#[tokio::test]
+async fn test_materialize_and_ingest() {
+    let empty_key: Vec<u8> = vec![0; 32];
+    let store = KVBlockStore::new(
+        String::from("./tmp/test_materialize_and_ingest"),
+        CODEC_DAG_CBOR,
+    );
+    let blockstore = &mut FFIFriendlyBlockStore::new(Box::new(store));
+    let (helper, _, _) = &mut PrivateDirectoryHelper::init(blockstore, empty_key.to_owned())
+        .await
+        .unwrap();
+
+    helper
+        .write_file(
+            &["root".into(), "docs".into(), "a.txt".into()],
+            b"first file".to_vec(),
+            0,
+        )
+        .await
+        .unwrap();
+    helper
+        .write_file(
+            &[
+                "root".into(),
+                "docs".into(),
+                "nested".into(),
+                "b.txt".into(),
+            ],
+            b"second file".to_vec(),
+            0,
+        )
+        .await
+        .unwrap();
+
+    let export_dir = tempfile::tempdir().unwrap();
+    let export_path = export_dir.path().to_string_lossy().into_owned();
+    let mut reported = 0;
+    let mut on_progress = |_: &crate::private_forest::TransferProgress| reported += 1;
+    let report = helper
+        .materialize(
+            &["root".into(), "docs".into()],
+            &export_path,
+            Default::default(),
+            Some(&mut on_progress),
+        )
+        .await
+        .unwrap();
+    assert_eq!(report.copied, 2);
+    assert_eq!(reported, 2);
+    assert_eq!(
+        read(export_dir.path().join("a.txt")).unwrap(),
+        b"first file".to_vec()
+    );
+    assert_eq!(
+        read(export_dir.path().join("nested").join("b.txt")).unwrap(),
+        b"second file".to_vec()
+    );
+
+    helper
+        .ingest(
+            &export_path,
+            &["root".into(), "copy".into()],
+            Default::default(),
+            None,
+        )
+        .await
+        .unwrap();
+    let content = helper
+        .read_file(&[
+            "root".into(),
+            "copy".into(),
+            "nested".into(),
+            "b.txt".into(),
+        ])
+        .await
+        .unwrap();
+    assert_eq!(content, b"second file".to_vec());
+
+    // A paged file comes out as one file, and the reserved directory stays in the forest.
+    use crate::private_forest::{PagedFileOptions, RESERVED_DIR};
+    let db: Vec<String> = vec!["root".into(), "docs".into(), "app.db".into()];
+    let options = PagedFileOptions {
+        page_size: 16,
+        pages_per_chunk: 2,
+    };
+    helper.create_paged_file(&db, options).await.unwrap();
+    helper.write_at(&db, 40, b"paged content").await.unwrap();
+    helper
+        .place_legal_hold(
+            &["root".into(), "docs".into(), "nested".into()],
+            "audit",
+            b"custodian",
+        )
+        .await
+        .unwrap();
+    let paged_dir = tempfile::tempdir().unwrap();
+    let paged_path = paged_dir.path().to_string_lossy().into_owned();
+    let report = helper
+        .materialize(&[], &paged_path, Default::default(), None)
+        .await
+        .unwrap();
+    assert_eq!(report.copied, 5);
+    let exported = read(paged_dir.path().join("root").join("docs").join("app.db")).unwrap();
+    assert_eq!(exported.len(), 53);
+    assert_eq!(&exported[40..], b"paged content");
+    assert!(!paged_dir.path().join(RESERVED_DIR).exists());
+
+    helper
+        .ingest(&paged_path, &["restored".into()], Default::default(), None)
+        .await
+        .unwrap();
+    let restored = helper
+        .read_file(&[
+            "restored".into(),
+            "root".into(),
+            "docs".into(),
+            "app.db".into(),
+        ])
+        .await
+        .unwrap();
+    assert_eq!(restored, helper.read_file(&db).await.unwrap());
+}
+
+#[tokio::test]
+async fn test_ingest_media_dedupes_by_content() {
+    let empty_key: Vec<u8> = vec![0; 32];
+    let store = KVBlockStore::new(String::from("./tmp/test_ingest_media"), CODEC_DAG_CBOR);
+    let blockstore = &mut FFIFriendlyBlockStore::new(Box::new(store));
+    let (helper, _, _) = &mut PrivateDirectoryHelper::init(blockstore, empty_key.to_owned())
+        .await
+        .unwrap();
+
+    let source_dir = tempfile::tempdir().unwrap();
+    let first = source_dir.path().join("IMG_0001.jpg");
+    let second = source_dir.path().join("IMG_0002.jpg");
+    std::fs::write(&first, b"not really a jpeg").unwrap();
+    std::fs::write(&second, b"not really a jpeg").unwrap();
+    let files = vec![
+        first.to_string_lossy().into_owned(),
+        second.to_string_lossy().into_owned(),
+    ];
+
+    let report = helper
+        .ingest_media(
+            &files,
+            &["root".into(), "photos".into()],
+            crate::private_forest::MediaIngestOptions::default(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(report.written.len(), 1);
+    assert_eq!(report.duplicates, vec![files[1].to_owned()]);
+    assert_eq!(report.commits, 1);
+    assert_eq!(report.written[0].len(), 6);
+    assert_eq!(report.written[0][5], "IMG_0001.jpg");
+
+    // A second run over the same files writes nothing new.
+    let report = helper
+        .ingest_media(
+            &files,
+            &["root".into(), "photos".into()],
+            crate::private_forest::MediaIngestOptions::default(),
+        )
+        .await
+        .unwrap();
+    assert!(report.written.is_empty());
+    assert_eq!(report.duplicates.len(), 2);
+    assert!(report.forest_cid.is_none());
+}
+
+#[tokio::test]
+async fn test_analyze_and_apply_dedup() {
+    use crate::error::ErrorCode;
+
+    let empty_key: Vec<u8> = vec![0; 32];
+    let store = KVBlockStore::new(String::from("./tmp/test_dedup"), CODEC_DAG_CBOR);
+    let blockstore = &mut FFIFriendlyBlockStore::new(Box::new(store));
+    let (helper, _, _) = &mut PrivateDirectoryHelper::init(blockstore, empty_key.to_owned())
+        .await
+        .unwrap();
+
+    for name in ["a.bin", "b.bin", "c.bin"] {
+        helper
+            .write_file(&["root".into(), name.into()], vec![7u8; 4096], 0)
+            .await
+            .unwrap();
+    }
+    helper
+        .write_file(&["root".into(), "unique.bin".into()], vec![1u8; 10], 0)
+        .await
+        .unwrap();
+
+    let report = helper.analyze_duplicates(&["root".into()]).await.unwrap();
+    assert_eq!(report.groups.len(), 1);
+    assert_eq!(report.groups[0].paths.len(), 3);
+    assert_eq!(report.reclaimable_bytes, 2 * 4096);
+
+    // A duplicate changed after the analysis stops the whole run before anything is replaced.
+    helper
+        .write_file(&["root".into(), "b.bin".into()], vec![8u8; 4096], 0)
+        .await
+        .unwrap();
+    let history_len = helper.root_history().len();
+    let err = helper.apply_dedup(&report).await.unwrap_err();
+    assert_eq!(ErrorCode::of_message(&err), ErrorCode::Conflict);
+    assert_eq!(helper.root_history().len(), history_len);
+    let content = helper
+        .read_file(&["root".into(), "b.bin".into()])
+        .await
+        .unwrap();
+    assert_eq!(content, vec![8u8; 4096]);
+
+    helper
+        .write_file(&["root".into(), "b.bin".into()], vec![7u8; 4096], 2_000)
+        .await
+        .unwrap();
+    let modified_of = |listing: &[(String, wnfs::common::Metadata)], name: &str| {
+        listing
+            .iter()
+            .find(|(entry, _)| entry == name)
+            .and_then(|(_, metadata)| metadata.get_modified())
+            .map(|time| time.timestamp())
+    };
+
+    // A held duplicate stops the run after the first one was replaced in memory, which is
+    // rolled back.
+    let held: Vec<String> = vec!["root".into(), "c.bin".into()];
+    helper
+        .place_legal_hold(&held, "audit", b"custodian")
+        .await
+        .unwrap();
+    let history_len = helper.root_history().len();
+    let err = helper.apply_dedup(&report).await.unwrap_err();
+    assert_eq!(ErrorCode::of_message(&err), ErrorCode::Held);
+    assert_eq!(helper.root_history().len(), history_len);
+    assert!(!helper.has_pending_commit());
+    let listing = helper.ls_files(&["root".into()]).await.unwrap();
+    assert_eq!(modified_of(&listing, "b.bin"), Some(2_000));
+    helper
+        .release_legal_hold(&held, b"custodian")
+        .await
+        .unwrap();
+
+    helper.apply_dedup(&report).await.unwrap();
+    let content = helper
+        .read_file(&["root".into(), "c.bin".into()])
+        .await
+        .unwrap();
+    assert_eq!(content, vec![7u8; 4096]);
+    let ls_result = helper.ls_files(&["root".into()]).await.unwrap();
+    assert_eq!(ls_result.len(), 4);
+    // Replaced files keep their own times.
+    assert_eq!(modified_of(&ls_result, "b.bin"), Some(2_000));
+}
+
+#[tokio::test]
+async fn test_indexed_name_privacy() {
+    use crate::private_forest::{HelperConfig, NameIndex, NamePrivacy};
+
+    let empty_key: Vec<u8> = vec![0; 32];
+    let store = KVBlockStore::new(String::from("./tmp/test_name_privacy"), CODEC_DAG_CBOR);
+    let blockstore = &mut FFIFriendlyBlockStore::new(Box::new(store));
+    let (helper, _, _) = &mut PrivateDirectoryHelper::init(blockstore, empty_key.to_owned())
+        .await
+        .unwrap();
+    helper.set_config(HelperConfig {
+        name_privacy: vec![(vec!["root".into(), "shared".into()], NamePrivacy::Indexed)],
+        ..Default::default()
+    });
+
+    helper
+        .write_file(
+            &["root".into(), "shared".into(), "listed.txt".into()],
+            b"content".to_vec(),
+            0,
+        )
+        .await
+        .unwrap();
+    helper
+        .write_file(
+            &["root".into(), "secret.txt".into()],
+            b"content".to_vec(),
+            0,
+        )
+        .await
+        .unwrap();
+
+    assert!(helper.name_index(&["root".into()]).is_none());
+    let index_cid = helper
+        .name_index(&["root".into(), "shared".into()])
+        .unwrap();
+    let index = NameIndex::load(&helper.store, &index_cid).await.unwrap();
+    assert_eq!(index.entries(&[]).unwrap(), &vec!["listed.txt".to_string()]);
+
+    let names = helper
+        .ls_names(&["root".into(), "shared".into()])
+        .await
+        .unwrap();
+    assert_eq!(names, vec!["listed.txt".to_string()]);
+
+    // A later write inside the subtree is picked up by the next index.
+    helper
+        .write_file(
+            &["root".into(), "shared".into(), "more.txt".into()],
+            b"content".to_vec(),
+            0,
+        )
+        .await
+        .unwrap();
+    let updated_cid = helper
+        .name_index(&["root".into(), "shared".into()])
+        .unwrap();
+    assert_ne!(updated_cid, index_cid);
+    let index = NameIndex::load(&helper.store, &updated_cid).await.unwrap();
+    assert_eq!(
+        index.entries(&[]).unwrap(),
+        &vec!["listed.txt".to_string(), "more.txt".to_string()]
+    );
+
+    // Writes not committed yet are listed from the tree, not from the stale index.
+    let mut tx = helper.begin();
+    tx.write_file(
+        &["root".into(), "shared".into(), "pending.txt".into()],
+        b"content".to_vec(),
+        0,
+    )
+    .await
+    .unwrap();
+    let names = tx
+        .ls_names(&["root".into(), "shared".into()])
+        .await
+        .unwrap();
+    assert!(names.contains(&"pending.txt".to_string()));
+    tx.commit().await.unwrap();
+
+    // Paged files are indexed under their own name only.
+    use crate::private_forest::{PagedFileOptions, PAGED_MARKER};
+    let db: Vec<String> = vec!["root".into(), "shared".into(), "app.db".into()];
+    let options = PagedFileOptions {
+        page_size: 16,
+        pages_per_chunk: 2,
+    };
+    helper.create_paged_file(&db, options).await.unwrap();
+    helper.write_at(&db, 0, &[1u8; 80]).await.unwrap();
+    let index_cid = helper
+        .name_index(&["root".into(), "shared".into()])
+        .unwrap();
+    let index = NameIndex::load(&helper.store, &index_cid).await.unwrap();
+    assert!(index.entries(&[]).unwrap().contains(&"app.db".to_string()));
+    assert!(index.entries(&["app.db".into()]).is_none());
+    assert!(!index
+        .directories
+        .values()
+        .flatten()
+        .any(|name| name == PAGED_MARKER));
+
+    // Stored configs from before these settings existed still parse.
+    let parsed: HelperConfig = serde_json::from_str("{}").unwrap();
+    assert!(parsed.name_privacy.is_empty());
+    assert!(parsed.media_metadata.is_none());
+}
+
+#[tokio::test]
+async fn test_decode_limits() {
+    let empty_key: Vec<u8> = vec![0; 32];
+    let store = KVBlockStore::new(
+        String::from("./tmp/test_decode_limits_helper"),
+        CODEC_DAG_CBOR,
+    );
+    let blockstore = &mut FFIFriendlyBlockStore::new(Box::new(store));
+    blockstore.set_decode_limits(DecodeLimits {
+        max_path_depth: 2,
+        max_dir_entries: 1,
+        ..Default::default()
+    });
+    let (helper, _, _) = &mut PrivateDirectoryHelper::init(blockstore, empty_key.to_owned())
+        .await
+        .unwrap();
+
+    helper.mkdir(&["root".into(), "a".into()]).await.unwrap();
+    assert_eq!(helper.ls_files(&["root".into()]).await.unwrap().len(), 1);
+    // Paths too deep to be read back can't be written either.
+    let err = helper
+        .mkdir(&["root".into(), "a".into(), "b".into()])
+        .await
+        .unwrap_err();
+    assert!(err.contains("path depth 3 exceeds the limit of 2"));
+    let deep: Vec<String> = vec!["root".into(), "a".into(), "b.txt".into()];
+    let err = helper
+        .write_file(&deep, b"x".to_vec(), 0)
+        .await
+        .unwrap_err();
+    assert!(err.contains("path depth 3 exceeds the limit of 2"));
+    let err = helper
+        .cp(&["root".into(), "a".into()], &deep)
+        .await
+        .unwrap_err();
+    assert!(err.contains("path depth 3 exceeds the limit of 2"));
+    let err = helper
+        .mv(&["root".into(), "a".into()], &deep)
+        .await
+        .unwrap_err();
+    assert!(err.contains("path depth 3 exceeds the limit of 2"));
+    let err = helper
+        .ls_files(&["root".into(), "a".into(), "b".into()])
+        .await
+        .unwrap_err();
+    assert!(err.contains("path depth 3 exceeds the limit of 2"));
+
+    helper.mkdir(&["root".into(), "c".into()]).await.unwrap();
+    let err = helper.ls_files(&["root".into()]).await.unwrap_err();
+    assert!(err.contains("2 entries exceeds the limit of 1"));
+}
+
+#[tokio::test]
+async fn test_export_manifest() {
+    use crate::error::ErrorCode;
+    use crate::private_forest::{DeviceSigningKey, PagedFileOptions, SignedManifest};
+
+    let empty_key: Vec<u8> = vec![0; 32];
+    let store = KVBlockStore::new(String::from("./tmp/test_export_manifest"), CODEC_DAG_CBOR);
+    let blockstore = &mut FFIFriendlyBlockStore::new(Box::new(store));
+    let (helper, _, _) = &mut PrivateDirectoryHelper::init(blockstore, empty_key.to_owned())
+        .await
+        .unwrap();
+    helper
+        .write_file(
+            &["root".into(), "archive".into(), "a.txt".into()],
+            b"first file".to_vec(),
+            0,
+        )
+        .await
+        .unwrap();
+    helper
+        .write_file(
+            &[
+                "root".into(),
+                "archive".into(),
+                "sub".into(),
+                "b.txt".into(),
+            ],
+            b"second file".to_vec(),
+            0,
+        )
+        .await
+        .unwrap();
+
+    let db: Vec<String> = vec!["root".into(), "archive".into(), "app.db".into()];
+    let options = PagedFileOptions {
+        page_size: 16,
+        pages_per_chunk: 2,
+    };
+    helper.create_paged_file(&db, options).await.unwrap();
+    helper.write_at(&db, 40, b"paged content").await.unwrap();
+
+    let archive: Vec<String> = vec!["root".into(), "archive".into()];
+    let signer = DeviceSigningKey::generate().unwrap();
+    let signed = helper.export_manifest(&archive, &signer).await.unwrap();
+    let paths: Vec<&str> = signed
+        .manifest
+        .entries
+        .iter()
+        .map(|entry| entry.path.as_str())
+        .collect();
+    assert_eq!(paths, vec!["a.txt", "app.db", "sub/b.txt"]);
+    assert_eq!(signed.manifest.entries[0].size, 10);
+    // A paged file is one entry of its logical length.
+    assert_eq!(signed.manifest.entries[1].size, 53);
+    assert_eq!(signed.device_did, signer.did().unwrap());
+
+    let parsed = SignedManifest::from_json(&signed.to_json().unwrap()).unwrap();
+    parsed.verify().unwrap();
+    let mut tampered = parsed.to_owned();
+    tampered.manifest.entries[0].size = 11;
+    assert!(tampered.verify().is_err());
+    let mut resigned = parsed.to_owned();
+    resigned.device_did = DeviceSigningKey::generate().unwrap().did().unwrap();
+    assert!(resigned.verify().is_err());
+
+    let export_dir = tempfile::tempdir().unwrap();
+    let export_path = export_dir.path().to_string_lossy().into_owned();
+    helper
+        .materialize(&archive, &export_path, Default::default(), None)
+        .await
+        .unwrap();
+    assert!(parsed.check_dir(export_dir.path()).is_complete());
+
+    std::fs::write(export_dir.path().join("a.txt"), b"changed").unwrap();
+    std::fs::remove_file(export_dir.path().join("sub").join("b.txt")).unwrap();
+    let check = parsed.check_dir(export_dir.path());
+    assert_eq!(check.mismatched, vec!["a.txt".to_string()]);
+    assert_eq!(check.missing, vec!["sub/b.txt".to_string()]);
+
+    // Entry paths and forest names never reach outside the local directory.
+    let mut escaping = parsed.to_owned();
+    escaping.manifest.entries[0].path = "../a.txt".into();
+    let check = escaping.check_dir(&export_dir.path().join("sub"));
+    assert_eq!(check.rejected, vec!["../a.txt".to_string()]);
+    assert!(!check.is_complete());
+    helper
+        .write_file(
+            &[
+                "root".into(),
+                "archive".into(),
+                "..".into(),
+                "escaped.txt".into(),
+            ],
+            b"outside".to_vec(),
+            0,
+        )
+        .await
+        .unwrap();
+    let nested = export_dir.path().join("nested");
+    let err = helper
+        .materialize(
+            &archive,
+            &nested.to_string_lossy().into_owned(),
+            Default::default(),
+            None,
+        )
+        .await
+        .unwrap_err();
+    assert_eq!(ErrorCode::of_message(&err), ErrorCode::InvalidArgument);
+    assert!(!export_dir.path().join("escaped.txt").exists());
+}
+
+#[tokio::test]
+async fn test_legal_hold() {
+    use crate::private_forest::RESERVED_DIR;
+
+    let empty_key: Vec<u8> = vec![0; 32];
+    let store = KVBlockStore::new(String::from("./tmp/test_legal_hold"), CODEC_DAG_CBOR);
+    let blockstore = &mut FFIFriendlyBlockStore::new(Box::new(store));
+    let (helper, _, _) = &mut PrivateDirectoryHelper::init(blockstore, empty_key.to_owned())
+        .await
+        .unwrap();
+    let held: Vec<String> = vec!["root".into(), "records".into()];
+    let file: Vec<String> = vec!["root".into(), "records".into(), "2023.txt".into()];
+    helper
+        .write_file(&file, b"ledger".to_vec(), 0)
+        .await
+        .unwrap();
+
+    let forest_cid = helper
+        .place_legal_hold(&held, "audit", b"custodian")
+        .await
+        .unwrap();
+    assert!(helper
+        .write_file(&file, b"rewritten".to_vec(), 0)
+        .await
+        .unwrap_err()
+        .contains("root/records is under legal hold"));
+    assert!(helper.rm(&file).await.is_err());
+    assert!(helper.rm(&["root".into()]).await.is_err());
+    assert!(helper
+        .mv(&held, &["root".into(), "moved".into()])
+        .await
+        .is_err());
+    assert!(helper.rm(&[RESERVED_DIR.into()]).await.is_err());
+    // Reading and writing outside the hold keep working.
+    assert_eq!(helper.read_file(&file).await.unwrap(), b"ledger".to_vec());
+    helper
+        .write_file(&["root".into(), "other.txt".into()], b"ok".to_vec(), 0)
+        .await
+        .unwrap();
+
+    // The hold is part of the forest and survives a reload.
+    let reloaded = &mut PrivateDirectoryHelper::load_with_wnfs_key(
+        blockstore,
+        forest_cid,
+        empty_key.to_owned(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(reloaded.legal_holds().await.unwrap().len(), 1);
+    assert!(reloaded.rm(&file).await.is_err());
+
+    assert!(helper
+        .release_legal_hold(&held, b"wrong key")
+        .await
+        .is_err());
+    helper
+        .release_legal_hold(&held, b"custodian")
+        .await
+        .unwrap();
+    helper.rm(&file).await.unwrap();
+
+    // With normalization on, another form of a held name is the same held path.
+    use crate::private_forest::{HelperConfig, PathNormalization};
+    helper.set_config(HelperConfig {
+        path_normalization: Some(PathNormalization::Nfc),
+        ..Default::default()
+    });
+    let nfc: Vec<String> = vec!["root".into(), "caf\u{e9}".into()];
+    let nfd: Vec<String> = vec!["root".into(), "cafe\u{301}".into()];
+    helper.mkdir(&nfc).await.unwrap();
+    helper
+        .place_legal_hold(&nfc, "audit", b"custodian")
+        .await
+        .unwrap();
+    let mut nfd_file = nfd.to_owned();
+    nfd_file.push("menu.txt".into());
+    assert!(helper
+        .write_file(&nfd_file, b"espresso".to_vec(), 0)
+        .await
+        .unwrap_err()
+        .contains("is under legal hold"));
+    helper.release_legal_hold(&nfd, b"custodian").await.unwrap();
+    helper
+        .write_file(&nfd_file, b"espresso".to_vec(), 0)
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_open_at_past_root() {
+    let empty_key: Vec<u8> = vec![0; 32];
+    let store = KVBlockStore::new(String::from("./tmp/test_open_at"), CODEC_DAG_CBOR);
+    let blockstore = &mut FFIFriendlyBlockStore::new(Box::new(store));
+    let (helper, _, _) = &mut PrivateDirectoryHelper::init(blockstore, empty_key.to_owned())
+        .await
+        .unwrap();
+    let path: Vec<String> = vec!["root".into(), "notes.txt".into()];
+    let first_cid = helper
+        .write_file(&path, b"tuesday".to_vec(), 0)
+        .await
+        .unwrap();
+    helper
+        .write_file(&path, b"today".to_vec(), 0)
+        .await
+        .unwrap();
+    helper
+        .write_file(&["root".into(), "new.txt".into()], b"new".to_vec(), 0)
+        .await
+        .unwrap();
+
+    let past = &mut helper.open_at(first_cid).await.unwrap();
+    assert_eq!(past.root_cid(), first_cid);
+    assert_eq!(past.read_file(&path).await.unwrap(), b"tuesday".to_vec());
+    assert!(!past
+        .exists(&["root".into(), "new.txt".into()])
+        .await
+        .unwrap());
+    assert_eq!(past.ls_files(&["root".into()]).await.unwrap().len(), 1);
+    assert_eq!(helper.read_file(&path).await.unwrap(), b"today".to_vec());
+}
+
+#[tokio::test]
+async fn test_copy_between_forests() {
+    let src_store = KVBlockStore::new(String::from("./tmp/test_copy_between_src"), CODEC_DAG_CBOR);
+    let src_blockstore = &mut FFIFriendlyBlockStore::new(Box::new(src_store));
+    let (src, _, _) = &mut PrivateDirectoryHelper::init(src_blockstore, vec![1; 32])
+        .await
+        .unwrap();
+    let dst_store = KVBlockStore::new(String::from("./tmp/test_copy_between_dst"), CODEC_DAG_CBOR);
+    let dst_blockstore = &mut FFIFriendlyBlockStore::new(Box::new(dst_store));
+    let (dst, _, _) = &mut PrivateDirectoryHelper::init(dst_blockstore, vec![2; 32])
+        .await
+        .unwrap();
+
+    let large = generate_dummy_data(3 * 1024 * 1024);
+    src.write_file(
+        &["root".into(), "album".into(), "big.bin".into()],
+        large.to_owned(),
+        0,
+    )
+    .await
+    .unwrap();
+    src.write_file(
+        &[
+            "root".into(),
+            "album".into(),
+            "sub".into(),
+            "small.txt".into(),
+        ],
+        b"small".to_vec(),
+        1_700_000_000,
+    )
+    .await
+    .unwrap();
+
+    PrivateDirectoryHelper::copy_between(
+        src,
+        &["root".into(), "album".into()],
+        dst,
+        &["root".into(), "migrated".into()],
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(
+        dst.read_file(&["root".into(), "migrated".into(), "big.bin".into()])
+            .await
+            .unwrap(),
+        large
+    );
+    let listing = dst
+        .ls_files(&["root".into(), "migrated".into(), "sub".into()])
+        .await
+        .unwrap();
+    assert_eq!(listing[0].0, "small.txt");
+    assert_eq!(
+        listing[0].1.get_modified().map(|time| time.timestamp()),
+        Some(1_700_000_000)
+    );
+    // A single file copies to exactly the destination path.
+    PrivateDirectoryHelper::copy_between(
+        src,
+        &[
+            "root".into(),
+            "album".into(),
+            "sub".into(),
+            "small.txt".into(),
+        ],
+        dst,
+        &["root".into(), "single.txt".into()],
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        dst.read_file(&["root".into(), "single.txt".into()])
+            .await
+            .unwrap(),
+        b"small".to_vec()
+    );
+
+    // Paged files arrive as their logical content, and the reserved directory stays behind.
+    use crate::private_forest::{PagedFileOptions, RESERVED_DIR};
+    let db: Vec<String> = vec!["root".into(), "db".into(), "app.db".into()];
+    let options = PagedFileOptions {
+        page_size: 16,
+        pages_per_chunk: 2,
+    };
+    src.create_paged_file(&db, options).await.unwrap();
+    src.write_at(&db, 40, b"paged content").await.unwrap();
+    src.place_legal_hold(&["root".into(), "album".into()], "audit", b"custodian")
+        .await
+        .unwrap();
+    PrivateDirectoryHelper::copy_between(src, &[], dst, &["everything".into()])
+        .await
+        .unwrap();
+    let copied_db: Vec<String> = vec![
+        "everything".into(),
+        "root".into(),
+        "db".into(),
+        "app.db".into(),
+    ];
+    let copied = dst.read_file(&copied_db).await.unwrap();
+    assert_eq!(copied, src.read_file(&db).await.unwrap());
+    assert!(!dst.is_paged_file(&copied_db).await.unwrap());
+    let names: Vec<String> = dst
+        .ls_files(&["everything".into()])
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect();
+    assert_eq!(names, vec!["root".to_string()]);
+}
+
+#[tokio::test]
+async fn test_export_and_import_account() {
+    use crate::error::ErrorCode;
+    use crate::private_forest::{AccountBundle, HelperConfig, NamePrivacy};
+
+    let key: Vec<u8> = vec![3; 32];
+    let store = KVBlockStore::new(String::from("./tmp/test_export_account"), CODEC_DAG_CBOR);
+    let blockstore = &mut FFIFriendlyBlockStore::new(Box::new(store));
+    let (helper, _, _) = &mut PrivateDirectoryHelper::init(blockstore, key.to_owned())
+        .await
+        .unwrap();
+    helper.set_config(HelperConfig {
+        name_privacy: vec![(vec!["root".into()], NamePrivacy::Indexed)],
+        ..Default::default()
+    });
+    helper
+        .write_file(&["root".into(), "a.txt".into()], b"backed up".to_vec(), 0)
+        .await
+        .unwrap();
+    let history = helper.root_history().to_vec();
+    assert_eq!(history.len(), 2);
+
+    let bundle = helper.export_account("correct horse", true).await.unwrap();
+    let bundle = AccountBundle::from_bytes(bundle.into_bytes());
+
+    let empty_store = KVBlockStore::new(String::from("./tmp/test_import_account"), CODEC_DAG_CBOR);
+    let empty_blockstore = &mut FFIFriendlyBlockStore::new(Box::new(empty_store));
+    assert!(
+        PrivateDirectoryHelper::import_account(empty_blockstore, &bundle, "wrong")
+            .await
+            .is_err()
+    );
+    let restored =
+        &mut PrivateDirectoryHelper::import_account(empty_blockstore, &bundle, "correct horse")
+            .await
+            .unwrap();
+    assert_eq!(
+        restored
+            .read_file(&["root".into(), "a.txt".into()])
+            .await
+            .unwrap(),
+        b"backed up".to_vec()
+    );
+    assert_eq!(restored.root_history(), history.as_slice());
+    for root in history.iter() {
+        assert_eq!(
+            restored.root_committed_at.get(root),
+            helper.root_committed_at.get(root)
+        );
+    }
+    assert_eq!(
+        restored.config().name_privacy,
+        vec![(vec!["root".to_string()], NamePrivacy::Indexed)]
+    );
+    // The restored forest is open for writing, so nobody else can take it.
+    let err = PrivateDirectoryHelper::load_exclusive(empty_blockstore, history[1], key.to_owned())
+        .await
+        .err()
+        .unwrap();
+    assert_eq!(ErrorCode::of_message(&err), ErrorCode::Conflict);
+}
+
+#[tokio::test]
+async fn test_directory_sharding() {
+    use crate::private_forest::{DirectorySharding, HelperConfig, SHARD_MARKER};
+
+    let key: Vec<u8> = vec![4; 32];
+    let store = KVBlockStore::new(
+        String::from("./tmp/test_directory_sharding"),
+        CODEC_DAG_CBOR,
+    );
+    let blockstore = &mut FFIFriendlyBlockStore::new(Box::new(store));
+    let (helper, _, _) = &mut PrivateDirectoryHelper::init(blockstore, key.to_owned())
+        .await
+        .unwrap();
+    helper.set_config(HelperConfig {
+        directory_sharding: Some(DirectorySharding { split_threshold: 8 }),
+        ..Default::default()
+    });
+    let names: Vec<String> = (0..20).map(|i| format!("file{}.txt", i)).collect();
+    for name in names.iter() {
+        helper
+            .write_file(
+                &["flat".into(), name.to_owned()],
+                name.as_bytes().to_vec(),
+                0,
+            )
+            .await
+            .unwrap();
+    }
+
+    let mut listed: Vec<String> = helper
+        .ls_files(&["flat".into()])
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect();
+    listed.sort();
+    let mut expected = names.to_owned();
+    expected.sort();
+    assert_eq!(listed, expected);
+    for name in names.iter() {
+        assert_eq!(
+            helper
+                .read_file(&["flat".into(), name.to_owned()])
+                .await
+                .unwrap(),
+            name.as_bytes().to_vec()
+        );
+    }
+
+    // The split is stored in the forest, so it's followed with sharding disabled too.
+    let resolved = helper
+        .resolve_path(&["flat".into(), "file0.txt".into()])
+        .await
+        .unwrap();
+    assert_eq!(resolved.len(), 3);
+    // Entries not stored yet resolve into their shard as well, and nothing below them is.
+    let resolved = helper
+        .resolve_path(&["flat".into(), "new".into(), "note.txt".into()])
+        .await
+        .unwrap();
+    assert_eq!(resolved.len(), 4);
+    assert_eq!(resolved[2..], ["new".to_string(), "note.txt".to_string()]);
+    assert!(helper
+        .ls_raw(&["flat".into()])
+        .await
+        .unwrap()
+        .iter()
+        .any(|(name, _)| name == SHARD_MARKER));
+    helper.set_config(HelperConfig::default());
+    assert_eq!(
+        helper.ls_files(&["flat".into()]).await.unwrap().len(),
+        names.len()
+    );
+    assert_eq!(
+        helper
+            .read_file(&["flat".into(), "file5.txt".into()])
+            .await
+            .unwrap(),
+        b"file5.txt".to_vec()
+    );
+    helper.set_config(HelperConfig {
+        directory_sharding: Some(DirectorySharding { split_threshold: 8 }),
+        ..Default::default()
+    });
+
+    helper
+        .mv(
+            &["flat".into(), "file0.txt".into()],
+            &["flat".into(), "renamed.txt".into()],
+        )
+        .await
+        .unwrap();
+    helper
+        .rm(&["flat".into(), "file1.txt".into()])
+        .await
+        .unwrap();
+    let listed: Vec<String> = helper
+        .ls_files(&["flat".into()])
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect();
+    assert_eq!(listed.len(), names.len() - 1);
+    assert!(listed.contains(&"renamed.txt".to_string()));
+    assert!(!listed.contains(&"file1.txt".to_string()));
+    assert_eq!(
+        helper
+            .read_file(&["flat".into(), "renamed.txt".into()])
+            .await
+            .unwrap(),
+        b"file0.txt".to_vec()
+    );
+}
+
+#[tokio::test]
+async fn test_ls_changes() {
+    let key: Vec<u8> = vec![5; 32];
+    let store = KVBlockStore::new(String::from("./tmp/test_ls_changes"), CODEC_DAG_CBOR);
+    let blockstore = &mut FFIFriendlyBlockStore::new(Box::new(store));
+    let (helper, _, _) = &mut PrivateDirectoryHelper::init(blockstore, key.to_owned())
+        .await
+        .unwrap();
+    let since = helper
+        .write_file(
+            &["root".into(), "a.txt".into()],
+            b"a".to_vec(),
+            1_700_000_000,
+        )
+        .await
+        .unwrap();
+    helper
+        .write_file(&["root".into(), "b.txt".into()], b"b".to_vec(), 0)
+        .await
+        .unwrap();
+    let since = helper
+        .ls_changes(&["root".into()], since)
+        .await
+        .map(|changes| {
+            assert_eq!(changes.added.len(), 1);
+            assert_eq!(changes.added[0].0, "b.txt");
+            changes.revision.unwrap()
+        })
+        .unwrap();
+
+    helper
+        .write_file(
+            &["root".into(), "a.txt".into()],
+            b"a2".to_vec(),
+            1_700_000_100,
+        )
+        .await
+        .unwrap();
+    helper
+        .write_file(&["root".into(), "c.txt".into()], b"c".to_vec(), 0)
+        .await
+        .unwrap();
+    helper.rm(&["root".into(), "b.txt".into()]).await.unwrap();
+    let changes = helper.ls_changes(&["root".into()], since).await.unwrap();
+    assert_eq!(
+        changes
+            .added
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect::<Vec<_>>(),
+        vec!["c.txt"]
+    );
+    assert_eq!(changes.removed, vec!["b.txt".to_string()]);
+    assert_eq!(
+        changes
+            .modified
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect::<Vec<_>>(),
+        vec!["a.txt"]
+    );
+
+    let revision = changes.revision.unwrap();
+    assert!(helper
+        .ls_changes(&["root".into()], revision)
+        .await
+        .unwrap()
+        .is_empty());
+}
+
+#[tokio::test]
+async fn test_vfs_operations() {
+    use crate::vfs::{OpenOptions, Vfs, VfsNodeKind};
+
+    let key: Vec<u8> = vec![6; 32];
+    let store = KVBlockStore::new(String::from("./tmp/test_vfs"), CODEC_DAG_CBOR);
+    let blockstore = &mut FFIFriendlyBlockStore::new(Box::new(store));
+    let (helper, _, _) = &mut PrivateDirectoryHelper::init(blockstore, key.to_owned())
+        .await
+        .unwrap();
+    let vfs: &mut dyn Vfs = helper;
+    let path: Vec<String> = vec!["docs".into(), "notes.txt".into()];
+
+    assert!(vfs.open(&path, OpenOptions::default()).await.is_err());
+    Vfs::mkdir(vfs, &["docs".into()]).await.unwrap();
+    let handle = vfs
+        .open(
+            &path,
+            OpenOptions {
+                write: true,
+                create: true,
+                truncate: false,
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+    vfs.write(&handle, 0, b"hello world").await.unwrap();
+    vfs.write(&handle, 6, b"wnfs!").await.unwrap();
+    assert_eq!(vfs.read(&handle, 6, 100).await.unwrap(), b"wnfs!".to_vec());
+    assert_eq!(vfs.read(&handle, 100, 10).await.unwrap(), Vec::<u8>::new());
+    assert_eq!(
+        vfs.read(&handle, u64::MAX, usize::MAX).await.unwrap(),
+        Vec::<u8>::new()
+    );
+    // Offsets past the bound of whole-file rewrites fail instead of allocating or overflowing.
+    assert!(vfs.write(&handle, u64::MAX, b"x").await.is_err());
+    let err = vfs
+        .write(&handle, crate::private_forest::VFS_MAX_FILE_SIZE, b"x")
+        .await
+        .unwrap_err();
+    assert!(err.contains("exceeds the limit"));
+    assert!(vfs.set_len(&handle, u64::MAX).await.is_err());
+
+    let stat = vfs.stat(&path).await.unwrap().unwrap();
+    assert_eq!(stat.kind, VfsNodeKind::File);
+    assert_eq!(stat.size, 11);
+    let listing = vfs.readdir(&["docs".into()]).await.unwrap();
+    assert_eq!(listing.len(), 1);
+    assert_eq!(listing[0].0, "notes.txt");
+
+    let read_only = vfs.open(&path, OpenOptions::default()).await.unwrap();
+    assert!(vfs.write(&read_only, 0, b"x").await.is_err());
+
+    let renamed: Vec<String> = vec!["docs".into(), "renamed.txt".into()];
+    vfs.rename(&path, &renamed).await.unwrap();
+    assert!(vfs.stat(&path).await.unwrap().is_none());
+    vfs.remove(&renamed).await.unwrap();
+    assert!(vfs.readdir(&["docs".into()]).await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn test_documents_provider_queries() {
+    use crate::private_forest::MIME_TYPE_DIR;
+
+    let key: Vec<u8> = vec![8; 32];
+    let store = KVBlockStore::new(String::from("./tmp/test_documents"), CODEC_DAG_CBOR);
+    let blockstore = &mut FFIFriendlyBlockStore::new(Box::new(store));
+    let (helper, _, _) = &mut PrivateDirectoryHelper::init(blockstore, key.to_owned())
+        .await
+        .unwrap();
+    let photos = helper
+        .create_document("/", MIME_TYPE_DIR, "photos")
+        .await
+        .unwrap();
+    assert_eq!(photos, "/photos");
+    for i in 0..5 {
+        helper
+            .write_file(
+                &["photos".into(), format!("img{}.jpg", i)],
+                vec![i; 10],
+                1_700_000_000,
+            )
+            .await
+            .unwrap();
+    }
+    assert!(helper
+        .create_document(&photos, "image/jpeg", "img0.jpg")
+        .await
+        .is_err());
+
+    let first = helper.query_child_documents(&photos, 0, 2).await.unwrap();
+    assert_eq!(first.total, 5);
+    assert!(first.has_more);
+    assert_eq!(first.rows[0].document_id, "/photos/img0.jpg");
+    assert_eq!(first.rows[0].mime_type, "image/jpeg");
+    assert_eq!(first.rows[0].size, 10);
+    assert_eq!(first.rows[0].last_modified, Some(1_700_000_000_000));
+    let last = helper.query_child_documents(&photos, 4, 2).await.unwrap();
+    assert_eq!(last.rows.len(), 1);
+    assert!(!last.has_more);
+
+    let root = helper.query_document("/").await.unwrap();
+    assert_eq!(root.mime_type, MIME_TYPE_DIR);
+
+    let renamed = helper
+        .rename_document("/photos/img4.jpg", "cover.png")
+        .await
+        .unwrap();
+    assert_eq!(renamed, "/photos/cover.png");
+    let out = NamedTempFile::new().unwrap();
+    let out_path = out.path().to_string_lossy().to_string();
+    helper.open_document(&renamed, &out_path).await.unwrap();
+    assert_eq!(read(out.path()).unwrap(), vec![4u8; 10]);
+    helper.delete_document(&renamed).await.unwrap();
+    assert!(helper.query_document(&renamed).await.is_err());
+}
+
+#[tokio::test]
+async fn test_file_provider_enumeration() {
+    use crate::private_forest::ROOT_CONTAINER_IDENTIFIER;
+
+    let key: Vec<u8> = vec![9; 32];
+    let store = KVBlockStore::new(String::from("./tmp/test_file_provider"), CODEC_DAG_CBOR);
+    let blockstore = &mut FFIFriendlyBlockStore::new(Box::new(store));
+    let (helper, _, _) = &mut PrivateDirectoryHelper::init(blockstore, key.to_owned())
+        .await
+        .unwrap();
+    for name in ["a.txt", "b.txt", "c.txt"] {
+        helper
+            .write_file(&[name.into()], name.as_bytes().to_vec(), 0)
+            .await
+            .unwrap();
+    }
+
+    let first = helper
+        .enumerate_items(ROOT_CONTAINER_IDENTIFIER, None, 2)
+        .await
+        .unwrap();
+    assert_eq!(first.items.len(), 2);
+    assert_eq!(first.items[0].identifier, "a.txt");
+    assert_eq!(first.items[0].parent_identifier, ROOT_CONTAINER_IDENTIFIER);
+    let second = helper
+        .enumerate_items(ROOT_CONTAINER_IDENTIFIER, first.next_page.as_deref(), 2)
+        .await
+        .unwrap();
+    assert_eq!(second.items.len(), 1);
+    assert_eq!(second.next_page, None);
+
+    // Same size and modification time, different content: the version still changes.
+    helper
+        .write_file(&["a.txt".into()], b"z.txt".to_vec(), 0)
+        .await
+        .unwrap();
+    let rewritten = helper.item("a.txt").await.unwrap();
+    assert_eq!(rewritten.size, first.items[0].size);
+    assert_eq!(rewritten.modified, first.items[0].modified);
+    assert_ne!(rewritten.version, first.items[0].version);
+    assert_eq!(
+        helper.item("a.txt").await.unwrap().version,
+        rewritten.version
+    );
+
+    let anchor = helper.current_sync_anchor().unwrap();
+    helper.rm(&["b.txt".into()]).await.unwrap();
+    helper
+        .write_file(&["d.txt".into()], b"d".to_vec(), 0)
+        .await
+        .unwrap();
+    let changes = helper
+        .enumerate_changes(ROOT_CONTAINER_IDENTIFIER, &anchor)
+        .await
+        .unwrap();
+    assert_eq!(changes.deleted, vec!["b.txt".to_string()]);
+    assert_eq!(changes.updated.len(), 1);
+    assert_eq!(changes.updated[0].identifier, "d.txt");
+    assert_eq!(changes.sync_anchor, helper.current_sync_anchor().unwrap());
+
+    let content: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+    helper
+        .write_file(&["big.bin".into()], content.to_owned(), 0)
+        .await
+        .unwrap();
+    let out = NamedTempFile::new().unwrap();
+    let out_path = out.path().to_string_lossy().to_string();
+    let written = helper
+        .fetch_partial_contents("big.bin", 150_000, 100_000, &out_path)
+        .await
+        .unwrap();
+    assert_eq!(written, 50_000);
+    assert_eq!(read(out.path()).unwrap(), content[150_000..].to_vec());
+}
+
+#[tokio::test]
+async fn test_hash_file_matches_local_copy() {
+    use crate::private_forest::HashAlgorithm;
+    use sha2::{Digest, Sha256};
+
+    let dir = tempfile::tempdir().unwrap();
+    let store = KVBlockStore::new(
+        dir.path().join("store").to_string_lossy().to_string(),
+        CODEC_DAG_CBOR,
+    );
+    let blockstore = &mut FFIFriendlyBlockStore::new(Box::new(store));
+    let (helper, _, _) = &mut PrivateDirectoryHelper::init(blockstore, vec![0; 32])
+        .await
+        .unwrap();
+
+    let content: Vec<u8> = (0..300_000u32).map(|i| (i % 253) as u8).collect();
+    let path: Vec<String> = vec!["mirror".into(), "data.bin".into()];
+    helper
+        .write_file(&path, content.to_owned(), 0)
+        .await
+        .unwrap();
+    let local = dir.path().join("data.bin");
+    std::fs::write(&local, &content).unwrap();
+
+    let sha256 = helper
+        .hash_file(&path, HashAlgorithm::Sha256)
+        .await
+        .unwrap();
+    assert_eq!(sha256, Sha256::digest(&content).to_vec());
+    assert_eq!(
+        sha256,
+        HashAlgorithm::Sha256.hash_local_file(&local).unwrap()
+    );
+
+    let blake3 = helper
+        .hash_file(&path, HashAlgorithm::Blake3)
+        .await
+        .unwrap();
+    assert_eq!(blake3, blake3::hash(&content).as_bytes().to_vec());
+    assert_eq!(
+        blake3,
+        HashAlgorithm::Blake3.hash_local_file(&local).unwrap()
+    );
+
+    assert!(helper
+        .hash_file(&["mirror".into()], HashAlgorithm::Sha256)
+        .await
+        .is_err());
+
+    // Paged files hash to the digest of their logical content.
+    let paged: Vec<String> = vec!["mirror".into(), "paged.bin".into()];
+    helper
+        .create_paged_file(&paged, Default::default())
+        .await
+        .unwrap();
+    helper.write_at(&paged, 0, &content).await.unwrap();
+    assert_eq!(
+        helper
+            .hash_file(&paged, HashAlgorithm::Blake3)
+            .await
+            .unwrap(),
+        blake3
+    );
+}
+
+#[tokio::test]
+async fn test_write_file_delta() {
+    use crate::private_forest::DELTA_BLOCK_SIZE;
+
+    let dir = tempfile::tempdir().unwrap();
+    let store = KVBlockStore::new(
+        dir.path().join("store").to_string_lossy().to_string(),
+        CODEC_DAG_CBOR,
+    );
+    let blockstore = &mut FFIFriendlyBlockStore::new(Box::new(store));
+    let (helper, _, _) = &mut PrivateDirectoryHelper::init(blockstore, vec![0; 32])
+        .await
+        .unwrap();
+
+    let mut content: Vec<u8> = (0..5 * DELTA_BLOCK_SIZE as u32 + 1234)
+        .map(|i| (i % 251) as u8 ^ (i / 4096) as u8)
+        .collect();
+    let local = dir.path().join("db.sqlite");
+    let local_name = local.to_string_lossy().to_string();
+    std::fs::write(&local, &content).unwrap();
+    let path: Vec<String> = vec!["db.sqlite".into()];
+
+    let first = helper.write_file_delta(&path, &local_name).await.unwrap();
+    assert_eq!(first.literal_bytes, content.len() as u64);
+    assert!(first.written.is_some());
+
+    let same = helper.write_file_delta(&path, &local_name).await.unwrap();
+    assert!(same.unchanged);
+    assert_eq!(same.literal_bytes, 0);
+    assert_eq!(same.written, None);
+
+    // Insert a few bytes in the middle: everything else is found by the rolling checksum.
+    content.splice(
+        2 * DELTA_BLOCK_SIZE + 10..2 * DELTA_BLOCK_SIZE + 10,
+        [1, 2, 3],
+    );
+    std::fs::write(&local, &content).unwrap();
+    let edited = helper.write_file_delta(&path, &local_name).await.unwrap();
+    assert!(!edited.unchanged);
+    assert!(edited.written.is_some());
+    assert_eq!(edited.matched_bytes, 4 * DELTA_BLOCK_SIZE as u64 + 1234);
+    assert_eq!(edited.literal_bytes, DELTA_BLOCK_SIZE as u64 + 3);
+    assert_eq!(helper.read_file(&path).await.unwrap(), content);
+}
+
+#[tokio::test]
+async fn test_write_file_delta_rewrites_changed_chunks_of_paged_files() {
+    use crate::private_forest::PagedFileOptions;
+
+    let dir = tempfile::tempdir().unwrap();
+    let store = KVBlockStore::new(
+        dir.path().join("store").to_string_lossy().to_string(),
+        CODEC_DAG_CBOR,
+    );
+    let blockstore = &mut FFIFriendlyBlockStore::new(Box::new(store));
+    let (helper, _, _) = &mut PrivateDirectoryHelper::init(blockstore, vec![0; 32])
+        .await
+        .unwrap();
+
+    let options = PagedFileOptions::default();
+    let chunk_size = (options.page_size * options.pages_per_chunk) as usize;
+    let path: Vec<String> = vec!["app.db".into()];
+    let mut content: Vec<u8> = (0..4 * chunk_size as u32 + 100)
+        .map(|i| (i % 251) as u8)
+        .collect();
+    helper.create_paged_file(&path, options).await.unwrap();
+    helper.write_at(&path, 0, &content).await.unwrap();
+    let local = dir.path().join("app.db");
+    let local_name = local.to_string_lossy().to_string();
+    std::fs::write(&local, &content).unwrap();
+
+    let same = helper.write_file_delta(&path, &local_name).await.unwrap();
+    assert!(same.unchanged);
+    assert_eq!(same.written, None);
+
+    let chunk_version = |index: u64| format!("app.db/{:016x}", index);
+    let first_chunk = helper.item(&chunk_version(0)).await.unwrap().version;
+    let third_chunk = helper.item(&chunk_version(2)).await.unwrap().version;
+    content[2 * chunk_size + 7] ^= 0xff;
+    std::fs::write(&local, &content).unwrap();
+    let edited = helper.write_file_delta(&path, &local_name).await.unwrap();
+    assert!(!edited.unchanged);
+    assert!(edited.written.is_some());
+    assert_eq!(edited.literal_bytes, chunk_size as u64);
+    assert_eq!(edited.matched_bytes, 3 * chunk_size as u64 + 100);
+    // Only the edited chunk was re-encrypted.
+    assert_eq!(
+        helper.item(&chunk_version(0)).await.unwrap().version,
+        first_chunk
+    );
+    assert_ne!(
+        helper.item(&chunk_version(2)).await.unwrap().version,
+        third_chunk
+    );
+    assert!(helper.is_paged_file(&path).await.unwrap());
+    assert_eq!(helper.read_file(&path).await.unwrap(), content);
+
+    content.truncate(chunk_size + 10);
+    std::fs::write(&local, &content).unwrap();
+    let truncated = helper.write_file_delta(&path, &local_name).await.unwrap();
+    assert_eq!(truncated.literal_bytes, 0);
+    assert!(!truncated.unchanged);
+    assert_eq!(
+        helper.paged_file_len(&path).await.unwrap(),
+        content.len() as u64
+    );
+    assert_eq!(helper.read_file(&path).await.unwrap(), content);
+}
+
+#[tokio::test]
+async fn test_paged_file_write_at() {
+    use crate::error::ErrorCode;
+    use crate::private_forest::{PagedFileOptions, PAGED_MARKER, SHARD_MARKER};
+
+    let dir = tempfile::tempdir().unwrap();
+    let store = KVBlockStore::new(
+        dir.path().join("store").to_string_lossy().to_string(),
+        CODEC_DAG_CBOR,
+    );
+    let blockstore = &mut FFIFriendlyBlockStore::new(Box::new(store));
+    let (helper, _, _) = &mut PrivateDirectoryHelper::init(blockstore, vec![0; 32])
+        .await
+        .unwrap();
+
+    let path: Vec<String> = vec!["app".into(), "app.db".into()];
+    let options = PagedFileOptions {
+        page_size: 4096,
+        pages_per_chunk: 2,
+    };
+    helper.create_paged_file(&path, options).await.unwrap();
+    assert!(helper.is_paged_file(&path).await.unwrap());
+    assert!(helper.create_paged_file(&path, options).await.is_err());
+
+    // Page 3 lives in the second chunk; pages 0..2 were never written.
+    helper
+        .write_at(&path, 3 * 4096, &[7u8; 4096])
+        .await
+        .unwrap();
+    assert_eq!(helper.paged_file_len(&path).await.unwrap(), 4 * 4096);
+    let zeros = helper.read_at(&path, 0, 4096).await.unwrap();
+    assert_eq!(zeros, vec![0u8; 4096]);
+
+    // A write spanning both chunks.
+    helper.write_at(&path, 8190, b"spanning").await.unwrap();
+    assert_eq!(
+        helper.read_at(&path, 8190, 8).await.unwrap(),
+        b"spanning".to_vec()
+    );
+    assert_eq!(
+        helper.read_at(&path, 3 * 4096, 2).await.unwrap(),
+        vec![7, 7]
+    );
+    assert_eq!(
+        helper.read_at(&path, 4 * 4096 - 1, 10).await.unwrap(),
+        vec![7]
+    );
+
+    helper.set_paged_len(&path, 8192).await.unwrap();
+    assert_eq!(helper.paged_file_len(&path).await.unwrap(), 8192);
+    helper.set_paged_len(&path, 4 * 4096).await.unwrap();
+    assert_eq!(
+        helper.read_at(&path, 8190, 8).await.unwrap(),
+        vec![b's', b'p', 0, 0, 0, 0, 0, 0]
+    );
+
+    assert!(helper.read_at(&["app".into()], 0, 1).await.is_err());
+
+    // A forged layout fails with a coded error instead of dividing by zero.
+    let marker: Vec<String> = vec!["app".into(), "app.db".into(), PAGED_MARKER.into()];
+    helper
+        .write_raw(
+            &marker,
+            br#"{"options":{"page_size":0,"pages_per_chunk":2},"len":16384}"#.to_vec(),
+        )
+        .await
+        .unwrap();
+    let err = helper.read_at(&path, 0, usize::MAX).await.unwrap_err();
+    assert_eq!(ErrorCode::of_message(&err), ErrorCode::Corrupt);
+
+    // The marker names are the helper's to write.
+    for name in [PAGED_MARKER, SHARD_MARKER, ".wnfsutils-shard-0a"] {
+        let err = helper
+            .write_file(&["docs".into(), name.into()], b"x".to_vec(), 0)
+            .await
+            .unwrap_err();
+        assert_eq!(ErrorCode::of_message(&err), ErrorCode::InvalidArgument);
+    }
+    assert!(!helper.is_paged_file(&["docs".into()]).await.unwrap());
+}
+
+#[tokio::test]
+async fn test_vfs_paged_files() {
+    use crate::vfs::{OpenOptions, Vfs, VfsNodeKind};
+
+    let dir = tempfile::tempdir().unwrap();
+    let store = KVBlockStore::new(
+        dir.path().join("store").to_string_lossy().to_string(),
+        CODEC_DAG_CBOR,
+    );
+    let blockstore = &mut FFIFriendlyBlockStore::new(Box::new(store));
+    let (helper, _, _) = &mut PrivateDirectoryHelper::init(blockstore, vec![0; 32])
+        .await
+        .unwrap();
+    let vfs: &mut dyn Vfs = helper;
+    let path: Vec<String> = vec!["app.db".into()];
+
+    let handle = vfs
+        .open(
+            &path,
+            OpenOptions {
+                write: true,
+                create: true,
+                paged: true,
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+    vfs.write(&handle, 8192, b"page two").await.unwrap();
+    assert_eq!(vfs.read(&handle, 8192, 4).await.unwrap(), b"page".to_vec());
+    let stat = vfs.stat(&path).await.unwrap().unwrap();
+    assert_eq!(stat.kind, VfsNodeKind::File);
+    assert_eq!(stat.size, 8200);
+    let listing = vfs.readdir(&[]).await.unwrap();
+    assert_eq!(listing[0].1.kind, VfsNodeKind::File);
+
+    vfs.set_len(&handle, 4096).await.unwrap();
+    assert_eq!(vfs.stat(&path).await.unwrap().unwrap().size, 4096);
+}
+
+#[test]
+fn test_synced_errors_carry_the_request_id() {
+    let dir = tempfile::tempdir().unwrap();
+    let store = KVBlockStore::new(
+        dir.path().join("store").to_string_lossy().to_string(),
+        CODEC_DAG_CBOR,
+    );
+    let blockstore = &mut FFIFriendlyBlockStore::new(Box::new(store));
+    let (mut helper, _, _) = PrivateDirectoryHelper::synced_init(blockstore, vec![0; 32]).unwrap();
+
+    let _scope = crate::request_id::enter("support-42".into());
+    let error = helper
+        .synced_read_file(&["missing.txt".into()])
+        .unwrap_err();
+    assert!(error.ends_with("(request support-42)"));
+}
+
+#[tokio::test]
+async fn test_exclusive_session_locks_the_forest() {
+    use crate::error::WnfsUtilsError;
+
+    let dir = tempfile::tempdir().unwrap();
+    let store = KVBlockStore::new(
+        dir.path().join("store").to_string_lossy().to_string(),
+        CODEC_DAG_CBOR,
+    );
+    let blockstore = &mut FFIFriendlyBlockStore::new(Box::new(store));
+    let wnfs_key = vec![7; 32];
+    let (mut session, _, _) =
+        PrivateDirectoryHelper::init_exclusive(blockstore, wnfs_key.to_owned())
+            .await
+            .unwrap();
+    let path = vec!["notes.txt".to_string()];
+    let cid = session
+        .write_file(&path, b"first".to_vec(), 0)
+        .await
+        .unwrap();
+
+    let second = PrivateDirectoryHelper::load_exclusive(blockstore, cid, wnfs_key.to_owned()).await;
+    assert_eq!(second.err(), Some(WnfsUtilsError::ForestLocked.to_string()));
+
+    let mut reader = session.shared().await.unwrap();
+    assert_eq!(reader.root_cid(), cid);
+    assert_eq!(reader.read_file(&path).await.unwrap(), b"first".to_vec());
+    let mut loaded = PrivateDirectoryHelper::load_shared(blockstore, cid, wnfs_key.to_owned())
+        .await
+        .unwrap();
+    assert!(loaded.exists(&path).await.unwrap());
+
+    drop(session);
+    assert!(
+        PrivateDirectoryHelper::load_exclusive(blockstore, cid, wnfs_key)
+            .await
+            .is_ok()
+    );
+}
+
+#[tokio::test]
+async fn test_idempotent_mutation_runs_once() {
+    use std::cell::Cell;
+
+    let dir = tempfile::tempdir().unwrap();
+    let store = KVBlockStore::new(
+        dir.path().join("store").to_string_lossy().to_string(),
+        CODEC_DAG_CBOR,
+    );
+    let blockstore = &mut FFIFriendlyBlockStore::new(Box::new(store));
+    let (mut helper, _, _) = PrivateDirectoryHelper::init(blockstore, vec![0; 32])
+        .await
+        .unwrap();
+    let path = vec!["photos".to_string()];
+    let runs = Cell::new(0);
+
+    let first = helper
+        .idempotent(Some("mkdir-1"), "mkdir", |helper| {
+            runs.set(runs.get() + 1);
+            Box::pin(helper.mkdir(&path))
+        })
+        .await
+        .unwrap();
+    let mut reloaded = PrivateDirectoryHelper::load_with_wnfs_key(blockstore, first, vec![0; 32])
+        .await
+        .unwrap();
+    let retried = reloaded
+        .idempotent(Some("mkdir-1"), "mkdir", |helper| {
+            runs.set(runs.get() + 1);
+            Box::pin(helper.mkdir(&path))
+        })
+        .await
+        .unwrap();
+    assert_eq!(retried, first);
+    assert_eq!(runs.get(), 1);
+
+    // Later mutations don't change what a duplicate returns, here or after reloading.
+    let content = b"later".to_vec();
+    let second = reloaded
+        .idempotent(Some("write-1"), "write_file", |helper| {
+            Box::pin(helper.write_file(&["later.txt".to_string()], content, 0))
+        })
+        .await
+        .unwrap();
+    reloaded.mkdir(&["unkeyed".to_string()]).await.unwrap();
+    let retried = reloaded
+        .idempotent(Some("write-1"), "write_file", |helper| {
+            runs.set(runs.get() + 1);
+            Box::pin(helper.mkdir(&path))
+        })
+        .await
+        .unwrap();
+    assert_eq!(retried, second);
+    let third = reloaded
+        .idempotent(Some("mkdir-2"), "mkdir", |helper| {
+            Box::pin(helper.mkdir(&["third".to_string()]))
+        })
+        .await
+        .unwrap();
+    let mut reloaded = PrivateDirectoryHelper::load_with_wnfs_key(blockstore, third, vec![0; 32])
+        .await
+        .unwrap();
+    let retried = reloaded
+        .idempotent(Some("write-1"), "write_file", |helper| {
+            runs.set(runs.get() + 1);
+            Box::pin(helper.mkdir(&path))
+        })
+        .await
+        .unwrap();
+    assert_eq!(retried, second);
+    assert_eq!(runs.get(), 1);
+
+    let reused = reloaded
+        .idempotent(Some("mkdir-1"), "rm", |helper| Box::pin(helper.rm(&path)))
+        .await;
+    assert!(reused.is_err());
+    let listing = reloaded.ls_files(&[]).await.unwrap();
+    assert!(listing.iter().any(|(name, _)| name == "photos"));
+}
+
+#[tokio::test]
+async fn test_paged_listings_continue_from_tokens() {
+    let dir = tempfile::tempdir().unwrap();
+    let store = KVBlockStore::new(
+        dir.path().join("store").to_string_lossy().to_string(),
+        CODEC_DAG_CBOR,
+    );
+    let blockstore = &mut FFIFriendlyBlockStore::new(Box::new(store));
+    let (mut helper, _, _) = PrivateDirectoryHelper::init(blockstore, vec![0; 32])
+        .await
+        .unwrap();
+    let folder = vec!["inbox".to_string()];
+    for name in ["c", "a", "e", "b", "d"] {
+        helper
+            .write_file(&[folder[0].to_owned(), name.into()], b"x".to_vec(), 0)
+            .await
+            .unwrap();
+    }
+
+    let mut names = Vec::new();
+    let mut page = None;
+    loop {
+        let listing = helper
+            .ls_files_page(&folder, page.as_deref(), 2)
+            .await
+            .unwrap();
+        assert!(listing.items.len() <= 2);
+        names.extend(listing.items.into_iter().map(|(name, _)| name));
+        page = listing.next_page;
+        if page.is_none() {
+            break;
+        }
+    }
+    assert_eq!(names, vec!["a", "b", "c", "d", "e"]);
+
+    let first = helper.root_history_page(None, 2).unwrap();
+    assert_eq!(first.items[0], *helper.root_history().last().unwrap());
+    let token = first.next_page.unwrap();
+    let second = helper.root_history_page(Some(&token), 2).unwrap();
+    assert_eq!(
+        second.items[0],
+        helper.root_history()[helper.root_history().len() - 3]
+    );
+    // A history token doesn't continue a directory listing.
+    assert!(helper
+        .ls_files_page(&folder, Some(&token), 2)
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn test_dir_handle_refreshes_after_commits() {
+    let dir = tempfile::tempdir().unwrap();
+    let store = KVBlockStore::new(
+        dir.path().join("store").to_string_lossy().to_string(),
+        CODEC_DAG_CBOR,
+    );
+    let blockstore = &mut FFIFriendlyBlockStore::new(Box::new(store));
+    let (mut helper, _, _) = PrivateDirectoryHelper::init(blockstore, vec![0; 32])
+        .await
+        .unwrap();
+    helper
+        .write_file(&["docs".into(), "a.txt".into()], b"a".to_vec(), 0)
+        .await
+        .unwrap();
+    helper
+        .mkdir(&["docs".into(), "drafts".into()])
+        .await
+        .unwrap();
+
+    let mut docs = helper.open_dir(&["docs".into()]).await.unwrap();
+    assert_eq!(docs.ls(&mut helper).await.unwrap().len(), 2);
+    assert!(!docs.is_stale(&helper));
+
+    helper
+        .write_file(&["docs".into(), "b.txt".into()], b"b".to_vec(), 0)
+        .await
+        .unwrap();
+    assert!(docs.is_stale(&helper));
+    assert_eq!(docs.ls(&mut helper).await.unwrap().len(), 3);
+
+    let drafts = docs.child(&mut helper, "drafts").await.unwrap();
+    assert_eq!(drafts.path(), ["docs".to_string(), "drafts".to_string()]);
+    assert!(docs.child(&mut helper, "a.txt").await.is_err());
+    assert!(helper.open_dir(&["missing".into()]).await.is_err());
+}
+
+#[tokio::test]
+async fn test_file_handle_positioned_reads() {
+    use std::io::SeekFrom;
+
+    let dir = tempfile::tempdir().unwrap();
+    let store = KVBlockStore::new(
+        dir.path().join("store").to_string_lossy().to_string(),
+        CODEC_DAG_CBOR,
+    );
+    let blockstore = &mut FFIFriendlyBlockStore::new(Box::new(store));
+    let (mut helper, _, _) = PrivateDirectoryHelper::init(blockstore, vec![0; 32])
+        .await
+        .unwrap();
+    // Spans several WNFS content blocks.
+    let mut content = vec![0u8; 700 * 1024];
+    rand::thread_rng().fill_bytes(&mut content);
+    let path = vec!["video.mp4".to_string()];
+    helper
+        .write_file(&path, content.to_owned(), 0)
+        .await
+        .unwrap();
+
+    let mut file = helper.open_file(&path).await.unwrap();
+    assert_eq!(file.len(&mut helper).await.unwrap(), content.len() as u64);
+    let offset = 300 * 1024 - 10;
+    assert_eq!(
+        file.read_at(&mut helper, offset, 4096).await.unwrap(),
+        content[offset as usize..offset as usize + 4096].to_vec()
+    );
+    assert_eq!(
+        file.seek(&mut helper, SeekFrom::End(-100)).await.unwrap(),
+        content.len() as u64 - 100
+    );
+    assert_eq!(
+        file.read(&mut helper, 1000).await.unwrap(),
+        content[content.len() - 100..].to_vec()
+    );
+    assert!(file.read(&mut helper, 10).await.unwrap().is_empty());
+    assert!(file
+        .seek(&mut helper, SeekFrom::Current(-(content.len() as i64) - 1))
+        .await
+        .is_err());
+
+    helper
+        .write_file(&["small.txt".into()], b"hello".to_vec(), 0)
+        .await
+        .unwrap();
+    let mut small = helper.open_file(&["small.txt".into()]).await.unwrap();
+    assert_eq!(small.len(&mut helper).await.unwrap(), 5);
+    assert_eq!(
+        small.read_at(&mut helper, 1, 3).await.unwrap(),
+        b"ell".to_vec()
+    );
+}
+
+#[tokio::test]
+async fn test_walk_visits_the_subtree_lazily() {
+    use crate::private_forest::WalkOptions;
+    use futures::StreamExt;
+
+    let dir = tempfile::tempdir().unwrap();
+    let store = KVBlockStore::new(
+        dir.path().join("store").to_string_lossy().to_string(),
+        CODEC_DAG_CBOR,
+    );
+    let blockstore = &mut FFIFriendlyBlockStore::new(Box::new(store));
+    let (mut helper, _, _) = PrivateDirectoryHelper::init(blockstore, vec![0; 32])
+        .await
+        .unwrap();
+    for path in ["a/x.txt", "a/b/y.txt", "c/z.txt", "top.txt"] {
+        let segments = PrivateDirectoryHelper::parse_path(path.to_string());
+        helper
+            .write_file(&segments, b"data".to_vec(), 0)
+            .await
+            .unwrap();
+    }
+
+    let paths: Vec<String> = helper
+        .walk(&[], WalkOptions::default())
+        .map(|entry| entry.unwrap().path.join("/"))
+        .collect()
+        .await;
+    assert_eq!(
+        paths,
+        vec![
+            "",
+            "a",
+            "a/b",
+            "a/b/y.txt",
+            "a/x.txt",
+            "c",
+            "c/z.txt",
+            "top.txt"
+        ]
+    );
+
+    let options = WalkOptions {
+        max_depth: Some(1),
+        directories: false,
+        exclude: vec![vec!["c".to_string()]],
+        ..Default::default()
+    };
+    let files: Vec<String> = helper
+        .walk(&[], options)
+        .map(|entry| entry.unwrap().path.join("/"))
+        .collect()
+        .await;
+    assert_eq!(files, vec!["top.txt"]);
+
+    let first = helper
+        .walk(&["a".into()], WalkOptions::default())
+        .next()
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(first.depth, 0);
+    assert!(helper
+        .walk(&["missing".into()], WalkOptions::default())
+        .next()
+        .await
+        .unwrap()
+        .is_err());
+}
+
+#[tokio::test]
+async fn test_forest_state_tracks_commits() {
+    use crate::private_forest::ForestState;
+
+    let dir = tempfile::tempdir().unwrap();
+    let store = KVBlockStore::new(
+        dir.path().join("store").to_string_lossy().to_string(),
+        CODEC_DAG_CBOR,
+    );
+    let blockstore = &mut FFIFriendlyBlockStore::new(Box::new(store));
+    let (mut helper, _, initial) =
+        PrivateDirectoryHelper::init_with_state(blockstore, vec![21; 32], "phone")
+            .await
+            .unwrap();
+    assert_eq!(initial.forest_version, 0);
+    helper.mkdir(&["a".into()]).await.unwrap();
+    let cid = helper.mkdir(&["b".into()]).await.unwrap();
+    let state = helper.forest_state().unwrap();
+    assert_eq!(state.root_cid, cid);
+    assert_eq!(state.forest_version, 2);
+    assert!(state.supersedes(&initial));
+
+    let json = state.to_json().unwrap();
+    assert!(json.contains(&format!("\"root_cid\":\"{}\"", cid)));
+    let parsed = ForestState::from_json(&json).unwrap();
+    assert_eq!(parsed, state);
+
+    // The phone's session has to close before another one opens the forest.
+    drop(helper);
+    let mut laptop =
+        PrivateDirectoryHelper::load_from_state(blockstore, &parsed, vec![21; 32], "laptop")
+            .await
+            .unwrap();
+    laptop.mkdir(&["c".into()]).await.unwrap();
+    let next = laptop.commit_state().await.unwrap();
+    assert_eq!(next, laptop.forest_state().unwrap());
+    assert_eq!(next.forest_version, 3);
+    assert_eq!(next.device, "laptop");
+    assert_eq!(next.created, initial.created);
+
+    // Payloads written before a field existed still load.
+    let minimal = ForestState::from_json(&format!("{{\"root_cid\":\"{}\"}}", cid)).unwrap();
+    assert_eq!(minimal.root_cid, cid);
+    assert_eq!(minimal.forest_version, 0);
+    assert!(minimal.device.is_empty());
+}
+
+#[tokio::test]
+async fn test_commit_with_retry_replays_on_the_latest_root() {
+    use std::cell::{Cell, RefCell};
+
+    use crate::private_forest::{RetryPolicy, RootPointer};
+
+    struct TestPointer(RefCell<Cid>);
+
+    impl RootPointer for TestPointer {
+        fn latest(&self) -> Result<Cid, String> {
+            Ok(*self.0.borrow())
+        }
+
+        fn compare_and_publish(&self, expected: Cid, new: Cid) -> Result<bool, String> {
+            let mut current = self.0.borrow_mut();
+            if *current != expected {
+                return Ok(false);
+            }
+            *current = new;
+            Ok(true)
+        }
+    }
+
+    let dir = tempfile::tempdir().unwrap();
+    let store = KVBlockStore::new(
+        dir.path().join("store").to_string_lossy().to_string(),
+        CODEC_DAG_CBOR,
+    );
+    let blockstore = &mut FFIFriendlyBlockStore::new(Box::new(store));
+    let (mut phone, _, initial) = PrivateDirectoryHelper::init(blockstore, vec![0; 32])
+        .await
+        .unwrap();
+    let pointer = TestPointer(RefCell::new(initial));
+    let mut laptop = PrivateDirectoryHelper::load_with_wnfs_key(blockstore, initial, vec![0; 32])
+        .await
+        .unwrap();
+    let laptop_root = laptop.mkdir(&["from-laptop".into()]).await.unwrap();
+    assert!(pointer.compare_and_publish(initial, laptop_root).unwrap());
+
+    let from_phone = vec!["from-phone".to_string()];
+    let runs = Cell::new(0);
+    let published = phone
+        .commit_with_retry(&pointer, RetryPolicy::default(), |helper, attempt| {
+            runs.set(runs.get() + 1);
+            assert_eq!(attempt.number == 0, attempt.previous_base.is_none());
+            Box::pin(helper.mkdir(&from_phone))
+        })
+        .await
+        .unwrap();
+    assert_eq!(runs.get(), 2);
+    assert_eq!(pointer.latest().unwrap(), published);
+    let names: Vec<String> = phone
+        .ls_files(&[])
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect();
+    assert!(names.contains(&"from-laptop".to_string()));
+    assert!(names.contains(&"from-phone".to_string()));
+
+    // A pointer another device keeps moving exhausts the retries.
+    struct BusyPointer(Cid);
+
+    impl RootPointer for BusyPointer {
+        fn latest(&self) -> Result<Cid, String> {
+            Ok(self.0)
+        }
+
+        fn compare_and_publish(&self, _expected: Cid, _new: Cid) -> Result<bool, String> {
+            Ok(false)
+        }
+    }
+
+    let again = vec!["again".to_string()];
+    let runs = Cell::new(0);
+    let error = phone
+        .commit_with_retry(
+            &BusyPointer(laptop_root),
+            RetryPolicy { max_retries: 1 },
+            |helper, _| {
+                runs.set(runs.get() + 1);
+                Box::pin(helper.mkdir(&again))
+            },
+        )
+        .await;
+    assert!(error.is_err());
+    assert_eq!(runs.get(), 2);
+}
+
+#[tokio::test]
+async fn test_streamed_writes_and_reads() {
+    use futures::{stream, StreamExt};
+
+    use crate::private_forest::STREAM_CHUNK_BYTES;
+
+    let dir = tempfile::tempdir().unwrap();
+    let store = KVBlockStore::new(
+        dir.path().join("store").to_string_lossy().to_string(),
+        CODEC_DAG_CBOR,
+    );
+    let blockstore = &mut FFIFriendlyBlockStore::new(Box::new(store));
+    let (mut helper, _, _) = PrivateDirectoryHelper::init(blockstore, vec![0; 32])
+        .await
+        .unwrap();
+    let mut content = vec![0u8; 3 * STREAM_CHUNK_BYTES + 1234];
+    rand::thread_rng().fill_bytes(&mut content);
+    let path = vec!["movie.mkv".to_string()];
+    // Chunk sizes unrelated to the block size.
+    let chunks: Vec<Result<Vec<u8>, String>> = content
+        .chunks(100_000)
+        .map(|chunk| Ok(chunk.to_vec()))
+        .collect();
+    helper
+        .write_file_chunks(&path, stream::iter(chunks), 0)
+        .await
+        .unwrap();
+
+    let mut read = Vec::new();
+    let mut stream = helper.read_file_stream(&path);
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.unwrap();
+        assert!(!chunk.is_empty() && chunk.len() <= STREAM_CHUNK_BYTES);
+        read.extend(chunk);
+    }
+    drop(stream);
+    assert_eq!(read, content);
+
+    // A failing source aborts the write.
+    let failing = stream::iter(vec![Ok(vec![1, 2, 3]), Err("connection reset".to_string())]);
+    assert!(helper
+        .write_file_chunks(&["partial.bin".to_string()], failing, 0)
+        .await
+        .is_err());
+    let missing: Vec<_> = helper
+        .read_file_stream(&["nothing.bin".into()])
+        .collect()
+        .await;
+    assert_eq!(missing.len(), 1);
+    assert!(missing[0].is_err());
+}
+
+#[tokio::test]
+async fn test_snapshot_reads_ignore_later_commits() {
+    use futures::StreamExt;
+
+    let dir = tempfile::tempdir().unwrap();
+    let store = KVBlockStore::new(
+        dir.path().join("store").to_string_lossy().to_string(),
+        CODEC_DAG_CBOR,
+    );
+    let blockstore = &mut FFIFriendlyBlockStore::new(Box::new(store));
+    let (mut helper, _, _) = PrivateDirectoryHelper::init(blockstore, vec![0; 32])
+        .await
+        .unwrap();
+    let mut original = vec![0u8; 600 * 1024];
+    rand::thread_rng().fill_bytes(&mut original);
+    let video = vec!["video.mp4".to_string()];
+    let root = helper
+        .write_file(&video, original.to_owned(), 0)
+        .await
+        .unwrap();
+
+    let mut snapshot = helper.snapshot().await.unwrap();
+    assert_eq!(snapshot.root_cid(), root);
+    // Commits land while the snapshot is being read.
+    let mut stream = snapshot.read_file_stream(&video);
+    let first = stream.next().await.unwrap().unwrap();
+    helper
+        .write_file(&video, b"replaced".to_vec(), 0)
+        .await
+        .unwrap();
+    helper
+        .write_file(&["new.txt".into()], b"new".to_vec(), 0)
+        .await
+        .unwrap();
+    let mut read = first;
+    while let Some(chunk) = stream.next().await {
+        read.extend(chunk.unwrap());
+    }
+    drop(stream);
+    assert_eq!(read, original);
+    assert!(!snapshot.exists(&["new.txt".into()]).await.unwrap());
+
+    assert_eq!(
+        helper.read_file(&video).await.unwrap(),
+        b"replaced".to_vec()
+    );
+    let mut later = helper.snapshot().await.unwrap();
+    assert!(later.exists(&["new.txt".into()]).await.unwrap());
+}
+
+#[tokio::test]
+async fn test_snapshot_reports_the_root_of_the_tree_it_views() {
+    use crate::private_forest::{CommitBatching, HelperConfig};
+
+    let dir = tempfile::tempdir().unwrap();
+    let store = KVBlockStore::new(
+        dir.path().join("store").to_string_lossy().to_string(),
+        CODEC_DAG_CBOR,
+    );
+    let blockstore = &mut FFIFriendlyBlockStore::new(Box::new(store));
+    let (mut helper, _, init_cid) = PrivateDirectoryHelper::init(blockstore, vec![0; 32])
+        .await
+        .unwrap();
+    helper.set_config(HelperConfig {
+        commit_batching: Some(CommitBatching { window_ms: 60_000 }),
+        ..Default::default()
+    });
+    let batched = vec!["batched.txt".to_string()];
+    helper
+        .write_file(&batched, b"batched".to_vec(), 0)
+        .await
+        .unwrap();
+    let mut snapshot = helper.snapshot().await.unwrap();
+    assert_ne!(snapshot.root_cid(), init_cid);
+    assert!(!helper.has_pending_commit());
+    let mut loaded = helper.open_at(snapshot.root_cid()).await.unwrap();
+    assert!(loaded.exists(&batched).await.unwrap());
+    assert!(snapshot.exists(&batched).await.unwrap());
+
+    // Within a transaction the view shows the root the transaction started from.
+    let started = snapshot.root_cid();
+    let mut tx = helper.begin();
+    tx.write_file(&["uncommitted.txt".into()], b"tx".to_vec(), 0)
+        .await
+        .unwrap();
+    let mut inside = tx.snapshot().await.unwrap();
+    assert_eq!(inside.root_cid(), started);
+    assert!(!inside.exists(&["uncommitted.txt".into()]).await.unwrap());
+    tx.rollback();
+}
+
+#[tokio::test]
+async fn test_forks_diverge_from_the_original() {
+    let dir = tempfile::tempdir().unwrap();
+    let store = KVBlockStore::new(
+        dir.path().join("store").to_string_lossy().to_string(),
+        CODEC_DAG_CBOR,
+    );
+    let blockstore = &mut FFIFriendlyBlockStore::new(Box::new(store));
+    let (mut helper, _, _) = PrivateDirectoryHelper::init(blockstore, vec![0; 32])
+        .await
+        .unwrap();
+    let shared = vec!["shared.txt".to_string()];
+    let original_root = helper
+        .write_file(&shared, b"before the fork".to_vec(), 0)
+        .await
+        .unwrap();
+
+    let fork_key = vec![1u8; 32];
+    let fork_root = helper.fork(fork_key.to_owned()).await.unwrap();
+    assert_ne!(fork_root, original_root);
+    // The new key only opens the fork.
+    assert!(PrivateDirectoryHelper::load_with_wnfs_key(
+        blockstore,
+        original_root,
+        fork_key.to_owned()
+    )
+    .await
+    .is_err());
+    let mut fork = PrivateDirectoryHelper::load_with_wnfs_key(blockstore, fork_root, fork_key)
+        .await
+        .unwrap();
+    assert_eq!(
+        fork.read_file(&shared).await.unwrap(),
+        b"before the fork".to_vec()
+    );
+
+    fork.write_file(&["fork.txt".into()], b"fork".to_vec(), 0)
+        .await
+        .unwrap();
+    helper
+        .write_file(&shared, b"changed in the original".to_vec(), 0)
+        .await
+        .unwrap();
+    assert!(helper.read_file(&["fork.txt".into()]).await.is_err());
+    assert_eq!(
+        fork.read_file(&shared).await.unwrap(),
+        b"before the fork".to_vec()
+    );
+}
+
+#[tokio::test]
+async fn test_init_from_template_commits_once() {
+    use crate::private_forest::{ForestTemplate, TemplateDocument};
+
+    let dir = tempfile::tempdir().unwrap();
+    let store = KVBlockStore::new(
+        dir.path().join("store").to_string_lossy().to_string(),
+        CODEC_DAG_CBOR,
+    );
+    let blockstore = &mut FFIFriendlyBlockStore::new(Box::new(store));
+    let json = ForestTemplate {
+        directories: vec![
+            vec!["photos".into(), "camera".into()],
+            vec!["documents".into()],
+        ],
+        documents: vec![TemplateDocument {
+            path: vec!["settings".into(), "app.json".into()],
+            content: b"{\"theme\":\"dark\"}".to_vec(),
+        }],
+        ..Default::default()
+    }
+    .to_json()
+    .unwrap();
+    let template = ForestTemplate::from_json(&json).unwrap();
+
+    let (mut helper, _, mut snapshot) =
+        PrivateDirectoryHelper::init_from_template(blockstore, vec![22; 32], &template)
+            .await
+            .unwrap();
+    assert_eq!(helper.root_history(), &[snapshot.root_cid()]);
+    assert_eq!(helper.forest_metrics().commits, 1);
+
+    helper
+        .write_file(&["settings".into(), "app.json".into()], b"{}".to_vec(), 0)
+        .await
+        .unwrap();
+    let mut names: Vec<String> = helper
+        .ls_files(&[])
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect();
+    names.sort();
+    assert_eq!(names, vec!["documents", "photos", "settings"]);
+    assert!(helper
+        .ls_files(&["photos".into(), "camera".into()])
+        .await
+        .unwrap()
+        .is_empty());
+    assert_eq!(
+        snapshot
+            .read_file(&["settings".into(), "app.json".into()])
+            .await
+            .unwrap(),
+        b"{\"theme\":\"dark\"}".to_vec()
+    );
+
+    // Templates are checked like any other write.
+    let invalid = ForestTemplate {
+        documents: vec![TemplateDocument {
+            path: vec!["settings".into(), "".into()],
+            content: Vec::new(),
+        }],
+        ..Default::default()
+    };
+    let store = KVBlockStore::new(
+        dir.path().join("invalid").to_string_lossy().to_string(),
+        CODEC_DAG_CBOR,
+    );
+    let blockstore = &mut FFIFriendlyBlockStore::new(Box::new(store));
+    let err = PrivateDirectoryHelper::init_from_template(blockstore, vec![23; 32], &invalid)
+        .await
+        .err()
+        .unwrap();
+    assert!(err.contains("path segments can't be empty"));
+}
+
+#[tokio::test]
+async fn test_rechunk_migrates_paged_files_once() {
+    use crate::private_forest::{PagedFileOptions, TransferProgress};
+
+    let dir = tempfile::tempdir().unwrap();
+    let store = KVBlockStore::new(
+        dir.path().join("store").to_string_lossy().to_string(),
+        CODEC_DAG_CBOR,
+    );
+    let blockstore = &mut FFIFriendlyBlockStore::new(Box::new(store));
+    let (mut helper, _, _) = PrivateDirectoryHelper::init(blockstore, vec![0; 32])
+        .await
+        .unwrap();
+    let old = PagedFileOptions {
+        page_size: 16,
+        pages_per_chunk: 1,
+    };
+    let path: Vec<String> = vec!["app".into(), "app.db".into()];
+    let other: Vec<String> = vec!["app".into(), "cache.db".into()];
+    for file in [&path, &other] {
+        helper.create_paged_file(file, old).await.unwrap();
+    }
+    // The first chunks stay sparse.
+    helper
+        .write_at(&path, 40, b"migrated content")
+        .await
+        .unwrap();
+    helper.write_at(&other, 0, b"other").await.unwrap();
+
+    let new = PagedFileOptions {
+        page_size: 16,
+        pages_per_chunk: 4,
+    };
+    let mut reports: Vec<TransferProgress> = Vec::new();
+    let mut on_progress = |report: &TransferProgress| reports.push(report.to_owned());
+    let migrated = helper
+        .rechunk(&["app".into()], new, Some(&mut on_progress))
+        .await
+        .unwrap();
+    assert_eq!(migrated, 2);
+    assert_eq!(reports.len(), 2);
+    assert_eq!(reports[1].files_done, 2);
+    assert_eq!(reports[1].bytes_done, 56 + 5);
+
+    assert_eq!(helper.paged_file_len(&path).await.unwrap(), 56);
+    assert_eq!(
+        helper.read_at(&path, 0, 56).await.unwrap(),
+        [vec![0u8; 40], b"migrated content".to_vec()].concat()
+    );
+    assert_eq!(helper.read_at(&other, 0, 5).await.unwrap(), b"other");
+    let mut names: Vec<String> = helper
+        .ls_files(&path)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect();
+    names.sort();
+    assert_eq!(names, vec![".wnfsutils-paged", "0000000000000000"]);
+    assert_eq!(helper.ls_files(&["app".into()]).await.unwrap().len(), 2);
+
+    // Files already migrated are skipped.
+    assert_eq!(helper.rechunk(&[], new, None).await.unwrap(), 0);
+}
+
+#[test]
+fn test_operation_limits_bound_concurrency() {
+    use crate::private_forest::{OperationLimiter, OperationLimits};
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    let limiter = OperationLimiter::new(Some(OperationLimits {
+        max_in_flight: 2,
+        max_per_second: None,
+        max_queued: None,
+    }));
+    let running = Arc::new(AtomicUsize::new(0));
+    let peak = Arc::new(AtomicUsize::new(0));
+    let threads: Vec<_> = (0..6)
+        .map(|_| {
+            let limiter = limiter.to_owned();
+            let running = Arc::clone(&running);
+            let peak = Arc::clone(&peak);
+            std::thread::spawn(move || {
+                let _permit = limiter.admit("test").unwrap();
+                let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                peak.fetch_max(now, Ordering::SeqCst);
+                std::thread::sleep(std::time::Duration::from_millis(20));
+                running.fetch_sub(1, Ordering::SeqCst);
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    assert!(peak.load(Ordering::SeqCst) <= 2);
+    assert_eq!(limiter.in_flight(), (0, 0));
+
+    // Helpers start unlimited and share a limiter once handed one.
+    let dir = tempfile::tempdir().unwrap();
+    let store = KVBlockStore::new(
+        dir.path().join("db").to_str().unwrap().into(),
+        CODEC_DAG_CBOR,
+    );
+    let blockstore = &mut FFIFriendlyBlockStore::new(Box::new(store));
+    let (mut helper, _, _) = PrivateDirectoryHelper::synced_init(blockstore, vec![0; 32]).unwrap();
+    assert!(helper.operation_limiter().limits().is_none());
+    helper.set_operation_limiter(limiter.to_owned());
+    limiter.set_limits(None);
+    assert!(helper.operation_limiter().limits().is_none());
+}
+
+#[tokio::test]
+async fn test_zero_byte_files_and_empty_directories() {
+    use crate::private_forest::PagedFileOptions;
+
+    let dir = tempfile::tempdir().unwrap();
+    let store = KVBlockStore::new(
+        dir.path().join("store").to_string_lossy().to_string(),
+        CODEC_DAG_CBOR,
+    );
+    let blockstore = &mut FFIFriendlyBlockStore::new(Box::new(store));
+    let (mut helper, _, _) = PrivateDirectoryHelper::init(blockstore, vec![0; 32])
+        .await
+        .unwrap();
+
+    let empty: Vec<String> = vec!["empty.txt".into()];
+    helper.write_file(&empty, Vec::new(), 0).await.unwrap();
+    assert!(helper.read_file(&empty).await.unwrap().is_empty());
+    let mut handle = helper.open_file(&empty).await.unwrap();
+    assert_eq!(handle.len(&mut helper).await.unwrap(), 0);
+    assert!(handle.read_at(&mut helper, 10, 4).await.unwrap().is_empty());
+
+    // Streamed from an empty local file and back.
+    let local = dir.path().join("empty.bin");
+    File::create(&local).unwrap();
+    let local = local.to_string_lossy().to_string();
+    let streamed: Vec<String> = vec!["streamed".into()];
+    helper
+        .write_file_stream_from_path(&streamed, &local)
+        .await
+        .unwrap();
+    assert!(helper.read_file(&streamed).await.unwrap().is_empty());
+    let out = dir.path().join("out.bin").to_string_lossy().to_string();
+    assert!(helper
+        .read_filestream_to_path(&out, &streamed, 0)
+        .await
+        .unwrap());
+    assert!(read(&out).unwrap().is_empty());
+
+    helper.mkdir(&["nothing".into()]).await.unwrap();
+    assert!(helper
+        .ls_files(&["nothing".into()])
+        .await
+        .unwrap()
+        .is_empty());
+
+    // Writing no bytes, even past the end, leaves a paged file alone.
+    let paged: Vec<String> = vec!["app.db".into()];
+    helper
+        .create_paged_file(&paged, PagedFileOptions::default())
+        .await
+        .unwrap();
+    helper.write_at(&paged, 4096, &[]).await.unwrap();
+    assert_eq!(helper.paged_file_len(&paged).await.unwrap(), 0);
+    assert!(helper.read_at(&paged, 0, 16).await.unwrap().is_empty());
+
+    let err = helper.write_file(&[], Vec::new(), 0).await.unwrap_err();
+    assert!(err.contains("a file can't be written at the root"));
+    let err = helper
+        .write_file(&["a".into(), "".into()], Vec::new(), 0)
+        .await
+        .unwrap_err();
+    assert!(err.contains("path segments can't be empty"));
+}
+
+#[tokio::test]
+async fn test_path_normalization_and_length_limits() {
+    use crate::private_forest::{HelperConfig, PathNormalization};
+
+    let dir = tempfile::tempdir().unwrap();
+    let store = KVBlockStore::new(
+        dir.path().join("store").to_string_lossy().to_string(),
+        CODEC_DAG_CBOR,
+    );
+    let blockstore = &mut FFIFriendlyBlockStore::new(Box::new(store));
+    let (mut helper, _, _) = PrivateDirectoryHelper::init(blockstore, vec![0; 32])
+        .await
+        .unwrap();
+    let nfc = "caf\u{e9}.txt".to_string();
+    let nfd = "cafe\u{301}.txt".to_string();
+
+    // Written by a client without normalization: one entry per form, plus a lone NFD one.
+    helper
+        .write_file(&[nfc.to_owned()], b"composed".to_vec(), 0)
+        .await
+        .unwrap();
+    helper
+        .write_file(&[nfd.to_owned()], b"decomposed".to_vec(), 0)
+        .await
+        .unwrap();
+    helper
+        .write_file(
+            &["d\u{f6}cs".into(), "re\u{301}sume\u{301}".into()],
+            b"cv".to_vec(),
+            0,
+        )
+        .await
+        .unwrap();
+    helper.mkdir(&["do\u{308}cs".into()]).await.unwrap();
+
+    helper.set_config(HelperConfig {
+        path_normalization: Some(PathNormalization::Nfc),
+        max_name_bytes: Some(16),
+        max_path_bytes: Some(24),
+        ..Default::default()
+    });
+    let report = helper.find_normalization_issues(&[]).await.unwrap();
+    assert_eq!(report.conflicts.len(), 2);
+    assert_eq!(report.unnormalized.len(), 1);
+    assert_eq!(report.unnormalized[0][1], "re\u{301}sume\u{301}");
+
+    let conflicts = helper.normalize_names(&[]).await.unwrap();
+    assert_eq!(conflicts.len(), 2);
+    assert!(helper
+        .find_normalization_issues(&[])
+        .await
+        .unwrap()
+        .unnormalized
+        .is_empty());
+    // Either form now reaches the renamed entry.
+    assert_eq!(
+        helper
+            .read_file(&["d\u{f6}cs".into(), "re\u{301}sume\u{301}".into()])
+            .await
+            .unwrap(),
+        b"cv".to_vec()
+    );
+    assert_eq!(
+        helper
+            .read_file(&["d\u{f6}cs".into(), "r\u{e9}sum\u{e9}".into()])
+            .await
+            .unwrap(),
+        b"cv".to_vec()
+    );
+
+    let err = helper
+        .write_file(&["a-very-long-file-name.txt".into()], Vec::new(), 0)
+        .await
+        .unwrap_err();
+    assert!(err.contains("exceeds the limit of 16 bytes"));
+    let err = helper
+        .mkdir(&["abcdefgh".into(), "abcdefgh".into(), "abcdefgh".into()])
+        .await
+        .unwrap_err();
+    assert!(err.contains("path of 26 bytes exceeds the limit of 24 bytes"));
+}
+
+#[tokio::test]
+async fn test_share_directory_with_exchange_key() {
+    use wnfs::common::BlockStore;
+
+    use crate::blockstore::FFIStore;
+    use crate::private_forest::{ExchangeKeyPair, SharePayload};
+
+    let dir = tempfile::tempdir().unwrap();
+    let store = KVBlockStore::new(
+        dir.path().join("store").to_string_lossy().to_string(),
+        CODEC_DAG_CBOR,
+    );
+    let blockstore = &mut FFIFriendlyBlockStore::new(Box::new(store));
+    let (mut helper, _, _) = PrivateDirectoryHelper::init(blockstore, vec![0; 32])
+        .await
+        .unwrap();
+    helper
+        .write_file(
+            &["shared".into(), "notes.txt".into()],
+            b"for you".to_vec(),
+            0,
+        )
+        .await
+        .unwrap();
+    helper
+        .write_file(&["private.txt".into()], b"not for you".to_vec(), 0)
+        .await
+        .unwrap();
+
+    let recipient = ExchangeKeyPair::from_seed([7; 32]).unwrap();
+    let err = helper
+        .share(&["private.txt".into()], &recipient.public_key())
+        .await
+        .unwrap_err();
+    assert!(err.contains("no directory found"));
+    let first = helper
+        .share(&["shared".into()], &recipient.public_key())
+        .await
+        .unwrap();
+    let second = helper
+        .share(&["shared".into()], &recipient.public_key())
+        .await
+        .unwrap();
+    assert_eq!(second.counter, first.counter + 1);
+    assert_eq!(first.sharer, helper.sharer_id());
+
+    let payload = SharePayload::from_bytes(&second.to_bytes().unwrap()).unwrap();
+    assert_eq!(payload, second);
+    let mut view = PrivateDirectoryHelper::accept_share(blockstore, &payload, &recipient)
+        .await
+        .unwrap();
+    assert_eq!(
+        view.read_file(&["notes.txt".into()]).await.unwrap(),
+        b"for you".to_vec()
+    );
+    assert!(!view.exists(&["private.txt".into()]).await.unwrap());
+
+    // Another key pair finds nothing under the same payload.
+    let stranger = ExchangeKeyPair::from_seed([8; 32]).unwrap();
+    assert!(
+        PrivateDirectoryHelper::accept_share(blockstore, &payload, &stranger)
+            .await
+            .is_err()
+    );
+
+    // The proof's blocks alone open the share, and they are only part of the forest.
+    let proof = payload.proof.to_owned().unwrap();
+    assert_eq!(proof.root, payload.forest_cid);
+    let stored = blockstore.ffi_store.list_blocks().unwrap().len();
+    assert!(proof.blocks.len() < stored);
+    let received = KVBlockStore::new(
+        dir.path().join("received").to_string_lossy().to_string(),
+        CODEC_DAG_CBOR,
+    );
+    for (cid, _) in &proof.blocks[1..] {
+        let data = blockstore.get_block(cid).await.unwrap();
+        received.put_block(cid.to_bytes(), data.to_vec()).unwrap();
+    }
+    let received = &mut FFIFriendlyBlockStore::new(Box::new(received));
+    let err = PrivateDirectoryHelper::accept_share(received, &payload, &recipient)
+        .await
+        .unwrap_err();
+    assert!(err.contains(&format!("block {} of the share not found", proof.root)));
+    let root = blockstore.get_block(&proof.root).await.unwrap();
+    received
+        .ffi_store
+        .put_block(proof.root.to_bytes(), root.to_vec())
+        .unwrap();
+    let mut view = PrivateDirectoryHelper::accept_share(received, &payload, &recipient)
+        .await
+        .unwrap();
+    assert_eq!(
+        view.read_file(&["notes.txt".into()]).await.unwrap(),
+        b"for you".to_vec()
+    );
+}
+
+#[tokio::test]
+async fn test_commit_batching_coalesces_mutations() {
+    use crate::private_forest::{CommitBatching, HelperConfig};
+
+    let dir = tempfile::tempdir().unwrap();
+    let store = KVBlockStore::new(
+        dir.path().join("store").to_string_lossy().to_string(),
+        CODEC_DAG_CBOR,
+    );
+    let blockstore = &mut FFIFriendlyBlockStore::new(Box::new(store));
+    let (mut helper, _, init_cid) = PrivateDirectoryHelper::init(blockstore, vec![0; 32])
+        .await
+        .unwrap();
+    helper.set_config(HelperConfig {
+        commit_batching: Some(CommitBatching { window_ms: 60_000 }),
+        ..Default::default()
+    });
+    let commits = helper.forest_metrics().commits;
+    let mut handle = helper.open_dir(&[]).await.unwrap();
+    assert!(handle.ls(&mut helper).await.unwrap().is_empty());
+
+    for i in 0..3 {
+        let cid = helper
+            .write_file(&[format!("{}.txt", i)], vec![i as u8], 0)
+            .await
+            .unwrap();
+        assert_eq!(cid, init_cid);
+    }
+    assert!(helper.has_pending_commit());
+    assert_eq!(helper.root_history(), &[init_cid]);
+    assert_eq!(helper.read_file(&["2.txt".into()]).await.unwrap(), vec![2]);
+    // Cached listings notice the pending mutations.
+    assert_eq!(handle.ls(&mut helper).await.unwrap().len(), 3);
+    assert_eq!(helper.flush_due_commits().await.unwrap(), None);
+
+    let flushed = helper.flush_commits().await.unwrap();
+    assert_ne!(flushed, init_cid);
+    assert!(!helper.has_pending_commit());
+    assert_eq!(helper.root_history(), &[init_cid, flushed]);
+    assert_eq!(helper.forest_metrics().commits, commits + 1);
+    assert_eq!(helper.flush_commits().await.unwrap(), flushed);
+
+    let mut reloaded = PrivateDirectoryHelper::load_with_wnfs_key(blockstore, flushed, vec![0; 32])
+        .await
+        .unwrap();
+    assert_eq!(reloaded.ls_files(&[]).await.unwrap().len(), 3);
+
+    // An empty window commits every mutation at once.
+    helper.set_config(HelperConfig {
+        commit_batching: Some(CommitBatching { window_ms: 0 }),
+        ..Default::default()
+    });
+    let cid = helper
+        .write_file(&["3.txt".into()], vec![3], 0)
+        .await
+        .unwrap();
+    assert_ne!(cid, flushed);
+    assert!(!helper.has_pending_commit());
+
+    // A window closing inside a transaction is left to the transaction's commit.
+    helper.set_config(HelperConfig {
+        commit_batching: Some(CommitBatching { window_ms: 60_000 }),
+        ..Default::default()
+    });
+    helper
+        .write_file(&["4.txt".into()], vec![4], 0)
+        .await
+        .unwrap();
+    let mut tx = helper.begin();
+    tx.write_file(&["5.txt".into()], vec![5], 0).await.unwrap();
+    tx.set_config(HelperConfig {
+        commit_batching: Some(CommitBatching { window_ms: 0 }),
+        ..Default::default()
+    });
+    assert_eq!(tx.flush_due_commits().await.unwrap(), None);
+    assert_eq!(tx.root_history().last(), Some(&cid));
+    let committed = tx.commit().await.unwrap();
+    assert_ne!(committed, cid);
+    assert_eq!(helper.root_history().last(), Some(&committed));
+}
+
+#[tokio::test]
+async fn test_commit_batching_flushes_before_reload_and_listing_changes() {
+    use crate::private_forest::{CommitBatching, HelperConfig};
+
+    let dir = tempfile::tempdir().unwrap();
+    let store = KVBlockStore::new(
+        dir.path().join("store").to_string_lossy().to_string(),
+        CODEC_DAG_CBOR,
+    );
+    let blockstore = &mut FFIFriendlyBlockStore::new(Box::new(store));
+    let (mut helper, _, init_cid) = PrivateDirectoryHelper::init(blockstore, vec![0; 32])
+        .await
+        .unwrap();
+    helper.create_snapshot("empty").await.unwrap();
+    helper.set_config(HelperConfig {
+        commit_batching: Some(CommitBatching { window_ms: 60_000 }),
+        ..Default::default()
+    });
+    let a: Vec<String> = vec!["a.txt".into()];
+    let b: Vec<String> = vec!["b.txt".into()];
+
+    helper.write_file(&a, b"a".to_vec(), 0).await.unwrap();
+    let changes = helper.ls_changes(&[], init_cid).await.unwrap();
+    assert!(!helper.has_pending_commit());
+    assert_eq!(changes.revision, helper.root_history().last().copied());
+
+    helper.write_file(&b, b"b".to_vec(), 0).await.unwrap();
+    let committed = helper.root_history().len();
+    helper.rollback("empty").await.unwrap();
+    assert!(helper.node_at(&b).await.unwrap().is_none());
+    // The mutation pending at the rollback was committed before the tree was replaced.
+    let before_rollback = helper.root_history()[committed];
+    let mut view = helper.open_at(before_rollback).await.unwrap();
+    assert_eq!(view.read_file(&b).await.unwrap(), b"b".to_vec());
+}
+
+#[tokio::test]
+async fn test_file_history_and_restore() {
+    let dir = tempfile::tempdir().unwrap();
+    let store = KVBlockStore::new(
+        dir.path().join("store").to_string_lossy().to_string(),
+        CODEC_DAG_CBOR,
+    );
+    let blockstore = &mut FFIFriendlyBlockStore::new(Box::new(store));
+    let (mut helper, _, _) = PrivateDirectoryHelper::init(blockstore, vec![0; 32])
+        .await
+        .unwrap();
+    let path: Vec<String> = vec!["docs".into(), "draft.txt".into()];
+    helper
+        .write_file(&path, b"one".to_vec(), 1000)
+        .await
+        .unwrap();
+    // Unrelated commits don't add revisions.
+    helper
+        .write_file(&["other.txt".into()], b"x".to_vec(), 1500)
+        .await
+        .unwrap();
+    helper
+        .write_file(&path, b"two".to_vec(), 2000)
+        .await
+        .unwrap();
+    helper
+        .write_file(&path, b"three".to_vec(), 3000)
+        .await
+        .unwrap();
+
+    let history = helper.history(&path).await.unwrap();
+    assert_eq!(history.len(), 3);
+    assert!(history.iter().all(|revision| !revision.is_dir));
+    assert_eq!(history[2].root_cid, helper.root_history().last().copied());
+    assert_eq!(
+        helper.read_file_at_revision(&path, 0).await.unwrap(),
+        b"one".to_vec()
+    );
+    assert_eq!(
+        helper.read_file_at_revision(&path, 1).await.unwrap(),
+        b"two".to_vec()
+    );
+    assert!(helper.read_file_at_revision(&path, 3).await.is_err());
+    assert!(helper
+        .read_file_at_revision(&["docs".into()], 0)
+        .await
+        .unwrap_err()
+        .contains("is a directory"));
+
+    helper.restore_revision(&path, 0).await.unwrap();
+    assert_eq!(helper.read_file(&path).await.unwrap(), b"one".to_vec());
+    assert_eq!(helper.history(&path).await.unwrap().len(), 4);
+}
+
+#[tokio::test]
+async fn test_read_file_at_ranges() {
+    use crate::private_forest::PagedFileOptions;
+
+    let dir = tempfile::tempdir().unwrap();
+    let store = KVBlockStore::new(
+        dir.path().join("store").to_string_lossy().to_string(),
+        CODEC_DAG_CBOR,
+    );
+    let blockstore = &mut FFIFriendlyBlockStore::new(Box::new(store));
+    let (mut helper, _, _) = PrivateDirectoryHelper::init(blockstore, vec![0; 32])
+        .await
+        .unwrap();
+    // Spans several content blocks.
+    let content: Vec<u8> = (0..700_000u32).map(|i| (i % 251) as u8).collect();
+    let path: Vec<String> = vec!["video.mp4".into()];
+    helper
+        .write_file(&path, content.to_owned(), 0)
+        .await
+        .unwrap();
+
+    assert_eq!(
+        helper.read_file_at(&path, 300_000, 1000).await.unwrap(),
+        content[300_000..301_000].to_vec()
+    );
+    assert_eq!(
+        helper.read_file_at(&path, 699_990, 100).await.unwrap(),
+        content[699_990..].to_vec()
+    );
+    assert!(helper
+        .read_file_at(&path, 800_000, 10)
+        .await
+        .unwrap()
+        .is_empty());
+    assert!(helper
+        .read_file_at(&["missing".into()], 0, 10)
+        .await
+        .is_err());
+
+    let paged: Vec<String> = vec!["app.db".into()];
+    helper
+        .create_paged_file(&paged, PagedFileOptions::default())
+        .await
+        .unwrap();
+    helper.write_at(&paged, 10, b"page").await.unwrap();
+    assert_eq!(
+        helper.read_file_at(&paged, 8, 6).await.unwrap(),
+        vec![0, 0, b'p', b'a', b'g', b'e']
+    );
+}
+
+#[tokio::test]
+async fn test_publish_snapshot_into_public_tree() {
+    use crate::private_forest::{PagedFileOptions, TransferProgress};
+    use crate::public_forest::PublicDirectoryHelper;
+
+    let dir = tempfile::tempdir().unwrap();
+    let store = KVBlockStore::new(
+        dir.path().join("store").to_string_lossy().to_string(),
+        CODEC_DAG_CBOR,
+    );
+    let blockstore = &mut FFIFriendlyBlockStore::new(Box::new(store));
+    let (mut helper, _, _) = PrivateDirectoryHelper::init(blockstore, vec![0; 32])
+        .await
+        .unwrap();
+    helper
+        .write_file(
+            &["album".into(), "cover.jpg".into()],
+            b"jpeg".to_vec(),
+            1_700_000_000,
+        )
+        .await
+        .unwrap();
+    helper
+        .mkdir(&["album".into(), "empty".into()])
+        .await
+        .unwrap();
+    let paged: Vec<String> = vec!["album".into(), "index.db".into()];
+    helper
+        .create_paged_file(&paged, PagedFileOptions::default())
+        .await
+        .unwrap();
+    helper.write_at(&paged, 0, b"rows").await.unwrap();
+    helper
+        .write_file(&["secret.txt".into()], b"private".to_vec(), 0)
+        .await
+        .unwrap();
+
+    let mut public = PublicDirectoryHelper::new(blockstore.to_owned());
+    public
+        .write_file(&["site".into(), "stale.txt".into()], b"old".to_vec())
+        .await
+        .unwrap();
+    let mut reports: Vec<TransferProgress> = Vec::new();
+    let mut record = |p: &TransferProgress| reports.push(p.to_owned());
+    let public_root = helper
+        .publish_snapshot(
+            &["album".into()],
+            &mut public,
+            &["site".into()],
+            Some(&mut record),
+        )
+        .await
+        .unwrap();
+    assert_eq!(reports.len(), 2);
+    assert_eq!(reports[1].files_total, 2);
+
+    let public = PublicDirectoryHelper::load(blockstore.to_owned(), public_root)
+        .await
+        .unwrap();
+    let mut names: Vec<String> = public
+        .ls_files(&["site".into()])
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect();
+    names.sort();
+    assert_eq!(names, vec!["cover.jpg", "empty", "index.db"]);
+    assert_eq!(
+        public
+            .read_file(&["site".into(), "index.db".into()])
+            .await
+            .unwrap(),
+        b"rows".to_vec()
+    );
+    let cover = public
+        .ls_files(&["site".into()])
+        .await
+        .unwrap()
+        .into_iter()
+        .find(|(name, _)| name == "cover.jpg")
+        .unwrap()
+        .1;
+    assert_eq!(
+        cover.get_modified().map(|time| time.timestamp()),
+        Some(1_700_000_000)
+    );
+    assert!(public.ls_files(&[]).await.unwrap().len() == 1);
+}
+
+#[tokio::test]
+async fn test_ingest_and_materialize_skip_unchanged() {
+    use crate::private_forest::TreeCopyOptions;
+
+    let store_dir = tempfile::tempdir().unwrap();
+    let store = KVBlockStore::new(
+        store_dir.path().join("store").to_string_lossy().to_string(),
+        CODEC_DAG_CBOR,
+    );
+    let blockstore = &mut FFIFriendlyBlockStore::new(Box::new(store));
+    let (helper, _, _) = &mut PrivateDirectoryHelper::init(blockstore, vec![0; 32])
+        .await
+        .unwrap();
+
+    let source = tempfile::tempdir().unwrap();
+    std::fs::create_dir_all(source.path().join("nested").join("empty")).unwrap();
+    std::fs::write(source.path().join("a.txt"), b"first file").unwrap();
+    std::fs::write(source.path().join("nested").join("b.txt"), b"second file").unwrap();
+    let source_path = source.path().to_string_lossy().into_owned();
+    let skip = TreeCopyOptions {
+        skip_unchanged: true,
+        ..Default::default()
+    };
+
+    let report = helper
+        .ingest(&source_path, &["backup".into()], skip, None)
+        .await
+        .unwrap();
+    assert_eq!((report.copied, report.skipped), (2, 0));
+    assert!(report.forest_cid.is_some());
+    assert!(helper
+        .ls_files(&["backup".into(), "nested".into(), "empty".into()])
+        .await
+        .unwrap()
+        .is_empty());
+
+    let report = helper
+        .ingest(&source_path, &["backup".into()], skip, None)
+        .await
+        .unwrap();
+    assert_eq!((report.copied, report.skipped), (0, 2));
+    assert!(report.forest_cid.is_none());
+
+    // Same modification second but another size, so it's imported again.
+    let changed = File::options()
+        .append(true)
+        .open(source.path().join("a.txt"))
+        .unwrap();
+    let modified = changed.metadata().unwrap().modified().unwrap();
+    (&changed).write_all(b", changed").unwrap();
+    changed.set_modified(modified).unwrap();
+    let report = helper
+        .ingest(&source_path, &["backup".into()], skip, None)
+        .await
+        .unwrap();
+    assert_eq!((report.copied, report.skipped), (1, 1));
+    assert_eq!(
+        helper
+            .read_file(&["backup".into(), "a.txt".into()])
+            .await
+            .unwrap(),
+        b"first file, changed".to_vec()
+    );
+
+    let target = tempfile::tempdir().unwrap();
+    let target_path = target.path().to_string_lossy().into_owned();
+    let report = helper
+        .materialize(&["backup".into()], &target_path, skip, None)
+        .await
+        .unwrap();
+    assert_eq!((report.copied, report.skipped), (2, 0));
+    assert!(target.path().join("nested").join("empty").is_dir());
+    assert_eq!(
+        read(target.path().join("nested").join("b.txt")).unwrap(),
+        b"second file".to_vec()
+    );
+    // Modification times are kept to the second.
+    let seconds = |time: std::time::SystemTime| {
+        time.duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    };
+    let exported = std::fs::metadata(target.path().join("a.txt")).unwrap();
+    assert_eq!(seconds(exported.modified().unwrap()), seconds(modified));
+
+    let report = helper
+        .materialize(&["backup".into()], &target_path, skip, None)
+        .await
+        .unwrap();
+    assert_eq!((report.copied, report.skipped), (0, 2));
+    let report = helper
+        .materialize(&["backup".into()], &target_path, Default::default(), None)
+        .await
+        .unwrap();
+    assert_eq!((report.copied, report.skipped), (2, 0));
+}
+
+#[tokio::test]
+async fn test_remote_watcher_reports_and_reloads_roots_of_other_devices() {
+    use std::cell::Cell;
+
+    use crate::daemon::{DaemonConfig, DaemonStatus};
+    use crate::private_forest::{RemoteWatcher, RootPointer, WatchOptions};
+
+    struct TestPointer(Cell<Cid>);
+
+    impl RootPointer for &TestPointer {
+        fn latest(&self) -> Result<Cid, String> {
+            Ok(self.0.get())
+        }
+
+        fn compare_and_publish(&self, _expected: Cid, new: Cid) -> Result<bool, String> {
+            self.0.set(new);
+            Ok(true)
+        }
+    }
+
+    let dir = tempfile::tempdir().unwrap();
+    let store = KVBlockStore::new(
+        dir.path().join("store").to_string_lossy().to_string(),
+        CODEC_DAG_CBOR,
+    );
+    let blockstore = &mut FFIFriendlyBlockStore::new(Box::new(store));
+    let (mut phone, _, initial) = PrivateDirectoryHelper::init(blockstore, vec![0; 32])
+        .await
+        .unwrap();
+    let mut laptop = PrivateDirectoryHelper::load_with_wnfs_key(blockstore, initial, vec![0; 32])
+        .await
+        .unwrap();
+    let pointer = TestPointer(Cell::new(initial));
+    let status = DaemonStatus::new(DaemonConfig::default());
+    let mut watcher = RemoteWatcher::new(
+        &pointer,
+        WatchOptions {
+            poll_interval_ms: 60_000,
+            auto_reload: true,
+        },
+    )
+    .with_daemon(status.clone());
+
+    // The phone's own root isn't a change.
+    assert_eq!(watcher.poll(&mut phone).await.unwrap(), None);
+
+    let laptop_root = laptop.mkdir(&["from-laptop".into()]).await.unwrap();
+    pointer.0.set(laptop_root);
+    // Within the poll interval only an announcement makes the watcher look.
+    assert_eq!(watcher.poll(&mut phone).await.unwrap(), None);
+    status.announce_root();
+    let change = watcher.poll(&mut phone).await.unwrap().unwrap();
+    assert_eq!(change.previous, Some(initial));
+    assert_eq!(change.root, laptop_root);
+    assert!(change.reloaded);
+    assert_eq!(phone.root_history().last(), Some(&laptop_root));
+    assert_eq!(phone.ls_files(&[]).await.unwrap()[0].0, "from-laptop");
+    assert_eq!(watcher.check_now(&mut phone).await.unwrap(), None);
+
+    // Without auto-reload the change is reported once and the helper stays put.
+    let mut watcher = RemoteWatcher::new(&pointer, WatchOptions::default());
+    let laptop_root = laptop.mkdir(&["again".into()]).await.unwrap();
+    pointer.0.set(laptop_root);
+    let change = watcher.check_now(&mut phone).await.unwrap().unwrap();
+    assert!(!change.reloaded);
+    assert_ne!(phone.root_history().last(), Some(&laptop_root));
+    assert_eq!(watcher.check_now(&mut phone).await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn test_transaction_commits_once_and_rolls_back_on_drop() {
+    let dir = tempfile::tempdir().unwrap();
+    let store = KVBlockStore::new(
+        dir.path().join("store").to_string_lossy().to_string(),
+        CODEC_DAG_CBOR,
+    );
+    let blockstore = &mut FFIFriendlyBlockStore::new(Box::new(store));
+    let (mut helper, _, initial) = PrivateDirectoryHelper::init(blockstore, vec![0; 32])
+        .await
+        .unwrap();
+
+    let mut tx = helper.begin();
+    assert!(tx.in_transaction());
+    assert_eq!(tx.mkdir(&["batch".into()]).await.unwrap(), initial);
+    for i in 0..50 {
+        let returned = tx
+            .write_file(
+                &["batch".into(), format!("{}.txt", i)],
+                i.to_string().into_bytes(),
+                0,
+            )
+            .await
+            .unwrap();
+        assert_eq!(returned, initial);
+    }
+    tx.rm(&["batch".into(), "0.txt".into()]).await.unwrap();
+    // Reads see the uncommitted changes.
+    assert_eq!(tx.ls_files(&["batch".into()]).await.unwrap().len(), 49);
+    let root = tx.commit().await.unwrap();
+    assert_ne!(root, initial);
+    assert_eq!(helper.root_history(), &[initial, root]);
+    assert!(!helper.in_transaction());
+
+    // A savepoint's rollback only undoes its own changes.
+    let mut tx = helper.begin();
+    tx.mkdir(&["kept".into()]).await.unwrap();
+    let mut savepoint = tx.begin();
+    savepoint.mkdir(&["undone".into()]).await.unwrap();
+    savepoint.rollback();
+    let second = tx.commit().await.unwrap();
+    let names: Vec<String> = helper
+        .ls_files(&[])
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect();
+    assert!(names.contains(&"kept".to_string()));
+    assert!(!names.contains(&"undone".to_string()));
+
+    // Dropping a transaction restores the tree, and committing nothing keeps the root.
+    {
+        let mut tx = helper.begin();
+        tx.rm(&["batch".into()]).await.unwrap();
+    }
+    assert_eq!(helper.ls_files(&["batch".into()]).await.unwrap().len(), 49);
+    assert_eq!(helper.begin().commit().await.unwrap(), second);
+    assert_eq!(helper.root_history().len(), 3);
+}
+
+#[tokio::test]
+async fn test_merge_forests_keeps_both_devices_changes() {
+    let dir = tempfile::tempdir().unwrap();
+    let store = KVBlockStore::new(
+        dir.path().join("store").to_string_lossy().to_string(),
+        CODEC_DAG_CBOR,
+    );
+    let blockstore = &mut FFIFriendlyBlockStore::new(Box::new(store));
+    let (mut phone, _, initial) = PrivateDirectoryHelper::init(blockstore, vec![0; 32])
+        .await
+        .unwrap();
+    phone
+        .write_file(&["shared.txt".into()], b"original".to_vec(), 1_000)
+        .await
+        .unwrap();
+    phone
+        .write_file(&["old.txt".into()], b"old".to_vec(), 1_000)
+        .await
+        .unwrap();
+    phone
+        .write_file(&["edited.txt".into()], b"draft".to_vec(), 1_000)
+        .await
+        .unwrap();
+    let base = phone.root_history().last().copied().unwrap();
+    assert_ne!(base, initial);
+    let mut laptop = PrivateDirectoryHelper::load_with_wnfs_key(blockstore, base, vec![0; 32])
+        .await
+        .unwrap();
+
+    phone
+        .write_file(&["photos".into(), "a.jpg".into()], b"jpeg".to_vec(), 0)
+        .await
+        .unwrap();
+    let phone_root = phone
+        .write_file(&["shared.txt".into()], b"from phone".to_vec(), 2_000)
+        .await
+        .unwrap();
+    phone
+        .write_file(&["edited.txt".into()], b"final".to_vec(), 2_000)
+        .await
+        .unwrap();
+    laptop.rm(&["old.txt".into()]).await.unwrap();
+    laptop.rm(&["edited.txt".into()]).await.unwrap();
+    laptop
+        .write_file(&["notes.md".into()], b"# notes".to_vec(), 0)
+        .await
+        .unwrap();
+    let laptop_root = laptop
+        .write_file(&["shared.txt".into()], b"from laptop".to_vec(), 3_000)
+        .await
+        .unwrap();
+
+    let diff = phone.diff_forests(phone_root, laptop_root).await.unwrap();
+    assert_eq!(diff.added, vec![vec!["notes.md".to_string()]]);
+    assert_eq!(
+        diff.removed,
+        vec![
+            vec!["edited.txt".to_string()],
+            vec!["old.txt".to_string()],
+            vec!["photos".to_string()],
+            vec!["photos".into(), "a.jpg".into()]
+        ]
+    );
+    assert_eq!(diff.modified, vec![vec!["shared.txt".to_string()]]);
+    assert!(phone
+        .diff_forests(phone_root, phone_root)
+        .await
+        .unwrap()
+        .is_empty());
+
+    let merged = phone
+        .merge_forests(Some(base), phone_root, laptop_root)
+        .await
+        .unwrap();
+    assert_eq!(phone.root_history().last(), Some(&merged));
+    assert_eq!(
+        phone.read_file(&["notes.md".into()]).await.unwrap(),
+        b"# notes"
+    );
+    assert_eq!(
+        phone
+            .read_file(&["photos".into(), "a.jpg".into()])
+            .await
+            .unwrap(),
+        b"jpeg"
+    );
+    // The laptop wrote the shared file last.
+    assert_eq!(
+        phone.read_file(&["shared.txt".into()]).await.unwrap(),
+        b"from laptop"
+    );
+    // The laptop's deletion applies where the phone didn't change the file since the base.
+    assert!(phone.node_at(&["old.txt".into()]).await.unwrap().is_none());
+    assert_eq!(
+        phone.read_file(&["edited.txt".into()]).await.unwrap(),
+        b"final"
+    );
+    let reopened = PrivateDirectoryHelper::load_with_wnfs_key(blockstore, merged, vec![0; 32])
+        .await
+        .unwrap()
+        .diff_forests(merged, laptop_root)
+        .await
+        .unwrap();
+    assert!(reopened.added.is_empty() && reopened.modified.is_empty());
+}
+
+#[tokio::test]
+async fn test_batch_operations_report_each_item() {
+    use crate::error::WnfsUtilsError;
+    use crate::private_forest::{BatchErrorKind, BatchItemError, BatchOptions};
+
+    let dir = tempfile::tempdir().unwrap();
+    let store = KVBlockStore::new(
+        dir.path().join("store").to_string_lossy().to_string(),
+        CODEC_DAG_CBOR,
+    );
+    let blockstore = &mut FFIFriendlyBlockStore::new(Box::new(store));
+    let (mut helper, _, initial) = PrivateDirectoryHelper::init(blockstore, vec![0; 32])
+        .await
+        .unwrap();
+    let files = vec![
+        (vec!["a.txt".to_string()], b"a".to_vec(), 0),
+        (vec!["".to_string(), "b.txt".into()], b"b".to_vec(), 0),
+        (vec!["c.txt".to_string()], b"c".to_vec(), 0),
+    ];
+
+    // Stopping at the failure still commits the items before it.
+    let report = helper
+        .write_files(files.clone(), BatchOptions::default())
+        .await
+        .unwrap();
+    assert!(report.stopped);
+    assert_eq!((report.items.len(), report.succeeded()), (2, 1));
+    let failure = report.failures().next().unwrap();
+    assert_eq!(failure.path, vec!["".to_string(), "b.txt".into()]);
+    assert_eq!(
+        failure.result.as_ref().unwrap_err().kind,
+        BatchErrorKind::Invalid
+    );
+    assert_eq!(helper.root_history().len(), 2);
+    assert!(helper.read_file(&["c.txt".into()]).await.is_err());
+
+    let options = BatchOptions {
+        continue_on_error: true,
+    };
+    let report = helper.write_files(files, options).await.unwrap();
+    assert!(!report.stopped);
+    assert_eq!((report.items.len(), report.succeeded()), (3, 2));
+    assert_eq!(helper.read_file(&["c.txt".into()]).await.unwrap(), b"c");
+    assert_eq!(helper.root_history().last(), report.forest_cid.as_ref());
+
+    let report = helper
+        .rm_recursive(
+            &[
+                vec!["a.txt".into()],
+                vec!["missing".into()],
+                vec!["c.txt".into()],
+            ],
+            options,
+        )
+        .await
+        .unwrap();
+    assert_eq!(report.succeeded(), 2);
+    assert_eq!(
+        report.items[1].result.as_ref().unwrap_err().kind,
+        BatchErrorKind::NotFound
+    );
+    // Kinds come from the error's code, not from its wording.
+    let mismatch = WnfsUtilsError::CopyMismatch {
+        path: "a.txt".into(),
+    };
+    assert_eq!(
+        BatchItemError::from(mismatch.to_owned()).kind,
+        BatchErrorKind::Integrity
+    );
+    assert_eq!(
+        BatchItemError::from(mismatch.to_string()).kind,
+        BatchErrorKind::Integrity
+    );
+    assert_eq!(
+        BatchItemError::from("integrity check failed for a.txt".to_string()).kind,
+        BatchErrorKind::Other
+    );
+    assert!(helper.ls_files(&[]).await.unwrap().is_empty());
+    assert_eq!(helper.root_history().len(), 4);
+
+    // Nothing succeeded, so nothing is committed.
+    let report = helper
+        .rm_recursive(&[vec!["a.txt".into()]], options)
+        .await
+        .unwrap();
+    assert_eq!(report.forest_cid, None);
+    assert_eq!(helper.root_history().len(), 4);
+    assert_ne!(helper.root_history().last(), Some(&initial));
+}
+
+#[tokio::test]
+async fn test_observed_operations_report_progress_and_cancel() {
+    use std::sync::{Arc, Mutex};
+
+    use crate::error::{ErrorCode, WnfsUtilsError};
+    use crate::progress::{
+        CancellationToken, OperationObserver, OperationProgress, ProgressCallback,
+    };
+
+    #[derive(Default)]
+    struct Collect(Mutex<Vec<OperationProgress>>);
+
+    impl ProgressCallback for Collect {
+        fn on_progress(&self, progress: &OperationProgress) {
+            self.0.lock().unwrap().push(progress.to_owned());
+        }
+    }
+
+    let dir = tempfile::tempdir().unwrap();
+    let store = KVBlockStore::new(
+        dir.path().join("store").to_string_lossy().to_string(),
+        CODEC_DAG_CBOR,
+    );
+    let blockstore = &mut FFIFriendlyBlockStore::new(Box::new(store));
+    let (mut helper, _, initial) = PrivateDirectoryHelper::init(blockstore, vec![24; 32])
+        .await
+        .unwrap();
+    let collect = Arc::new(Collect::default());
+    let observer = OperationObserver {
+        callback: Some(collect.clone()),
+        cancellation: Some(CancellationToken::new()),
+    };
+    let content: Vec<u8> = (0..600_000u32).map(|i| (i % 251) as u8).collect();
+
+    let root = helper
+        .write_file_observed(&["big.bin".into()], content.clone(), 0, &observer)
+        .await
+        .unwrap();
+    let writes = std::mem::take(&mut *collect.0.lock().unwrap());
+    assert!(writes.len() > 1);
+    assert!(writes
+        .windows(2)
+        .all(|pair| pair[0].bytes_done < pair[1].bytes_done));
+    let last = writes.last().unwrap();
+    assert_eq!(last.operation, "write_file");
+    assert_eq!(
+        (last.bytes_done, last.bytes_total),
+        (600_000, Some(600_000))
+    );
+
+    let mut reloaded = PrivateDirectoryHelper::load_with_wnfs_key_observed(
+        blockstore,
+        root,
+        vec![24; 32],
+        &observer,
+    )
+    .await
+    .unwrap();
+    let read = reloaded
+        .read_file_observed(&["big.bin".into()], &observer)
+        .await
+        .unwrap();
+    assert_eq!(read, content);
+    let reads = std::mem::take(&mut *collect.0.lock().unwrap());
+    assert_eq!(reads[0].operation, "load_forest");
+    let last = reads.last().unwrap();
+    assert_eq!((last.operation, last.bytes_done), ("read_file", 600_000));
+    assert!(last.blocks_fetched > 0);
+
+    // Cancelling from the callback stops the write mid-stream, leaving nothing behind.
+    struct CancelAt(u64, CancellationToken);
+
+    impl ProgressCallback for CancelAt {
+        fn on_progress(&self, progress: &OperationProgress) {
+            if progress.bytes_done >= self.0 {
+                self.1.cancel();
+            }
+        }
+    }
+
+    let token = CancellationToken::new();
+    let cancelling = OperationObserver {
+        callback: Some(Arc::new(CancelAt(200_000, token.clone()))),
+        cancellation: Some(token.clone()),
+    };
+    let cancelled = helper
+        .write_file_observed(&["partial.bin".into()], content.clone(), 0, &cancelling)
+        .await
+        .unwrap_err();
+    assert!(token.is_cancelled());
+    assert_eq!(ErrorCode::of_message(&cancelled), ErrorCode::Cancelled);
+    assert!(helper.read_file(&["partial.bin".into()]).await.is_err());
+    assert_eq!(helper.root_history(), &[initial, root]);
+
+    // So does a write cancelled before it started.
+    observer.cancellation.as_ref().unwrap().cancel();
+    let cancelled = helper
+        .write_file_observed(&["other.bin".into()], content, 0, &observer)
+        .await;
+    assert_eq!(cancelled, Err(WnfsUtilsError::Cancelled.to_string()));
+    assert!(helper.read_file(&["other.bin".into()]).await.is_err());
+    assert_eq!(helper.root_history(), &[initial, root]);
+    assert!(reloaded
+        .read_file_observed(&["big.bin".into()], &observer)
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn test_rotate_wnfs_key_reencrypts_the_tree() {
+    use crate::error::WnfsUtilsError;
+    use crate::private_forest::{OperationLimiter, OperationLimits};
+
+    let dir = tempfile::tempdir().unwrap();
+    let store = KVBlockStore::new(
+        dir.path().join("store").to_string_lossy().to_string(),
+        CODEC_DAG_CBOR,
+    );
+    let blockstore = &mut FFIFriendlyBlockStore::new(Box::new(store));
+    let (mut helper, _, _) = PrivateDirectoryHelper::init(blockstore, vec![1; 32])
+        .await
+        .unwrap();
+    helper
+        .write_file(&["docs".into(), "a.txt".into()], b"alpha".to_vec(), 1_000)
+        .await
+        .unwrap();
+    helper.mark_checkpoint();
+    let old_root = helper
+        .write_file(&["b.txt".into()], b"beta".to_vec(), 2_000)
+        .await
+        .unwrap();
+    let limits = OperationLimits {
+        max_in_flight: 2,
+        ..Default::default()
+    };
+    helper.set_operation_limiter(OperationLimiter::new(Some(limits)));
+    let held: Vec<String> = vec!["docs".into()];
+    let hold_root = helper
+        .place_legal_hold(&held, "audit", b"custodian")
+        .await
+        .unwrap();
+    let history = helper.root_history().to_vec();
+    let docs_modified = |listing: &[(String, wnfs::common::Metadata)]| {
+        listing
+            .iter()
+            .find(|(name, _)| name == "docs")
+            .and_then(|(_, metadata)| metadata.get_modified())
+    };
+    let modified_before = docs_modified(&helper.ls_files(&[]).await.unwrap());
+
+    assert_eq!(
+        helper.rotate_wnfs_key(&[2; 32], vec![3; 32]).await,
+        Err(WnfsUtilsError::WrongKey.to_string())
+    );
+    let new_root = helper.rotate_wnfs_key(&[1; 32], vec![3; 32]).await.unwrap();
+    assert_ne!(new_root, old_root);
+    assert_ne!(new_root, hold_root);
+    // The helper keeps its history and checkpoints, followed by the new forest's root.
+    assert_eq!(
+        helper.root_history(),
+        [history.as_slice(), &[new_root]].concat()
+    );
+    assert!(helper.checkpoints.contains(&history[1]));
+    // Its limiter and holds stay in place, and directories keep their times.
+    assert_eq!(helper.operation_limiter().limits(), Some(limits));
+    assert_eq!(helper.legal_holds().await.unwrap().len(), 1);
+    assert!(helper
+        .write_file(&["docs".into(), "c.txt".into()], b"gamma".to_vec(), 3_000)
+        .await
+        .is_err());
+    assert_eq!(
+        docs_modified(&helper.ls_files(&[]).await.unwrap()),
+        modified_before
+    );
+    assert_eq!(
+        helper
+            .read_file(&["docs".into(), "a.txt".into()])
+            .await
+            .unwrap(),
+        b"alpha"
+    );
+
+    let mut reloaded =
+        PrivateDirectoryHelper::load_with_wnfs_key(blockstore, new_root, vec![3; 32])
+            .await
+            .unwrap();
+    assert_eq!(
+        reloaded.read_file(&["b.txt".into()]).await.unwrap(),
+        b"beta"
+    );
+    let listing = reloaded.ls_files(&[]).await.unwrap();
+    let b = listing.iter().find(|(name, _)| name == "b.txt").unwrap();
+    assert_eq!(b.1.get_modified().map(|time| time.timestamp()), Some(2_000));
+    assert!(
+        PrivateDirectoryHelper::load_with_wnfs_key(blockstore, new_root, vec![1; 32])
+            .await
+            .is_err()
+    );
+}
+
+#[tokio::test]
+async fn test_attest_forest_state() {
+    use crate::private_forest::{DeviceSigningKey, SignedAttestation};
+
+    let dir = tempfile::tempdir().unwrap();
+    let store = KVBlockStore::new(
+        dir.path().join("store").to_string_lossy().to_string(),
+        CODEC_DAG_CBOR,
+    );
+    let blockstore = &mut FFIFriendlyBlockStore::new(Box::new(store));
+    let (mut helper, _, _) = PrivateDirectoryHelper::init(blockstore, vec![0; 32])
+        .await
+        .unwrap();
+    let root = helper
+        .write_file(&["a.txt".into()], b"alpha".to_vec(), 1_000)
+        .await
+        .unwrap();
+
+    let device = DeviceSigningKey::generate().unwrap();
+    let signed = helper.attest(&root, &device).await.unwrap();
+    assert_eq!(signed.attestation.forest_cid, root.to_string());
+    assert!(signed.attestation.total_blocks > 0);
+    assert_eq!(signed.attestation.device_did, device.did().unwrap());
+    assert!(signed.attestation.device_did.starts_with("did:key:z"));
+    let restored = DeviceSigningKey::from_seed(device.seed()).unwrap();
+    assert_eq!(restored.did().unwrap(), device.did().unwrap());
+
+    // Another device of the same forest signs as itself, not as the first one.
+    let other = DeviceSigningKey::generate().unwrap();
+    let other_signed = helper.attest(&root, &other).await.unwrap();
+    other_signed.verify().unwrap();
+    assert_ne!(other_signed.attestation.device_did, device.did().unwrap());
+    assert_eq!(
+        other_signed.attestation.merkle_root,
+        signed.attestation.merkle_root
+    );
+    let mut impersonated = other_signed.to_owned();
+    impersonated.attestation.device_did = device.did().unwrap();
+    assert!(impersonated.verify().is_err());
+    let shared = SignedAttestation::from_json(&signed.to_json().unwrap()).unwrap();
+    shared.verify().unwrap();
+    shared.check_store(blockstore).await.unwrap();
+
+    // Later writes leave the attested state in the store.
+    helper
+        .write_file(&["b.txt".into()], b"beta".to_vec(), 2_000)
+        .await
+        .unwrap();
+    shared.check_store(blockstore).await.unwrap();
+
+    let mut forged = shared.to_owned();
+    forged.attestation.total_blocks += 1;
+    assert!(forged.verify().is_err());
+
+    let empty_dir = tempfile::tempdir().unwrap();
+    let empty = FFIFriendlyBlockStore::new(Box::new(KVBlockStore::new(
+        empty_dir.path().join("store").to_string_lossy().to_string(),
+        CODEC_DAG_CBOR,
+    )));
+    assert!(shared.check_store(&empty).await.is_err());
+}
+
+#[tokio::test]
+async fn test_scoped_handle_permissions() {
+    use crate::{
+        error::WnfsUtilsError,
+        private_forest::{ExchangeKeyPair, Permissions},
+    };
+
+    let dir = tempfile::tempdir().unwrap();
+    let store = KVBlockStore::new(
+        dir.path().join("store").to_string_lossy().to_string(),
+        CODEC_DAG_CBOR,
+    );
+    let blockstore = &mut FFIFriendlyBlockStore::new(Box::new(store));
+    let (mut helper, _, _) = PrivateDirectoryHelper::init(blockstore, vec![0; 32])
+        .await
+        .unwrap();
+    helper
+        .write_file(&["secret.txt".into()], b"host only".to_vec(), 0)
+        .await
+        .unwrap();
+    helper.mkdir(&["plugin".into()]).await.unwrap();
+    let host = helper.into_handle().await.unwrap();
+
+    let handle = host
+        .scoped(&["plugin".into()], Permissions::READ | Permissions::WRITE)
+        .unwrap();
+    assert_eq!(
+        Permissions::from_bits(handle.permissions().bits()),
+        Ok(handle.permissions())
+    );
+    assert!(Permissions::from_bits(0x80).is_err());
+    handle
+        .write_file(&["notes.txt".into()], b"plugin data".to_vec(), 0)
+        .await
+        .unwrap();
+    assert_eq!(
+        host.read_file(&["plugin".into(), "notes.txt".into()])
+            .await
+            .unwrap(),
+        b"plugin data"
+    );
+    assert_eq!(
+        handle.read_file(&["notes.txt".into()]).await.unwrap(),
+        b"plugin data"
+    );
+
+    let denied = |operation: &str| {
+        Err(WnfsUtilsError::PermissionDenied {
+            operation: operation.to_string(),
+        }
+        .to_string())
+    };
+    assert_eq!(handle.rm(&["notes.txt".into()]).await, denied("rm"));
+    assert_eq!(
+        handle
+            .mv(&["notes.txt".into()], &["moved.txt".into()])
+            .await,
+        denied("mv")
+    );
+    let recipient = ExchangeKeyPair::generate().unwrap();
+    assert!(handle.share(&[], &recipient.public_key()).await.is_err());
+    assert!(handle.history(&["notes.txt".into()]).await.is_err());
+    // Paths can't climb out of the scope.
+    assert!(handle
+        .read_file(&["..".into(), "secret.txt".into()])
+        .await
+        .is_err());
+
+    let read_only = handle
+        .narrow(&[], Permissions::READ | Permissions::DELETE)
+        .unwrap();
+    assert_eq!(read_only.permissions(), Permissions::READ);
+    assert!(read_only
+        .write_file(&["other.txt".into()], b"x".to_vec(), 0)
+        .await
+        .is_err());
+
+    let full = host.scoped(&["plugin".into()], Permissions::ALL).unwrap();
+    assert!(full.rm(&[]).await.is_err());
+    full.rm(&["notes.txt".into()]).await.unwrap();
+    assert!(host.ls_files(&["plugin".into()]).await.unwrap().is_empty());
+    // Handles are the only way in, and the plugin's see the host's writes.
+    host.write_file(
+        &["plugin".into(), "from-host.txt".into()],
+        b"hi".to_vec(),
+        0,
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        read_only
+            .read_file(&["from-host.txt".into()])
+            .await
+            .unwrap(),
+        b"hi"
+    );
+}
+
+#[tokio::test]
+async fn test_adaptive_chunking() {
+    use crate::private_forest::{AdaptiveChunking, FileSizeClass, PagedFileOptions};
+
+    let dir = tempfile::tempdir().unwrap();
+    let store = KVBlockStore::new(
+        dir.path().join("store").to_string_lossy().to_string(),
+        CODEC_DAG_CBOR,
+    );
+    let blockstore = &mut FFIFriendlyBlockStore::new(Box::new(store));
+    let (mut helper, _, _) = PrivateDirectoryHelper::init(blockstore, vec![0; 32])
+        .await
+        .unwrap();
+    let policy = AdaptiveChunking {
+        medium_from: 1024,
+        large_from: 64 * 1024,
+        small: PagedFileOptions {
+            page_size: 256,
+            pages_per_chunk: 1,
+        },
+        medium: PagedFileOptions {
+            page_size: 256,
+            pages_per_chunk: 4,
+        },
+        large: PagedFileOptions {
+            page_size: 4096,
+            pages_per_chunk: 4,
+        },
+    };
+    assert_eq!(policy.class_of(100), FileSizeClass::Small);
+    assert_eq!(policy.class_of(1024), FileSizeClass::Medium);
+    assert_eq!(policy.class_of(1 << 20), FileSizeClass::Large);
+    helper.set_config(HelperConfig {
+        adaptive_chunking: Some(policy),
+        ..Default::default()
+    });
+
+    let document = vec![7u8; 600];
+    let video: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+    helper
+        .write_paged_file(&["doc.db".into()], &document)
+        .await
+        .unwrap();
+    helper
+        .write_paged_file(&["video.bin".into()], &video)
+        .await
+        .unwrap();
+    assert_eq!(
+        helper.paged_file_options(&["doc.db".into()]).await.unwrap(),
+        policy.small
+    );
+    assert_eq!(
+        helper
+            .paged_file_options(&["video.bin".into()])
+            .await
+            .unwrap(),
+        policy.large
+    );
+    assert_eq!(
+        helper
+            .read_at(&["video.bin".into()], 0, video.len())
+            .await
+            .unwrap(),
+        video
+    );
+
+    // The document grows into the medium class and is moved on the next adaptive rechunk.
+    helper
+        .write_at(&["doc.db".into()], 600, &[9u8; 1000])
+        .await
+        .unwrap();
+    assert_eq!(helper.rechunk_adaptive(&[], None).await.unwrap(), 1);
+    assert_eq!(
+        helper.paged_file_options(&["doc.db".into()]).await.unwrap(),
+        policy.medium
+    );
+    let content = helper.read_at(&["doc.db".into()], 0, 1600).await.unwrap();
+    assert_eq!(&content[..600], &document[..]);
+    assert_eq!(&content[600..], &[9u8; 1000][..]);
+    assert_eq!(helper.rechunk_adaptive(&[], None).await.unwrap(), 0);
+
+    // The configured policy also applies to plain writes, replacing the file written before.
+    let notes: Vec<String> = vec!["notes.txt".into()];
+    helper
+        .write_file(&notes, document.clone(), 0)
+        .await
+        .unwrap();
+    assert_eq!(
+        helper.paged_file_options(&notes).await.unwrap(),
+        policy.small
+    );
+    assert_eq!(helper.read_file(&notes).await.unwrap(), document);
+    helper.write_file(&notes, video.clone(), 0).await.unwrap();
+    assert_eq!(
+        helper.paged_file_options(&notes).await.unwrap(),
+        policy.large
+    );
+    assert_eq!(helper.read_file(&notes).await.unwrap(), video);
+
+    // A stream of unknown length that ends past `large_from` is rechunked once it is complete.
+    let streamed: Vec<String> = vec!["streamed.bin".into()];
+    helper
+        .write_file_from_reader(&streamed, futures::io::Cursor::new(video.clone()), 0)
+        .await
+        .unwrap();
+    assert_eq!(
+        helper.paged_file_options(&streamed).await.unwrap(),
+        policy.large
+    );
+    assert_eq!(helper.read_file(&streamed).await.unwrap(), video);
+
+    // Imports too, and they are verified against the paged content.
+    let local = dir.path().join("local");
+    std::fs::create_dir_all(&local).unwrap();
+    std::fs::write(local.join("report.txt"), vec![3u8; 2000]).unwrap();
+    helper
+        .ingest(
+            &local.to_string_lossy().to_string(),
+            &["imported".into()],
+            Default::default(),
+            None,
+        )
+        .await
+        .unwrap();
+    let report: Vec<String> = vec!["imported".into(), "report.txt".into()];
+    assert_eq!(
+        helper.paged_file_options(&report).await.unwrap(),
+        policy.medium
+    );
+    assert_eq!(helper.read_file(&report).await.unwrap(), vec![3u8; 2000]);
+}
+
+#[tokio::test]
+async fn test_sync_status_tracks_unpublished_and_remote_changes() {
+    let dir = tempfile::tempdir().unwrap();
+    let store = KVBlockStore::new(
+        dir.path().join("store").to_string_lossy().to_string(),
+        CODEC_DAG_CBOR,
+    );
+    let blockstore = &mut FFIFriendlyBlockStore::new(Box::new(store));
+    let (mut phone, _, initial) = PrivateDirectoryHelper::init(blockstore, vec![0; 32])
+        .await
+        .unwrap();
+    let mut laptop = PrivateDirectoryHelper::load_with_wnfs_key(blockstore, initial, vec![0; 32])
+        .await
+        .unwrap();
+    phone.mark_published();
+    let status = phone.sync_status();
+    assert!(status.is_synced());
+    assert_eq!(status.pending_writeback.blocks, 0);
+
+    let laptop_root = laptop.mkdir(&["from-laptop".into()]).await.unwrap();
+    phone.record_remote_root(laptop_root);
+    let status = phone.sync_status();
+    assert_eq!(status.last_remote_root, Some(laptop_root));
+    assert!(status.remote_changes);
+    assert!(!status.unresolved_conflict);
+    assert!(!status.is_synced());
+
+    let phone_root = phone.mkdir(&["from-phone".into()]).await.unwrap();
+    let status = phone.sync_status();
+    assert!(status.unpublished);
+    assert!(status.unresolved_conflict);
+    assert_eq!(status.items_pending(), 1);
+
+    phone
+        .merge_forests(Some(initial), phone_root, laptop_root)
+        .await
+        .unwrap();
+    let status = phone.sync_status();
+    assert!(!status.remote_changes);
+    assert!(!status.unresolved_conflict);
+    assert!(status.unpublished);
+    phone.mark_published();
+    assert!(phone.sync_status().is_synced());
+
+    // Publishing over a remote root that wasn't merged doesn't hide its changes.
+    let laptop_root = laptop.mkdir(&["again".into()]).await.unwrap();
+    phone.record_remote_root(laptop_root);
+    phone.mkdir(&["overwrite".into()]).await.unwrap();
+    phone.mark_published();
+    let status = phone.sync_status();
+    assert!(status.remote_changes);
+    assert!(!status.unresolved_conflict);
+    assert!(!status.is_synced());
+}
+
+#[tokio::test]
+async fn test_squash_revisions_keeps_checkpoints_and_run_ends() {
+    use std::time::Duration;
+
+    use crate::blockstore::CompactionSchedule;
+    use crate::private_forest::{HelperConfig, RevisionSquashing};
+
+    let dir = tempfile::tempdir().unwrap();
+    let store = KVBlockStore::new(
+        dir.path().join("store").to_string_lossy().to_string(),
+        CODEC_DAG_CBOR,
+    );
+    let blockstore = &mut FFIFriendlyBlockStore::new(Box::new(store));
+    let (mut helper, _, _) = PrivateDirectoryHelper::init(blockstore, vec![0; 32])
+        .await
+        .unwrap();
+    let path = ["notes.txt".to_string()];
+    let mut roots = Vec::new();
+    for keystroke in 1..=5 {
+        let content = "draft".as_bytes()[..keystroke].to_vec();
+        roots.push(helper.write_file(&path, content, 0).await.unwrap());
+        if keystroke == 2 {
+            helper.mark_checkpoint();
+        }
+    }
+    // Without a policy nothing is squashed.
+    assert_eq!(helper.squash_revisions(), 0);
+    assert_eq!(helper.history(&path).await.unwrap().len(), 5);
+
+    helper.set_config(HelperConfig {
+        revision_squashing: Some(RevisionSquashing {
+            run_gap_ms: 60_000,
+            ..Default::default()
+        }),
+        ..Default::default()
+    });
+    let squashed = helper.squash_revisions();
+    assert_eq!(helper.root_history(), &[roots[1], roots[4]]);
+    assert_eq!(squashed, 4);
+    // The revisions of squashed roots are still listed, just without a root.
+    let history = helper.history(&path).await.unwrap();
+    assert_eq!(history.len(), 4);
+    assert_eq!(history[0].root_cid, Some(roots[1]));
+    assert_eq!(history[1].root_cid, None);
+    assert_eq!(
+        helper.read_file_at_revision(&path, 0).await.unwrap(),
+        b"dr".to_vec()
+    );
+    assert_eq!(
+        helper.read_file_at_revision(&path, 1).await.unwrap(),
+        b"dra".to_vec()
+    );
+    assert_eq!(helper.read_file(&path).await.unwrap(), b"draft".to_vec());
+    // Squashed roots can still be opened directly.
+    let mut view = helper.open_at(roots[2]).await.unwrap();
+    assert_eq!(view.read_file(&path).await.unwrap(), b"dra".to_vec());
+
+    helper.set_config(HelperConfig {
+        revision_squashing: Some(RevisionSquashing {
+            run_gap_ms: 0,
+            max_revisions: 1,
+        }),
+        ..Default::default()
+    });
+    assert_eq!(helper.squash_revisions(), 1);
+    assert_eq!(helper.root_history(), &[roots[4]]);
+
+    // Idle maintenance stores the history, which the next helper goes on squashing.
+    let config = HelperConfig {
+        revision_squashing: Some(RevisionSquashing {
+            run_gap_ms: 60_000,
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    helper.set_config(config.to_owned());
+    helper.mark_checkpoint();
+    let later = helper
+        .write_file(&path, b"drafts".to_vec(), 0)
+        .await
+        .unwrap();
+    let schedule = CompactionSchedule::new(Duration::ZERO, Duration::ZERO);
+    // The first poll only notes the writes so far.
+    assert!(helper.maintain_if_idle(&schedule).await.unwrap().is_none());
+    let report = helper.maintain_if_idle(&schedule).await.unwrap().unwrap();
+    assert_eq!(report.squashed, 0);
+    let persisted = report.persisted.unwrap();
+    assert_eq!(helper.root_history(), &[roots[4], later, persisted]);
+    assert_eq!(helper.persist_revisions().await.unwrap(), None);
+
+    let mut reloaded =
+        PrivateDirectoryHelper::load_with_wnfs_key(blockstore, persisted, vec![0; 32])
+            .await
+            .unwrap();
+    assert_eq!(reloaded.root_history(), &[roots[4], later, persisted]);
+    reloaded.set_config(config);
+    assert_eq!(reloaded.squash_revisions(), 1);
+    assert_eq!(reloaded.root_history(), &[roots[4], persisted]);
+}
+
+#[tokio::test]
+async fn test_helper_handle_reads_while_a_write_is_in_flight() {
+    use crate::private_forest::{handle_channel, serve_handle, HandleBridge};
+
+    let dir = tempfile::tempdir().unwrap();
+    let store = KVBlockStore::new(
+        dir.path().join("store").to_string_lossy().to_string(),
+        CODEC_DAG_CBOR,
+    );
+    let blockstore = &mut FFIFriendlyBlockStore::new(Box::new(store));
+    let (helper, _, _) = PrivateDirectoryHelper::init(blockstore, vec![0; 32])
+        .await
+        .unwrap();
+    let handle = helper.into_handle().await.unwrap();
+    let first = ["first.txt".to_string()];
+    let second = ["second.txt".to_string()];
+    handle
+        .write_file(&first, b"first".to_vec(), 0)
+        .await
+        .unwrap();
+
+    // A reader doesn't wait for the write holding the helper, and sees the last completed one.
+    let reader = handle.clone();
+    let (written, names) = handle
+        .write(move |helper| {
+            Box::pin(async move {
+                let written = helper.write_file(&second, b"second".to_vec(), 0).await?;
+                let (names, content) =
+                    futures::join!(reader.ls_files(&[]), reader.read_file(&first));
+                assert_eq!(content?, b"first".to_vec());
+                Ok((written, names?))
+            })
+        })
+        .await
+        .unwrap();
+    assert_eq!(names.len(), 1);
+    assert_eq!(handle.root_cid(), written);
+    assert!(handle.exists(&["second.txt".into()]).await.unwrap());
+
+    // Concurrent writes are serialized, so neither is lost.
+    let (a, b) = futures::join!(
+        handle.mkdir(&["a".into()]),
+        handle.write_file(&["b.txt".into()], b"b".to_vec(), 0)
+    );
+    a.unwrap();
+    b.unwrap();
+    let names: Vec<String> = handle
+        .ls_files(&[])
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect();
+    assert_eq!(names.len(), 4);
+    assert!(names.contains(&"a".to_string()) && names.contains(&"b.txt".to_string()));
+
+    // Other threads reach the handle through a bridge served on this one.
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<HandleBridge>();
+    let (bridge, requests) = handle_channel(4);
+    let client = std::thread::spawn(move || {
+        futures::executor::block_on(async {
+            bridge
+                .write_file(&["c.txt".into()], b"c".to_vec(), 0)
+                .await
+                .unwrap();
+            bridge
+                .mv(&["c.txt".into()], &["d.txt".into()])
+                .await
+                .unwrap();
+            bridge.read_file(&["d.txt".into()]).await.unwrap()
+        })
+    });
+    serve_handle(handle.clone(), requests).await;
+    assert_eq!(client.join().unwrap(), b"c".to_vec());
+    assert!(handle.exists(&["d.txt".into()]).await.unwrap());
+    assert!(handle.into_inner().is_ok());
+}
+
+#[tokio::test]
+async fn test_content_scanners_store_metadata_and_veto_writes() {
+    use std::rc::Rc;
+
+    use async_trait::async_trait;
+    use libipld::Ipld;
+
+    use crate::private_forest::{
+        ContentScan, ContentScanner, PagedFileOptions, STREAM_CHUNK_BYTES,
+    };
+
+    struct LengthScanner;
+
+    struct LengthScan {
+        bytes: usize,
+        largest_chunk: usize,
+    }
+
+    impl ContentScanner for LengthScanner {
+        fn name(&self) -> &str {
+            "length"
+        }
+
+        fn start(&self, _path_segments: &[String]) -> Option<Box<dyn ContentScan>> {
+            Some(Box::new(LengthScan {
+                bytes: 0,
+                largest_chunk: 0,
+            }))
+        }
+    }
+
+    #[async_trait(?Send)]
+    impl ContentScan for LengthScan {
+        async fn update(&mut self, chunk: &[u8]) -> Result<(), String> {
+            self.bytes += chunk.len();
+            self.largest_chunk = self.largest_chunk.max(chunk.len());
+            Ok(())
+        }
+
+        async fn finish(self: Box<Self>) -> Result<Vec<(String, Ipld)>, String> {
+            assert!(self.largest_chunk <= STREAM_CHUNK_BYTES);
+            Ok(vec![(
+                "length.bytes".to_string(),
+                Ipld::Integer(self.bytes as i128),
+            )])
+        }
+    }
+
+    // Refuses content containing "EICAR", and anything written below "quarantine".
+    struct PolicyScanner;
+
+    struct PolicyScan {
+        quarantined: bool,
+    }
+
+    impl ContentScanner for PolicyScanner {
+        fn name(&self) -> &str {
+            "policy"
+        }
+
+        fn start(&self, path_segments: &[String]) -> Option<Box<dyn ContentScan>> {
+            Some(Box::new(PolicyScan {
+                quarantined: path_segments.first().map(String::as_str) == Some("quarantine"),
+            }))
+        }
+    }
+
+    #[async_trait(?Send)]
+    impl ContentScan for PolicyScan {
+        async fn update(&mut self, chunk: &[u8]) -> Result<(), String> {
+            match chunk.windows(5).any(|window| window == b"EICAR") {
+                true => Err("test signature found".to_string()),
+                false => Ok(()),
+            }
+        }
+
+        async fn finish(self: Box<Self>) -> Result<Vec<(String, Ipld)>, String> {
+            match self.quarantined {
+                true => Err("quarantined path".to_string()),
+                false => Ok(Vec::new()),
+            }
+        }
+    }
+
+    let dir = tempfile::tempdir().unwrap();
+    let store = KVBlockStore::new(
+        dir.path().join("store").to_string_lossy().to_string(),
+        CODEC_DAG_CBOR,
+    );
+    let blockstore = &mut FFIFriendlyBlockStore::new(Box::new(store));
+    let (mut helper, _, _) = PrivateDirectoryHelper::init(blockstore, vec![0; 32])
+        .await
+        .unwrap();
+    helper.add_content_scanner(Rc::new(LengthScanner));
+    helper.add_content_scanner(Rc::new(PolicyScanner));
+
+    helper
+        .write_file(&["note.txt".into()], b"hello".to_vec(), 0)
+        .await
+        .unwrap();
+    let data = vec![7u8; 600 * 1024];
+    let source = dir.path().join("large.bin");
+    std::fs::write(&source, &data).unwrap();
+    helper
+        .write_file_stream_from_path(
+            &["large.bin".into()],
+            &source.to_string_lossy().into_owned(),
+        )
+        .await
+        .unwrap();
+    let listing = helper.ls_files(&[]).await.unwrap();
+    let length = |name: &str| {
+        listing
+            .iter()
+            .find(|(entry, _)| entry == name)
+            .and_then(|(_, metadata)| metadata.get("length.bytes").cloned())
+    };
+    assert_eq!(length("note.txt"), Some(Ipld::Integer(5)));
+    assert_eq!(length("large.bin"), Some(Ipld::Integer(600 * 1024)));
+    assert_eq!(helper.read_file(&["large.bin".into()]).await.unwrap(), data);
+
+    let err = helper
+        .write_file(&["note.txt".into()], b"an EICAR test".to_vec(), 0)
+        .await
+        .unwrap_err();
+    assert!(err.contains("policy rejected the write"));
+    assert_eq!(
+        helper.read_file(&["note.txt".into()]).await.unwrap(),
+        b"hello".to_vec()
+    );
+
+    // A veto after the stream was written leaves the tree unchanged.
+    helper.mkdir(&["quarantine".into()]).await.unwrap();
+    let root = *helper.root_history().last().unwrap();
+    let err = helper
+        .write_file_stream_from_path(
+            &["quarantine".into(), "large.bin".into()],
+            &source.to_string_lossy().into_owned(),
+        )
+        .await
+        .unwrap_err();
+    assert!(err.contains("quarantined path"));
+    assert!(helper
+        .ls_files(&["quarantine".into()])
+        .await
+        .unwrap()
+        .is_empty());
+    assert_eq!(helper.root_history().last(), Some(&root));
+
+    // Imports, copies between forests and paged writes are scanned like `write_file`.
+    let local = dir.path().join("import");
+    std::fs::create_dir(&local).unwrap();
+    std::fs::write(local.join("ok.txt"), b"fine").unwrap();
+    helper
+        .ingest(
+            &local.to_string_lossy().into_owned(),
+            &["imported".into()],
+            Default::default(),
+            None,
+        )
+        .await
+        .unwrap();
+    let listing = helper.ls_files(&["imported".into()]).await.unwrap();
+    assert_eq!(listing[0].1.get("length.bytes"), Some(&Ipld::Integer(4)));
+    std::fs::write(local.join("ok.txt"), b"EICAR").unwrap();
+    let err = helper
+        .ingest(
+            &local.to_string_lossy().into_owned(),
+            &["imported".into()],
+            Default::default(),
+            None,
+        )
+        .await
+        .unwrap_err();
+    assert!(err.contains("policy rejected the write"));
+    assert_eq!(
+        helper
+            .read_file(&["imported".into(), "ok.txt".into()])
+            .await
+            .unwrap(),
+        b"fine".to_vec()
+    );
+
+    let dst_store = KVBlockStore::new(
+        dir.path().join("dst").to_string_lossy().to_string(),
+        CODEC_DAG_CBOR,
+    );
+    let dst_blockstore = &mut FFIFriendlyBlockStore::new(Box::new(dst_store));
+    let (mut dst, _, _) = PrivateDirectoryHelper::init(dst_blockstore, vec![1; 32])
+        .await
+        .unwrap();
+    dst.add_content_scanner(Rc::new(PolicyScanner));
+    let err = PrivateDirectoryHelper::copy_between(
+        &mut helper,
+        &["note.txt".into()],
+        &mut dst,
+        &["quarantine".into(), "note.txt".into()],
+    )
+    .await
+    .unwrap_err();
+    assert!(err.contains("quarantined path"));
+    assert!(!dst
+        .exists(&["quarantine".into(), "note.txt".into()])
+        .await
+        .unwrap());
+
+    helper
+        .create_paged_file(&["db".into()], PagedFileOptions::default())
+        .await
+        .unwrap();
+    let err = helper
+        .write_at(&["db".into()], 0, b"EICAR")
+        .await
+        .unwrap_err();
+    assert!(err.contains("policy rejected the write"));
+    assert_eq!(helper.paged_file_len(&["db".into()]).await.unwrap(), 0);
+
+    helper.clear_content_scanners();
+    helper
+        .write_file(
+            &["quarantine".into(), "ok.txt".into()],
+            b"EICAR".to_vec(),
+            0,
+        )
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_du_ls_recursive_and_search() {
+    let dir = tempfile::tempdir().unwrap();
+    let store = KVBlockStore::new(
+        dir.path().join("store").to_string_lossy().to_string(),
+        CODEC_DAG_CBOR,
+    );
+    let blockstore = &mut FFIFriendlyBlockStore::new(Box::new(store));
+    let (mut helper, _, _) = PrivateDirectoryHelper::init(blockstore, vec![0; 32])
+        .await
+        .unwrap();
+    for (path, size) in [
+        ("docs/report.pdf", 10),
+        ("docs/notes/Todo.txt", 4),
+        ("photos/beach.jpg", 300_000),
+    ] {
+        let segments = PrivateDirectoryHelper::parse_path(path.to_string());
+        helper
+            .write_file(&segments, vec![1; size], 0)
+            .await
+            .unwrap();
+    }
+
+    let usage = helper.du(&[]).await.unwrap();
+    assert_eq!((usage.files, usage.directories), (3, 4));
+    assert!(usage.size >= 300_014);
+    // A node per entry, plus one content block per small file and two for the large one.
+    assert_eq!(usage.blocks, 7 + 4);
+    assert_eq!(helper.du(&["docs".into()]).await.unwrap().files, 2);
+
+    let listed: Vec<String> = helper
+        .ls_recursive(&[], 2)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|entry| entry.path.join("/"))
+        .collect();
+    assert_eq!(
+        listed,
+        vec![
+            "docs",
+            "docs/notes",
+            "docs/report.pdf",
+            "photos",
+            "photos/beach.jpg"
+        ]
+    );
+
+    let found = |entries: Vec<crate::private_forest::WalkEntry>| -> Vec<String> {
+        entries
+            .into_iter()
+            .map(|entry| entry.path.join("/"))
+            .collect()
+    };
+    assert_eq!(
+        found(helper.search(&[], "todo").await.unwrap()),
+        vec!["docs/notes/Todo.txt"]
+    );
+    assert_eq!(
+        found(helper.search(&[], "*.jp?").await.unwrap()),
+        vec!["photos/beach.jpg"]
+    );
+    assert_eq!(
+        found(helper.search(&["docs".into()], "*o*").await.unwrap()),
+        vec!["docs/notes", "docs/notes/Todo.txt", "docs/report.pdf"]
+    );
+    assert!(helper.search(&[], "*.txt.*").await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn test_file_metadata_can_be_set_and_listed() {
+    use crate::error::{ErrorCode, WnfsUtilsError};
+    use crate::private_forest::CONTENT_HASH_KEY;
+    use libipld::Ipld;
+
+    let dir = tempfile::tempdir().unwrap();
+    let store = KVBlockStore::new(
+        dir.path().join("store").to_string_lossy().to_string(),
+        CODEC_DAG_CBOR,
+    );
+    let blockstore = &mut FFIFriendlyBlockStore::new(Box::new(store));
+    let (mut helper, _, _) = PrivateDirectoryHelper::init(blockstore, vec![0; 32])
+        .await
+        .unwrap();
+    let path = vec!["docs".to_string(), "plan.md".to_string()];
+    helper
+        .write_file(&path, b"# plan".to_vec(), 1_700_000_000)
+        .await
+        .unwrap();
+
+    helper
+        .set_metadata(&path, "tag", Ipld::String("work".into()))
+        .await
+        .unwrap();
+    helper
+        .set_content_type(&path, "text/markdown")
+        .await
+        .unwrap();
+    let metadata = helper.get_metadata(&path).await.unwrap();
+    assert_eq!(metadata.modified, Some(1_700_000_000));
+    assert_eq!(metadata.content_type.as_deref(), Some("text/markdown"));
+    assert_eq!(
+        metadata.attributes.get("tag"),
+        Some(&Ipld::String("work".into()))
+    );
+    assert!(!metadata.attributes.contains_key("modified"));
+
+    helper.set_mtime(&path, 1_800_000_000).await.unwrap();
+    let listed = helper.ls_with_metadata(&["docs".into()]).await.unwrap();
+    assert_eq!(listed.len(), 1);
+    assert_eq!(listed[0].0, "plan.md");
+    assert_eq!(listed[0].1.modified, Some(1_800_000_000));
+    assert_eq!(listed[0].1.content_type.as_deref(), Some("text/markdown"));
+
+    // Both providers report the stored type over the one guessed from the name.
+    let row = helper.query_document("/docs/plan.md").await.unwrap();
+    assert_eq!(row.mime_type, "text/markdown");
+    let item = helper.item("docs/plan.md").await.unwrap();
+    assert_eq!(item.mime_type.as_deref(), Some("text/markdown"));
+
+    helper.remove_metadata(&path, "tag").await.unwrap();
+    let metadata = helper.get_metadata(&path).await.unwrap();
+    assert!(metadata.attributes.get("tag").is_none());
+    assert_eq!(metadata.modified, Some(1_800_000_000));
+    assert_eq!(helper.read_file(&path).await.unwrap(), b"# plan".to_vec());
+    let err = helper.remove_metadata(&path, "tag").await.unwrap_err();
+    assert_eq!(ErrorCode::of_message(&err), ErrorCode::NotFound);
+
+    // The times and the content hash are the helper's own, and directories and missing files
+    // can't be edited.
+    assert_eq!(
+        helper
+            .set_metadata(&path, "modified", Ipld::Integer(0))
+            .await
+            .unwrap_err(),
+        WnfsUtilsError::ReservedMetadataKey("modified".into()).to_string()
+    );
+    assert_eq!(
+        helper
+            .set_metadata(&path, CONTENT_HASH_KEY, Ipld::String("00".into()))
+            .await
+            .unwrap_err(),
+        WnfsUtilsError::ReservedMetadataKey(CONTENT_HASH_KEY.into()).to_string()
+    );
+    assert!(helper
+        .remove_metadata(&path, CONTENT_HASH_KEY)
+        .await
+        .is_err());
+    assert!(helper
+        .set_metadata(&["docs".into()], "tag", Ipld::Null)
+        .await
+        .is_err());
+    let missing = vec!["docs".to_string(), "missing.md".to_string()];
+    assert!(helper
+        .set_content_type(&missing, "text/plain")
+        .await
+        .is_err());
+    assert!(helper.node_at(&missing).await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn test_rollback_to_named_snapshots() {
+    let dir = tempfile::tempdir().unwrap();
+    let store = KVBlockStore::new(
+        dir.path().join("store").to_string_lossy().to_string(),
+        CODEC_DAG_CBOR,
+    );
+    let blockstore = &mut FFIFriendlyBlockStore::new(Box::new(store));
+    let (mut helper, _, _) = PrivateDirectoryHelper::init(blockstore, vec![0; 32])
+        .await
+        .unwrap();
+    let a = vec!["a.txt".to_string()];
+    let b = vec!["b.txt".to_string()];
+    helper.write_file(&a, b"first".to_vec(), 0).await.unwrap();
+    helper.create_snapshot("before").await.unwrap();
+    assert!(helper.create_snapshot("before").await.is_err());
+
+    helper.write_file(&a, b"second".to_vec(), 0).await.unwrap();
+    helper.write_file(&b, b"new".to_vec(), 0).await.unwrap();
+    helper.create_snapshot("after").await.unwrap();
+
+    helper.rollback("before").await.unwrap();
+    assert_eq!(helper.read_file(&a).await.unwrap(), b"first".to_vec());
+    assert!(helper.node_at(&b).await.unwrap().is_none());
+    // Later snapshots survive the rollback.
+    let names: Vec<String> = helper
+        .list_snapshots()
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|snapshot| snapshot.name)
+        .collect();
+    assert_eq!(names, vec!["before", "after"]);
+
+    helper.rollback("after").await.unwrap();
+    assert_eq!(helper.read_file(&b).await.unwrap(), b"new".to_vec());
+    assert!(helper.rollback("missing").await.is_err());
+
+    // A hold placed since a snapshot can't be lifted by rolling back to it.
+    helper
+        .place_legal_hold(&b, "audit", b"custodian")
+        .await
+        .unwrap();
+    assert!(helper.rollback("before").await.is_err());
+    assert_eq!(helper.read_file(&b).await.unwrap(), b"new".to_vec());
+
+    // Snapshot roots have to survive garbage collection next to the latest root.
+    let snapshots = helper.list_snapshots().await.unwrap();
+    let roots = helper.live_roots().await.unwrap();
+    assert_eq!(roots.first(), helper.root_history().last());
+    assert!(snapshots
+        .iter()
+        .all(|snapshot| roots.contains(&snapshot.root_cid)));
+
+    helper.delete_snapshot("before").await.unwrap();
+    assert_eq!(helper.list_snapshots().await.unwrap().len(), 1);
+    assert!(!helper
+        .live_roots()
+        .await
+        .unwrap()
+        .contains(&snapshots[0].root_cid));
+}
+
+#[test]
+fn test_stats_count_blocks_cache_lookups_and_operations() {
+    use crate::blockstore::CachedBlockStore;
+
+    let dir = tempfile::tempdir().unwrap();
+    let store = KVBlockStore::new(
+        dir.path().join("store").to_string_lossy().to_string(),
+        CODEC_DAG_CBOR,
+    );
+    let cached = CachedBlockStore::new(Box::new(store), 1024 * 1024);
+    let blockstore = &mut FFIFriendlyBlockStore::new(Box::new(cached));
+    let (mut helper, _, _) = PrivateDirectoryHelper::synced_init(blockstore, vec![0; 32]).unwrap();
+    let path = vec!["stats.txt".to_string()];
+    helper
+        .synced_write_file(&path, b"counted".to_vec(), 0)
+        .unwrap();
+    helper.synced_read_file(&path).unwrap();
+    assert!(helper.synced_read_file(&["missing".into()]).is_err());
+
+    let stats = helper.stats();
+    assert!(stats.store.blocks_written > 0);
+    assert!(stats.store.bytes_written > 0);
+    assert!(stats.store.cache_hit_rate().is_some());
+    assert!(stats.forest.commits >= 1);
+    // Counted per helper, once each although the synced calls run the timed async ones.
+    let reads = stats.operations["read_file"];
+    assert_eq!((reads.calls, reads.errors), (2, 1));
+    assert_eq!(stats.operations["write_file"].calls, 1);
+    futures::executor::block_on(helper.read_file(&path)).unwrap();
+    assert_eq!(helper.stats().operations["read_file"].calls, 3);
+    assert!(helper
+        .prometheus_metrics()
+        .contains("wnfsutils_operation_calls_total{operation=\"read_file\"}"));
+}
+
+#[tokio::test]
+async fn test_store_failures_keep_their_code() {
+    use std::sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    };
+
+    use crate::blockstore::FFIStore;
+    use crate::error::{ErrorCode, WnfsUtilsError};
+
+    // A backend timing out on every call while `failing` is set.
+    #[derive(Clone)]
+    struct TimingOutStore {
+        inner: KVBlockStore,
+        failing: Arc<AtomicBool>,
+    }
+
+    impl TimingOutStore {
+        fn check(&self) -> anyhow::Result<()> {
+            match self.failing.load(Ordering::SeqCst) {
+                true => Err(WnfsUtilsError::StoreUnavailable("timed out".to_string()).into()),
+                false => Ok(()),
+            }
+        }
+    }
+
+    impl<'a> FFIStore<'a> for TimingOutStore {
+        fn get_block(&self, cid: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+            self.check()?;
+            self.inner.get_block(cid)
+        }
+
+        fn put_block(&self, cid: Vec<u8>, bytes: Vec<u8>) -> anyhow::Result<()> {
+            self.check()?;
+            self.inner.put_block(cid, bytes)
+        }
+    }
+
+    let dir = tempfile::tempdir().unwrap();
+    let store = TimingOutStore {
+        inner: KVBlockStore::new(
+            dir.path().join("store").to_string_lossy().to_string(),
+            CODEC_DAG_CBOR,
+        ),
+        failing: Arc::new(AtomicBool::new(false)),
+    };
+    let failing = Arc::clone(&store.failing);
+    let blockstore = &mut FFIFriendlyBlockStore::new(Box::new(store));
+    let (helper, _, _) = &mut PrivateDirectoryHelper::init(blockstore, vec![0; 32])
+        .await
+        .unwrap();
+    let a: Vec<String> = vec!["a.bin".into()];
+    helper.write_file(&a, vec![1; 300_000], 0).await.unwrap();
+
+    failing.store(true, Ordering::SeqCst);
+    let err = helper.read_file(&a).await.unwrap_err();
+    assert_eq!(
+        ErrorCode::of_message(&err),
+        ErrorCode::StoreUnavailable,
+        "{}",
+        err
+    );
+    let err = helper
+        .write_file(&["b.bin".into()], vec![2; 300_000], 0)
+        .await
+        .unwrap_err();
+    assert_eq!(
+        ErrorCode::of_message(&err),
+        ErrorCode::StoreUnavailable,
+        "{}",
+        err
+    );
+    let mut tx = helper.begin();
+    tx.mkdir(&["c".into()]).await.unwrap();
+    let err = tx.commit().await.unwrap_err();
+    assert_eq!(
+        ErrorCode::of_message(&err),
+        ErrorCode::StoreUnavailable,
+        "{}",
+        err
+    );
+}
@@ -1,153 +1,227 @@
-use std::collections::HashMap;
-use std::sync::Mutex;
-use anyhow::Result;
-use bytes::Bytes;
+//! Integration tests against a local Kubo daemon, ignored by default. Run them with
+//! `cargo test -- --ignored` and `WNFSUTILS_KUBO_API` naming its RPC API, e.g.
+//! `http://127.0.0.1:5001`. Blocks are read back through its gateway at
+//! `WNFSUTILS_KUBO_GATEWAY`, `http://127.0.0.1:8080` by default. Each test writes its own forest
+//! to a local store, imports it into the daemon as a CAR and reads it through the remote stores.
+
+use std::io::Cursor;
+
 use libipld::Cid;
 use log::trace;
-use reqwest;
-use once_cell::sync::Lazy;
-use wnfs::common::{BlockStore, CODEC_DAG_CBOR};
-use crate::{blockstore::FFIStore, private_forest::FFIFriendlyBlockStore};
-use sha2::{Sha256, Digest};
+use reqwest::header::CONTENT_TYPE;
 use tokio::time::Duration;
-// Global memory store
-static MEMORY_STORE: Lazy<Mutex<HashMap<String, Vec<u8>>>> = Lazy::new(|| {
-    Mutex::new(HashMap::new())
-});
-
-#[derive(Clone)]
-pub struct WebBlockStore {
-    pub gateway_url: String,
-    pub codec: u64,
+use wnfs::common::CODEC_DAG_CBOR;
+
+use crate::blockstore::{
+    CachedBlockStore, FFIFriendlyBlockStore, HttpGatewayConfig, HttpGatewayStore, PrefetchConfig,
+    PrefetchingStore,
+};
+use crate::car::{export_car, import_car, CarVersion};
+use crate::kvstore::KVBlockStore;
+use crate::private_forest::PrivateDirectoryHelper;
+
+const DEFAULT_GATEWAY: &str = "http://127.0.0.1:8080";
+const MULTIPART_BOUNDARY: &str = "wnfsutils-car-boundary";
+// A maximal block plus its CID.
+const MAX_SECTION: usize = 4 * 1024 * 1024 + 128;
+
+const TEST_WNFS_KEY: [u8; 32] = [7; 32];
+
+struct Kubo {
+    api: String,
+    gateway: String,
+    client: reqwest::Client,
 }
 
-impl WebBlockStore {
-    pub fn new(gateway_url: String, codec: u64) -> Self {
+impl Kubo {
+    fn from_env() -> Self {
+        let _ = env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info"))
+            .is_test(true)
+            .try_init();
+        let api = std::env::var("WNFSUTILS_KUBO_API")
+            .expect("WNFSUTILS_KUBO_API names the Kubo RPC API")
+            .trim_end_matches('/')
+            .to_string();
+        let gateway = std::env::var("WNFSUTILS_KUBO_GATEWAY")
+            .unwrap_or_else(|_| DEFAULT_GATEWAY.to_string())
+            .trim_end_matches('/')
+            .to_string();
         Self {
-            gateway_url,
-            codec,
+            api,
+            gateway,
+            client: reqwest::Client::new(),
         }
     }
 
-    fn cid_to_string(cid: &[u8]) -> String {
-        Cid::try_from(cid).unwrap().to_string()
+    // Reads blocks through the daemon's gateway, as apps do.
+    fn gateway_store(&self) -> HttpGatewayStore {
+        let get_url = format!("{}/ipfs/{{cid}}?format=raw", self.gateway);
+        HttpGatewayStore::new(HttpGatewayConfig {
+            timeout: Duration::from_secs(60),
+            ..HttpGatewayConfig::read_only(&get_url)
+        })
+        .unwrap()
     }
-}
 
-#[async_trait::async_trait(?Send)]
-impl<'a> FFIStore<'a> for WebBlockStore {
-    fn get_block(&self, cid: Vec<u8>) -> Result<Vec<u8>> {
-        // Use tokio::task::block_in_place to properly handle blocking operations in async context
-        tokio::task::block_in_place(|| {
-            let cid_string = Self::cid_to_string(&cid);
-            
-            if let Some(data) = MEMORY_STORE.lock().unwrap().get(&cid_string) {
-                trace!("Retrieved from memory store: {}", cid_string);
-                return Ok(data.clone());
-            }
-
-            let url = format!("{}/{}?raw", self.gateway_url, cid_string);
-            trace!("Fetching from remote: {}", url);
-
-            let client = reqwest::blocking::Client::builder()
-                .timeout(Duration::from_secs(60))
-                .build()?;
-            
-            let response = client
-                .get(&url)
-                .header("Accept", "*/*")
-                .header("Content-Type", "application/octet-stream")
-                .send()?
-                .bytes()?;
-
-            let data = response.to_vec();
-            trace!("Result of get: {:?}", data);
-            MEMORY_STORE.lock().unwrap().insert(cid_string, data.clone());
-            Ok(data)
-        })
+    // Imports and pins a CAR archive with `dag/import`.
+    async fn import(&self, car: Vec<u8>) {
+        let mut body = format!(
+            "--{MULTIPART_BOUNDARY}\r\nContent-Disposition: form-data; name=\"file\"; \
+             filename=\"forest.car\"\r\nContent-Type: application/octet-stream\r\n\r\n"
+        )
+        .into_bytes();
+        body.extend(car);
+        body.extend(format!("\r\n--{MULTIPART_BOUNDARY}--\r\n").into_bytes());
+        let response = self
+            .client
+            .post(format!("{}/api/v0/dag/import?pin-roots=true", self.api))
+            .header(
+                CONTENT_TYPE,
+                format!("multipart/form-data; boundary={MULTIPART_BOUNDARY}"),
+            )
+            .body(body)
+            .send()
+            .await
+            .unwrap();
+        let status = response.status();
+        let text = response.text().await.unwrap();
+        assert!(
+            status.is_success(),
+            "dag/import failed: {} {}",
+            status,
+            text
+        );
+        trace!("dag/import: {}", text);
     }
 
-    fn put_block(&self, cid: Vec<u8>, bytes: Vec<u8>) -> Result<()> {
-        let cid_string = Self::cid_to_string(&cid);
-        MEMORY_STORE.lock().unwrap().insert(cid_string, bytes);
-        Ok(())
+    // The DAG below `root` as a CAR archive, with `dag/export`.
+    async fn export(&self, root: &Cid) -> Vec<u8> {
+        let response = self
+            .client
+            .post(format!("{}/api/v0/dag/export?arg={}", self.api, root))
+            .send()
+            .await
+            .unwrap();
+        assert!(response.status().is_success(), "dag/export failed");
+        response.bytes().await.unwrap().to_vec()
     }
 }
 
+// Content of a few files, the last one spanning several blocks.
+fn test_files() -> Vec<(Vec<String>, Vec<u8>)> {
+    let large: Vec<u8> = (0..700_000u32).map(|i| (i % 251) as u8).collect();
+    vec![
+        (vec!["hello.txt".into()], b"hello kubo".to_vec()),
+        (
+            vec!["docs".into(), "notes.md".into()],
+            b"# notes\n".repeat(100),
+        ),
+        (vec!["media".into(), "large.bin".into()], large),
+    ]
+}
 
-#[cfg(test)]
-mod tests {
-
-    use crate::private_forest::PrivateDirectoryHelper;
-    use env_logger::{Builder, Env};
-
-    use super::*;
-
-    use once_cell::sync::Lazy;
-
-    static INIT_LOGGER: Lazy<()> = Lazy::new(|| {
-        env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("trace"))
-            .is_test(true)
-            .try_init()
-            .ok();
-    });
-
-    fn setup() {
-        Lazy::force(&INIT_LOGGER);
+// Writes `test_files` to a local store and imports the forest into the daemon.
+async fn publish_test_forest(kubo: &Kubo) -> Cid {
+    let dir = tempfile::tempdir().unwrap();
+    let store = KVBlockStore::new(
+        dir.path().join("store").to_string_lossy().to_string(),
+        CODEC_DAG_CBOR,
+    );
+    let blockstore = &mut FFIFriendlyBlockStore::new(Box::new(store));
+    let (mut helper, _, mut root) =
+        PrivateDirectoryHelper::init(blockstore, TEST_WNFS_KEY.to_vec())
+            .await
+            .unwrap();
+    for (path, content) in test_files() {
+        root = helper.write_file(&path, content, 0).await.unwrap();
     }
+    drop(helper);
+
+    let mut car = Cursor::new(Vec::new());
+    export_car(&*blockstore, &root, CarVersion::V1, &mut car)
+        .await
+        .unwrap();
+    kubo.import(car.into_inner()).await;
+    root
+}
 
-    fn clear_memory_store() {
-        MEMORY_STORE.lock().unwrap().clear();
+async fn assert_test_files(helper: &mut PrivateDirectoryHelper<'_>) {
+    for (path, content) in test_files() {
+        assert_eq!(helper.read_file(&path).await.unwrap(), content);
     }
+    let mut names: Vec<String> = helper
+        .ls_files(&[])
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect();
+    names.sort();
+    assert_eq!(names, vec!["docs", "hello.txt", "media"]);
+}
 
-    const TEST_WNFS_KEY: &'static str = "253,78,31,107,225,226,191,37,170,183,150,195,158,20,19,61,113,210,91,33,107,114,123,83,39,213,125,249,10,28,254,218,113,89,93,240,221,54,221,217,134,126,143,122,131,4,215,228,120,80,219,105,171,10,63,167,39,216,151,74,134,43,1,235";
-    const TEST_CID: &str = "bafyr4iantpew6r3hd6rsu5l5ioffbrryovkwlry7niwlqybph4qo75um5m";
+#[tokio::test(flavor = "multi_thread")]
+#[ignore = "needs a Kubo daemon, see WNFSUTILS_KUBO_API"]
+async fn test_load_with_wnfs_key() {
+    let kubo = Kubo::from_env();
+    let root = publish_test_forest(&kubo).await;
+
+    let mut blockstore = FFIFriendlyBlockStore::new(Box::new(kubo.gateway_store()));
+    let mut helper =
+        PrivateDirectoryHelper::load_with_wnfs_key(&mut blockstore, root, TEST_WNFS_KEY.to_vec())
+            .await
+            .unwrap();
+    assert_test_files(&mut helper).await;
+}
 
-    #[tokio::test(flavor = "multi_thread")]
-    async fn test_load_with_wnfs_key() {
-        setup();
-        trace!("test_load_with_wnfs_key started");
-        let store = WebBlockStore::new(
-            "https://ipfs.cloud.fx.land/gateway".to_string(),
-            CODEC_DAG_CBOR
-        );
-        let mut blockstore = FFIFriendlyBlockStore::new(Box::new(store));
-        // Hash the 64-byte key to get 32-byte key
-        let mut hasher = Sha256::new();
-        let wnfs_key_b = TEST_WNFS_KEY.as_bytes();
-        hasher.update(&wnfs_key_b);
-        let hash32 = hasher.finalize();
-        let wnfs_key = hash32.as_slice();
-        trace!("wnfs key is: {:?}", wnfs_key);
-        let cid = Cid::try_from(TEST_CID).unwrap();
-        let result = PrivateDirectoryHelper::load_with_wnfs_key(
-            &mut blockstore,
-            cid,
-            wnfs_key.to_vec()
-        ).await;
-
-        assert!(result.is_ok(), "Failed to load with WNFS key: {:?}", result.err());
-    }
+#[tokio::test(flavor = "multi_thread")]
+#[ignore = "needs a Kubo daemon, see WNFSUTILS_KUBO_API"]
+async fn test_load_private_forest() {
+    let kubo = Kubo::from_env();
+    let root = publish_test_forest(&kubo).await;
+
+    let blockstore = FFIFriendlyBlockStore::new(Box::new(kubo.gateway_store()));
+    let result = PrivateDirectoryHelper::load_private_forest(blockstore, root).await;
+    assert!(
+        result.is_ok(),
+        "Failed to load private forest: {:?}",
+        result.err()
+    );
+}
 
-    #[tokio::test]
-    async fn test_load_private_forest() {
-        trace!("test_load_private_forest started");
-        let store = WebBlockStore::new(
-            "https://ipfs.cloud.fx.land/gateway".to_string(),
-            CODEC_DAG_CBOR
-        );
-        let blockstore = FFIFriendlyBlockStore::new(Box::new(store));
-
-        let cid = Cid::try_from(TEST_CID).unwrap();
-        trace!("loading {:?}", cid);
-        let result = PrivateDirectoryHelper::load_private_forest(blockstore, cid).await;
-
-        assert!(result.is_ok(), "Failed to load private forest: {:?}", result.err());
-        
-        // Verify the loaded forest
-        if let Ok(forest) = result {
-            // Add specific forest verification here
-            trace!("forest ok");
-        }
-    }
+#[tokio::test(flavor = "multi_thread")]
+#[ignore = "needs a Kubo daemon, see WNFSUTILS_KUBO_API"]
+async fn test_round_trip_through_kubo() {
+    let kubo = Kubo::from_env();
+    let root = publish_test_forest(&kubo).await;
+
+    // Reads prefetched and cached in front of the gateway.
+    let prefetching =
+        PrefetchingStore::new(Box::new(kubo.gateway_store()), PrefetchConfig::default());
+    let cached = CachedBlockStore::new(Box::new(prefetching.to_owned()), 16 * 1024 * 1024);
+    let mut blockstore = FFIFriendlyBlockStore::new(Box::new(cached));
+    let mut helper =
+        PrivateDirectoryHelper::load_with_wnfs_key(&mut blockstore, root, TEST_WNFS_KEY.to_vec())
+            .await
+            .unwrap();
+    assert_test_files(&mut helper).await;
+    // The test forest's HAMT may fit in its root node, leaving no child nodes to prefetch.
+    assert_eq!(prefetching.stats().failed, 0);
+
+    // Exported by the daemon and restored into a fresh local store.
+    let car = kubo.export(&root).await;
+    let dir = tempfile::tempdir().unwrap();
+    let store = KVBlockStore::new(
+        dir.path().join("restored").to_string_lossy().to_string(),
+        CODEC_DAG_CBOR,
+    );
+    let (roots, count) = import_car(&mut Cursor::new(car), &store, MAX_SECTION).unwrap();
+    assert_eq!(roots, vec![root]);
+    assert!(count > 0);
+    let mut blockstore = FFIFriendlyBlockStore::new(Box::new(store));
+    let mut helper =
+        PrivateDirectoryHelper::load_with_wnfs_key(&mut blockstore, root, TEST_WNFS_KEY.to_vec())
+            .await
+            .unwrap();
+    assert_test_files(&mut helper).await;
 }
@@ -0,0 +1,89 @@
+//! "Make this folder public": a decrypted, read-only copy of a private subtree in the public
+//! tree, see `public_forest::PublicDirectoryHelper`.
+//!
+//! The copy is a snapshot. Later changes to the private subtree don't show up in it until it is
+//! published again, which replaces the previous copy. Modification times are kept, paged files
+//! are published as plain files and the helper's bookkeeping entries are left out.
+
+use chrono::Utc;
+use futures::StreamExt;
+use libipld::Cid;
+
+use super::{PrivateDirectoryHelper, TransferProgress, WalkOptions};
+use crate::public_forest::PublicDirectoryHelper;
+use crate::vfs::VfsNodeKind;
+
+impl<'a> PrivateDirectoryHelper<'a> {
+    /// Copies the subtree at `path_segments` into `public` at `public_path`, replacing what was
+    /// there, in a single commit of the public tree, and returns its new root. The copy is staged
+    /// on a copy of the public tree, so `public` only changes once it's committed. A file is
+    /// published as the file at `public_path`. Public files are single blocks, so each file must
+    /// fit into the store's `max_block_size`.
+    pub async fn publish_snapshot(
+        &mut self,
+        path_segments: &[String],
+        public: &mut PublicDirectoryHelper<'_>,
+        public_path: &[String],
+        mut progress: Option<&mut dyn FnMut(&TransferProgress)>,
+    ) -> Result<Cid, String> {
+        let entries: Vec<_> = self
+            .walk(path_segments, WalkOptions::default())
+            .collect::<Vec<_>>()
+            .await
+            .into_iter()
+            .collect::<Result<_, String>>()?;
+        let mut status = TransferProgress {
+            files_total: entries
+                .iter()
+                .filter(|entry| entry.stat.kind == VfsNodeKind::File)
+                .count(),
+            ..Default::default()
+        };
+
+        let mut staged = public.staging();
+        staged.stage_clear(public_path).await?;
+        for entry in entries {
+            let mut target = public_path.to_vec();
+            target.extend(entry.path[path_segments.len()..].iter().cloned());
+            let modified = entry
+                .stat
+                .modified
+                .map(Self::modification_time)
+                .transpose()?
+                .unwrap_or_else(Utc::now);
+            match entry.stat.kind {
+                VfsNodeKind::Directory if target.is_empty() => {}
+                VfsNodeKind::Directory => staged.stage_mkdir(&target, modified).await?,
+                VfsNodeKind::File => {
+                    let content = self.read_file_at(&entry.path, 0, usize::MAX).await?;
+                    status.bytes_done += content.len() as u64;
+                    staged.stage_write(&target, content, modified).await?;
+                    status.files_done += 1;
+                    status.current_path = entry.path.join("/");
+                    if let Some(callback) = progress.as_mut() {
+                        callback(&status);
+                    }
+                }
+            }
+        }
+        let root = staged.commit().await?;
+        *public = staged;
+        Ok(root)
+    }
+}
+
+impl<'a> PrivateDirectoryHelper<'a> {
+    pub fn synced_publish_snapshot(
+        &mut self,
+        path_segments: &[String],
+        public: &mut PublicDirectoryHelper<'_>,
+        public_path: &[String],
+        progress: Option<&mut dyn FnMut(&TransferProgress)>,
+    ) -> Result<Cid, String> {
+        Self::run_limited(
+            &self.operation_limiter(),
+            "publish_snapshot",
+            self.publish_snapshot(path_segments, public, public_path, progress),
+        )
+    }
+}
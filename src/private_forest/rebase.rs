@@ -0,0 +1,151 @@
+//! Retrying a transaction on top of the latest published root when another device advanced it.
+//!
+//! The forest is only shared through the root an app publishes, e.g. to a pointer service.
+//! `commit_with_retry` runs a transaction, publishes its root with a compare-and-swap on the
+//! root it started from and, when that fails because another device published first, reloads
+//! the latest root and replays the transaction on top of it. The replay is told which attempt
+//! it is and which root it runs on, so it can check for conflicts, e.g. a file it edits having
+//! changed, and give up instead of overwriting the other device's change.
+
+use std::rc::Rc;
+
+use futures::future::LocalBoxFuture;
+use libipld::Cid;
+use log::trace;
+
+use super::PrivateDirectoryHelper;
+use crate::error::WnfsUtilsError;
+
+/// Where the app publishes its forest root. Reading it can go through a
+/// `gateway::PointerResolver`; publishing depends on the service.
+pub trait RootPointer {
+    /// The root currently published.
+    fn latest(&self) -> Result<Cid, String>;
+
+    /// Publishes `new` only if the pointer still holds `expected`, returning whether it did.
+    fn compare_and_publish(&self, expected: Cid, new: Cid) -> Result<bool, String>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Replays after the first attempt before failing with `WnfsUtilsError::StaleRoot`.
+    pub max_retries: usize,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self { max_retries: 3 }
+    }
+}
+
+/// Passed to each run of a transaction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Attempt {
+    /// 0 for the first run, then 1 for the first replay and so on.
+    pub number: usize,
+    /// Root the transaction runs on.
+    pub base: Cid,
+    /// Root the previous attempt ran on, `None` on the first run.
+    pub previous_base: Option<Cid>,
+}
+
+impl<'a> PrivateDirectoryHelper<'a> {
+    /// Runs `transaction`, which must return the root it committed, and publishes it through
+    /// `pointer`, replaying it on the latest root up to `policy.max_retries` times while
+    /// another device keeps publishing first. An error returned by `transaction` ends the
+    /// retries, so a conflict-aware replay can refuse to run on a root it can't merge with.
+    pub async fn commit_with_retry<F>(
+        &mut self,
+        pointer: &impl RootPointer,
+        policy: RetryPolicy,
+        mut transaction: F,
+    ) -> Result<Cid, String>
+    where
+        F: for<'h> FnMut(
+            &'h mut PrivateDirectoryHelper<'a>,
+            Attempt,
+        ) -> LocalBoxFuture<'h, Result<Cid, String>>,
+    {
+        let mut previous_base = None;
+        let mut number = 0;
+        loop {
+            let base = self
+                .root_history()
+                .last()
+                .copied()
+                .ok_or_else(|| "wnfsError no root to commit on".to_string())?;
+            let attempt = Attempt {
+                number,
+                base,
+                previous_base,
+            };
+            let new_root = transaction(self, attempt).await?;
+            // A batched transaction returned the root it started from.
+            let new_root = match self.has_pending_commit() {
+                true => self.flush_commits().await?,
+                false => new_root,
+            };
+            if pointer.compare_and_publish(base, new_root)? {
+                // The pointer moved from a root of ours to this one.
+                self.record_remote_root(new_root);
+                self.mark_published();
+                return Ok(new_root);
+            }
+            let latest = pointer.latest()?;
+            self.record_remote_root(latest);
+            trace!(
+                "wnfsutils: commit_with_retry attempt {} stale, {} published over {}",
+                number,
+                latest,
+                base
+            );
+            if number >= policy.max_retries {
+                return Err(WnfsUtilsError::StaleRoot {
+                    expected: base.to_string(),
+                    found: latest.to_string(),
+                }
+                .to_string());
+            }
+            self.reload_in_place(latest).await?;
+            previous_base = Some(base);
+            number += 1;
+        }
+    }
+
+    // Moves this helper to `forest_cid`, dropping what the previous root left cached. Batched
+    // mutations are committed first, so they stay reachable from the root history.
+    pub(super) async fn reload_in_place(&mut self, forest_cid: Cid) -> Result<(), String> {
+        self.flush_commits().await?;
+        let mut store = self.store.to_owned();
+        let fresh =
+            Self::load_with_wnfs_key(&mut store, forest_cid, self.wnfs_key.to_owned()).await?;
+        // The helper implements `Drop`, so its fields are shared rather than moved out.
+        self.forest = Rc::clone(&fresh.forest);
+        self.root_dir = Rc::clone(&fresh.root_dir);
+        self.legal_holds = None;
+        self.pending_commit = None;
+        self.record_root(forest_cid);
+        self.refresh_name_indexes().await
+    }
+}
+
+impl<'a> PrivateDirectoryHelper<'a> {
+    pub fn synced_commit_with_retry<F>(
+        &mut self,
+        pointer: &impl RootPointer,
+        policy: RetryPolicy,
+        transaction: F,
+    ) -> Result<Cid, String>
+    where
+        F: for<'h> FnMut(
+            &'h mut PrivateDirectoryHelper<'a>,
+            Attempt,
+        ) -> LocalBoxFuture<'h, Result<Cid, String>>,
+    {
+        Self::run_limited(
+            &self.operation_limiter(),
+            "commit_with_retry",
+            self.commit_with_retry(pointer, policy, transaction),
+        )
+    }
+}
@@ -0,0 +1,179 @@
+//! Moving a filesystem to a new wnfs key.
+//!
+//! The root directory is shared with the exchange key derived from the wnfs key, and whoever
+//! holds a directory's key can follow its ratchet to every later revision. Re-sharing the
+//! existing root under a new key would therefore still let the old key read everything written
+//! afterwards. `rotate_wnfs_key` instead re-encrypts the tree into a new forest shared only
+//! with the new key: every directory and file gets a fresh name, ratchet and content key. The
+//! tree is copied as stored, shards, pages and the helper's bookkeeping included, so legal
+//! holds carry over. Content stays in the same block store without going through the app.
+//!
+//! The helper keeps its revision history, checkpoints, forest state, limits and every other
+//! setting, and the process only switches to the new key once the new forest was committed.
+//! Blocks of the old forest stay readable with the old key until they are collected, see `gc`.
+//! Other devices have to load the new root with the new key.
+
+use std::rc::Rc;
+
+use chrono::{DateTime, Utc};
+use futures::{StreamExt, TryStreamExt};
+use libipld::Cid;
+use log::trace;
+use wnfs::private::PrivateNode;
+
+use super::{PrivateDirectoryHelper, WriterLock};
+use crate::error::{describe, WnfsUtilsError};
+
+impl<'a> PrivateDirectoryHelper<'a> {
+    /// Re-encrypts the whole tree under `new_key`, see the module docs, and moves this helper
+    /// to the new forest, whose CID it returns. `old_key` has to be the helper's current key.
+    pub async fn rotate_wnfs_key(
+        &mut self,
+        old_key: &[u8],
+        new_key: Vec<u8>,
+    ) -> Result<Cid, String> {
+        if old_key != self.wnfs_key.as_slice() {
+            trace!("wnfsError in rotate_wnfs_key: old key doesn't match");
+            return Err(WnfsUtilsError::WrongKey.to_string());
+        }
+        Self::seed_from_key(&new_key)?;
+        // Batched mutations are committed to the old forest first, so they stay in its history
+        // and no open window is left to be committed on drop.
+        self.flush_commits().await?;
+        // The helper of an exclusive session keeps its forest locked under the new key.
+        let writer_lock = match self.writer_lock {
+            Some(_) => Some(WriterLock::acquire(&new_key)?),
+            None => None,
+        };
+        let mut store = self.store.to_owned();
+        let (mut rotated, _, _) = Self::create_forest(&mut store, new_key).await?;
+        rotated.content_scanners = self.content_scanners.to_owned();
+
+        let mut pending = vec![Vec::new()];
+        while let Some(dir) = pending.pop() {
+            for (name, _) in self.ls_raw(&dir).await? {
+                let mut child = dir.to_owned();
+                child.push(name);
+                match self.raw_node_at(&child).await? {
+                    Some(PrivateNode::Dir(source)) => {
+                        let time = source.get_metadata().get_modified();
+                        rotated.mkdir_rotated(&child, time).await?;
+                        pending.push(child);
+                    }
+                    Some(_) => self.copy_rotated(&child, &mut rotated).await?,
+                    None => {}
+                }
+            }
+        }
+
+        // Only the new tree is taken over: the limiter, holds, indexes, revision history and the
+        // rest of the helper's state stay as they are. The helper implements `Drop`, so its
+        // fields are shared rather than moved out.
+        let forest = std::mem::replace(&mut self.forest, Rc::clone(&rotated.forest));
+        let root_dir = std::mem::replace(&mut self.root_dir, Rc::clone(&rotated.root_dir));
+        let root = match self.commit_now().await {
+            Ok(root) => root,
+            Err(e) => {
+                self.forest = forest;
+                self.root_dir = root_dir;
+                return Err(e);
+            }
+        };
+        self.wnfs_key = rotated.wnfs_key.to_owned();
+        if let Some(lock) = writer_lock {
+            self.writer_lock = Some(lock);
+        }
+        Self::update_state(self.wnfs_key.to_owned());
+        Ok(root)
+    }
+
+    // Creates the directory at the stored path `path_segments`, modified at `time` like its
+    // source.
+    async fn mkdir_rotated(
+        &mut self,
+        path_segments: &[String],
+        time: Option<DateTime<Utc>>,
+    ) -> Result<(), String> {
+        let forest = &mut self.forest;
+        let root_dir = &mut self.root_dir;
+        root_dir
+            .mkdir(
+                path_segments,
+                true,
+                time.unwrap_or_else(Utc::now),
+                forest,
+                &mut self.store,
+                &mut self.rng,
+            )
+            .await
+            .map_err(|e| {
+                trace!("wnfsError in rotate_wnfs_key on mkdir: {:?}", e.to_string());
+                describe(&e)
+            })
+    }
+
+    // Streams the file at the stored path `path_segments` into the same path of `dst`, with
+    // all of its metadata.
+    async fn copy_rotated(
+        &mut self,
+        path_segments: &[String],
+        dst: &mut PrivateDirectoryHelper<'_>,
+    ) -> Result<(), String> {
+        let file = self
+            .raw_node_at(path_segments)
+            .await?
+            .ok_or_else(|| format!("wnfsError no file found at {}", path_segments.join("/")))?
+            .as_file()
+            .map_err(|e| describe(&e))?;
+        let modified = file.get_metadata().get_modified().unwrap_or_else(Utc::now);
+        let stream = file
+            .stream_content(0, &self.forest, &self.store)
+            .map(|block| {
+                block.map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))
+            });
+        let reader = Box::pin(stream).into_async_read();
+
+        let (_, entries) = dst
+            .set_content_scanned(path_segments, path_segments, reader, modified)
+            .await?;
+        let forest = &mut dst.forest;
+        let dst_file = dst
+            .root_dir
+            .open_file_mut(
+                path_segments,
+                true,
+                modified,
+                forest,
+                &mut dst.store,
+                &mut dst.rng,
+            )
+            .await
+            .map_err(|e| {
+                trace!(
+                    "wnfsError in rotate_wnfs_key on open_file_mut: {:?}",
+                    e.to_string()
+                );
+                describe(&e)
+            })?;
+        let metadata = dst_file.get_metadata_mut();
+        *metadata = file.get_metadata().to_owned();
+        for (key, value) in entries {
+            metadata.put(&key, value);
+        }
+        Ok(())
+    }
+}
+
+impl<'a> PrivateDirectoryHelper<'a> {
+    pub fn synced_rotate_wnfs_key(
+        &mut self,
+        old_key: &[u8],
+        new_key: Vec<u8>,
+    ) -> Result<Cid, String> {
+        Self::run_limited(
+            &self.operation_limiter(),
+            "rotate_wnfs_key",
+            self.rotate_wnfs_key(old_key, new_key),
+        )
+    }
+}
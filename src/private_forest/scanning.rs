@@ -0,0 +1,316 @@
+//! Content scanners run on every file write, for derived data such as hashes, previews or
+//! embeddings, and for policies refusing content.
+//!
+//! A [`ContentScanner`] registered with `add_content_scanner` starts a [`ContentScan`] for each
+//! file written through `write_file`, `write_file_stream` and the variants built on them, and
+//! for every other path writing file content: imports (`ingest`, `ingest_media`),
+//! copies (`cp`, `merge`), template documents and key rotation. The scan is fed the content in
+//! order while it is written, in chunks of at most `STREAM_CHUNK_BYTES`, and returns metadata
+//! entries that are stored on the file's node, like the media metadata entries. Keys should
+//! carry the scanner's own prefix so scanners don't overwrite each other's entries.
+//!
+//! `write_at` on a paged file scans each write on its own, and keeps the entries on the file's
+//! `PAGED_MARKER`, like the writes storing whole files as paged ones under
+//! `HelperConfig::adaptive_chunking`. Key rotation walks the stored tree, so its scans see stored paths, which
+//! differ from the logical ones under name privacy or sharding.
+//!
+//! An error from a scan vetoes the write: it fails with `WnfsUtilsError::ContentRejected` and the
+//! tree is left as it was. Blocks the stream already stored stay in the store until garbage
+//! collection. Streams are read no faster than the scans consume them, at most
+//! `SCAN_QUEUE_CHUNKS` chunks ahead.
+
+use std::{
+    io,
+    pin::Pin,
+    rc::Rc,
+    task::{ready, Context, Poll},
+};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use futures::{channel::mpsc, AsyncRead, Stream, StreamExt};
+use libipld::{Cid, Ipld};
+use log::trace;
+
+use super::{AdaptiveChunking, PrivateDirectoryHelper, STREAM_CHUNK_BYTES};
+use crate::error::{describe, WnfsUtilsError};
+
+/// Chunks a streamed write reads ahead of the slowest scan.
+pub const SCAN_QUEUE_CHUNKS: usize = 4;
+
+pub trait ContentScanner {
+    /// Names the scanner in `WnfsUtilsError::ContentRejected`.
+    fn name(&self) -> &str;
+
+    /// Starts scanning a write to `path_segments`, `None` to leave the file alone, e.g. by its
+    /// extension.
+    fn start(&self, path_segments: &[String]) -> Option<Box<dyn ContentScan>>;
+}
+
+/// A scan of a single write, see [`ContentScanner::start`].
+#[async_trait(?Send)]
+pub trait ContentScan {
+    /// The next chunk of the content. An error vetoes the write.
+    async fn update(&mut self, chunk: &[u8]) -> Result<(), String>;
+
+    /// Called after the last chunk with the entries to store in the file's metadata. An error
+    /// vetoes the write.
+    async fn finish(self: Box<Self>) -> Result<Vec<(String, Ipld)>, String>;
+}
+
+// The scans of a single write, with the name of their scanner.
+pub(super) struct Scans(Vec<(String, Box<dyn ContentScan>)>);
+
+impl Scans {
+    async fn run(
+        mut self,
+        mut chunks: impl Stream<Item = Vec<u8>> + Unpin,
+    ) -> Result<Vec<(String, Ipld)>, String> {
+        while let Some(chunk) = chunks.next().await {
+            for (name, scan) in self.0.iter_mut() {
+                scan.update(&chunk)
+                    .await
+                    .map_err(|e| Self::rejected(name, e))?;
+            }
+        }
+        let mut entries = Vec::new();
+        for (name, scan) in self.0 {
+            entries.extend(scan.finish().await.map_err(|e| Self::rejected(&name, e))?);
+        }
+        Ok(entries)
+    }
+
+    // `run` on the scans of a write, if there are any.
+    async fn run_optional(
+        scans: Option<Self>,
+        chunks: mpsc::Receiver<Vec<u8>>,
+    ) -> Result<Vec<(String, Ipld)>, String> {
+        match scans {
+            Some(scans) => scans.run(chunks).await,
+            None => Ok(Vec::new()),
+        }
+    }
+
+    fn rejected(scanner: &str, reason: String) -> String {
+        trace!(
+            "wnfsError in content scan: {} rejected: {}",
+            scanner,
+            reason
+        );
+        WnfsUtilsError::ContentRejected {
+            scanner: scanner.to_string(),
+            reason,
+        }
+        .to_string()
+    }
+}
+
+// Passes what it reads on to the scans, if any, waiting while `SCAN_QUEUE_CHUNKS` are queued.
+struct ScanningReader<R> {
+    inner: R,
+    chunks: Option<mpsc::Sender<Vec<u8>>>,
+    read: u64,
+    finished: bool,
+}
+
+impl<R> ScanningReader<R> {
+    fn new(inner: R, chunks: Option<mpsc::Sender<Vec<u8>>>) -> Self {
+        Self {
+            inner,
+            chunks,
+            read: 0,
+            finished: false,
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for ScanningReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        if this.finished {
+            return Poll::Ready(Ok(0));
+        }
+        // The scans only stop early when one of them vetoed the write.
+        if let Some(chunks) = this.chunks.as_mut() {
+            if ready!(chunks.poll_ready(cx)).is_err() {
+                return Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "content scan stopped",
+                )));
+            }
+        }
+        let limit = buf.len().min(STREAM_CHUNK_BYTES);
+        let read = ready!(Pin::new(&mut this.inner).poll_read(cx, &mut buf[..limit]))?;
+        this.read += read as u64;
+        match (read, this.chunks.as_mut()) {
+            (_, None) => this.finished = read == 0,
+            (0, Some(chunks)) => {
+                this.finished = true;
+                chunks.close_channel();
+            }
+            (_, Some(chunks)) => chunks
+                .start_send(buf[..read].to_vec())
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?,
+        }
+        Poll::Ready(Ok(read))
+    }
+}
+
+impl<'a> PrivateDirectoryHelper<'a> {
+    /// Runs `scanner` on every file written from now on, after the scanners added before it.
+    pub fn add_content_scanner(&mut self, scanner: Rc<dyn ContentScanner>) {
+        self.content_scanners.push(scanner);
+    }
+
+    pub fn clear_content_scanners(&mut self) {
+        self.content_scanners.clear();
+    }
+
+    pub(super) fn start_scans(&self, path_segments: &[String]) -> Option<Scans> {
+        let scans: Vec<_> = self
+            .content_scanners
+            .iter()
+            .filter_map(|scanner| {
+                let scan = scanner.start(path_segments)?;
+                Some((scanner.name().to_string(), scan))
+            })
+            .collect();
+        match scans.is_empty() {
+            true => None,
+            false => Some(Scans(scans)),
+        }
+    }
+
+    // The metadata entries of the scanners for `content`, written in one piece.
+    pub(super) async fn scan_content(
+        &self,
+        path_segments: &[String],
+        content: &[u8],
+    ) -> Result<Vec<(String, Ipld)>, String> {
+        match self.start_scans(path_segments) {
+            Some(scans) => {
+                let chunks = content.chunks(STREAM_CHUNK_BYTES).map(<[u8]>::to_vec);
+                scans.run(futures::stream::iter(chunks)).await
+            }
+            None => Ok(Vec::new()),
+        }
+    }
+
+    // `write_file_stream_unscanned`, feeding the content to `scans` while it is written.
+    pub(super) async fn write_file_stream_scanned<R: AsyncRead + Unpin>(
+        &mut self,
+        path_segments: &[String],
+        content: R,
+        modification_time_seconds: i64,
+        extra_metadata: Vec<(String, Ipld)>,
+        scans: Scans,
+    ) -> Result<Cid, String> {
+        let (sender, receiver) = mpsc::channel(SCAN_QUEUE_CHUNKS);
+        let reader = ScanningReader::new(content, Some(sender));
+        let mut tx = self.begin();
+        let (written, scanned) = futures::join!(
+            tx.write_file_stream_unscanned(
+                path_segments,
+                reader,
+                modification_time_seconds,
+                extra_metadata,
+            ),
+            scans.run(receiver),
+        );
+        // A veto also fails the write, with a less telling error.
+        let entries = scanned?;
+        written?;
+        if !entries.is_empty() {
+            let modified = Self::modification_time(modification_time_seconds)?;
+            tx.put_file_metadata(path_segments, entries, modified)
+                .await?;
+        }
+        tx.commit().await
+    }
+
+    // Streams `content` into the file at the stored path `resolved` without committing, feeding
+    // it to the scans of `path_segments`. Returns the size written and the entries of the scans
+    // for the caller to store; a veto leaves the tree as it was.
+    pub(super) async fn set_content_scanned<R: AsyncRead + Unpin>(
+        &mut self,
+        path_segments: &[String],
+        resolved: &[String],
+        content: R,
+        modified: DateTime<Utc>,
+    ) -> Result<(u64, Vec<(String, Ipld)>), String> {
+        let scans = self.start_scans(path_segments);
+        let (sender, receiver) = mpsc::channel(SCAN_QUEUE_CHUNKS);
+        let mut reader = ScanningReader::new(content, scans.is_some().then_some(sender));
+        let mut tx = self.begin();
+        let (written, scanned) = futures::join!(
+            tx.set_content_raw(resolved, &mut reader, modified),
+            Scans::run_optional(scans, receiver),
+        );
+        let entries = scanned?;
+        written?;
+        tx.release();
+        Ok((reader.read, entries))
+    }
+
+    // `set_content_scanned`, storing the content as a paged file chunked by `policy`, see
+    // `write_adaptive_raw`.
+    pub(super) async fn write_adaptive_scanned<R: AsyncRead + Unpin>(
+        &mut self,
+        path_segments: &[String],
+        policy: &AdaptiveChunking,
+        content: R,
+    ) -> Result<(u64, Vec<(String, Ipld)>), String> {
+        let scans = self.start_scans(path_segments);
+        let (sender, receiver) = mpsc::channel(SCAN_QUEUE_CHUNKS);
+        let mut reader = ScanningReader::new(content, scans.is_some().then_some(sender));
+        let mut tx = self.begin();
+        let (written, scanned) = futures::join!(
+            tx.write_adaptive_raw(path_segments, policy, &mut reader, None),
+            Scans::run_optional(scans, receiver),
+        );
+        let entries = scanned?;
+        let len = written?;
+        tx.release();
+        Ok((len, entries))
+    }
+
+    async fn set_content_raw<R: AsyncRead + Unpin>(
+        &mut self,
+        resolved: &[String],
+        content: &mut R,
+        modified: DateTime<Utc>,
+    ) -> Result<(), String> {
+        let forest = &mut self.forest;
+        let root_dir = &mut self.root_dir;
+        let file = root_dir
+            .open_file_mut(
+                resolved,
+                true,
+                modified,
+                forest,
+                &mut self.store,
+                &mut self.rng,
+            )
+            .await
+            .map_err(|e| {
+                trace!(
+                    "wnfsError in set_content_scanned on open_file_mut: {:?}",
+                    e.to_string()
+                );
+                describe(&e)
+            })?;
+        file.set_content(modified, content, forest, &mut self.store, &mut self.rng)
+            .await
+            .map_err(|e| {
+                trace!(
+                    "wnfsError in set_content_scanned on set_content: {:?}",
+                    e.to_string()
+                );
+                describe(&e)
+            })
+    }
+}
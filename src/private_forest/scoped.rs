@@ -0,0 +1,391 @@
+//! Handles limiting what their holder may do with a forest, for host apps handing a subtree to a
+//! plugin.
+//!
+//! A [`ScopedHandle`] names a directory and the [`Permissions`] granted below it. Paths given to
+//! the handle are relative to that directory, and every operation passes through
+//! `ScopedHandle::authorize`, which checks the operation's permissions and keeps the path inside
+//! the scope. Handles wrap a [`HelperHandle`] rather than taking the helper on each call, so a
+//! plugin holding one has no way to reach the forest around its scope. The host keeps a handle
+//! on the whole forest, see `HelperHandle::scoped`, and narrows it for each plugin. Over FFI
+//! the permissions travel as `Permissions::bits`, see `ffi`.
+
+use std::ops::BitOr;
+
+use libipld::Cid;
+use log::trace;
+use wnfs::common::Metadata;
+
+use super::{HelperHandle, PrivateDirectoryHelper, RevisionInfo, SharePayload};
+use crate::error::WnfsUtilsError;
+
+/// Operations a handle allows, combined with `|`. Over FFI they travel as `bits`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Permissions(u8);
+
+impl Permissions {
+    pub const NONE: Self = Self(0);
+    /// Reading files and listing directories.
+    pub const READ: Self = Self(1);
+    /// Writing files and creating directories.
+    pub const WRITE: Self = Self(1 << 1);
+    pub const DELETE: Self = Self(1 << 2);
+    /// Sharing directories with other users.
+    pub const SHARE: Self = Self(1 << 3);
+    /// Listing and reading earlier revisions.
+    pub const HISTORY: Self = Self(1 << 4);
+    pub const ALL: Self = Self(0b1_1111);
+
+    pub fn bits(&self) -> u8 {
+        self.0
+    }
+
+    /// Fails on bits that name no permission, so a newer host's handle isn't silently widened.
+    pub fn from_bits(bits: u8) -> Result<Self, String> {
+        match bits & !Self::ALL.0 {
+            0 => Ok(Self(bits)),
+            unknown => Err(format!("wnfsError unknown permission bits {:#x}", unknown)),
+        }
+    }
+
+    pub fn contains(&self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// The permissions granted by both.
+    pub fn intersection(&self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+}
+
+impl BitOr for Permissions {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+}
+
+/// See the module docs. Clones share the helper.
+#[derive(Clone)]
+pub struct ScopedHandle<'a> {
+    handle: HelperHandle<'a>,
+    root: Vec<String>,
+    permissions: Permissions,
+}
+
+impl<'a> ScopedHandle<'a> {
+    /// The directory the handle is scoped to.
+    pub fn root(&self) -> &[String] {
+        &self.root
+    }
+
+    pub fn permissions(&self) -> Permissions {
+        self.permissions
+    }
+
+    /// A handle on the subdirectory `path_segments` with at most the permissions of this one,
+    /// for passing a smaller part of the scope on.
+    pub fn narrow(
+        &self,
+        path_segments: &[String],
+        permissions: Permissions,
+    ) -> Result<ScopedHandle<'a>, String> {
+        Ok(ScopedHandle {
+            handle: self.handle.to_owned(),
+            root: self.authorize("narrow", Permissions::NONE, path_segments)?,
+            permissions: self.permissions.intersection(permissions),
+        })
+    }
+
+    /// Checks that the handle grants `required` and returns the forest path of `path_segments`.
+    /// Every operation of the handle goes through here.
+    fn authorize(
+        &self,
+        operation: &str,
+        required: Permissions,
+        path_segments: &[String],
+    ) -> Result<Vec<String>, String> {
+        if !self.permissions.contains(required) {
+            trace!(
+                "wnfsError in scoped handle: {} denied at {:?}",
+                operation,
+                self.root
+            );
+            return Err(WnfsUtilsError::PermissionDenied {
+                operation: operation.to_string(),
+            }
+            .to_string());
+        }
+        if let Some(segment) = path_segments
+            .iter()
+            .find(|segment| matches!(segment.as_str(), "" | "." | ".."))
+        {
+            return Err(WnfsUtilsError::InvalidPath {
+                path: path_segments.join("/"),
+                reason: format!("{:?} would leave the handle's scope", segment),
+            }
+            .to_string());
+        }
+        Ok([self.root.as_slice(), path_segments].concat())
+    }
+
+    fn check_below_root(&self, path_segments: &[String]) -> Result<(), String> {
+        match path_segments.is_empty() {
+            true => Err(WnfsUtilsError::InvalidPath {
+                path: self.root.join("/"),
+                reason: "a handle can't remove or move its own directory".to_string(),
+            }
+            .to_string()),
+            false => Ok(()),
+        }
+    }
+
+    pub async fn read_file(&self, path_segments: &[String]) -> Result<Vec<u8>, String> {
+        let path = self.authorize("read_file", Permissions::READ, path_segments)?;
+        self.handle.read_file(&path).await
+    }
+
+    pub async fn ls_files(
+        &self,
+        path_segments: &[String],
+    ) -> Result<Vec<(String, Metadata)>, String> {
+        let path = self.authorize("ls_files", Permissions::READ, path_segments)?;
+        self.handle.ls_files(&path).await
+    }
+
+    pub async fn write_file(
+        &self,
+        path_segments: &[String],
+        content: Vec<u8>,
+        modification_time_seconds: i64,
+    ) -> Result<Cid, String> {
+        let path = self.authorize("write_file", Permissions::WRITE, path_segments)?;
+        self.handle
+            .write_file(&path, content, modification_time_seconds)
+            .await
+    }
+
+    pub async fn mkdir(&self, path_segments: &[String]) -> Result<Cid, String> {
+        let path = self.authorize("mkdir", Permissions::WRITE, path_segments)?;
+        self.handle.mkdir(&path).await
+    }
+
+    /// Removing the scope's own directory isn't possible; that takes a handle on its parent.
+    pub async fn rm(&self, path_segments: &[String]) -> Result<Cid, String> {
+        let path = self.authorize("rm", Permissions::DELETE, path_segments)?;
+        self.check_below_root(path_segments)?;
+        self.handle.rm(&path).await
+    }
+
+    /// Moving takes the source away, so it needs both `WRITE` and `DELETE`. Like `rm`, it
+    /// can't move the scope's own directory.
+    pub async fn mv(
+        &self,
+        source_path_segments: &[String],
+        target_path_segments: &[String],
+    ) -> Result<Cid, String> {
+        let required = Permissions::WRITE | Permissions::DELETE;
+        let source = self.authorize("mv", required, source_path_segments)?;
+        self.check_below_root(source_path_segments)?;
+        let target = self.authorize("mv", required, target_path_segments)?;
+        self.handle.mv(&source, &target).await
+    }
+
+    pub async fn share(
+        &self,
+        path_segments: &[String],
+        recipient_public_key: &[u8],
+    ) -> Result<SharePayload, String> {
+        let path = self.authorize("share", Permissions::SHARE, path_segments)?;
+        let recipient_public_key = recipient_public_key.to_vec();
+        self.handle
+            .write(move |helper| {
+                Box::pin(async move { helper.share(&path, &recipient_public_key).await })
+            })
+            .await
+    }
+
+    pub async fn history(&self, path_segments: &[String]) -> Result<Vec<RevisionInfo>, String> {
+        let path = self.authorize("history", Permissions::HISTORY, path_segments)?;
+        self.handle
+            .write(move |helper| Box::pin(async move { helper.history(&path).await }))
+            .await
+    }
+
+    pub async fn read_file_at_revision(
+        &self,
+        path_segments: &[String],
+        revision: usize,
+    ) -> Result<Vec<u8>, String> {
+        let required = Permissions::HISTORY | Permissions::READ;
+        let path = self.authorize("read_file_at_revision", required, path_segments)?;
+        self.handle
+            .write(move |helper| {
+                Box::pin(async move { helper.read_file_at_revision(&path, revision).await })
+            })
+            .await
+    }
+
+    pub async fn restore_revision(
+        &self,
+        path_segments: &[String],
+        revision: usize,
+    ) -> Result<Cid, String> {
+        let required = Permissions::HISTORY | Permissions::WRITE;
+        let path = self.authorize("restore_revision", required, path_segments)?;
+        self.handle
+            .write(move |helper| {
+                Box::pin(async move { helper.restore_revision(&path, revision).await })
+            })
+            .await
+    }
+}
+
+impl<'a> HelperHandle<'a> {
+    /// A handle on the directory at `path_segments` of this handle's helper, allowing
+    /// `permissions` below it.
+    pub fn scoped(
+        &self,
+        path_segments: &[String],
+        permissions: Permissions,
+    ) -> Result<ScopedHandle<'a>, String> {
+        self.snapshot()?.helper.check_path_depth(path_segments)?;
+        ScopedHandle {
+            handle: self.to_owned(),
+            root: Vec::new(),
+            permissions,
+        }
+        .narrow(path_segments, permissions)
+    }
+}
+
+impl<'a> PrivateDirectoryHelper<'a> {
+    /// Moves the helper behind a handle, see `into_handle`, and returns a handle on the
+    /// directory at `path_segments` allowing `permissions` below it.
+    pub async fn into_scoped_handle(
+        self,
+        path_segments: &[String],
+        permissions: Permissions,
+    ) -> Result<ScopedHandle<'a>, String> {
+        self.into_handle().await?.scoped(path_segments, permissions)
+    }
+
+    pub fn synced_into_scoped_handle(
+        self,
+        path_segments: &[String],
+        permissions: Permissions,
+    ) -> Result<ScopedHandle<'a>, String> {
+        let limiter = self.operation_limiter();
+        Self::run_limited(
+            &limiter,
+            "into_scoped_handle",
+            self.into_scoped_handle(path_segments, permissions),
+        )
+    }
+}
+
+impl<'a> ScopedHandle<'a> {
+    pub fn synced_read_file(&self, path_segments: &[String]) -> Result<Vec<u8>, String> {
+        PrivateDirectoryHelper::run_limited(
+            &self.handle.operation_limiter(),
+            "scoped_read_file",
+            self.read_file(path_segments),
+        )
+    }
+
+    pub fn synced_ls_files(
+        &self,
+        path_segments: &[String],
+    ) -> Result<Vec<(String, Metadata)>, String> {
+        PrivateDirectoryHelper::run_limited(
+            &self.handle.operation_limiter(),
+            "scoped_ls_files",
+            self.ls_files(path_segments),
+        )
+    }
+
+    pub fn synced_write_file(
+        &self,
+        path_segments: &[String],
+        content: Vec<u8>,
+        modification_time_seconds: i64,
+    ) -> Result<Cid, String> {
+        PrivateDirectoryHelper::run_limited(
+            &self.handle.operation_limiter(),
+            "scoped_write_file",
+            self.write_file(path_segments, content, modification_time_seconds),
+        )
+    }
+
+    pub fn synced_mkdir(&self, path_segments: &[String]) -> Result<Cid, String> {
+        PrivateDirectoryHelper::run_limited(
+            &self.handle.operation_limiter(),
+            "scoped_mkdir",
+            self.mkdir(path_segments),
+        )
+    }
+
+    pub fn synced_rm(&self, path_segments: &[String]) -> Result<Cid, String> {
+        PrivateDirectoryHelper::run_limited(
+            &self.handle.operation_limiter(),
+            "scoped_rm",
+            self.rm(path_segments),
+        )
+    }
+
+    pub fn synced_mv(
+        &self,
+        source_path_segments: &[String],
+        target_path_segments: &[String],
+    ) -> Result<Cid, String> {
+        PrivateDirectoryHelper::run_limited(
+            &self.handle.operation_limiter(),
+            "scoped_mv",
+            self.mv(source_path_segments, target_path_segments),
+        )
+    }
+
+    pub fn synced_share(
+        &self,
+        path_segments: &[String],
+        recipient_public_key: &[u8],
+    ) -> Result<SharePayload, String> {
+        PrivateDirectoryHelper::run_limited(
+            &self.handle.operation_limiter(),
+            "scoped_share",
+            self.share(path_segments, recipient_public_key),
+        )
+    }
+
+    pub fn synced_history(&self, path_segments: &[String]) -> Result<Vec<RevisionInfo>, String> {
+        PrivateDirectoryHelper::run_limited(
+            &self.handle.operation_limiter(),
+            "scoped_history",
+            self.history(path_segments),
+        )
+    }
+
+    pub fn synced_read_file_at_revision(
+        &self,
+        path_segments: &[String],
+        revision: usize,
+    ) -> Result<Vec<u8>, String> {
+        PrivateDirectoryHelper::run_limited(
+            &self.handle.operation_limiter(),
+            "scoped_read_file_at_revision",
+            self.read_file_at_revision(path_segments, revision),
+        )
+    }
+
+    pub fn synced_restore_revision(
+        &self,
+        path_segments: &[String],
+        revision: usize,
+    ) -> Result<Cid, String> {
+        PrivateDirectoryHelper::run_limited(
+            &self.handle.operation_limiter(),
+            "scoped_restore_revision",
+            self.restore_revision(path_segments, revision),
+        )
+    }
+}
@@ -0,0 +1,157 @@
+//! Subtree queries answered in one call, for UIs that would otherwise `ls` each directory level
+//! across the FFI: disk usage, recursive listings and search by name.
+//!
+//! All three are built on `walk`, so they see what the path APIs see: sharded directories as one
+//! directory, paged files as files, and the helper's reserved entries not at all. Sizes are the
+//! files' content size upper bounds, as in `VfsStat`.
+
+use futures::StreamExt;
+
+use super::{PrivateDirectoryHelper, WalkEntry, WalkOptions};
+use crate::vfs::VfsNodeKind;
+
+// Content bytes WNFS puts into each encrypted block: 2^18 less the nonce and the tag.
+const CONTENT_BLOCK_BYTES: u64 = (1 << 18) - 24 - 16;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DiskUsage {
+    /// Content bytes of all files.
+    pub size: u64,
+    pub files: usize,
+    /// Directories, the starting one included.
+    pub directories: usize,
+    /// Blocks holding the subtree: one per directory and file, plus the content blocks of each
+    /// file, estimated from its size.
+    pub blocks: u64,
+}
+
+impl<'a> PrivateDirectoryHelper<'a> {
+    /// Totals of the subtree at `path_segments`, which may also be a single file.
+    pub async fn du(&mut self, path_segments: &[String]) -> Result<DiskUsage, String> {
+        let mut usage = DiskUsage::default();
+        for entry in self.walk_collect(path_segments, None).await? {
+            usage.blocks += 1;
+            match entry.stat.kind {
+                VfsNodeKind::Directory => usage.directories += 1,
+                VfsNodeKind::File => {
+                    usage.files += 1;
+                    usage.size += entry.stat.size;
+                    usage.blocks += entry.stat.size.div_ceil(CONTENT_BLOCK_BYTES);
+                }
+            }
+        }
+        Ok(usage)
+    }
+
+    /// The entries below the directory at `path_segments` up to `depth` levels down, 1 listing
+    /// its children only, in the order `walk` visits them.
+    pub async fn ls_recursive(
+        &mut self,
+        path_segments: &[String],
+        depth: usize,
+    ) -> Result<Vec<WalkEntry>, String> {
+        let entries = self.walk_collect(path_segments, Some(depth)).await?;
+        Ok(entries
+            .into_iter()
+            .filter(|entry| entry.depth > 0)
+            .collect())
+    }
+
+    /// The entries below `path_segments` whose name matches `pattern`, ignoring case. A pattern
+    /// with `*` or `?` is a glob matched against the whole name, any other is a substring.
+    pub async fn search(
+        &mut self,
+        path_segments: &[String],
+        pattern: &str,
+    ) -> Result<Vec<WalkEntry>, String> {
+        let pattern = pattern.to_lowercase();
+        let entries = self.walk_collect(path_segments, None).await?;
+        Ok(entries
+            .into_iter()
+            .filter(|entry| entry.depth > 0)
+            .filter(|entry| {
+                let name = entry.path.last().map(|name| name.to_lowercase());
+                name.map_or(false, |name| Self::name_matches(&pattern, &name))
+            })
+            .collect())
+    }
+
+    async fn walk_collect(
+        &mut self,
+        path_segments: &[String],
+        max_depth: Option<usize>,
+    ) -> Result<Vec<WalkEntry>, String> {
+        let options = WalkOptions {
+            max_depth,
+            ..Default::default()
+        };
+        self.walk(path_segments, options)
+            .collect::<Vec<_>>()
+            .await
+            .into_iter()
+            .collect()
+    }
+
+    fn name_matches(pattern: &str, name: &str) -> bool {
+        if !pattern.contains(&['*', '?'][..]) {
+            return name.contains(pattern);
+        }
+        let pattern: Vec<char> = pattern.chars().collect();
+        let name: Vec<char> = name.chars().collect();
+        let (mut p, mut n) = (0, 0);
+        // Position of the last `*` and of the name where it started matching, to backtrack to.
+        let mut star = None;
+        while n < name.len() {
+            match pattern.get(p) {
+                Some('*') => {
+                    star = Some((p, n));
+                    p += 1;
+                }
+                Some(&c) if c == '?' || c == name[n] => {
+                    p += 1;
+                    n += 1;
+                }
+                _ => match star {
+                    Some((star_p, star_n)) => {
+                        // Let the `*` take one more character.
+                        star = Some((star_p, star_n + 1));
+                        p = star_p + 1;
+                        n = star_n + 1;
+                    }
+                    None => return false,
+                },
+            }
+        }
+        pattern[p..].iter().all(|&c| c == '*')
+    }
+}
+
+impl<'a> PrivateDirectoryHelper<'a> {
+    pub fn synced_du(&mut self, path_segments: &[String]) -> Result<DiskUsage, String> {
+        Self::run_limited(&self.operation_limiter(), "du", self.du(path_segments))
+    }
+
+    pub fn synced_ls_recursive(
+        &mut self,
+        path_segments: &[String],
+        depth: usize,
+    ) -> Result<Vec<WalkEntry>, String> {
+        Self::run_limited(
+            &self.operation_limiter(),
+            "ls_recursive",
+            self.ls_recursive(path_segments, depth),
+        )
+    }
+
+    pub fn synced_search(
+        &mut self,
+        path_segments: &[String],
+        pattern: &str,
+    ) -> Result<Vec<WalkEntry>, String> {
+        Self::run_limited(
+            &self.operation_limiter(),
+            "search",
+            self.search(path_segments, pattern),
+        )
+    }
+}
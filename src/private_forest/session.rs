@@ -0,0 +1,200 @@
+//! Session types that spell out the forest's locking rules.
+//!
+//! A forest has a single writer: two helpers loaded from the same root each commit their own
+//! new root, and whichever is published last silently drops the other's changes. An
+//! [`ExclusiveSession`] owns the only helper of this process allowed to mutate a forest; opening
+//! a second one for the same wnfs key fails with `WnfsUtilsError::ForestLocked` until the first
+//! is dropped. A [`SharedSession`] reads the forest at a fixed root, can't mutate it, and any
+//! number of them can be open next to the exclusive one.
+//!
+//! The sessions are the only way to open a forest from outside the crate: the public
+//! constructors return one, and `init` and `load_with_wnfs_key` are crate-internal. The lock
+//! belongs to the helper, so a helper taken out of its session with `into_helper`, e.g. to
+//! build a `HelperHandle` on it, keeps the forest locked until it's dropped.
+
+use std::{
+    collections::HashSet,
+    ops::{Deref, DerefMut},
+    sync::Mutex,
+};
+
+use libipld::Cid;
+use log::trace;
+use sha3::{Digest, Sha3_256};
+use wnfs::private::AccessKey;
+
+use super::{PrivateDirectoryHelper, ReadOnlyView};
+use crate::blockstore::FFIFriendlyBlockStore;
+use crate::error::WnfsUtilsError;
+
+// Forests with an open exclusive session, by hash of their wnfs key.
+static WRITERS: Mutex<Option<HashSet<Vec<u8>>>> = Mutex::new(None);
+
+// Held by the helper of an exclusive session, releases the forest when dropped.
+pub(super) struct WriterLock {
+    forest_id: Vec<u8>,
+}
+
+impl WriterLock {
+    pub(super) fn acquire(wnfs_key: &[u8]) -> Result<Self, String> {
+        let forest_id = Sha3_256::digest(wnfs_key).to_vec();
+        let mut writers = match WRITERS.lock() {
+            Ok(writers) => writers,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        if !writers
+            .get_or_insert_with(HashSet::new)
+            .insert(forest_id.to_owned())
+        {
+            trace!("wnfsError in session: forest already open for writing");
+            return Err(WnfsUtilsError::ForestLocked.to_string());
+        }
+        Ok(Self { forest_id })
+    }
+}
+
+impl Drop for WriterLock {
+    fn drop(&mut self) {
+        let mut writers = match WRITERS.lock() {
+            Ok(writers) => writers,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        if let Some(writers) = writers.as_mut() {
+            writers.remove(&self.forest_id);
+        }
+    }
+}
+
+/// The only helper of this process allowed to commit to its forest. It derefs to the helper
+/// and can't be cloned; hand out [`SharedSession`]s to readers instead.
+pub struct ExclusiveSession<'a> {
+    helper: PrivateDirectoryHelper<'a>,
+}
+
+impl<'a> ExclusiveSession<'a> {
+    // The session of `helper`, which holds `lock` from now on.
+    pub(super) fn new(mut helper: PrivateDirectoryHelper<'a>, lock: WriterLock) -> Self {
+        helper.writer_lock = Some(lock);
+        Self { helper }
+    }
+
+    /// The helper, which keeps the forest locked until it's dropped.
+    pub fn into_helper(self) -> PrivateDirectoryHelper<'a> {
+        self.helper
+    }
+
+    /// Opens a read-only session at the latest root this session committed or was loaded at.
+    pub async fn shared(&self) -> Result<SharedSession<'a>, String> {
+        let root = self.helper.root_history().last().copied().ok_or_else(|| {
+            trace!("wnfsError in shared: no root recorded");
+            "wnfsError no root recorded for this session".to_string()
+        })?;
+        Ok(SharedSession {
+            view: self.helper.open_at(root).await?,
+        })
+    }
+}
+
+impl<'a> Deref for ExclusiveSession<'a> {
+    type Target = PrivateDirectoryHelper<'a>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.helper
+    }
+}
+
+impl<'a> DerefMut for ExclusiveSession<'a> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.helper
+    }
+}
+
+/// Reads a forest at a fixed root. It derefs to a [`ReadOnlyView`], so only read operations
+/// are available.
+pub struct SharedSession<'a> {
+    view: ReadOnlyView<'a>,
+}
+
+impl<'a> Deref for SharedSession<'a> {
+    type Target = ReadOnlyView<'a>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.view
+    }
+}
+
+impl<'a> DerefMut for SharedSession<'a> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.view
+    }
+}
+
+impl<'a> PrivateDirectoryHelper<'a> {
+    /// Like `init`, returning the new forest in an exclusive session.
+    pub async fn init_exclusive(
+        store: &mut FFIFriendlyBlockStore<'a>,
+        wnfs_key: Vec<u8>,
+    ) -> Result<(ExclusiveSession<'a>, AccessKey, Cid), String> {
+        let lock = WriterLock::acquire(&wnfs_key)?;
+        let (helper, access_key, forest_cid) = Self::init(store, wnfs_key).await?;
+        Ok((ExclusiveSession::new(helper, lock), access_key, forest_cid))
+    }
+
+    /// Like `load_with_wnfs_key`, failing while another exclusive session of the same forest
+    /// is open.
+    pub async fn load_exclusive(
+        store: &mut FFIFriendlyBlockStore<'a>,
+        forest_cid: Cid,
+        wnfs_key: Vec<u8>,
+    ) -> Result<ExclusiveSession<'a>, String> {
+        let lock = WriterLock::acquire(&wnfs_key)?;
+        let helper = Self::load_with_wnfs_key(store, forest_cid, wnfs_key).await?;
+        Ok(ExclusiveSession::new(helper, lock))
+    }
+
+    /// Opens the forest at `forest_cid` for reading only.
+    pub async fn load_shared(
+        store: &mut FFIFriendlyBlockStore<'a>,
+        forest_cid: Cid,
+        wnfs_key: Vec<u8>,
+    ) -> Result<SharedSession<'a>, String> {
+        let helper = Self::load_with_wnfs_key(store, forest_cid, wnfs_key).await?;
+        Ok(SharedSession {
+            view: ReadOnlyView {
+                helper,
+                root_cid: forest_cid,
+            },
+        })
+    }
+}
+
+impl<'a> PrivateDirectoryHelper<'a> {
+    pub fn synced_init_exclusive(
+        store: &mut FFIFriendlyBlockStore<'a>,
+        wnfs_key: Vec<u8>,
+    ) -> Result<(ExclusiveSession<'a>, AccessKey, Cid), String> {
+        Self::run_request("init_exclusive", Self::init_exclusive(store, wnfs_key))
+    }
+
+    pub fn synced_load_exclusive(
+        store: &mut FFIFriendlyBlockStore<'a>,
+        forest_cid: Cid,
+        wnfs_key: Vec<u8>,
+    ) -> Result<ExclusiveSession<'a>, String> {
+        Self::run_request(
+            "load_exclusive",
+            Self::load_exclusive(store, forest_cid, wnfs_key),
+        )
+    }
+
+    pub fn synced_load_shared(
+        store: &mut FFIFriendlyBlockStore<'a>,
+        forest_cid: Cid,
+        wnfs_key: Vec<u8>,
+    ) -> Result<SharedSession<'a>, String> {
+        Self::run_request(
+            "load_shared",
+            Self::load_shared(store, forest_cid, wnfs_key),
+        )
+    }
+}
@@ -0,0 +1,223 @@
+//! Transparent sharding of very large flat directories.
+//!
+//! Listing a WNFS directory decrypts its node, which holds every entry, so a folder with
+//! hundreds of thousands of files makes each listing and each write below it slow. Once a
+//! directory grows past `DirectorySharding::split_threshold` entries, the helper moves its
+//! entries into up to 256 shard subdirectories picked by the SHA-256 of the entry name, and
+//! marks the directory with an empty `SHARD_MARKER` subdirectory. Path APIs keep taking the
+//! logical path: `dir/name` resolves to `dir/<shard of name>/name` below a marked directory,
+//! and `ls_files` merges the shards back into one listing.
+//!
+//! `HelperConfig::directory_sharding` only decides when directories get split. Resolution
+//! follows the `SHARD_MARKER` stored in the forest, so a helper with the default config still
+//! reads a sharded directory written by another device.
+
+use std::rc::Rc;
+
+use chrono::Utc;
+use log::trace;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use wnfs::{
+    common::Metadata,
+    private::{PrivateDirectory, PrivateNode},
+};
+
+use super::{PrivateDirectoryHelper, PAGED_MARKER, RESERVED_DIR};
+use crate::error::describe;
+
+/// Marks a directory whose entries live in shard subdirectories.
+pub const SHARD_MARKER: &str = ".wnfsutils-sharded";
+const SHARD_PREFIX: &str = ".wnfsutils-shard-";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DirectorySharding {
+    /// A directory holding more entries than this is split into shards on the next write.
+    pub split_threshold: usize,
+}
+
+impl Default for DirectorySharding {
+    fn default() -> Self {
+        Self {
+            split_threshold: 10_000,
+        }
+    }
+}
+
+impl<'a> PrivateDirectoryHelper<'a> {
+    // Maps a logical path to the path actually stored in the forest, in a single descent from
+    // the root directory.
+    pub(super) async fn resolve_path(
+        &mut self,
+        path_segments: &[String],
+    ) -> Result<Vec<String>, String> {
+        let path_segments = self.normalize_path(path_segments);
+        let mut resolved = Vec::with_capacity(path_segments.len() * 2);
+        // The directory stored at `resolved`, `None` once the path leaves the stored tree.
+        let mut dir = Some(Rc::clone(&self.root_dir));
+        for segment in &path_segments {
+            let Some(mut current) = dir.take() else {
+                resolved.push(segment.to_owned());
+                continue;
+            };
+            if !Self::is_reserved_name(segment)
+                && self.child_dir(&current, SHARD_MARKER).await?.is_some()
+            {
+                let shard = Self::shard_name(segment);
+                match self.child_dir(&current, &shard).await? {
+                    Some(shard_dir) => current = shard_dir,
+                    None => {
+                        resolved.push(shard);
+                        resolved.push(segment.to_owned());
+                        continue;
+                    }
+                }
+                resolved.push(shard);
+            }
+            dir = self.child_dir(&current, segment).await?;
+            resolved.push(segment.to_owned());
+        }
+        Ok(resolved)
+    }
+
+    // The directory `name` of `dir`, `None` if there is none.
+    async fn child_dir(
+        &mut self,
+        dir: &Rc<PrivateDirectory>,
+        name: &str,
+    ) -> Result<Option<Rc<PrivateDirectory>>, String> {
+        let forest = &mut self.forest;
+        let node = dir
+            .get_node(&[name.to_string()], true, forest, &mut self.store)
+            .await
+            .map_err(|e| {
+                trace!("wnfsError in resolve_path: {:?}", e.to_string());
+                describe(&e)
+            })?;
+        Ok(match node {
+            Some(PrivateNode::Dir(child)) => Some(child),
+            _ => None,
+        })
+    }
+
+    // Resolves the logical entry `name` of the directory stored at `resolved_dir`.
+    pub(super) async fn resolve_child(
+        &mut self,
+        resolved_dir: &[String],
+        name: &str,
+    ) -> Result<Vec<String>, String> {
+        let mut resolved = resolved_dir.to_vec();
+        if !Self::is_reserved_name(name) && self.is_sharded(resolved_dir).await? {
+            resolved.push(Self::shard_name(name));
+        }
+        resolved.push(name.to_string());
+        Ok(resolved)
+    }
+
+    // Lists the directory stored at `resolved`, merging its shards when it is sharded.
+    pub(super) async fn ls_resolved(
+        &mut self,
+        resolved: &[String],
+    ) -> Result<Vec<(String, Metadata)>, String> {
+        let entries = self.ls_raw(resolved).await?;
+        if !entries.iter().any(|(name, _)| name == SHARD_MARKER) {
+            return Ok(entries);
+        }
+        let mut merged = Vec::new();
+        for (name, metadata) in entries {
+            if name == SHARD_MARKER {
+                continue;
+            }
+            if name.starts_with(SHARD_PREFIX) {
+                let mut shard = resolved.to_vec();
+                shard.push(name);
+                merged.extend(self.ls_raw(&shard).await?);
+            } else {
+                merged.push((name, metadata));
+            }
+            self.check_dir_entries(merged.len())?;
+        }
+        Ok(merged)
+    }
+
+    // Splits the parent directory of the logical `path_segments` into shards once it holds
+    // more entries than the configured threshold. Doesn't commit.
+    pub(super) async fn split_parent_if_needed(
+        &mut self,
+        path_segments: &[String],
+    ) -> Result<(), String> {
+        let threshold = match self.config.directory_sharding {
+            Some(sharding) => sharding.split_threshold,
+            None => return Ok(()),
+        };
+        let parent = match path_segments.split_last() {
+            Some((_, parent)) => self.resolve_path(parent).await?,
+            None => return Ok(()),
+        };
+        let entries = self.ls_raw(&parent).await?;
+        if entries.len() <= threshold || entries.iter().any(|(name, _)| name == SHARD_MARKER) {
+            return Ok(());
+        }
+
+        let time = Utc::now();
+        let mut marker = parent.to_owned();
+        marker.push(SHARD_MARKER.to_string());
+        let forest = &mut self.forest;
+        let root_dir = &mut self.root_dir;
+        root_dir
+            .mkdir(&marker, true, time, forest, &mut self.store, &mut self.rng)
+            .await
+            .map_err(|e| {
+                trace!("wnfsError in split_parent_if_needed: {:?}", e.to_string());
+                describe(&e)
+            })?;
+        for (name, _) in entries {
+            if Self::is_reserved_name(&name) {
+                continue;
+            }
+            let mut source = parent.to_owned();
+            source.push(name.to_owned());
+            let mut target = parent.to_owned();
+            target.push(Self::shard_name(&name));
+            target.push(name);
+            let forest = &mut self.forest;
+            let root_dir = &mut self.root_dir;
+            root_dir
+                .basic_mv(
+                    &source,
+                    &target,
+                    true,
+                    time,
+                    forest,
+                    &mut self.store,
+                    &mut self.rng,
+                )
+                .await
+                .map_err(|e| {
+                    trace!("wnfsError in split_parent_if_needed: {:?}", e.to_string());
+                    describe(&e)
+                })?;
+        }
+        Ok(())
+    }
+
+    async fn is_sharded(&mut self, resolved_dir: &[String]) -> Result<bool, String> {
+        let mut marker = resolved_dir.to_vec();
+        marker.push(SHARD_MARKER.to_string());
+        Ok(self.raw_node_at(&marker).await?.is_some())
+    }
+
+    fn shard_name(name: &str) -> String {
+        format!("{}{:02x}", SHARD_PREFIX, Sha256::digest(name.as_bytes())[0])
+    }
+
+    // Names of the helper's own bookkeeping, which stay where they are.
+    pub(super) fn is_reserved_name(name: &str) -> bool {
+        name == RESERVED_DIR || Self::is_marker_name(name)
+    }
+
+    // Names that make their directory a paged file or a sharded directory, or a shard of one.
+    pub(super) fn is_marker_name(name: &str) -> bool {
+        name == SHARD_MARKER || name == PAGED_MARKER || name.starts_with(SHARD_PREFIX)
+    }
+}
@@ -0,0 +1,453 @@
+//! Sharing a private directory with another user without handing over the WNFS key.
+//!
+//! The recipient generates an [`ExchangeKeyPair`] and sends its public key to the sharer.
+//! `share` encrypts the directory's access key for that public key and stores it in the
+//! sharer's forest; the returned [`SharePayload`] is what the recipient needs to find it.
+//! `accept_share` opens the shared directory read-only from the forest named in the payload.
+//! Each share goes under a new counter, so sharing again never overwrites an earlier share.
+//!
+//! The payload carries a [`ShareProof`] of the blocks the recipient reads to open the share:
+//! the path through the forest to the share and to the directory's nodes, and the directory's
+//! own blocks. However the blocks travel, `accept_share` checks them against it first. The
+//! proof lists nothing else of the sharer's forest.
+
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, HashSet},
+    rc::Rc,
+};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::Utc;
+use futures::StreamExt;
+use libipld::{cbor::DagCborCodec, codec::Codec, Cid, Ipld};
+use log::trace;
+use rand::{thread_rng, RngCore};
+use sha3::{Digest, Sha3_256};
+use wnfs::{
+    common::{BlockStore, CODEC_RAW},
+    nameaccumulator::Name,
+    private::{
+        forest::traits::PrivateForest,
+        share::{recipient, sharer},
+        AccessKey, PrivateNode,
+    },
+    public::{PublicDirectory, PublicLink, PublicNode},
+};
+
+use super::{PrivateDirectoryHelper, PublicExchangeKey, ReadOnlyView, SeededExchangeKey};
+use crate::blockstore::{cid_from_bytes, FFIFriendlyBlockStore, FFIStore};
+use crate::car::ShareProof;
+use crate::error::{describe, WnfsUtilsError};
+
+// Share counters searched for the latest one.
+const SHARE_COUNTER_LIMIT: u64 = 1000;
+
+/// An RSA exchange key pair derived from a 32-byte seed, which is all the app has to keep.
+pub struct ExchangeKeyPair {
+    seed: [u8; 32],
+    key: SeededExchangeKey,
+}
+
+impl ExchangeKeyPair {
+    /// A key pair from a fresh random seed.
+    pub fn generate() -> Result<Self, String> {
+        let mut seed = [0u8; 32];
+        thread_rng().fill_bytes(&mut seed);
+        Self::from_seed(seed)
+    }
+
+    pub fn from_seed(seed: [u8; 32]) -> Result<Self, String> {
+        let key = SeededExchangeKey::from_seed(seed).map_err(|e| {
+            trace!(
+                "wnfsError in ExchangeKeyPair::from_seed: {:?}",
+                e.to_string()
+            );
+            describe(&e)
+        })?;
+        Ok(Self { seed, key })
+    }
+
+    /// The secret to persist, e.g. in the platform keychain.
+    pub fn seed(&self) -> [u8; 32] {
+        self.seed
+    }
+
+    /// The key to hand to sharers, the RSA modulus.
+    pub fn public_key(&self) -> Vec<u8> {
+        self.key.encode_public_key()
+    }
+}
+
+/// Where a recipient finds a share: the sharer's forest, who shared and under which counter.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SharePayload {
+    /// Forest the share was committed to. Any later forest CID of the sharer works too and
+    /// opens the latest revision of the directory it contains.
+    pub forest_cid: Cid,
+    /// Public identifier of the sharer, derived from their WNFS key.
+    pub sharer: String,
+    pub counter: u64,
+    /// Blocks of the share at `forest_cid`, `None` in payloads of earlier versions.
+    pub proof: Option<ShareProof>,
+}
+
+impl SharePayload {
+    /// DAG-CBOR `{"forest": link, "sharer": string, "counter": int, "proof": bytes}`, with the
+    /// proof's own encoding and without `"proof"` when there is none.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, String> {
+        let mut map = BTreeMap::from([
+            ("forest".to_string(), Ipld::Link(self.forest_cid)),
+            ("sharer".to_string(), Ipld::String(self.sharer.to_owned())),
+            ("counter".to_string(), Ipld::Integer(self.counter as i128)),
+        ]);
+        if let Some(proof) = &self.proof {
+            map.insert("proof".to_string(), Ipld::Bytes(proof.to_bytes()?));
+        }
+        DagCborCodec
+            .encode(&Ipld::Map(map))
+            .map_err(|e| describe(&e))
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        let map = match DagCborCodec.decode(bytes).map_err(|e| describe(&e))? {
+            Ipld::Map(map) => map,
+            _ => return Err("a share payload must be a map".into()),
+        };
+        let proof = match map.get("proof") {
+            None => None,
+            Some(Ipld::Bytes(proof)) => Some(ShareProof::from_bytes(proof)?),
+            Some(_) => return Err("malformed share payload proof".into()),
+        };
+        match (map.get("forest"), map.get("sharer"), map.get("counter")) {
+            (
+                Some(Ipld::Link(forest_cid)),
+                Some(Ipld::String(sharer)),
+                Some(Ipld::Integer(counter)),
+            ) => Ok(Self {
+                forest_cid: *forest_cid,
+                sharer: sharer.to_owned(),
+                counter: u64::try_from(*counter).map_err(|e| describe(&e))?,
+                proof,
+            }),
+            _ => Err("malformed share payload".into()),
+        }
+    }
+}
+
+// Records the blocks read through it, in the order they were first read, for `share_proof`.
+#[derive(Clone)]
+struct ReadRecorder<'a> {
+    inner: Box<dyn FFIStore<'a> + 'a>,
+    read: Rc<RefCell<(HashSet<Vec<u8>>, Vec<(Vec<u8>, u64)>)>>,
+}
+
+impl<'a> ReadRecorder<'a> {
+    fn record(&self, cid: Vec<u8>, data: &[u8]) {
+        let (seen, blocks) = &mut *self.read.borrow_mut();
+        if seen.insert(cid.to_owned()) {
+            blocks.push((cid, data.len() as u64));
+        }
+    }
+
+    fn blocks(&self) -> Result<Vec<(Cid, u64)>, String> {
+        self.read
+            .borrow()
+            .1
+            .iter()
+            .map(|(cid, size)| Ok((cid_from_bytes(cid).map_err(|e| describe(&e))?, *size)))
+            .collect()
+    }
+}
+
+#[async_trait(?Send)]
+impl<'a> FFIStore<'a> for ReadRecorder<'a> {
+    fn get_block(&self, cid: Vec<u8>) -> Result<Vec<u8>> {
+        let data = self.inner.get_block(cid.to_owned())?;
+        self.record(cid, &data);
+        Ok(data)
+    }
+
+    fn put_block(&self, cid: Vec<u8>, bytes: Vec<u8>) -> Result<()> {
+        self.inner.put_block(cid, bytes)
+    }
+
+    async fn get_block_async(&self, cid: Vec<u8>) -> Result<Vec<u8>> {
+        let data = self.inner.get_block_async(cid.to_owned()).await?;
+        self.record(cid, &data);
+        Ok(data)
+    }
+}
+
+impl<'a> PrivateDirectoryHelper<'a> {
+    /// Public identifier of this helper's user in shares. It's a hash of the WNFS key, so unlike
+    /// the identifier of the helper's own root share it can be handed out.
+    pub fn sharer_id(&self) -> String {
+        let mut hasher = Sha3_256::new();
+        hasher.update(b"wnfsutils-sharer");
+        hasher.update(&self.wnfs_key);
+        Self::bytes_to_hex_str(&hasher.finalize())
+    }
+
+    /// Shares the directory at `path_segments` with the holder of `recipient_public_key`, see
+    /// `ExchangeKeyPair::public_key`, in a new commit.
+    pub async fn share(
+        &mut self,
+        path_segments: &[String],
+        recipient_public_key: &[u8],
+    ) -> Result<SharePayload, String> {
+        let node = match self.node_at(path_segments).await? {
+            Some(node) if node.is_dir() => node,
+            _ => {
+                trace!("wnfsError in share: no directory at {:?}", path_segments);
+                return Err(format!(
+                    "wnfsError no directory found at {:?}",
+                    path_segments
+                ));
+            }
+        };
+        let access_key = node
+            .store(&mut self.forest, &mut self.store, &mut self.rng)
+            .await
+            .map_err(|e| {
+                trace!("wnfsError in share: {:?}", e.to_string());
+                describe(&e)
+            })?;
+        let sharer = self.sharer_id();
+        let exchange_root = self.recipient_exchange_root(recipient_public_key).await?;
+        let counter = recipient::find_latest_share_counter(
+            0,
+            SHARE_COUNTER_LIMIT,
+            recipient_public_key,
+            &sharer,
+            &self.forest,
+            &self.store,
+        )
+        .await
+        .map_err(|e| {
+            trace!("wnfsError in share: {:?}", e.to_string());
+            describe(&e)
+        })?
+        .map(|latest| latest + 1)
+        .unwrap_or_default();
+        sharer::share::<PublicExchangeKey>(
+            &access_key,
+            counter,
+            &sharer,
+            exchange_root,
+            &mut self.forest,
+            &self.store,
+        )
+        .await
+        .map_err(|e| {
+            trace!("wnfsError in share: {:?}", e.to_string());
+            describe(&e)
+        })?;
+        let forest_cid = self.commit_now().await?;
+        let name = sharer::create_share_name(counter, &sharer, recipient_public_key, &self.forest);
+        let proof = self.share_proof(forest_cid, &name, &access_key).await?;
+        Ok(SharePayload {
+            forest_cid,
+            sharer,
+            counter,
+            proof: Some(proof),
+        })
+    }
+
+    // Opens the share at `name` in `forest_cid` the way the recipient does, from a fresh copy of
+    // the forest, and walks the whole shared directory, recording every block read.
+    async fn share_proof(
+        &self,
+        forest_cid: Cid,
+        name: &Name,
+        access_key: &AccessKey,
+    ) -> Result<ShareProof, String> {
+        let recorder = ReadRecorder {
+            inner: self.store.ffi_store.to_owned(),
+            read: Rc::default(),
+        };
+        let store = FFIFriendlyBlockStore::new(Box::new(recorder.to_owned()));
+        let forest = Self::load_private_forest(store.to_owned(), forest_cid).await?;
+        let shares: Vec<Cid> = forest
+            .get_encrypted(name, &store)
+            .await
+            .map_err(|e| {
+                trace!("wnfsError in share_proof: {:?}", e.to_string());
+                describe(&e)
+            })?
+            .map(|cids| cids.iter().copied().collect())
+            .unwrap_or_default();
+        for cid in &shares {
+            store.get_block(cid).await.map_err(|e| describe(&e))?;
+        }
+        let shared_dir = PrivateNode::load(access_key, &forest, &store, None)
+            .await
+            .map_err(|e| describe(&e))?
+            .search_latest(&forest, &store)
+            .await
+            .and_then(|node| node.as_dir())
+            .map_err(|e| {
+                trace!("wnfsError in share_proof: {:?}", e.to_string());
+                describe(&e)
+            })?;
+
+        let mut shared = Self::from_parts(store, forest, shared_dir, thread_rng(), Vec::new());
+        let mut pending = vec![Vec::new()];
+        while let Some(dir) = pending.pop() {
+            for (name, _) in shared.ls_raw(&dir).await? {
+                let mut child = dir.to_owned();
+                child.push(name);
+                match shared.raw_node_at(&child).await? {
+                    Some(PrivateNode::Dir(_)) => pending.push(child),
+                    Some(PrivateNode::File(file)) => {
+                        let mut content =
+                            Box::pin(file.stream_content(0, &shared.forest, &shared.store));
+                        while let Some(block) = content.next().await {
+                            block.map_err(|e| describe(&e))?;
+                        }
+                    }
+                    None => {}
+                }
+            }
+        }
+        Ok(ShareProof {
+            root: forest_cid,
+            blocks: recorder.blocks()?,
+            closed: false,
+        })
+    }
+
+    // A public tree holding only `public_key`, where `sharer::share` looks for the recipient's
+    // exchange keys.
+    async fn recipient_exchange_root(&self, public_key: &[u8]) -> Result<PublicLink, String> {
+        let key_cid = self
+            .store
+            .put_block(public_key.to_vec(), CODEC_RAW)
+            .await
+            .map_err(|e| {
+                trace!("wnfsError in share: {:?}", e.to_string());
+                describe(&e)
+            })?;
+        let mut exchange_root = Rc::new(PublicDirectory::new(Utc::now()));
+        exchange_root
+            .write(
+                &["main".into(), "v1.exchange_key".into()],
+                key_cid,
+                Utc::now(),
+                &self.store,
+            )
+            .await
+            .map_err(|e| {
+                trace!("wnfsError in share: {:?}", e.to_string());
+                describe(&e)
+            })?;
+        Ok(PublicLink::new(PublicNode::Dir(exchange_root)))
+    }
+
+    /// Opens the directory shared by `payload` with `recipient`, at its latest revision in the
+    /// payload's forest.
+    pub async fn accept_share(
+        store: &mut FFIFriendlyBlockStore<'a>,
+        payload: &SharePayload,
+        recipient: &ExchangeKeyPair,
+    ) -> Result<ReadOnlyView<'a>, String> {
+        if let Some(proof) = &payload.proof {
+            Self::check_share_proof(store, payload.forest_cid, proof).await?;
+        }
+        let forest =
+            PrivateDirectoryHelper::load_private_forest(store.to_owned(), payload.forest_cid)
+                .await?;
+        let name = sharer::create_share_name(
+            payload.counter,
+            &payload.sharer,
+            &recipient.public_key(),
+            &forest,
+        );
+        let shared_dir = recipient::receive_share(&name, &recipient.key, &forest, store)
+            .await
+            .map_err(|e| {
+                trace!("wnfsError in accept_share: {:?}", e.to_string());
+                describe(&e)
+            })?
+            .search_latest(&forest, store)
+            .await
+            .and_then(|node| node.as_dir())
+            .map_err(|e| {
+                trace!("wnfsError in accept_share: {:?}", e.to_string());
+                describe(&e)
+            })?;
+        // The recipient's view never reloads or commits, so it holds no WNFS key.
+        let helper = Self::from_parts(
+            store.to_owned(),
+            forest,
+            shared_dir,
+            thread_rng(),
+            Vec::new(),
+        );
+        Ok(ReadOnlyView {
+            helper,
+            root_cid: payload.forest_cid,
+        })
+    }
+}
+
+impl<'a> PrivateDirectoryHelper<'a> {
+    // Fails unless `store` holds every block of `proof`, a proof of the share in `forest_cid`.
+    async fn check_share_proof(
+        store: &FFIFriendlyBlockStore<'a>,
+        forest_cid: Cid,
+        proof: &ShareProof,
+    ) -> Result<(), String> {
+        if proof.root != forest_cid {
+            return Err(WnfsUtilsError::BlockIntegrity {
+                cid: forest_cid.to_string(),
+                reason: format!("the share's proof is of {}", proof.root),
+            }
+            .to_string());
+        }
+        let check = proof.check(store).await?;
+        if let Some(cid) = check.missing.first() {
+            trace!(
+                "wnfsError in accept_share: {} blocks missing",
+                check.missing.len()
+            );
+            return Err(
+                WnfsUtilsError::NotFound(format!("block {} of the share", cid)).to_string(),
+            );
+        }
+        match check.mismatched.first() {
+            Some(cid) => Err(WnfsUtilsError::BlockIntegrity {
+                cid: cid.to_string(),
+                reason: "it doesn't match the share's proof".to_string(),
+            }
+            .to_string()),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<'a> PrivateDirectoryHelper<'a> {
+    pub fn synced_share(
+        &mut self,
+        path_segments: &[String],
+        recipient_public_key: &[u8],
+    ) -> Result<SharePayload, String> {
+        Self::run_limited(
+            &self.operation_limiter(),
+            "share",
+            self.share(path_segments, recipient_public_key),
+        )
+    }
+
+    pub fn synced_accept_share(
+        store: &mut FFIFriendlyBlockStore<'a>,
+        payload: &SharePayload,
+        recipient: &ExchangeKeyPair,
+    ) -> Result<ReadOnlyView<'a>, String> {
+        Self::run_request(
+            "accept_share",
+            Self::accept_share(store, payload, recipient),
+        )
+    }
+}
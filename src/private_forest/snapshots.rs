@@ -0,0 +1,173 @@
+//! Named snapshots of the forest, so an app can take a checkpoint before a risky bulk operation
+//! and roll back to it without keeping root CIDs itself.
+//!
+//! The snapshots are listed in a reserved file inside the private forest, like the legal holds,
+//! so they are encrypted and travel with the forest CID. A snapshot names the root committed
+//! when it was created. `rollback` loads that root and commits it again with the current list of
+//! snapshots, so snapshots taken after the one rolled back to are kept and the roots in between
+//! stay reachable through them.
+//!
+//! A snapshot root is only referenced from the list, which a block walk can't read, so its
+//! blocks are unreachable from the latest root: pass `live_roots` to garbage collection and
+//! pinning instead of the latest root alone. Snapshots taken by a helper are checkpoints for
+//! `RevisionSquashing` while it stays loaded; a reloaded helper doesn't know them as such.
+
+use chrono::Utc;
+use libipld::Cid;
+use log::trace;
+use serde::{Deserialize, Serialize};
+
+use super::{forest_state::cid_string, PrivateDirectoryHelper, RESERVED_DIR};
+use crate::error::{describe, WnfsUtilsError};
+
+const SNAPSHOTS_FILE: &str = "snapshots.json";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub name: String,
+    #[serde(with = "cid_string")]
+    pub root_cid: Cid,
+    /// RFC 3339 time the snapshot was created.
+    pub created_at: String,
+}
+
+impl<'a> PrivateDirectoryHelper<'a> {
+    /// The snapshots, oldest first.
+    pub async fn list_snapshots(&mut self) -> Result<Vec<Snapshot>, String> {
+        let path = Self::snapshots_path();
+        if self.node_at(&path).await?.is_none() {
+            return Ok(Vec::new());
+        }
+        let content = self.read_file(&path).await?;
+        serde_json::from_slice(&content).map_err(|e| {
+            trace!("wnfsError in list_snapshots: {:?}", e.to_string());
+            describe(&e)
+        })
+    }
+
+    /// Records the current root, after committing pending mutations, as the snapshot `name`.
+    /// Names are unique; an existing snapshot has to be deleted before its name is reused.
+    pub async fn create_snapshot(&mut self, name: &str) -> Result<Cid, String> {
+        if name.is_empty() {
+            return Err("wnfsError snapshot name is empty".to_string());
+        }
+        let mut snapshots = self.list_snapshots().await?;
+        if snapshots.iter().any(|snapshot| snapshot.name == name) {
+            trace!("wnfsError in create_snapshot: {} exists", name);
+            return Err(format!("wnfsError snapshot {} already exists", name));
+        }
+        let root_cid = self.flush_commits().await?;
+        self.checkpoints.insert(root_cid);
+        snapshots.push(Snapshot {
+            name: name.to_string(),
+            root_cid,
+            created_at: Utc::now().to_rfc3339(),
+        });
+        self.store_snapshots(&snapshots).await
+    }
+
+    /// Forgets the snapshot `name`. The blocks of its root stay in the store, but are no longer
+    /// in `live_roots`, so the next garbage collection deletes those the latest root doesn't
+    /// share.
+    pub async fn delete_snapshot(&mut self, name: &str) -> Result<Cid, String> {
+        let mut snapshots = self.list_snapshots().await?;
+        let position = Self::snapshot_position(&snapshots, name)?;
+        let removed = snapshots.remove(position);
+        self.checkpoints.remove(&removed.root_cid);
+        self.store_snapshots(&snapshots).await
+    }
+
+    /// The roots whose blocks have to be kept, e.g. the live roots for `gc::collect_garbage`:
+    /// the latest root, after committing pending mutations, followed by the snapshot roots.
+    pub async fn live_roots(&mut self) -> Result<Vec<Cid>, String> {
+        let mut roots = vec![self.flush_commits().await?];
+        for snapshot in self.list_snapshots().await? {
+            if !roots.contains(&snapshot.root_cid) {
+                roots.push(snapshot.root_cid);
+            }
+        }
+        Ok(roots)
+    }
+
+    /// Replaces the tree with the one of snapshot `name`, in a new commit, and returns its root.
+    /// Mutations not committed yet are committed first, so they stay in the root history. Fails
+    /// with `WnfsUtilsError::LegalHold` if a hold placed since the snapshot was taken would be
+    /// lifted.
+    pub async fn rollback(&mut self, name: &str) -> Result<Cid, String> {
+        let snapshots = self.list_snapshots().await?;
+        let snapshot = snapshots[Self::snapshot_position(&snapshots, name)?].to_owned();
+        let current_holds = self.legal_holds().await?;
+        let mut view = self.open_at(snapshot.root_cid).await?;
+        let snapshot_holds = view.helper.legal_holds().await?;
+        if let Some(hold) = current_holds
+            .iter()
+            .find(|hold| !snapshot_holds.contains(hold))
+        {
+            trace!(
+                "wnfsError in rollback: {:?} is held since {}",
+                hold.path,
+                name
+            );
+            return Err(WnfsUtilsError::LegalHold {
+                path: hold.path.join("/"),
+            }
+            .to_string());
+        }
+        self.reload_in_place(snapshot.root_cid).await?;
+        self.store_snapshots(&snapshots).await
+    }
+
+    async fn store_snapshots(&mut self, snapshots: &[Snapshot]) -> Result<Cid, String> {
+        let content = serde_json::to_vec(snapshots).map_err(|e| describe(&e))?;
+        self.write_raw(&Self::snapshots_path(), content).await?;
+        self.commit().await
+    }
+
+    fn snapshot_position(snapshots: &[Snapshot], name: &str) -> Result<usize, String> {
+        snapshots
+            .iter()
+            .position(|snapshot| snapshot.name == name)
+            .ok_or_else(|| {
+                trace!("wnfsError no snapshot named {}", name);
+                format!("wnfsError no snapshot named {}", name)
+            })
+    }
+
+    fn snapshots_path() -> Vec<String> {
+        vec![RESERVED_DIR.to_string(), SNAPSHOTS_FILE.to_string()]
+    }
+}
+
+impl<'a> PrivateDirectoryHelper<'a> {
+    pub fn synced_list_snapshots(&mut self) -> Result<Vec<Snapshot>, String> {
+        Self::run_limited(
+            &self.operation_limiter(),
+            "list_snapshots",
+            self.list_snapshots(),
+        )
+    }
+
+    pub fn synced_create_snapshot(&mut self, name: &str) -> Result<Cid, String> {
+        Self::run_limited(
+            &self.operation_limiter(),
+            "create_snapshot",
+            self.create_snapshot(name),
+        )
+    }
+
+    pub fn synced_delete_snapshot(&mut self, name: &str) -> Result<Cid, String> {
+        Self::run_limited(
+            &self.operation_limiter(),
+            "delete_snapshot",
+            self.delete_snapshot(name),
+        )
+    }
+
+    pub fn synced_rollback(&mut self, name: &str) -> Result<Cid, String> {
+        Self::run_limited(&self.operation_limiter(), "rollback", self.rollback(name))
+    }
+
+    pub fn synced_live_roots(&mut self) -> Result<Vec<Cid>, String> {
+        Self::run_limited(&self.operation_limiter(), "live_roots", self.live_roots())
+    }
+}
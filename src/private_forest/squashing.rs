@@ -0,0 +1,230 @@
+//! Squashing runs of tiny revisions, e.g. a commit per keystroke of an autosaving editor.
+//!
+//! Every commit adds a root to `root_history`, so frequent saves bury the roots a user would
+//! roll back or browse to among hundreds of near-identical ones. With
+//! `HelperConfig::revision_squashing` set, `squash_revisions` drops the roots of runs committed
+//! less than `run_gap_ms` apart and keeps the last root of each run, which holds the changes of
+//! all of them. `history` still lists the revisions of squashed roots, without a root to open.
+//! `maintain_if_idle` does it from the app's timer once the store has been idle, and stores
+//! `root_history` with commit times and checkpoints in the forest, so a helper loading a later
+//! root goes on squashing where this one stopped.
+//!
+//! Checkpoints are never squashed: roots marked with `mark_checkpoint`, roots the app published
+//! and roots of unknown age, e.g. those restored from an account backup.
+//!
+//! Squashing keeps history navigable; it doesn't bound storage. The private forest keeps every
+//! revision of every node it was given, so the blocks of squashed roots stay reachable from the
+//! latest root, garbage collection keeps them, and `open_at` still opens them.
+
+use libipld::Cid;
+use log::trace;
+use serde::{Deserialize, Serialize};
+
+use super::{forest_state::cid_string, PrivateDirectoryHelper, RESERVED_DIR, ROOT_HISTORY_LIMIT};
+use crate::blockstore::CompactionSchedule;
+use crate::error::describe;
+
+const REVISIONS_FILE: &str = "revisions.json";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RevisionSquashing {
+    /// Roots committed less than this apart form a run, squashed into its last root.
+    pub run_gap_ms: u64,
+    /// Most roots kept in `root_history`, at most `ROOT_HISTORY_LIMIT`. The oldest are dropped
+    /// first, checkpoints included.
+    pub max_revisions: usize,
+}
+
+impl Default for RevisionSquashing {
+    fn default() -> Self {
+        Self {
+            run_gap_ms: 10_000,
+            max_revisions: ROOT_HISTORY_LIMIT,
+        }
+    }
+}
+
+// A root of `root_history` as stored by `persist_revisions`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct RevisionRecord {
+    #[serde(with = "cid_string")]
+    root_cid: Cid,
+    /// Milliseconds since the Unix epoch, `None` for roots of unknown age.
+    #[serde(default)]
+    committed_at: Option<i64>,
+    #[serde(default)]
+    checkpoint: bool,
+}
+
+/// What `maintain_if_idle` did.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MaintenanceReport {
+    /// Roots dropped from `root_history`.
+    pub squashed: usize,
+    /// Root committed with the revision state, `None` when the stored one was current.
+    pub persisted: Option<Cid>,
+    /// Bytes reclaimed by compacting the store.
+    pub compacted_bytes: u64,
+}
+
+impl<'a> PrivateDirectoryHelper<'a> {
+    /// Keeps the latest root when revisions are squashed, e.g. after the user saved explicitly.
+    pub fn mark_checkpoint(&mut self) {
+        if let Some(root) = self.root_history.last().copied() {
+            self.checkpoints.insert(root);
+        }
+    }
+
+    /// Squashes runs of revisions as configured in `HelperConfig::revision_squashing`, see the
+    /// module docs, and returns the number of roots dropped from `root_history`.
+    pub fn squash_revisions(&mut self) -> usize {
+        let policy = match self.config.revision_squashing {
+            Some(policy) => policy,
+            None => return 0,
+        };
+        let gap = policy.run_gap_ms as i64;
+        let mut kept = Vec::with_capacity(self.root_history.len());
+        let mut dropped = Vec::new();
+        for (index, root) in self.root_history.iter().enumerate() {
+            let committed_at = self.root_committed_at.get(root);
+            let next_committed_at = self
+                .root_history
+                .get(index + 1)
+                .and_then(|next| self.root_committed_at.get(next));
+            let in_run = match (committed_at, next_committed_at) {
+                (Some(at), Some(next_at)) => next_at - at < gap,
+                _ => false,
+            };
+            match in_run && !self.checkpoints.contains(root) {
+                true => dropped.push(*root),
+                false => kept.push(*root),
+            }
+        }
+        let limit = self.revision_limit();
+        if kept.len() > limit {
+            dropped.extend(kept.drain(..kept.len() - limit));
+        }
+        self.root_history = kept;
+        self.forget_roots(&dropped);
+        dropped.len()
+    }
+
+    /// Squashes revisions, persists them and compacts the store when `schedule` says the store
+    /// has been idle long enough, see the module docs. Returns `None` when it wasn't due. Meant
+    /// to be polled from the app's own timer, like `FFIFriendlyBlockStore::compact_if_idle`.
+    pub async fn maintain_if_idle(
+        &mut self,
+        schedule: &CompactionSchedule,
+    ) -> Result<Option<MaintenanceReport>, String> {
+        if !self.store.is_idle(schedule) {
+            return Ok(None);
+        }
+        let squashed = self.squash_revisions();
+        let persisted = self.persist_revisions().await?;
+        let compacted_bytes = self.store.compact().map_err(|e| {
+            trace!("wnfsError in maintain_if_idle: {:?}", e.to_string());
+            describe(&e)
+        })?;
+        Ok(Some(MaintenanceReport {
+            squashed,
+            persisted,
+            compacted_bytes,
+        }))
+    }
+
+    /// Stores `root_history` with commit times and checkpoints in the forest, which loading a
+    /// later root restores, and returns the root committed with it. A root can't list itself, so
+    /// the state lists the roots before it; `None` means it was current already.
+    pub async fn persist_revisions(&mut self) -> Result<Option<Cid>, String> {
+        self.flush_commits().await?;
+        let records: Vec<RevisionRecord> = self
+            .root_history
+            .iter()
+            .map(|root| RevisionRecord {
+                root_cid: *root,
+                committed_at: self.root_committed_at.get(root).copied(),
+                checkpoint: self.checkpoints.contains(root),
+            })
+            .collect();
+        // Unchanged when only the root committed with the stored state came since.
+        let before_latest = &records[..records.len().saturating_sub(1)];
+        if self.stored_revisions().await? == before_latest {
+            return Ok(None);
+        }
+        let content = serde_json::to_vec(&records).map_err(|e| describe(&e))?;
+        self.write_raw(&Self::revisions_path(), content).await?;
+        self.commit_now().await.map(Some)
+    }
+
+    // Restores the state `persist_revisions` stored, before the loaded root is recorded.
+    pub(super) async fn restore_revisions(&mut self) -> Result<(), String> {
+        for record in self.stored_revisions().await? {
+            self.root_history.push(record.root_cid);
+            if let Some(committed_at) = record.committed_at {
+                self.root_committed_at.insert(record.root_cid, committed_at);
+            }
+            if record.checkpoint {
+                self.checkpoints.insert(record.root_cid);
+            }
+        }
+        Ok(())
+    }
+
+    async fn stored_revisions(&mut self) -> Result<Vec<RevisionRecord>, String> {
+        let path = Self::revisions_path();
+        if self.node_at(&path).await?.is_none() {
+            return Ok(Vec::new());
+        }
+        let content = self.read_file(&path).await?;
+        serde_json::from_slice(&content).map_err(|e| {
+            trace!("wnfsError in stored_revisions: {:?}", e.to_string());
+            describe(&e)
+        })
+    }
+
+    fn revisions_path() -> Vec<String> {
+        vec![RESERVED_DIR.to_string(), REVISIONS_FILE.to_string()]
+    }
+
+    // Most roots `root_history` keeps.
+    pub(super) fn revision_limit(&self) -> usize {
+        self.config
+            .revision_squashing
+            .map_or(ROOT_HISTORY_LIMIT, |policy| {
+                policy.max_revisions.clamp(1, ROOT_HISTORY_LIMIT)
+            })
+    }
+
+    // Drops what is tracked about `roots` after they left `root_history`.
+    pub(super) fn forget_roots(&mut self, roots: &[Cid]) {
+        for root in roots {
+            if !self.root_history.contains(root) {
+                self.root_committed_at.remove(root);
+                self.checkpoints.remove(root);
+            }
+        }
+        // A remote root loaded earlier stays resolved when it is squashed away.
+        self.resolve_remote_root(roots);
+    }
+}
+
+impl<'a> PrivateDirectoryHelper<'a> {
+    pub fn synced_maintain_if_idle(
+        &mut self,
+        schedule: &CompactionSchedule,
+    ) -> Result<Option<MaintenanceReport>, String> {
+        Self::run_limited(
+            &self.operation_limiter(),
+            "maintain_if_idle",
+            self.maintain_if_idle(schedule),
+        )
+    }
+
+    pub fn synced_persist_revisions(&mut self) -> Result<Option<Cid>, String> {
+        Self::run_limited(
+            &self.operation_limiter(),
+            "persist_revisions",
+            self.persist_revisions(),
+        )
+    }
+}
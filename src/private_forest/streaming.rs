@@ -0,0 +1,132 @@
+//! Streaming file content in and out of the forest, for media too large to hold in memory.
+//!
+//! Writes encrypt content block by block as it is read from the source, and reads yield the
+//! content in chunks of `STREAM_CHUNK_BYTES`, so memory use is bounded by a few blocks whatever
+//! the size of the file. Reads see the file as it was when the stream started, like a
+//! [`FileHandle`](super::FileHandle).
+
+use std::io;
+
+use futures::{
+    stream::{self, LocalBoxStream, Stream, StreamExt, TryStreamExt},
+    AsyncRead,
+};
+use libipld::Cid;
+
+use super::{FileHandle, PrivateDirectoryHelper};
+
+/// Bytes yielded per chunk by `read_file_stream`, the size of a WNFS content block.
+pub const STREAM_CHUNK_BYTES: usize = 256 * 1024;
+
+impl<'a> PrivateDirectoryHelper<'a> {
+    /// Writes the content read from `reader` to `path_segments` without buffering it whole.
+    /// Media metadata isn't extracted, as that needs to seek in the content.
+    pub async fn write_file_from_reader<R: AsyncRead + Unpin>(
+        &mut self,
+        path_segments: &[String],
+        reader: R,
+        modification_time_seconds: i64,
+    ) -> Result<Cid, String> {
+        self.write_file_stream_with_metadata(
+            path_segments,
+            reader,
+            modification_time_seconds,
+            Vec::new(),
+        )
+        .await
+    }
+
+    /// Like `write_file_from_reader`, for content that arrives in chunks, e.g. from a binding
+    /// or a network download. The first error of `chunks` aborts the write.
+    pub async fn write_file_chunks<S>(
+        &mut self,
+        path_segments: &[String],
+        chunks: S,
+        modification_time_seconds: i64,
+    ) -> Result<Cid, String>
+    where
+        S: Stream<Item = Result<Vec<u8>, String>> + Unpin,
+    {
+        let reader = chunks
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+            .into_async_read();
+        self.write_file_from_reader(path_segments, reader, modification_time_seconds)
+            .await
+    }
+
+    /// Content of the file at `path_segments` in chunks of up to `STREAM_CHUNK_BYTES`. The
+    /// stream ends after the first error. Paged files are read with `read_at`.
+    pub fn read_file_stream<'h>(
+        &'h mut self,
+        path_segments: &[String],
+    ) -> LocalBoxStream<'h, Result<Vec<u8>, String>> {
+        let path = path_segments.to_vec();
+        let start = async move {
+            self.check_path_depth(&path)?;
+            let handle = self.open_file(&path).await?;
+            Ok::<_, String>((self, handle))
+        };
+        stream::once(start)
+            .flat_map(|state| match state {
+                Ok(state) => stream::unfold(Some(state), Self::read_stream_next).boxed_local(),
+                Err(e) => stream::iter([Err(e)]).boxed_local(),
+            })
+            .boxed_local()
+    }
+
+    async fn read_stream_next<'h>(
+        state: Option<(&'h mut Self, FileHandle)>,
+    ) -> Option<(Result<Vec<u8>, String>, Option<(&'h mut Self, FileHandle)>)> {
+        let (helper, mut handle) = state?;
+        match handle.read(helper, STREAM_CHUNK_BYTES).await {
+            Ok(chunk) if chunk.is_empty() => None,
+            Ok(chunk) => Some((Ok(chunk), Some((helper, handle)))),
+            Err(e) => Some((Err(e), None)),
+        }
+    }
+}
+
+impl<'a> PrivateDirectoryHelper<'a> {
+    /// Writes the chunks returned by `next_chunk` until it returns `None`.
+    pub fn synced_write_file_chunks<F>(
+        &mut self,
+        path_segments: &[String],
+        mut next_chunk: F,
+        modification_time_seconds: i64,
+    ) -> Result<Cid, String>
+    where
+        F: FnMut() -> Result<Option<Vec<u8>>, String>,
+    {
+        let chunks = stream::iter(std::iter::from_fn(move || next_chunk().transpose()));
+        Self::run_limited(
+            &self.operation_limiter(),
+            "write_file_chunks",
+            self.write_file_chunks(
+                path_segments,
+                chunks.boxed_local(),
+                modification_time_seconds,
+            ),
+        )
+    }
+
+    /// Passes the content of the file to `on_chunk` chunk by chunk, returning its length.
+    pub fn synced_read_file_chunks<F>(
+        &mut self,
+        path_segments: &[String],
+        mut on_chunk: F,
+    ) -> Result<u64, String>
+    where
+        F: FnMut(Vec<u8>) -> Result<(), String>,
+    {
+        Self::run_limited(&self.operation_limiter(), "read_file_chunks", async {
+            let mut chunks = self.read_file_stream(path_segments);
+            let mut len = 0;
+            while let Some(chunk) = chunks.next().await {
+                let chunk = chunk?;
+                len += chunk.len() as u64;
+                on_chunk(chunk)?;
+            }
+            Ok(len)
+        })
+    }
+}
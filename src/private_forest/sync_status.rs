@@ -0,0 +1,106 @@
+//! One call answering "are all changes saved?", for the sync indicator of an app's UI.
+//!
+//! [`SyncStatus`] gathers what the helper and its store know about changes that haven't reached
+//! other devices yet: mutations waiting for their batched commit, commits the app hasn't
+//! published, blocks the store accepted but hasn't uploaded, and roots other devices published.
+//! The helper only sees those roots when it reads the pointer, i.e. in `RemoteWatcher` and
+//! `commit_with_retry`; apps reading their pointer themselves report it with
+//! `record_remote_root`.
+
+use chrono::{DateTime, Utc};
+use libipld::Cid;
+
+use super::PrivateDirectoryHelper;
+use crate::blockstore::PendingWriteback;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyncStatus {
+    /// Mutations waiting for their batched commit, see `flush_commits`.
+    pub pending_commit: bool,
+    pub last_commit: Option<DateTime<Utc>>,
+    /// Last `mark_published`.
+    pub last_published: Option<DateTime<Utc>>,
+    /// Whether a commit was made since the last `mark_published`.
+    pub unpublished: bool,
+    /// Blocks the store accepted and hasn't uploaded yet.
+    pub pending_writeback: PendingWriteback,
+    /// Latest root read from the pointer, and when it was read.
+    pub last_remote_root: Option<Cid>,
+    pub last_remote_root_seen: Option<DateTime<Utc>>,
+    /// Whether `last_remote_root` was published by another device and hasn't been loaded or
+    /// merged by this helper.
+    pub remote_changes: bool,
+    /// Remote changes while this helper has unpublished commits of its own: publishing would
+    /// overwrite them, so they need `merge_forests` or `commit_with_retry` first.
+    pub unresolved_conflict: bool,
+}
+
+impl SyncStatus {
+    /// "All changes saved": nothing is waiting to be committed, published, uploaded or merged.
+    pub fn is_synced(&self) -> bool {
+        self.items_pending() == 0 && !self.remote_changes
+    }
+
+    /// Things still to be synced, for "Syncing 3 items": blocks waiting for upload, plus one
+    /// each for a pending commit and an unpublished root.
+    pub fn items_pending(&self) -> usize {
+        self.pending_writeback.blocks + self.pending_commit as usize + self.unpublished as usize
+    }
+}
+
+// The latest root read from the pointer.
+#[derive(Debug, Clone, Copy)]
+pub(super) struct RemoteRootSeen {
+    root: Cid,
+    seen_at: DateTime<Utc>,
+    // Whether it was an input of `merge_forests` or squashed away after being loaded.
+    resolved: bool,
+}
+
+impl<'a> PrivateDirectoryHelper<'a> {
+    pub fn sync_status(&self) -> SyncStatus {
+        let metrics = self.forest_metrics;
+        let unpublished = match (metrics.last_commit, metrics.last_published) {
+            (Some(commit), Some(published)) => commit > published,
+            (Some(_), None) => true,
+            (None, _) => false,
+        };
+        let remote_changes = self.remote_root.map_or(false, |remote| {
+            !remote.resolved && !self.root_history().contains(&remote.root)
+        });
+        SyncStatus {
+            pending_commit: self.has_pending_commit(),
+            last_commit: metrics.last_commit,
+            last_published: metrics.last_published,
+            unpublished,
+            pending_writeback: self.store.pending_writeback(),
+            last_remote_root: self.remote_root.map(|remote| remote.root),
+            last_remote_root_seen: self.remote_root.map(|remote| remote.seen_at),
+            remote_changes,
+            unresolved_conflict: remote_changes && (unpublished || self.has_pending_commit()),
+        }
+    }
+
+    /// Records `root` as the one currently published, read from the app's pointer.
+    pub fn record_remote_root(&mut self, root: Cid) {
+        let resolved = self
+            .remote_root
+            .filter(|remote| remote.root == root)
+            .map(|remote| remote.resolved)
+            .unwrap_or(false);
+        self.remote_root = Some(RemoteRootSeen {
+            root,
+            seen_at: Utc::now(),
+            resolved,
+        });
+    }
+
+    // Marks the remote root resolved if it is one of `roots`.
+    pub(super) fn resolve_remote_root(&mut self, roots: &[Cid]) {
+        if let Some(remote) = self.remote_root.as_mut() {
+            if roots.contains(&remote.root) {
+                remote.resolved = true;
+            }
+        }
+    }
+}
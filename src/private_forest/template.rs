@@ -0,0 +1,118 @@
+//! First-run setup from a template: a new forest with a directory skeleton and default settings
+//! documents, committed as its first root instead of one commit per directory and file.
+//!
+//! Templates are plain data, so an app can ship its layout as JSON next to its other defaults.
+
+use std::rc::Rc;
+
+use log::trace;
+use serde::{Deserialize, Serialize};
+use wnfs::private::AccessKey;
+
+use super::{ContentScanner, ExclusiveSession, HelperConfig, PrivateDirectoryHelper, ReadOnlyView};
+use crate::blockstore::FFIFriendlyBlockStore;
+use crate::error::describe;
+
+/// A file written by a template, e.g. `settings/app.json`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TemplateDocument {
+    pub path: Vec<String>,
+    pub content: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ForestTemplate {
+    /// Directories to create, parents included.
+    #[serde(default)]
+    pub directories: Vec<Vec<String>>,
+    /// Written after the directories, creating any missing parent.
+    #[serde(default)]
+    pub documents: Vec<TemplateDocument>,
+    /// Config of the new helper, in effect while the template is applied, e.g. for sharding.
+    #[serde(default)]
+    pub config: HelperConfig,
+}
+
+impl ForestTemplate {
+    pub fn to_json(&self) -> Result<String, String> {
+        serde_json::to_string(self).map_err(|e| describe(&e))
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, String> {
+        serde_json::from_str(json).map_err(|e| {
+            trace!(
+                "wnfsError in ForestTemplate::from_json: {:?}",
+                e.to_string()
+            );
+            describe(&e)
+        })
+    }
+}
+
+impl<'a> PrivateDirectoryHelper<'a> {
+    /// Like `init_exclusive`, provisioning the forest from `template`. The whole template lands
+    /// in a single commit, which is the first root of the helper's history; the returned view is
+    /// a snapshot of it, e.g. to restore the defaults later.
+    pub async fn init_from_template(
+        store: &mut FFIFriendlyBlockStore<'a>,
+        wnfs_key: Vec<u8>,
+        template: &ForestTemplate,
+    ) -> Result<(ExclusiveSession<'a>, AccessKey, ReadOnlyView<'a>), String> {
+        Self::init_from_template_with_scanners(store, wnfs_key, template, Vec::new()).await
+    }
+
+    /// Like `init_from_template`, with `scanners` added to the new helper before the template's
+    /// documents are written, so they're scanned like any later write.
+    pub async fn init_from_template_with_scanners(
+        store: &mut FFIFriendlyBlockStore<'a>,
+        wnfs_key: Vec<u8>,
+        template: &ForestTemplate,
+        scanners: Vec<Rc<dyn ContentScanner>>,
+    ) -> Result<(ExclusiveSession<'a>, AccessKey, ReadOnlyView<'a>), String> {
+        let (mut helper, access_key, _) = Self::init_exclusive(store, wnfs_key).await?;
+        for scanner in scanners {
+            helper.add_content_scanner(scanner);
+        }
+        helper.set_config(template.config.to_owned());
+        // Through `mkdir` and `write_file`, so the template gets the checks, normalization and
+        // scans of any later write; the transaction keeps it to a single commit.
+        let mut tx = helper.begin();
+        for directory in &template.directories {
+            tx.mkdir(directory).await?;
+        }
+        for document in &template.documents {
+            tx.write_file(&document.path, document.content.to_owned(), 0)
+                .await?;
+        }
+        // The empty root committed by `init` is never handed out, so history starts here.
+        tx.root_history.clear();
+        tx.commit().await?;
+        let snapshot = helper.snapshot().await?;
+        Ok((helper, access_key, snapshot))
+    }
+}
+
+impl<'a> PrivateDirectoryHelper<'a> {
+    pub fn synced_init_from_template(
+        store: &mut FFIFriendlyBlockStore<'a>,
+        wnfs_key: Vec<u8>,
+        template: &ForestTemplate,
+    ) -> Result<(ExclusiveSession<'a>, AccessKey, ReadOnlyView<'a>), String> {
+        Self::run_request(
+            "init_from_template",
+            Self::init_from_template(store, wnfs_key, template),
+        )
+    }
+
+    pub fn synced_init_from_template_with_scanners(
+        store: &mut FFIFriendlyBlockStore<'a>,
+        wnfs_key: Vec<u8>,
+        template: &ForestTemplate,
+        scanners: Vec<Rc<dyn ContentScanner>>,
+    ) -> Result<(ExclusiveSession<'a>, AccessKey, ReadOnlyView<'a>), String> {
+        Self::run_request(
+            "init_from_template",
+            Self::init_from_template_with_scanners(store, wnfs_key, template, scanners),
+        )
+    }
+}
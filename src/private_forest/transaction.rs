@@ -0,0 +1,140 @@
+//! Explicit transactions: many mutations, one new root.
+//!
+//! `begin` returns a [`Transaction`] that derefs to the helper, so every operation is available
+//! on it. Mutations made through it only change the in-memory tree and return the root the
+//! transaction started from; `Transaction::commit` stores the forest once and returns the new
+//! root. A transaction dropped without committing, or rolled back, restores the tree it started
+//! from. Restoring is cheap because the tree is copy-on-write.
+//!
+//! A transaction begun inside another one acts as a savepoint: its commit keeps its changes
+//! for the enclosing transaction to commit, and its rollback only undoes its own changes.
+
+use std::{
+    ops::{Deref, DerefMut},
+    rc::Rc,
+};
+
+use libipld::Cid;
+use wnfs::private::{forest::hamt::HamtForest, PrivateDirectory};
+
+use super::PrivateDirectoryHelper;
+
+pub struct Transaction<'h, 'a> {
+    helper: &'h mut PrivateDirectoryHelper<'a>,
+    forest: Rc<HamtForest>,
+    root_dir: Rc<PrivateDirectory>,
+    mutations: u64,
+    outermost: bool,
+    finished: bool,
+}
+
+impl<'a> PrivateDirectoryHelper<'a> {
+    /// Opens a transaction, see [`Transaction`].
+    pub fn begin(&mut self) -> Transaction<'_, 'a> {
+        let outermost = !self.in_transaction;
+        self.in_transaction = true;
+        Transaction {
+            forest: Rc::clone(&self.forest),
+            root_dir: Rc::clone(&self.root_dir),
+            mutations: self.mutations,
+            outermost,
+            finished: false,
+            helper: self,
+        }
+    }
+
+    /// Whether mutations currently go into an open transaction.
+    pub fn in_transaction(&self) -> bool {
+        self.in_transaction
+    }
+}
+
+impl<'h, 'a> Transaction<'h, 'a> {
+    /// Stores the forest with all mutations of the transaction and returns the new root. When
+    /// nothing was changed, or within an enclosing transaction, it returns the current root.
+    pub async fn commit(mut self) -> Result<Cid, String> {
+        self.finished = true;
+        if !self.outermost {
+            return self.latest_root();
+        }
+        self.helper.in_transaction = false;
+        if self.helper.mutations == self.mutations {
+            if let Ok(root) = self.latest_root() {
+                return Ok(root);
+            }
+        }
+        self.helper.commit_now().await
+    }
+
+    // Ends the transaction keeping its changes in memory, for operations whose caller commits.
+    pub(super) fn release(mut self) {
+        self.finished = true;
+        if self.outermost {
+            self.helper.in_transaction = false;
+        }
+    }
+
+    /// Undoes the mutations of the transaction.
+    pub fn rollback(mut self) {
+        self.restore();
+    }
+
+    fn latest_root(&self) -> Result<Cid, String> {
+        self.helper
+            .root_history
+            .last()
+            .copied()
+            .ok_or_else(|| "wnfsError no root recorded".to_string())
+    }
+
+    fn restore(&mut self) {
+        if self.finished {
+            return;
+        }
+        self.finished = true;
+        if self.outermost {
+            self.helper.in_transaction = false;
+        }
+        // An operation that failed halfway changed the tree without counting a mutation.
+        let changed = self.helper.mutations != self.mutations
+            || !Rc::ptr_eq(&self.helper.forest, &self.forest)
+            || !Rc::ptr_eq(&self.helper.root_dir, &self.root_dir);
+        if changed {
+            self.helper.forest = Rc::clone(&self.forest);
+            self.helper.root_dir = Rc::clone(&self.root_dir);
+            // Cached holds and open directory handles may have seen the undone changes.
+            self.helper.legal_holds = None;
+            self.helper.mutations += 1;
+        }
+    }
+}
+
+impl<'h, 'a> Deref for Transaction<'h, 'a> {
+    type Target = PrivateDirectoryHelper<'a>;
+
+    fn deref(&self) -> &Self::Target {
+        self.helper
+    }
+}
+
+impl<'h, 'a> DerefMut for Transaction<'h, 'a> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.helper
+    }
+}
+
+impl<'h, 'a> Drop for Transaction<'h, 'a> {
+    fn drop(&mut self) {
+        self.restore();
+    }
+}
+
+impl<'h, 'a> Transaction<'h, 'a> {
+    pub fn synced_commit(self) -> Result<Cid, String> {
+        PrivateDirectoryHelper::run_limited(
+            &self.helper.operation_limiter(),
+            "transaction_commit",
+            self.commit(),
+        )
+    }
+}
@@ -0,0 +1,146 @@
+//! Copies between two private forests held by different helpers, and so under different keys.
+//! Content is decrypted from the source and re-encrypted into the destination one chunk at a
+//! time, so memory use doesn't grow with file size. Paged files are copied as regular files of
+//! their logical content, and the helper's reserved entries aren't copied.
+
+use chrono::Utc;
+use futures::{stream, StreamExt, TryStreamExt};
+use libipld::Cid;
+use log::trace;
+
+use super::PrivateDirectoryHelper;
+use crate::error::describe;
+
+impl<'a> PrivateDirectoryHelper<'a> {
+    /// Copies the file or directory at `src_path` of `src` to `dst_path` of `dst`. The new
+    /// content is committed to `dst` in a single transaction; `src` is left untouched.
+    pub async fn copy_between<'b>(
+        src: &mut PrivateDirectoryHelper<'a>,
+        src_path: &[String],
+        dst: &mut PrivateDirectoryHelper<'b>,
+        dst_path: &[String],
+    ) -> Result<Cid, String> {
+        let (dirs, files) = src.collect_subtree(src_path).await?;
+        let prefix_len = src_path.len();
+        let target_of = |path: &[String]| {
+            let mut target = dst_path.to_vec();
+            target.extend(path[prefix_len..].iter().cloned());
+            target
+        };
+
+        for dir in dirs.iter() {
+            let target = target_of(dir);
+            if target.is_empty() {
+                continue;
+            }
+            dst.check_not_held(&target, false).await?;
+            let target = dst.resolve_path(&target).await?;
+            let forest = &mut dst.forest;
+            let root_dir = &mut dst.root_dir;
+            root_dir
+                .mkdir(
+                    &target,
+                    true,
+                    Utc::now(),
+                    forest,
+                    &mut dst.store,
+                    &mut dst.rng,
+                )
+                .await
+                .map_err(|e| {
+                    trace!("wnfsError in copy_between on mkdir: {:?}", e.to_string());
+                    describe(&e)
+                })?;
+        }
+        for file in files.iter() {
+            src.copy_file_into(file, dst, &target_of(file)).await?;
+        }
+        dst.commit().await
+    }
+
+    // Streams the file at `path_segments` into `target` of `dst` without committing `dst`.
+    pub(super) async fn copy_file_into<'b>(
+        &mut self,
+        path_segments: &[String],
+        dst: &mut PrivateDirectoryHelper<'b>,
+        target: &[String],
+    ) -> Result<(), String> {
+        dst.check_not_held(target, false).await?;
+        let resolved = dst.resolve_path(target).await?;
+        if self.is_paged_file(path_segments).await? {
+            return self
+                .copy_paged_file_into(path_segments, dst, target, &resolved)
+                .await;
+        }
+        let node = self
+            .node_at(path_segments)
+            .await?
+            .ok_or_else(|| format!("wnfsError no file found at {}", path_segments.join("/")))?;
+        let file = node.as_file().map_err(|e| describe(&e))?;
+        let modified = file.get_metadata().get_modified().unwrap_or_else(Utc::now);
+
+        let stream = file
+            .stream_content(0, &self.forest, &self.store)
+            .map(|block| {
+                block.map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))
+            });
+        let reader = Box::pin(stream).into_async_read();
+
+        let (_, entries) = dst
+            .set_content_scanned(target, &resolved, reader, modified)
+            .await?;
+        if !entries.is_empty() {
+            dst.put_file_metadata(target, entries, modified).await?;
+        }
+        Ok(())
+    }
+
+    // `copy_file_into` for a paged file, which arrives in `dst` as a regular file of its
+    // logical content, read a chunk at a time.
+    async fn copy_paged_file_into<'b>(
+        &mut self,
+        path_segments: &[String],
+        dst: &mut PrivateDirectoryHelper<'b>,
+        target: &[String],
+        resolved: &[String],
+    ) -> Result<(), String> {
+        let modified = match self.node_at(path_segments).await? {
+            Some(node) => node
+                .as_dir()
+                .map_err(|e| describe(&e))?
+                .get_metadata()
+                .get_modified(),
+            None => None,
+        }
+        .unwrap_or_else(Utc::now);
+        let options = self.paged_file_options(path_segments).await?;
+        let len = self.paged_file_len(path_segments).await?;
+        let chunk_size = options.page_size as usize * options.pages_per_chunk as usize;
+
+        let path = path_segments.to_vec();
+        let stream = stream::unfold((self, 0u64), move |(helper, offset)| {
+            let path = path.to_owned();
+            async move {
+                if offset >= len {
+                    return None;
+                }
+                let chunk = helper.read_at(&path, offset, chunk_size).await;
+                // An error ends the stream after it is reported.
+                let next = chunk
+                    .as_ref()
+                    .map_or(len, |chunk| offset + chunk.len() as u64);
+                let chunk = chunk.map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e));
+                Some((chunk, (helper, next)))
+            }
+        });
+        let reader = Box::pin(stream).into_async_read();
+
+        let (_, entries) = dst
+            .set_content_scanned(target, resolved, reader, modified)
+            .await?;
+        if !entries.is_empty() {
+            dst.put_file_metadata(target, entries, modified).await?;
+        }
+        Ok(())
+    }
+}
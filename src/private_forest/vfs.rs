@@ -0,0 +1,220 @@
+//! `Vfs` implementation of the helper.
+//!
+//! `read` decrypts only the blocks covering the requested range. WNFS replaces file content as a
+//! whole though, so `write` and `set_len` decrypt the entire file, then re-encrypt and commit it,
+//! and fail past `VFS_MAX_FILE_SIZE`. Frontends should buffer small writes and flush them in as
+//! few calls as possible. Paged files (see `PagedFileOptions`) show up as plain files, are read
+//! and written chunk by chunk instead and have no such bound.
+
+use async_trait::async_trait;
+use log::trace;
+use wnfs::private::PrivateNode;
+
+use super::{PagedFileOptions, PrivateDirectoryHelper};
+use crate::error::{describe, WnfsUtilsError};
+use crate::vfs::{OpenOptions, Vfs, VfsHandle, VfsNodeKind, VfsStat};
+
+/// Largest file `Vfs::write` and `Vfs::set_len` produce outside paged files, which are held in
+/// memory as a whole while they are rewritten.
+pub const VFS_MAX_FILE_SIZE: u64 = 256 * 1024 * 1024;
+
+impl<'a> PrivateDirectoryHelper<'a> {
+    pub(super) fn vfs_stat_of(node: &PrivateNode) -> VfsStat {
+        match node {
+            PrivateNode::Dir(dir) => VfsStat {
+                kind: VfsNodeKind::Directory,
+                size: 0,
+                modified: dir
+                    .get_metadata()
+                    .get_modified()
+                    .map(|time| time.timestamp()),
+            },
+            PrivateNode::File(file) => VfsStat {
+                kind: VfsNodeKind::File,
+                size: file.get_content_size_upper_bound() as u64,
+                modified: file
+                    .get_metadata()
+                    .get_modified()
+                    .map(|time| time.timestamp()),
+            },
+        }
+    }
+
+    async fn vfs_paged_stat(&mut self, path: &[String]) -> Result<Option<VfsStat>, String> {
+        if !self.is_paged_file(path).await? {
+            return Ok(None);
+        }
+        let modified = self
+            .node_at(path)
+            .await?
+            .and_then(|node| Self::vfs_stat_of(&node).modified);
+        Ok(Some(VfsStat {
+            kind: VfsNodeKind::File,
+            size: self.paged_file_len(path).await?,
+            modified,
+        }))
+    }
+
+    async fn vfs_file_content(&mut self, path: &[String]) -> Result<Option<Vec<u8>>, String> {
+        match self.node_at(path).await? {
+            Some(node) if node.is_file() => Ok(Some(self.read_file(path).await?)),
+            Some(_) => Err(format!("wnfsError {} is a directory", path.join("/"))),
+            None => Ok(None),
+        }
+    }
+
+    // The length of a file rewritten by the Vfs to end at `end`, if it is within
+    // `VFS_MAX_FILE_SIZE`. `None` is an end past `u64::MAX`.
+    fn vfs_file_len(path: &[String], end: Option<u64>) -> Result<usize, String> {
+        let size = end.unwrap_or(u64::MAX);
+        if size > VFS_MAX_FILE_SIZE {
+            trace!(
+                "wnfsError in vfs: {} would grow to {} bytes",
+                path.join("/"),
+                size
+            );
+            return Err(WnfsUtilsError::FileTooLarge {
+                size,
+                limit: VFS_MAX_FILE_SIZE,
+            }
+            .to_string());
+        }
+        usize::try_from(size).map_err(|e| describe(&e))
+    }
+}
+
+#[async_trait(?Send)]
+impl<'a> Vfs for PrivateDirectoryHelper<'a> {
+    async fn stat(&mut self, path: &[String]) -> Result<Option<VfsStat>, String> {
+        if let Some(stat) = self.vfs_paged_stat(path).await? {
+            return Ok(Some(stat));
+        }
+        Ok(self
+            .node_at(path)
+            .await?
+            .map(|node| Self::vfs_stat_of(&node)))
+    }
+
+    async fn readdir(&mut self, path: &[String]) -> Result<Vec<(String, VfsStat)>, String> {
+        let mut entries = Vec::new();
+        for (name, _) in self.ls_files(path).await? {
+            let mut child = path.to_vec();
+            child.push(name.to_owned());
+            if let Some(stat) = Vfs::stat(self, &child).await? {
+                entries.push((name, stat));
+            }
+        }
+        Ok(entries)
+    }
+
+    async fn open(&mut self, path: &[String], options: OpenOptions) -> Result<VfsHandle, String> {
+        if (options.create || options.truncate) && !options.write {
+            return Err("wnfsError create and truncate require write access".to_string());
+        }
+        if options.paged && !options.create {
+            return Err("wnfsError paged requires create".to_string());
+        }
+        if self.is_paged_file(path).await? {
+            if options.truncate {
+                self.set_paged_len(path, 0).await?;
+            }
+            return Ok(VfsHandle {
+                path: path.to_vec(),
+                writable: options.write,
+            });
+        }
+        let exists = self.vfs_file_content(path).await?.is_some();
+        if !exists && options.paged {
+            self.create_paged_file(path, PagedFileOptions::default())
+                .await?;
+            return Ok(VfsHandle {
+                path: path.to_vec(),
+                writable: options.write,
+            });
+        }
+        if !exists && !options.create {
+            return Err(WnfsUtilsError::NotFound(format!("file {}", path.join("/"))).to_string());
+        }
+        if !exists || options.truncate {
+            self.write_file(path, Vec::new(), 0).await?;
+        }
+        Ok(VfsHandle {
+            path: path.to_vec(),
+            writable: options.write,
+        })
+    }
+
+    async fn read(
+        &mut self,
+        handle: &VfsHandle,
+        offset: u64,
+        len: usize,
+    ) -> Result<Vec<u8>, String> {
+        self.read_file_at(&handle.path, offset, len).await
+    }
+
+    async fn write(
+        &mut self,
+        handle: &VfsHandle,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<usize, String> {
+        if !handle.writable {
+            return Err(format!(
+                "wnfsError {} is not open for writing",
+                handle.path.join("/")
+            ));
+        }
+        if self.is_paged_file(&handle.path).await? {
+            self.write_at(&handle.path, offset, data).await?;
+            return Ok(data.len());
+        }
+        let end = Self::vfs_file_len(&handle.path, offset.checked_add(data.len() as u64))?;
+        let start = end - data.len();
+        let mut content = self
+            .vfs_file_content(&handle.path)
+            .await?
+            .unwrap_or_default();
+        if content.len() < end {
+            content.resize(end, 0);
+        }
+        content[start..end].copy_from_slice(data);
+        self.write_file(&handle.path, content, 0).await?;
+        Ok(data.len())
+    }
+
+    async fn set_len(&mut self, handle: &VfsHandle, len: u64) -> Result<(), String> {
+        if !handle.writable {
+            return Err(format!(
+                "wnfsError {} is not open for writing",
+                handle.path.join("/")
+            ));
+        }
+        if self.is_paged_file(&handle.path).await? {
+            return self.set_paged_len(&handle.path, len).await.map(|_| ());
+        }
+        let len = Self::vfs_file_len(&handle.path, Some(len))?;
+        let mut content = self
+            .vfs_file_content(&handle.path)
+            .await?
+            .ok_or_else(|| format!("wnfsError no file found at {}", handle.path.join("/")))?;
+        content.resize(len, 0);
+        self.write_file(&handle.path, content, 0).await.map(|_| ())
+    }
+
+    async fn rename(&mut self, from: &[String], to: &[String]) -> Result<(), String> {
+        self.mv(from, to).await.map(|_| ())
+    }
+
+    async fn mkdir(&mut self, path: &[String]) -> Result<(), String> {
+        PrivateDirectoryHelper::mkdir(self, path).await.map(|_| ())
+    }
+
+    async fn remove(&mut self, path: &[String]) -> Result<(), String> {
+        self.rm(path).await.map(|_| ())
+    }
+
+    async fn sync(&mut self) -> Result<(), String> {
+        self.flush_commits().await.map(|_| ())
+    }
+}
@@ -0,0 +1,170 @@
+//! Lazy recursive traversal, the shared building block for features that visit a whole subtree
+//! such as search, export, sync or disk usage.
+//!
+//! `walk` returns a stream that visits the tree depth first, parents before children and
+//! siblings by name, decrypting a directory only when the stream reaches it. Dropping the
+//! stream stops the walk. Sharded directories are listed as one directory and paged files are
+//! reported as files, like the other path APIs do.
+
+use futures::stream::{self, LocalBoxStream, StreamExt};
+use log::trace;
+
+use super::{PrivateDirectoryHelper, PAGED_MARKER};
+use crate::vfs::{VfsNodeKind, VfsStat};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WalkOptions {
+    /// Deepest level visited, the starting directory being 0. `None` walks the whole subtree.
+    pub max_depth: Option<usize>,
+    /// Whether files are yielded.
+    pub files: bool,
+    /// Whether directories are yielded. They are visited either way.
+    pub directories: bool,
+    /// Logical paths whose subtrees are skipped.
+    pub exclude: Vec<Vec<String>>,
+    /// Whether the helper's own bookkeeping entries, such as `RESERVED_DIR`, are visited.
+    pub include_reserved: bool,
+}
+
+impl Default for WalkOptions {
+    fn default() -> Self {
+        Self {
+            max_depth: None,
+            files: true,
+            directories: true,
+            exclude: Vec::new(),
+            include_reserved: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WalkEntry {
+    /// Logical path of the entry.
+    pub path: Vec<String>,
+    /// Levels below the starting directory.
+    pub depth: usize,
+    pub stat: VfsStat,
+}
+
+// An entry found but not visited yet.
+struct Pending {
+    path: Vec<String>,
+    resolved: Vec<String>,
+    depth: usize,
+}
+
+struct WalkState<'h, 'a> {
+    helper: &'h mut PrivateDirectoryHelper<'a>,
+    options: WalkOptions,
+    stack: Vec<Pending>,
+}
+
+impl<'a> PrivateDirectoryHelper<'a> {
+    /// Walks the subtree at `path_segments`, yielding the starting entry first. The stream ends
+    /// after the first error.
+    pub fn walk<'h>(
+        &'h mut self,
+        path_segments: &[String],
+        options: WalkOptions,
+    ) -> LocalBoxStream<'h, Result<WalkEntry, String>> {
+        let path = path_segments.to_vec();
+        let start = async move {
+            self.check_path_depth(&path)?;
+            let resolved = self.resolve_path(&path).await?;
+            Ok::<_, String>(WalkState {
+                helper: self,
+                options,
+                stack: vec![Pending {
+                    path,
+                    resolved,
+                    depth: 0,
+                }],
+            })
+        };
+        stream::once(start)
+            .flat_map(|state| match state {
+                Ok(state) => stream::unfold(Some(state), Self::walk_next).boxed_local(),
+                Err(e) => stream::iter([Err(e)]).boxed_local(),
+            })
+            .boxed_local()
+    }
+
+    async fn walk_next<'h>(
+        state: Option<WalkState<'h, 'a>>,
+    ) -> Option<(Result<WalkEntry, String>, Option<WalkState<'h, 'a>>)> {
+        let mut state = state?;
+        loop {
+            let pending = state.stack.pop()?;
+            match Self::visit(&mut state, pending).await {
+                Ok(Some(entry)) => return Some((Ok(entry), Some(state))),
+                Ok(None) => continue,
+                Err(e) => {
+                    trace!("wnfsError in walk: {:?}", e);
+                    return Some((Err(e), None));
+                }
+            }
+        }
+    }
+
+    // Visits one entry, queueing its children, and returns it unless the options filter it out.
+    async fn visit(
+        state: &mut WalkState<'_, 'a>,
+        pending: Pending,
+    ) -> Result<Option<WalkEntry>, String> {
+        let helper = &mut *state.helper;
+        let node = helper
+            .raw_node_at(&pending.resolved)
+            .await?
+            .ok_or_else(|| format!("wnfsError no node found at {}", pending.path.join("/")))?;
+        let mut stat = Self::vfs_stat_of(&node);
+        if node.is_dir() {
+            let mut names: Vec<String> = helper
+                .ls_resolved(&pending.resolved)
+                .await?
+                .into_iter()
+                .map(|(name, _)| name)
+                .collect();
+            if names.iter().any(|name| name == PAGED_MARKER) {
+                stat = VfsStat {
+                    kind: VfsNodeKind::File,
+                    size: helper.paged_file_len(&pending.path).await?,
+                    modified: stat.modified,
+                };
+            } else if state
+                .options
+                .max_depth
+                .map_or(true, |max| pending.depth < max)
+            {
+                names.sort();
+                // Reversed, so the stack pops siblings in name order.
+                for name in names.into_iter().rev() {
+                    if !state.options.include_reserved && Self::is_reserved_name(&name) {
+                        continue;
+                    }
+                    let mut path = pending.path.to_owned();
+                    path.push(name.to_owned());
+                    if state.options.exclude.contains(&path) {
+                        continue;
+                    }
+                    helper.check_path_depth(&path)?;
+                    let resolved = helper.resolve_child(&pending.resolved, &name).await?;
+                    state.stack.push(Pending {
+                        path,
+                        resolved,
+                        depth: pending.depth + 1,
+                    });
+                }
+            }
+        }
+        let wanted = match stat.kind {
+            VfsNodeKind::File => state.options.files,
+            VfsNodeKind::Directory => state.options.directories,
+        };
+        Ok(wanted.then(|| WalkEntry {
+            path: pending.path,
+            depth: pending.depth,
+            stat,
+        }))
+    }
+}
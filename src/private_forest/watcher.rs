@@ -0,0 +1,152 @@
+//! Noticing roots published by other devices.
+//!
+//! A [`RemoteWatcher`] asks a [`RootPointer`] for the published root once its poll interval has
+//! passed and reports a root this helper hasn't committed or loaded itself. In daemon mode the
+//! pointer service can POST to the daemon's `/root` endpoint instead, see
+//! `daemon::DaemonStatus::announce_root`, which makes the next `poll` check right away. The
+//! announcement only triggers the check: the root itself is always read from the pointer, so a
+//! forged webhook can't roll the helper back to an older root.
+//!
+//! With `WatchOptions::auto_reload` the helper moves to the new root in place. A reload drops
+//! local changes that weren't published, so it's skipped while batched commits are pending;
+//! apps with unpublished roots of their own reconcile through `commit_with_retry` instead.
+
+use std::time::Duration;
+
+use libipld::Cid;
+use log::trace;
+use web_time::Instant;
+
+use super::{PrivateDirectoryHelper, RootPointer};
+use crate::daemon::DaemonStatus;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WatchOptions {
+    /// How often `poll` asks the pointer, between announcements.
+    pub poll_interval_ms: u64,
+    /// Reload the helper when another device published a root.
+    pub auto_reload: bool,
+}
+
+impl Default for WatchOptions {
+    fn default() -> Self {
+        Self {
+            poll_interval_ms: 30_000,
+            auto_reload: false,
+        }
+    }
+}
+
+/// A root published by another device, reported once per root.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RemoteRootChange {
+    /// The helper's root when the change was seen.
+    pub previous: Option<Cid>,
+    pub root: Cid,
+    /// Whether the helper now runs on `root`.
+    pub reloaded: bool,
+}
+
+pub struct RemoteWatcher<P: RootPointer> {
+    pointer: P,
+    options: WatchOptions,
+    daemon: Option<DaemonStatus>,
+    last_poll: Option<Instant>,
+    last_reported: Option<Cid>,
+}
+
+impl<P: RootPointer> RemoteWatcher<P> {
+    pub fn new(pointer: P, options: WatchOptions) -> Self {
+        Self {
+            pointer,
+            options,
+            daemon: None,
+            last_poll: None,
+            last_reported: None,
+        }
+    }
+
+    /// Checks right away whenever the daemon behind `status` received a root announcement.
+    pub fn with_daemon(mut self, status: DaemonStatus) -> Self {
+        self.daemon = Some(status);
+        self
+    }
+
+    pub fn pointer(&self) -> &P {
+        &self.pointer
+    }
+
+    /// Checks the pointer if the poll interval passed or a root was announced since the last
+    /// check, e.g. from the app's UI timer. `None` when nothing new was published.
+    pub async fn poll(
+        &mut self,
+        helper: &mut PrivateDirectoryHelper<'_>,
+    ) -> Result<Option<RemoteRootChange>, String> {
+        let announced = self
+            .daemon
+            .as_ref()
+            .map(|status| status.take_root_announcement())
+            .unwrap_or_default();
+        let interval = Duration::from_millis(self.options.poll_interval_ms);
+        let due = self
+            .last_poll
+            .map(|at| at.elapsed() >= interval)
+            .unwrap_or(true);
+        if !announced && !due {
+            return Ok(None);
+        }
+        self.check_now(helper).await
+    }
+
+    /// Checks the pointer regardless of the poll interval.
+    pub async fn check_now(
+        &mut self,
+        helper: &mut PrivateDirectoryHelper<'_>,
+    ) -> Result<Option<RemoteRootChange>, String> {
+        self.last_poll = Some(Instant::now());
+        let latest = self.pointer.latest().map_err(|e| {
+            trace!("wnfsError in RemoteWatcher::check_now: {:?}", e);
+            e
+        })?;
+        helper.record_remote_root(latest);
+        // Roots this helper went through are its own, published or about to be.
+        if helper.root_history().contains(&latest) || self.last_reported == Some(latest) {
+            return Ok(None);
+        }
+        let previous = helper.root_history().last().copied();
+        let reloaded = self.options.auto_reload && !helper.has_pending_commit();
+        if reloaded {
+            helper.reload_in_place(latest).await?;
+        }
+        self.last_reported = Some(latest);
+        Ok(Some(RemoteRootChange {
+            previous,
+            root: latest,
+            reloaded,
+        }))
+    }
+}
+
+impl<P: RootPointer> RemoteWatcher<P> {
+    pub fn synced_poll(
+        &mut self,
+        helper: &mut PrivateDirectoryHelper<'_>,
+    ) -> Result<Option<RemoteRootChange>, String> {
+        PrivateDirectoryHelper::run_limited(
+            &helper.operation_limiter(),
+            "remote_watcher_poll",
+            self.poll(helper),
+        )
+    }
+
+    pub fn synced_check_now(
+        &mut self,
+        helper: &mut PrivateDirectoryHelper<'_>,
+    ) -> Result<Option<RemoteRootChange>, String> {
+        PrivateDirectoryHelper::run_limited(
+            &helper.operation_limiter(),
+            "remote_watcher_check_now",
+            self.check_now(helper),
+        )
+    }
+}
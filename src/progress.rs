@@ -0,0 +1,188 @@
+//! Progress reporting and cancellation for long running helper operations.
+//!
+//! Writing or reading a large file, copying a tree or loading a forest over a gateway can take
+//! minutes. The `*_observed` variants of those operations take an [`OperationObserver`]: its
+//! [`ProgressCallback`] is called as content passes through, and its [`CancellationToken`]
+//! stops the operation at the next block, or the next file when copying trees. A cancelled
+//! write or import leaves the tree as it was. Like [`crate::error_sink::ErrorSink`] the
+//! callback is a plain `Send + Sync` trait object, so bindings implement it on the foreign side.
+
+use std::{
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+};
+
+use futures::{future::poll_fn, task::AtomicWaker, AsyncRead};
+
+use crate::{error::WnfsUtilsError, metrics::StoreMetrics};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OperationProgress {
+    /// Name of the helper operation, e.g. `"write_file"`.
+    pub operation: &'static str,
+    pub bytes_done: u64,
+    /// `None` when the size isn't known up front.
+    pub bytes_total: Option<u64>,
+    /// Blocks read from the store since the operation started.
+    pub blocks_fetched: u64,
+}
+
+pub trait ProgressCallback: Send + Sync {
+    fn on_progress(&self, progress: &OperationProgress);
+}
+
+#[derive(Default)]
+struct Cancellation {
+    cancelled: AtomicBool,
+    waker: AtomicWaker,
+}
+
+/// A flag shared by its clones: the app keeps one to call `cancel`, the operation another.
+#[derive(Clone, Default)]
+pub struct CancellationToken(Arc<Cancellation>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.cancelled.store(true, Ordering::SeqCst);
+        self.0.waker.wake();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Completes once `cancel` was called.
+    pub async fn cancelled(&self) {
+        poll_fn(|cx| {
+            self.0.waker.register(cx.waker());
+            match self.is_cancelled() {
+                true => Poll::Ready(()),
+                false => Poll::Pending,
+            }
+        })
+        .await
+    }
+}
+
+/// Callback and cancellation of one operation, both optional.
+#[derive(Clone, Default)]
+pub struct OperationObserver {
+    pub callback: Option<Arc<dyn ProgressCallback>>,
+    pub cancellation: Option<CancellationToken>,
+}
+
+impl OperationObserver {
+    pub fn is_cancelled(&self) -> bool {
+        self.cancellation
+            .as_ref()
+            .map(CancellationToken::is_cancelled)
+            .unwrap_or_default()
+    }
+
+    /// Errors with `WnfsUtilsError::Cancelled` once the operation was cancelled.
+    pub fn check(&self) -> Result<(), String> {
+        match self.is_cancelled() {
+            true => Err(WnfsUtilsError::Cancelled.to_string()),
+            false => Ok(()),
+        }
+    }
+
+    pub fn report(&self, progress: &OperationProgress) {
+        if let Some(callback) = &self.callback {
+            callback.on_progress(progress);
+        }
+    }
+
+    /// Completes once the operation was cancelled, never without a cancellation token.
+    pub async fn cancelled(&self) {
+        match &self.cancellation {
+            Some(token) => token.cancelled().await,
+            None => futures::future::pending().await,
+        }
+    }
+}
+
+/// Progress of one operation, reported to its observer as it advances.
+pub(crate) struct ProgressMeter {
+    metrics: Arc<StoreMetrics>,
+    blocks_before: u64,
+    progress: OperationProgress,
+}
+
+impl ProgressMeter {
+    pub(crate) fn new(
+        metrics: Arc<StoreMetrics>,
+        operation: &'static str,
+        bytes_total: Option<u64>,
+    ) -> Self {
+        Self {
+            blocks_before: metrics.snapshot().blocks_read,
+            metrics,
+            progress: OperationProgress {
+                operation,
+                bytes_total,
+                ..Default::default()
+            },
+        }
+    }
+
+    /// Adds `bytes` to the bytes done and reports the progress.
+    pub(crate) fn advance(&mut self, observer: &OperationObserver, bytes: u64) {
+        self.progress.bytes_done += bytes;
+        self.progress.blocks_fetched = self
+            .metrics
+            .snapshot()
+            .blocks_read
+            .saturating_sub(self.blocks_before);
+        observer.report(&self.progress);
+    }
+}
+
+/// Reports the bytes read through it to `observer` and fails the read once cancelled.
+pub(crate) struct ObservedReader<'o, R> {
+    inner: R,
+    observer: &'o OperationObserver,
+    meter: ProgressMeter,
+}
+
+impl<'o, R> ObservedReader<'o, R> {
+    pub(crate) fn new(inner: R, observer: &'o OperationObserver, meter: ProgressMeter) -> Self {
+        Self {
+            inner,
+            observer,
+            meter,
+        }
+    }
+}
+
+impl<'o, R: AsyncRead + Unpin> AsyncRead for ObservedReader<'o, R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        if self.observer.is_cancelled() {
+            return Poll::Ready(Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                WnfsUtilsError::Cancelled.to_string(),
+            )));
+        }
+        let this = &mut *self;
+        let read = match Pin::new(&mut this.inner).poll_read(cx, buf) {
+            Poll::Ready(Ok(read)) => read,
+            other => return other,
+        };
+        if read > 0 {
+            this.meter.advance(this.observer, read as u64);
+        }
+        Poll::Ready(Ok(read))
+    }
+}
@@ -0,0 +1,331 @@
+//! Windows Projected File System frontend: exposes the forest as a directory whose entries are
+//! listed and whose content is fetched only when first accessed (on-demand hydration).
+//!
+//! The projection is read-only: ProjFS keeps local changes in the virtualization root but they
+//! are not written back to the forest. Requires Windows 10 1809 or later with the "Projected
+//! File System" optional feature enabled, and the `projfs` feature of this crate.
+
+use std::{
+    collections::HashMap,
+    ffi::c_void,
+    fs,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use log::trace;
+use tokio::runtime::Handle;
+use windows::{
+    core::{GUID, HRESULT, HSTRING, PCWSTR},
+    Win32::{
+        Foundation::{
+            BOOLEAN, ERROR_FILE_NOT_FOUND, ERROR_INSUFFICIENT_BUFFER, E_FAIL, E_OUTOFMEMORY, S_OK,
+        },
+        Storage::{
+            FileSystem::{FILE_ATTRIBUTE_DIRECTORY, FILE_ATTRIBUTE_READONLY},
+            ProjectedFileSystem::{
+                PrjAllocateAlignedBuffer, PrjFileNameCompare, PrjFileNameMatch,
+                PrjFillDirEntryBuffer, PrjFreeAlignedBuffer, PrjMarkDirectoryAsPlaceholder,
+                PrjStartVirtualizing, PrjStopVirtualizing, PrjWriteFileData,
+                PrjWritePlaceholderInfo, PRJ_CALLBACKS, PRJ_CALLBACK_DATA,
+                PRJ_CB_DATA_FLAG_ENUM_RESTART_SCAN, PRJ_DIR_ENTRY_BUFFER_HANDLE,
+                PRJ_FILE_BASIC_INFO, PRJ_NAMESPACE_VIRTUALIZATION_CONTEXT, PRJ_PLACEHOLDER_INFO,
+            },
+        },
+    },
+};
+
+use crate::vfs::{OpenOptions, VfsBridge, VfsNodeKind, VfsStat};
+
+// Seconds between 1601-01-01 (FILETIME epoch) and 1970-01-01.
+const FILETIME_UNIX_OFFSET: i64 = 11_644_473_600;
+
+/// A running projection of the forest. Stops when dropped.
+pub struct ProjectedDrive {
+    context: PRJ_NAMESPACE_VIRTUALIZATION_CONTEXT,
+    instance: *mut ProjFsInstance,
+}
+
+struct ProjFsInstance {
+    vfs: VfsBridge,
+    runtime: Handle,
+    // Open directory enumerations by enumeration id.
+    enumerations: Mutex<HashMap<u128, Enumeration>>,
+}
+
+struct Enumeration {
+    entries: Vec<(String, VfsStat)>,
+    next: usize,
+    search_expression: Option<String>,
+}
+
+impl ProjectedDrive {
+    /// Projects the forest behind `vfs` into `root`, creating the directory if needed. ProjFS
+    /// calls back on its own threads, which block on `runtime` for the bridge calls; the app
+    /// keeps serving the bridge with `vfs::serve_vfs`.
+    ///
+    /// The instance id of the root is kept in `<root>.projfs-id`, so restarting on the same
+    /// root reuses the hydrated files.
+    pub fn start(root: &Path, vfs: VfsBridge, runtime: Handle) -> Result<Self, String> {
+        fs::create_dir_all(root).map_err(|e| e.to_string())?;
+        let root_name = HSTRING::from(root.as_os_str());
+        let id_path = instance_id_path(root);
+        let instance_id = match fs::read(&id_path) {
+            Ok(bytes) => <[u8; 16]>::try_from(bytes.as_slice())
+                .map(|bytes| GUID::from_u128(u128::from_be_bytes(bytes)))
+                .map_err(|_| format!("wnfsError corrupted {}", id_path.display()))?,
+            Err(_) => {
+                let id = GUID::from_u128(rand::random());
+                unsafe { PrjMarkDirectoryAsPlaceholder(&root_name, PCWSTR::null(), None, &id) }
+                    .map_err(|e| {
+                        trace!("wnfsError in ProjectedDrive::start: {:?}", e);
+                        e.to_string()
+                    })?;
+                fs::write(&id_path, id.to_u128().to_be_bytes()).map_err(|e| e.to_string())?;
+                id
+            }
+        };
+        trace!("projfs: starting {:?} as {:?}", root, instance_id);
+
+        let callbacks = PRJ_CALLBACKS {
+            StartDirectoryEnumerationCallback: Some(start_enumeration),
+            EndDirectoryEnumerationCallback: Some(end_enumeration),
+            GetDirectoryEnumerationCallback: Some(get_enumeration),
+            GetPlaceholderInfoCallback: Some(get_placeholder_info),
+            GetFileDataCallback: Some(get_file_data),
+            ..Default::default()
+        };
+        let instance = Box::into_raw(Box::new(ProjFsInstance {
+            vfs,
+            runtime,
+            enumerations: Mutex::new(HashMap::new()),
+        }));
+        let context = unsafe {
+            PrjStartVirtualizing(
+                &root_name,
+                &callbacks,
+                Some(instance as *const c_void),
+                None,
+            )
+        };
+        match context {
+            Ok(context) => Ok(Self { context, instance }),
+            Err(e) => {
+                drop(unsafe { Box::from_raw(instance) });
+                trace!("wnfsError in ProjectedDrive::start: {:?}", e);
+                Err(e.to_string())
+            }
+        }
+    }
+}
+
+impl Drop for ProjectedDrive {
+    fn drop(&mut self) {
+        unsafe {
+            // No callbacks run once this returns, so the instance can be freed.
+            PrjStopVirtualizing(self.context);
+            drop(Box::from_raw(self.instance));
+        }
+    }
+}
+
+fn instance_id_path(root: &Path) -> PathBuf {
+    let mut path = root.as_os_str().to_owned();
+    path.push(".projfs-id");
+    PathBuf::from(path)
+}
+
+unsafe fn instance<'a>(data: *const PRJ_CALLBACK_DATA) -> &'a ProjFsInstance {
+    &*((*data).InstanceContext as *const ProjFsInstance)
+}
+
+// The path a callback is about, relative to the virtualization root.
+unsafe fn callback_path(data: *const PRJ_CALLBACK_DATA) -> Vec<String> {
+    (*data)
+        .FilePathName
+        .to_string()
+        .unwrap_or_default()
+        .split('\\')
+        .filter(|segment| !segment.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+fn with_enumerations<T>(
+    instance: &ProjFsInstance,
+    f: impl FnOnce(&mut HashMap<u128, Enumeration>) -> T,
+) -> T {
+    let mut guard = match instance.enumerations.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    f(&mut guard)
+}
+
+fn basic_info(stat: &VfsStat) -> PRJ_FILE_BASIC_INFO {
+    let time = stat
+        .modified
+        .map(|seconds| (seconds + FILETIME_UNIX_OFFSET) * 10_000_000)
+        .unwrap_or_default();
+    let (is_directory, attributes) = match stat.kind {
+        VfsNodeKind::Directory => (true, FILE_ATTRIBUTE_DIRECTORY.0),
+        VfsNodeKind::File => (false, FILE_ATTRIBUTE_READONLY.0),
+    };
+    PRJ_FILE_BASIC_INFO {
+        IsDirectory: BOOLEAN::from(is_directory),
+        FileSize: stat.size as i64,
+        CreationTime: time,
+        LastAccessTime: time,
+        LastWriteTime: time,
+        ChangeTime: time,
+        FileAttributes: attributes,
+    }
+}
+
+unsafe extern "system" fn start_enumeration(
+    data: *const PRJ_CALLBACK_DATA,
+    enumeration_id: *const GUID,
+) -> HRESULT {
+    let instance = instance(data);
+    let path = callback_path(data);
+    let mut entries = match instance.runtime.block_on(instance.vfs.readdir(&path)) {
+        Ok(entries) => entries,
+        Err(e) => {
+            trace!("wnfsError in projfs start_enumeration: {:?}", e);
+            return ERROR_FILE_NOT_FOUND.to_hresult();
+        }
+    };
+    // ProjFS expects entries in its own file name order.
+    entries.sort_by(|(a, _), (b, _)| {
+        PrjFileNameCompare(&HSTRING::from(a.as_str()), &HSTRING::from(b.as_str())).cmp(&0)
+    });
+    let id = (*enumeration_id).to_u128();
+    with_enumerations(instance, |enumerations| {
+        enumerations.insert(
+            id,
+            Enumeration {
+                entries,
+                next: 0,
+                search_expression: None,
+            },
+        )
+    });
+    S_OK
+}
+
+unsafe extern "system" fn end_enumeration(
+    data: *const PRJ_CALLBACK_DATA,
+    enumeration_id: *const GUID,
+) -> HRESULT {
+    let id = (*enumeration_id).to_u128();
+    with_enumerations(instance(data), |enumerations| enumerations.remove(&id));
+    S_OK
+}
+
+unsafe extern "system" fn get_enumeration(
+    data: *const PRJ_CALLBACK_DATA,
+    enumeration_id: *const GUID,
+    search_expression: PCWSTR,
+    buffer: PRJ_DIR_ENTRY_BUFFER_HANDLE,
+) -> HRESULT {
+    let id = (*enumeration_id).to_u128();
+    let restart = (*data).Flags.0 & PRJ_CB_DATA_FLAG_ENUM_RESTART_SCAN.0 != 0;
+    with_enumerations(instance(data), |enumerations| {
+        let Some(enumeration) = enumerations.get_mut(&id) else {
+            return E_FAIL;
+        };
+        if restart || enumeration.search_expression.is_none() {
+            enumeration.next = 0;
+            enumeration.search_expression = Some(match search_expression.is_null() {
+                true => "*".to_string(),
+                false => search_expression.to_string().unwrap_or_default(),
+            });
+        }
+        let pattern = HSTRING::from(enumeration.search_expression.as_deref().unwrap_or("*"));
+        let mut filled = 0;
+        while let Some((name, stat)) = enumeration.entries.get(enumeration.next) {
+            let name = HSTRING::from(name.as_str());
+            if PrjFileNameMatch(&name, &pattern).as_bool() {
+                let info = basic_info(stat);
+                if let Err(e) = PrjFillDirEntryBuffer(&name, Some(&info), buffer) {
+                    // The buffer is full: the rest is handed out on the next call, unless not
+                    // even the first entry fit.
+                    if filled > 0 && e.code() == ERROR_INSUFFICIENT_BUFFER.to_hresult() {
+                        return S_OK;
+                    }
+                    return e.code();
+                }
+                filled += 1;
+            }
+            enumeration.next += 1;
+        }
+        S_OK
+    })
+}
+
+unsafe extern "system" fn get_placeholder_info(data: *const PRJ_CALLBACK_DATA) -> HRESULT {
+    let instance = instance(data);
+    let path = callback_path(data);
+    let stat = match instance.runtime.block_on(instance.vfs.stat(&path)) {
+        Ok(Some(stat)) => stat,
+        Ok(None) => return ERROR_FILE_NOT_FOUND.to_hresult(),
+        Err(e) => {
+            trace!("wnfsError in projfs get_placeholder_info: {:?}", e);
+            return E_FAIL;
+        }
+    };
+    let info = PRJ_PLACEHOLDER_INFO {
+        FileBasicInfo: basic_info(&stat),
+        ..Default::default()
+    };
+    match PrjWritePlaceholderInfo(
+        (*data).NamespaceVirtualizationContext,
+        (*data).FilePathName,
+        &info,
+        std::mem::size_of::<PRJ_PLACEHOLDER_INFO>() as u32,
+    ) {
+        Ok(()) => S_OK,
+        Err(e) => e.code(),
+    }
+}
+
+unsafe extern "system" fn get_file_data(
+    data: *const PRJ_CALLBACK_DATA,
+    byte_offset: u64,
+    length: u32,
+) -> HRESULT {
+    let instance = instance(data);
+    let path = callback_path(data);
+    let content = instance.runtime.block_on(async {
+        let handle = instance.vfs.open(&path, OpenOptions::default()).await?;
+        instance
+            .vfs
+            .read(&handle, byte_offset, length as usize)
+            .await
+    });
+    let content = match content {
+        Ok(content) => content,
+        Err(e) => {
+            trace!("wnfsError in projfs get_file_data: {:?}", e);
+            return E_FAIL;
+        }
+    };
+    let context = (*data).NamespaceVirtualizationContext;
+    let buffer = PrjAllocateAlignedBuffer(context, content.len());
+    if buffer.is_null() {
+        return E_OUTOFMEMORY;
+    }
+    std::ptr::copy_nonoverlapping(content.as_ptr(), buffer as *mut u8, content.len());
+    let result = PrjWriteFileData(
+        context,
+        &(*data).DataStreamId,
+        buffer,
+        byte_offset,
+        content.len() as u32,
+    );
+    PrjFreeAlignedBuffer(buffer);
+    match result {
+        Ok(()) => S_OK,
+        Err(e) => e.code(),
+    }
+}
@@ -0,0 +1,370 @@
+//! Helper over a public (unencrypted) WNFS tree, with the same file operations as
+//! `PrivateDirectoryHelper` for data an app publishes, e.g. a profile or shared assets.
+//!
+//! Every mutation stores the tree and returns its new root CID. A [`CombinedRoot`] links a
+//! public root and a private forest, so an app publishing both only has to track one CID.
+//! File content is kept in a single raw block, so public files are bound by the block store's
+//! `max_block_size`; larger writes are refused with `WnfsUtilsError::BlockTooLarge`.
+
+use std::{collections::BTreeMap, rc::Rc};
+
+use chrono::{DateTime, Utc};
+use libipld::{cbor::DagCborCodec, codec::Codec, Cid, Ipld};
+use log::trace;
+use wnfs::{
+    common::{BlockStore, Metadata, CODEC_DAG_CBOR, CODEC_RAW},
+    public::{PublicDirectory, PublicNode},
+};
+
+use crate::blockstore::FFIFriendlyBlockStore;
+use crate::error::{describe, WnfsUtilsError};
+use crate::private_forest::PrivateDirectoryHelper;
+
+pub struct PublicDirectoryHelper<'a> {
+    pub store: FFIFriendlyBlockStore<'a>,
+    root_dir: Rc<PublicDirectory>,
+    root_cid: Option<Cid>,
+}
+
+impl<'a> PublicDirectoryHelper<'a> {
+    /// A helper over a new, empty public tree. Nothing is stored until the first mutation.
+    pub fn new(store: FFIFriendlyBlockStore<'a>) -> Self {
+        Self {
+            store,
+            root_dir: Rc::new(PublicDirectory::new(Utc::now())),
+            root_cid: None,
+        }
+    }
+
+    pub async fn load(store: FFIFriendlyBlockStore<'a>, root_cid: Cid) -> Result<Self, String> {
+        let root_dir = PublicNode::load(&root_cid, &store)
+            .await
+            .and_then(|node| node.as_dir())
+            .map_err(|e| {
+                trace!("wnfsError in public load: {:?}", e.to_string());
+                describe(&e)
+            })?;
+        Ok(Self {
+            store,
+            root_dir,
+            root_cid: Some(root_cid),
+        })
+    }
+
+    /// CID of the tree as last stored or loaded, `None` for a new tree with no mutation yet.
+    pub fn root_cid(&self) -> Option<Cid> {
+        self.root_cid
+    }
+
+    // A copy to stage changes on, which leaves this tree as it is until it replaces it.
+    pub(crate) fn staging(&self) -> Self {
+        Self {
+            store: self.store.to_owned(),
+            root_dir: Rc::clone(&self.root_dir),
+            root_cid: self.root_cid,
+        }
+    }
+
+    pub(crate) async fn commit(&mut self) -> Result<Cid, String> {
+        let root_cid = self.root_dir.store(&self.store).await.map_err(|e| {
+            trace!("wnfsError in public commit: {:?}", e.to_string());
+            describe(&e)
+        })?;
+        self.root_cid = Some(root_cid);
+        Ok(root_cid)
+    }
+
+    fn check_path_depth(&self, path_segments: &[String]) -> Result<(), String> {
+        self.store
+            .decode_limits()
+            .check_path_depth(path_segments.len())
+            .map_err(|e| {
+                trace!("wnfsError in public check_path_depth: {:?}", e.to_string());
+                describe(&e)
+            })
+    }
+
+    pub async fn write_file(
+        &mut self,
+        path_segments: &[String],
+        content: Vec<u8>,
+    ) -> Result<Cid, String> {
+        self.check_path_depth(path_segments)?;
+        self.stage_write(path_segments, content, Utc::now()).await?;
+        self.commit().await
+    }
+
+    // Writes a file without storing the tree, for operations committing many changes at once.
+    pub(crate) async fn stage_write(
+        &mut self,
+        path_segments: &[String],
+        content: Vec<u8>,
+        time: DateTime<Utc>,
+    ) -> Result<(), String> {
+        let limit = self.store.decode_limits().max_block_size;
+        if content.len() > limit {
+            let err = WnfsUtilsError::BlockTooLarge {
+                size: content.len(),
+                limit,
+            };
+            trace!("wnfsError in public write_file: {:?}", err.to_string());
+            return Err(err.to_string());
+        }
+        let content_cid = self
+            .store
+            .put_block(content, CODEC_RAW)
+            .await
+            .map_err(|e| {
+                trace!("wnfsError in public write_file: {:?}", e.to_string());
+                describe(&e)
+            })?;
+        self.root_dir
+            .write(path_segments, content_cid, time, &self.store)
+            .await
+            .map_err(|e| {
+                trace!("wnfsError in public write_file: {:?}", e.to_string());
+                describe(&e)
+            })
+    }
+
+    pub async fn read_file(&self, path_segments: &[String]) -> Result<Vec<u8>, String> {
+        self.check_path_depth(path_segments)?;
+        let content_cid = self
+            .root_dir
+            .read(path_segments, &self.store)
+            .await
+            .map_err(|e| {
+                trace!("wnfsError in public read_file: {:?}", e.to_string());
+                describe(&e)
+            })?;
+        self.store
+            .get_block(&content_cid)
+            .await
+            .map(|bytes| bytes.to_vec())
+            .map_err(|e| {
+                trace!("wnfsError in public read_file: {:?}", e.to_string());
+                describe(&e)
+            })
+    }
+
+    pub async fn mkdir(&mut self, path_segments: &[String]) -> Result<Cid, String> {
+        self.check_path_depth(path_segments)?;
+        self.stage_mkdir(path_segments, Utc::now()).await?;
+        self.commit().await
+    }
+
+    pub(crate) async fn stage_mkdir(
+        &mut self,
+        path_segments: &[String],
+        time: DateTime<Utc>,
+    ) -> Result<(), String> {
+        self.root_dir
+            .mkdir(path_segments, time, &self.store)
+            .await
+            .map_err(|e| {
+                trace!("wnfsError in public mkdir: {:?}", e.to_string());
+                describe(&e)
+            })
+    }
+
+    // Removes whatever is at `path_segments` without storing the tree, the root included.
+    pub(crate) async fn stage_clear(&mut self, path_segments: &[String]) -> Result<(), String> {
+        if path_segments.is_empty() {
+            self.root_dir = Rc::new(PublicDirectory::new(Utc::now()));
+            return Ok(());
+        }
+        let exists = self
+            .root_dir
+            .get_node(path_segments, &self.store)
+            .await
+            .map_err(|e| {
+                trace!("wnfsError in public rm: {:?}", e.to_string());
+                describe(&e)
+            })?
+            .is_some();
+        if exists {
+            self.root_dir
+                .rm(path_segments, &self.store)
+                .await
+                .map_err(|e| {
+                    trace!("wnfsError in public rm: {:?}", e.to_string());
+                    describe(&e)
+                })?;
+        }
+        Ok(())
+    }
+
+    pub async fn rm(&mut self, path_segments: &[String]) -> Result<Cid, String> {
+        self.root_dir
+            .rm(path_segments, &self.store)
+            .await
+            .map_err(|e| {
+                trace!("wnfsError in public rm: {:?}", e.to_string());
+                describe(&e)
+            })?;
+        self.commit().await
+    }
+
+    pub async fn mv(
+        &mut self,
+        source_path_segments: &[String],
+        target_path_segments: &[String],
+    ) -> Result<Cid, String> {
+        self.check_path_depth(target_path_segments)?;
+        self.root_dir
+            .basic_mv(
+                source_path_segments,
+                target_path_segments,
+                Utc::now(),
+                &self.store,
+            )
+            .await
+            .map_err(|e| {
+                trace!("wnfsError in public mv: {:?}", e.to_string());
+                describe(&e)
+            })?;
+        self.commit().await
+    }
+
+    pub async fn ls_files(
+        &self,
+        path_segments: &[String],
+    ) -> Result<Vec<(String, Metadata)>, String> {
+        self.check_path_depth(path_segments)?;
+        let entries = self
+            .root_dir
+            .ls(path_segments, &self.store)
+            .await
+            .map_err(|e| {
+                trace!("wnfsError in public ls_files: {:?}", e.to_string());
+                describe(&e)
+            })?;
+        self.store
+            .decode_limits()
+            .check_dir_entries(entries.len())
+            .map_err(|e| {
+                trace!("wnfsError in public ls_files: {:?}", e.to_string());
+                describe(&e)
+            })?;
+        Ok(entries)
+    }
+}
+
+// Synced versions for the bindings, run like the private helper's.
+impl<'a> PublicDirectoryHelper<'a> {
+    pub fn synced_load(store: FFIFriendlyBlockStore<'a>, root_cid: Cid) -> Result<Self, String> {
+        PrivateDirectoryHelper::run_request("public_load", Self::load(store, root_cid))
+    }
+
+    pub fn synced_write_file(
+        &mut self,
+        path_segments: &[String],
+        content: Vec<u8>,
+    ) -> Result<Cid, String> {
+        PrivateDirectoryHelper::run_request(
+            "public_write_file",
+            self.write_file(path_segments, content),
+        )
+    }
+
+    pub fn synced_read_file(&self, path_segments: &[String]) -> Result<Vec<u8>, String> {
+        PrivateDirectoryHelper::run_request("public_read_file", self.read_file(path_segments))
+    }
+
+    pub fn synced_mkdir(&mut self, path_segments: &[String]) -> Result<Cid, String> {
+        PrivateDirectoryHelper::run_request("public_mkdir", self.mkdir(path_segments))
+    }
+
+    pub fn synced_rm(&mut self, path_segments: &[String]) -> Result<Cid, String> {
+        PrivateDirectoryHelper::run_request("public_rm", self.rm(path_segments))
+    }
+
+    pub fn synced_mv(
+        &mut self,
+        source_path_segments: &[String],
+        target_path_segments: &[String],
+    ) -> Result<Cid, String> {
+        PrivateDirectoryHelper::run_request(
+            "public_mv",
+            self.mv(source_path_segments, target_path_segments),
+        )
+    }
+
+    pub fn synced_ls_files(
+        &self,
+        path_segments: &[String],
+    ) -> Result<Vec<(String, Metadata)>, String> {
+        PrivateDirectoryHelper::run_request("public_ls_files", self.ls_files(path_segments))
+    }
+}
+
+/// Links a public tree and a private forest under one CID, stored as DAG-CBOR
+/// `{"public": link, "private": link}` with absent trees left out.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CombinedRoot {
+    pub public: Option<Cid>,
+    pub private: Option<Cid>,
+}
+
+impl CombinedRoot {
+    /// The latest roots of `public` and `private`, committing batched private mutations first.
+    pub async fn of(
+        public: &PublicDirectoryHelper,
+        private: &mut PrivateDirectoryHelper<'_>,
+    ) -> Result<Self, String> {
+        Ok(Self {
+            public: public.root_cid(),
+            private: Some(private.flush_commits().await?),
+        })
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, String> {
+        let mut map = BTreeMap::new();
+        if let Some(public) = self.public {
+            map.insert("public".to_string(), Ipld::Link(public));
+        }
+        if let Some(private) = self.private {
+            map.insert("private".to_string(), Ipld::Link(private));
+        }
+        DagCborCodec
+            .encode(&Ipld::Map(map))
+            .map_err(|e| describe(&e))
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        let map = match DagCborCodec.decode(bytes).map_err(|e| describe(&e))? {
+            Ipld::Map(map) => map,
+            _ => return Err("a combined root must be a map".into()),
+        };
+        let link = |key: &str| match map.get(key) {
+            None => Ok(None),
+            Some(Ipld::Link(cid)) => Ok(Some(*cid)),
+            Some(_) => Err(format!("{} of a combined root must be a link", key)),
+        };
+        Ok(Self {
+            public: link("public")?,
+            private: link("private")?,
+        })
+    }
+
+    pub async fn store(&self, store: &impl BlockStore) -> Result<Cid, String> {
+        store
+            .put_block(self.to_bytes()?, CODEC_DAG_CBOR)
+            .await
+            .map_err(|e| {
+                trace!("wnfsError in CombinedRoot::store: {:?}", e.to_string());
+                describe(&e)
+            })
+    }
+
+    pub async fn load(store: &impl BlockStore, cid: &Cid) -> Result<Self, String> {
+        let bytes = store.get_block(cid).await.map_err(|e| {
+            trace!("wnfsError in CombinedRoot::load: {:?}", e.to_string());
+            describe(&e)
+        })?;
+        Self::from_bytes(&bytes)
+    }
+}
+
+#[cfg(test)]
+mod public_forest_tests;
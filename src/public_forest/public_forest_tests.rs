@@ -0,0 +1,134 @@
+use wnfs::common::CODEC_DAG_CBOR;
+
+use crate::blockstore::{DecodeLimits, FFIFriendlyBlockStore};
+use crate::error::ErrorCode;
+use crate::kvstore::KVBlockStore;
+use crate::private_forest::PrivateDirectoryHelper;
+use crate::public_forest::{CombinedRoot, PublicDirectoryHelper};
+
+#[tokio::test]
+async fn public_and_private_trees_share_one_root() {
+    let dir = tempfile::tempdir().unwrap();
+    let store = KVBlockStore::new(
+        dir.path().join("store").to_string_lossy().to_string(),
+        CODEC_DAG_CBOR,
+    );
+    let blockstore = &mut FFIFriendlyBlockStore::new(Box::new(store));
+
+    let mut public = PublicDirectoryHelper::new(blockstore.to_owned());
+    assert!(public.root_cid().is_none());
+    public.mkdir(&["assets".into()]).await.unwrap();
+    public
+        .write_file(&["assets".into(), "logo.svg".into()], b"<svg/>".to_vec())
+        .await
+        .unwrap();
+    public
+        .write_file(&["profile.json".into()], b"{}".to_vec())
+        .await
+        .unwrap();
+    public
+        .mv(
+            &["profile.json".into()],
+            &["assets".into(), "profile.json".into()],
+        )
+        .await
+        .unwrap();
+    public
+        .rm(&["assets".into(), "logo.svg".into()])
+        .await
+        .unwrap();
+    let entries = public.ls_files(&["assets".into()]).await.unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].0, "profile.json");
+
+    let (mut private, _, _) = PrivateDirectoryHelper::init(blockstore, vec![0; 32])
+        .await
+        .unwrap();
+    private
+        .write_file(&["secret.txt".into()], b"hidden".to_vec(), 0)
+        .await
+        .unwrap();
+
+    let root = CombinedRoot::of(&public, &mut private).await.unwrap();
+    let root_cid = root.store(blockstore).await.unwrap();
+    let loaded = CombinedRoot::load(blockstore, &root_cid).await.unwrap();
+    assert_eq!(loaded, root);
+
+    let public = PublicDirectoryHelper::load(blockstore.to_owned(), loaded.public.unwrap())
+        .await
+        .unwrap();
+    assert_eq!(
+        public
+            .read_file(&["assets".into(), "profile.json".into()])
+            .await
+            .unwrap(),
+        b"{}".to_vec()
+    );
+    let mut private = PrivateDirectoryHelper::load_with_wnfs_key(
+        blockstore,
+        loaded.private.unwrap(),
+        vec![0; 32],
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        private.read_file(&["secret.txt".into()]).await.unwrap(),
+        b"hidden".to_vec()
+    );
+
+    // A root without a public tree leaves its link out.
+    let private_only = CombinedRoot {
+        public: None,
+        ..root
+    };
+    let decoded = CombinedRoot::from_bytes(&private_only.to_bytes().unwrap()).unwrap();
+    assert_eq!(decoded, private_only);
+}
+
+#[tokio::test]
+async fn files_past_the_block_size_limit_are_refused() {
+    let dir = tempfile::tempdir().unwrap();
+    let store = KVBlockStore::new(
+        dir.path().join("store").to_string_lossy().to_string(),
+        CODEC_DAG_CBOR,
+    );
+    let blockstore = &mut FFIFriendlyBlockStore::new(Box::new(store));
+    let (mut private, _, _) = PrivateDirectoryHelper::init(blockstore, vec![0; 32])
+        .await
+        .unwrap();
+    private
+        .write_file(&["site".into(), "small.txt".into()], b"small".to_vec(), 0)
+        .await
+        .unwrap();
+    private
+        .write_file(&["site".into(), "large.bin".into()], vec![7; 4096], 0)
+        .await
+        .unwrap();
+
+    let mut public_store = blockstore.to_owned();
+    public_store.set_decode_limits(DecodeLimits {
+        max_block_size: 1024,
+        ..Default::default()
+    });
+    let mut public = PublicDirectoryHelper::new(public_store);
+    let err = public
+        .write_file(&["large.bin".into()], vec![7; 4096])
+        .await
+        .unwrap_err();
+    assert_eq!(ErrorCode::of_message(&err), ErrorCode::LimitExceeded);
+    let root = public
+        .write_file(&["index.html".into()], b"<html/>".to_vec())
+        .await
+        .unwrap();
+
+    // A snapshot that fails halfway leaves the public tree as it was.
+    let err = private
+        .publish_snapshot(&["site".into()], &mut public, &[], None)
+        .await
+        .unwrap_err();
+    assert_eq!(ErrorCode::of_message(&err), ErrorCode::LimitExceeded);
+    assert_eq!(public.root_cid(), Some(root));
+    let entries = public.ls_files(&[]).await.unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].0, "index.html");
+}
@@ -0,0 +1,56 @@
+//! Request IDs for top-level helper operations, so support can match a failure a user reports
+//! with the gateway's logs.
+//!
+//! Each synced helper operation runs inside a request scope. The ID shows up in the crate's log
+//! lines, is appended to the error message returned to the app and is attached to events sent
+//! to the `error_sink`. An `FFIStore` implementation can read it with [`current`] and forward it
+//! to the gateway, e.g. as an `X-Request-Id` header. Apps that already have an ID for the user
+//! action, such as one minted by their UI layer, can [`enter`] it before calling the helper and
+//! it is used instead of a new one.
+
+use std::cell::RefCell;
+
+use rand::{thread_rng, Rng};
+
+thread_local! {
+    static CURRENT: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// ID of the request running on this thread, if any.
+pub fn current() -> Option<String> {
+    CURRENT.with(|current| current.borrow().to_owned())
+}
+
+/// A random 16 hex digit ID.
+pub fn new_request_id() -> String {
+    format!("{:016x}", thread_rng().gen::<u64>())
+}
+
+/// Makes `id` the current request ID on this thread until the returned scope is dropped.
+pub fn enter(id: String) -> RequestScope {
+    let previous = CURRENT.with(|current| current.replace(Some(id)));
+    RequestScope { previous }
+}
+
+/// Restores the previous request ID when dropped.
+pub struct RequestScope {
+    previous: Option<String>,
+}
+
+impl Drop for RequestScope {
+    fn drop(&mut self) {
+        let previous = self.previous.take();
+        CURRENT.with(|current| *current.borrow_mut() = previous);
+    }
+}
+
+/// Appends the current request ID to an error message.
+pub fn tag_error(message: String) -> String {
+    match current() {
+        Some(id) => format!("{} (request {})", message, id),
+        None => message,
+    }
+}
+
+#[cfg(test)]
+mod request_id_tests;
@@ -0,0 +1,27 @@
+use crate::request_id::{current, enter, new_request_id, tag_error};
+
+#[test]
+fn scopes_nest_and_restore_the_previous_id() {
+    assert_eq!(current(), None);
+    assert_eq!(tag_error("wnfsError boom".into()), "wnfsError boom");
+    {
+        let _outer = enter("outer".into());
+        {
+            let _inner = enter("inner".into());
+            assert_eq!(current().as_deref(), Some("inner"));
+        }
+        assert_eq!(current().as_deref(), Some("outer"));
+        assert_eq!(
+            tag_error("wnfsError boom".into()),
+            "wnfsError boom (request outer)"
+        );
+    }
+    assert_eq!(current(), None);
+}
+
+#[test]
+fn new_ids_are_distinct() {
+    let id = new_request_id();
+    assert_eq!(id.len(), 16);
+    assert_ne!(id, new_request_id());
+}
@@ -0,0 +1,360 @@
+//! SFTP frontend, so backup tools and file managers can use a forest over a loopback endpoint.
+//!
+//! The server reaches the forest through a [`VfsBridge`]; the app keeps the helper on its own
+//! thread and drives `vfs::serve_vfs` next to [`serve_sftp`]. Requires the `sftp` feature.
+
+use std::{collections::HashMap, net::SocketAddr, sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use log::trace;
+use russh::{
+    server::{Auth, Msg, Server, Session},
+    Channel, ChannelId,
+};
+use russh_keys::key::KeyPair;
+use russh_sftp::protocol::{
+    Attrs, Data, File, FileAttributes, Handle, Name, OpenFlags, Status, StatusCode, Version,
+};
+use tokio::sync::Mutex;
+
+use crate::vfs::{OpenOptions, VfsBridge, VfsHandle, VfsNodeKind, VfsStat};
+
+#[derive(Debug, Clone)]
+pub struct SftpConfig {
+    /// Address to listen on. Keep it on loopback unless the network is trusted.
+    pub listen: SocketAddr,
+    pub username: String,
+    pub password: String,
+}
+
+/// Serves the forest behind `vfs` over SFTP until the listener fails. A new host key is
+/// generated on every start.
+pub async fn serve_sftp(config: SftpConfig, vfs: VfsBridge) -> Result<(), String> {
+    let host_key = KeyPair::generate_ed25519()
+        .ok_or_else(|| "wnfsError unable to generate an SFTP host key".to_string())?;
+    let ssh_config = russh::server::Config {
+        keys: vec![host_key],
+        auth_rejection_time: Duration::from_secs(1),
+        auth_rejection_time_initial: Some(Duration::from_secs(0)),
+        ..Default::default()
+    };
+    let mut server = SftpServer {
+        config: Arc::new(config.to_owned()),
+        vfs,
+    };
+    server
+        .run_on_address(Arc::new(ssh_config), config.listen)
+        .await
+        .map_err(|e| {
+            trace!("wnfsError in serve_sftp: {:?}", e.to_string());
+            e.to_string()
+        })
+}
+
+#[derive(Clone)]
+struct SftpServer {
+    config: Arc<SftpConfig>,
+    vfs: VfsBridge,
+}
+
+impl Server for SftpServer {
+    type Handler = SshSession;
+
+    fn new_client(&mut self, _: Option<SocketAddr>) -> SshSession {
+        SshSession {
+            config: Arc::clone(&self.config),
+            vfs: self.vfs.to_owned(),
+            channels: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+}
+
+struct SshSession {
+    config: Arc<SftpConfig>,
+    vfs: VfsBridge,
+    channels: Arc<Mutex<HashMap<ChannelId, Channel<Msg>>>>,
+}
+
+#[async_trait]
+impl russh::server::Handler for SshSession {
+    type Error = anyhow::Error;
+
+    async fn auth_password(&mut self, user: &str, password: &str) -> Result<Auth, Self::Error> {
+        if user == self.config.username && password == self.config.password {
+            return Ok(Auth::Accept);
+        }
+        Ok(Auth::Reject {
+            proceed_with_methods: None,
+        })
+    }
+
+    async fn channel_open_session(
+        &mut self,
+        channel: Channel<Msg>,
+        _session: &mut Session,
+    ) -> Result<bool, Self::Error> {
+        self.channels.lock().await.insert(channel.id(), channel);
+        Ok(true)
+    }
+
+    async fn subsystem_request(
+        &mut self,
+        channel_id: ChannelId,
+        name: &str,
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        let channel = self.channels.lock().await.remove(&channel_id);
+        match channel {
+            Some(channel) if name == "sftp" => {
+                session.channel_success(channel_id);
+                let handler = SftpSession {
+                    vfs: self.vfs.to_owned(),
+                    handles: HashMap::new(),
+                    next_handle: 0,
+                };
+                russh_sftp::server::run(channel.into_stream(), handler).await;
+            }
+            _ => session.channel_failure(channel_id),
+        }
+        Ok(())
+    }
+}
+
+enum OpenHandle {
+    File(VfsHandle),
+    // Entries are handed out by the first `readdir`, the next one reports the end.
+    Dir(Option<Vec<(String, VfsStat)>>),
+}
+
+struct SftpSession {
+    vfs: VfsBridge,
+    handles: HashMap<String, OpenHandle>,
+    next_handle: u64,
+}
+
+impl SftpSession {
+    fn add_handle(&mut self, handle: OpenHandle) -> String {
+        self.next_handle += 1;
+        let name = self.next_handle.to_string();
+        self.handles.insert(name.to_owned(), handle);
+        name
+    }
+
+    fn file_handle(&self, handle: &str) -> Result<VfsHandle, StatusCode> {
+        match self.handles.get(handle) {
+            Some(OpenHandle::File(file)) => Ok(file.to_owned()),
+            _ => Err(StatusCode::Failure),
+        }
+    }
+
+    async fn attributes(&self, path: &[String]) -> Result<FileAttributes, StatusCode> {
+        match self.vfs.stat(path).await.map_err(failure)? {
+            Some(stat) => Ok(attributes_of(&stat)),
+            None => Err(StatusCode::NoSuchFile),
+        }
+    }
+}
+
+#[async_trait]
+impl russh_sftp::server::Handler for SftpSession {
+    type Error = StatusCode;
+
+    fn unimplemented(&self) -> Self::Error {
+        StatusCode::OpUnsupported
+    }
+
+    async fn init(
+        &mut self,
+        _version: u32,
+        _extensions: HashMap<String, String>,
+    ) -> Result<Version, Self::Error> {
+        Ok(Version::new())
+    }
+
+    async fn open(
+        &mut self,
+        id: u32,
+        filename: String,
+        pflags: OpenFlags,
+        _attrs: FileAttributes,
+    ) -> Result<Handle, Self::Error> {
+        let write = pflags.intersects(OpenFlags::WRITE | OpenFlags::APPEND);
+        let options = OpenOptions {
+            write,
+            create: write && pflags.contains(OpenFlags::CREATE),
+            truncate: write && pflags.contains(OpenFlags::TRUNCATE),
+            ..Default::default()
+        };
+        let file = self
+            .vfs
+            .open(&segments(&filename), options)
+            .await
+            .map_err(failure)?;
+        Ok(Handle {
+            id,
+            handle: self.add_handle(OpenHandle::File(file)),
+        })
+    }
+
+    async fn close(&mut self, id: u32, handle: String) -> Result<Status, Self::Error> {
+        self.handles.remove(&handle);
+        Ok(ok_status(id))
+    }
+
+    async fn read(
+        &mut self,
+        id: u32,
+        handle: String,
+        offset: u64,
+        len: u32,
+    ) -> Result<Data, Self::Error> {
+        let file = self.file_handle(&handle)?;
+        let data = self
+            .vfs
+            .read(&file, offset, len as usize)
+            .await
+            .map_err(failure)?;
+        if data.is_empty() && len > 0 {
+            return Err(StatusCode::Eof);
+        }
+        Ok(Data { id, data })
+    }
+
+    async fn write(
+        &mut self,
+        id: u32,
+        handle: String,
+        offset: u64,
+        data: Vec<u8>,
+    ) -> Result<Status, Self::Error> {
+        let file = self.file_handle(&handle)?;
+        self.vfs.write(&file, offset, data).await.map_err(failure)?;
+        Ok(ok_status(id))
+    }
+
+    async fn opendir(&mut self, id: u32, path: String) -> Result<Handle, Self::Error> {
+        let entries = self.vfs.readdir(&segments(&path)).await.map_err(failure)?;
+        Ok(Handle {
+            id,
+            handle: self.add_handle(OpenHandle::Dir(Some(entries))),
+        })
+    }
+
+    async fn readdir(&mut self, id: u32, handle: String) -> Result<Name, Self::Error> {
+        let entries = match self.handles.get_mut(&handle) {
+            Some(OpenHandle::Dir(entries)) => entries.take().ok_or(StatusCode::Eof)?,
+            _ => return Err(StatusCode::Failure),
+        };
+        let files = entries
+            .iter()
+            .map(|(name, stat)| File::new(name, attributes_of(stat)))
+            .collect();
+        Ok(Name { id, files })
+    }
+
+    async fn realpath(&mut self, id: u32, path: String) -> Result<Name, Self::Error> {
+        let path = format!("/{}", segments(&path).join("/"));
+        Ok(Name {
+            id,
+            files: vec![File::dummy(path)],
+        })
+    }
+
+    async fn stat(&mut self, id: u32, path: String) -> Result<Attrs, Self::Error> {
+        let attrs = self.attributes(&segments(&path)).await?;
+        Ok(Attrs { id, attrs })
+    }
+
+    async fn lstat(&mut self, id: u32, path: String) -> Result<Attrs, Self::Error> {
+        self.stat(id, path).await
+    }
+
+    async fn fstat(&mut self, id: u32, handle: String) -> Result<Attrs, Self::Error> {
+        let file = self.file_handle(&handle)?;
+        let attrs = self.attributes(&file.path).await?;
+        Ok(Attrs { id, attrs })
+    }
+
+    async fn mkdir(
+        &mut self,
+        id: u32,
+        path: String,
+        _attrs: FileAttributes,
+    ) -> Result<Status, Self::Error> {
+        self.vfs.mkdir(&segments(&path)).await.map_err(failure)?;
+        Ok(ok_status(id))
+    }
+
+    async fn rmdir(&mut self, id: u32, path: String) -> Result<Status, Self::Error> {
+        self.vfs.remove(&segments(&path)).await.map_err(failure)?;
+        Ok(ok_status(id))
+    }
+
+    async fn remove(&mut self, id: u32, filename: String) -> Result<Status, Self::Error> {
+        self.vfs
+            .remove(&segments(&filename))
+            .await
+            .map_err(failure)?;
+        Ok(ok_status(id))
+    }
+
+    async fn rename(
+        &mut self,
+        id: u32,
+        oldpath: String,
+        newpath: String,
+    ) -> Result<Status, Self::Error> {
+        self.vfs
+            .rename(&segments(&oldpath), &segments(&newpath))
+            .await
+            .map_err(failure)?;
+        Ok(ok_status(id))
+    }
+}
+
+// Splits an SFTP path into segments, resolving `.` and `..` against the forest root.
+fn segments(path: &str) -> Vec<String> {
+    let mut segments: Vec<String> = Vec::new();
+    for segment in path.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
+            }
+            _ => segments.push(segment.to_string()),
+        }
+    }
+    segments
+}
+
+fn attributes_of(stat: &VfsStat) -> FileAttributes {
+    let permissions = match stat.kind {
+        VfsNodeKind::Directory => 0o040755,
+        VfsNodeKind::File => 0o100644,
+    };
+    let mtime = stat.modified.map(|modified| modified.max(0) as u32);
+    FileAttributes {
+        size: Some(stat.size),
+        permissions: Some(permissions),
+        atime: mtime,
+        mtime,
+        ..Default::default()
+    }
+}
+
+fn ok_status(id: u32) -> Status {
+    Status {
+        id,
+        status_code: StatusCode::Ok,
+        error_message: "Ok".to_string(),
+        language_tag: "en-US".to_string(),
+    }
+}
+
+fn failure(e: String) -> StatusCode {
+    trace!("wnfsError in sftp: {:?}", e);
+    StatusCode::Failure
+}
+
+#[cfg(test)]
+mod sftp_tests;
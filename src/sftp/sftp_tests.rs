@@ -0,0 +1,30 @@
+use crate::{
+    sftp::{attributes_of, segments},
+    vfs::{VfsNodeKind, VfsStat},
+};
+
+#[test]
+fn sftp_paths_resolve_against_the_forest_root() {
+    assert!(segments("/").is_empty());
+    assert!(segments("..").is_empty());
+    assert_eq!(segments("/docs/./a.txt"), vec!["docs", "a.txt"]);
+    assert_eq!(segments("docs/sub/../a.txt"), vec!["docs", "a.txt"]);
+}
+
+#[test]
+fn directories_and_files_get_distinct_modes() {
+    let dir = attributes_of(&VfsStat {
+        kind: VfsNodeKind::Directory,
+        size: 0,
+        modified: Some(1_700_000_000),
+    });
+    assert_eq!(dir.permissions, Some(0o040755));
+    assert_eq!(dir.mtime, Some(1_700_000_000));
+    let file = attributes_of(&VfsStat {
+        kind: VfsNodeKind::File,
+        size: 12,
+        modified: None,
+    });
+    assert_eq!(file.permissions, Some(0o100644));
+    assert_eq!(file.size, Some(12));
+}
@@ -0,0 +1,162 @@
+//! Experimental SQLite VFS, so apps can open a database whose pages live encrypted in the forest.
+//!
+//! Databases and journals are opened as paged files (see `PagedFileOptions`) through a
+//! [`VfsBridge`], so SQLite can run on any thread while the app keeps the helper and drives
+//! `vfs::serve_vfs`. SQLite must not be called from the thread serving the bridge, which would
+//! deadlock. Writes are committed to the forest right away unless a batching window is open;
+//! `sync` commits whatever the window still holds back. Locks are only tracked per handle: keep a single connection per database. WAL mode isn't
+//! supported. Requires the `sqlite` feature.
+
+use std::{
+    io::{Error, ErrorKind},
+    thread,
+    time::Duration,
+};
+
+use futures::executor::block_on;
+use log::trace;
+use rand::{thread_rng, Rng};
+use sqlite_vfs::{DatabaseHandle, LockKind, OpenAccess, OpenOptions, Vfs, WalDisabled};
+
+use crate::vfs::{self, VfsBridge, VfsHandle};
+
+/// Registers the forest behind `bridge` as the SQLite VFS `name`. Database names are paths below
+/// the forest root, such as `app/app.db`.
+pub fn register_sqlite_vfs(name: &str, bridge: VfsBridge) -> Result<(), String> {
+    sqlite_vfs::register(name, ForestVfs { bridge }, false).map_err(|e| {
+        trace!("wnfsError in register_sqlite_vfs: {:?}", e.to_string());
+        e.to_string()
+    })
+}
+
+struct ForestVfs {
+    bridge: VfsBridge,
+}
+
+struct ForestDatabase {
+    bridge: VfsBridge,
+    handle: VfsHandle,
+    lock: LockKind,
+}
+
+impl Vfs for ForestVfs {
+    type Handle = ForestDatabase;
+
+    fn open(&self, db: &str, opts: OpenOptions) -> Result<Self::Handle, Error> {
+        let path = segments(db);
+        let exists = block_on(self.bridge.stat(&path))
+            .map_err(io_error)?
+            .is_some();
+        let create = match opts.access {
+            OpenAccess::Read | OpenAccess::Write => false,
+            OpenAccess::Create => true,
+            OpenAccess::CreateNew if exists => {
+                return Err(Error::new(ErrorKind::AlreadyExists, db.to_string()))
+            }
+            OpenAccess::CreateNew => true,
+        };
+        let options = vfs::OpenOptions {
+            write: !matches!(opts.access, OpenAccess::Read),
+            create,
+            paged: create,
+            ..Default::default()
+        };
+        let handle = block_on(self.bridge.open(&path, options)).map_err(io_error)?;
+        Ok(ForestDatabase {
+            bridge: self.bridge.to_owned(),
+            handle,
+            lock: LockKind::None,
+        })
+    }
+
+    fn delete(&self, db: &str) -> Result<(), Error> {
+        block_on(self.bridge.remove(&segments(db))).map_err(io_error)
+    }
+
+    fn exists(&self, db: &str) -> Result<bool, Error> {
+        Ok(block_on(self.bridge.stat(&segments(db)))
+            .map_err(io_error)?
+            .is_some())
+    }
+
+    fn temporary_name(&self) -> String {
+        format!("etilqs_{:016x}", thread_rng().gen::<u64>())
+    }
+
+    fn random(&self, buffer: &mut [i8]) {
+        let mut rng = thread_rng();
+        for byte in buffer.iter_mut() {
+            *byte = rng.gen();
+        }
+    }
+
+    fn sleep(&self, duration: Duration) -> Duration {
+        thread::sleep(duration);
+        duration
+    }
+}
+
+impl DatabaseHandle for ForestDatabase {
+    type WalIndex = WalDisabled;
+
+    fn size(&self) -> Result<u64, Error> {
+        let stat = block_on(self.bridge.stat(&self.handle.path)).map_err(io_error)?;
+        Ok(stat.map(|stat| stat.size).unwrap_or_default())
+    }
+
+    fn read_exact_at(&mut self, buf: &mut [u8], offset: u64) -> Result<(), Error> {
+        let data = block_on(self.bridge.read(&self.handle, offset, buf.len())).map_err(io_error)?;
+        buf[..data.len()].copy_from_slice(&data);
+        if data.len() < buf.len() {
+            // SQLite expects the rest of a short read to be zeroed.
+            buf[data.len()..].fill(0);
+            return Err(Error::new(ErrorKind::UnexpectedEof, "short read"));
+        }
+        Ok(())
+    }
+
+    fn write_all_at(&mut self, buf: &[u8], offset: u64) -> Result<(), Error> {
+        block_on(self.bridge.write(&self.handle, offset, buf.to_vec())).map_err(io_error)?;
+        Ok(())
+    }
+
+    fn sync(&mut self, _data_only: bool) -> Result<(), Error> {
+        block_on(self.bridge.sync()).map_err(io_error)
+    }
+
+    fn set_len(&mut self, size: u64) -> Result<(), Error> {
+        block_on(self.bridge.set_len(&self.handle, size)).map_err(io_error)
+    }
+
+    fn lock(&mut self, lock: LockKind) -> Result<bool, Error> {
+        self.lock = lock;
+        Ok(true)
+    }
+
+    fn reserved(&mut self) -> Result<bool, Error> {
+        Ok(self.lock >= LockKind::Reserved)
+    }
+
+    fn current_lock(&self) -> Result<LockKind, Error> {
+        Ok(self.lock)
+    }
+
+    fn wal_index(&self, _readonly: bool) -> Result<Self::WalIndex, Error> {
+        Ok(WalDisabled)
+    }
+}
+
+fn segments(db: &str) -> Vec<String> {
+    db.split('/')
+        .filter(|segment| !segment.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+fn io_error(e: String) -> Error {
+    trace!("wnfsError in sqlite: {:?}", e);
+    Error::new(ErrorKind::Other, e)
+}
+
+#[cfg(test)]
+mod sqlite_tests;
@@ -0,0 +1,49 @@
+use rusqlite::{Connection, OpenFlags};
+use wnfs::common::CODEC_DAG_CBOR;
+
+use crate::{
+    blockstore::FFIFriendlyBlockStore,
+    kvstore::KVBlockStore,
+    private_forest::PrivateDirectoryHelper,
+    sqlite::{register_sqlite_vfs, segments},
+    vfs::{serve_vfs, vfs_channel},
+};
+
+#[test]
+fn database_names_are_forest_paths() {
+    assert_eq!(segments("/app/app.db"), vec!["app", "app.db"]);
+    assert_eq!(segments("app.db-journal"), vec!["app.db-journal"]);
+}
+
+#[tokio::test]
+async fn sqlite_runs_on_a_paged_forest_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("store").to_string_lossy().to_string();
+    let store = KVBlockStore::new(path, CODEC_DAG_CBOR);
+    let blockstore = &mut FFIFriendlyBlockStore::new(Box::new(store));
+    let (helper, _, _) = &mut PrivateDirectoryHelper::init(blockstore, vec![3; 32])
+        .await
+        .unwrap();
+
+    let (bridge, requests) = vfs_channel(8);
+    register_sqlite_vfs("wnfs-test", bridge).unwrap();
+    // SQLite blocks on the bridge, so it runs on its own thread while this one serves it.
+    let database = tokio::task::spawn_blocking(|| {
+        let flags = OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE;
+        let connection = Connection::open_with_flags_and_vfs("app.db", flags, "wnfs-test")?;
+        connection.execute_batch(
+            "CREATE TABLE notes (id INTEGER PRIMARY KEY, body TEXT);
+             INSERT INTO notes (body) VALUES ('first'), ('second');",
+        )?;
+        connection.query_row("SELECT group_concat(body) FROM notes", [], |row| {
+            row.get::<_, String>(0)
+        })
+    });
+    // The registered VFS keeps a bridge alive, so serving stops once SQLite is done.
+    let bodies = tokio::select! {
+        _ = serve_vfs(helper, requests) => unreachable!(),
+        result = database => result.unwrap().unwrap(),
+    };
+    assert_eq!(bodies, "first,second");
+    assert!(helper.is_paged_file(&["app.db".into()]).await.unwrap());
+}
@@ -0,0 +1,16 @@
+//! Sleeping in async code on every target. Tokio's timers need its time driver, which browsers
+//! don't run, so wasm builds wait on `setTimeout` instead.
+
+use std::time::Duration;
+
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) async fn sleep(delay: Duration) {
+    tokio::time::sleep(delay).await;
+}
+
+#[cfg(target_arch = "wasm32")]
+pub(crate) async fn sleep(delay: Duration) {
+    // `setTimeout` takes milliseconds as an i32, about 24 days.
+    let millis = delay.as_millis().min(i32::MAX as u128) as u32;
+    gloo_timers::future::TimeoutFuture::new(millis).await;
+}
@@ -0,0 +1,253 @@
+//! The file system operations frontends (FUSE, WebDAV, HTTP, SFTP, ...) build on, so a new
+//! frontend only needs a [`Vfs`] and never the helper internals.
+//!
+//! Paths are split into segments as returned by `PrivateDirectoryHelper::parse_path`; the empty
+//! path is the root directory. Frontends on a multi-threaded runtime reach the `Vfs` through a
+//! [`VfsBridge`].
+
+use async_trait::async_trait;
+use tokio::sync::{mpsc, oneshot};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VfsNodeKind {
+    File,
+    Directory,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VfsStat {
+    pub kind: VfsNodeKind,
+    /// Content size in bytes, 0 for directories.
+    pub size: u64,
+    /// Modification time in seconds since the Unix epoch, if recorded.
+    pub modified: Option<i64>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OpenOptions {
+    pub write: bool,
+    /// Create the file if it doesn't exist. Requires `write`.
+    pub create: bool,
+    /// Empty the file on open. Requires `write`.
+    pub truncate: bool,
+    /// Create the file page-aligned, for random-access writers such as databases. Requires
+    /// `create`.
+    pub paged: bool,
+}
+
+/// An open file. Handles carry no state in the file system, so they can be cloned and kept
+/// across calls freely.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VfsHandle {
+    pub path: Vec<String>,
+    pub writable: bool,
+}
+
+#[async_trait(?Send)]
+pub trait Vfs {
+    /// `None` if nothing exists at `path`.
+    async fn stat(&mut self, path: &[String]) -> Result<Option<VfsStat>, String>;
+
+    async fn readdir(&mut self, path: &[String]) -> Result<Vec<(String, VfsStat)>, String>;
+
+    async fn open(&mut self, path: &[String], options: OpenOptions) -> Result<VfsHandle, String>;
+
+    /// Reads up to `len` bytes at `offset`; fewer at the end of the file.
+    async fn read(
+        &mut self,
+        handle: &VfsHandle,
+        offset: u64,
+        len: usize,
+    ) -> Result<Vec<u8>, String>;
+
+    /// Writes `data` at `offset`, growing the file (zero filled) as needed, and commits.
+    async fn write(
+        &mut self,
+        handle: &VfsHandle,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<usize, String>;
+
+    /// Truncates or zero-extends the file to `len` bytes, and commits.
+    async fn set_len(&mut self, handle: &VfsHandle, len: u64) -> Result<(), String>;
+
+    async fn rename(&mut self, from: &[String], to: &[String]) -> Result<(), String>;
+
+    async fn mkdir(&mut self, path: &[String]) -> Result<(), String>;
+
+    /// Removes the file or directory (with everything below it) at `path`.
+    async fn remove(&mut self, path: &[String]) -> Result<(), String>;
+
+    /// Commits changes still held back, such as those of an open batching window.
+    async fn sync(&mut self) -> Result<(), String>;
+}
+
+/// A request to a [`Vfs`] served by [`serve_vfs`], carrying the channel its result is sent on.
+pub enum VfsRequest {
+    Stat(
+        Vec<String>,
+        oneshot::Sender<Result<Option<VfsStat>, String>>,
+    ),
+    Readdir(
+        Vec<String>,
+        oneshot::Sender<Result<Vec<(String, VfsStat)>, String>>,
+    ),
+    Open(
+        Vec<String>,
+        OpenOptions,
+        oneshot::Sender<Result<VfsHandle, String>>,
+    ),
+    Read(
+        VfsHandle,
+        u64,
+        usize,
+        oneshot::Sender<Result<Vec<u8>, String>>,
+    ),
+    Write(
+        VfsHandle,
+        u64,
+        Vec<u8>,
+        oneshot::Sender<Result<usize, String>>,
+    ),
+    SetLen(VfsHandle, u64, oneshot::Sender<Result<(), String>>),
+    Rename(
+        Vec<String>,
+        Vec<String>,
+        oneshot::Sender<Result<(), String>>,
+    ),
+    Mkdir(Vec<String>, oneshot::Sender<Result<(), String>>),
+    Remove(Vec<String>, oneshot::Sender<Result<(), String>>),
+    Sync(oneshot::Sender<Result<(), String>>),
+}
+
+/// A `Send` handle to a [`Vfs`] that stays on the thread owning it, for frontends running on a
+/// multi-threaded runtime. The helper isn't `Send`, so the app keeps it and drives
+/// [`serve_vfs`] while the frontend calls through the bridge. Cheap to clone.
+#[derive(Clone)]
+pub struct VfsBridge {
+    requests: mpsc::Sender<VfsRequest>,
+}
+
+/// Creates a bridge and the receiving end to pass to [`serve_vfs`]. `buffer` bounds the number
+/// of requests waiting to be served.
+pub fn vfs_channel(buffer: usize) -> (VfsBridge, mpsc::Receiver<VfsRequest>) {
+    let (requests, receiver) = mpsc::channel(buffer);
+    (VfsBridge { requests }, receiver)
+}
+
+/// Serves requests one at a time until every bridge is dropped.
+pub async fn serve_vfs(vfs: &mut dyn Vfs, mut requests: mpsc::Receiver<VfsRequest>) {
+    // A requester that went away doesn't want its result, so failed replies are ignored.
+    while let Some(request) = requests.recv().await {
+        match request {
+            VfsRequest::Stat(path, reply) => {
+                let _ = reply.send(vfs.stat(&path).await);
+            }
+            VfsRequest::Readdir(path, reply) => {
+                let _ = reply.send(vfs.readdir(&path).await);
+            }
+            VfsRequest::Open(path, options, reply) => {
+                let _ = reply.send(vfs.open(&path, options).await);
+            }
+            VfsRequest::Read(handle, offset, len, reply) => {
+                let _ = reply.send(vfs.read(&handle, offset, len).await);
+            }
+            VfsRequest::Write(handle, offset, data, reply) => {
+                let _ = reply.send(vfs.write(&handle, offset, &data).await);
+            }
+            VfsRequest::SetLen(handle, len, reply) => {
+                let _ = reply.send(vfs.set_len(&handle, len).await);
+            }
+            VfsRequest::Rename(from, to, reply) => {
+                let _ = reply.send(vfs.rename(&from, &to).await);
+            }
+            VfsRequest::Mkdir(path, reply) => {
+                let _ = reply.send(vfs.mkdir(&path).await);
+            }
+            VfsRequest::Remove(path, reply) => {
+                let _ = reply.send(vfs.remove(&path).await);
+            }
+            VfsRequest::Sync(reply) => {
+                let _ = reply.send(vfs.sync().await);
+            }
+        }
+    }
+}
+
+impl VfsBridge {
+    pub async fn stat(&self, path: &[String]) -> Result<Option<VfsStat>, String> {
+        self.call(|reply| VfsRequest::Stat(path.to_vec(), reply))
+            .await
+    }
+
+    pub async fn readdir(&self, path: &[String]) -> Result<Vec<(String, VfsStat)>, String> {
+        self.call(|reply| VfsRequest::Readdir(path.to_vec(), reply))
+            .await
+    }
+
+    pub async fn open(&self, path: &[String], options: OpenOptions) -> Result<VfsHandle, String> {
+        self.call(|reply| VfsRequest::Open(path.to_vec(), options, reply))
+            .await
+    }
+
+    pub async fn read(
+        &self,
+        handle: &VfsHandle,
+        offset: u64,
+        len: usize,
+    ) -> Result<Vec<u8>, String> {
+        self.call(|reply| VfsRequest::Read(handle.to_owned(), offset, len, reply))
+            .await
+    }
+
+    pub async fn write(
+        &self,
+        handle: &VfsHandle,
+        offset: u64,
+        data: Vec<u8>,
+    ) -> Result<usize, String> {
+        self.call(|reply| VfsRequest::Write(handle.to_owned(), offset, data, reply))
+            .await
+    }
+
+    pub async fn set_len(&self, handle: &VfsHandle, len: u64) -> Result<(), String> {
+        self.call(|reply| VfsRequest::SetLen(handle.to_owned(), len, reply))
+            .await
+    }
+
+    pub async fn rename(&self, from: &[String], to: &[String]) -> Result<(), String> {
+        self.call(|reply| VfsRequest::Rename(from.to_vec(), to.to_vec(), reply))
+            .await
+    }
+
+    pub async fn mkdir(&self, path: &[String]) -> Result<(), String> {
+        self.call(|reply| VfsRequest::Mkdir(path.to_vec(), reply))
+            .await
+    }
+
+    pub async fn remove(&self, path: &[String]) -> Result<(), String> {
+        self.call(|reply| VfsRequest::Remove(path.to_vec(), reply))
+            .await
+    }
+
+    pub async fn sync(&self) -> Result<(), String> {
+        self.call(VfsRequest::Sync).await
+    }
+
+    async fn call<T>(
+        &self,
+        request: impl FnOnce(oneshot::Sender<Result<T, String>>) -> VfsRequest,
+    ) -> Result<T, String> {
+        let (reply, result) = oneshot::channel();
+        self.requests
+            .send(request(reply))
+            .await
+            .map_err(|_| "wnfsError the vfs is no longer served".to_string())?;
+        result
+            .await
+            .map_err(|_| "wnfsError the vfs is no longer served".to_string())?
+    }
+}
+
+#[cfg(test)]
+mod vfs_tests;
@@ -0,0 +1,51 @@
+use wnfs::common::CODEC_DAG_CBOR;
+
+use crate::{
+    blockstore::FFIFriendlyBlockStore,
+    kvstore::KVBlockStore,
+    private_forest::PrivateDirectoryHelper,
+    vfs::{serve_vfs, vfs_channel, OpenOptions, VfsNodeKind},
+};
+
+#[tokio::test]
+async fn bridge_forwards_calls_to_the_served_vfs() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("store").to_string_lossy().to_string();
+    let store = KVBlockStore::new(path, CODEC_DAG_CBOR);
+    let blockstore = &mut FFIFriendlyBlockStore::new(Box::new(store));
+    let (helper, _, _) = &mut PrivateDirectoryHelper::init(blockstore, vec![7; 32])
+        .await
+        .unwrap();
+
+    let (bridge, requests) = vfs_channel(8);
+    let frontend = async move {
+        let path: Vec<String> = vec!["bridged.txt".into()];
+        let handle = bridge
+            .open(
+                &path,
+                OpenOptions {
+                    write: true,
+                    create: true,
+                    truncate: true,
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        bridge
+            .write(&handle, 0, b"over the bridge".to_vec())
+            .await
+            .unwrap();
+        assert_eq!(bridge.read(&handle, 5, 3).await.unwrap(), b"the".to_vec());
+        let stat = bridge.stat(&path).await.unwrap().unwrap();
+        assert_eq!(stat.kind, VfsNodeKind::File);
+        assert!(bridge.stat(&["missing".into()]).await.unwrap().is_none());
+        // Dropping the last bridge ends `serve_vfs`.
+    };
+    tokio::join!(serve_vfs(helper, requests), frontend);
+
+    assert_eq!(
+        helper.read_file(&["bridged.txt".into()]).await.unwrap(),
+        b"over the bridge".to_vec()
+    );
+}
@@ -0,0 +1,166 @@
+//! The helper for browser apps, exported with `wasm-bindgen`.
+//!
+//! [`WasmHelper`] keeps a [`HelperHandle`] on a `JsBlockStore`, so JS can fire reads while a
+//! write is in flight, as with the handle itself. Every method returns a `Promise`: paths are
+//! `/`-separated strings, see `PrivateDirectoryHelper::parse_path`, CIDs and file contents are
+//! `Uint8Array`s, and a failed call rejects with the error message, code included.
+
+use js_sys::{Array, Promise, Uint8Array};
+use libipld::Cid;
+use wasm_bindgen::{prelude::wasm_bindgen, JsValue};
+use wasm_bindgen_futures::future_to_promise;
+
+use crate::{
+    blockstore::{cid_from_bytes, FFIFriendlyBlockStore, JsBlockStore},
+    private_forest::{HelperHandle, PrivateDirectoryHelper},
+};
+
+#[wasm_bindgen]
+#[derive(Clone)]
+pub struct WasmHelper {
+    handle: HelperHandle<'static>,
+}
+
+#[wasm_bindgen]
+impl WasmHelper {
+    /// Creates a new forest on `store`, resolving to the helper.
+    pub fn init(store: JsBlockStore, wnfs_key: Vec<u8>) -> Promise {
+        future_to_promise(async move {
+            let mut store = FFIFriendlyBlockStore::new(Box::new(store));
+            let (session, _, _) = PrivateDirectoryHelper::init_exclusive(&mut store, wnfs_key)
+                .await
+                .map_err(rejected)?;
+            Self::wrap(session.into_helper()).await
+        })
+    }
+
+    /// Loads the forest `forest_cid` of `store`, resolving to the helper.
+    pub fn load(store: JsBlockStore, wnfs_key: Vec<u8>, forest_cid: Vec<u8>) -> Promise {
+        future_to_promise(async move {
+            let forest_cid = cid_from_bytes(&forest_cid).map_err(|e| rejected(e.to_string()))?;
+            let mut store = FFIFriendlyBlockStore::new(Box::new(store));
+            let session = PrivateDirectoryHelper::load_exclusive(&mut store, forest_cid, wnfs_key)
+                .await
+                .map_err(rejected)?;
+            Self::wrap(session.into_helper()).await
+        })
+    }
+
+    /// The forest CID reads currently run on.
+    #[wasm_bindgen(js_name = rootCid)]
+    pub fn root_cid(&self) -> Vec<u8> {
+        self.handle.root_cid().to_bytes()
+    }
+
+    #[wasm_bindgen(js_name = readFile)]
+    pub fn read_file(&self, path: String) -> Promise {
+        let handle = self.handle.to_owned();
+        future_to_promise(async move {
+            let content = handle
+                .read_file(&PrivateDirectoryHelper::parse_path(path))
+                .await
+                .map_err(rejected)?;
+            Ok(Uint8Array::from(content.as_slice()).into())
+        })
+    }
+
+    /// Resolves to the names of the entries of the directory `path`.
+    pub fn ls(&self, path: String) -> Promise {
+        let handle = self.handle.to_owned();
+        future_to_promise(async move {
+            let entries = handle
+                .ls_files(&PrivateDirectoryHelper::parse_path(path))
+                .await
+                .map_err(rejected)?;
+            let names: Array = entries
+                .into_iter()
+                .map(|(name, _)| JsValue::from(name))
+                .collect();
+            Ok(names.into())
+        })
+    }
+
+    pub fn exists(&self, path: String) -> Promise {
+        let handle = self.handle.to_owned();
+        future_to_promise(async move {
+            let exists = handle
+                .exists(&PrivateDirectoryHelper::parse_path(path))
+                .await
+                .map_err(rejected)?;
+            Ok(JsValue::from_bool(exists))
+        })
+    }
+
+    /// Resolves to the new forest CID, as do the other writes.
+    #[wasm_bindgen(js_name = writeFile)]
+    pub fn write_file(
+        &self,
+        path: String,
+        content: Vec<u8>,
+        modification_time_seconds: i64,
+    ) -> Promise {
+        let handle = self.handle.to_owned();
+        future_to_promise(async move {
+            let root = handle
+                .write_file(
+                    &PrivateDirectoryHelper::parse_path(path),
+                    content,
+                    modification_time_seconds,
+                )
+                .await
+                .map_err(rejected)?;
+            Ok(cid_value(root))
+        })
+    }
+
+    pub fn mkdir(&self, path: String) -> Promise {
+        let handle = self.handle.to_owned();
+        future_to_promise(async move {
+            let root = handle
+                .mkdir(&PrivateDirectoryHelper::parse_path(path))
+                .await
+                .map_err(rejected)?;
+            Ok(cid_value(root))
+        })
+    }
+
+    pub fn rm(&self, path: String) -> Promise {
+        let handle = self.handle.to_owned();
+        future_to_promise(async move {
+            let root = handle
+                .rm(&PrivateDirectoryHelper::parse_path(path))
+                .await
+                .map_err(rejected)?;
+            Ok(cid_value(root))
+        })
+    }
+
+    pub fn mv(&self, source: String, target: String) -> Promise {
+        let handle = self.handle.to_owned();
+        future_to_promise(async move {
+            let root = handle
+                .mv(
+                    &PrivateDirectoryHelper::parse_path(source),
+                    &PrivateDirectoryHelper::parse_path(target),
+                )
+                .await
+                .map_err(rejected)?;
+            Ok(cid_value(root))
+        })
+    }
+}
+
+impl WasmHelper {
+    async fn wrap(helper: PrivateDirectoryHelper<'static>) -> Result<JsValue, JsValue> {
+        let handle = helper.into_handle().await.map_err(rejected)?;
+        Ok(Self { handle }.into())
+    }
+}
+
+fn cid_value(cid: Cid) -> JsValue {
+    Uint8Array::from(cid.to_bytes().as_slice()).into()
+}
+
+fn rejected(message: String) -> JsValue {
+    JsValue::from(js_sys::Error::new(&message))
+}